@@ -0,0 +1,460 @@
+//! HTTP endpoints for operating the server, on the address given with `--admin-listen`:
+//! `/metrics` for Prometheus to scrape, see [`crate::metrics`], a dashboard at `/`, see
+//! [`crate::dashboard`], and a JSON API under `/api` to look into and change the running
+//! server. They are served over plain HTTP/1.1 without authentication, so the address is meant
+//! to be reachable from the host or a private network only. Requests changing the server are
+//! refused when a browser sends them from a page of another origin. The API is also answered on
+//! the unix socket given with `--control-socket`, for `ctl`, see [`crate::ctl`].
+//!
+//! - `GET /api/stats`: the metrics as JSON
+//! - `GET /api/dashboard`: what the dashboard shows, the metrics along with the latest queries
+//!   and the names blocked and clients querying the most
+//! - `GET /api/cache`: the size of the cache, `DELETE /api/cache` empties it
+//! - `GET /api/blocklist`: the names blocked and allowed through the API, and whether blocking
+//!   is paused
+//! - `PUT` and `DELETE /api/blocklist/blocked/<name>` and `/api/blocklist/allowed/<name>`: block
+//!   or allow a name until it is removed again, see [`Blocklist::add_blocked`]
+//! - `POST /api/reload`: reads the configuration file, lists, hosts file and zones again, like
+//!   SIGHUP
+//! - `POST /api/blocking/pause?seconds=<seconds>`: stops blocking for a while, or until resumed
+//!   without `seconds`, and `POST /api/blocking/resume` blocks again
+
+use std::{sync::Arc, time::Duration};
+
+use dns::{
+    filter::{BlockingState, Blocklist},
+    protocol::name::DnsName,
+};
+use serde_json::{json, Value};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::info;
+
+use crate::{
+    dashboard::{Activity, PAGE},
+    doh::{serve_http1, Request, Respond, Response},
+    logging::timestamp,
+    metrics::Gauges,
+    state::Server,
+};
+
+/// Most connections served at a time, scrapers and scripts only need one
+const MAX_CONNECTIONS: usize = 16;
+
+/// Content type of the Prometheus text format
+/// https://prometheus.io/docs/instrumenting/exposition_formats/#basic-info
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const JSON: &str = "application/json";
+
+const HTML: &str = "text/html; charset=utf-8";
+
+/// Answers the requests of an admin connection
+#[derive(Clone)]
+struct Admin {
+    server: Arc<Server>,
+    activity: Arc<Activity>,
+}
+
+/// Accepts admin connections until the process exits, closing those that send nothing for
+/// `idle_timeout`
+pub async fn serve_admin(listener: TcpListener, idle_timeout: Duration, server: Arc<Server>) {
+    let admin = Admin::new(server);
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let Ok((stream, client)) = listener.accept().await else {
+            continue;
+        };
+        if let Ok(permit) = Arc::clone(&connections).try_acquire_owned() {
+            admin.spawn(stream, client.to_string(), idle_timeout, permit);
+        }
+    }
+}
+
+/// Accepts connections to the control socket until the process exits, like [`serve_admin`],
+/// see [`crate::ctl`]
+#[cfg(unix)]
+pub async fn serve_control(listener: UnixListener, idle_timeout: Duration, server: Arc<Server>) {
+    let admin = Admin::new(server);
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        if let Ok(permit) = Arc::clone(&connections).try_acquire_owned() {
+            admin.spawn(stream, "control socket".to_string(), idle_timeout, permit);
+        }
+    }
+}
+
+impl Respond for Admin {
+    async fn respond(&self, request: Request) -> Response {
+        let (path, parameters) = (request.target.split_once('?')).unwrap_or((&request.target, ""));
+        let segments: Vec<_> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let method = request.method.as_str();
+        // a page elsewhere could otherwise make the browser of someone on the admin network
+        // change the server, https://owasp.org/www-community/attacks/csrf
+        if method != "GET" && !same_origin(&request) {
+            return json_error(403, "cross-origin requests are not allowed");
+        }
+        let state = self.server.state();
+        let blocklist = &state.blocklist;
+
+        let allowed = match segments[..] {
+            [] if method == "GET" => return Response::new(HTML, PAGE.as_bytes().to_vec()),
+            ["metrics"] if method == "GET" => {
+                let metrics = state.metrics.render(&self.gauges());
+                return Response::new(PROMETHEUS_TEXT, metrics.into_bytes());
+            }
+            [] | ["metrics"] | ["api", "stats" | "dashboard" | "blocklist"] if method != "GET" => {
+                "GET"
+            }
+            ["api", "stats"] => {
+                return json_response(state.metrics.to_json(&self.gauges()));
+            }
+            ["api", "dashboard"] => {
+                let mut dashboard = self.activity.to_json();
+                dashboard["stats"] = state.metrics.to_json(&self.gauges());
+                dashboard["blocking"] = blocklist.as_deref().map(blocking_json).into();
+                return json_response(dashboard);
+            }
+            ["api", "cache"] if state.args.no_cache => {
+                return json_error(404, "the cache is disabled");
+            }
+            ["api", "cache"] if method == "GET" => {
+                let cache = &state.cache;
+                return json_response(json!({"entries": cache.len(), "bytes": cache.bytes()}));
+            }
+            ["api", "cache"] if method == "DELETE" => {
+                let flushed = state.cache.clear();
+                info!("Flushed {flushed} cache entries through the admin API");
+                return json_response(json!({ "flushed": flushed }));
+            }
+            ["api", "cache"] => "GET, DELETE",
+            ["api", "reload"] if method == "POST" => {
+                crate::reload(&self.server);
+                return json_response(json!({ "reloaded": true }));
+            }
+            ["api", "reload"] => "POST",
+            ["api", "blocklist", ..] | ["api", "blocking", ..] if blocklist.is_none() => {
+                return json_error(404, "no blocklist");
+            }
+            ["api", "blocklist"] => {
+                let blocklist = blocklist.as_deref().expect("checked above");
+                return json_response(blocklist_json(blocklist));
+            }
+            ["api", "blocklist", list @ ("blocked" | "allowed"), name] => {
+                let blocklist = blocklist.as_deref().expect("checked above");
+                let Ok(name) = name.parse::<DnsName>() else {
+                    return json_error(400, "invalid name");
+                };
+                let changed = match (method, list) {
+                    ("PUT", "blocked") => {
+                        blocklist.add_blocked(&name);
+                        true
+                    }
+                    ("PUT", _) => {
+                        blocklist.add_allowed(&name);
+                        true
+                    }
+                    ("DELETE", "blocked") => blocklist.remove_blocked(&name),
+                    ("DELETE", _) => blocklist.remove_allowed(&name),
+                    _ => return method_not_allowed("PUT, DELETE"),
+                };
+                if !changed {
+                    return json_error(404, &format!("{name} was not {list} through the API"));
+                }
+                match method {
+                    "PUT" => info!(name = %name, "Added to the {list} names through the admin API"),
+                    _ => info!(name = %name, "Removed from the {list} names through the admin API"),
+                }
+                return json_response(blocklist_json(blocklist));
+            }
+            ["api", "blocking", "resume"] if method == "POST" => {
+                let blocklist = blocklist.as_deref().expect("checked above");
+                blocklist.resume();
+                info!("Resumed blocking through the admin API");
+                return json_response(blocking_json(blocklist));
+            }
+            ["api", "blocking", "pause"] if method == "POST" => {
+                let blocklist = blocklist.as_deref().expect("checked above");
+                let seconds = (parameters.split('&'))
+                    .filter_map(|parameter| parameter.split_once('='))
+                    .find(|(key, _)| *key == "seconds")
+                    .map(|(_, seconds)| seconds.parse());
+                let duration = match seconds {
+                    Some(Ok(seconds)) => Some(Duration::from_secs(seconds)),
+                    Some(Err(_)) => return json_error(400, "seconds is not a number"),
+                    None => None,
+                };
+                blocklist.pause(duration);
+                match duration {
+                    Some(duration) => {
+                        info!("Paused blocking for {duration:?} through the admin API")
+                    }
+                    None => info!("Paused blocking through the admin API"),
+                }
+                return json_response(blocking_json(blocklist));
+            }
+            ["api", "blocking", "pause" | "resume"] => "POST",
+            _ => return json_error(404, "not found"),
+        };
+        method_not_allowed(allowed)
+    }
+}
+
+impl Admin {
+    fn new(server: Arc<Server>) -> Self {
+        Admin {
+            // kept across reloads, like the metrics
+            activity: server.state().activity.clone().unwrap_or_default(),
+            server,
+        }
+    }
+
+    /// Serves the connection from `client` until it is closed, holding `permit` meanwhile
+    fn spawn(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Send + 'static,
+        client: String,
+        idle_timeout: Duration,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let admin = self.clone();
+        tokio::spawn(async move {
+            let quiet = admin.server.state().args.quiet;
+            if let Err(e) = serve_http1(stream, idle_timeout, admin).await {
+                if !quiet {
+                    info!(client = %client, error = %e, "Admin connection failed");
+                }
+            }
+            drop(permit);
+        });
+    }
+
+    /// What the metrics are rendered with besides their counters
+    fn gauges(&self) -> Gauges {
+        let state = self.server.state();
+        let upstreams = &state.upstreams;
+        Gauges {
+            in_flight: state.queries_in_flight(),
+            cache: (!state.args.no_cache).then(|| state.cache.shard_stats()),
+            upstreams: (upstreams.servers().iter().cloned())
+                .zip(upstreams.stats())
+                .collect(),
+        }
+    }
+}
+
+/// The names blocked and allowed through the API, and whether blocking is paused
+fn blocklist_json(blocklist: &Blocklist) -> Value {
+    let (blocked, allowed) = blocklist.added();
+    let names = |names: Vec<DnsName>| -> Vec<_> { names.iter().map(DnsName::to_string).collect() };
+    json!({
+        "rules": blocklist.len(),
+        "blocked": names(blocked),
+        "allowed": names(allowed),
+        "blocking": blocking_json(blocklist),
+    })
+}
+
+/// Whether `blocklist` blocks names right now, and until when it does not if paused
+fn blocking_json(blocklist: &Blocklist) -> Value {
+    match blocklist.state() {
+        BlockingState::Enabled => json!({"enabled": true, "paused_until": null}),
+        BlockingState::Paused(until) => {
+            json!({"enabled": false, "paused_until": until.map(timestamp)})
+        }
+    }
+}
+
+/// Whether `request` comes from a page of the admin address itself, or not from a browser at
+/// all. Browsers send the origin of the page along with the requests changing anything.
+fn same_origin(request: &Request) -> bool {
+    let Some(origin) = request.header("origin") else {
+        return true;
+    };
+    let host = request.header("host");
+    host.is_some_and(|host| origin.strip_prefix("http://") == Some(host))
+}
+
+fn json_response(value: Value) -> Response {
+    Response::new(JSON, format!("{value}\n").into_bytes())
+}
+
+fn json_error(status: u16, message: &str) -> Response {
+    Response {
+        status,
+        ..json_response(json!({ "error": message }))
+    }
+}
+
+fn method_not_allowed(allowed: &str) -> Response {
+    let mut response = json_error(405, "method not allowed");
+    response.headers.push(("allow", allowed.to_string()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{cli::ServerArgs, state::State};
+
+    use super::*;
+
+    fn admin() -> Admin {
+        // there is a blocklist for the admin API then, nothing listens on the address
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--admin-listen=127.0.0.1:9153",
+            "--quiet",
+        ]);
+        Admin::new(Arc::new(Server::new(State::new(server_args))))
+    }
+
+    fn request(method: &str, target: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            ..Request::default()
+        }
+    }
+
+    async fn json(admin: &Admin, method: &str, target: &str) -> (u16, Value) {
+        let response = admin.respond(request(method, target)).await;
+        let body = serde_json::from_slice(&response.body).unwrap();
+        (response.status, body)
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let admin = admin();
+        let (status, stats) = json(&admin, "GET", "/api/stats").await;
+        assert_eq!(status, 200);
+        assert!(stats["queries"].is_u64());
+        assert_eq!(stats["upstreams"][0]["upstream"], "1.1.1.1:53");
+
+        let (status, cache) = json(&admin, "GET", "/api/cache").await;
+        assert_eq!(status, 200);
+        assert!(cache["entries"].is_u64());
+        let (status, flushed) = json(&admin, "DELETE", "/api/cache").await;
+        assert_eq!(status, 200);
+        assert!(flushed["flushed"].is_u64());
+
+        let (status, dashboard) = json(&admin, "GET", "/api/dashboard").await;
+        assert_eq!(status, 200);
+        assert!(dashboard["recent"].is_array());
+        assert!(dashboard["stats"]["queries"].is_u64());
+        assert_eq!(dashboard["blocking"]["enabled"], true);
+
+        assert_eq!(json(&admin, "GET", "/api/nothing").await.0, 404);
+        let response = admin.respond(request("POST", "/api/stats")).await;
+        assert_eq!(response.status, 405);
+        assert!(response.headers.contains(&("allow", "GET".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_cross_origin() {
+        let admin = admin();
+        let from = |origin: &str| {
+            let mut request = request("DELETE", "/api/cache");
+            request.headers = vec![
+                ("host".to_string(), "127.0.0.1:9153".to_string()),
+                ("origin".to_string(), origin.to_string()),
+            ];
+            request
+        };
+        let response = admin.respond(from("https://attacker.example")).await;
+        assert_eq!(response.status, 403);
+        assert_eq!(admin.respond(from("null")).await.status, 403);
+        // the dashboard on the admin address itself, and clients that are not browsers
+        assert_eq!(
+            admin.respond(from("http://127.0.0.1:9153")).await.status,
+            200
+        );
+        assert_eq!(json(&admin, "DELETE", "/api/cache").await.0, 200);
+        let mut read = from("https://attacker.example");
+        read.method = "GET".to_string();
+        assert_eq!(admin.respond(read).await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn test_blocklist() {
+        let admin = admin();
+        let client = "192.0.2.1".parse().unwrap();
+        let blocklist = admin.server.state().blocklist.clone().unwrap();
+        let blocked = |name: &str| blocklist.is_blocked(&name.parse().unwrap(), client);
+
+        let target = "/api/blocklist/blocked/ads.admin.example";
+        let (status, listed) = json(&admin, "PUT", target).await;
+        assert_eq!(status, 200);
+        assert!(listed["blocked"]
+            .as_array()
+            .unwrap()
+            .contains(&"ads.admin.example".into()));
+        assert!(blocked("eu.ads.admin.example"));
+        let allowed = "/api/blocklist/allowed/eu.ads.admin.example";
+        assert_eq!(json(&admin, "PUT", allowed).await.0, 200);
+        assert!(!blocked("eu.ads.admin.example"));
+        assert_eq!(json(&admin, "DELETE", allowed).await.0, 200);
+        assert_eq!(json(&admin, "DELETE", allowed).await.0, 404);
+        assert!(blocked("eu.ads.admin.example"));
+
+        let (status, paused) = json(&admin, "POST", "/api/blocking/pause?seconds=300").await;
+        assert_eq!(status, 200);
+        assert_eq!(paused["enabled"], false);
+        assert!(paused["paused_until"].is_string());
+        assert!(!blocked("ads.admin.example"));
+        let (_, resumed) = json(&admin, "POST", "/api/blocking/resume").await;
+        assert_eq!(resumed["enabled"], true);
+        assert!(blocked("ads.admin.example"));
+        let (status, _) = json(&admin, "POST", "/api/blocking/pause?seconds=soon").await;
+        assert_eq!(status, 400);
+
+        assert_eq!(json(&admin, "DELETE", target).await.0, 200);
+        assert!(!blocked("ads.admin.example"));
+        let invalid = "/api/blocklist/blocked/a..b";
+        assert_eq!(json(&admin, "PUT", invalid).await.0, 400);
+    }
+
+    #[tokio::test]
+    async fn test_serve_admin() {
+        let Admin { server, .. } = admin();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let idle_timeout = Duration::from_secs(5);
+        tokio::spawn(serve_admin(listener, idle_timeout, server));
+
+        let get = |target: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            let request = format!("GET {target} HTTP/1.1\r\nconnection: close\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("content-type: {PROMETHEUS_TEXT}\r\n")));
+        assert!(response.contains("\n# TYPE dns_queries_in_flight gauge\n"));
+        assert!(response.contains("dns_upstream_healthy{upstream="));
+        let response = get("/api/stats").await;
+        assert!(response.contains(&format!("content-type: {JSON}\r\n")));
+        let response = get("/").await;
+        assert!(response.contains(&format!("content-type: {HTML}\r\n")));
+        assert!(response.contains("fetch(\"/api/dashboard\")"));
+        assert!(get("/nothing")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,21 +1,335 @@
-use clap::Parser;
+use std::{
+    ffi::OsString,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use clap::{Parser, Subcommand};
+use dns::{
+    authority::{Acl, ZoneSet},
+    cache::{DnsCache, DEFAULT_MAX_ENTRIES},
+    dnssec::{load_keys, Algorithm, Denial, SigningPolicy, ZoneSigner},
+    filter::{AllowRule, BlockResponse, Blocklist, BlocklistFile, BLOCKED_TTL},
+    hosts::HostsFile,
+    protocol::name::DnsName,
+    records::{LocalRecord, LocalRecords, LOCAL_RECORD_TTL},
+    secondary::SecondaryZone,
+    tsig::TsigKey,
+    upstream::{Strategy, UpstreamPool},
+    zonefile::Zone,
+};
+use tracing::{warn, Level};
+
+use crate::{
+    config,
+    listen::Listener,
+    logging::LogFormat,
+    query_log::{QueryLog, Rotation},
+    rate_limit::{RateLimitAction, RateLimiter},
+    resolution::Protocol,
+    rrl::{ResponseRateLimiter, RrlSettings},
+    zones::roll_zone_keys,
+};
 
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct ServerArgs {
-    /// DNS server to forward to
-    /// TODO: Add support for multiple DNS servers
-    #[arg(short, long, default_value_t = String::from("1.1.1.1:53"))]
-    pub dns_relay: String,
+    /// Configuration file in TOML, see `config.example.toml`. Arguments given on the command
+    /// line take precedence over its settings
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// DNS servers to forward to, in order of preference. Can be given multiple times, the next
+    /// server is used whenever the current one times out or fails. Servers given as URL, eg.
+    /// `https://cloudflare-dns.com/dns-query`, are queried over DNS over HTTPS. With the `doq`
+    /// feature, `quic://` URLs like `quic://dns.adguard-dns.com` are queried over DNS over QUIC
+    #[arg(short, long, default_values_t = [String::from("1.1.1.1:53")])]
+    pub dns_relay: Vec<String>,
+
+    /// Always try the DNS servers in the given order, instead of preferring the fastest one
+    #[arg(long, default_value_t = false)]
+    pub strict_order: bool,
+
+    /// Milliseconds to wait for a response of the DNS server before sending the query again
+    #[arg(long, default_value_t = 2000)]
+    pub relay_timeout_ms: u64,
+
+    /// Number of times a query is sent again to the DNS server if it did not respond in time
+    #[arg(long, default_value_t = 2)]
+    pub relay_retries: u32,
+
+    /// Always forward queries, instead of answering them from the cache while their TTL lasts
+    #[arg(long, default_value_t = false)]
+    pub no_cache: bool,
+
+    /// Maximum number of questions whose answers are cached, the least recently used ones are
+    /// evicted beyond it
+    #[arg(long, default_value_t = DEFAULT_MAX_ENTRIES)]
+    pub cache_size: usize,
+
+    /// Seconds for which expired answers are kept in the cache, to be served while no DNS server
+    /// responds. Disabled with 0
+    #[arg(long, default_value_t = 0)]
+    pub serve_stale_secs: u64,
+
+    /// Percentage of the TTL left at which popular answers are refreshed in the background, so
+    /// their clients never wait for a DNS server. Disabled with 0
+    #[arg(long, default_value_t = 10)]
+    pub prefetch_percent: u8,
+
+    /// How often an answer has to be served from the cache to be refreshed before it expires
+    #[arg(long, default_value_t = 3)]
+    pub prefetch_min_hits: u32,
+
+    /// File the cache is restored from on startup and saved to on shutdown, so the relay
+    /// restarts with the answers it had
+    #[arg(long)]
+    pub cache_file: Option<String>,
+
+    /// Hosts file whose names are answered without asking the DNS servers, eg. `/etc/hosts`. It
+    /// is read again whenever it changes
+    #[arg(long)]
+    pub hosts_file: Option<String>,
+
+    /// Record answered locally before the blocklists and the DNS servers are consulted, as
+    /// `name=address`, or as `name=TYPE rdata` for other types, eg. `router.lan=192.168.1.1`,
+    /// `www.lan=CNAME router.lan` or `lan=TXT "hello"`. Names with local records are only
+    /// answered from them. Can be given multiple times
+    #[arg(long)]
+    pub record: Vec<LocalRecord>,
+
+    /// Seconds clients may cache local records
+    #[arg(long, default_value_t = LOCAL_RECORD_TTL)]
+    pub record_ttl: usize,
+
+    /// List of names to block instead of forwarding them, either in hosts file format like
+    /// `0.0.0.0 ads.example.com`, with one domain per line, which also blocks its subdomains, or
+    /// with adblock rules like `||ads.example.com^`. Given as `[response=]path` to answer its
+    /// names differently than `--block-response` says, eg. `refused=malware.txt`. Lines of domain
+    /// lists may name a response after the domain as well. Read again whenever it changes. Lists
+    /// given as `http://` or `https://` URLs are downloaded to `--blocklist-dir` at startup and
+    /// every `--blocklist-refresh-hours`, and the copy kept there is used while they cannot be.
+    /// Can be given multiple times
+    #[arg(long)]
+    pub blocklist: Vec<BlocklistFile>,
+
+    /// Directory keeping the blocklists downloaded from URLs
+    #[arg(long, default_value = "blocklists")]
+    pub blocklist_dir: PathBuf,
+
+    /// Hours between downloads of the blocklists given as URLs. Lists that did not change since
+    /// the last download are not downloaded again
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u64).range(1..))]
+    pub blocklist_refresh_hours: u64,
+
+    /// Regex of names to block, eg. `^ad[0-9]+\.`, matched ignoring case. Can be given multiple
+    /// times
+    #[arg(long)]
+    pub block_regex: Vec<String>,
+
+    /// List of names that are never blocked, whatever the blocklists say, with a rule like
+    /// `--allow` takes per line. Read again whenever it changes. Can be given multiple times
+    #[arg(long)]
+    pub allowlist: Vec<String>,
+
+    /// Name that is never blocked, eg. `cdn.example.com`, or `*.example.com` for its subdomains.
+    /// Followed by networks like `'example.com 192.0.2.0/24 2001:db8::7'` it is only allowed for
+    /// the clients in them. Can be given multiple times
+    #[arg(long)]
+    pub allow: Vec<String>,
+
+    /// How queries for blocked names are answered: nxdomain, nodata, null for `0.0.0.0` and
+    /// `::`, refused, or the addresses of a landing page like `192.0.2.80,2001:db8::80`
+    #[arg(long, default_value = "nxdomain")]
+    pub block_response: BlockResponse,
+
+    /// Seconds clients may cache the responses to blocked names
+    #[arg(long, default_value_t = BLOCKED_TTL)]
+    pub block_ttl: usize,
+
+    /// Zone file whose names are answered authoritatively, as `origin=path`, eg.
+    /// `home.arpa=/etc/zones/home.arpa.zone`. Can be given multiple times
+    #[arg(long)]
+    pub zone: Vec<String>,
+
+    /// Address of a secondary allowed to transfer the zones with AXFR over TCP. Can be given
+    /// multiple times
+    #[arg(long)]
+    pub allow_transfer: Vec<IpAddr>,
+
+    /// TSIG key as `[algorithm:]name:secret` with the secret in base64, like `dig -y` takes it.
+    /// Secondaries signing their transfer requests with it may transfer the zones from any
+    /// address. Can be given multiple times
+    #[arg(long)]
+    pub transfer_key: Vec<String>,
+
+    /// Zone to transfer from its primary and answer authoritatively, as `origin=address`, eg.
+    /// `example.lan=192.0.2.53:53`. The serial of the primary is checked as often as the SOA
+    /// record of the zone says, or right away when the primary sends a NOTIFY, and the zone is
+    /// transferred again once the serial increased. Transfers are signed with the first
+    /// `--transfer-key`, if any. Can be given multiple times
+    #[arg(long)]
+    pub secondary: Vec<String>,
+
+    /// Address of a secondary that is sent a NOTIFY whenever a zone file changes, so it
+    /// transfers the zone right away. Can be given multiple times
+    #[arg(long)]
+    pub notify: Vec<String>,
+
+    /// Address of a client allowed to change the zone files' zones with DNS UPDATE. Updated
+    /// records are kept in memory, and lost when the zone file is read again. Can be given
+    /// multiple times
+    #[arg(long)]
+    pub allow_update: Vec<IpAddr>,
+
+    /// TSIG key as `[algorithm:]name:secret`, see `--transfer-key`. Clients signing their
+    /// updates with it may update the zones from any address. Can be given multiple times
+    #[arg(long)]
+    pub update_key: Vec<String>,
+
+    /// Directory to keep DNSSEC keys in, which signs the zones of `--zone` with them. The keys of
+    /// a zone are kept in `<origin>.keys`, and generated if there are none yet. The DS records
+    /// to publish at the parent zone are printed whenever the keys change
+    #[arg(long)]
+    pub dnssec_keys: Option<PathBuf>,
+
+    /// Algorithm of new DNSSEC keys: ECDSAP256SHA256, ECDSAP384SHA384 or ED25519
+    #[arg(long, default_value = "ECDSAP256SHA256")]
+    pub dnssec_algorithm: Algorithm,
+
+    /// How signed zones prove that names do not exist: nsec, or nsec3 to hash the names
+    #[arg(long, default_value = "nsec")]
+    pub dnssec_denial: Denial,
 
-    /// Port to listen on
-    #[arg(long, default_value_t = String::from("0.0.0.0"))]
-    pub bind_address: String,
+    /// Days DNSSEC signatures are valid for, they are renewed once a quarter of that has passed
+    #[arg(long, default_value_t = 14)]
+    pub dnssec_signature_validity_days: u64,
 
-    /// Port to listen on
+    /// Days a zone signing key signs before it is replaced, 0 to keep it forever
+    #[arg(long, default_value_t = 90)]
+    pub dnssec_zsk_lifetime_days: u64,
+
+    /// Days a key signing key signs before it is replaced, 0 to keep it forever. The DS record of
+    /// the new key has to be published at the parent zone before the old key retires
+    #[arg(long, default_value_t = 0)]
+    pub dnssec_ksk_lifetime_days: u64,
+
+    /// Addresses to listen on, as `[PROTOCOLS://]ADDRESS[?SETTINGS]`. Can be given multiple
+    /// times, eg. `0.0.0.0:53`, `[::]:53` or `udp://192.168.1.1:5353`. Listens over UDP and TCP
+    /// unless the protocols are given as `udp`, `tcp`, `tls` for DNS over TLS on port 853,
+    /// `https` for DNS over HTTPS on port 443 or, with the `doq` feature, `quic` for DNS over
+    /// QUIC on port 853. All but UDP listeners take `max-connections` and `idle-timeout-secs`
+    /// settings like `tcp://0.0.0.0:53?max-connections=20`, TLS, HTTPS and QUIC ones `cert`,
+    /// `key` and `client-ca`. On Linux, sockets for the same address passed by a systemd socket
+    /// unit are listened on instead of binding new ones. Replaces `--bind-address` and
+    /// `--bind-port`
+    #[arg(short, long)]
+    pub listen: Vec<Listener>,
+
+    /// Address to listen on over UDP and TCP, unless `--listen` is given
+    #[arg(long, default_value_t = IpAddr::from([0, 0, 0, 0]))]
+    pub bind_address: IpAddr,
+
+    /// Port to listen on, unless `--listen` is given
     #[arg(long, default_value_t = 53000)]
     pub bind_port: u16,
 
+    /// Number of TCP connections open at the same time, further ones are closed right away.
+    /// Applies to each TCP listener separately
+    #[arg(long, default_value_t = 150)]
+    pub tcp_max_connections: usize,
+
+    /// Seconds a TCP connection stays open without the client sending a query
+    #[arg(long, default_value_t = 10)]
+    pub tcp_idle_timeout_secs: u64,
+
+    /// Number of tasks receiving on each UDP listener, each with a socket of its own on Unix so
+    /// the kernel spreads the queries across cores. Defaults to half the cores
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub udp_workers: Option<u16>,
+
+    /// Number of queries answered at the same time across all listeners. Further queries wait
+    /// until one of them is answered, so queries to a slow DNS server cannot pile up without
+    /// bound
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrent_queries: u32,
+
+    /// Queries a second each client may send, 0 for any number. IPv6 clients are limited by
+    /// their /64. Protects the server from floods and from being used to flood others with
+    /// responses to queries sent from their addresses
+    #[arg(long, default_value_t = 0)]
+    pub rate_limit_qps: u32,
+
+    /// Queries a client may send at once before `--rate-limit-qps` applies. Defaults to a
+    /// second's worth
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit_burst: Option<u32>,
+
+    /// What clients over the rate limit get: refused, a response no larger than the query, or
+    /// drop for none at all
+    #[arg(long, default_value = "refused")]
+    pub rate_limit_action: RateLimitAction,
+
+    /// Responses a second sent over UDP to a network for the same name and type, or NXDOMAIN or
+    /// errors for any name, 0 for any number. Once over the limit, responses are slipped or
+    /// dropped, so the server cannot be used to reflect floods at forged addresses
+    #[arg(long, default_value_t = 0)]
+    pub rrl_responses_per_second: u32,
+
+    /// Seconds the responses to a network are accounted for over, a network over the limit is
+    /// limited until it stayed below it for as long
+    #[arg(long, default_value_t = 15, value_parser = clap::value_parser!(u64).range(1..))]
+    pub rrl_window_secs: u64,
+
+    /// Every how many responses over the limit one is sent truncated, for real clients to retry
+    /// over TCP, the others are dropped. 0 to drop all of them, 1 to truncate all of them
+    #[arg(long, default_value_t = 2)]
+    pub rrl_slip: u32,
+
+    /// Length of the IPv4 networks accounted for as one
+    #[arg(long, default_value_t = 24, value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub rrl_ipv4_prefix_len: u8,
+
+    /// Length of the IPv6 networks accounted for as one
+    #[arg(long, default_value_t = 56, value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub rrl_ipv6_prefix_len: u8,
+
+    /// Seconds to wait for the queries being answered when shutting down on SIGTERM or ctrl-c,
+    /// before exiting anyway
+    #[arg(long, default_value_t = 5)]
+    pub shutdown_timeout_secs: u64,
+
+    /// PEM file with the certificate chain presented to DNS over TLS, HTTPS and QUIC clients,
+    /// unless a listener sets its own as `cert`
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of `--tls-cert`, unless a listener sets its own as `key`
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file with the certificate authorities DNS over TLS, HTTPS and QUIC clients need a
+    /// certificate of to connect, unless a listener sets its own as `client-ca`. Any client may
+    /// connect without it
+    #[arg(long)]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// User to run as once the listeners are bound, by name or id, if started as root. The
+    /// server keeps running as root without it
+    #[arg(long)]
+    pub user: Option<String>,
+
+    /// Group to run as with `--user`, by name or id. The group of the user if not given
+    #[arg(long)]
+    pub group: Option<String>,
+
+    /// Directory to confine the server to with `--user`. Files opened once the listeners are
+    /// bound are then looked up in it, like `--cache-file`, `--recording-folder`, the DNSSEC
+    /// keys and the files read again on SIGHUP
+    #[arg(long)]
+    pub chroot: Option<PathBuf>,
+
     /// Whether benchmark mode is enabled, ie. if forwarding should be skipped and to avoid network calls upstream
     #[arg(short, long, default_value_t = false)]
     pub benchmark: bool,
@@ -28,13 +342,596 @@ pub struct ServerArgs {
     #[arg(short, long)]
     pub recording_folder: Option<String>,
 
+    /// File every query answered is logged to with its client, name, type, response code,
+    /// whether it was blocked or cached, and how long answering it took, one JSON object per
+    /// line. It is rotated next to it as `<file>.<milliseconds since 1970>`
+    #[arg(long)]
+    pub query_log_file: Option<PathBuf>,
+
+    /// Size in megabytes the query log is rotated at, 0 for any size
+    #[arg(long, default_value_t = 100)]
+    pub query_log_max_size_mb: u64,
+
+    /// Hours the query log is rotated after, 0 to only rotate it by size
+    #[arg(long, default_value_t = 24)]
+    pub query_log_rotate_hours: u64,
+
+    /// Most rotated query logs kept, the oldest are deleted first
+    #[arg(long, default_value_t = 7)]
+    pub query_log_keep_files: usize,
+
+    /// Days rotated query logs are deleted after, 0 to only limit how many are kept
+    #[arg(long, default_value_t = 0)]
+    pub query_log_keep_days: u64,
+
+    /// Address to serve `/metrics` on over plain HTTP for Prometheus to scrape, along with a
+    /// dashboard at `/` and a JSON API under `/api` to flush the cache, block names and pause
+    /// blocking. Anyone who can connect to it can use them, so it is best kept to localhost or a
+    /// private network
+    #[arg(long)]
+    pub admin_listen: Option<SocketAddr>,
+
+    /// Unix socket to answer the admin API on, for `ctl` to talk to the server without an HTTP
+    /// port. Only the user and group the server is started as can connect to it
+    #[arg(long)]
+    pub control_socket: Option<PathBuf>,
+
     /// Whether to disable logging
     #[arg(short, long, default_value_t = false)]
     pub quiet: bool,
+
+    /// How to log: text, or json for one object per line with the fields of each event, like
+    /// the id, name, type and client of the query it is about
+    #[arg(long, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Least severe events logged: error, warn, info, debug or trace. Debug also logs how each
+    /// upstream answered and how quickly
+    #[arg(long, default_value = "info")]
+    pub log_level: Level,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Checks a configuration file and the files it refers to without starting the server,
+    /// printing every problem found. Exits with 1 if there are any
+    CheckConfig {
+        /// Configuration file in TOML, see `--config`
+        file: PathBuf,
+    },
+    /// Asks the server running with `--control-socket` to do something, like flushing the cache,
+    /// reloading or blocking a name, and prints what it answered as JSON. Exits with 1 if it
+    /// could not. Give `--control-socket`, or `--config` with the file the server runs with
+    Ctl {
+        #[command(subcommand)]
+        action: Action,
+    },
+}
+
+/// What `ctl` asks the server to do
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Prints the metrics as JSON
+    Stats,
+    /// Prints how large the cache is
+    Cache,
+    /// Empties the cache
+    FlushCache,
+    /// Reads the configuration file, hosts file, blocklists and zones again, like SIGHUP
+    Reload,
+    /// Prints the names blocked and allowed with `block` and `allow`, and whether blocking is
+    /// paused
+    Blocklist,
+    /// Blocks a name and its subdomains right away, until the server restarts or it is unblocked
+    Block { name: String },
+    /// Removes a name blocked with `block`
+    Unblock { name: String },
+    /// Allows a name through the blocklist right away, until the server restarts or it is
+    /// unallowed
+    Allow { name: String },
+    /// Removes a name allowed with `allow`
+    Unallow { name: String },
+    /// Stops blocking, for a while or until resumed
+    Pause {
+        /// Seconds to stop blocking for, until resumed without
+        #[arg(long)]
+        seconds: Option<u64>,
+    },
+    /// Blocks names again after `pause`
+    Resume,
 }
 
 impl ServerArgs {
+    /// Parses the command line, after the settings of the configuration file given with
+    /// `--config`. Exits printing every problem with the file if it is invalid.
     pub fn from_env() -> Self {
-        Self::parse()
+        let args = Self::parse();
+        if args.config.is_none() {
+            return args;
+        }
+        match Self::from_command_line(std::env::args_os()) {
+            Ok(args) => args,
+            Err(problems) => {
+                for problem in problems {
+                    eprintln!("{problem}");
+                }
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Parses `command_line` like [`ServerArgs::from_env`], returning every problem with it or
+    /// the configuration file instead of exiting, eg. to read the file again on SIGHUP
+    pub fn from_command_line(
+        command_line: impl IntoIterator<Item = OsString>,
+    ) -> Result<Self, Vec<String>> {
+        let mut command_line = command_line.into_iter();
+        let binary = command_line.next().unwrap_or_default();
+        let command_line: Vec<_> = command_line.collect();
+        let args = Self::try_parse_from(std::iter::once(&binary).chain(&command_line))
+            .map_err(|e| vec![e.to_string().trim_end().to_string()])?;
+        let Some(path) = &args.config else {
+            return Ok(args);
+        };
+        let settings = config::load(path).map_err(|errors| {
+            let errors = errors.into_iter();
+            errors
+                .map(|e| format!("{}: {e}", path.display()))
+                .collect::<Vec<_>>()
+        })?;
+        let settings = settings.into_iter().map(OsString::from);
+        let arguments = std::iter::once(binary).chain(settings).chain(command_line);
+        Self::try_parse_from(arguments).map_err(|e| vec![e.to_string().trim_end().to_string()])
+    }
+
+    /// The `--listen` addresses, or else `--bind-address` and `--bind-port`
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        vec![Listener::new(SocketAddr::new(
+            self.bind_address,
+            self.bind_port,
+        ))]
+    }
+
+    pub fn upstreams(&self) -> UpstreamPool {
+        let strategy = if self.strict_order {
+            Strategy::StrictOrder
+        } else {
+            Strategy::Fastest
+        };
+        UpstreamPool::new(&self.dns_relay).strategy(strategy)
+    }
+
+    /// A new cache sized by `--cache-size`
+    pub fn cache(&self) -> DnsCache {
+        let mut cache = DnsCache::new()
+            .max_entries(self.cache_size)
+            .serve_stale(Duration::from_secs(self.serve_stale_secs));
+        if self.prefetch_percent > 0 {
+            cache = cache.prefetch(self.prefetch_min_hits, self.prefetch_percent);
+        }
+        cache
+    }
+
+    /// Reads the hosts file. `None` if no hosts file was given or it could not be read.
+    pub fn hosts(&self) -> Option<HostsFile> {
+        let path = self.hosts_file.as_ref()?;
+        match HostsFile::load(path) {
+            Ok(hosts) => Some(hosts),
+            Err(e) => {
+                warn!("Could not read hosts file {path}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Opens the query log. `None` if no query log was given or it could not be opened.
+    pub fn query_log(&self) -> Option<QueryLog> {
+        let path = self.query_log_file.as_ref()?;
+        const MB: u64 = 1024 * 1024;
+        const HOUR: u64 = 60 * 60;
+        let rotation = Rotation {
+            max_bytes: (self.query_log_max_size_mb > 0).then_some(self.query_log_max_size_mb * MB),
+            max_age: (self.query_log_rotate_hours > 0)
+                .then(|| Duration::from_secs(self.query_log_rotate_hours * HOUR)),
+            keep_files: self.query_log_keep_files,
+            keep_age: (self.query_log_keep_days > 0)
+                .then(|| Duration::from_secs(self.query_log_keep_days * 24 * HOUR)),
+        };
+        match QueryLog::open(path, rotation) {
+            Ok(query_log) => Some(query_log),
+            Err(e) => {
+                warn!("Could not open query log {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// A new rate limiter for all listeners. `None` without `--rate-limit-qps`.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        let qps = self.rate_limit_qps;
+        let burst = self.rate_limit_burst.unwrap_or(qps);
+        (qps > 0).then(|| RateLimiter::new(qps, burst))
+    }
+
+    /// A new response rate limiter for all UDP listeners. `None` without
+    /// `--rrl-responses-per-second`.
+    pub fn response_rate_limiter(&self) -> Option<ResponseRateLimiter> {
+        let settings = RrlSettings {
+            responses_per_second: self.rrl_responses_per_second,
+            window: Duration::from_secs(self.rrl_window_secs),
+            slip: self.rrl_slip,
+            ipv4_prefix_len: self.rrl_ipv4_prefix_len,
+            ipv6_prefix_len: self.rrl_ipv6_prefix_len,
+        };
+        (settings.responses_per_second > 0).then(|| ResponseRateLimiter::new(settings))
+    }
+
+    /// The local records of `--record`. `None` if none were given.
+    pub fn records(&self) -> Option<LocalRecords> {
+        let records = LocalRecords::new(self.record.clone()).ttl(self.record_ttl);
+        (!records.is_empty()).then_some(records)
+    }
+
+    /// The arguments with the secrets of the TSIG keys left out, to be logged
+    pub fn redacted(&self) -> ServerArgs {
+        let redact = |keys: &[String]| -> Vec<String> {
+            (keys.iter())
+                .map(|key| match key.rsplit_once(':') {
+                    Some((name, _)) => format!("{name}:<redacted>"),
+                    None => "<redacted>".to_string(),
+                })
+                .collect()
+        };
+        ServerArgs {
+            transfer_key: redact(&self.transfer_key),
+            update_key: redact(&self.update_key),
+            ..self.clone()
+        }
+    }
+
+    /// Number of tasks receiving on each UDP listener, `--udp-workers` or half the cores
+    pub fn udp_workers(&self) -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        // at least one, or UDP would go unanswered on a single core
+        (self.udp_workers.map(usize::from)).unwrap_or((cores / 2).max(1))
+    }
+
+    /// Reads the blocklists. `None` if no lists were given or one of them could not be read.
+    /// With `--admin-listen` or `--control-socket` there is one even without lists, for names to
+    /// be blocked through the admin API.
+    pub fn blocklist(&self) -> Option<Blocklist> {
+        let lists = !self.blocklist.is_empty() || !self.block_regex.is_empty();
+        let admin = self.admin_listen.is_some() || self.control_socket.is_some();
+        if !lists && !admin {
+            return None;
+        }
+        let files = self
+            .blocklist
+            .iter()
+            .map(|file| file.clone().in_directory(&self.blocklist_dir));
+        let blocklist = Blocklist::load_files(files)
+            .and_then(|blocklist| blocklist.allowlists(&self.allowlist));
+        let mut blocklist = match blocklist {
+            Ok(blocklist) => blocklist.response(self.block_response).ttl(self.block_ttl),
+            Err(e) => {
+                warn!("Could not read blocklists: {e}");
+                return None;
+            }
+        };
+        for regex in &self.block_regex {
+            if let Err(e) = blocklist.add_regex(regex) {
+                warn!("Could not use block regex {regex}: {e}");
+            }
+        }
+        for rule in &self.allow {
+            match rule.parse::<AllowRule>() {
+                Ok(rule) => blocklist.allow(rule),
+                Err(e) => warn!("Could not use allow rule {rule}: {e}"),
+            }
+        }
+        Some(blocklist)
+    }
+
+    /// Reads the zones. Zones that could not be read are left out, and `None` if there are
+    /// none.
+    pub fn zones(&self) -> Option<ZoneSet> {
+        let mut zones: ZoneSet = self
+            .zone
+            .iter()
+            .filter_map(|zone| match load_zone(zone) {
+                Ok(zone) => Some(zone),
+                Err(e) => {
+                    warn!("Could not read zone {zone}: {e}");
+                    None
+                }
+            })
+            .collect();
+        for (origin, _) in self.zone_files() {
+            if let Some(signer) = self.zone_signer(&origin) {
+                zones = zones.signer(signer);
+            }
+        }
+        (!zones.is_empty() || !self.secondary.is_empty()).then(|| {
+            zones
+                .transfer_acl(self.transfer_acl())
+                .update_acl(self.update_acl())
+        })
+    }
+
+    /// The zones to transfer from primaries. Secondaries that cannot be parsed are left out.
+    pub fn secondaries(&self) -> Vec<SecondaryZone> {
+        let key = self.transfer_keys().into_iter().next();
+        self.secondary
+            .iter()
+            .filter_map(|secondary| match parse_secondary(secondary) {
+                Ok(secondary) => Some(match &key {
+                    Some(key) => secondary.key(key.clone()),
+                    None => secondary,
+                }),
+                Err(e) => {
+                    warn!("Could not use secondary zone {secondary}: {e}");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Problems the server would run into with these arguments beyond parsing them, like files
+    /// that cannot be read or values that it would leave out. Nothing is started, and lists
+    /// given as URLs are not downloaded.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for server in &self.dns_relay {
+            if let Err(e) = check_upstream(server) {
+                problems.push(format!("upstream {server}: {e}"));
+            }
+        }
+        for listener in self.listeners() {
+            for &protocol in &listener.protocols {
+                if !matches!(protocol, Protocol::Tls | Protocol::Https | Protocol::Quic) {
+                    continue;
+                }
+                if let Err(e) = listener.tls_config(protocol, self) {
+                    problems.push(format!("listener {listener}: {e}"));
+                }
+            }
+        }
+        if let Some(path) = &self.hosts_file {
+            if let Err(e) = HostsFile::load(path) {
+                problems.push(format!("hosts file {path}: {e}"));
+            }
+        }
+
+        for file in &self.blocklist {
+            let file = file.clone().in_directory(&self.blocklist_dir);
+            if let Err(e) = Blocklist::load_files([file.clone()]) {
+                problems.push(format!("blocklist {}: {e}", file.path.display()));
+            }
+        }
+        for path in &self.allowlist {
+            if let Err(e) = Blocklist::default().allowlists([path]) {
+                problems.push(format!("allowlist {path}: {e}"));
+            }
+        }
+        let mut blocklist = Blocklist::default();
+        for regex in &self.block_regex {
+            if let Err(e) = blocklist.add_regex(regex) {
+                problems.push(format!("block regex {regex}: {e}"));
+            }
+        }
+        for rule in &self.allow {
+            if let Err(e) = rule.parse::<AllowRule>() {
+                problems.push(format!("allow rule {rule}: {e}"));
+            }
+        }
+
+        for zone in &self.zone {
+            if let Err(e) = load_zone(zone) {
+                problems.push(format!("zone {zone}: {e}"));
+            }
+        }
+        for secondary in &self.secondary {
+            if let Err(e) = parse_secondary(secondary) {
+                problems.push(format!("secondary zone {secondary}: {e}"));
+            }
+        }
+        for key in self.transfer_key.iter().chain(&self.update_key) {
+            if let Err(e) = key.parse::<TsigKey>() {
+                // the key itself is left out, it is a secret
+                problems.push(format!("TSIG key: {e}"));
+            }
+        }
+
+        if let Some(directory) = &self.dnssec_keys {
+            if !directory.is_dir() {
+                problems.push(format!("{}: not a directory", directory.display()));
+            }
+            for (origin, _) in self.zone_files() {
+                let path = self.key_file(&origin).unwrap_or_default();
+                match load_keys(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        problems.push(format!("DNSSEC keys {}: {e}", path.display()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        #[cfg(unix)]
+        if let Some(user) = &self.user {
+            if let Err(e) = crate::privileges::identity(user, self.group.as_deref()) {
+                problems.push(format!("user {user}: {e}"));
+            }
+        }
+        if let Some(directory) = &self.chroot {
+            if !directory.is_dir() {
+                problems.push(format!("chroot {}: not a directory", directory.display()));
+            }
+        }
+        if let Some(path) = &self.cache_file {
+            let directory = Path::new(path).parent().unwrap_or(Path::new("."));
+            if !directory.as_os_str().is_empty() && !directory.is_dir() {
+                problems.push(format!("cache file {path}: no directory to keep it in"));
+            }
+        }
+        if let Some(path) = &self.query_log_file {
+            let directory = path.parent().unwrap_or(Path::new("."));
+            if !directory.as_os_str().is_empty() && !directory.is_dir() {
+                let path = path.display();
+                problems.push(format!("query log {path}: no directory to keep it in"));
+            }
+        }
+        problems
+    }
+
+    /// The zone files given as `origin=path`, leaving out the ones that cannot be parsed
+    pub fn zone_files(&self) -> Vec<(DnsName, String)> {
+        self.zone
+            .iter()
+            .filter_map(|zone| parse_zone_arg(zone).ok())
+            .collect()
+    }
+
+    /// The file the DNSSEC keys of the zone `origin` are kept in, if zones are signed
+    pub fn key_file(&self, origin: &DnsName) -> Option<PathBuf> {
+        let directory = self.dnssec_keys.as_ref()?;
+        Some(directory.join(format!("{origin}.keys")))
+    }
+
+    /// The signer of the zone `origin` with its keys, which are generated and saved if there are
+    /// none yet. `None` if zones are not signed, or the keys cannot be read.
+    fn zone_signer(&self, origin: &DnsName) -> Option<ZoneSigner> {
+        let path = self.key_file(origin)?;
+        let keys = match load_keys(&path) {
+            Ok(keys) => keys,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                warn!(
+                    "Could not read DNSSEC keys of {origin} from {}: {e}",
+                    path.display()
+                );
+                return None;
+            }
+        };
+        let signer = ZoneSigner::new(origin.clone(), self.signing_policy(), keys);
+        roll_zone_keys(&signer, &path, SystemTime::now());
+        Some(signer)
+    }
+
+    fn signing_policy(&self) -> SigningPolicy {
+        const DAY: u64 = 24 * 60 * 60;
+        let lifetime = |days: u64| (days > 0).then(|| Duration::from_secs(days * DAY));
+        SigningPolicy {
+            algorithm: self.dnssec_algorithm,
+            denial: self.dnssec_denial.clone(),
+            signature_validity: Duration::from_secs(self.dnssec_signature_validity_days * DAY),
+            ksk_lifetime: lifetime(self.dnssec_ksk_lifetime_days),
+            zsk_lifetime: lifetime(self.dnssec_zsk_lifetime_days),
+            ..SigningPolicy::default()
+        }
+    }
+
+    /// The secondaries allowed to transfer the zones
+    fn transfer_acl(&self) -> Acl {
+        Acl {
+            addresses: self.allow_transfer.clone(),
+            keys: self.transfer_keys(),
+        }
+    }
+
+    /// The clients allowed to update the zones
+    fn update_acl(&self) -> Acl {
+        Acl {
+            addresses: self.allow_update.clone(),
+            keys: parse_keys(&self.update_key, "update"),
+        }
+    }
+
+    /// The keys of `--transfer-key`, leaving out the ones that cannot be parsed
+    fn transfer_keys(&self) -> Vec<TsigKey> {
+        parse_keys(&self.transfer_key, "transfer")
+    }
+}
+
+/// Parses the TSIG `keys` given for `purpose`, leaving out the ones that cannot be parsed
+fn parse_keys(keys: &[String], purpose: &str) -> Vec<TsigKey> {
+    keys.iter()
+        .filter_map(|key| match key.parse::<TsigKey>() {
+            Ok(key) => Some(key),
+            Err(e) => {
+                warn!("Could not use {purpose} key: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Checks that `server` is an upstream that can be queried, without resolving its name
+fn check_upstream(server: &str) -> Result<(), String> {
+    if server.starts_with("https://") {
+        return Ok(());
+    }
+    if server.starts_with("quic://") {
+        if cfg!(feature = "doq") {
+            return Ok(());
+        }
+        return Err("DNS over QUIC needs the doq feature".to_string());
+    }
+    match server.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err("expected host:port, or an https:// or quic:// URL".to_string()),
+    }
+}
+
+/// Reads a zone given as `origin=path`
+fn load_zone(zone: &str) -> Result<Zone, String> {
+    let (origin, path) = parse_zone_arg(zone)?;
+    Zone::load(path, &origin).map_err(|e| e.to_string())
+}
+
+fn parse_zone_arg(zone: &str) -> Result<(DnsName, String), String> {
+    let (origin, path) = zone
+        .split_once('=')
+        .ok_or("expected origin=path, eg. home.arpa=home.arpa.zone")?;
+    let origin = origin.parse().map_err(|e| format!("{e}"))?;
+    Ok((origin, path.to_string()))
+}
+
+/// Parses a secondary zone given as `origin=address`, with port 53 if the address has none
+fn parse_secondary(secondary: &str) -> Result<SecondaryZone, String> {
+    let (origin, primary) = secondary
+        .split_once('=')
+        .ok_or("expected origin=address, eg. example.lan=192.0.2.53")?;
+    let origin = origin.parse().map_err(|e| format!("{e}"))?;
+    let primary = primary
+        .parse::<SocketAddr>()
+        .or_else(|_| primary.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid address {primary}"))?;
+    Ok(SecondaryZone::new(origin, primary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--transfer-key=hmac-sha256:transfer.example:c2VjcmV0",
+            "--update-key=c2VjcmV0",
+        ]);
+        let redacted = server_args.redacted();
+        assert_eq!(
+            redacted.transfer_key,
+            ["hmac-sha256:transfer.example:<redacted>"]
+        );
+        assert_eq!(redacted.update_key, ["<redacted>"]);
+        assert!(!format!("{redacted:?}").contains("c2VjcmV0"));
     }
 }
@@ -0,0 +1,379 @@
+//! Configuration file of the server, in TOML. It groups the command line arguments into tables,
+//! see `config.example.toml`, and stands for them: it is read before the command line, so
+//! arguments given there take precedence, replacing single values and adding to lists.
+
+use std::{error::Error, path::Path};
+
+use clap::{ArgAction, Command, CommandFactory, Parser};
+
+use crate::{
+    cli::ServerArgs,
+    toml::{self, Table, Value},
+};
+
+/// Keys of the configuration file as `table.key`, and the arguments they stand for
+const SETTINGS: &[(&str, &str)] = &[
+    ("listen.sockets", "listen"),
+    ("listen.address", "bind_address"),
+    ("listen.port", "bind_port"),
+    ("listen.tcp_max_connections", "tcp_max_connections"),
+    ("listen.tcp_idle_timeout_secs", "tcp_idle_timeout_secs"),
+    ("listen.udp_workers", "udp_workers"),
+    ("listen.max_concurrent_queries", "max_concurrent_queries"),
+    ("listen.shutdown_timeout_secs", "shutdown_timeout_secs"),
+    ("rate_limit.qps", "rate_limit_qps"),
+    ("rate_limit.burst", "rate_limit_burst"),
+    ("rate_limit.action", "rate_limit_action"),
+    ("rrl.responses_per_second", "rrl_responses_per_second"),
+    ("rrl.window_secs", "rrl_window_secs"),
+    ("rrl.slip", "rrl_slip"),
+    ("rrl.ipv4_prefix_len", "rrl_ipv4_prefix_len"),
+    ("rrl.ipv6_prefix_len", "rrl_ipv6_prefix_len"),
+    ("tls.cert", "tls_cert"),
+    ("tls.key", "tls_key"),
+    ("tls.client_ca", "tls_client_ca"),
+    ("privileges.user", "user"),
+    ("privileges.group", "group"),
+    ("privileges.chroot", "chroot"),
+    ("upstreams.servers", "dns_relay"),
+    ("upstreams.strict_order", "strict_order"),
+    ("upstreams.timeout_ms", "relay_timeout_ms"),
+    ("upstreams.retries", "relay_retries"),
+    ("cache.enabled", "no_cache"),
+    ("cache.size", "cache_size"),
+    ("cache.serve_stale_secs", "serve_stale_secs"),
+    ("cache.prefetch_percent", "prefetch_percent"),
+    ("cache.prefetch_min_hits", "prefetch_min_hits"),
+    ("cache.file", "cache_file"),
+    ("hosts.file", "hosts_file"),
+    ("local.records", "record"),
+    ("local.ttl", "record_ttl"),
+    ("blocking.lists", "blocklist"),
+    ("blocking.directory", "blocklist_dir"),
+    ("blocking.refresh_hours", "blocklist_refresh_hours"),
+    ("blocking.regexes", "block_regex"),
+    ("blocking.allowlists", "allowlist"),
+    ("blocking.allow", "allow"),
+    ("blocking.response", "block_response"),
+    ("blocking.ttl", "block_ttl"),
+    ("zones.files", "zone"),
+    ("zones.secondaries", "secondary"),
+    ("zones.notify", "notify"),
+    ("acl.transfer", "allow_transfer"),
+    ("acl.transfer_keys", "transfer_key"),
+    ("acl.update", "allow_update"),
+    ("acl.update_keys", "update_key"),
+    ("dnssec.keys", "dnssec_keys"),
+    ("dnssec.algorithm", "dnssec_algorithm"),
+    ("dnssec.denial", "dnssec_denial"),
+    (
+        "dnssec.signature_validity_days",
+        "dnssec_signature_validity_days",
+    ),
+    ("dnssec.zsk_lifetime_days", "dnssec_zsk_lifetime_days"),
+    ("dnssec.ksk_lifetime_days", "dnssec_ksk_lifetime_days"),
+    ("logging.quiet", "quiet"),
+    ("logging.format", "log_format"),
+    ("logging.level", "log_level"),
+    ("logging.recording_folder", "recording_folder"),
+    ("query_log.file", "query_log_file"),
+    ("query_log.max_size_mb", "query_log_max_size_mb"),
+    ("query_log.rotate_hours", "query_log_rotate_hours"),
+    ("query_log.keep_files", "query_log_keep_files"),
+    ("query_log.keep_days", "query_log_keep_days"),
+    ("admin.listen", "admin_listen"),
+    ("admin.control_socket", "control_socket"),
+    ("benchmark.enabled", "benchmark"),
+    ("benchmark.resolution_delay_ms", "resolution_delay_ms"),
+];
+
+/// Keys whose flag is given when they are false
+const NEGATED: &[&str] = &["cache.enabled"];
+
+/// Reads the configuration file at `path` into the command line arguments it stands for, see
+/// [`arguments`]
+pub fn load(path: &Path) -> Result<Vec<String>, Vec<String>> {
+    let text = std::fs::read_to_string(path).map_err(|e| vec![e.to_string()])?;
+    arguments(&text)
+}
+
+/// Checks the configuration file at `path` and the files it refers to, see
+/// [`ServerArgs::problems`], returning every problem found
+pub fn check(path: &Path) -> Vec<String> {
+    let arguments = match load(path) {
+        Ok(arguments) => arguments,
+        Err(errors) => return errors,
+    };
+    let name = ServerArgs::command().get_name().to_string();
+    match ServerArgs::try_parse_from(std::iter::once(name).chain(arguments)) {
+        Ok(args) => args.problems(),
+        Err(e) => vec![e.to_string()],
+    }
+}
+
+/// Converts a configuration file into the command line arguments it stands for, validated as
+/// the command line would be. Otherwise returns every problem found, each naming the line and
+/// the key it is about.
+pub fn arguments(text: &str) -> Result<Vec<String>, Vec<String>> {
+    let document = toml::parse(text).map_err(|e| vec![e])?;
+    let command = ServerArgs::command();
+    let mut arguments = vec![];
+    let mut errors = vec![];
+    for table in &document.entries {
+        let Value::Table(entries) = &table.value else {
+            let error = format!("expected a table, not {}", table.value.type_name());
+            errors.push(format!("line {}: `{}`: {error}", table.line, table.key));
+            continue;
+        };
+        for entry in &entries.entries {
+            let key = format!("{}.{}", table.key, entry.key);
+            // the pairs of tables are checked one at a time, so errors name their line
+            let values = match &entry.value {
+                Value::Table(pairs) => (pairs.entries.iter())
+                    .map(|pair| {
+                        let name = format!("{key}.{}", pair.key);
+                        let entries = vec![pair.clone()];
+                        (name, pair.line, Value::Table(Table { entries }))
+                    })
+                    .collect(),
+                value => vec![(key.clone(), entry.line, value.clone())],
+            };
+            for (name, line, value) in values {
+                match argument(&command, &key, &value) {
+                    Ok(argument) => arguments.extend(argument),
+                    Err(e) => errors.push(format!("line {line}: `{name}`: {e}")),
+                }
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(arguments)
+}
+
+/// The command line arguments standing for `key` set to `value`
+fn argument(command: &Command, key: &str, value: &Value) -> Result<Vec<String>, String> {
+    let Some(&(_, id)) = SETTINGS.iter().find(|(setting, _)| *setting == key) else {
+        return Err("unknown key".to_string());
+    };
+    let arg = (command.get_arguments())
+        .find(|arg| arg.get_id() == id)
+        .expect("settings stand for arguments");
+    let flag = format!("--{}", arg.get_long().expect("arguments have long flags"));
+
+    let arguments = match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(set)) if *set != NEGATED.contains(&key) => vec![flag],
+        (ArgAction::SetTrue, Value::Boolean(_)) => vec![],
+        (ArgAction::SetTrue, value) => {
+            return Err(format!("expected a boolean, not {}", value.type_name()))
+        }
+        (ArgAction::Append, Value::Array(values)) => (values.iter())
+            .map(|value| Ok(format!("{flag}={}", scalar(value)?)))
+            .collect::<Result<_, String>>()?,
+        // tables of lists stand for `key=value` pairs, like `--record` and `--zone` take
+        (ArgAction::Append, Value::Table(table)) => {
+            let mut arguments = vec![];
+            for entry in &table.entries {
+                let values = match &entry.value {
+                    Value::Array(values) => values.iter().collect(),
+                    value => vec![value],
+                };
+                for value in values {
+                    arguments.push(format!("{flag}={}={}", entry.key, scalar(value)?));
+                }
+            }
+            arguments
+        }
+        (_, value) => vec![format!("{flag}={}", scalar(value)?)],
+    };
+
+    // checking the arguments of each key on their own lets errors name the key
+    let name = command.get_name().to_string();
+    let command_line = std::iter::once(name).chain(arguments.iter().cloned());
+    if let Err(e) = command.clone().try_get_matches_from(command_line) {
+        return Err(match e.source() {
+            Some(source) => source.to_string(),
+            None => (e.to_string().lines().next().unwrap_or_default())
+                .trim_start_matches("error: ")
+                .to_string(),
+        });
+    }
+    Ok(arguments)
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string.clone()),
+        Value::Integer(integer) => Ok(integer.to_string()),
+        Value::Boolean(boolean) => Ok(boolean.to_string()),
+        _ => Err(format!(
+            "expected a single value, not {}",
+            value.type_name()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_stand_for_arguments() {
+        let command = ServerArgs::command();
+        for (key, id) in SETTINGS {
+            let arg = command.get_arguments().find(|arg| arg.get_id() == id);
+            assert!(arg.is_some_and(|arg| arg.get_long().is_some()), "{key}");
+        }
+    }
+
+    #[test]
+    fn test_config_arguments() {
+        let arguments = arguments(
+            r#"
+[listen]
+port = 53
+
+[upstreams]
+servers = ["9.9.9.9:53", "https://dns.quad9.net/dns-query"]
+strict_order = true
+
+[cache]
+enabled = false
+
+[local.records]
+"router.lan" = "192.168.1.1"
+"nas.lan" = ["192.168.1.2", "CNAME router.lan"]
+
+[blocking]
+lists = ["ads.txt"]
+response = "refused"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            arguments,
+            [
+                "--bind-port=53",
+                "--dns-relay=9.9.9.9:53",
+                "--dns-relay=https://dns.quad9.net/dns-query",
+                "--strict-order",
+                "--no-cache",
+                "--record=router.lan=192.168.1.1",
+                "--record=nas.lan=192.168.1.2",
+                "--record=nas.lan=CNAME router.lan",
+                "--blocklist=ads.txt",
+                "--block-response=refused",
+            ]
+        );
+
+        // the command line comes after the file and takes precedence
+        let command_line = std::iter::once("dns-block-tokio".to_string())
+            .chain(arguments)
+            .chain(["--bind-port=5353", "--blocklist=more.txt"].map(String::from));
+        let args = ServerArgs::parse_from(command_line);
+        assert_eq!(args.bind_port, 5353);
+        assert_eq!(args.dns_relay.len(), 2);
+        assert_eq!(args.blocklist.len(), 2);
+        assert!(args.no_cache && args.strict_order);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let errors = arguments(
+            r#"
+port = 53
+
+[listen]
+port = 70000
+address = ["::", "0.0.0.0"]
+
+[upstreams]
+servers = "9.9.9.9:53"
+strict_order = "yes"
+retry = 3
+
+[blocking]
+response = "maybe"
+
+[local.records]
+"router.lan" = "router"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 7, "{errors:#?}");
+        assert_eq!(
+            errors[0],
+            "line 2: `port`: expected a table, not an integer"
+        );
+        assert!(errors[1].starts_with("line 5: `listen.port`: 70000 is not in"));
+        assert_eq!(
+            errors[2],
+            "line 6: `listen.address`: expected a single value, not an array"
+        );
+        assert_eq!(
+            errors[3],
+            "line 10: `upstreams.strict_order`: expected a boolean, not a string"
+        );
+        assert_eq!(errors[4], "line 11: `upstreams.retry`: unknown key");
+        assert!(errors[5].starts_with("line 14: `blocking.response`: "));
+        assert!(errors[6].starts_with("line 17: `local.records.router.lan`: "));
+
+        let errors = arguments("[listen\nport = 53").unwrap_err();
+        assert_eq!(errors, ["line 1: expected `]` after the table name"]);
+    }
+
+    #[test]
+    fn test_check_config() {
+        let directory = std::env::temp_dir().join(format!("check-config-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let blocklist = directory.join("ads.txt");
+        std::fs::write(&blocklist, "ads.example.com\n").unwrap();
+        let config = directory.join("config.toml");
+        let missing = directory.join("missing.txt");
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+[upstreams]
+servers = ["9.9.9.9:53", "dns.example"]
+
+[blocking]
+lists = ["{}", "{}", "https://example.com/hosts"]
+regexes = ["(unclosed"]
+directory = "{}"
+
+[zones]
+files = ["home.arpa={}"]
+"#,
+                blocklist.display(),
+                missing.display(),
+                directory.display(),
+                missing.display(),
+            ),
+        )
+        .unwrap();
+
+        let problems = check(&config);
+        assert_eq!(problems.len(), 4, "{problems:#?}");
+        assert!(problems[0].starts_with("upstream dns.example: expected host:port"));
+        assert!(problems[1].starts_with(&format!("blocklist {}: ", missing.display())));
+        assert!(problems[2].starts_with("block regex (unclosed: "));
+        assert!(problems[3].starts_with(&format!("zone home.arpa={}: ", missing.display())));
+
+        std::fs::write(&config, "[listen]\nport = \"fifty-three\"\n").unwrap();
+        let problems = check(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("line 2: `listen.port`: "));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_example_config() {
+        let arguments = arguments(include_str!("../config.example.toml")).unwrap();
+        let command_line = std::iter::once("dns-block-tokio".to_string()).chain(arguments);
+        let args = ServerArgs::try_parse_from(command_line).unwrap();
+        let listeners: Vec<_> = args.listeners().iter().map(|l| l.to_string()).collect();
+        assert_eq!(listeners, ["udp+tcp://0.0.0.0:53", "udp+tcp://[::]:53"]);
+    }
+}
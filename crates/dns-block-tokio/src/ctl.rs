@@ -0,0 +1,146 @@
+//! Controlling the running server from the command line with `dns-block-tokio ctl`, over the
+//! unix socket given with `--control-socket`. The server answers on it with the admin API of
+//! [`crate::admin`], which the actions are requests to, so scripts can flush the cache, reload or
+//! block a name without an HTTP port being open. Only the owner and group of the socket can
+//! connect to it.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::cli::Action;
+
+impl Action {
+    /// The method and target of the admin API request for the action
+    fn request(&self) -> (&'static str, String) {
+        match self {
+            Action::Stats => ("GET", "/api/stats".to_string()),
+            Action::Cache => ("GET", "/api/cache".to_string()),
+            Action::FlushCache => ("DELETE", "/api/cache".to_string()),
+            Action::Reload => ("POST", "/api/reload".to_string()),
+            Action::Blocklist => ("GET", "/api/blocklist".to_string()),
+            Action::Block { name } => ("PUT", format!("/api/blocklist/blocked/{name}")),
+            Action::Unblock { name } => ("DELETE", format!("/api/blocklist/blocked/{name}")),
+            Action::Allow { name } => ("PUT", format!("/api/blocklist/allowed/{name}")),
+            Action::Unallow { name } => ("DELETE", format!("/api/blocklist/allowed/{name}")),
+            Action::Pause { seconds: None } => ("POST", "/api/blocking/pause".to_string()),
+            Action::Pause {
+                seconds: Some(seconds),
+            } => ("POST", format!("/api/blocking/pause?seconds={seconds}")),
+            Action::Resume => ("POST", "/api/blocking/resume".to_string()),
+        }
+    }
+}
+
+/// Binds the control socket at `path`, replacing the socket of an earlier run but no other file,
+/// and lets only its owner and group connect
+pub fn bind_control_socket(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(ErrorKind::AlreadyExists, "not a socket")),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+/// Asks the server listening on `socket` to carry out `action`, returning what it answered as
+/// indented JSON, or the error it answered with
+pub async fn run(socket: &Path, action: &Action) -> Result<String, String> {
+    let (method, target) = action.request();
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| format!("Could not connect to {}: {e}", socket.display()))?;
+    let request = format!(
+        "{method} {target} HTTP/1.1\r\nhost: localhost\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+    );
+    let mut response = vec![];
+    let exchanged = match stream.write_all(request.as_bytes()).await {
+        Ok(()) => stream.read_to_end(&mut response).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    exchanged.map_err(|e| format!("Could not talk to the server: {e}"))?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = (response.split_once("\r\n\r\n")).ok_or("Incomplete response")?;
+    let status: u16 = (head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("Invalid response")?;
+    let body: Value = serde_json::from_str(body).map_err(|e| format!("Invalid response: {e}"))?;
+    match status {
+        200..=299 => Ok(serde_json::to_string_pretty(&body).unwrap_or_default()),
+        _ => Err(match body["error"].as_str() {
+            Some(error) => format!("The server answered {status}: {error}"),
+            None => format!("The server answered {status}"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        admin::serve_control,
+        cli::ServerArgs,
+        state::{Server, State},
+    };
+
+    #[test]
+    fn test_request() {
+        let action = Action::Block {
+            name: "ads.example".to_string(),
+        };
+        assert_eq!(
+            action.request(),
+            ("PUT", "/api/blocklist/blocked/ads.example".to_string())
+        );
+        let action = Action::Pause { seconds: Some(60) };
+        assert_eq!(
+            action.request(),
+            ("POST", "/api/blocking/pause?seconds=60".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let path = std::env::temp_dir().join(format!("ctl-{}.sock", std::process::id()));
+        fs::write(&path, "").unwrap();
+        assert!(bind_control_socket(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        // a socket left behind is replaced
+        drop(bind_control_socket(&path).unwrap());
+        let listener = bind_control_socket(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let server_args = ServerArgs::parse_from(["dns-block-tokio", "--quiet"]);
+        let idle_timeout = Duration::from_secs(5);
+        let server = Arc::new(Server::new(State::new(server_args)));
+        tokio::spawn(serve_control(listener, idle_timeout, server));
+
+        let stats: Value =
+            serde_json::from_str(&run(&path, &Action::Stats).await.unwrap()).unwrap();
+        assert!(stats["queries"].is_u64());
+        let unblock = Action::Unblock {
+            name: "ads.ctl.example".to_string(),
+        };
+        let error = run(&path, &unblock).await.unwrap_err();
+        assert!(error.starts_with("The server answered 404"), "{error}");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! A dashboard served at `/` on the admin address, for looking into the server from a browser
+//! without setting up Prometheus: the latest queries, the names blocked and the clients querying
+//! the most, and the state of the cache and the upstreams. The page is static and refreshes itself
+//! from `/api/dashboard`, which the queries are tracked for only while there is an admin address.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::query_log::Entry;
+
+/// The dashboard page, with its styles and script inline
+pub const PAGE: &str = include_str!("dashboard.html");
+
+/// Latest queries kept
+const RECENT: usize = 100;
+
+/// Most names or clients counted, further ones make room by decaying the counts
+const MAX_TRACKED: usize = 10_000;
+
+/// Names and clients listed
+const TOP: usize = 10;
+
+/// The queries answered lately, for the dashboard
+#[derive(Debug, Default)]
+pub struct Activity {
+    recent: Mutex<VecDeque<Entry>>,
+    blocked: Mutex<Counts<String>>,
+    clients: Mutex<Counts<IpAddr>>,
+}
+
+impl Activity {
+    pub fn record(&self, entry: &Entry) {
+        {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() == RECENT {
+                recent.pop_front();
+            }
+            recent.push_back(entry.clone());
+        }
+        if entry.blocked {
+            let name = entry.name.to_ascii_lowercase();
+            self.blocked.lock().unwrap().add(name);
+        }
+        self.clients.lock().unwrap().add(entry.client.ip());
+    }
+
+    /// The latest queries, newest first, and the names blocked and clients querying the most
+    pub fn to_json(&self) -> Value {
+        let recent: Vec<_> = (self.recent.lock().unwrap().iter().rev())
+            .map(|entry| {
+                let fields = entry.fields().into_iter();
+                Value::Object(
+                    fields
+                        .map(|(name, value)| (name.to_string(), value))
+                        .collect(),
+                )
+            })
+            .collect();
+        let top = |top: Vec<(String, u64)>, key: &str| -> Vec<_> {
+            (top.into_iter())
+                .map(|(name, queries)| {
+                    let mut object = Map::new();
+                    object.insert(key.to_string(), name.into());
+                    object.insert("queries".to_string(), queries.into());
+                    Value::Object(object)
+                })
+                .collect()
+        };
+        let blocked = self.blocked.lock().unwrap().top(TOP);
+        let clients = (self.clients.lock().unwrap().top(TOP).into_iter())
+            .map(|(client, queries)| (client.to_string(), queries))
+            .collect();
+        json!({
+            "recent": recent,
+            "top_blocked": top(blocked, "name"),
+            "top_clients": top(clients, "client"),
+        })
+    }
+}
+
+/// How often each key was seen, approximately once there are too many keys
+#[derive(Debug)]
+struct Counts<K> {
+    counts: HashMap<K, u64>,
+}
+
+impl<K> Default for Counts<K> {
+    fn default() -> Self {
+        Counts {
+            counts: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Counts<K> {
+    fn add(&mut self, key: K) {
+        if self.counts.len() >= MAX_TRACKED && !self.counts.contains_key(&key) {
+            // halving every count forgets the keys seen once, and keeps the frequent ones on top
+            self.counts.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+        }
+        *self.counts.entry(key).or_default() += 1;
+    }
+
+    /// The `n` keys seen the most, most first
+    fn top(&self, n: usize) -> Vec<(K, u64)> {
+        let mut counts: Vec<_> = (self.counts.iter())
+            .map(|(key, &count)| (key.clone(), count))
+            .collect();
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        counts.truncate(n);
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use dns::protocol::{record_type::RecordType, response_code::ResponseCode};
+
+    use super::*;
+    use crate::resolution::Protocol;
+
+    fn entry(client: &str, name: &str, blocked: bool) -> Entry {
+        Entry {
+            time: UNIX_EPOCH + Duration::from_secs(1_730_376_000),
+            client: client.parse().unwrap(),
+            protocol: Protocol::Udp,
+            name: name.to_string(),
+            r#type: RecordType::A,
+            rcode: Some(ResponseCode::NoError),
+            blocked,
+            cached: false,
+            latency: Duration::from_millis(2),
+        }
+    }
+
+    #[test]
+    fn test_activity() {
+        let activity = Activity::default();
+        activity.record(&entry("192.0.2.1:5300", "ADS.example", true));
+        for _ in 0..2 {
+            activity.record(&entry("192.0.2.2:5300", "ads.example", true));
+        }
+        activity.record(&entry("192.0.2.2:5300", "example.com", false));
+        activity.record(&entry("192.0.2.1:5300", "tracker.example", true));
+
+        let json = activity.to_json();
+        assert_eq!(json["recent"].as_array().unwrap().len(), 5);
+        assert_eq!(json["recent"][0]["qname"], "tracker.example");
+        assert_eq!(json["recent"][0]["client"], "192.0.2.1");
+        assert_eq!(
+            json["top_blocked"],
+            json!([
+                {"name": "ads.example", "queries": 3},
+                {"name": "tracker.example", "queries": 1},
+            ])
+        );
+        assert_eq!(
+            json["top_clients"],
+            json!([
+                {"client": "192.0.2.2", "queries": 3},
+                {"client": "192.0.2.1", "queries": 2},
+            ])
+        );
+
+        // only the latest are kept
+        for _ in 0..RECENT {
+            activity.record(&entry("192.0.2.3:5300", "example.org", false));
+        }
+        let json = activity.to_json();
+        assert_eq!(json["recent"].as_array().unwrap().len(), RECENT);
+        assert_eq!(json["top_clients"][0]["client"], "192.0.2.3");
+    }
+
+    #[test]
+    fn test_counts_decay() {
+        let mut counts = Counts::default();
+        for _ in 0..4 {
+            counts.add(u64::MAX);
+        }
+        for key in 0..MAX_TRACKED as u64 {
+            counts.add(key);
+        }
+        // the keys seen once made room, the frequent one stays
+        assert!(counts.counts.len() <= MAX_TRACKED);
+        assert_eq!(counts.top(1), [(u64::MAX, 2)]);
+    }
+}
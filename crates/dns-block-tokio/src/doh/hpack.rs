@@ -0,0 +1,668 @@
+//! HPACK, the compression of HTTP/2 header fields.
+//! https://datatracker.ietf.org/doc/html/rfc7541
+
+use std::{collections::VecDeque, sync::OnceLock};
+
+/// Size of the dynamic table unless the peer allows another one, as the server never does
+const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// Longest header field name or value that is accepted
+const MAX_STRING_LEN: usize = 16 * 1024;
+
+/// Largest header list a block may decode to, with the fields counted as in the dynamic table.
+/// Short references to table entries could otherwise blow up a block many times over.
+pub const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+
+/// A header field, with a lowercase name
+pub type Header = (String, String);
+
+/// Decodes the header blocks of a connection, which refer to the fields of earlier ones
+#[derive(Debug)]
+pub struct Decoder {
+    /// Most recently added entry first
+    table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decodes a complete header block
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Header>, String> {
+        let (mut headers, mut list_size) = (vec![], 0);
+        while let Some(&first) = block.first() {
+            let header = if first & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                self.entry(index)?
+            } else if first & 0xe0 == 0x20 {
+                let size = decode_integer(&mut block, 5)?;
+                if size > DEFAULT_TABLE_SIZE {
+                    return Err(format!("table size {size} exceeds the allowed one"));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // with incremental indexing, without indexing or never indexed
+                let (prefix, indexed) = match first & 0xc0 == 0x40 {
+                    true => (6, true),
+                    false => (4, false),
+                };
+                let index = decode_integer(&mut block, prefix)?;
+                let name = match index {
+                    0 => decode_string(&mut block)?,
+                    index => self.entry(index)?.0,
+                };
+                let value = decode_string(&mut block)?;
+                if indexed {
+                    self.insert((name.clone(), value.clone()));
+                }
+                (name, value)
+            };
+            list_size += entry_size(&header);
+            if list_size > MAX_HEADER_LIST_SIZE {
+                return Err(format!("header list exceeds {MAX_HEADER_LIST_SIZE} bytes"));
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> Result<Header, String> {
+        let entry = match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.to_string(), value.to_string()))
+            }
+            _ => self.table.get(index - 62).cloned(),
+        };
+        entry.ok_or_else(|| format!("invalid table index {index}"))
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = entry_size(&header);
+        self.evict(size);
+        // entries larger than the table empty it without being added
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Evicts the oldest entries until `additional` bytes fit
+    fn evict(&mut self, additional: usize) {
+        while self.size + additional > self.max_size {
+            let Some(header) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&header);
+        }
+    }
+}
+
+fn entry_size((name, value): &Header) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Encodes a header block without the dynamic table, naming fields by their index in the
+/// static table where possible
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = vec![];
+    for &(name, value) in headers {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|entry| *entry == (name, value))
+        {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(entry, _)| *entry == name) {
+            Some(index) => encode_integer(&mut block, 0, 4, index + 1),
+            None => {
+                block.push(0);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+/// Decodes an integer with a `prefix` bit prefix, the bits before which are ignored
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, String> {
+    let truncated = || "truncated header block".to_string();
+    let (&first, rest) = block.split_first().ok_or_else(truncated)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    for shift in (0..28).step_by(7) {
+        let (&byte, rest) = block.split_first().ok_or_else(truncated)?;
+        *block = rest;
+        value += ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("integer in header block too large".to_string())
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<String, String> {
+    let huffman = block.first().is_some_and(|first| first & 0x80 != 0);
+    let len = decode_integer(block, 7)?;
+    if len > MAX_STRING_LEN {
+        return Err(format!("header field of {len} bytes is too long"));
+    }
+    if block.len() < len {
+        return Err("truncated header block".to_string());
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+    let bytes = match huffman {
+        true => huffman_decode(raw)?,
+        false => raw.to_vec(),
+    };
+    String::from_utf8(bytes).map_err(|_| "header field is not UTF-8".to_string())
+}
+
+/// Writes `value` as is, as Huffman coding saves little on the short fields of responses
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_integer(block, 0, 7, value.len());
+    block.extend_from_slice(value.as_bytes());
+}
+
+/// Nodes of the tree of [`HUFFMAN_CODES`], whose two children are either the index of another
+/// node or a symbol, as [`LEAF`] plus the symbol
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0u16; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit in (0..len).rev() {
+                let branch = (code >> bit & 1) as usize;
+                if bit == 0 {
+                    tree[node][branch] = LEAF + symbol as u16;
+                } else if tree[node][branch] == 0 {
+                    tree.push([0; 2]);
+                    tree[node][branch] = tree.len() as u16 - 1;
+                    node = tree.len() - 1;
+                } else {
+                    node = tree[node][branch] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+/// Marks the children of [`huffman_tree`] that are symbols
+const LEAF: u16 = 0x8000;
+
+/// Symbol of the end of string, which must not be encoded
+const EOS: u16 = 256;
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, String> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(raw.len() * 8 / 5);
+    let (mut node, mut depth) = (0, 0);
+    // the padding is the most significant bits of EOS, all ones
+    let mut padding = true;
+    for byte in raw {
+        for bit in (0..8).rev() {
+            let branch = (byte >> bit & 1) as usize;
+            padding &= branch == 1;
+            depth += 1;
+            let child = tree[node][branch];
+            if child & LEAF == 0 {
+                node = child as usize;
+                continue;
+            }
+            if child - LEAF == EOS {
+                return Err("EOS in Huffman coded header field".to_string());
+            }
+            decoded.push((child - LEAF) as u8);
+            (node, depth, padding) = (0, 0, true);
+        }
+    }
+    if depth > 7 || !padding {
+        return Err("invalid padding of Huffman coded header field".to_string());
+    }
+    Ok(decoded)
+}
+
+/// Entries of the static table, index 1 first
+/// https://datatracker.ietf.org/doc/html/rfc7541#appendix-A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code and its length in bits of every octet, followed by the one of EOS
+/// https://datatracker.ietf.org/doc/html/rfc7541#appendix-B
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<Header> {
+        (pairs.iter())
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The requests of https://datatracker.ietf.org/doc/html/rfc7541#appendix-C.4
+    #[test]
+    fn test_decode_huffman_requests() {
+        let mut decoder = Decoder::default();
+        let first = decoder.decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff"));
+        assert_eq!(
+            first.unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = decoder.decode(&hex("828684be5886a8eb10649cbf")).unwrap();
+        assert_eq!(second[3], headers(&[(":authority", "www.example.com")])[0]);
+        assert_eq!(second[4], headers(&[("cache-control", "no-cache")])[0]);
+        let third = decoder.decode(&hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf"));
+        assert_eq!(
+            third.unwrap(),
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_encode() {
+        let fields = [
+            (":status", "200"),
+            (":status", "415"),
+            ("content-type", "application/dns-message"),
+            ("x-long", &"a".repeat(200)),
+        ];
+        let block = encode(&fields);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block).unwrap(), headers(&fields));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.decode(&[0xff, 0x80]).is_err());
+        assert!(decoder.decode(&[0xbe]).is_err());
+        assert!(decoder.decode(&hex("418cf1e3c2e5f23a6ba0ab90f4")).is_err());
+        // padding of more than 7 bits
+        assert!(decoder.decode(&hex("4182f1ff")).is_err());
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f]).is_err());
+
+        // a large entry referred to again and again
+        let mut block = encode(&[("x-large", &"a".repeat(4000))]);
+        block[0] = 0x40;
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(&block).unwrap().len(), 1);
+        assert_eq!(decoder.decode(&[0xbe; 15]).unwrap().len(), 15);
+        assert!(decoder.decode(&[0xbe; 17]).is_err());
+    }
+}
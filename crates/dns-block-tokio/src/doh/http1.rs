@@ -0,0 +1,152 @@
+//! Just enough of HTTP/1.1 for DNS over HTTPS clients without HTTP/2, like the one of the dns
+//! crate. Requests on a connection are answered one after the other.
+//! https://datatracker.ietf.org/doc/html/rfc9112
+
+use std::{io, time::Duration};
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+
+use super::{reason, Request, Respond, Response, MAX_MESSAGE_SIZE};
+
+/// Largest request header section that is accepted
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+pub(super) async fn serve(
+    stream: impl AsyncRead + AsyncWrite,
+    idle_timeout: Duration,
+    endpoint: impl Respond,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = tokio::io::BufReader::new(reader);
+    loop {
+        let request = match tokio::time::timeout(idle_timeout, read_request(&mut reader)).await {
+            Err(_) | Ok(Ok(None)) => return Ok(()),
+            Ok(Ok(Some(request))) => request,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                let response = Response::error(400, &e.to_string());
+                return write_response(&mut writer, &response, true).await;
+            }
+            Ok(Err(e)) => return Err(e),
+        };
+        let close = !request.keep_alive;
+        let response = endpoint.respond(request.request).await;
+        write_response(&mut writer, &response, close).await?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+struct ParsedRequest {
+    request: Request,
+    /// Whether the client keeps the connection open for further requests
+    keep_alive: bool,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the next request, `None` if the connection was closed before it
+async fn read_request(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<ParsedRequest>> {
+    let mut lines = vec![];
+    let mut size = 0;
+    loop {
+        let mut line = vec![];
+        let read = (&mut *reader)
+            .take((MAX_HEADER_SIZE - size) as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        size += read;
+        match line.strip_suffix(b"\n") {
+            _ if read == 0 && lines.is_empty() => return Ok(None),
+            None if size > MAX_HEADER_SIZE => return Err(invalid("header too large")),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Some(line) => {
+                let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line));
+                // empty lines before the request line are ignored
+                // https://datatracker.ietf.org/doc/html/rfc9112#section-2.2
+                match (line.is_empty(), lines.is_empty()) {
+                    (true, true) => continue,
+                    (true, false) => break,
+                    (false, _) => lines.push(line.into_owned()),
+                }
+            }
+        }
+    }
+
+    let mut request_line = lines[0].split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(invalid("malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("not HTTP/1.x"));
+    }
+    let headers: Vec<(String, String)> = (lines[1..].iter())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body: vec![],
+    };
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(invalid("transfer encodings are not supported"));
+    }
+    if let Some(len) = request.header("content-length") {
+        let len: usize = len
+            .parse()
+            .map_err(|_| invalid("malformed Content-Length"))?;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(invalid("body too large"));
+        }
+        request.body = vec![0; len];
+        reader.read_exact(&mut request.body).await?;
+    }
+
+    let connection = request.header("connection").map(str::to_ascii_lowercase);
+    let keep_alive = match version {
+        "HTTP/1.0" => connection.as_deref() == Some("keep-alive"),
+        _ => connection.as_deref() != Some("close"),
+    };
+    Ok(Some(ParsedRequest {
+        request,
+        keep_alive,
+    }))
+}
+
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    response: &Response,
+    close: bool,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("content-length: {}\r\n", response.body.len()));
+    if close {
+        head.push_str("connection: close\r\n");
+    }
+    head.push_str("\r\n");
+
+    let mut message = head.into_bytes();
+    message.extend_from_slice(&response.body);
+    writer.write_all(&message).await?;
+    writer.flush().await
+}
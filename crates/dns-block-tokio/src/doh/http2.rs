@@ -0,0 +1,887 @@
+//! Just enough of HTTP/2 to serve DNS over HTTPS: every stream carries one request and its
+//! response, there is no server push and priorities are ignored. Streams are answered
+//! concurrently, each response is written once it is ready.
+//! https://datatracker.ietf.org/doc/html/rfc9113
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+};
+
+use super::{
+    hpack::{self, Decoder},
+    Endpoint, Request, Respond, Response, MAX_MESSAGE_SIZE,
+};
+
+/// Sent by clients before anything else
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Largest frame payload either side may send, as the server never allows larger ones
+const MAX_FRAME_SIZE: usize = 16_384;
+
+/// Largest frame size a client may allow
+const MAX_ALLOWED_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// Streams a client may have open at the same time
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Largest header block accepted, across its CONTINUATION frames
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+
+/// Frames queued for the writer, beyond which a client that sends faster than it reads is sent
+/// away
+const MAX_QUEUED: usize = 1024;
+
+/// Flow control window of connections and streams until the peer sets another one
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// Largest flow control window
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+// frame types
+// https://datatracker.ietf.org/doc/html/rfc9113#section-6
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// settings
+const MAX_CONCURRENT_STREAMS_SETTING: u16 = 0x3;
+const INITIAL_WINDOW_SIZE_SETTING: u16 = 0x4;
+const MAX_FRAME_SIZE_SETTING: u16 = 0x5;
+const MAX_HEADER_LIST_SIZE_SETTING: u16 = 0x6;
+
+// error codes
+// https://datatracker.ietf.org/doc/html/rfc9113#section-7
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+#[derive(Debug)]
+struct Frame {
+    r#type: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(r#type: u8, flags: u8, stream: u32, payload: Vec<u8>) -> Self {
+        Self {
+            r#type,
+            flags,
+            stream,
+            payload,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9 + self.payload.len());
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes()[1..]);
+        bytes.push(self.r#type);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.stream.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// The payload without its padding, if the frame is of a type that may be padded
+    fn unpadded(&self) -> Result<&[u8], Error> {
+        if self.flags & PADDED == 0 {
+            return Ok(&self.payload);
+        }
+        let (&padding, rest) = (self.payload.split_first()).ok_or(Error(FRAME_SIZE_ERROR))?;
+        let len = (rest.len()).checked_sub(padding as usize);
+        Ok(&rest[..len.ok_or(Error(PROTOCOL_ERROR))?])
+    }
+}
+
+/// A connection error, after which the connection is closed with a GOAWAY frame
+#[derive(Debug)]
+struct Error(u32);
+
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Result<Frame, Error>> {
+    let mut header = [0u8; 9];
+    reader.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Ok(Err(Error(FRAME_SIZE_ERROR)));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+    Ok(Ok(Frame::new(header[3], header[4], stream, payload)))
+}
+
+/// What the task reading the connection hands to the one writing it
+#[derive(Debug)]
+enum Outgoing {
+    /// A frame written as it is
+    Frame(Frame),
+    /// A stream was opened, with its flow control window starting at the current initial one
+    Open(u32),
+    /// The response of a stream
+    Response(u32, Response),
+    /// The client allowed more data on a stream, or on the connection as stream 0
+    WindowUpdate(u32, u32),
+    /// The client changed its settings, to be acknowledged by the writer
+    Settings {
+        initial_window: Option<u32>,
+        max_frame_size: Option<u32>,
+    },
+    /// The client closed a stream
+    Reset(u32),
+}
+
+impl From<Frame> for Outgoing {
+    fn from(frame: Frame) -> Self {
+        Self::Frame(frame)
+    }
+}
+
+pub(super) async fn serve(
+    stream: impl AsyncRead + AsyncWrite,
+    idle_timeout: Duration,
+    endpoint: Endpoint,
+) -> io::Result<()> {
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut preface = [0u8; PREFACE.len()];
+    match tokio::time::timeout(idle_timeout, reader.read_exact(&mut preface)).await {
+        Ok(Ok(_)) if preface == PREFACE => {}
+        Ok(Err(e)) => return Err(e),
+        _ => return Ok(()),
+    }
+
+    let (outgoing, receiver) = mpsc::channel(MAX_QUEUED);
+    let reading = async move {
+        let mut connection = ReadState {
+            outgoing,
+            decoder: Decoder::default(),
+            streams: HashMap::new(),
+            last_stream: 0,
+            streams_left: Arc::new(Semaphore::new(MAX_CONCURRENT_STREAMS as usize)),
+            endpoint,
+        };
+        connection.read(&mut reader, idle_timeout).await
+    };
+    let writing = WriteState::new().write(writer, receiver);
+    tokio::try_join!(reading, writing)?;
+    Ok(())
+}
+
+/// A stream whose request is still being received
+#[derive(Debug)]
+struct Incoming {
+    request: Request,
+    /// The header block so far while CONTINUATION frames follow
+    header_block: Option<Vec<u8>>,
+    end_stream: bool,
+    permit: OwnedSemaphorePermit,
+}
+
+struct ReadState {
+    outgoing: mpsc::Sender<Outgoing>,
+    decoder: Decoder,
+    streams: HashMap<u32, Incoming>,
+    /// Highest stream opened by the client
+    last_stream: u32,
+    streams_left: Arc<Semaphore>,
+    endpoint: Endpoint,
+}
+
+impl ReadState {
+    async fn read(
+        &mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        idle_timeout: Duration,
+    ) -> io::Result<()> {
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (MAX_CONCURRENT_STREAMS_SETTING, MAX_CONCURRENT_STREAMS),
+            (MAX_FRAME_SIZE_SETTING, MAX_FRAME_SIZE as u32),
+            (
+                MAX_HEADER_LIST_SIZE_SETTING,
+                hpack::MAX_HEADER_LIST_SIZE as u32,
+            ),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        // the queue is still empty
+        let _ = self.send(Frame::new(SETTINGS, 0, 0, settings));
+
+        loop {
+            let frame = match tokio::time::timeout(idle_timeout, read_frame(reader)).await {
+                Err(_) => Err(Error(NO_ERROR)),
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(frame) => frame?,
+            };
+            let handled = match frame {
+                Ok(frame) => self.handle(frame),
+                Err(e) => Err(e),
+            };
+            match handled {
+                Ok(true) => {}
+                // the client is done, the responses still due are written before closing
+                Ok(false) => return Ok(()),
+                Err(Error(code)) => {
+                    let mut payload = self.last_stream.to_be_bytes().to_vec();
+                    payload.extend_from_slice(&code.to_be_bytes());
+                    // waiting for the writer to catch up, as the queue may be what is full
+                    let goaway = Frame::new(GOAWAY, 0, 0, payload).into();
+                    let _ = tokio::time::timeout(idle_timeout, self.outgoing.send(goaway)).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Queues a frame for the writer, failing once the client left too many unread
+    fn send(&self, outgoing: impl Into<Outgoing>) -> Result<(), Error> {
+        match self.outgoing.try_send(outgoing.into()) {
+            Err(mpsc::error::TrySendError::Full(_)) => Err(Error(ENHANCE_YOUR_CALM)),
+            // the writer only stops once the connection failed, which the reader notices as well
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles a frame, returning whether to read further ones
+    fn handle(&mut self, frame: Frame) -> Result<bool, Error> {
+        let continuing = self
+            .streams
+            .iter()
+            .find_map(|(&id, stream)| stream.header_block.as_ref().map(|_| id));
+        if let Some(id) = continuing {
+            if frame.r#type != CONTINUATION || frame.stream != id {
+                return Err(Error(PROTOCOL_ERROR));
+            }
+        }
+
+        match frame.r#type {
+            HEADERS => self.headers(frame)?,
+            CONTINUATION => {
+                let stream = (self.streams.get_mut(&frame.stream)).ok_or(Error(PROTOCOL_ERROR))?;
+                let block = (stream.header_block.as_mut()).ok_or(Error(PROTOCOL_ERROR))?;
+                block.extend_from_slice(&frame.payload);
+                if block.len() > MAX_HEADER_BLOCK {
+                    return Err(Error(PROTOCOL_ERROR));
+                }
+                if frame.flags & END_HEADERS != 0 {
+                    self.end_headers(frame.stream)?;
+                }
+            }
+            DATA => self.data(frame)?,
+            SETTINGS => self.settings(frame)?,
+            PING if frame.flags & ACK == 0 => {
+                if frame.payload.len() != 8 || frame.stream != 0 {
+                    return Err(Error(FRAME_SIZE_ERROR));
+                }
+                self.send(Frame::new(PING, ACK, 0, frame.payload))?;
+            }
+            WINDOW_UPDATE => {
+                let increment: [u8; 4] = (frame.payload[..])
+                    .try_into()
+                    .map_err(|_| Error(FRAME_SIZE_ERROR))?;
+                let increment = u32::from_be_bytes(increment) & 0x7fff_ffff;
+                if increment == 0 {
+                    return Err(Error(PROTOCOL_ERROR));
+                }
+                self.send(Outgoing::WindowUpdate(frame.stream, increment))?;
+            }
+            RST_STREAM => {
+                self.streams.remove(&frame.stream);
+                self.send(Outgoing::Reset(frame.stream))?;
+            }
+            GOAWAY => return Ok(false),
+            // clients never push
+            PUSH_PROMISE => return Err(Error(PROTOCOL_ERROR)),
+            // PRIORITY, acknowledgements of PING and unknown frames
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), Error> {
+        let id = frame.stream;
+        // trailers are not expected by the endpoint, but have to be decoded all the same
+        if let Some(stream) = self.streams.get_mut(&id) {
+            let block = frame.unpadded()?.to_vec();
+            stream.end_stream = frame.flags & END_STREAM != 0;
+            stream.header_block = Some(block);
+            if frame.flags & END_HEADERS != 0 {
+                self.end_headers(id)?;
+            }
+            return Ok(());
+        }
+        if id == 0 || id.is_multiple_of(2) || id <= self.last_stream {
+            return Err(Error(PROTOCOL_ERROR));
+        }
+        self.last_stream = id;
+
+        let mut block = frame.unpadded()?;
+        if frame.flags & PRIORITY != 0 {
+            block = block.get(5..).ok_or(Error(FRAME_SIZE_ERROR))?;
+        }
+        let Ok(permit) = Arc::clone(&self.streams_left).try_acquire_owned() else {
+            // the header block still has to be decoded to keep the table in sync
+            self.decoder
+                .decode(block)
+                .map_err(|_| Error(COMPRESSION_ERROR))?;
+            self.send(Frame::new(
+                RST_STREAM,
+                0,
+                id,
+                REFUSED_STREAM.to_be_bytes().to_vec(),
+            ))?;
+            return Ok(());
+        };
+        let stream = Incoming {
+            request: Request::default(),
+            header_block: Some(block.to_vec()),
+            end_stream: frame.flags & END_STREAM != 0,
+            permit,
+        };
+        self.streams.insert(id, stream);
+        self.send(Outgoing::Open(id))?;
+        if frame.flags & END_HEADERS != 0 {
+            self.end_headers(id)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes the complete header block of stream `id`
+    fn end_headers(&mut self, id: u32) -> Result<(), Error> {
+        let stream = self.streams.get_mut(&id).ok_or(Error(PROTOCOL_ERROR))?;
+        let block = stream.header_block.take().unwrap_or_default();
+        let headers = (self.decoder.decode(&block)).map_err(|_| Error(COMPRESSION_ERROR))?;
+        if stream.request.method.is_empty() {
+            for (name, value) in headers {
+                match name.as_str() {
+                    ":method" => stream.request.method = value,
+                    ":path" => stream.request.target = value,
+                    name if name.starts_with(':') => {}
+                    _ => stream.request.headers.push((name, value)),
+                }
+            }
+        }
+        if stream.end_stream {
+            self.dispatch(id);
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), Error> {
+        let len = frame.payload.len() as u32;
+        if len > 0 {
+            // the data is taken off the client's hands right away, so its windows are restored
+            self.send(Frame::new(WINDOW_UPDATE, 0, 0, len.to_be_bytes().to_vec()))?;
+        }
+        let data = frame.unpadded()?;
+        let Some(stream) = self.streams.get_mut(&frame.stream) else {
+            // the stream may have been answered already, as its request was too large
+            return match frame.stream <= self.last_stream && frame.stream != 0 {
+                true => Ok(()),
+                false => Err(Error(PROTOCOL_ERROR)),
+            };
+        };
+        if stream.header_block.is_some() || stream.request.method.is_empty() {
+            return Err(Error(PROTOCOL_ERROR));
+        }
+        if stream.request.body.len() + data.len() > MAX_MESSAGE_SIZE {
+            self.streams.remove(&frame.stream);
+            let response = Response::error(413, "DNS message too large");
+            self.send(Outgoing::Response(frame.stream, response))?;
+            return Ok(());
+        }
+        stream.request.body.extend_from_slice(data);
+        if frame.flags & END_STREAM != 0 {
+            self.dispatch(frame.stream);
+        } else if len > 0 {
+            let payload = len.to_be_bytes().to_vec();
+            self.send(Frame::new(WINDOW_UPDATE, 0, frame.stream, payload))?;
+        }
+        Ok(())
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.flags & ACK != 0 {
+            return Ok(());
+        }
+        if !frame.payload.len().is_multiple_of(6) || frame.stream != 0 {
+            return Err(Error(FRAME_SIZE_ERROR));
+        }
+        let (mut initial_window, mut max_frame_size) = (None, None);
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                INITIAL_WINDOW_SIZE_SETTING if value as i64 > MAX_WINDOW => {
+                    return Err(Error(FLOW_CONTROL_ERROR))
+                }
+                INITIAL_WINDOW_SIZE_SETTING => initial_window = Some(value),
+                MAX_FRAME_SIZE_SETTING
+                    if !(MAX_FRAME_SIZE as u32..=MAX_ALLOWED_FRAME_SIZE).contains(&value) =>
+                {
+                    return Err(Error(PROTOCOL_ERROR))
+                }
+                MAX_FRAME_SIZE_SETTING => max_frame_size = Some(value),
+                // the header table is never used for responses, and nothing is pushed
+                _ => {}
+            }
+        }
+        self.send(Outgoing::Settings {
+            initial_window,
+            max_frame_size,
+        })
+    }
+
+    /// Answers the complete request of stream `id` in a task of its own
+    fn dispatch(&mut self, id: u32) {
+        let Some(stream) = self.streams.remove(&id) else {
+            return;
+        };
+        let (endpoint, outgoing) = (self.endpoint.clone(), self.outgoing.clone());
+        tokio::spawn(async move {
+            let response = match stream.request.method.is_empty() {
+                true => Response::error(400, "missing :method"),
+                false => endpoint.respond(stream.request).await,
+            };
+            let _ = outgoing.send(Outgoing::Response(id, response)).await;
+            drop(stream.permit);
+        });
+    }
+}
+
+/// A response whose body is still being sent, as flow control allows
+#[derive(Debug)]
+struct Pending {
+    stream: u32,
+    body: Vec<u8>,
+    sent: usize,
+}
+
+struct WriteState {
+    max_frame_size: usize,
+    initial_window: i64,
+    connection_window: i64,
+    /// Windows of the streams that are not answered completely yet
+    windows: HashMap<u32, i64>,
+    pending: VecDeque<Pending>,
+}
+
+impl WriteState {
+    fn new() -> Self {
+        Self {
+            max_frame_size: MAX_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW,
+            connection_window: DEFAULT_WINDOW,
+            windows: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Writes until the reader and all streams being answered are done
+    async fn write(
+        mut self,
+        mut writer: impl AsyncWrite + Unpin,
+        mut receiver: mpsc::Receiver<Outgoing>,
+    ) -> io::Result<()> {
+        while let Some(outgoing) = receiver.recv().await {
+            let mut bytes = self.handle(outgoing);
+            // whatever else is ready goes out in the same write
+            while let Ok(outgoing) = receiver.try_recv() {
+                bytes.extend(self.handle(outgoing));
+            }
+            bytes.extend(self.flush_pending());
+            writer.write_all(&bytes).await?;
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, outgoing: Outgoing) -> Vec<u8> {
+        match outgoing {
+            Outgoing::Frame(frame) => frame.to_bytes(),
+            Outgoing::Open(stream) => {
+                self.windows.insert(stream, self.initial_window);
+                vec![]
+            }
+            Outgoing::Response(stream, response) => self.response(stream, response),
+            Outgoing::WindowUpdate(0, increment) => {
+                self.connection_window =
+                    (self.connection_window + increment as i64).min(MAX_WINDOW);
+                vec![]
+            }
+            Outgoing::WindowUpdate(stream, increment) => {
+                if let Some(window) = self.windows.get_mut(&stream) {
+                    *window = (*window + increment as i64).min(MAX_WINDOW);
+                }
+                vec![]
+            }
+            Outgoing::Settings {
+                initial_window,
+                max_frame_size,
+            } => {
+                if let Some(initial_window) = initial_window {
+                    let delta = initial_window as i64 - self.initial_window;
+                    self.initial_window = initial_window as i64;
+                    for window in self.windows.values_mut() {
+                        *window += delta;
+                    }
+                }
+                if let Some(max_frame_size) = max_frame_size {
+                    self.max_frame_size = max_frame_size as usize;
+                }
+                Frame::new(SETTINGS, ACK, 0, vec![]).to_bytes()
+            }
+            Outgoing::Reset(stream) => {
+                self.windows.remove(&stream);
+                self.pending.retain(|pending| pending.stream != stream);
+                vec![]
+            }
+        }
+    }
+
+    /// The HEADERS and CONTINUATION frames of `response`, whose body is sent as DATA frames by
+    /// [`WriteState::flush_pending`]
+    fn response(&mut self, stream: u32, response: Response) -> Vec<u8> {
+        if !self.windows.contains_key(&stream) {
+            // reset by the client in the meantime
+            return vec![];
+        }
+        let status = response.status.to_string();
+        let len = response.body.len().to_string();
+        let mut fields = vec![(":status", status.as_str()), ("content-length", &len)];
+        fields.extend(
+            response
+                .headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str())),
+        );
+        let block = hpack::encode(&fields);
+
+        let mut bytes = vec![];
+        let chunks: Vec<_> = block.chunks(self.max_frame_size).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let r#type = if i == 0 { HEADERS } else { CONTINUATION };
+            let mut flags = if i == chunks.len() - 1 {
+                END_HEADERS
+            } else {
+                0
+            };
+            if i == 0 && response.body.is_empty() {
+                flags |= END_STREAM;
+            }
+            bytes.extend(Frame::new(r#type, flags, stream, chunk.to_vec()).to_bytes());
+        }
+        match response.body.is_empty() {
+            true => {
+                self.windows.remove(&stream);
+            }
+            false => self.pending.push_back(Pending {
+                stream,
+                body: response.body,
+                sent: 0,
+            }),
+        }
+        bytes
+    }
+
+    /// DATA frames of the pending responses, as far as the flow control windows allow
+    fn flush_pending(&mut self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut i = 0;
+        while i < self.pending.len() && self.connection_window > 0 {
+            let pending = &mut self.pending[i];
+            let window = self
+                .windows
+                .get_mut(&pending.stream)
+                .map_or(0, |window| *window);
+            let left = pending.body.len() - pending.sent;
+            let len = left
+                .min(self.max_frame_size)
+                .min(window.max(0) as usize)
+                .min(self.connection_window as usize);
+            if len == 0 {
+                i += 1;
+                continue;
+            }
+            let data = pending.body[pending.sent..pending.sent + len].to_vec();
+            pending.sent += len;
+            let done = pending.sent == pending.body.len();
+            let stream = pending.stream;
+            let flags = if done { END_STREAM } else { 0 };
+            bytes.extend(Frame::new(DATA, flags, stream, data).to_bytes());
+
+            self.connection_window -= len as i64;
+            if let Some(window) = self.windows.get_mut(&stream) {
+                *window -= len as i64;
+            }
+            if done {
+                self.pending.remove(i);
+                self.windows.remove(&stream);
+            }
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use dns::{parse::parser::DnsParser, protocol::query::QueryBuilder};
+
+    use crate::{
+        cli::ServerArgs,
+        state::{Server, State},
+    };
+
+    use tokio::{io::DuplexStream, task::JoinHandle};
+
+    use super::*;
+
+    fn connect() -> (DuplexStream, JoinHandle<io::Result<()>>) {
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--record=example.com=192.0.2.1",
+            "--quiet",
+        ]);
+        let endpoint = Endpoint {
+            client: "127.0.0.1:5353".parse().unwrap(),
+            server: Arc::new(Server::new(State::new(server_args))),
+        };
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(serve(server, Duration::from_secs(5), endpoint));
+        (client, served)
+    }
+
+    /// The preface followed by `frames`
+    fn frames(frames: impl IntoIterator<Item = Frame>) -> Vec<u8> {
+        let mut bytes = PREFACE.to_vec();
+        for frame in frames {
+            bytes.extend(frame.to_bytes());
+        }
+        bytes
+    }
+
+    fn get() -> Vec<u8> {
+        hpack::encode(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/dns-query?name=example.com"),
+            (":authority", "dns.test"),
+        ])
+    }
+
+    fn post() -> Vec<u8> {
+        hpack::encode(&[
+            (":method", "POST"),
+            (":scheme", "https"),
+            (":path", "/dns-query"),
+            ("content-type", "application/dns-message"),
+        ])
+    }
+
+    /// The next frame of `r#type`, skipping the others
+    async fn next(client: &mut DuplexStream, r#type: u8) -> Frame {
+        loop {
+            let frame = read_frame(client).await.unwrap().unwrap();
+            if frame.r#type == r#type {
+                return frame;
+            }
+        }
+    }
+
+    /// The error the server closes the connection with
+    async fn goaway(client: &mut DuplexStream) -> u32 {
+        let goaway = next(client, GOAWAY).await;
+        u32::from_be_bytes(goaway.payload[4..8].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let (mut client, served) = connect();
+        let (get, post) = (get(), post());
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(3)
+            .build();
+        let bytes = frames([
+            Frame::new(SETTINGS, 0, 0, vec![]),
+            Frame::new(HEADERS, END_STREAM | END_HEADERS, 1, get),
+            Frame::new(HEADERS, 0, 3, post[..4].to_vec()),
+            Frame::new(CONTINUATION, END_HEADERS, 3, post[4..].to_vec()),
+            Frame::new(DATA, 0, 3, query[..5].to_vec()),
+            Frame::new(DATA, END_STREAM, 3, query[5..].to_vec()),
+            Frame::new(PING, 0, 0, b"pingpong".to_vec()),
+        ]);
+        client.write_all(&bytes).await.unwrap();
+
+        let mut decoder = Decoder::default();
+        let mut responses: HashMap<u32, (Vec<hpack::Header>, Vec<u8>)> = HashMap::new();
+        let (mut settings, mut acknowledged, mut pong) = (false, false, false);
+        let mut ended = 0;
+        while ended < 2 || !pong || !acknowledged {
+            let frame = read_frame(&mut client).await.unwrap().unwrap();
+            let response = responses.entry(frame.stream).or_default();
+            match frame.r#type {
+                SETTINGS if frame.flags & ACK != 0 => acknowledged = true,
+                SETTINGS => settings = true,
+                PING => pong = frame.flags & ACK != 0 && frame.payload == b"pingpong",
+                HEADERS => response.0 = decoder.decode(&frame.payload).unwrap(),
+                DATA => response.1.extend(frame.payload),
+                WINDOW_UPDATE => continue,
+                r#type => panic!("unexpected frame {type}"),
+            }
+            if frame.r#type == DATA && frame.flags & END_STREAM != 0 {
+                ended += 1;
+            }
+        }
+        assert!(settings);
+
+        let (headers, body) = &responses[&1];
+        assert_eq!(headers[0], (":status".to_string(), "200".to_string()));
+        let header = |name: &str| (headers.iter()).any(|(header, _)| header == name);
+        assert!(header("cache-control"));
+        assert!(String::from_utf8_lossy(body).contains("192.0.2.1"));
+        let (_, body) = &responses[&3];
+        let packet = DnsParser::new(body).parse_packet().unwrap();
+        assert_eq!(packet.header.request_id, 3);
+        assert_eq!(packet.answers.len(), 1);
+
+        // streams of clients are odd
+        let frame = Frame::new(HEADERS, END_STREAM | END_HEADERS, 4, vec![]);
+        client.write_all(&frame.to_bytes()).await.unwrap();
+        let goaway = read_frame(&mut client).await.unwrap().unwrap();
+        assert_eq!(goaway.r#type, GOAWAY);
+        assert_eq!(goaway.payload, [0, 0, 0, 3, 0, 0, 0, 1]);
+        served.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() {
+        let (mut client, served) = connect();
+        let window_update = |stream, increment: u32| {
+            Frame::new(WINDOW_UPDATE, 0, stream, increment.to_be_bytes().to_vec()).to_bytes()
+        };
+        // streams start without a window
+        let bytes = frames([
+            Frame::new(SETTINGS, 0, 0, vec![0, 4, 0, 0, 0, 0]),
+            Frame::new(HEADERS, END_STREAM | END_HEADERS, 1, get()),
+        ]);
+        client.write_all(&bytes).await.unwrap();
+        assert_eq!(next(&mut client, HEADERS).await.stream, 1);
+        let stalled = tokio::time::timeout(Duration::from_millis(100), read_frame(&mut client));
+        assert!(stalled.await.is_err());
+
+        // the body follows as the window allows
+        client.write_all(&window_update(1, 10)).await.unwrap();
+        let data = next(&mut client, DATA).await;
+        assert_eq!((data.payload.len(), data.flags), (10, 0));
+        client.write_all(&window_update(1, 65_535)).await.unwrap();
+        let rest = next(&mut client, DATA).await;
+        assert_eq!(rest.flags, END_STREAM);
+        let body = [data.payload, rest.payload].concat();
+        assert!(String::from_utf8_lossy(&body).contains("192.0.2.1"));
+
+        // nothing more is sent on streams reset while their response is pending
+        let headers = Frame::new(HEADERS, END_STREAM | END_HEADERS, 3, get());
+        client.write_all(&headers.to_bytes()).await.unwrap();
+        assert_eq!(next(&mut client, HEADERS).await.stream, 3);
+        let mut bytes = Frame::new(RST_STREAM, 0, 3, 8u32.to_be_bytes().to_vec()).to_bytes();
+        bytes.extend(window_update(3, 65_535));
+        bytes.extend(Frame::new(PING, 0, 0, b"pingpong".to_vec()).to_bytes());
+        client.write_all(&bytes).await.unwrap();
+        loop {
+            let frame = read_frame(&mut client).await.unwrap().unwrap();
+            assert_ne!(frame.r#type, DATA);
+            if frame.r#type == PING {
+                break;
+            }
+        }
+
+        drop(client);
+        served.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        // header blocks cannot be interleaved with frames of other streams
+        let (mut client, served) = connect();
+        let bytes = frames([
+            Frame::new(HEADERS, END_STREAM, 1, get()[..4].to_vec()),
+            Frame::new(HEADERS, END_STREAM | END_HEADERS, 3, get()),
+            Frame::new(CONTINUATION, END_HEADERS, 1, get()[4..].to_vec()),
+        ]);
+        client.write_all(&bytes).await.unwrap();
+        assert_eq!(goaway(&mut client).await, PROTOCOL_ERROR);
+        served.await.unwrap().unwrap();
+
+        // padding longer than the rest of the frame
+        let (mut client, served) = connect();
+        let mut padded = vec![10];
+        padded.extend(get());
+        padded.truncate(5);
+        let frame = Frame::new(HEADERS, END_STREAM | END_HEADERS | PADDED, 1, padded);
+        client.write_all(&frames([frame])).await.unwrap();
+        assert_eq!(goaway(&mut client).await, PROTOCOL_ERROR);
+        served.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        // bodies larger than a DNS message are answered right away
+        let (mut client, served) = connect();
+        let mut bytes = frames([Frame::new(HEADERS, END_HEADERS, 1, post())]);
+        for _ in 0..4 {
+            bytes.extend(Frame::new(DATA, 0, 1, vec![0; MAX_FRAME_SIZE]).to_bytes());
+        }
+        bytes.extend(Frame::new(DATA, END_STREAM, 1, vec![0; MAX_FRAME_SIZE]).to_bytes());
+        bytes.extend(Frame::new(PING, 0, 0, b"pingpong".to_vec()).to_bytes());
+        client.write_all(&bytes).await.unwrap();
+        let headers = next(&mut client, HEADERS).await;
+        let headers = Decoder::default().decode(&headers.payload).unwrap();
+        assert_eq!(headers[0], (":status".to_string(), "413".to_string()));
+        assert_eq!(next(&mut client, PING).await.flags, ACK);
+
+        // header blocks decoding to large header lists, by referring to a large entry
+        let mut block = hpack::encode(&[("x-large", &"a".repeat(4000))]);
+        block[0] = 0x40;
+        block.extend([0xbe; 20]);
+        let headers = Frame::new(HEADERS, END_STREAM | END_HEADERS, 3, block);
+        client.write_all(&headers.to_bytes()).await.unwrap();
+        assert_eq!(goaway(&mut client).await, COMPRESSION_ERROR);
+        served.await.unwrap().unwrap();
+
+        // clients sending PINGs without reading the acknowledgements
+        let (mut client, served) = connect();
+        let ping = Frame::new(PING, 0, 0, b"pingpong".to_vec()).to_bytes();
+        client.write_all(&frames([])).await.unwrap();
+        // enough to fill the connection and the queue
+        client.write_all(&ping.repeat(7000)).await.unwrap();
+        assert_eq!(goaway(&mut client).await, ENHANCE_YOUR_CALM);
+        served.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,412 @@
+//! DNS over HTTPS endpoint at `/dns-query`, so browsers and mobile devices can use the server as
+//! their resolver. Speaks HTTP/2, or HTTP/1.1 to clients that negotiate it with ALPN. Queries go
+//! through the same pipeline as over UDP, and are answered as `application/dns-message` or in
+//! the JSON format of `application/dns-json`.
+//! https://datatracker.ietf.org/doc/html/rfc8484
+
+mod hpack;
+mod http1;
+mod http2;
+
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dns::{
+    parse::parser::DnsParser,
+    protocol::{
+        doh_json::DNS_JSON,
+        query::{EdnsOptions, QueryBuilder},
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    transport::https::DNS_MESSAGE,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    process,
+    resolution::{error_response, Protocol},
+    state::Server,
+};
+
+/// Path of the endpoint
+/// https://datatracker.ietf.org/doc/html/rfc8484#section-3
+pub const DOH_PATH: &str = "/dns-query";
+
+/// ALPN identifiers of the HTTP versions, HTTP/2 preferred
+pub const DOH_ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// Largest DNS message accepted in a request
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// An HTTP request of either version, with lowercase header names
+#[derive(Debug, Default)]
+pub struct Request {
+    pub method: String,
+    /// Path and query
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        (self.headers.iter())
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("content-type", content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            ..Self::new("text/plain", format!("{message}\n").into_bytes())
+        }
+    }
+}
+
+/// Answers the requests of a connection
+pub trait Respond {
+    fn respond(&self, request: Request) -> impl Future<Output = Response> + Send;
+}
+
+/// What the requests of a connection are answered with
+#[derive(Clone)]
+struct Endpoint {
+    client: SocketAddr,
+    server: Arc<Server>,
+}
+
+/// Serves the requests sent over `stream` with the HTTP version negotiated for it, until the
+/// client closes the connection or sends nothing for `idle_timeout`
+pub async fn serve_connection(
+    stream: impl AsyncRead + AsyncWrite,
+    alpn: Option<&[u8]>,
+    client: SocketAddr,
+    idle_timeout: Duration,
+    server: Arc<Server>,
+) -> std::io::Result<()> {
+    let endpoint = Endpoint { client, server };
+    match alpn {
+        Some(b"h2") => http2::serve(stream, idle_timeout, endpoint).await,
+        _ => http1::serve(stream, idle_timeout, endpoint).await,
+    }
+}
+
+/// Serves the requests sent over `stream` with HTTP/1.1 and no TLS, answering them with
+/// `endpoint`, eg. for [`crate::admin`]
+pub async fn serve_http1(
+    stream: impl AsyncRead + AsyncWrite,
+    idle_timeout: Duration,
+    endpoint: impl Respond,
+) -> std::io::Result<()> {
+    http1::serve(stream, idle_timeout, endpoint).await
+}
+
+impl Respond for Endpoint {
+    async fn respond(&self, request: Request) -> Response {
+        let (path, parameters) = (request.target.split_once('?')).unwrap_or((&request.target, ""));
+        if path != DOH_PATH {
+            return Response::error(404, "not found");
+        }
+        let parameter = |name: &str| {
+            (parameters.split('&'))
+                .filter_map(|parameter| parameter.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| percent_decode(value))
+        };
+        let mut json = (request.header("accept")).is_some_and(|accept| accept.contains(DNS_JSON));
+
+        let query = match request.method.as_str() {
+            "GET" => match (parameter("dns"), parameter("name")) {
+                (Some(dns), _) => match URL_SAFE_NO_PAD.decode(dns.trim_end_matches('=')) {
+                    Ok(query) => query,
+                    Err(_) => return Response::error(400, "dns is not base64url"),
+                },
+                (None, Some(name)) => {
+                    json = true;
+                    match json_query(&name, parameter) {
+                        Ok(query) => query,
+                        Err(e) => return Response::error(400, &e),
+                    }
+                }
+                (None, None) => return Response::error(400, "missing dns or name parameter"),
+            },
+            "POST" => {
+                let content_type = request.header("content-type").unwrap_or_default();
+                if !content_type.starts_with(DNS_MESSAGE) {
+                    return Response::error(415, "expected application/dns-message");
+                }
+                request.body
+            }
+            _ => {
+                let mut response = Response::error(405, "method not allowed");
+                response.headers.push(("allow", "GET, POST".to_string()));
+                return response;
+            }
+        };
+        if query.len() > MAX_MESSAGE_SIZE {
+            return Response::error(413, "DNS message too large");
+        }
+        if DnsParser::new(&query).scan_query().is_err() {
+            return Response::error(400, "malformed DNS message");
+        }
+
+        let state = self.server.state();
+        let slot = state.query_slot().await;
+        let response = process(&query, &self.client, Protocol::Https, &state).await;
+        drop(slot);
+        let response = response.or_else(|| error_response(&query, ResponseCode::ServFail));
+        let Some(response) = response else {
+            return Response::error(500, "could not answer");
+        };
+        let packet = DnsParser::new(&response).parse_packet().ok();
+        let mut response = match (json, &packet) {
+            (true, Some(packet)) => Response::new(DNS_JSON, packet.to_doh_json().into_bytes()),
+            _ => Response::new(DNS_MESSAGE, response),
+        };
+        // HTTP caches keep the answer no longer than its records
+        // https://datatracker.ietf.org/doc/html/rfc8484#section-5.1
+        let ttl = (packet.iter())
+            .flat_map(|packet| packet.answers.iter().chain(&packet.authorities))
+            .map(|answer| answer.meta().ttl)
+            .min();
+        if let Some(ttl) = ttl {
+            response
+                .headers
+                .push(("cache-control", format!("max-age={ttl}")));
+        }
+        response
+    }
+}
+
+/// A query for the `name`, `type`, `cd` and `do` parameters of the JSON API
+fn json_query(name: &str, parameter: impl Fn(&str) -> Option<String>) -> Result<Vec<u8>, String> {
+    let name = name.parse().map_err(|e| format!("invalid name: {e}"))?;
+    let record_type = match parameter("type") {
+        Some(number) if number.chars().all(|c| c.is_ascii_digit()) => {
+            RecordType::from(number.parse::<usize>().map_err(|e| e.to_string())?)
+        }
+        Some(mnemonic) => mnemonic.parse()?,
+        None => RecordType::A,
+    };
+    let flag = |name| parameter(name).is_some_and(|value| value == "1" || value == "true");
+    let edns = EdnsOptions {
+        dnssec_ok: flag("do"),
+        ..EdnsOptions::default()
+    };
+    let query = QueryBuilder::new(name)
+        .record_type(record_type)
+        .checking_disabled(flag("cd"))
+        .edns(Some(edns))
+        .build();
+    Ok(query)
+}
+
+/// Decodes `%XX` escapes and `+` as space, keeping malformed escapes as they are
+fn percent_decode(value: &str) -> String {
+    let mut decoded = vec![];
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = bytes.clone().take(2).collect();
+                let escaped = std::str::from_utf8(&hex)
+                    .ok()
+                    .filter(|hex| hex.len() == 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(escaped) => {
+                        decoded.push(escaped);
+                        bytes.nth(1);
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reason phrase of the status codes responded with
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use dns::{
+        protocol::{answer::Answer, packet::Packet},
+        transport::https::{DohMethod, DohTransport},
+    };
+    use rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::{cli::ServerArgs, state::State, tcp::serve_tcp, tls::server_config};
+
+    use super::*;
+
+    fn endpoint() -> Endpoint {
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--record=example.com=192.0.2.1",
+            "--quiet",
+        ]);
+        Endpoint {
+            client: "127.0.0.1:5353".parse().unwrap(),
+            server: Arc::new(Server::new(State::new(server_args))),
+        }
+    }
+
+    fn get(target: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            ..Request::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let endpoint = endpoint();
+        let response = endpoint.respond(get("/dns-query?name=example.com")).await;
+        assert_eq!(response.status, 200);
+        let json = String::from_utf8(response.body).unwrap();
+        let packet = Packet::from_doh_json(&json).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert!(response
+            .headers
+            .contains(&("cache-control", "max-age=300".to_string())));
+
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(9)
+            .build();
+        let encoded = URL_SAFE_NO_PAD.encode(&query);
+        let response = endpoint
+            .respond(get(&format!("/dns-query?dns={encoded}")))
+            .await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers[0],
+            ("content-type", DNS_MESSAGE.to_string())
+        );
+        let packet = DnsParser::new(&response.body).parse_packet().unwrap();
+        assert_eq!(packet.header.request_id, 9);
+        assert!(matches!(packet.answers[..], [Answer::A { .. }]));
+
+        let mut post = Request {
+            method: "POST".to_string(),
+            target: DOH_PATH.to_string(),
+            headers: vec![("content-type".to_string(), DNS_MESSAGE.to_string())],
+            body: query.clone(),
+        };
+        post.headers
+            .push(("accept".to_string(), DNS_JSON.to_string()));
+        let response = endpoint.respond(post).await;
+        assert_eq!(response.headers[0], ("content-type", DNS_JSON.to_string()));
+
+        let status = |request| async { endpoint.respond(request).await.status };
+        assert_eq!(status(get("/")).await, 404);
+        assert_eq!(status(get(DOH_PATH)).await, 400);
+        assert_eq!(status(get("/dns-query?dns=%%%")).await, 400);
+        assert_eq!(status(get("/dns-query?dns=AAAA")).await, 400);
+        assert_eq!(
+            status(get("/dns-query?name=example.com&type=BOGUS")).await,
+            400
+        );
+        let put = Request {
+            method: "PUT".to_string(),
+            ..get(DOH_PATH)
+        };
+        assert_eq!(status(put).await, 405);
+        let form = Request {
+            method: "POST".to_string(),
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            body: query,
+            ..get(DOH_PATH)
+        };
+        assert_eq!(status(form).await, 415);
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("example.com"), "example.com");
+        assert_eq!(percent_decode("a%2Eb+c%zz%4"), "a.b c%zz%4");
+    }
+
+    #[tokio::test]
+    async fn test_serve_https() {
+        let directory = std::env::temp_dir().join(format!("serve-https-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".into()]).unwrap();
+        let (cert, key) = (directory.join("doh.pem"), directory.join("doh.key"));
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        let config = server_config(&cert, &key, None, &DOH_ALPN).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let Endpoint { server, .. } = endpoint();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_tcp(
+            listener,
+            Protocol::Https,
+            8,
+            Duration::from_secs(5),
+            Some(TlsAcceptor::from(config)),
+            server,
+        ));
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(certified.cert.der().to_vec()))
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let client_config = Arc::new(client_config);
+        let url = format!("https://127.0.0.1:{port}{DOH_PATH}");
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        for method in [DohMethod::Post, DohMethod::Get] {
+            let transport =
+                DohTransport::with_tls_config(&url, method, Arc::clone(&client_config)).unwrap();
+            for _ in 0..2 {
+                let exchange = transport.exchange_async(&query, Duration::from_secs(5));
+                let response = exchange.await.unwrap();
+                let packet = DnsParser::new(&response).parse_packet().unwrap();
+                assert_eq!(packet.answers.len(), 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Sockets the server takes queries on, given as `--listen`
+
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use dns::transport::{https::DOH_PORT, tls::DOT_PORT};
+use rustls::ServerConfig;
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::{
+    cli::ServerArgs,
+    doh::DOH_ALPN,
+    resolution::Protocol,
+    tls::{server_config, DOT_ALPN},
+};
+
+/// Pending connections queued by the kernel for each TCP listener
+const TCP_BACKLOG: i32 = 1024;
+
+/// Largest query received over UDP, anything longer is cut off. Clients advertising an EDNS
+/// payload size send queries of up to that size, and none advertise more than this.
+pub const UDP_BUFFER_SIZE: usize = 4096;
+
+/// An address to listen on, parsed from `[PROTOCOLS://]ADDRESS[?SETTINGS]`
+///
+/// The protocols are `udp`, `tcp`, `tls` for DNS over TLS, `https` for DNS over HTTPS, `quic`
+/// for DNS over QUIC with the `doq` feature, or several joined by `+`, and `udp+tcp` by default.
+/// The port defaults to 53, or 853 for `tls` and `quic` and 443 for `https`. The settings
+/// override the global ones for this listener alone, as `key=value` pairs separated by `&`:
+/// `max-connections` and `idle-timeout-secs` for all but UDP, and the PEM files `cert`, `key`
+/// and `client-ca` for TLS, HTTPS and QUIC. Eg. `[::]:53`, `udp://192.168.1.1:5353`,
+/// `tcp://0.0.0.0:53?max-connections=20&idle-timeout-secs=30`, `tls+quic://[::]` or
+/// `https://0.0.0.0?cert=doh.pem&key=doh.key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub address: SocketAddr,
+    pub protocols: Vec<Protocol>,
+    tcp_max_connections: Option<usize>,
+    tcp_idle_timeout_secs: Option<u64>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>,
+}
+
+impl Listener {
+    /// Listens on `address` over UDP and TCP with the global settings
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            protocols: vec![Protocol::Udp, Protocol::Tcp],
+            tcp_max_connections: None,
+            tcp_idle_timeout_secs: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+        }
+    }
+
+    /// Number of TCP connections open at the same time, `--tcp-max-connections` unless set
+    pub fn tcp_max_connections(&self, server_args: &ServerArgs) -> usize {
+        (self.tcp_max_connections).unwrap_or(server_args.tcp_max_connections)
+    }
+
+    /// How long a TCP connection stays open without a query, `--tcp-idle-timeout-secs` unless set
+    pub fn tcp_idle_timeout(&self, server_args: &ServerArgs) -> Duration {
+        let secs = (self.tcp_idle_timeout_secs).unwrap_or(server_args.tcp_idle_timeout_secs);
+        Duration::from_secs(secs)
+    }
+
+    /// TLS configuration of `protocol`, DNS over TLS, HTTPS or QUIC, with the `cert`, `key` and
+    /// `client-ca` of the listener or else `--tls-cert`, `--tls-key` and `--tls-client-ca`
+    pub fn tls_config(
+        &self,
+        protocol: Protocol,
+        server_args: &ServerArgs,
+    ) -> Result<Arc<ServerConfig>, String> {
+        let cert = (self.tls_cert.as_ref())
+            .or(server_args.tls_cert.as_ref())
+            .ok_or("no certificate, given as --tls-cert or cert")?;
+        let key = (self.tls_key.as_ref())
+            .or(server_args.tls_key.as_ref())
+            .ok_or("no private key, given as --tls-key or key")?;
+        let client_ca = (self.tls_client_ca.as_ref()).or(server_args.tls_client_ca.as_ref());
+        let alpn = match protocol {
+            Protocol::Https => &DOH_ALPN[..],
+            #[cfg(feature = "doq")]
+            Protocol::Quic => &[dns::transport::quic::DOQ_ALPN],
+            _ => &[DOT_ALPN],
+        };
+        server_config(cert, key, client_ca.map(PathBuf::as_path), alpn)
+    }
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocols, rest) = match s.split_once("://") {
+            Some((protocols, rest)) => {
+                let protocols = protocols
+                    .split('+')
+                    .map(|protocol| match protocol.to_ascii_lowercase().as_str() {
+                        "udp" => Ok(Protocol::Udp),
+                        "tcp" => Ok(Protocol::Tcp),
+                        "tls" => Ok(Protocol::Tls),
+                        "https" => Ok(Protocol::Https),
+                        "quic" if cfg!(feature = "doq") => Ok(Protocol::Quic),
+                        "quic" => Err("DNS over QUIC needs the doq feature".to_string()),
+                        _ => Err(format!(
+                            "unknown protocol {protocol}, expected udp, tcp, tls, https or quic"
+                        )),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                (protocols, rest)
+            }
+            None => (vec![Protocol::Udp, Protocol::Tcp], s),
+        };
+        let (address, settings) = rest.split_once('?').unwrap_or((rest, ""));
+        let secure = |protocol: &Protocol| matches!(protocol, Protocol::Tls | Protocol::Quic);
+        let port = match protocols[..] {
+            [Protocol::Https] => DOH_PORT,
+            // DNS over QUIC shares the port of DNS over TLS
+            _ if protocols.iter().all(secure) => DOT_PORT,
+            _ => 53,
+        };
+        let ip = address.trim_start_matches('[').trim_end_matches(']');
+        let address = match ip.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port),
+            Err(_) => address.parse().map_err(|_| {
+                format!("expected an address, eg. 0.0.0.0:53 or [::]:53, not {address}")
+            })?,
+        };
+
+        let mut listener = Self {
+            protocols,
+            ..Self::new(address)
+        };
+        for setting in settings.split('&').filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, not {setting}"))?;
+            let has = |protocols: &[Protocol]| {
+                (listener.protocols.iter()).any(|protocol| protocols.contains(protocol))
+            };
+            let invalid = |e: std::num::ParseIntError| format!("invalid {key} {value}: {e}");
+            match key {
+                "max-connections" | "idle-timeout-secs"
+                    if !has(&[
+                        Protocol::Tcp,
+                        Protocol::Tls,
+                        Protocol::Https,
+                        Protocol::Quic,
+                    ]) =>
+                {
+                    return Err(format!("{key} only applies to TCP, TLS, HTTPS and QUIC"))
+                }
+                "cert" | "key" | "client-ca"
+                    if !has(&[Protocol::Tls, Protocol::Https, Protocol::Quic]) =>
+                {
+                    return Err(format!("{key} only applies to TLS, HTTPS and QUIC"))
+                }
+                "max-connections" => {
+                    listener.tcp_max_connections = Some(value.parse().map_err(invalid)?)
+                }
+                "idle-timeout-secs" => {
+                    listener.tcp_idle_timeout_secs = Some(value.parse().map_err(invalid)?)
+                }
+                "cert" => listener.tls_cert = Some(value.into()),
+                "key" => listener.tls_key = Some(value.into()),
+                "client-ca" => listener.tls_client_ca = Some(value.into()),
+                _ => return Err(format!("unknown setting {key}")),
+            }
+        }
+        Ok(listener)
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let protocols: Vec<_> = (self.protocols.iter())
+            .map(|protocol| match protocol {
+                Protocol::Udp => "udp",
+                Protocol::Tcp => "tcp",
+                Protocol::Tls => "tls",
+                Protocol::Https => "https",
+                Protocol::Quic => "quic",
+            })
+            .collect();
+        write!(f, "{}://{}", protocols.join("+"), self.address)
+    }
+}
+
+/// A socket for `address`, which only takes IPv6 if it is an IPv6 address, so `[::]` and
+/// `0.0.0.0` can be listened on side by side
+fn socket(address: SocketAddr, r#type: socket2::Type) -> io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(address), r#type, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// The sockets for `address` passed by a systemd socket unit, if any
+fn activated(address: SocketAddr, r#type: socket2::Type) -> Vec<socket2::Socket> {
+    #[cfg(target_os = "linux")]
+    return crate::systemd::take_sockets(address, r#type);
+    #[cfg(not(target_os = "linux"))]
+    vec![]
+}
+
+pub fn bind_udp(address: SocketAddr) -> io::Result<UdpSocket> {
+    if let Some(socket) = activated(address, socket2::Type::DGRAM).pop() {
+        return UdpSocket::from_std(socket.into());
+    }
+    let socket = socket(address, socket2::Type::DGRAM)?;
+    socket.bind(&address.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// `workers` UDP sockets bound to `address` with `SO_REUSEPORT`, so the kernel spreads the
+/// datagrams received on it across them by their source, and with them across cores. Other
+/// processes of the same user may join them on the address, unlike with [`bind_udp`]. A single
+/// socket where the platform has no such option, or for a single worker. Sockets passed by
+/// systemd are taken as they are instead, however many there are.
+pub fn bind_udp_workers(address: SocketAddr, workers: usize) -> io::Result<Vec<UdpSocket>> {
+    let activated = activated(address, socket2::Type::DGRAM);
+    if !activated.is_empty() {
+        let sockets = activated
+            .into_iter()
+            .map(|socket| UdpSocket::from_std(socket.into()));
+        return sockets.collect();
+    }
+    if workers <= 1 || cfg!(not(unix)) {
+        return Ok(vec![bind_udp(address)?]);
+    }
+    let mut sockets = Vec::with_capacity(workers);
+    // the first socket picks the port if it is 0, which the others then share
+    let mut address = address;
+    for _ in 0..workers {
+        let socket = socket(address, socket2::Type::DGRAM)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&address.into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        address = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+pub fn bind_tcp(address: SocketAddr) -> io::Result<TcpListener> {
+    if let Some(socket) = activated(address, socket2::Type::STREAM).pop() {
+        return TcpListener::from_std(socket.into());
+    }
+    let socket = socket(address, socket2::Type::STREAM)?;
+    // restarts need not wait for the connections of the last run to time out
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(TCP_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_listener_from_str() {
+        let listener = |s: &str| s.parse::<Listener>();
+        assert_eq!(
+            listener("[::]:53").unwrap(),
+            Listener::new("[::]:53".parse().unwrap())
+        );
+        assert_eq!(
+            listener("udp://192.168.1.1:5353").unwrap().to_string(),
+            "udp://192.168.1.1:5353"
+        );
+        assert_eq!(
+            listener("TCP+udp://0.0.0.0:53").unwrap().to_string(),
+            "tcp+udp://0.0.0.0:53"
+        );
+
+        let server_args = ServerArgs::parse_from(["dns-block-tokio"]);
+        let tcp = listener("tcp://0.0.0.0:53?max-connections=20&idle-timeout-secs=30").unwrap();
+        assert_eq!(tcp.protocols, [Protocol::Tcp]);
+        assert_eq!(tcp.tcp_max_connections(&server_args), 20);
+        assert_eq!(tcp.tcp_idle_timeout(&server_args), Duration::from_secs(30));
+        let defaults = listener("0.0.0.0:53").unwrap();
+        assert_eq!(defaults.tcp_max_connections(&server_args), 150);
+
+        assert_eq!(
+            listener("0.0.0.0").unwrap().to_string(),
+            "udp+tcp://0.0.0.0:53"
+        );
+        assert_eq!(
+            listener("tls://[::]").unwrap().to_string(),
+            "tls://[::]:853"
+        );
+
+        let tls = listener("tls://[::1]:8853?cert=dns.pem&key=dns.key").unwrap();
+        assert_eq!(tls.tls_cert, Some(PathBuf::from("dns.pem")));
+        assert_eq!(tls.tls_key, Some(PathBuf::from("dns.key")));
+        assert!(tls
+            .tls_config(Protocol::Tls, &server_args)
+            .unwrap_err()
+            .starts_with("dns.pem: "));
+        let tls = listener("tls://[::1]:8853").unwrap();
+        assert!(tls
+            .tls_config(Protocol::Tls, &server_args)
+            .unwrap_err()
+            .starts_with("no certificate"));
+        let https = listener("https://0.0.0.0?cert=doh.pem&key=doh.key&max-connections=5").unwrap();
+        assert_eq!(https.to_string(), "https://0.0.0.0:443");
+        assert_eq!(https.tcp_max_connections(&server_args), 5);
+        if cfg!(feature = "doq") {
+            let quic = listener("tls+quic://[::]?idle-timeout-secs=30").unwrap();
+            assert_eq!(quic.to_string(), "tls+quic://[::]:853");
+            assert_eq!(quic.tcp_idle_timeout(&server_args), Duration::from_secs(30));
+        } else {
+            assert!(listener("quic://[::]").is_err());
+        }
+
+        assert!(listener("localhost:53").is_err());
+        assert!(listener("sctp://0.0.0.0:53").is_err());
+        assert!(listener("udp://0.0.0.0:53?max-connections=20").is_err());
+        assert!(listener("tcp://0.0.0.0:53?cert=dns.pem").is_err());
+        assert!(listener("0.0.0.0:53?max-connections=many").is_err());
+        assert!(listener("0.0.0.0:53?backlog=10").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_ipv4_and_ipv6() {
+        let udp = bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = udp.local_addr().unwrap().port();
+        // may fail on hosts without IPv6, but never conflicts with the IPv4 socket
+        if let Err(e) = bind_udp(SocketAddr::new("::".parse().unwrap(), port)) {
+            assert_ne!(e.kind(), io::ErrorKind::AddrInUse);
+        }
+        let again = bind_udp(SocketAddr::new("127.0.0.1".parse().unwrap(), port));
+        assert_eq!(again.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        let tcp = bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert!(tcp.local_addr().unwrap().port() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_workers() {
+        let workers = bind_udp_workers("127.0.0.1:0".parse().unwrap(), 4).unwrap();
+        let address = workers[0].local_addr().unwrap();
+        let expected = if cfg!(unix) { 4 } else { 1 };
+        assert_eq!(workers.len(), expected);
+        assert!((workers.iter()).all(|worker| worker.local_addr().unwrap() == address));
+        // sockets without the option cannot join them
+        assert_eq!(
+            bind_udp(address).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        // every datagram reaches one of the workers
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for i in 0..8u8 {
+            client.send_to(&[i], address).await.unwrap();
+        }
+        let mut received = 0;
+        let mut buffer = [0; 1];
+        while received < 8 {
+            for worker in &workers {
+                if worker.try_recv_from(&mut buffer).is_ok() {
+                    received += 1;
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Output of the `tracing` events of the server and the `dns` crate, as plain text or as one
+//! JSON object per line for log shippers. Fields of the spans an event happened in are added
+//! to it, eg. the query id, name, type and client of the query being answered.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Debug, Write as _},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+
+/// How events are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The level, message and fields, eg. `WARN Could not resolve error="timed out" qname=...`
+    Text,
+    /// An object with the timestamp, level, target, message and fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {s}, expected text or json")),
+        }
+    }
+}
+
+/// Prints events of `level` and above to stdout, debug and trace ones only from the server and
+/// the `dns` crate, not from the libraries they use.
+pub fn init(format: LogFormat, level: Level) {
+    let subscriber = Printer::new(format, LevelFilter::from_level(level));
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        eprintln!("Could not set up logging, it is already set up");
+    }
+}
+
+/// Targets of the events that are printed at any level
+const OWN_TARGETS: &[&str] = &["dns_block_tokio", "dns"];
+
+type Fields = Vec<(&'static str, Value)>;
+
+struct SpanData {
+    parent: Option<u64>,
+    fields: Fields,
+    /// handles to the span, including the ones of its children
+    references: usize,
+}
+
+struct Printer {
+    format: LogFormat,
+    level: LevelFilter,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    /// lines kept instead of printed, to test what would be printed
+    #[cfg(test)]
+    captured: Mutex<Vec<String>>,
+}
+
+thread_local! {
+    /// The spans entered on this thread, the innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Printer {
+    fn new(format: LogFormat, level: LevelFilter) -> Self {
+        Printer {
+            format,
+            level,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            captured: Mutex::new(vec![]),
+        }
+    }
+
+    /// The fields of the span `id` and the ones it is in, the outermost first
+    fn span_fields(&self, mut id: Option<u64>) -> Fields {
+        let spans = self.spans.lock().unwrap();
+        let mut chain = vec![];
+        while let Some(span) = id.and_then(|id| spans.get(&id)) {
+            chain.push(&span.fields);
+            id = span.parent;
+        }
+        chain.into_iter().rev().flatten().cloned().collect()
+    }
+
+    /// The event printed as one line, without the line break
+    fn format(&self, event: &Event<'_>, span_fields: Fields, now: SystemTime) -> String {
+        let metadata = event.metadata();
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+        let message = visitor.message.unwrap_or_default();
+        let fields = visitor.fields.into_iter().chain(span_fields);
+
+        let mut line = String::new();
+        match self.format {
+            LogFormat::Text => {
+                // an info event reads like the message alone did before there were levels
+                if *metadata.level() != Level::INFO {
+                    let _ = write!(line, "{} ", metadata.level());
+                }
+                line.push_str(&message);
+                for (name, value) in fields {
+                    match value {
+                        Value::String(s) if !s.is_empty() && !s.contains(char::is_whitespace) => {
+                            let _ = write!(line, " {name}={s}");
+                        }
+                        value => {
+                            let _ = write!(line, " {name}={value}");
+                        }
+                    }
+                }
+            }
+            LogFormat::Json => {
+                let fields = [
+                    ("timestamp", Value::String(timestamp(now))),
+                    ("level", Value::String(metadata.level().to_string())),
+                    ("target", Value::String(metadata.target().to_string())),
+                    ("message", Value::String(message)),
+                ]
+                .into_iter()
+                .chain(fields);
+                line = json_object(fields);
+            }
+        }
+        line
+    }
+}
+
+impl Subscriber for Printer {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let level = match OWN_TARGETS
+            .iter()
+            .any(|own| is_target(metadata.target(), own))
+        {
+            true => self.level,
+            false => self.level.min(LevelFilter::INFO),
+        };
+        metadata.level() <= &level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+        let mut visitor = Visitor::default();
+        attributes.record(&mut visitor);
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => current(),
+            None => None,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(parent) = parent.and_then(|parent| spans.get_mut(&parent)) {
+            parent.references += 1;
+        }
+        let span = SpanData {
+            parent,
+            fields: visitor.fields,
+            references: 1,
+        };
+        spans.insert(id, span);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        let mut visitor = Visitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            for (name, value) in visitor.fields {
+                match span.fields.iter_mut().find(|(field, _)| *field == name) {
+                    Some((_, recorded)) => *recorded = value,
+                    None => span.fields.push((name, value)),
+                }
+            }
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let span = match event.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if event.is_contextual() => current(),
+            None => None,
+        };
+        let line = self.format(event, self.span_fields(span), SystemTime::now());
+        #[cfg(test)]
+        self.captured.lock().unwrap().push(line);
+        #[cfg(not(test))]
+        let _ = std::io::Write::write_all(&mut std::io::stdout().lock(), (line + "\n").as_bytes());
+    }
+
+    fn enter(&self, span: &span::Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|&id| id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.references += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let mut id = Some(span.into_u64());
+        let mut closed = false;
+        // a span closing releases its parent, which may then close as well
+        while let Some(current) = id {
+            let Some(data) = spans.get_mut(&current) else {
+                break;
+            };
+            data.references -= 1;
+            if data.references > 0 {
+                break;
+            }
+            id = spans.remove(&current).and_then(|data| data.parent);
+            closed |= current == span.into_u64();
+        }
+        closed
+    }
+}
+
+/// The innermost span entered on this thread
+fn current() -> Option<u64> {
+    ENTERED.with(|entered| entered.borrow().last().copied())
+}
+
+/// Whether `target` is the module `module` or one in it
+fn is_target(target: &str, module: &str) -> bool {
+    target
+        .strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Collects the message and the other fields of an event or span
+#[derive(Default)]
+struct Visitor {
+    message: Option<String>,
+    fields: Fields,
+}
+
+impl Visit for Visitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, Value::String(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, Value::String(value.to_string()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, value.into());
+    }
+}
+
+impl Visitor {
+    fn record(&mut self, field: &Field, value: Value) {
+        match (field.name(), value) {
+            ("message", Value::String(message)) => self.message = Some(message),
+            (name, value) => self.fields.push((name, value)),
+        }
+    }
+}
+
+/// A JSON object with the `fields` in the order given, which a map would not keep
+pub fn json_object<'a>(fields: impl IntoIterator<Item = (&'a str, Value)>) -> String {
+    let mut object = String::from("{");
+    for (i, (name, value)) in fields.into_iter().enumerate() {
+        if i > 0 {
+            object.push(',');
+        }
+        let _ = write!(object, "{}:{value}", Value::String(name.to_string()));
+    }
+    object.push('}');
+    object
+}
+
+/// `time` in UTC as in RFC 3339 with milliseconds, eg. `2024-10-31T12:00:00.000Z`
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // the civil date of a day since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+
+    /// The lines `emit` would print, and how many spans are still open afterwards
+    fn capture(format: LogFormat, level: LevelFilter, emit: impl FnOnce()) -> (Vec<String>, usize) {
+        let printer = Arc::new(Printer::new(format, level));
+        tracing::subscriber::with_default(Arc::clone(&printer), emit);
+        let open = printer.spans.lock().unwrap().len();
+        let lines = printer.captured.lock().unwrap().clone();
+        (lines, open)
+    }
+
+    #[test]
+    fn test_format() {
+        let emit = || {
+            let span = tracing::info_span!("query", id = 4660u16, qname = "example.com.");
+            let _entered = span.enter();
+            tracing::info!(outcome = "blocked", "Answered query");
+            tracing::warn!(error = "timed out", "Could not resolve");
+            tracing::trace!("left out");
+        };
+        let (lines, open) = capture(LogFormat::Text, LevelFilter::DEBUG, emit);
+        assert_eq!(
+            lines,
+            [
+                "Answered query outcome=blocked id=4660 qname=example.com.",
+                "WARN Could not resolve error=\"timed out\" id=4660 qname=example.com.",
+            ]
+        );
+        assert_eq!(open, 0);
+
+        let (lines, _) = capture(LogFormat::Json, LevelFilter::DEBUG, emit);
+        let (timestamp, rest) = lines[0].split_once(',').unwrap();
+        assert!(timestamp.starts_with(r#"{"timestamp":"20"#));
+        assert_eq!(
+            rest,
+            r#""level":"INFO","target":"dns_block_tokio::logging::tests","message":"Answered query","outcome":"blocked","id":4660,"qname":"example.com."}"#
+        );
+    }
+
+    #[test]
+    fn test_nested_spans() {
+        let (lines, open) = capture(LogFormat::Text, LevelFilter::INFO, || {
+            let outer = tracing::info_span!("connection", client = "192.0.2.1:53000");
+            let inner = tracing::info_span!(parent: &outer, "query", id = 1u16);
+            drop(outer);
+            tracing::info!(parent: &inner, "Answered query");
+        });
+        assert_eq!(lines, ["Answered query client=192.0.2.1:53000 id=1"]);
+        assert_eq!(open, 0);
+    }
+
+    #[test]
+    fn test_enabled() {
+        let (lines, _) = capture(LogFormat::Text, LevelFilter::DEBUG, || {
+            tracing::debug!(target: "dns::upstream", "own");
+            tracing::debug!(target: "dnsx", "library");
+            tracing::debug!(target: "quinn_proto", "library");
+            tracing::info!(target: "quinn_proto", "library");
+        });
+        assert_eq!(lines, ["DEBUG own", "library"]);
+
+        let (lines, _) = capture(LogFormat::Text, LevelFilter::WARN, || {
+            tracing::info!("left out");
+            tracing::error!("kept");
+        });
+        assert_eq!(lines, ["ERROR kept"]);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_730_376_000_123);
+        assert_eq!(timestamp(time), "2024-10-31T12:00:00.123Z");
+        let time = UNIX_EPOCH + Duration::from_secs(951_827_696);
+        assert_eq!(timestamp(time), "2000-02-29T12:34:56.000Z");
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,129 +1,638 @@
+mod admin;
 mod cli;
+mod config;
+#[cfg(unix)]
+mod ctl;
+mod dashboard;
+mod doh;
+mod listen;
+mod logging;
+mod metrics;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+mod mmsg;
+#[cfg(unix)]
+mod privileges;
+mod query_log;
+#[cfg(feature = "doq")]
+mod quic;
+mod rate_limit;
 mod recording;
 mod resolution;
+mod rrl;
+mod state;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod tcp;
+mod tls;
+mod toml;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod zones;
 
-use cli::ServerArgs;
-use resolution::{handle_benchmark, handle_filter, handle_resolution};
-use std::{sync::Arc, thread::available_parallelism};
-use tokio::net::UdpSocket;
+use cli::{Command, ServerArgs};
+use listen::{bind_tcp, bind_udp, bind_udp_workers, Listener, UDP_BUFFER_SIZE};
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+use mmsg::accept_udp;
+use rate_limit::RateLimitAction;
+use resolution::{
+    error_response, handle_benchmark, handle_filter, handle_local, handle_resolution, Protocol,
+};
+use rrl::Verdict;
+use state::{Server, State};
+use std::{sync::Arc, thread::available_parallelism, time::Duration};
+use tcp::serve_tcp;
+#[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn, Instrument};
 
 use dns::{
-    filter::is_domain_blacklisted,
-    parse::parser::{DnsPacketBuffer, DnsParser},
+    parse::parser::DnsParser,
+    protocol::{opcode::Opcode, response_code::ResponseCode},
+    resolver::{fit_udp_response, ResponseSource},
+    secondary::handle_notify,
 };
 
+/// How often the hosts file is checked for changes
+const HOSTS_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the blocklists are checked for changes
+const BLOCKLIST_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long downloading a blocklist from its URL may take
+const BLOCKLIST_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() {
     let server_args = ServerArgs::from_env();
+    // the results of checking a configuration are printed as they are, not logged
+    if let Some(Command::CheckConfig { file }) = &server_args.command {
+        let problems = config::check(file);
+        for problem in &problems {
+            eprintln!("{}: {problem}", file.display());
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("{} is valid", file.display());
+        return;
+    }
+    if let Some(Command::Ctl { action }) = &server_args.command {
+        let Some(socket) = &server_args.control_socket else {
+            eprintln!("ctl needs --control-socket, or --config with control_socket set");
+            std::process::exit(2);
+        };
+        #[cfg(unix)]
+        match ctl::run(socket, action).await {
+            Ok(answer) => println!("{answer}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (socket, action);
+            eprintln!("ctl needs unix sockets, which this system does not have");
+            std::process::exit(1);
+        }
+        return;
+    }
+    logging::init(server_args.log_format, server_args.log_level);
 
-    println!(
-        "Started DNS blocker on {0}::{1} [benchmark={2}]",
-        server_args.bind_address, server_args.bind_port, server_args.benchmark,
+    let listeners: Vec<_> = (server_args.listeners().iter())
+        .map(Listener::to_string)
+        .collect();
+    info!(
+        "Started DNS blocker on {0} [benchmark={1}]",
+        listeners.join(", "),
+        server_args.benchmark,
     );
-    println!("Options {server_args:#?}");
+    info!("Options {:#?}", server_args.redacted());
 
-    println!(
+    info!(
         "Number of Cores: {0}",
         available_parallelism().unwrap().get()
     );
 
+    let server = Arc::new(Server::new(State::new(server_args)));
+    let state = server.state();
+    // opened before dropping privileges, rotating it takes write access to its directory though
+    if let Some(path) = &state.args.query_log_file {
+        if state.query_log.is_some() {
+            info!("Logging queries to {}", path.display());
+        }
+    }
+
+    // the lists are looked up in the current state each time, a reload may have replaced them
+    {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HOSTS_RELOAD_INTERVAL).await;
+                let state = server.state();
+                let Some(hosts) = &state.hosts else {
+                    continue;
+                };
+                match hosts.reload_if_changed() {
+                    Ok(true) if !state.args.quiet => {
+                        info!("Reloaded {} names from hosts file", hosts.len())
+                    }
+                    Err(e) => warn!("Could not reload hosts file: {e}"),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    if let Some(blocklist) = &state.blocklist {
+        info!("Blocking {} names", blocklist.len());
+    }
+    {
+        let server = Arc::clone(&server);
+        let refresh = Duration::from_secs(state.args.blocklist_refresh_hours * 3600);
+        // the reload loop below swaps the downloaded lists in
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh);
+            loop {
+                interval.tick().await;
+                let state = server.state();
+                let Some(blocklist) = &state.blocklist else {
+                    continue;
+                };
+                for file in blocklist.files() {
+                    let Some(url) = &file.url else {
+                        continue;
+                    };
+                    match file.download_async(BLOCKLIST_DOWNLOAD_TIMEOUT).await {
+                        Ok(true) if !state.args.quiet => info!("Downloaded blocklist {url}"),
+                        Err(e) => warn!("Could not download blocklist {url}: {e}"),
+                        _ => {}
+                    }
+                }
+            }
+        });
+    }
+    {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(BLOCKLIST_RELOAD_INTERVAL).await;
+                let state = server.state();
+                let Some(blocklist) = &state.blocklist else {
+                    continue;
+                };
+                match blocklist.reload_if_changed() {
+                    Ok(true) if !state.args.quiet => {
+                        info!("Reloaded blocklists, blocking {} names", blocklist.len())
+                    }
+                    Err(e) => warn!("Could not reload blocklists: {e}"),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    server.spawn_zone_tasks();
+    #[cfg(unix)]
+    {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move { reload_on_hangup(server).await });
+    }
+
     // A) Create a pool of tasks to handle incoming DNS requests
-    // start_server_without_task_delegation(server_args.clone()).await;
+    // start_server_without_task_delegation(Arc::clone(&server)).await;
     // B) One acceptor task that spawns further tasks for each incoming request
-    // start_server_with_acceptors(server_args.clone(), 1).await;
+    // start_server_with_acceptors(Arc::clone(&server), 1).await;
     // C) Multiple acceptor tasks that spawn further tasks for each incoming request
-    start_server_with_acceptors(server_args, get_acceptor_pool_size()).await;
-}
-
-#[allow(unused)]
-async fn start_server_without_task_delegation(server_args: ServerArgs) {
-    let server_args = Arc::new(server_args);
-    let socket = Arc::new(
-        tokio::net::UdpSocket::bind((server_args.bind_address.clone(), server_args.bind_port))
-            .await
-            .unwrap(),
-    );
+    let cache_file = state
+        .args
+        .cache_file
+        .clone()
+        .filter(|_| !state.args.no_cache);
+    let workers = state.args.udp_workers();
+    let cache = cache_file.as_ref().map(|cache_file| {
+        let cache = Arc::clone(&state.cache);
+        match cache.load(cache_file) {
+            Ok(loaded) => info!("Restored {loaded} cache entries from {cache_file}"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not restore cache from {cache_file}: {e}"),
+        }
+        cache
+    });
 
-    let mut handles = vec![];
-    for _ in 0..get_acceptor_pool_size() {
-        let server_args = Arc::clone(&server_args);
-        let socket = Arc::clone(&socket);
+    let shutdown_timeout = Duration::from_secs(state.args.shutdown_timeout_secs);
+    // not to keep the lists of this state once a reload replaced them
+    drop(state);
+    tokio::select! {
+        _ = start_server_with_acceptors(Arc::clone(&server), workers) => {}
+        signal = shutdown_signal() => {
+            // the acceptors stop receiving, the sockets are closed once main returns
+            info!("Received {signal}, waiting for the queries being answered");
+            #[cfg(target_os = "linux")]
+            systemd::stopping();
+            let (timeout, state) = (shutdown_timeout, server.state());
+            if tokio::time::timeout(timeout, state.drain_queries()).await.is_err() {
+                warn!("Shutting down with queries still being answered after {timeout:?}");
+            }
+        }
+    }
+    if let (Some(cache), Some(cache_file)) = (cache, cache_file) {
+        match cache.save(&cache_file) {
+            Ok(()) => info!("Saved {} cache entries to {cache_file}", cache.len()),
+            Err(e) => warn!("Could not save cache to {cache_file}: {e}"),
+        }
+    }
+}
 
-        let handle = tokio::spawn(async move {
-            loop {
-                let mut buffer = [0u8; 512];
-                let (_, sender) = socket.recv_from(&mut buffer).await.unwrap();
+/// Waits for ctrl-c or, on Unix, SIGTERM, returning the name of the signal received
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-                process(&socket, &buffer, &sender, &server_args).await;
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminations) => {
+                return tokio::select! {
+                    _ = tokio::signal::ctrl_c() => "SIGINT",
+                    _ = terminations.recv() => "SIGTERM",
+                };
             }
-        });
-        handles.push(handle);
+            Err(e) => warn!("Could not listen for SIGTERM: {e}"),
+        }
     }
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
 
-    for handle in handles {
-        handle.await.unwrap();
+/// Reloads whenever the process receives SIGHUP, see [`reload`]
+#[cfg(unix)]
+async fn reload_on_hangup(server: Arc<Server>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Could not listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading");
+        reload(&server);
     }
 }
 
-async fn start_server_with_acceptors(server_args: ServerArgs, num_acceptor_tasks: u8) {
-    let server_args = Arc::new(server_args);
-    let socket = Arc::new(
-        tokio::net::UdpSocket::bind((server_args.bind_address.clone(), server_args.bind_port))
-            .await
-            .unwrap(),
-    );
+/// Reads the configuration file along with the hosts file, blocklists, allowlists and zone
+/// files again, whether they changed or not, see [`Server::reload`]. The current configuration
+/// is kept if the file is invalid. Queries already being answered are answered as before.
+fn reload(server: &Server) {
+    let args = ServerArgs::from_command_line(std::env::args_os()).unwrap_or_else(|problems| {
+        for problem in problems {
+            warn!("Could not reload configuration: {problem}");
+        }
+        server.state().args.clone()
+    });
+    server.reload(args);
+    let state = server.state();
+    if let Some(hosts) = &state.hosts {
+        info!("Reloaded {} names from hosts file", hosts.len());
+    }
+    if let Some(blocklist) = &state.blocklist {
+        info!("Reloaded blocklists, blocking {} names", blocklist.len());
+    }
+    if let Some(zones) = &state.zones {
+        info!("Reloaded {} zones", zones.len());
+    }
+}
 
+#[allow(unused)]
+async fn start_server_without_task_delegation(server: Arc<Server>) {
+    let server_args = &server.state().args;
     let mut handles = vec![];
-    for _ in 0..num_acceptor_tasks {
-        let server_args = Arc::clone(&server_args);
-        let socket = Arc::clone(&socket);
+    for listener in server_args.listeners() {
+        if !listener.protocols.contains(&Protocol::Udp) {
+            continue;
+        }
+        let socket = Arc::new(bind_or_exit(bind_udp(listener.address), &listener));
+        for _ in 0..server_args.udp_workers() {
+            let server = Arc::clone(&server);
+            let socket = Arc::clone(&socket);
 
-        let handle = tokio::spawn(async move {
-            loop {
-                let server_args = Arc::clone(&server_args);
-                let socket = Arc::clone(&socket);
+            let handle = tokio::spawn(async move {
+                loop {
+                    let mut buffer = [0u8; UDP_BUFFER_SIZE];
+                    let (len, sender) = socket.recv_from(&mut buffer).await.unwrap();
 
-                let mut buffer = [0u8; 512];
-                let (_, sender) = socket.recv_from(&mut buffer).await.unwrap();
+                    let query = &buffer[..len];
+                    let state = server.state();
+                    if let Some(response) = process(query, &sender, Protocol::Udp, &state).await {
+                        let _ = socket
+                            .send_to(&fit_udp_response(query, response), sender)
+                            .await;
+                    }
+                }
+            });
+            handles.push(handle);
+        }
+    }
 
-                tokio::spawn(async move {
-                    process(&socket, &buffer, &sender, &server_args).await;
-                });
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+/// Serves every listener, with `num_acceptor_tasks` tasks receiving on each UDP address. Each
+/// task has a socket of its own where the platform lets them share the address, see
+/// [`bind_udp_workers`].
+async fn start_server_with_acceptors(server: Arc<Server>, num_acceptor_tasks: usize) {
+    let server_args = &server.state().args.clone();
+    let mut handles = vec![];
+    for listener in server_args.listeners() {
+        for protocol in &listener.protocols {
+            let server = Arc::clone(&server);
+            match protocol {
+                Protocol::Udp => {
+                    let sockets = bind_udp_workers(listener.address, num_acceptor_tasks);
+                    let sockets: Vec<_> = (bind_or_exit(sockets, &listener).into_iter())
+                        .map(Arc::new)
+                        .collect();
+                    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                    let accept_udp = uring::accept_udp;
+                    // the sockets are shared by the tasks where the platform only binds one
+                    for socket in sockets.iter().cycle().take(num_acceptor_tasks) {
+                        handles.push(accept_udp(Arc::clone(socket), Arc::clone(&server)));
+                    }
+                }
+                Protocol::Tcp => {
+                    let tcp = bind_or_exit(bind_tcp(listener.address), &listener);
+                    let max_connections = listener.tcp_max_connections(server_args);
+                    let idle_timeout = listener.tcp_idle_timeout(server_args);
+                    handles.push(tokio::spawn(serve_tcp(
+                        tcp,
+                        Protocol::Tcp,
+                        max_connections,
+                        idle_timeout,
+                        None,
+                        server,
+                    )));
+                }
+                &protocol @ (Protocol::Tls | Protocol::Https) => {
+                    let config = listener.tls_config(protocol, server_args);
+                    let config = bind_or_exit(config, &listener);
+                    let tcp = bind_or_exit(bind_tcp(listener.address), &listener);
+                    let max_connections = listener.tcp_max_connections(server_args);
+                    let idle_timeout = listener.tcp_idle_timeout(server_args);
+                    handles.push(tokio::spawn(serve_tcp(
+                        tcp,
+                        protocol,
+                        max_connections,
+                        idle_timeout,
+                        Some(TlsAcceptor::from(config)),
+                        server,
+                    )));
+                }
+                #[cfg(feature = "doq")]
+                Protocol::Quic => {
+                    let config = listener.tls_config(Protocol::Quic, server_args);
+                    let config = bind_or_exit(config, &listener);
+                    let idle_timeout = listener.tcp_idle_timeout(server_args);
+                    let endpoint = quic::bind_quic(listener.address, config, idle_timeout);
+                    let endpoint = bind_or_exit(endpoint, &listener);
+                    let max_connections = listener.tcp_max_connections(server_args);
+                    let serving = quic::serve_quic(endpoint, max_connections, server);
+                    handles.push(tokio::spawn(serving));
+                }
+                // only parsed with the doq feature
+                #[cfg(not(feature = "doq"))]
+                Protocol::Quic => unreachable!("DNS over QUIC needs the doq feature"),
             }
+        }
+    }
+    if let Some(address) = server_args.admin_listen {
+        let admin = bind_tcp(address).unwrap_or_else(|e| {
+            error!("Could not serve the admin endpoints on {address}: {e}");
+            std::process::exit(1);
+        });
+        info!("Serving metrics on http://{address}/metrics");
+        let idle_timeout = Duration::from_secs(server_args.tcp_idle_timeout_secs);
+        handles.push(tokio::spawn(admin::serve_admin(
+            admin,
+            idle_timeout,
+            Arc::clone(&server),
+        )));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &server_args.control_socket {
+        let control = ctl::bind_control_socket(path).unwrap_or_else(|e| {
+            error!("Could not listen on control socket {}: {e}", path.display());
+            std::process::exit(1);
         });
-        handles.push(handle);
+        info!("Listening for ctl on {}", path.display());
+        let idle_timeout = Duration::from_secs(server_args.tcp_idle_timeout_secs);
+        handles.push(tokio::spawn(admin::serve_control(
+            control,
+            idle_timeout,
+            Arc::clone(&server),
+        )));
+    }
+
+    // files read later are found in the new root, the ones needed to answer queries are read
+    #[cfg(unix)]
+    if let Err(e) = privileges::drop_privileges(server_args) {
+        error!("Could not drop privileges: {e}");
+        std::process::exit(1);
     }
+    #[cfg(target_os = "linux")]
+    systemd::ready();
 
     for handle in handles {
         handle.await.unwrap();
     }
 }
 
-fn get_acceptor_pool_size() -> u8 {
-    available_parallelism().unwrap().get() as u8 / 2
+/// An acceptor task, which spawns a further task for each query received on `socket`. It only
+/// receives once a query slot is free, so queries beyond `--max-concurrent-queries` wait in the
+/// socket's buffer while slow ones are answered.
+#[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+fn accept_udp(socket: Arc<UdpSocket>, server: Arc<Server>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let socket = Arc::clone(&socket);
+
+            // slots are only taken once there is something to receive, so idle acceptors hold
+            // none, eg. while shutting down
+            if let Err(e) = socket.readable().await {
+                warn!("Could not receive queries: {e}");
+                return;
+            }
+            let slot = server.state().query_slot().await;
+            let mut buffer = [0u8; UDP_BUFFER_SIZE];
+            let (len, sender) = match socket.try_recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                // eg. an ICMP error for an earlier response, which only concerns that client
+                Err(e) => {
+                    if !server.state().args.quiet {
+                        warn!("Could not receive query: {e}");
+                    }
+                    continue;
+                }
+            };
+
+            let state = server.state();
+            tokio::spawn(async move {
+                let query = &buffer[..len];
+                if let Some(response) = process(query, &sender, Protocol::Udp, &state).await {
+                    let _ = socket
+                        .send_to(&fit_udp_response(query, response), sender)
+                        .await;
+                }
+                drop(slot);
+            });
+        }
+    })
+}
+
+/// The socket bound for `listener`, exiting if it could not be bound since the server would
+/// not be reachable where it was configured to be
+fn bind_or_exit<T, E: std::fmt::Display>(bound: Result<T, E>, listener: &Listener) -> T {
+    bound.unwrap_or_else(|e| {
+        error!("Could not listen on {listener}: {e}");
+        std::process::exit(1);
+    })
 }
 
+/// Answers a query the way the server is configured to, `None` if it should go unanswered.
+/// Shared by UDP and TCP, which only differ in how the messages are sent.
 async fn process(
-    receiving_socket: &UdpSocket,
-    original_query: &DnsPacketBuffer,
+    query: &[u8],
     sender: &std::net::SocketAddr,
-    server_args: &ServerArgs,
-) {
+    protocol: Protocol,
+    state: &State,
+) -> Option<Vec<u8>> {
+    let server_args = &state.args;
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if !rate_limiter.allow(sender.ip(), std::time::Instant::now()) {
+            state.metrics.record_rate_limited();
+            debug!(client = %sender, "Rate limiting query");
+            return match server_args.rate_limit_action {
+                RateLimitAction::Refused => error_response(query, ResponseCode::Refused),
+                RateLimitAction::Drop => None,
+            };
+        }
+    }
     let start = std::time::SystemTime::now();
-    let mut parser = DnsParser::new(original_query);
-    let (request_id, question) = parser.get_relay_information().unwrap();
-
-    if server_args.benchmark {
-        handle_benchmark(
-            request_id,
-            receiving_socket,
-            sender,
-            std::time::Duration::from_millis(server_args.resolution_delay_ms),
-        )
-        .await;
-    } else if is_domain_blacklisted(&question.domain_name) {
-        handle_filter(server_args, &question, request_id, receiving_socket, sender).await;
-    } else {
-        handle_resolution(original_query, server_args, receiving_socket, sender, start).await;
+    let opcode = DnsParser::new(query)
+        .parse_header()
+        .map(|header| header.opcode());
+    if opcode == Ok(Opcode::Update) {
+        let response =
+            (state.zones.as_ref()).and_then(|zones| zones.respond_update(query, sender.ip()))?;
+        if !server_args.quiet {
+            info!(client = %sender, "Received UPDATE");
+        }
+        return Some(response);
+    }
+    if opcode == Ok(Opcode::Notify) {
+        let response = handle_notify(&state.secondaries, query, sender.ip())?;
+        if !server_args.quiet {
+            info!(client = %sender, "Received NOTIFY");
+        }
+        return Some(response);
+    }
+    // the questions are only parsed in full for logging, relaying just needs them well-formed
+    let Ok(scanned) = DnsParser::new(query).scan_query() else {
+        if !server_args.quiet {
+            info!(client = %sender, "Dropping malformed query");
+        }
+        return None;
+    };
+
+    // everything logged while answering carries the query, the fields are only formatted if
+    // the span is enabled
+    let question = scanned.question.as_ref();
+    let span = tracing::info_span!(
+        "query",
+        id = scanned.header.request_id,
+        qname = question.map(|question| tracing::field::display(&question.name)),
+        qtype = question.map(|question| tracing::field::display(question.r#type)),
+        client = %sender,
+        protocol = ?protocol,
+    );
+
+    async {
+        let (mut response, blocked, cached) = if server_args.benchmark {
+            let delay = std::time::Duration::from_millis(server_args.resolution_delay_ms);
+            let response = handle_benchmark(scanned.header.request_id, delay).await;
+            (response, false, false)
+        } else if let Some(response) =
+            (state.records.as_ref()).and_then(|records| records.respond(query))
+        {
+            (Some(handle_local(server_args, response)), false, false)
+        } else if let Some(response) =
+            (state.blocklist.as_ref()).and_then(|blocklist| blocklist.respond(query, sender.ip()))
+        {
+            (Some(handle_filter(server_args, response)), true, false)
+        } else {
+            match handle_resolution(query, protocol, state, start).await {
+                Some(relayed) => {
+                    if let Some((upstream, rtt)) = &relayed.upstream {
+                        state.metrics.record_upstream(upstream, *rtt);
+                    }
+                    let cached = matches!(
+                        relayed.source,
+                        ResponseSource::Cache | ResponseSource::Stale
+                    );
+                    (Some(relayed.response), false, cached)
+                }
+                // the client would otherwise wait for its own timeout and retry
+                None => (error_response(query, ResponseCode::ServFail), false, false),
+            }
+        };
+
+        if let Some(question) = question {
+            let mut rcode = (response.as_deref())
+                .and_then(|response| DnsParser::new(response).parse_header().ok())
+                .map(|header| header.rcode());
+            let name = question.name.to_string();
+            // over UDP only, which is what forged addresses are used with
+            let limiter = state.response_rate_limiter.as_ref();
+            if let (Some(limiter), Some(sent), Protocol::Udp) = (limiter, rcode, protocol) {
+                let now = std::time::Instant::now();
+                let verdict = limiter.check(sender.ip(), &name, question.r#type, sent, now);
+                match verdict {
+                    Verdict::Send => {}
+                    Verdict::Slip => response = response.as_deref().and_then(rrl::truncated),
+                    Verdict::Drop => (response, rcode) = (None, None),
+                }
+                if verdict != Verdict::Send {
+                    state.metrics.record_rrl(verdict);
+                    debug!(verdict = ?verdict, "Rate limiting response");
+                }
+            }
+            let entry = query_log::Entry {
+                time: start,
+                client: *sender,
+                protocol,
+                name,
+                r#type: question.r#type,
+                rcode,
+                blocked,
+                cached,
+                latency: start.elapsed().unwrap_or_default(),
+            };
+            state.metrics.record(&entry);
+            if let Some(activity) = &state.activity {
+                activity.record(&entry);
+            }
+            if let Some(log) = &state.query_log {
+                log.record(entry);
+            }
+        }
+        response
     }
+    .instrument(span)
+    .await
 }
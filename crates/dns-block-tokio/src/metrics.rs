@@ -0,0 +1,520 @@
+//! Counters and histograms of the queries answered, for graphing the server with Prometheus and
+//! Grafana. They are served at `/metrics` by [`crate::admin`] in the Prometheus text format,
+//! along with what the cache and the upstreams know about themselves at that moment.
+//! https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use dns::{
+    cache::ShardStats,
+    protocol::{record_type::RecordType, response_code::ResponseCode},
+    upstream::UpstreamStats,
+};
+
+use crate::{query_log::Entry, rrl::Verdict};
+
+/// Upper bounds of the histogram buckets in seconds, from cached answers to upstreams timing out
+const BUCKETS: [f64; 13] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Record types are counted by their number below this, all further ones together
+const COUNTED_TYPES: usize = 256;
+
+/// Response codes fit into the 4 bits of the header
+const RCODES: usize = 16;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, the last one for those beyond all bounds
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = (BUCKETS.iter())
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = value.as_micros().try_into().unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Writes the cumulative buckets, sum and count, with `label` if given
+    fn write(&self, exposition: &mut Exposition, name: &str, label: Option<(&str, &str)>) {
+        let mut count = 0;
+        let bounds = BUCKETS
+            .iter()
+            .map(f64::to_string)
+            .chain(["+Inf".to_string()]);
+        for (bucket, bound) in self.buckets.iter().zip(bounds) {
+            count += bucket.load(Ordering::Relaxed);
+            let labels: Vec<_> = label.into_iter().chain([("le", bound.as_str())]).collect();
+            exposition.sample(&format!("{name}_bucket"), &labels, count);
+        }
+        let labels: Vec<_> = label.into_iter().collect();
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        exposition.sample(&format!("{name}_sum"), &labels, sum);
+        exposition.sample(&format!("{name}_count"), &labels, count);
+    }
+}
+
+/// What is counted while answering queries, shared by all of them
+#[derive(Debug)]
+pub struct Metrics {
+    /// By record type number, the last one for all types from [`COUNTED_TYPES`] on
+    queries: [AtomicU64; COUNTED_TYPES + 1],
+    /// By response code
+    responses: [AtomicU64; RCODES],
+    unanswered: AtomicU64,
+    blocked: AtomicU64,
+    /// Over the rate limit, not counted otherwise
+    rate_limited: AtomicU64,
+    /// Responses over the response rate limit sent truncated, and dropped
+    rrl_slipped: AtomicU64,
+    rrl_dropped: AtomicU64,
+    /// Time from receiving a query until its response was ready
+    duration: Histogram,
+    /// Round trip times of the upstreams that answered, in the order they were given in
+    upstreams: Vec<(String, Histogram)>,
+}
+
+/// What the server knows about itself when the metrics are rendered, besides the counters
+#[derive(Debug, Default)]
+pub struct Gauges {
+    pub in_flight: usize,
+    /// `None` without a cache
+    pub cache: Option<Vec<ShardStats>>,
+    /// By name
+    pub upstreams: Vec<(String, UpstreamStats)>,
+}
+
+impl Metrics {
+    /// New metrics, with a histogram for each of the `upstreams`
+    pub fn new(upstreams: &[String]) -> Self {
+        Metrics {
+            queries: std::array::from_fn(|_| AtomicU64::new(0)),
+            responses: Default::default(),
+            unanswered: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            rrl_slipped: AtomicU64::new(0),
+            rrl_dropped: AtomicU64::new(0),
+            duration: Histogram::default(),
+            upstreams: (upstreams.iter())
+                .map(|upstream| (upstream.clone(), Histogram::default()))
+                .collect(),
+        }
+    }
+
+    /// Counts the query `entry` is about
+    pub fn record(&self, entry: &Entry) {
+        let r#type = usize::from(u16::from(entry.r#type)).min(COUNTED_TYPES);
+        self.queries[r#type].fetch_add(1, Ordering::Relaxed);
+        match entry.rcode {
+            Some(rcode) => {
+                let rcode = usize::from(rcode.header_bits());
+                self.responses[rcode].fetch_add(1, Ordering::Relaxed);
+            }
+            None => _ = self.unanswered.fetch_add(1, Ordering::Relaxed),
+        }
+        if entry.blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        self.duration.observe(entry.latency);
+    }
+
+    /// Counts a query over the rate limit
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a response over the response rate limit, unless it was sent after all
+    pub fn record_rrl(&self, verdict: Verdict) {
+        match verdict {
+            Verdict::Send => {}
+            Verdict::Slip => _ = self.rrl_slipped.fetch_add(1, Ordering::Relaxed),
+            Verdict::Drop => _ = self.rrl_dropped.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Records the round trip time of `upstream` answering a query
+    pub fn record_upstream(&self, upstream: &str, rtt: Duration) {
+        if let Some((_, histogram)) = self.upstreams.iter().find(|(name, _)| name == upstream) {
+            histogram.observe(rtt);
+        }
+    }
+
+    /// The metrics in the Prometheus text format, along with `gauges`
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut exposition = Exposition::default();
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        exposition.family(
+            "dns_queries_total",
+            "counter",
+            "Queries received, by record type",
+        );
+        for (r#type, queries) in self.queries_by_type() {
+            exposition.sample("dns_queries_total", &[("type", &r#type)], queries);
+        }
+
+        exposition.family(
+            "dns_responses_total",
+            "counter",
+            "Responses sent, by response code",
+        );
+        for (rcode, responses) in self.responses_by_rcode() {
+            exposition.sample("dns_responses_total", &[("rcode", &rcode)], responses);
+        }
+
+        exposition.family(
+            "dns_unanswered_queries_total",
+            "counter",
+            "Queries left unanswered, eg. as their responses were rate limited",
+        );
+        exposition.sample(
+            "dns_unanswered_queries_total",
+            &[],
+            counter(&self.unanswered),
+        );
+        exposition.family(
+            "dns_blocked_queries_total",
+            "counter",
+            "Queries answered from the blocklists",
+        );
+        exposition.sample("dns_blocked_queries_total", &[], counter(&self.blocked));
+        exposition.family(
+            "dns_rate_limited_queries_total",
+            "counter",
+            "Queries over the rate limit of their client, refused or dropped",
+        );
+        exposition.sample(
+            "dns_rate_limited_queries_total",
+            &[],
+            counter(&self.rate_limited),
+        );
+        exposition.family(
+            "dns_rrl_responses_total",
+            "counter",
+            "Responses over the response rate limit, by whether they were slipped or dropped",
+        );
+        for (action, responses) in [("slip", &self.rrl_slipped), ("drop", &self.rrl_dropped)] {
+            let labels = [("action", action)];
+            exposition.sample("dns_rrl_responses_total", &labels, counter(responses));
+        }
+        exposition.family(
+            "dns_queries_in_flight",
+            "gauge",
+            "Queries being answered right now",
+        );
+        exposition.sample("dns_queries_in_flight", &[], gauges.in_flight);
+        exposition.family(
+            "dns_query_duration_seconds",
+            "histogram",
+            "Time from receiving a query until its response was ready",
+        );
+        (self.duration).write(&mut exposition, "dns_query_duration_seconds", None);
+
+        if let Some(cache) = gauges.cache() {
+            exposition.family(
+                "dns_cache_hits_total",
+                "counter",
+                "Cache lookups that found an answer, stale ones included",
+            );
+            exposition.sample("dns_cache_hits_total", &[], cache.hits);
+            exposition.family(
+                "dns_cache_misses_total",
+                "counter",
+                "Cache lookups that found none",
+            );
+            exposition.sample("dns_cache_misses_total", &[], cache.misses);
+            exposition.family(
+                "dns_cache_hit_ratio",
+                "gauge",
+                "Share of the cache lookups so far that found an answer",
+            );
+            exposition.sample("dns_cache_hit_ratio", &[], hit_ratio(&cache));
+            exposition.family("dns_cache_entries", "gauge", "Entries in the cache");
+            exposition.sample("dns_cache_entries", &[], cache.entries);
+            exposition.family(
+                "dns_cache_bytes",
+                "gauge",
+                "Size of the responses in the cache",
+            );
+            exposition.sample("dns_cache_bytes", &[], cache.bytes);
+        }
+
+        exposition.family(
+            "dns_upstream_rtt_seconds",
+            "histogram",
+            "Round trip times of the upstreams answering queries, including retries",
+        );
+        for (name, histogram) in &self.upstreams {
+            let label = Some(("upstream", name.as_str()));
+            histogram.write(&mut exposition, "dns_upstream_rtt_seconds", label);
+        }
+        exposition.family(
+            "dns_upstream_queries_total",
+            "counter",
+            "Queries sent to each upstream",
+        );
+        for (name, stats) in &gauges.upstreams {
+            let labels = [("upstream", name.as_str())];
+            exposition.sample("dns_upstream_queries_total", &labels, stats.queries);
+        }
+        exposition.family(
+            "dns_upstream_errors_total",
+            "counter",
+            "Queries to each upstream that failed or were answered with SERVFAIL",
+        );
+        for (name, stats) in &gauges.upstreams {
+            let labels = [("upstream", name.as_str())];
+            exposition.sample("dns_upstream_errors_total", &labels, stats.errors);
+        }
+        exposition.family(
+            "dns_upstream_healthy",
+            "gauge",
+            "Whether each upstream is tried before the ones that failed repeatedly",
+        );
+        for (name, stats) in &gauges.upstreams {
+            let labels = [("upstream", name.as_str())];
+            let healthy = u8::from(stats.is_healthy());
+            exposition.sample("dns_upstream_healthy", &labels, healthy);
+        }
+        exposition.0
+    }
+
+    /// A summary of the metrics along with `gauges` as JSON, for the admin API
+    pub fn to_json(&self, gauges: &Gauges) -> serde_json::Value {
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let counts = |counts: Vec<(String, u64)>| -> serde_json::Map<_, _> {
+            (counts.into_iter())
+                .map(|(name, count)| (name, count.into()))
+                .collect()
+        };
+        let cache = gauges.cache().map(|cache| {
+            serde_json::json!({
+                "entries": cache.entries,
+                "bytes": cache.bytes,
+                "hits": cache.hits,
+                "misses": cache.misses,
+                "hit_ratio": hit_ratio(&cache),
+            })
+        });
+        let upstreams: Vec<_> = (gauges.upstreams.iter())
+            .map(|(name, stats)| {
+                serde_json::json!({
+                    "upstream": name,
+                    "healthy": stats.is_healthy(),
+                    "queries": stats.queries,
+                    "errors": stats.errors,
+                    "srtt_ms": stats.srtt.map(|srtt| srtt.as_secs_f64() * 1000.0),
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "queries": self.queries.iter().map(counter).sum::<u64>(),
+            "queries_by_type": counts(self.queries_by_type()),
+            "responses_by_rcode": counts(self.responses_by_rcode()),
+            "blocked": counter(&self.blocked),
+            "unanswered": counter(&self.unanswered),
+            "rate_limited": counter(&self.rate_limited),
+            "rrl": {
+                "slipped": counter(&self.rrl_slipped),
+                "dropped": counter(&self.rrl_dropped),
+            },
+            "in_flight": gauges.in_flight,
+            "cache": cache,
+            "upstreams": upstreams,
+        })
+    }
+
+    /// The record types queried for, with how many queries there were for each
+    fn queries_by_type(&self) -> Vec<(String, u64)> {
+        (self.queries.iter().enumerate())
+            .map(|(number, queries)| (number, queries.load(Ordering::Relaxed)))
+            .filter(|&(_, queries)| queries > 0)
+            .map(|(number, queries)| match number {
+                COUNTED_TYPES => ("other".to_string(), queries),
+                number => (RecordType::from(number).to_string(), queries),
+            })
+            .collect()
+    }
+
+    /// The response codes responded with, with how many responses there were for each
+    fn responses_by_rcode(&self) -> Vec<(String, u64)> {
+        (self.responses.iter().enumerate())
+            .map(|(number, responses)| (number, responses.load(Ordering::Relaxed)))
+            .filter(|&(_, responses)| responses > 0)
+            .map(|(number, responses)| (ResponseCode::from(number as u16).to_string(), responses))
+            .collect()
+    }
+}
+
+impl Gauges {
+    /// The usage of all shards of the cache added up, `None` without a cache
+    fn cache(&self) -> Option<ShardStats> {
+        let shards = self.cache.as_ref()?;
+        Some(ShardStats {
+            entries: shards.iter().map(|shard| shard.entries).sum(),
+            bytes: shards.iter().map(|shard| shard.bytes).sum(),
+            hits: shards.iter().map(|shard| shard.hits).sum(),
+            misses: shards.iter().map(|shard| shard.misses).sum(),
+        })
+    }
+}
+
+/// Share of the lookups of `cache` that found an answer
+fn hit_ratio(cache: &ShardStats) -> f64 {
+    match cache.hits + cache.misses {
+        0 => 0.0,
+        lookups => cache.hits as f64 / lookups as f64,
+    }
+}
+
+/// A document in the Prometheus text format
+#[derive(Debug, Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, r#type: &str, help: &str) {
+        self.0.push_str(&format!("# HELP {name} {help}\n"));
+        self.0.push_str(&format!("# TYPE {name} {type}\n"));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = (labels.iter())
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            self.0.push_str(&format!("{{{}}}", labels.join(",")));
+        }
+        self.0.push_str(&format!(" {value}\n"));
+    }
+}
+
+/// Escapes a label value, whose backslashes, quotes and line feeds would end it early
+fn escape(value: &str) -> String {
+    (value.replace('\\', r"\\"))
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::resolution::Protocol;
+
+    use super::*;
+
+    fn entry(r#type: RecordType, rcode: Option<ResponseCode>, latency_ms: u64) -> Entry {
+        Entry {
+            time: UNIX_EPOCH,
+            client: "192.0.2.1:53000".parse().unwrap(),
+            protocol: Protocol::Udp,
+            name: "example.com".to_string(),
+            r#type,
+            rcode,
+            blocked: rcode == Some(ResponseCode::NXDomain),
+            cached: false,
+            latency: Duration::from_millis(latency_ms),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new(&["9.9.9.9:53".to_string(), "1.1.1.1:53".to_string()]);
+        metrics.record(&entry(RecordType::A, Some(ResponseCode::NoError), 2));
+        metrics.record(&entry(RecordType::A, Some(ResponseCode::NXDomain), 0));
+        metrics.record(&entry(RecordType::AAAA, None, 3000));
+        metrics.record(&entry(
+            RecordType::from(65280),
+            Some(ResponseCode::NoError),
+            20,
+        ));
+        metrics.record_upstream("9.9.9.9:53", Duration::from_millis(20));
+        metrics.record_upstream("unknown:53", Duration::from_millis(20));
+        metrics.record_rate_limited();
+        metrics.record_rrl(Verdict::Slip);
+        metrics.record_rrl(Verdict::Send);
+
+        let gauges = Gauges {
+            in_flight: 3,
+            cache: Some(vec![ShardStats {
+                entries: 2,
+                bytes: 100,
+                hits: 1,
+                misses: 3,
+            }]),
+            upstreams: vec![
+                (
+                    "9.9.9.9:53".to_string(),
+                    UpstreamStats {
+                        queries: 2,
+                        errors: 1,
+                        ..UpstreamStats::default()
+                    },
+                ),
+                (
+                    "1.1.1.1:53".to_string(),
+                    UpstreamStats {
+                        consecutive_failures: 10,
+                        ..UpstreamStats::default()
+                    },
+                ),
+            ],
+        };
+        let rendered = metrics.render(&gauges);
+        let lines: Vec<_> = rendered.lines().collect();
+        for line in [
+            "# TYPE dns_queries_total counter",
+            r#"dns_queries_total{type="A"} 2"#,
+            r#"dns_queries_total{type="AAAA"} 1"#,
+            r#"dns_queries_total{type="other"} 1"#,
+            r#"dns_responses_total{rcode="NOERROR"} 2"#,
+            r#"dns_responses_total{rcode="NXDOMAIN"} 1"#,
+            "dns_unanswered_queries_total 1",
+            "dns_blocked_queries_total 1",
+            "dns_rate_limited_queries_total 1",
+            r#"dns_rrl_responses_total{action="slip"} 1"#,
+            r#"dns_rrl_responses_total{action="drop"} 0"#,
+            "dns_queries_in_flight 3",
+            r#"dns_query_duration_seconds_bucket{le="0.0005"} 1"#,
+            r#"dns_query_duration_seconds_bucket{le="0.0025"} 2"#,
+            r#"dns_query_duration_seconds_bucket{le="5"} 4"#,
+            r#"dns_query_duration_seconds_bucket{le="+Inf"} 4"#,
+            "dns_query_duration_seconds_sum 3.022",
+            "dns_query_duration_seconds_count 4",
+            "dns_cache_hits_total 1",
+            "dns_cache_misses_total 3",
+            "dns_cache_hit_ratio 0.25",
+            "dns_cache_entries 2",
+            r#"dns_upstream_rtt_seconds_bucket{upstream="9.9.9.9:53",le="0.025"} 1"#,
+            r#"dns_upstream_rtt_seconds_count{upstream="9.9.9.9:53"} 1"#,
+            r#"dns_upstream_rtt_seconds_count{upstream="1.1.1.1:53"} 0"#,
+            r#"dns_upstream_errors_total{upstream="9.9.9.9:53"} 1"#,
+            r#"dns_upstream_healthy{upstream="9.9.9.9:53"} 1"#,
+            r#"dns_upstream_healthy{upstream="1.1.1.1:53"} 0"#,
+        ] {
+            assert!(lines.contains(&line), "{line} missing from\n{rendered}");
+        }
+        assert!(!rendered.contains(r#"rcode="SERVFAIL""#));
+        assert!(!rendered.contains("unknown"));
+    }
+
+    #[test]
+    fn test_escape() {
+        let mut exposition = Exposition::default();
+        exposition.sample("upstream", &[("url", "a\"b\\c\nd")], 1);
+        assert_eq!(exposition.0, "upstream{url=\"a\\\"b\\\\c\\nd\"} 1\n");
+    }
+}
@@ -0,0 +1,238 @@
+//! UDP acceptors receiving and sending datagrams in batches with `recvmmsg` and `sendmmsg`,
+//! available on Linux with the `mmsg` feature. Under load a single system call then moves up to
+//! [`BATCH_SIZE`] queries or responses instead of one.
+
+use std::{
+    io,
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+    ptr::null_mut,
+    sync::Arc,
+};
+
+use dns::resolver::fit_udp_response;
+use socket2::SockAddr;
+use tokio::{io::Interest, net::UdpSocket, sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+use crate::{listen::UDP_BUFFER_SIZE, process, resolution::Protocol, state::Server};
+
+/// Most datagrams moved by one system call
+pub const BATCH_SIZE: usize = 64;
+
+/// A datagram and the address it came from or goes to
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// An acceptor task spawning a further task for each query received on `socket`, like the one
+/// without batching, but receiving as many queries at once as there are free query slots, up
+/// to [`BATCH_SIZE`]. Responses are sent by a further task, together with the others that are
+/// ready by then.
+pub fn accept_udp(socket: Arc<UdpSocket>, server: Arc<Server>) -> JoinHandle<()> {
+    let (responses, ready) = mpsc::unbounded_channel();
+    tokio::spawn(send_responses(Arc::clone(&socket), ready));
+    tokio::spawn(async move {
+        loop {
+            // slots are only taken once there is something to receive, not to starve other
+            // listeners while idle
+            if let Err(e) = socket.readable().await {
+                warn!("Could not receive queries: {e}");
+                return;
+            }
+            let state = server.state();
+            let mut slots = vec![state.query_slot().await];
+            while slots.len() < BATCH_SIZE {
+                match state.try_query_slot() {
+                    Some(slot) => slots.push(slot),
+                    None => break,
+                }
+            }
+            let received = socket.try_io(Interest::READABLE, || {
+                recv_batch(socket.as_raw_fd(), slots.len())
+            });
+            let datagrams = match received {
+                Ok(datagrams) => datagrams,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // eg. an ICMP error for an earlier response, which only concerns that client
+                Err(e) => {
+                    if !state.args.quiet {
+                        warn!("Could not receive queries: {e}");
+                    }
+                    continue;
+                }
+            };
+
+            for ((query, sender), slot) in datagrams.into_iter().zip(slots) {
+                let (state, responses) = (Arc::clone(&state), responses.clone());
+                tokio::spawn(async move {
+                    let processed = process(&query, &sender, Protocol::Udp, &state).await;
+                    if let Some(response) = processed {
+                        let _ = responses.send((fit_udp_response(&query, response), sender));
+                    }
+                    drop(slot);
+                });
+            }
+        }
+    })
+}
+
+/// Sends the responses as they become ready, all of the ones waiting in one batch
+async fn send_responses(socket: Arc<UdpSocket>, mut ready: mpsc::UnboundedReceiver<Datagram>) {
+    while let Some(response) = ready.recv().await {
+        let mut batch = vec![response];
+        while batch.len() < BATCH_SIZE {
+            match ready.try_recv() {
+                Ok(response) => batch.push(response),
+                Err(_) => break,
+            }
+        }
+        let mut sent = 0;
+        while sent < batch.len() {
+            let sending = socket.async_io(Interest::WRITABLE, || {
+                send_batch(socket.as_raw_fd(), &batch[sent..])
+            });
+            match sending.await {
+                Ok(count) => sent += count,
+                // the response that could not be sent is dropped, like with `send_to`
+                Err(_) => sent += 1,
+            }
+        }
+    }
+}
+
+/// Receives at most `max` datagrams waiting on the socket `fd`, without blocking
+fn recv_batch(fd: RawFd, max: usize) -> io::Result<Vec<Datagram>> {
+    let mut buffers = vec![[0u8; UDP_BUFFER_SIZE]; max];
+    // SAFETY: all-zero bytes are valid for these plain C structures
+    let mut addresses: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; max];
+    let mut iovecs: Vec<libc::iovec> = (buffers.iter_mut())
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = (iovecs.iter_mut().zip(&mut addresses))
+        .map(|(iovec, address)| {
+            // SAFETY: as above
+            let mut message: libc::mmsghdr = unsafe { zeroed() };
+            message.msg_hdr.msg_name = (address as *mut libc::sockaddr_storage).cast();
+            message.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    // SAFETY: every message points to a buffer and an address that outlive the call
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            messages.as_mut_ptr(),
+            max as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let received = (messages.iter().zip(&buffers).zip(&addresses)).take(received as usize);
+    let datagrams = received.filter_map(|((message, buffer), address)| {
+        // SAFETY: the kernel wrote an address of `msg_namelen` bytes
+        let address = unsafe { SockAddr::new(*address, message.msg_hdr.msg_namelen) };
+        let len = (message.msg_len as usize).min(UDP_BUFFER_SIZE);
+        Some((buffer[..len].to_vec(), address.as_socket()?))
+    });
+    Ok(datagrams.collect())
+}
+
+/// Sends the `datagrams` on the socket `fd`, without blocking, returning how many of them were
+/// sent. Fails with the error of the first one if none was.
+fn send_batch(fd: RawFd, datagrams: &[Datagram]) -> io::Result<usize> {
+    let addresses: Vec<SockAddr> = (datagrams.iter())
+        .map(|(_, address)| SockAddr::from(*address))
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = (datagrams.iter())
+        .map(|(datagram, _)| libc::iovec {
+            // only read by the kernel
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+    let mut messages: Vec<libc::mmsghdr> = (iovecs.iter_mut().zip(&addresses))
+        .map(|(iovec, address)| {
+            // SAFETY: all-zero bytes are valid for this plain C structure
+            let mut message: libc::mmsghdr = unsafe { zeroed() };
+            message.msg_hdr.msg_name = address.as_ptr() as *mut libc::c_void;
+            message.msg_hdr.msg_namelen = address.len();
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    // SAFETY: every message points to a datagram and an address that outlive the call
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            messages.as_mut_ptr(),
+            messages.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_and_recv_batch() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (server_address, client_address) =
+            (server.local_addr().unwrap(), client.local_addr().unwrap());
+
+        let datagrams: Vec<Datagram> = (0..3u8)
+            .map(|i| (vec![i; i as usize + 1], server_address))
+            .collect();
+        let sending = client.async_io(Interest::WRITABLE, || {
+            send_batch(client.as_raw_fd(), &datagrams)
+        });
+        assert_eq!(sending.await.unwrap(), 3);
+
+        let mut received = vec![];
+        while received.len() < 3 {
+            server.readable().await.unwrap();
+            let receiving = server.try_io(Interest::READABLE, || {
+                recv_batch(server.as_raw_fd(), BATCH_SIZE)
+            });
+            match receiving {
+                Ok(datagrams) => received.extend(datagrams),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            }
+        }
+        let expected: Vec<Datagram> = (0..3u8)
+            .map(|i| (vec![i; i as usize + 1], client_address))
+            .collect();
+        assert_eq!(received, expected);
+
+        // nothing left, and batches are limited to `max`
+        client.send_to(&[1], server_address).await.unwrap();
+        client.send_to(&[2], server_address).await.unwrap();
+        let mut received = vec![];
+        while received.len() < 2 {
+            server.readable().await.unwrap();
+            let receiving = server.try_io(Interest::READABLE, || recv_batch(server.as_raw_fd(), 1));
+            if let Ok(datagrams) = receiving {
+                assert_eq!(datagrams.len(), 1);
+                received.extend(datagrams);
+            }
+        }
+    }
+}
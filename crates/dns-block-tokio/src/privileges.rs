@@ -0,0 +1,181 @@
+//! Dropping root privileges once the listeners are bound, so the server can listen on port 53
+//! without answering queries as root. Given `--chroot`, the server is also confined to a
+//! directory, in which files it opens later are then looked up, eg. `--cache-file`.
+
+use std::{ffi::CString, io, mem::MaybeUninit, path::Path, ptr::null_mut};
+
+use tracing::{info, warn};
+
+use crate::cli::ServerArgs;
+
+/// The user and group the server runs as after dropping privileges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Switches to `--user` and `--group` after changing the root directory to `--chroot`, if the
+/// server runs as root. Fails if the identity could not be switched to entirely, as continuing
+/// with part of root's privileges would be worse than not starting.
+pub fn drop_privileges(server_args: &ServerArgs) -> Result<(), String> {
+    // SAFETY: never fails
+    let root = unsafe { libc::geteuid() } == 0;
+    let Some(user) = &server_args.user else {
+        if root {
+            warn!("Running as root, set --user to drop privileges once listening");
+        }
+        if server_args.chroot.is_some() || server_args.group.is_some() {
+            return Err("--group and --chroot need --user".to_string());
+        }
+        return Ok(());
+    };
+    if !root {
+        info!("Not running as root, keeping the privileges of the current user");
+        return Ok(());
+    }
+    // the databases are no longer found once in the new root
+    let identity = identity(user, server_args.group.as_deref())?;
+
+    if let Some(directory) = &server_args.chroot {
+        change_root(directory).map_err(|e| format!("chroot to {}: {e}", directory.display()))?;
+    }
+    // SAFETY: plain system calls, the group list outlives the call. The groups are set first,
+    // the user could not change them anymore.
+    unsafe {
+        check(libc::setgroups(1, &identity.gid))?;
+        check(libc::setgid(identity.gid))?;
+        check(libc::setuid(identity.uid))?;
+    }
+    // SAFETY: as above
+    if identity.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err("root privileges could be regained".to_string());
+    }
+    info!(
+        "Running as user {} and group {}",
+        identity.uid, identity.gid
+    );
+    Ok(())
+}
+
+/// Looks up the ids of `user` and `group`, which may be given by name or as numbers. Without a
+/// group, that of the user is taken.
+pub fn identity(user: &str, group: Option<&str>) -> Result<Identity, String> {
+    let (uid, primary) = match user.parse() {
+        Ok(uid) => (uid, None),
+        Err(_) => {
+            let (uid, gid) = lookup_user(user)?.ok_or_else(|| format!("no user named {user}"))?;
+            (uid, Some(gid))
+        }
+    };
+    let gid = match group {
+        Some(group) => match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => lookup_group(group)?.ok_or_else(|| format!("no group named {group}"))?,
+        },
+        None => match primary {
+            Some(gid) => gid,
+            None => {
+                lookup_uid(uid)?.ok_or_else(|| format!("no user with id {uid}, set --group"))?
+            }
+        },
+    };
+    Ok(Identity { uid, gid })
+}
+
+fn change_root(directory: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(directory.as_os_str().as_bytes())?;
+    // SAFETY: the path is a NUL-terminated string that outlives the call
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // the previous working directory would still be reachable through `..`
+    std::env::set_current_dir("/")
+}
+
+fn check(result: libc::c_int) -> Result<(), String> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error().to_string()),
+    }
+}
+
+/// The id and group of the user `name`
+fn lookup_user(name: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>, String> {
+    let name = CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: the entry and buffer outlive the call, which is told their sizes
+    let passwd = lookup(|entry, buffer, found| unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            found,
+        )
+    })?;
+    Ok(passwd.map(|passwd: libc::passwd| (passwd.pw_uid, passwd.pw_gid)))
+}
+
+/// The group of the user with the id `uid`
+fn lookup_uid(uid: libc::uid_t) -> Result<Option<libc::gid_t>, String> {
+    // SAFETY: as above
+    let passwd = lookup(|entry, buffer, found| unsafe {
+        libc::getpwuid_r(uid, entry, buffer.as_mut_ptr(), buffer.len(), found)
+    })?;
+    Ok(passwd.map(|passwd: libc::passwd| passwd.pw_gid))
+}
+
+/// The id of the group `name`
+fn lookup_group(name: &str) -> Result<Option<libc::gid_t>, String> {
+    let name = CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: as above
+    let group = lookup(|entry, buffer, found| unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            found,
+        )
+    })?;
+    Ok(group.map(|group: libc::group| group.gr_gid))
+}
+
+/// Calls one of the reentrant lookups of the user and group databases, growing the buffer for
+/// the strings of the entry until they fit. Those are dropped on return, so only the ids of the
+/// entry may be used.
+fn lookup<T>(
+    mut call: impl FnMut(*mut T, &mut [libc::c_char], *mut *mut T) -> libc::c_int,
+) -> Result<Option<T>, String> {
+    let mut buffer = vec![0; 1024];
+    loop {
+        let mut entry = MaybeUninit::<T>::uninit();
+        let mut found = null_mut();
+        match call(entry.as_mut_ptr(), &mut buffer, &mut found) {
+            // SAFETY: the entry was written if one was found
+            0 if !found.is_null() => return Ok(Some(unsafe { entry.assume_init() })),
+            0 => return Ok(None),
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(buffer.len() * 2, 0),
+            error => return Err(io::Error::from_raw_os_error(error).to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(identity("root", None), Ok(Identity { uid: 0, gid: 0 }));
+        assert_eq!(identity("0", None), Ok(Identity { uid: 0, gid: 0 }));
+        assert_eq!(identity("0", Some("0")), Ok(Identity { uid: 0, gid: 0 }));
+        assert_eq!(identity("1234567", Some("42")).unwrap().gid, 42);
+        assert!(identity("no-such-user", None).is_err());
+        assert!(identity("root", Some("no-such-group")).is_err());
+        // numeric ids without an entry need a group
+        assert!(identity("1234567", None).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! A log of every query answered, one JSON object per line, like the query log of Pi-hole. It is
+//! written by a thread of its own, so slow disks do not hold up answering, and rotated once it
+//! grows too large or too old. Rotated files are named after the time they were rotated at and
+//! deleted once there are too many or they are too old.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use dns::protocol::{record_type::RecordType, response_code::ResponseCode};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    logging::{json_object, timestamp},
+    resolution::Protocol,
+};
+
+/// Most entries waiting to be written, further ones are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// A query answered, or left unanswered
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub client: SocketAddr,
+    pub protocol: Protocol,
+    pub name: String,
+    pub r#type: RecordType,
+    /// `None` if the query went unanswered
+    pub rcode: Option<ResponseCode>,
+    pub blocked: bool,
+    /// Whether the response came from the cache, including stale records
+    pub cached: bool,
+    pub latency: Duration,
+}
+
+impl Entry {
+    fn to_json(&self) -> String {
+        json_object(self.fields())
+    }
+
+    /// The fields of the entry as written to the log
+    pub fn fields(&self) -> [(&'static str, Value); 9] {
+        [
+            ("timestamp", timestamp(self.time).into()),
+            ("client", self.client.ip().to_string().into()),
+            (
+                "protocol",
+                format!("{:?}", self.protocol).to_lowercase().into(),
+            ),
+            ("qname", self.name.clone().into()),
+            ("qtype", self.r#type.to_string().into()),
+            ("rcode", self.rcode.map(|rcode| rcode.to_string()).into()),
+            ("blocked", self.blocked.into()),
+            ("cached", self.cached.into()),
+            // to the microsecond
+            (
+                "latency_ms",
+                (self.latency.as_micros() as f64 / 1000.0).into(),
+            ),
+        ]
+    }
+}
+
+/// When the log is rotated and how many rotated files are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Size the log is rotated at, `None` for any size
+    pub max_bytes: Option<u64>,
+    /// Age the log is rotated at, `None` for any age
+    pub max_age: Option<Duration>,
+    /// Most rotated files kept, the oldest are deleted first
+    pub keep_files: usize,
+    /// Age rotated files are deleted at, `None` to keep them regardless
+    pub keep_age: Option<Duration>,
+}
+
+/// The query log, which entries are sent to for writing
+pub struct QueryLog {
+    entries: SyncSender<Entry>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+    /// Opens the log at `path`, appending to it if it exists, and starts the thread writing to it
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let writer = Writer::open(path.to_path_buf(), rotation)?;
+        let (entries, received) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let reported = Arc::clone(&dropped);
+        std::thread::spawn(move || writer.run(received, &reported));
+        Ok(QueryLog { entries, dropped })
+    }
+
+    /// Queues `entry` to be written, or drops it if the writer cannot keep up
+    pub fn record(&self, entry: Entry) {
+        if let Err(TrySendError::Full(_)) = self.entries.try_send(entry) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    file: BufWriter<File>,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl Writer {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // an existing log is as old as its first entry, which is about when it was created
+        let opened = (metadata.created().or_else(|_| metadata.modified()))
+            .unwrap_or_else(|_| SystemTime::now());
+        Ok(Writer {
+            path,
+            rotation,
+            file: BufWriter::new(file),
+            size: metadata.len(),
+            opened,
+        })
+    }
+
+    fn run(mut self, entries: Receiver<Entry>, dropped: &AtomicU64) {
+        // written in batches, flushed once there is nothing more to write
+        while let Ok(entry) = entries.recv() {
+            let mut result = self.write(&entry, entry.time);
+            while let (Ok(()), Ok(entry)) = (&result, entries.try_recv()) {
+                result = self.write(&entry, entry.time);
+            }
+            if let Err(e) = result.and_then(|()| self.file.flush()) {
+                warn!("Could not write query log {}: {e}", self.path.display());
+            }
+            let dropped = dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!("Dropped {dropped} query log entries, the log could not keep up");
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &Entry, now: SystemTime) -> io::Result<()> {
+        let line = entry.to_json() + "\n";
+        if self.size > 0 && self.due(line.len() as u64, now) {
+            self.rotate(now)?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Whether the log is to be rotated before writing `len` more bytes
+    fn due(&self, len: u64, now: SystemTime) -> bool {
+        let age = now.duration_since(self.opened).unwrap_or_default();
+        (self.rotation.max_bytes).is_some_and(|max_bytes| self.size + len > max_bytes)
+            || (self.rotation.max_age).is_some_and(|max_age| age >= max_age)
+    }
+
+    /// Renames the log after the time `now` and starts a new one, then deletes the rotated files
+    /// that are no longer kept
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(format!(".{}", since_epoch.as_millis()));
+        fs::rename(&self.path, &rotated)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.opened = now;
+        self.delete_rotated(now)
+    }
+
+    fn delete_rotated(&self, now: SystemTime) -> io::Result<()> {
+        let mut rotated = rotated_files(&self.path)?;
+        // newest first
+        rotated.sort_by_key(|&(rotated_at, _)| std::cmp::Reverse(rotated_at));
+        for (i, (rotated_at, path)) in rotated.into_iter().enumerate() {
+            let age = now.duration_since(rotated_at).unwrap_or_default();
+            let expired = (self.rotation.keep_age).is_some_and(|keep_age| age >= keep_age);
+            if i >= self.rotation.keep_files || expired {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files the log at `path` was rotated to, with the times they were rotated at
+fn rotated_files(path: &Path) -> io::Result<Vec<(SystemTime, PathBuf)>> {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{name}.");
+    let mut rotated = vec![];
+    for file in fs::read_dir(directory)? {
+        let file = file?;
+        let file_name = file.file_name();
+        let millis = (file_name.to_str())
+            .and_then(|file_name| file_name.strip_prefix(&prefix))
+            .and_then(|millis| millis.parse().ok());
+        if let Some(millis) = millis {
+            rotated.push((UNIX_EPOCH + Duration::from_millis(millis), file.path()));
+        }
+    }
+    Ok(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> Entry {
+        Entry {
+            time: UNIX_EPOCH + Duration::from_millis(1_730_376_000_123),
+            client: "192.0.2.1:53000".parse().unwrap(),
+            protocol: Protocol::Udp,
+            name: name.to_string(),
+            r#type: RecordType::AAAA,
+            rcode: Some(ResponseCode::NXDomain),
+            blocked: true,
+            cached: false,
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(
+            entry("ads.example.com").to_json(),
+            r#"{"timestamp":"2024-10-31T12:00:00.123Z","client":"192.0.2.1","protocol":"udp","qname":"ads.example.com","qtype":"AAAA","rcode":"NXDOMAIN","blocked":true,"cached":false,"latency_ms":1.5}"#
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        let directory = std::env::temp_dir().join(format!("query-log-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("queries.log");
+        let line_len = entry("example.com").to_json().len() as u64 + 1;
+        let rotation = Rotation {
+            max_bytes: Some(2 * line_len),
+            max_age: Some(Duration::from_secs(3600)),
+            keep_files: 2,
+            keep_age: Some(Duration::from_secs(24 * 3600)),
+        };
+        let mut writer = Writer::open(path.clone(), rotation).unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(1_730_376_000);
+        writer.opened = start;
+
+        // two entries fit, the third one starts a new file
+        for _ in 0..3 {
+            writer.write(&entry("example.com"), start).unwrap();
+        }
+        writer.file.flush().unwrap();
+        let rotated = rotated_files(&path).unwrap();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(rotated[0].0, start);
+        assert_eq!(fs::metadata(&rotated[0].1).unwrap().len(), 2 * line_len);
+        assert_eq!(fs::metadata(&path).unwrap().len(), line_len);
+
+        // by age, keeping two rotated files
+        for hour in 1..=3 {
+            writer
+                .write(
+                    &entry("example.com"),
+                    start + Duration::from_secs(hour * 3600),
+                )
+                .unwrap();
+        }
+        let mut rotated: Vec<_> = (rotated_files(&path).unwrap().into_iter())
+            .map(|(rotated_at, _)| rotated_at.duration_since(start).unwrap().as_secs())
+            .collect();
+        rotated.sort();
+        assert_eq!(rotated, [2 * 3600, 3 * 3600]);
+
+        // and none older than a day
+        writer
+            .write(
+                &entry("example.com"),
+                start + Duration::from_secs(27 * 3600),
+            )
+            .unwrap();
+        let rotated = rotated_files(&path).unwrap();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(rotated[0].0, start + Duration::from_secs(27 * 3600));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
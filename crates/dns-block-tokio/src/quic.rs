@@ -0,0 +1,206 @@
+//! Queries over DNS over QUIC, available with the `doq` feature. Each query arrives on a stream
+//! of its own and goes through the same pipeline as over TCP, zone transfers included.
+//! https://datatracker.ietf.org/doc/html/rfc9250
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use quinn::{
+    crypto::rustls::QuicServerConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream,
+    ServerConfig, TokioRuntime, TransportConfig, VarInt,
+};
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use crate::{listen::bind_udp, resolution::Protocol, state::Server, tcp::answer};
+
+/// Streams a client may have open at the same time, ie. queries in flight
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+// error codes
+// https://datatracker.ietf.org/doc/html/rfc9250#section-8.4
+const DOQ_NO_ERROR: u32 = 0x0;
+const DOQ_INTERNAL_ERROR: u32 = 0x1;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// A QUIC endpoint on `address` with the TLS configuration `tls`, whose connections are closed
+/// once idle for `idle_timeout`. The socket is bound like the ones of UDP listeners, so `[::]`
+/// and `0.0.0.0` can be listened on side by side.
+pub fn bind_quic(
+    address: SocketAddr,
+    tls: Arc<rustls::ServerConfig>,
+    idle_timeout: Duration,
+) -> Result<Endpoint, String> {
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| e.to_string())?;
+    let mut transport = TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(VarInt::from_u32(MAX_CONCURRENT_STREAMS))
+        .max_concurrent_uni_streams(VarInt::from_u32(0))
+        .max_idle_timeout(Some(idle_timeout.try_into().map_err(|e| format!("{e}"))?));
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+
+    let socket = bind_udp(address).and_then(|socket| socket.into_std());
+    let socket = socket.map_err(|e| e.to_string())?;
+    let runtime = Arc::new(TokioRuntime);
+    Endpoint::new(EndpointConfig::default(), Some(config), socket, runtime)
+        .map_err(|e| e.to_string())
+}
+
+/// Accepts connections until the process exits, at most `max_connections` at a time. Further
+/// ones are refused, so their clients try elsewhere.
+pub async fn serve_quic(endpoint: Endpoint, max_connections: usize, server: Arc<Server>) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+    while let Some(incoming) = endpoint.accept().await {
+        let client = incoming.remote_address();
+        let Ok(permit) = Arc::clone(&connections).try_acquire_owned() else {
+            if !server.state().args.quiet {
+                info!(client = %client, "Refusing QUIC connection, too many are open");
+            }
+            incoming.refuse();
+            continue;
+        };
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let served = match incoming.await {
+                Ok(connection) => serve(connection, client, &server).await,
+                Err(e) => Err(io::Error::other(e)),
+            };
+            if let Err(e) = served {
+                if !server.state().args.quiet {
+                    info!(client = %client, error = %e, "QUIC connection failed");
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Answers the queries of `connection` concurrently until the client closes it or it times out
+async fn serve(connection: Connection, client: SocketAddr, server: &Server) -> io::Result<()> {
+    loop {
+        let (send, receive) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(
+                quinn::ConnectionError::ApplicationClosed(_)
+                | quinn::ConnectionError::ConnectionClosed(_)
+                | quinn::ConnectionError::TimedOut
+                | quinn::ConnectionError::LocallyClosed,
+            ) => return Ok(()),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        let (connection, state) = (connection.clone(), server.state());
+        // no further streams are accepted while all slots are taken
+        let slot = state.query_slot().await;
+        tokio::spawn(async move {
+            match query(receive).await {
+                Ok(query) => {
+                    let messages = answer(&query, client, Protocol::Quic, &state);
+                    respond(send, messages.await).await;
+                }
+                Err(code) => connection.close(VarInt::from_u32(code), b""),
+            }
+            drop(slot);
+        });
+    }
+}
+
+/// Reads the query of a stream, or the error code the connection is to be closed with
+/// https://datatracker.ietf.org/doc/html/rfc9250#section-4.2
+async fn query(mut receive: RecvStream) -> Result<Vec<u8>, u32> {
+    let message = match receive.read_to_end(2 + u16::MAX as usize).await {
+        Ok(message) => message,
+        Err(quinn::ReadToEndError::TooLong) => return Err(DOQ_PROTOCOL_ERROR),
+        // the stream or connection is gone, so nobody waits for an answer
+        Err(_) => return Err(DOQ_NO_ERROR),
+    };
+    let len = message
+        .get(..2)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]));
+    if len.map(usize::from) != Some(message.len().saturating_sub(2)) {
+        return Err(DOQ_PROTOCOL_ERROR);
+    }
+    // the stream identifies the query, so its ID has to be 0
+    if message.get(2..4).is_some_and(|id| id != [0, 0]) {
+        return Err(DOQ_PROTOCOL_ERROR);
+    }
+    Ok(message[2..].to_vec())
+}
+
+/// Writes the `messages` answering a query, each prefixed with its length, and finishes the
+/// stream. Queries without an answer have their stream reset.
+async fn respond(mut send: SendStream, messages: Option<Vec<Vec<u8>>>) {
+    let Some(messages) = messages else {
+        let _ = send.reset(VarInt::from_u32(DOQ_INTERNAL_ERROR));
+        return;
+    };
+    let mut bytes = vec![];
+    for message in messages {
+        bytes.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        bytes.extend(message);
+    }
+    // the client may have cancelled the query in the meantime
+    if send.write_all(&bytes).await.is_ok() {
+        let _ = send.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use dns::{
+        parse::parser::DnsParser,
+        protocol::query::QueryBuilder,
+        transport::quic::{DoqTransport, DOQ_ALPN},
+    };
+    use rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore};
+
+    use crate::{cli::ServerArgs, state::State, tls::server_config};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_quic() {
+        let directory = std::env::temp_dir().join(format!("serve-quic-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".into()]).unwrap();
+        let (cert, key) = (directory.join("doq.pem"), directory.join("doq.key"));
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        let tls = server_config(&cert, &key, None, &[DOQ_ALPN]).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--record=example.com=192.0.2.1",
+            "--quiet",
+        ]);
+        let address = "127.0.0.1:0".parse().unwrap();
+        let endpoint = bind_quic(address, tls, Duration::from_secs(5)).unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        let server = Arc::new(Server::new(State::new(server_args)));
+        tokio::spawn(serve_quic(endpoint, 8, server));
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(certified.cert.der().to_vec()))
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let url = format!("quic://127.0.0.1:{port}");
+        let transport = DoqTransport::with_tls_config(&url, Arc::new(client_config)).unwrap();
+        for id in [1, 2] {
+            let query = QueryBuilder::new("example.com".parse().unwrap())
+                .id(id)
+                .build();
+            let exchange = transport.exchange_async(&query, Duration::from_secs(5));
+            let response = exchange.await.unwrap();
+            let packet = DnsParser::new(&response).parse_packet().unwrap();
+            assert_eq!(packet.header.request_id, id);
+            assert_eq!(packet.answers.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Limiting how many queries each client may send, so one client cannot take up the server and
+//! the server cannot be used to flood others with responses to queries sent from their
+//! addresses. Every client has a token bucket holding up to `--rate-limit-burst` queries, which
+//! refills at `--rate-limit-qps`. Queries finding it empty are answered with REFUSED, or not at
+//! all. IPv6 clients are limited by their /64, which a single host usually has to itself.
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Shards of the buckets, each locked on its own
+const SHARDS: usize = 16;
+
+/// Most clients tracked across the shards. Once one is full, clients that are not tracked yet
+/// are limited until buckets are pruned, so a flood from forged addresses cannot grow it
+const MAX_CLIENTS: usize = 65_536;
+
+/// How often a full shard is pruned of the buckets that refilled, which it may as well forget
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happens to queries over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Answered with REFUSED, which is no larger than the query
+    Refused,
+    /// Not answered
+    Drop,
+}
+
+impl FromStr for RateLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refused" => Ok(RateLimitAction::Refused),
+            "drop" => Ok(RateLimitAction::Drop),
+            _ => Err(format!(
+                "unknown rate limit action {s}, expected refused or drop"
+            )),
+        }
+    }
+}
+
+/// The token buckets of the clients
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens added per second
+    rate: f64,
+    /// Most tokens a bucket holds
+    burst: f64,
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allows each client `qps` queries a second, and `burst` at once
+    pub fn new(qps: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: f64::from(qps),
+            burst: f64::from(burst.max(1)),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Whether `client` may send another query at `now`, taking a token from its bucket if so
+    pub fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let client = prefix(client);
+        let shard = (self.hasher.hash_one(client) as usize) % SHARDS;
+        let mut shard = self.shards[shard].lock().unwrap();
+        if !shard.buckets.contains_key(&client) && shard.buckets.len() >= MAX_CLIENTS / SHARDS {
+            if shard
+                .pruned
+                .is_some_and(|pruned| now - pruned < PRUNE_INTERVAL)
+            {
+                return false;
+            }
+            shard.pruned = Some(now);
+            shard
+                .buckets
+                .retain(|_, bucket| self.refill(*bucket, now) < self.burst);
+            if shard.buckets.len() >= MAX_CLIENTS / SHARDS {
+                return false;
+            }
+        }
+        let bucket = shard.buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(*bucket, now);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// The tokens `bucket` holds at `now`
+    fn refill(&self, bucket: Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// What `client` is limited by, its /64 if an IPv6 address
+fn prefix(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(address) => {
+            let network = u128::from(address) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(network))
+        }
+        address => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow() {
+        let limiter = RateLimiter::new(2, 3);
+        let client = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        // the burst at once, then as the bucket refills
+        assert_eq!(
+            (0..4)
+                .map(|_| limiter.allow(client, start))
+                .collect::<Vec<_>>(),
+            [true, true, true, false]
+        );
+        assert!(limiter.allow("192.0.2.2".parse().unwrap(), start));
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow(client, later));
+        assert!(!limiter.allow(client, later));
+        // never more than the burst
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(
+            (0..4).filter(|_| limiter.allow(client, much_later)).count(),
+            3
+        );
+
+        // by /64, and IPv4 the same whether mapped or not
+        let limiter = RateLimiter::new(1, 1);
+        assert!(limiter.allow("2001:db8::1".parse().unwrap(), start));
+        assert!(!limiter.allow("2001:db8::2".parse().unwrap(), start));
+        assert!(limiter.allow("2001:db8:0:1::1".parse().unwrap(), start));
+        assert!(limiter.allow("::ffff:192.0.2.1".parse().unwrap(), start));
+        assert!(!limiter.allow("192.0.2.1".parse().unwrap(), start));
+    }
+
+    #[test]
+    fn test_prune() {
+        let limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+        let clients: Vec<IpAddr> = (0..MAX_CLIENTS as u32)
+            .map(|i| IpAddr::from((0x0a00_0000 + i).to_be_bytes()))
+            .collect();
+        for &client in &clients {
+            limiter.allow(client, start);
+        }
+        let tracked = |limiter: &RateLimiter| -> usize {
+            (limiter.shards.iter())
+                .map(|shard| shard.lock().unwrap().buckets.len())
+                .sum()
+        };
+        let full = tracked(&limiter);
+        // as the clients fill the shards, new ones are limited while the buckets are in use
+        let limited = (0..=255)
+            .map(|i| IpAddr::from([192, 0, 2, i]))
+            .find(|&client| !limiter.allow(client, start))
+            .unwrap();
+        assert!(!limiter.allow(limited, start + Duration::from_millis(500)));
+        // and tracked once the others refilled
+        assert!(limiter.allow(limited, start + Duration::from_secs(2)));
+        assert!(tracked(&limiter) < full);
+    }
+}
@@ -1,64 +1,193 @@
+use std::{net::SocketAddr, sync::Arc};
+
 use dns::{
-    parse::parser::{DnsPacketBuffer, DnsParser},
-    protocol::{question::Question, utils::generate_nx_response},
-    resolver::{relay_query_async, stub_response_with_delay},
+    parse::parser::DnsParser,
+    protocol::{header::Flags, packet::Packet, response_code::ResponseCode},
+    resolver::{
+        bind_query_socket_async, prefetch_async, relay_exchange_async, stub_response_with_delay,
+        QuerySocket, Relayed, ResolveOptions,
+    },
 };
+use tracing::{info, warn};
+
+use crate::{cli::ServerArgs, state::State};
+
+/// How a query reached the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    /// DNS over TLS
+    Tls,
+    /// DNS over HTTPS
+    Https,
+    /// DNS over QUIC
+    Quic,
+}
+
+/// The socket a query is relayed from
+enum UpstreamSocket {
+    Tokio(tokio::net::UdpSocket),
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Ring(crate::uring::RingSocket),
+}
+
+impl UpstreamSocket {
+    /// Binds a socket on the io_uring of the upstreams if it could be set up
+    async fn bind(opts: &ResolveOptions) -> std::io::Result<Self> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(ring) = crate::uring::upstream_ring() {
+            return crate::uring::RingSocket::bind(ring, opts).map(Self::Ring);
+        }
+        bind_query_socket_async(opts).await.map(Self::Tokio)
+    }
+}
 
-use crate::cli::ServerArgs;
+impl QuerySocket for UpstreamSocket {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Self::Tokio(socket) => socket.local_addr(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Self::Ring(socket) => socket.local_addr(),
+        }
+    }
+
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+        match self {
+            Self::Tokio(socket) => socket.send_to(datagram, target).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Self::Ring(socket) => socket.send_to(datagram, target).await,
+        }
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Tokio(socket) => socket.recv_from(buffer).await,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            Self::Ring(socket) => socket.recv_from(buffer).await,
+        }
+    }
+}
 
+/// Relays `query` to the cache or the upstreams, `None` if none of them could answer it
 pub async fn handle_resolution(
-    query: &DnsPacketBuffer,
-    server_args: &ServerArgs,
-    receiving_socket: &tokio::net::UdpSocket,
-    sender: &std::net::SocketAddr,
+    query: &[u8],
+    protocol: Protocol,
+    state: &State,
     start: std::time::SystemTime,
-) {
-    let upstream_socket = tokio::net::UdpSocket::bind(("0.0.0.0", 0)).await.unwrap();
-    match relay_query_async(query, &server_args.dns_relay, &upstream_socket).await {
-        Ok(reply) => {
-            receiving_socket.send_to(&reply, sender).await.unwrap();
+) -> Option<Relayed> {
+    let (server_args, upstreams) = (&state.args, &state.upstreams);
+    let mut opts = state.resolve_options();
+    // clients over anything but UDP take responses of any size, so truncated ones are resolved
+    // over TCP
+    opts.tcp_fallback = protocol != Protocol::Udp;
+    // eg. out of file descriptors, the caller answers SERVFAIL then
+    let upstream_socket = match UpstreamSocket::bind(&opts).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(outcome = "failed", error = %e, "Could not bind a socket for the upstreams");
+            return None;
+        }
+    };
+    match relay_exchange_async(query, upstreams, &upstream_socket, &opts).await {
+        Ok(relayed) => {
+            if opts
+                .cache
+                .as_ref()
+                .is_some_and(|cache| cache.take_prefetch(query))
+            {
+                let query = query.to_vec();
+                let upstreams = Arc::clone(upstreams);
+                tokio::spawn(async move {
+                    if let Err(e) = prefetch_async(&query, &upstreams, &opts).await {
+                        warn!(error = %e, "Could not prefetch");
+                    }
+                });
+            }
             if !server_args.quiet {
-                // We only pick the first question, since multiple questions seem to be unsupported by most
-                // nameservers anyways, see https://stackoverflow.com/questions/4082081/requesting-a-and-aaaa-records-in-single-dns-query/4083071#4083071.
-                let (_, question) = DnsParser::new(query).get_relay_information().unwrap();
-                println!(
-                    "Handled query for {} [{}ms]",
-                    &question.domain_name,
-                    std::time::SystemTime::now()
-                        .duration_since(start)
-                        .unwrap()
-                        .as_millis()
+                let rtt = std::time::SystemTime::now().duration_since(start).unwrap();
+                info!(
+                    outcome = "resolved",
+                    rtt_ms = rtt.as_millis() as u64,
+                    "Handled query"
                 );
             }
+            Some(relayed)
+        }
+        Err(e) if e.is_timeout() => {
+            if !server_args.quiet {
+                info!(outcome = "timed out", "Upstreams timed out resolving query");
+            }
+            None
         }
         Err(e) => {
-            dbg!(e);
+            warn!(outcome = "failed", error = %e, "Could not resolve query");
+            None
         }
     }
 }
 
-pub async fn handle_filter(
-    server_args: &ServerArgs,
-    question: &Question,
-    request_id: u16,
-    socket: &tokio::net::UdpSocket,
-    sender: &std::net::SocketAddr,
-) {
+/// A response to `query` with its ID and question that only carries `rcode`, eg. SERVFAIL when
+/// no upstream answered it
+pub fn error_response(query: &[u8], rcode: ResponseCode) -> Option<Vec<u8>> {
+    let query = DnsParser::new(query).parse_packet().ok()?;
+    let mut response = Packet {
+        questions: query.questions,
+        ..Packet::default()
+    };
+    response.header.request_id = query.header.request_id;
+    response.header.flags = Flags {
+        query: false,
+        opcode: query.header.flags.opcode,
+        recursion_desired: query.header.flags.recursion_desired,
+        recursion_available: true,
+        response_code: rcode,
+        ..Flags::default()
+    };
+    Some(response.to_bytes())
+}
+
+pub fn handle_filter(server_args: &ServerArgs, response: Vec<u8>) -> Vec<u8> {
     if !server_args.quiet {
-        println!("Blocking request for {:?}", question.domain_name);
+        info!(outcome = "blocked", "Blocking query");
     }
-    let nx_response = generate_nx_response(request_id).unwrap();
-    socket.send_to(&nx_response, sender).await.unwrap();
+    response
+}
+
+pub fn handle_local(server_args: &ServerArgs, response: Vec<u8>) -> Vec<u8> {
+    if !server_args.quiet {
+        info!(outcome = "local", "Answered query from local records");
+    }
+    response
 }
 
 pub async fn handle_benchmark(
     request_id: u16,
-    socket: &tokio::net::UdpSocket,
-    sender: &std::net::SocketAddr,
     resolution_delay: std::time::Duration,
-) {
+) -> Option<Vec<u8>> {
     let (_, reply) = stub_response_with_delay(Some(request_id), resolution_delay)
         .await
         .unwrap();
-    socket.send_to(&reply, sender).await.unwrap();
+    Some(reply.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use dns::protocol::query::QueryBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_error_response() {
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(0x1234)
+            .build();
+        let response = error_response(&query, ResponseCode::Refused).unwrap();
+        let response = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(response.header.request_id, 0x1234);
+        assert_eq!(response.header.rcode(), ResponseCode::Refused);
+        assert!(!response.header.flags.query);
+        assert_eq!(response.questions.len(), 1);
+        assert!(error_response(&[0; 5], ResponseCode::ServFail).is_none());
+    }
 }
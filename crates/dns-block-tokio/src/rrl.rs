@@ -0,0 +1,290 @@
+//! Response rate limiting like BIND's, for when the server is reachable from the internet and
+//! used to reflect responses at the forged addresses of queries. Unlike [`crate::rate_limit`],
+//! which limits queries by client, it limits the responses sent over UDP by what they are and
+//! the network they go to: answers by name and type, NXDOMAIN and errors regardless of the name,
+//! each to an IPv4 /24 or IPv6 /56 by default. Once a network receives more of one than
+//! `--rrl-responses-per-second` over `--rrl-window-secs`, every `--rrl-slip`th response is sent
+//! truncated with nothing but the question, so real clients retry over TCP, which cannot be
+//! forged, and the others are dropped.
+//! https://kb.isc.org/docs/aa-00994
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use dns::{
+    parse::parser::DnsParser,
+    protocol::{record_type::RecordType, response_code::ResponseCode},
+    serialize::writer::write_response,
+};
+
+/// Shards of the accounts, each locked on its own
+const SHARDS: usize = 16;
+
+/// Most responses accounted for across the shards. Once one is full, responses that are not
+/// accounted for yet are slipped until it is pruned, so a flood from forged addresses cannot
+/// grow it
+const MAX_ACCOUNTS: usize = 65_536;
+
+/// How often a full shard is pruned of the accounts that recovered, which it may as well forget
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What becomes of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Send,
+    /// Sent truncated, see [`truncated`]
+    Slip,
+    Drop,
+}
+
+/// How rates are limited, see the arguments of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RrlSettings {
+    pub responses_per_second: u32,
+    pub window: Duration,
+    /// Every how many limited responses one is slipped, 0 for none
+    pub slip: u32,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+/// The kind of response accounted for separately
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Kind {
+    /// By the name and type answered, including responses without records
+    Answer(String, RecordType),
+    /// For any name, as floods of random names all have names of their own
+    NxDomain,
+    /// Any other response code
+    Error,
+}
+
+/// The responses sent lately, by the network they went to and what they were
+#[derive(Debug)]
+pub struct ResponseRateLimiter {
+    settings: RrlSettings,
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    accounts: HashMap<(IpAddr, Kind), Account>,
+    pruned: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Account {
+    /// Responses that may still be sent, negative once over the limit, down to a window's worth
+    balance: f64,
+    updated: Instant,
+    /// Responses limited, for slipping every so many
+    limited: u32,
+}
+
+impl ResponseRateLimiter {
+    pub fn new(settings: RrlSettings) -> Self {
+        ResponseRateLimiter {
+            settings,
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Accounts for a response with `rcode` to `name` and `type` sent to `client` at `now`, and
+    /// whether to send it
+    pub fn check(
+        &self,
+        client: IpAddr,
+        name: &str,
+        r#type: RecordType,
+        rcode: ResponseCode,
+        now: Instant,
+    ) -> Verdict {
+        let kind = match rcode {
+            ResponseCode::NoError => Kind::Answer(name.to_ascii_lowercase(), r#type),
+            ResponseCode::NXDomain => Kind::NxDomain,
+            _ => Kind::Error,
+        };
+        let key = (self.network(client), kind);
+        let shard = (self.hasher.hash_one(&key) as usize) % SHARDS;
+        let mut shard = self.shards[shard].lock().unwrap();
+        if !shard.accounts.contains_key(&key) && shard.accounts.len() >= MAX_ACCOUNTS / SHARDS {
+            let due = (shard.pruned).is_none_or(|pruned| now - pruned >= PRUNE_INTERVAL);
+            if due {
+                shard.pruned = Some(now);
+                let rate = self.rate();
+                shard
+                    .accounts
+                    .retain(|_, account| self.credit(*account, now) < rate);
+            }
+            if shard.accounts.len() >= MAX_ACCOUNTS / SHARDS {
+                return self.limited(1);
+            }
+        }
+        let account = shard.accounts.entry(key).or_insert(Account {
+            balance: self.rate(),
+            updated: now,
+            limited: 0,
+        });
+        let floor = -self.rate() * self.settings.window.as_secs_f64();
+        account.balance = (self.credit(*account, now) - 1.0).max(floor);
+        account.updated = now;
+        if account.balance >= 0.0 {
+            account.limited = 0;
+            return Verdict::Send;
+        }
+        account.limited = account.limited.wrapping_add(1);
+        self.limited(account.limited)
+    }
+
+    fn rate(&self) -> f64 {
+        f64::from(self.settings.responses_per_second)
+    }
+
+    /// The balance of `account` at `now`, at most a second's worth
+    fn credit(&self, account: Account, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(account.updated).as_secs_f64();
+        (account.balance + elapsed * self.rate()).min(self.rate())
+    }
+
+    /// What becomes of the `limited`th response over the limit in a row
+    fn limited(&self, limited: u32) -> Verdict {
+        match self.settings.slip {
+            0 => Verdict::Drop,
+            slip if limited.is_multiple_of(slip) => Verdict::Slip,
+            _ => Verdict::Drop,
+        }
+    }
+
+    /// The network of `client` that is accounted for as one
+    fn network(&self, client: IpAddr) -> IpAddr {
+        match client.to_canonical() {
+            IpAddr::V4(address) => {
+                let len = u32::from(self.settings.ipv4_prefix_len.min(32));
+                let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+            }
+            IpAddr::V6(address) => {
+                let len = u32::from(self.settings.ipv6_prefix_len.min(128));
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+            }
+        }
+    }
+}
+
+/// `response` cut down to its question with the TC bit set, which is no larger than the query
+/// and gets real clients to retry over TCP
+pub fn truncated(response: &[u8]) -> Option<Vec<u8>> {
+    let mut header = DnsParser::new(response).parse_header().ok()?;
+    let (_, questions) = DnsParser::new(response).get_relay_information().ok()?;
+    header.flags.truncation = true;
+    Some(write_response(&header, &questions, &[], response.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use dns::protocol::query::QueryBuilder;
+
+    use super::*;
+
+    fn limiter(slip: u32) -> ResponseRateLimiter {
+        ResponseRateLimiter::new(RrlSettings {
+            responses_per_second: 2,
+            window: Duration::from_secs(5),
+            slip,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        })
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = limiter(2);
+        let start = Instant::now();
+        let answer = |client: &str, name: &str, now: Instant| {
+            let client = client.parse().unwrap();
+            limiter.check(client, name, RecordType::A, ResponseCode::NoError, now)
+        };
+        let verdicts: Vec<_> = (0..6)
+            .map(|_| answer("192.0.2.1", "example.com", start))
+            .collect();
+        use Verdict::*;
+        assert_eq!(verdicts, [Send, Send, Drop, Slip, Drop, Slip]);
+        // the whole /24, names regardless of case, but not other names
+        assert_eq!(answer("192.0.2.200", "EXAMPLE.com", start), Drop);
+        assert_eq!(answer("192.0.2.1", "example.org", start), Send);
+        assert_eq!(answer("198.51.100.1", "example.com", start), Send);
+
+        // limited until the network was quiet for long enough, a window's worth at most
+        for _ in 0..20 {
+            answer("192.0.2.1", "example.com", start);
+        }
+        let later = start + Duration::from_secs(3);
+        assert_ne!(answer("192.0.2.1", "example.com", later), Send);
+        let much_later = start + Duration::from_secs(10);
+        assert_eq!(answer("192.0.2.1", "example.com", much_later), Send);
+
+        // NXDOMAIN for any name
+        let nxdomain = |name: &str| {
+            let client = "2001:db8:0:1::1".parse().unwrap();
+            limiter.check(client, name, RecordType::A, ResponseCode::NXDomain, start)
+        };
+        assert_eq!(nxdomain("a.example.com"), Send);
+        assert_eq!(nxdomain("b.example.com"), Send);
+        assert_eq!(nxdomain("c.example.com"), Drop);
+    }
+
+    #[test]
+    fn test_slip() {
+        let start = Instant::now();
+        let client = "192.0.2.1".parse().unwrap();
+        for (slip, expected) in [(0, Verdict::Drop), (1, Verdict::Slip)] {
+            let limiter = limiter(slip);
+            let verdicts: Vec<_> = (0..4)
+                .map(|_| limiter.check(client, "a.b", RecordType::A, ResponseCode::ServFail, start))
+                .collect();
+            assert_eq!(verdicts[2..], [expected, expected]);
+        }
+    }
+
+    #[test]
+    fn test_network() {
+        let limiter = limiter(2);
+        let network = |client: &str| limiter.network(client.parse().unwrap()).to_string();
+        assert_eq!(network("192.0.2.201"), "192.0.2.0");
+        assert_eq!(network("::ffff:192.0.2.201"), "192.0.2.0");
+        assert_eq!(network("2001:db8:1:2ff::1"), "2001:db8:1:200::");
+        let limiter = ResponseRateLimiter::new(RrlSettings {
+            ipv4_prefix_len: 0,
+            ipv6_prefix_len: 128,
+            ..limiter.settings
+        });
+        assert_eq!(
+            limiter.network("192.0.2.1".parse().unwrap()).to_string(),
+            "0.0.0.0"
+        );
+        let address = "2001:db8::1".parse().unwrap();
+        assert_eq!(limiter.network(address), address);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(7)
+            .build();
+        let response = truncated(&query).unwrap();
+        let header = DnsParser::new(&response).parse_header().unwrap();
+        assert!(header.flags.truncation);
+        assert_eq!(header.request_id, 7);
+        assert_eq!(header.question_count, 1);
+        assert_eq!(header.answer_count, 0);
+        assert!(response.len() <= query.len());
+    }
+}
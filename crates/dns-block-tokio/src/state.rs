@@ -0,0 +1,409 @@
+//! What the server answers queries with, built from its arguments when it starts and again
+//! whenever its configuration is reloaded
+
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use dns::{
+    authority::ZoneSet,
+    cache::DnsCache,
+    filter::{BlockingState, Blocklist},
+    hosts::HostsFile,
+    records::LocalRecords,
+    resolver::ResolveOptions,
+    secondary::SecondaryZone,
+    upstream::UpstreamPool,
+    zonefile::Zone,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+use crate::{
+    cli::ServerArgs, dashboard::Activity, metrics::Metrics, query_log::QueryLog,
+    rate_limit::RateLimiter, rrl::ResponseRateLimiter, zones,
+};
+
+/// The state new queries are answered with, replaced as a whole when the configuration is
+/// reloaded. Queries being answered keep the state they started with.
+pub struct Server {
+    state: RwLock<Arc<State>>,
+    /// The tasks keeping the zones of the current state up to date
+    zone_tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Server {
+    pub fn new(state: State) -> Self {
+        Server {
+            state: RwLock::new(Arc::new(state)),
+            zone_tasks: Mutex::default(),
+        }
+    }
+
+    /// The current state, to answer a query with
+    pub fn state(&self) -> Arc<State> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+
+    /// Answers the queries from now on with the state `args` stand for, see [`State::reload`]
+    pub fn reload(&self, args: ServerArgs) {
+        let state = self.state().reload(args);
+        *self.state.write().unwrap() = Arc::new(state);
+        self.spawn_zone_tasks();
+    }
+
+    /// Starts keeping the zones of the current state up to date, see
+    /// [`zones::spawn_zone_tasks`], and stops the tasks that kept those of the state before
+    pub fn spawn_zone_tasks(&self) {
+        let tasks = zones::spawn_zone_tasks(&self.state());
+        let before = std::mem::replace(&mut *self.zone_tasks.lock().unwrap(), tasks);
+        before.iter().for_each(JoinHandle::abort);
+    }
+}
+
+/// Everything the queries of a server share. Each server builds its own from its arguments, so
+/// servers in the same process, eg. those of tests, do not see each other's caches or lists.
+pub struct State {
+    pub args: ServerArgs,
+    pub upstreams: Arc<UpstreamPool>,
+    /// Kept even with `--no-cache`, nothing is added to it then
+    pub cache: Arc<DnsCache>,
+    pub hosts: Option<Arc<HostsFile>>,
+    pub query_log: Option<Arc<QueryLog>>,
+    pub metrics: Arc<Metrics>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub response_rate_limiter: Option<Arc<ResponseRateLimiter>>,
+    /// The queries tracked for the dashboard, only with `--admin-listen` as they would not be
+    /// shown anywhere else
+    pub activity: Option<Arc<Activity>>,
+    pub records: Option<LocalRecords>,
+    pub zones: Option<Arc<ZoneSet>>,
+    pub secondaries: Arc<Vec<SecondaryZone>>,
+    pub blocklist: Option<Arc<Blocklist>>,
+    query_slots: Arc<Semaphore>,
+}
+
+impl State {
+    /// Reads the files and opens the query log the arguments name, leaving out those that
+    /// cannot be read
+    pub fn new(args: ServerArgs) -> Self {
+        State {
+            upstreams: Arc::new(args.upstreams()),
+            cache: Arc::new(args.cache()),
+            hosts: args.hosts().map(Arc::new),
+            query_log: args.query_log().map(Arc::new),
+            metrics: Arc::new(Metrics::new(&args.dns_relay)),
+            rate_limiter: args.rate_limiter().map(Arc::new),
+            response_rate_limiter: args.response_rate_limiter().map(Arc::new),
+            activity: args.admin_listen.map(|_| Arc::default()),
+            records: args.records(),
+            zones: args.zones().map(Arc::new),
+            secondaries: Arc::new(args.secondaries()),
+            blocklist: args.blocklist().map(Arc::new),
+            query_slots: Arc::new(Semaphore::new(args.max_concurrent_queries as usize)),
+            args,
+        }
+    }
+
+    pub fn resolve_options(&self) -> ResolveOptions {
+        let args = &self.args;
+        ResolveOptions {
+            timeout: Duration::from_millis(args.relay_timeout_ms),
+            retries: args.relay_retries,
+            // truncated responses are passed on, so the client can retry over TCP itself
+            tcp_fallback: false,
+            cache: (!args.no_cache).then(|| Arc::clone(&self.cache)),
+            zones: self.zones.clone(),
+            hosts: self.hosts.clone(),
+            ..ResolveOptions::default()
+        }
+    }
+
+    /// Waits until fewer than `--max-concurrent-queries` queries are being answered, across all
+    /// listeners. The query holds the returned slot until it is answered.
+    pub async fn query_slot(&self) -> OwnedSemaphorePermit {
+        let slot = Arc::clone(&self.query_slots).acquire_owned().await;
+        slot.expect("the query slots are never closed")
+    }
+
+    /// Returns a query slot like [`State::query_slot`] if one is free right away
+    #[cfg(all(target_os = "linux", any(feature = "mmsg", feature = "io-uring")))]
+    pub fn try_query_slot(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.query_slots).try_acquire_owned().ok()
+    }
+
+    /// Takes every query slot once the queries being answered are done. No further queries are
+    /// received while the returned slots are held, eg. to shut down without dropping any.
+    pub async fn drain_queries(&self) -> OwnedSemaphorePermit {
+        let slots = Arc::clone(&self.query_slots);
+        let slots = slots.acquire_many_owned(self.args.max_concurrent_queries);
+        slots.await.expect("the query slots are never closed")
+    }
+
+    /// Number of queries being answered right now, across all listeners
+    pub fn queries_in_flight(&self) -> usize {
+        self.args.max_concurrent_queries as usize - self.query_slots.available_permits()
+    }
+
+    /// The state `args` stand for, keeping the cache, metrics, query log, dashboard activity and
+    /// query slots, and the upstreams and rate limiters unless their settings changed. Settings
+    /// only used when the server starts keep their value, warning about the change. The hosts
+    /// file and blocklists are read again, and the current ones are kept if they cannot be read.
+    /// The zones and secondaries are built anew, and the zones transferred already are answered
+    /// until the secondaries transferred them again.
+    pub fn reload(&self, mut args: ServerArgs) -> State {
+        keep_startup_settings(&self.args, &mut args);
+        let (old, new) = (&self.args, &args);
+        let upstreams =
+            if (old.dns_relay == new.dns_relay) && (old.strict_order == new.strict_order) {
+                Arc::clone(&self.upstreams)
+            } else {
+                Arc::new(args.upstreams())
+            };
+        // the clients limited already stay limited
+        let rate_limiter = if (old.rate_limit_qps == new.rate_limit_qps)
+            && (old.rate_limit_burst == new.rate_limit_burst)
+        {
+            self.rate_limiter.clone()
+        } else {
+            args.rate_limiter().map(Arc::new)
+        };
+        let rrl = |args: &ServerArgs| {
+            (
+                args.rrl_responses_per_second,
+                args.rrl_window_secs,
+                args.rrl_slip,
+                args.rrl_ipv4_prefix_len,
+                args.rrl_ipv6_prefix_len,
+            )
+        };
+        let response_rate_limiter = if rrl(old) == rrl(new) {
+            self.response_rate_limiter.clone()
+        } else {
+            args.response_rate_limiter().map(Arc::new)
+        };
+        let zones = args.zones().map(Arc::new);
+        let secondaries = args.secondaries();
+        if let (Some(zones), Some(current)) = (&zones, &self.zones) {
+            for secondary in &secondaries {
+                if let Some(zone) = current.get(&secondary.origin) {
+                    zones.insert(Zone::clone(&zone));
+                }
+            }
+        }
+        State {
+            upstreams,
+            cache: Arc::clone(&self.cache),
+            hosts: self.reload_hosts(&args),
+            query_log: self.query_log.clone(),
+            metrics: Arc::clone(&self.metrics),
+            rate_limiter,
+            response_rate_limiter,
+            activity: self.activity.clone(),
+            records: args.records(),
+            zones,
+            secondaries: Arc::new(secondaries),
+            blocklist: self.reload_blocklist(&args),
+            query_slots: Arc::clone(&self.query_slots),
+            args,
+        }
+    }
+
+    fn reload_hosts(&self, args: &ServerArgs) -> Option<Arc<HostsFile>> {
+        let Some(hosts) = self
+            .hosts
+            .as_ref()
+            .filter(|_| args.hosts_file == self.args.hosts_file)
+        else {
+            return args.hosts().map(Arc::new);
+        };
+        if let Err(e) = hosts.reload() {
+            warn!("Could not reload hosts file: {e}");
+        }
+        Some(Arc::clone(hosts))
+    }
+
+    /// The blocklist for `args`, blocking and allowing the names the current one did through
+    /// the admin API, and paused if it is
+    fn reload_blocklist(&self, args: &ServerArgs) -> Option<Arc<Blocklist>> {
+        let (old, new) = (&self.args, args);
+        let unchanged = (old.blocklist == new.blocklist)
+            && (old.blocklist_dir == new.blocklist_dir)
+            && (old.block_regex == new.block_regex)
+            && (old.allowlist == new.allowlist)
+            && (old.allow == new.allow)
+            && (old.block_response == new.block_response)
+            && (old.block_ttl == new.block_ttl);
+        let Some(current) = &self.blocklist else {
+            return args.blocklist().map(Arc::new);
+        };
+        if unchanged {
+            if let Err(e) = current.reload() {
+                warn!("Could not reload blocklists: {e}");
+            }
+            return Some(Arc::clone(current));
+        }
+        let Some(blocklist) = args.blocklist() else {
+            // the lists could not be read, unless there are none left
+            let lists = !args.blocklist.is_empty() || !args.block_regex.is_empty();
+            return lists.then(|| Arc::clone(current));
+        };
+        let (blocked, allowed) = current.added();
+        blocked.iter().for_each(|name| blocklist.add_blocked(name));
+        allowed.iter().for_each(|name| blocklist.add_allowed(name));
+        match current.state() {
+            BlockingState::Enabled => {}
+            BlockingState::Paused(None) => blocklist.pause(None),
+            BlockingState::Paused(Some(until)) => {
+                let left = until.duration_since(SystemTime::now()).unwrap_or_default();
+                blocklist.pause(Some(left));
+            }
+        }
+        Some(Arc::new(blocklist))
+    }
+}
+
+/// Sets the settings in `new` that are only used when the server starts, like the addresses
+/// it listens on, back to their `old` values
+fn keep_startup_settings(old: &ServerArgs, new: &mut ServerArgs) {
+    macro_rules! keep {
+        ($($setting:ident),*) => {$(
+            if new.$setting != old.$setting {
+                warn!("Keeping {0}, changing it takes a restart", stringify!($setting));
+                new.$setting = old.$setting.clone();
+            }
+        )*};
+    }
+    keep!(
+        listen,
+        bind_address,
+        bind_port,
+        tcp_max_connections,
+        tcp_idle_timeout_secs,
+        cache_size,
+        cache_file,
+        blocklist_refresh_hours,
+        tls_cert,
+        tls_key,
+        tls_client_ca,
+        max_concurrent_queries,
+        udp_workers,
+        shutdown_timeout_secs,
+        user,
+        group,
+        chroot,
+        log_format,
+        log_level,
+        query_log_file,
+        query_log_max_size_mb,
+        query_log_rotate_hours,
+        query_log_keep_files,
+        query_log_keep_days,
+        admin_listen,
+        control_socket
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use dns::{filter::BlockResponse, protocol::query::QueryBuilder};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload() {
+        let directory = std::env::temp_dir().join(format!("reload-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let blocklist = directory.join("ads.txt");
+        std::fs::write(&blocklist, "ads.reload.example\n").unwrap();
+        let zone = directory.join("zone.txt");
+        let soa = "@ 300 IN SOA ns admin 1 3600 600 86400 300\n";
+        std::fs::write(&zone, soa).unwrap();
+        let config = directory.join("config.toml");
+        let write_config = |socket: &str, record: &str, origin: &str, response: &str| {
+            let settings = format!(
+                r#"
+[listen]
+sockets = ["{socket}"]
+
+[local.records]
+"{record}" = "192.0.2.1"
+
+[blocking]
+lists = ["{}"]
+response = "{response}"
+
+[zones]
+files = ["{origin}={}"]
+"#,
+                blocklist.display(),
+                zone.display()
+            );
+            std::fs::write(&config, settings).unwrap();
+        };
+        let command_line = [
+            "dns-block-tokio",
+            "--config",
+            config.to_str().unwrap(),
+            "--rate-limit-qps=100",
+            "--rrl-responses-per-second=5",
+            "--quiet",
+        ];
+        let command_line = || command_line.map(Into::into);
+
+        write_config(
+            "127.0.0.1:5353",
+            "old.reload.example",
+            "old.reload.example",
+            "nxdomain",
+        );
+        let args = ServerArgs::from_command_line(command_line()).unwrap();
+        let server = Server::new(State::new(args));
+        let before = server.state();
+        let admin = "admin.reload.example".parse().unwrap();
+        before.blocklist.as_ref().unwrap().add_blocked(&admin);
+
+        write_config(
+            "127.0.0.1:5354",
+            "new.reload.example",
+            "new.reload.example",
+            "refused",
+        );
+        server.reload(ServerArgs::from_command_line(command_line()).unwrap());
+        let after = server.state();
+        let answered = |name: &str| {
+            let query = QueryBuilder::new(name.parse().unwrap()).build();
+            after.records.as_ref().unwrap().respond(&query).is_some()
+        };
+        assert!(answered("new.reload.example"));
+        assert!(!answered("old.reload.example"));
+        let zones = after.zones.as_ref().unwrap();
+        assert!(zones.get(&"new.reload.example".parse().unwrap()).is_some());
+        assert!(zones.get(&"old.reload.example".parse().unwrap()).is_none());
+        let client = "192.0.2.2".parse().unwrap();
+        let blocklist = after.blocklist.as_ref().unwrap();
+        let response = blocklist.blocked_response(&"ads.reload.example".parse().unwrap(), client);
+        assert_eq!(response, Some(BlockResponse::Refused));
+        assert!(blocklist.is_blocked(&admin, client));
+        // the listeners are only bound when the server starts
+        assert_eq!(after.args.listen, before.args.listen);
+        assert!(Arc::ptr_eq(&after.cache, &before.cache));
+        assert!(Arc::ptr_eq(&after.upstreams, &before.upstreams));
+        assert!(Arc::ptr_eq(&after.metrics, &before.metrics));
+        let rate_limiters = (after.rate_limiter.as_ref()).zip(before.rate_limiter.as_ref());
+        assert!(rate_limiters.is_some_and(|(after, before)| Arc::ptr_eq(after, before)));
+        let rate_limiters =
+            (after.response_rate_limiter.as_ref()).zip(before.response_rate_limiter.as_ref());
+        assert!(rate_limiters.is_some_and(|(after, before)| Arc::ptr_eq(after, before)));
+
+        std::fs::write(&config, "[listen]\nsockets = 53\n").unwrap();
+        let problems = ServerArgs::from_command_line(command_line()).unwrap_err();
+        assert!(problems[0].starts_with(config.to_str().unwrap()));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Integration with systemd on Linux: listening on the sockets of a socket unit, eg. so the
+//! server need not be started as root to listen on port 53, and telling the service manager
+//! when the server is ready, alive and stopping.
+//! https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+//! https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    net::SocketAddr,
+    ops::Range,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{self, UnixDatagram},
+    },
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use socket2::{Socket, Type};
+use tracing::{info, warn};
+
+/// First descriptor passed by systemd, the ones after it follow without gaps
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the sockets passed by systemd that are bound to `address` with the type `r#type`,
+/// several if the socket unit sets `ReusePort=` and lists the address more than once
+pub fn take_sockets(address: SocketAddr, r#type: Type) -> Vec<Socket> {
+    let mut passed = passed_sockets().lock().unwrap();
+    let (taken, rest) = passed.drain(..).partition(|socket| {
+        let bound = socket.local_addr().ok().and_then(|bound| bound.as_socket());
+        bound == Some(address) && socket.r#type().ok() == Some(r#type)
+    });
+    *passed = rest;
+    taken
+}
+
+/// Tells systemd the server is ready, once all listeners are up, and keeps its watchdog from
+/// firing for as long as the runtime runs. Sockets passed by systemd that no listener took are
+/// closed, they are not listened on.
+pub fn ready() {
+    for socket in passed_sockets().lock().unwrap().drain(..) {
+        let address = socket.local_addr().ok().and_then(|bound| bound.as_socket());
+        let address = address.map_or("an unknown address".to_string(), |a| a.to_string());
+        info!("Ignoring the socket systemd passed for {address}, no listener uses it");
+    }
+    notify("READY=1");
+
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    tokio::spawn(async move {
+        // twice per interval, as recommended, so a late ping does not count as a hang
+        let mut pings = tokio::time::interval(interval / 2);
+        loop {
+            pings.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Tells systemd the server is shutting down
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Sends `state` to the service manager, if the server was started by one
+fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = notify_to(&path, state) {
+        warn!("Could not notify systemd: {e}");
+    }
+}
+
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    // abstract sockets are given with a leading `@`
+    let address = match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => net::SocketAddr::from_abstract_name(name)?,
+        None => net::SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// How often systemd expects to hear from the server, if it watches it
+fn watchdog_interval() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usecs: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usecs > 0).then(|| Duration::from_micros(usecs))
+}
+
+/// The sockets passed by systemd that no listener took yet
+fn passed_sockets() -> &'static Mutex<Vec<Socket>> {
+    static PASSED: OnceLock<Mutex<Vec<Socket>>> = OnceLock::new();
+    PASSED.get_or_init(|| {
+        let fds = passed_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+        // not to be passed on to processes started by the server
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        let sockets = fds.map(|fd| {
+            // SAFETY: systemd passed the descriptor for this process to own
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        });
+        let sockets = sockets.filter_map(|socket: io::Result<_>| {
+            socket
+                .inspect_err(|e| warn!("Could not use a socket systemd passed: {e}"))
+                .ok()
+        });
+        Mutex::new(sockets.collect())
+    })
+}
+
+/// The descriptors passed according to `LISTEN_PID` and `LISTEN_FDS`, none unless they were
+/// passed to the process `pid`
+fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<RawFd> {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid) {
+        return LISTEN_FDS_START..LISTEN_FDS_START;
+    }
+    let count = listen_fds.and_then(|count| count.parse::<RawFd>().ok());
+    LISTEN_FDS_START..LISTEN_FDS_START + count.unwrap_or(0).max(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passed_fds() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42), 3..5);
+        assert!(passed_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(passed_fds(None, Some("2"), 42).is_empty());
+        assert!(passed_fds(Some("42"), Some("-1"), 42).is_empty());
+        assert!(passed_fds(Some("42"), None, 42).is_empty());
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("notify-{}", std::process::id()));
+        let manager = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buffer = [0; 64];
+        let len = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        let name = format!("@notify-{}", std::process::id());
+        let address = net::SocketAddr::from_abstract_name(&name.as_bytes()[1..]).unwrap();
+        let manager = UnixDatagram::bind_addr(&address).unwrap();
+        notify_to(name.as_ref(), "WATCHDOG=1").unwrap();
+        let len = manager.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"WATCHDOG=1");
+    }
+}
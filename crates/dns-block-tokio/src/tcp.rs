@@ -0,0 +1,249 @@
+//! Queries over TCP, for clients whose responses were truncated over UDP, stub resolvers
+//! preferring it, and zone transfers, as well as over DNS over TLS. Queries go through the same
+//! pipeline as over UDP. Connections of DNS over HTTPS are accepted here as well, and handed to
+//! [`crate::doh`] once their TLS session is established.
+//! https://datatracker.ietf.org/doc/html/rfc7766
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use dns::tcp::{read_tcp_message_async, write_tcp_message_async};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{mpsc, Semaphore},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+use crate::{
+    doh::serve_connection,
+    process,
+    resolution::Protocol,
+    state::{Server, State},
+};
+
+/// Accepts connections of `protocol` until the process exits, at most `max_connections` at a
+/// time, and establishes a TLS session on each one if `tls` is given. Connections beyond that
+/// are closed right away, so their clients try elsewhere.
+pub async fn serve_tcp(
+    listener: TcpListener,
+    protocol: Protocol,
+    max_connections: usize,
+    idle_timeout: Duration,
+    tls: Option<TlsAcceptor>,
+    server: Arc<Server>,
+) {
+    let name = match protocol {
+        Protocol::Tls => "TLS",
+        Protocol::Https => "HTTPS",
+        _ => "TCP",
+    };
+    let connections = Arc::new(Semaphore::new(max_connections));
+    loop {
+        let Ok((stream, client)) = listener.accept().await else {
+            continue;
+        };
+        let Ok(permit) = Arc::clone(&connections).try_acquire_owned() else {
+            if !server.state().args.quiet {
+                info!(client = %client, "Closing {name} connection, too many are open");
+            }
+            continue;
+        };
+        let server = Arc::clone(&server);
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let connection = Connection {
+                client,
+                protocol,
+                idle_timeout,
+                server: &server,
+            };
+            let served = match tls {
+                // clients that never finish the handshake are idle as well
+                Some(tls) => match tokio::time::timeout(idle_timeout, tls.accept(stream)).await {
+                    Ok(Ok(stream)) if protocol == Protocol::Https => {
+                        let alpn = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+                        let server = Arc::clone(&server);
+                        serve_connection(stream, alpn.as_deref(), client, idle_timeout, server)
+                            .await
+                    }
+                    Ok(Ok(stream)) => connection.serve(stream).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Ok(()),
+                },
+                None => connection.serve(stream).await,
+            };
+            if let Err(e) = served {
+                if !server.state().args.quiet {
+                    info!(client = %client, error = %e, "{name} connection failed");
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// A client connected over TCP or TLS
+struct Connection<'a> {
+    client: SocketAddr,
+    protocol: Protocol,
+    idle_timeout: Duration,
+    server: &'a Server,
+}
+
+impl Connection<'_> {
+    /// Answers the queries sent over `stream` concurrently, writing each response once it is
+    /// ready. The connection is closed once the client closes it or sends nothing for the idle
+    /// timeout, after the responses still due are written.
+    async fn serve(self, stream: impl AsyncRead + AsyncWrite) -> io::Result<()> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (responses, mut ready) = mpsc::unbounded_channel::<Vec<Vec<u8>>>();
+
+        let reading = async move {
+            loop {
+                let read = read_tcp_message_async(&mut reader);
+                let query = match tokio::time::timeout(self.idle_timeout, read).await {
+                    Err(_) => return Ok::<_, io::Error>(()),
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    Ok(result) => result?,
+                };
+                let responses = responses.clone();
+                // each query is answered with the state current when it is read
+                let state = self.server.state();
+                let (client, protocol) = (self.client, self.protocol);
+                // no further queries are read while all slots are taken
+                let slot = state.query_slot().await;
+                tokio::spawn(async move {
+                    let answer = answer(&query, client, protocol, &state);
+                    if let Some(messages) = answer.await {
+                        // the connection may have failed in the meantime
+                        let _ = responses.send(messages);
+                    }
+                    drop(slot);
+                });
+            }
+        };
+        // ends once the reader and all queries still being answered dropped their senders
+        let writing = async move {
+            while let Some(messages) = ready.recv().await {
+                for message in messages {
+                    write_tcp_message_async(&mut writer, &message).await?;
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+        tokio::try_join!(reading, writing)?;
+        Ok(())
+    }
+}
+
+/// Answers a query with the messages of a zone transfer, or else the response of the pipeline
+pub async fn answer(
+    query: &[u8],
+    client: SocketAddr,
+    protocol: Protocol,
+    state: &State,
+) -> Option<Vec<Vec<u8>>> {
+    let transfer =
+        (state.zones.as_ref()).and_then(|zones| zones.respond_transfer(query, client.ip()));
+    if let Some(messages) = transfer {
+        if !state.args.quiet {
+            info!(
+                client = %client,
+                messages = messages.len(),
+                "Answered zone transfer"
+            );
+        }
+        return Some(messages);
+    }
+    let response = process(query, &client, protocol, state).await?;
+    Some(vec![response])
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use dns::{
+        parse::parser::DnsParser,
+        protocol::{query::QueryBuilder, response_code::ResponseCode},
+    };
+    use tokio::net::TcpStream;
+
+    use crate::cli::ServerArgs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_tcp() {
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--benchmark",
+            "--resolution-delay-ms=0",
+            "--quiet",
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let idle_timeout = Duration::from_secs(1);
+        let server = Arc::new(Server::new(State::new(server_args)));
+        let server = serve_tcp(listener, Protocol::Tcp, 1, idle_timeout, None, server);
+        tokio::spawn(server);
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        for id in [1, 2] {
+            let query = QueryBuilder::new("example.com".parse().unwrap())
+                .id(id)
+                .build();
+            write_tcp_message_async(&mut writer, &query).await.unwrap();
+        }
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let response = read_tcp_message_async(&mut reader).await.unwrap();
+            ids.push(DnsParser::new(&response).parse_header().unwrap().request_id);
+        }
+        ids.sort();
+        assert_eq!(ids, [1, 2]);
+
+        // beyond the cap
+        let mut second = TcpStream::connect(address).await.unwrap();
+        assert!(read_tcp_message_async(&mut second).await.is_err());
+
+        // closed once idle
+        let idle =
+            tokio::time::timeout(Duration::from_secs(5), read_tcp_message_async(&mut reader));
+        assert!(idle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp_server_failure() {
+        // takes the queries and never answers them
+        let upstream = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            &format!("--dns-relay={}", upstream.local_addr().unwrap()),
+            "--relay-timeout-ms=50",
+            "--relay-retries=0",
+            "--no-cache",
+            "--quiet",
+        ]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let idle_timeout = Duration::from_secs(1);
+        let server = Arc::new(Server::new(State::new(server_args)));
+        let server = serve_tcp(listener, Protocol::Tcp, 1, idle_timeout, None, server);
+        tokio::spawn(server);
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(7)
+            .build();
+        write_tcp_message_async(&mut stream, &query).await.unwrap();
+        let response = read_tcp_message_async(&mut stream).await.unwrap();
+        let response = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(response.header.request_id, 7);
+        assert_eq!(response.header.flags.response_code, ResponseCode::ServFail);
+        assert_eq!(response.questions[0].domain_name, "example.com");
+        assert!(response.answers.is_empty());
+        drop(upstream);
+    }
+}
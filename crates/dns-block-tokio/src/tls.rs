@@ -0,0 +1,138 @@
+//! Queries over DNS over TLS, which are served like the ones over TCP once the TLS session is
+//! established.
+//! https://datatracker.ietf.org/doc/html/rfc7858
+
+use std::{fmt::Display, path::Path, sync::Arc};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+/// ALPN identifier of DNS over TLS
+/// https://www.iana.org/assignments/tls-extensiontype-values/tls-extensiontype-values.xhtml#alpn-protocol-ids
+pub const DOT_ALPN: &[u8] = b"dot";
+
+/// Reads the PEM encoded certificate chain and private key the server presents. Clients have
+/// to present a certificate issued by one of the certificates in `client_ca` if given, and
+/// negotiate one of the `alpn` protocols if they name any.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+    alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>, String> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| in_file(cert, e))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates", cert.display()));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|e| in_file(key, e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(path).map_err(|e| in_file(path, e))? {
+                let certificate = certificate.map_err(|e| in_file(path, e))?;
+                roots.add(certificate).map_err(|e| in_file(path, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                .build()
+                .map_err(|e| in_file(path, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(chain, private_key)
+        .map_err(|e| in_file(cert, e))?;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+fn in_file(path: &Path, e: impl Display) -> String {
+    format!("{}: {e}", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use dns::{
+        parse::parser::DnsParser,
+        protocol::query::QueryBuilder,
+        transport::tls::{spki_pin, DotTransport},
+    };
+    use tokio::net::TcpListener;
+
+    use crate::{
+        cli::ServerArgs,
+        resolution::Protocol,
+        state::{Server, State},
+        tcp::serve_tcp,
+    };
+
+    use super::*;
+
+    /// Serves DNS over TLS in benchmark mode with a self-signed certificate for `dns.test`,
+    /// returning its address and SPKI pin
+    async fn serve(directory: &Path, client_ca: Option<&Path>) -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.test".into()]).unwrap();
+        let (cert, key) = (directory.join("dns.pem"), directory.join("dns.key"));
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        let config = server_config(&cert, &key, client_ca, &[DOT_ALPN]).unwrap();
+
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--benchmark",
+            "--resolution-delay-ms=0",
+            "--quiet",
+        ]);
+        let server = Arc::new(Server::new(State::new(server_args)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let tls = Some(config.into());
+        let idle_timeout = Duration::from_secs(5);
+        let server = serve_tcp(listener, Protocol::Tls, 8, idle_timeout, tls, server);
+        tokio::spawn(server);
+        (address, spki_pin(certified.cert.der()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serve_tls() {
+        let directory = std::env::temp_dir().join(format!("serve-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let (address, pin) = serve(&directory, None).await;
+        let transport = DotTransport::new(&address, "dns.test", Some(&pin)).unwrap();
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(7)
+            .build();
+        let response = transport
+            .exchange_async(&query, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let header = DnsParser::new(&response).parse_header().unwrap();
+        assert_eq!(header.request_id, 7);
+
+        // clients need a certificate issued by the CA then
+        let ca = directory.join("ca.pem");
+        let issuer = rcgen::generate_simple_self_signed(vec!["ca.test".into()]).unwrap();
+        std::fs::write(&ca, issuer.cert.pem()).unwrap();
+        let (address, pin) = serve(&directory, Some(&ca)).await;
+        let transport = DotTransport::new(&address, "dns.test", Some(&pin)).unwrap();
+        let exchange = transport.exchange_async(&query, Duration::from_secs(5));
+        assert!(exchange.await.is_err());
+
+        assert!(server_config(&ca, &directory.join("missing.key"), None, &[]).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,492 @@
+//! Parser for the subset of TOML the configuration file needs: tables, arrays of tables, dotted
+//! and quoted keys, and values that are strings, integers, booleans, arrays or inline tables.
+//! Floats, dates and multi-line strings are not supported.
+//! https://toml.io/en/v1.0.0
+
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    /// How the type of the value is called in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+            Value::Table(_) => "a table",
+        }
+    }
+}
+
+/// Key of a table with its value, and the line it is defined on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// Entries of a table, in the order they are defined
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Table {
+    pub entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        let entry = self.entries.iter().find(|entry| entry.key == key)?;
+        Some(&entry.value)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        let entry = self.entries.iter_mut().find(|entry| entry.key == key)?;
+        Some(&mut entry.value)
+    }
+
+    /// The table at `path` below this one, which is created if it does not exist yet. Arrays of
+    /// tables stand for their last table, as in the headers of TOML.
+    fn table_at(&mut self, path: &[String], line: usize) -> Result<&mut Table, String> {
+        let Some((key, rest)) = path.split_first() else {
+            return Ok(self);
+        };
+        if self.get(key).is_none() {
+            self.entries.push(Entry {
+                key: key.clone(),
+                value: Value::Table(Table::default()),
+                line,
+            });
+        }
+        let table = match self.get_mut(key) {
+            Some(Value::Table(table)) => table,
+            Some(Value::Array(tables)) => match tables.last_mut() {
+                Some(Value::Table(table)) => table,
+                _ => return Err(format!("`{key}` is not a table")),
+            },
+            _ => return Err(format!("`{key}` is not a table")),
+        };
+        table.table_at(rest, line)
+    }
+
+    fn insert(&mut self, key: String, value: Value, line: usize) -> Result<(), String> {
+        if self.get(&key).is_some() {
+            return Err(format!("`{key}` is defined twice"));
+        }
+        self.entries.push(Entry { key, value, line });
+        Ok(())
+    }
+}
+
+/// Parses a TOML document into its root table. Errors name the line they are on.
+pub fn parse(text: &str) -> Result<Table, String> {
+    let mut parser = Parser {
+        text,
+        pos: 0,
+        line: 1,
+    };
+    parser
+        .document()
+        .map_err(|e| format!("line {}: {e}", parser.line))
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = Table::default();
+        let mut current = vec![];
+        let mut defined = HashSet::new();
+        loop {
+            self.skip_trivia();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.bump();
+                    let array = self.eat('[');
+                    self.skip_whitespace();
+                    let path = self.keys()?;
+                    self.skip_whitespace();
+                    if !self.eat(']') || (array && !self.eat(']')) {
+                        return Err("expected `]` after the table name".to_string());
+                    }
+                    if array {
+                        let (key, parent) = path.split_last().unwrap();
+                        let parent = root.table_at(parent, self.line)?;
+                        match parent.get_mut(key) {
+                            None => parent.insert(key.clone(), Value::Array(vec![]), self.line)?,
+                            Some(Value::Array(_)) => {}
+                            Some(_) => return Err(format!("`{key}` is not an array of tables")),
+                        }
+                        if let Some(Value::Array(tables)) = parent.get_mut(key) {
+                            tables.push(Value::Table(Table::default()));
+                        }
+                    } else {
+                        if !defined.insert(path.clone()) {
+                            return Err(format!("table `{}` is defined twice", path.join(".")));
+                        }
+                        root.table_at(&path, self.line)?;
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    // values may span lines, the key's line is the one named
+                    let line = self.line;
+                    let (keys, value) = self.key_value()?;
+                    let (key, parents) = keys.split_last().unwrap();
+                    let path: Vec<_> = current.iter().chain(parents).cloned().collect();
+                    let table = root.table_at(&path, line)?;
+                    table.insert(key.clone(), value, line)?;
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    fn key_value(&mut self) -> Result<(Vec<String>, Value), String> {
+        let keys = self.keys()?;
+        self.skip_whitespace();
+        if !self.eat('=') {
+            return Err(format!("expected `=` after `{}`", keys.join(".")));
+        }
+        self.skip_whitespace();
+        Ok((keys, self.value()?))
+    }
+
+    /// A key, or keys separated by dots
+    fn keys(&mut self) -> Result<Vec<String>, String> {
+        let mut keys = vec![self.key()?];
+        loop {
+            self.skip_whitespace();
+            if !self.eat('.') {
+                return Ok(keys);
+            }
+            self.skip_whitespace();
+            keys.push(self.key()?);
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let key = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if key.is_empty() {
+                    return Err("expected a key".to_string());
+                }
+                Ok(key.to_string())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            _ => {
+                let token = self.take_while(|c| c.is_ascii_alphanumeric() || "+-_.:".contains(c));
+                match token {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    "" => Err("expected a value".to_string()),
+                    _ => match token.replace('_', "").parse() {
+                        Ok(integer) if !token.starts_with('_') && !token.ends_with('_') => {
+                            Ok(Value::Integer(integer))
+                        }
+                        _ => Err(format!("invalid value `{token}`")),
+                    },
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut values = vec![];
+        loop {
+            self.skip_trivia();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_trivia();
+            if !self.eat(',') {
+                self.skip_trivia();
+                if self.eat(']') {
+                    return Ok(Value::Array(values));
+                }
+                return Err("expected `,` or `]` in array".to_string());
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut table = Table::default();
+        self.skip_whitespace();
+        if self.eat('}') {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_whitespace();
+            let (keys, value) = self.key_value()?;
+            let (key, parents) = keys.split_last().unwrap();
+            let line = self.line;
+            table
+                .table_at(parents, line)?
+                .insert(key.clone(), value, line)?;
+            self.skip_whitespace();
+            if self.eat('}') {
+                return Ok(Value::Table(table));
+            }
+            if !self.eat(',') {
+                return Err("expected `,` or `}` in inline table".to_string());
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut string = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err("unterminated string".to_string()),
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some(c @ ('u' | 'U')) => {
+                            self.unicode_escape(if c == 'u' { 4 } else { 8 })?
+                        }
+                        _ => return Err("invalid escape in string".to_string()),
+                    };
+                    string.push(escaped);
+                }
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    fn unicode_escape(&mut self, digits: usize) -> Result<char, String> {
+        let hex = self
+            .text
+            .get(self.pos..self.pos + digits)
+            .unwrap_or_default();
+        let c = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == digits)
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("invalid unicode escape `{hex}`"))?;
+        self.pos += digits;
+        Ok(c)
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let string = self.take_while(|c| c != '\'' && c != '\n').to_string();
+        if !self.eat('\'') {
+            return Err("unterminated string".to_string());
+        }
+        Ok(string)
+    }
+
+    /// Whitespace and a comment up to the end of the line, which must follow every statement
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        self.skip_comment();
+        self.eat('\r');
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err(format!("unexpected `{c}`")),
+        }
+    }
+
+    /// Whitespace, comments and line breaks, which may be anywhere between statements and
+    /// between the values of arrays
+    fn skip_trivia(&mut self) {
+        loop {
+            self.skip_whitespace();
+            self.skip_comment();
+            if !(self.eat('\n') || self.eat('\r')) {
+                return;
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.take_while(|c| c == ' ' || c == '\t');
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            self.take_while(|c| c != '\n');
+        }
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        while self.peek().is_some_and(&predicate) {
+            self.bump();
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() != Some(expected) {
+            return false;
+        }
+        self.bump();
+        true
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    fn table(value: &Value) -> &Table {
+        match value {
+            Value::Table(table) => table,
+            _ => panic!("not a table: {value:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_values() {
+        let document = parse(
+            r#"
+# comment
+title = "dns \"thingy\"\t\u00e9" # trailing comment
+path = 'C:\zones'
+port = 5_300
+offset = -1
+enabled = true
+servers = [
+    "1.1.1.1:53", # first
+    "9.9.9.9:53",
+]
+nested = [[1, 2], []]
+point = { x = 1, y.z = 'deep' }
+"#,
+        )
+        .unwrap();
+        assert_eq!(document.get("title"), Some(&string("dns \"thingy\"\té")));
+        assert_eq!(document.get("path"), Some(&string("C:\\zones")));
+        assert_eq!(document.get("port"), Some(&Value::Integer(5300)));
+        assert_eq!(document.get("offset"), Some(&Value::Integer(-1)));
+        assert_eq!(document.get("enabled"), Some(&Value::Boolean(true)));
+        assert_eq!(
+            document.get("servers"),
+            Some(&Value::Array(vec![
+                string("1.1.1.1:53"),
+                string("9.9.9.9:53")
+            ]))
+        );
+        assert_eq!(
+            document.get("nested"),
+            Some(&Value::Array(vec![
+                Value::Array(vec![Value::Integer(1), Value::Integer(2)]),
+                Value::Array(vec![]),
+            ]))
+        );
+        let point = table(document.get("point").unwrap());
+        assert_eq!(point.get("x"), Some(&Value::Integer(1)));
+        let y = table(point.get("y").unwrap());
+        assert_eq!(y.get("z"), Some(&string("deep")));
+        let lines: Vec<_> = document.entries.iter().map(|e| e.line).collect();
+        assert_eq!(lines, [3, 4, 5, 6, 7, 8, 12, 13]);
+    }
+
+    #[test]
+    fn test_parse_tables() {
+        let document = parse(
+            r#"
+top = 1
+
+[server]
+port = 53
+listen.address = "::"
+
+[server."tls"]
+cert = "cert.pem"
+
+[[record]]
+name = "a"
+
+[[record]]
+name = "b"
+"#,
+        )
+        .unwrap();
+        let keys: Vec<_> = document.entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, ["top", "server", "record"]);
+        let server = table(document.get("server").unwrap());
+        assert_eq!(server.get("port"), Some(&Value::Integer(53)));
+        let listen = table(server.get("listen").unwrap());
+        assert_eq!(listen.get("address"), Some(&string("::")));
+        let tls = table(server.get("tls").unwrap());
+        assert_eq!(tls.get("cert"), Some(&string("cert.pem")));
+        let Some(Value::Array(records)) = document.get("record") else {
+            panic!("record is not an array");
+        };
+        let names: Vec<_> = records.iter().map(|r| table(r).get("name")).collect();
+        assert_eq!(names, [Some(&string("a")), Some(&string("b"))]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| parse(text).unwrap_err();
+        assert_eq!(error("a = 1\na = 2"), "line 2: `a` is defined twice");
+        assert_eq!(error("[a]\n[a]"), "line 2: table `a` is defined twice");
+        assert_eq!(error("a = 1\n[a.b]"), "line 2: `a` is not a table");
+        assert_eq!(error("\n\na = 1.5"), "line 3: invalid value `1.5`");
+        assert_eq!(error("a = \"open"), "line 1: unterminated string");
+        assert_eq!(error("a = [1 2]"), "line 1: expected `,` or `]` in array");
+        assert_eq!(error("a = 1 b = 2"), "line 1: unexpected `b`");
+        assert_eq!(error("a"), "line 1: expected `=` after `a`");
+        assert_eq!(error("= 1"), "line 1: expected a key");
+        assert_eq!(error("a ="), "line 1: expected a value");
+        assert_eq!(error("[a"), "line 1: expected `]` after the table name");
+    }
+}
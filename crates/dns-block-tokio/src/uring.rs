@@ -0,0 +1,929 @@
+//! UDP acceptors receiving and sending datagrams through io_uring, available on Linux with the
+//! `io-uring` feature. Each socket gets a ring of its own, driven by a blocking thread that
+//! keeps [`RECEIVES`] receives in flight and submits the responses as they become ready, so
+//! the datagrams of a busy socket take few system calls and no readiness polling. Queries are
+//! still answered by tasks on the tokio runtime. The sockets querying the upstreams share one
+//! more ring, see [`UpstreamRing`].
+//! https://kernel.dk/io_uring.pdf
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+};
+
+use dns::resolver::{bind_query_socket, fit_udp_response, QuerySocket, ResolveOptions};
+use socket2::{SockAddr, SockRef};
+use tokio::{
+    net::UdpSocket,
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use tracing::warn;
+
+use crate::{listen::UDP_BUFFER_SIZE, process, resolution::Protocol, state::Server};
+
+/// Receives in flight on each socket
+const RECEIVES: usize = 64;
+
+/// Responses in flight on each socket, further ones wait for one of them to complete
+const SENDS: usize = 128;
+
+/// Submission queue entries of each ring, enough for all receives and sends in flight
+const RING_ENTRIES: u32 = 256;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/io_uring.h
+const IORING_OP_SENDMSG: u8 = 9;
+const IORING_OP_RECVMSG: u8 = 10;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+/// `user_data` of the read of the eventfd signalling ready responses
+const WAKE: u64 = u64::MAX;
+
+/// Bit set in the `user_data` of sends, whose lower bits are the index of the send
+const SEND: u64 = 1 << 32;
+
+/// Bit set in the `user_data` of cancellations, whose lower bits are the operation cancelled
+const CANCEL: u64 = 1 << 63;
+
+/// An acceptor like the one without io_uring, receiving and sending the datagrams of `socket`
+/// through a ring of its own. Falls back to that one if the ring cannot be set up, eg. as the
+/// kernel is older than 5.6 or io_uring is disabled.
+pub fn accept_udp(socket: Arc<UdpSocket>, server: Arc<Server>) -> JoinHandle<()> {
+    let acceptor = Ring::new(RING_ENTRIES).and_then(|ring| {
+        let wake = Arc::new(eventfd()?);
+        // io_uring waits for blocking descriptors instead of failing with EAGAIN
+        SockRef::from(&*socket).set_nonblocking(false)?;
+        Ok(Acceptor::new(ring, socket.as_raw_fd(), wake))
+    });
+    let mut acceptor = match acceptor {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            warn!("Could not set up io_uring, receiving without it: {e}");
+            return crate::accept_udp(socket, server);
+        }
+    };
+
+    // a thread of its own rather than a blocking task, which the runtime would wait for when
+    // shutting down
+    let runtime = Handle::current();
+    let (stopped, stopping) = oneshot::channel::<()>();
+    std::thread::spawn(move || {
+        // the ring receives on the socket for as long as it lives
+        let _socket = socket;
+        if let Err(e) = acceptor.run(&runtime, &server) {
+            warn!("Could not receive queries through io_uring: {e}");
+        }
+        drop(stopped);
+    });
+    tokio::spawn(async move {
+        let _ = stopping.await;
+    })
+}
+
+/// A datagram and the address it came from or goes to
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// An eventfd for tasks to wake a ring with [`wake`]
+fn eventfd() -> io::Result<OwnedFd> {
+    // SAFETY: eventfd returns a new descriptor or -1
+    let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if wake < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just created and is owned by nobody else
+    Ok(unsafe { OwnedFd::from_raw_fd(wake) })
+}
+
+/// Completes the read of the ring waiting on `eventfd`
+fn wake(eventfd: &OwnedFd) {
+    let one = 1u64;
+    // SAFETY: writes the 8 bytes of `one` to the eventfd
+    unsafe { libc::write(eventfd.as_raw_fd(), (&one as *const u64).cast(), 8) };
+}
+
+/// The result of a completed operation, the number of bytes sent or received
+fn result(res: i32) -> io::Result<usize> {
+    usize::try_from(res).map_err(|_| io::Error::from_raw_os_error(-res))
+}
+
+/// Buffers of a receive in flight, which the kernel writes to until it completes
+struct Receive {
+    buffer: Vec<u8>,
+    address: libc::sockaddr_storage,
+    iovec: libc::iovec,
+    header: libc::msghdr,
+}
+
+impl Receive {
+    fn new(len: usize) -> Self {
+        Self {
+            buffer: vec![0; len],
+            // SAFETY: all-zero bytes are valid for these plain C structures
+            address: unsafe { zeroed() },
+            iovec: unsafe { zeroed() },
+            header: unsafe { zeroed() },
+        }
+    }
+
+    /// The entry receiving into the buffers, which must not move until the receive completed
+    fn entry(&mut self, socket: RawFd, user_data: u64) -> Entry {
+        self.iovec = libc::iovec {
+            iov_base: self.buffer.as_mut_ptr().cast(),
+            iov_len: self.buffer.len(),
+        };
+        self.header.msg_name = (&mut self.address as *mut libc::sockaddr_storage).cast();
+        self.header.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        self.header.msg_iov = &mut self.iovec;
+        self.header.msg_iovlen = 1;
+        Entry {
+            opcode: IORING_OP_RECVMSG,
+            fd: socket,
+            addr: &self.header as *const libc::msghdr as u64,
+            len: 1,
+            user_data,
+            ..Entry::default()
+        }
+    }
+
+    /// The datagram of the completed receive, `None` if it failed
+    fn received(&self, res: i32) -> Option<Datagram> {
+        let len = usize::try_from(res).ok()?.min(self.buffer.len());
+        // SAFETY: the kernel wrote an address of `msg_namelen` bytes
+        let address = unsafe { SockAddr::new(self.address, self.header.msg_namelen) };
+        Some((self.buffer[..len].to_vec(), address.as_socket()?))
+    }
+}
+
+/// Buffers of a send in flight, which the kernel reads from until it completes
+struct Send {
+    datagram: Vec<u8>,
+    address: SockAddr,
+    iovec: libc::iovec,
+    header: libc::msghdr,
+}
+
+impl Send {
+    fn new((datagram, address): Datagram) -> Box<Self> {
+        // SAFETY: all-zero bytes are valid for these plain C structures
+        let mut send = Box::new(Self {
+            datagram,
+            address: SockAddr::from(address),
+            iovec: unsafe { zeroed() },
+            header: unsafe { zeroed() },
+        });
+        send.iovec = libc::iovec {
+            // only read by the kernel
+            iov_base: send.datagram.as_ptr() as *mut libc::c_void,
+            iov_len: send.datagram.len(),
+        };
+        send.header.msg_name = send.address.as_ptr() as *mut libc::c_void;
+        send.header.msg_namelen = send.address.len();
+        send.header.msg_iov = &mut send.iovec;
+        send.header.msg_iovlen = 1;
+        send
+    }
+
+    fn entry(&self, socket: RawFd, user_data: u64) -> Entry {
+        Entry {
+            opcode: IORING_OP_SENDMSG,
+            fd: socket,
+            addr: &self.header as *const libc::msghdr as u64,
+            len: 1,
+            user_data,
+            ..Entry::default()
+        }
+    }
+}
+
+struct Acceptor {
+    ring: Ring,
+    socket: RawFd,
+    /// Written by the tasks answering queries whenever they sent a response to `ready`
+    wake: Arc<OwnedFd>,
+    /// Target of the read of `wake` in flight
+    woken: Box<u64>,
+    ready: mpsc::UnboundedReceiver<Datagram>,
+    responses: mpsc::UnboundedSender<Datagram>,
+    /// Never reallocated, as the kernel writes into them
+    receives: Vec<Receive>,
+    sends: Vec<Option<Box<Send>>>,
+    /// Responses waiting for a send to complete
+    queued: VecDeque<Datagram>,
+}
+
+// SAFETY: the pointers into the ring and the buffers are only followed by the thread owning
+// the acceptor, and by the kernel
+unsafe impl std::marker::Send for Acceptor {}
+
+impl Acceptor {
+    fn new(ring: Ring, socket: RawFd, wake: Arc<OwnedFd>) -> Self {
+        let (responses, ready) = mpsc::unbounded_channel();
+        let receives = (0..RECEIVES)
+            .map(|_| Receive::new(UDP_BUFFER_SIZE))
+            .collect();
+        Self {
+            ring,
+            socket,
+            wake,
+            woken: Box::new(0),
+            ready,
+            responses,
+            receives,
+            sends: (0..SENDS).map(|_| None).collect(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Receives queries until the ring fails, which it only does if the process runs out of
+    /// memory or the like
+    fn run(&mut self, runtime: &Handle, server: &Server) -> io::Result<()> {
+        for index in 0..RECEIVES {
+            self.receive(index);
+        }
+        self.read_wake();
+        loop {
+            self.ring.submit_and_wait(1)?;
+            for completion in self.ring.completions() {
+                match completion.user_data {
+                    WAKE => self.read_wake(),
+                    data if data & SEND != 0 => self.sends[(data & !SEND) as usize] = None,
+                    index => {
+                        let index = index as usize;
+                        if let Some(datagram) = self.receives[index].received(completion.res) {
+                            self.answer(datagram, runtime, server);
+                        }
+                        self.receive(index);
+                    }
+                }
+            }
+            while let Ok(response) = self.ready.try_recv() {
+                self.queued.push_back(response);
+            }
+            self.send_queued();
+        }
+    }
+
+    /// Answers `query` in a task, once a query slot is free
+    fn answer(&self, (query, sender): Datagram, runtime: &Handle, server: &Server) {
+        let state = server.state();
+        // the ring stops receiving while all slots are taken, like the other acceptors
+        let slot = (state.try_query_slot()).unwrap_or_else(|| runtime.block_on(state.query_slot()));
+        let (responses, wake_fd) = (self.responses.clone(), Arc::clone(&self.wake));
+        runtime.spawn(async move {
+            let processed = process(&query, &sender, Protocol::Udp, &state).await;
+            if let Some(response) = processed {
+                let _ = responses.send((fit_udp_response(&query, response), sender));
+                wake(&wake_fd);
+            }
+            drop(slot);
+        });
+    }
+
+    /// Submits the receive into the buffers at `index`
+    fn receive(&mut self, index: usize) {
+        let entry = self.receives[index].entry(self.socket, index as u64);
+        self.ring.push(entry);
+    }
+
+    fn read_wake(&mut self) {
+        let entry = Entry {
+            opcode: IORING_OP_READ,
+            fd: self.wake.as_raw_fd(),
+            addr: &mut *self.woken as *mut u64 as u64,
+            len: 8,
+            user_data: WAKE,
+            ..Entry::default()
+        };
+        self.ring.push(entry);
+    }
+
+    /// Submits the queued responses, as far as there are sends free
+    fn send_queued(&mut self) {
+        while !self.queued.is_empty() {
+            let Some(index) = self.sends.iter().position(Option::is_none) else {
+                return;
+            };
+            let send = Send::new(self.queued.pop_front().expect("not empty"));
+            let entry = send.entry(self.socket, SEND | index as u64);
+            self.sends[index] = Some(send);
+            self.ring.push(entry);
+        }
+    }
+}
+
+/// The ring the sockets querying upstreams send and receive through, set up on first use. `None`
+/// if it cannot be set up, then the upstreams are queried without io_uring.
+pub fn upstream_ring() -> Option<&'static UpstreamRing> {
+    static RING: OnceLock<Option<UpstreamRing>> = OnceLock::new();
+    let ring = RING.get_or_init(|| match UpstreamRing::start() {
+        Ok(ring) => Some(ring),
+        Err(e) => {
+            warn!("Could not set up io_uring, querying the upstreams without it: {e}");
+            None
+        }
+    });
+    ring.as_ref()
+}
+
+/// Sends and receives the datagrams of all sockets querying upstreams, in a thread of its own.
+/// Each of those sockets only has a few datagrams in flight, so they share one ring, while every
+/// query still gets a socket of its own with a random port.
+pub struct UpstreamRing {
+    operations: mpsc::UnboundedSender<Operation>,
+    wake: Arc<OwnedFd>,
+    next_id: AtomicU64,
+}
+
+impl UpstreamRing {
+    fn start() -> io::Result<Self> {
+        let ring = Ring::new(RING_ENTRIES)?;
+        let wake = Arc::new(eventfd()?);
+        let (operations, receiver) = mpsc::unbounded_channel();
+        let mut driver = UpstreamDriver {
+            ring,
+            wake: Arc::clone(&wake),
+            woken: Box::new(0),
+            operations: receiver,
+            in_flight: HashMap::new(),
+        };
+        std::thread::spawn(move || {
+            if let Err(e) = driver.run() {
+                warn!("Could not query the upstreams through io_uring: {e}");
+            }
+        });
+        Ok(Self {
+            operations,
+            wake,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Hands `operation` to the thread driving the ring
+    fn submit(&self, operation: Operation) -> io::Result<()> {
+        (self.operations.send(operation)).map_err(|_| io::Error::other("io_uring stopped"))?;
+        wake(&self.wake);
+        Ok(())
+    }
+
+    fn id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// What the sockets querying upstreams ask the thread driving their ring for
+enum Operation {
+    Send {
+        id: u64,
+        socket: Arc<std::net::UdpSocket>,
+        datagram: Datagram,
+        done: oneshot::Sender<io::Result<usize>>,
+    },
+    Receive {
+        id: u64,
+        socket: Arc<std::net::UdpSocket>,
+        len: usize,
+        done: oneshot::Sender<io::Result<Datagram>>,
+    },
+    /// The receive with this ID is no longer waited for
+    Cancel(u64),
+}
+
+/// The buffers of an operation the kernel is working on, and where its result goes
+enum InFlight {
+    Send(Box<Send>, oneshot::Sender<io::Result<usize>>),
+    Receive(Box<Receive>, oneshot::Sender<io::Result<Datagram>>),
+}
+
+struct UpstreamDriver {
+    ring: Ring,
+    wake: Arc<OwnedFd>,
+    /// Target of the read of `wake` in flight
+    woken: Box<u64>,
+    operations: mpsc::UnboundedReceiver<Operation>,
+    /// By the IDs of the operations, with the sockets they are on, which stay open until the
+    /// operations complete
+    in_flight: HashMap<u64, (InFlight, Arc<std::net::UdpSocket>)>,
+}
+
+// SAFETY: the pointers into the ring and the buffers are only followed by the thread owning
+// the driver, and by the kernel
+unsafe impl std::marker::Send for UpstreamDriver {}
+
+impl UpstreamDriver {
+    /// Runs the operations until the ring fails, like [`Acceptor::run`]
+    fn run(&mut self) -> io::Result<()> {
+        self.read_wake();
+        loop {
+            self.ring.submit_and_wait(1)?;
+            for completion in self.ring.completions() {
+                match completion.user_data {
+                    WAKE => self.read_wake(),
+                    data if data & CANCEL != 0 => {}
+                    id => self.complete(id, completion.res),
+                }
+            }
+            // further operations wait for the next round once the submission queue is full
+            while self.ring.has_room() {
+                let Ok(operation) = self.operations.try_recv() else {
+                    break;
+                };
+                self.start(operation);
+            }
+        }
+    }
+
+    fn start(&mut self, operation: Operation) {
+        let entry = match operation {
+            Operation::Send {
+                id,
+                socket,
+                datagram,
+                done,
+            } => {
+                let send = Send::new(datagram);
+                let entry = send.entry(socket.as_raw_fd(), id);
+                self.in_flight
+                    .insert(id, (InFlight::Send(send, done), socket));
+                entry
+            }
+            Operation::Receive {
+                id,
+                socket,
+                len,
+                done,
+            } => {
+                let mut receive = Box::new(Receive::new(len));
+                let entry = receive.entry(socket.as_raw_fd(), id);
+                let in_flight = InFlight::Receive(receive, done);
+                self.in_flight.insert(id, (in_flight, socket));
+                entry
+            }
+            // the receive may have completed in the meantime
+            Operation::Cancel(id) if !self.in_flight.contains_key(&id) => return,
+            Operation::Cancel(id) => Entry {
+                opcode: IORING_OP_ASYNC_CANCEL,
+                fd: -1,
+                addr: id,
+                user_data: CANCEL | id,
+                ..Entry::default()
+            },
+        };
+        self.ring.push(entry);
+    }
+
+    fn complete(&mut self, id: u64, res: i32) {
+        // those waiting for the result may have given up already
+        match self.in_flight.remove(&id) {
+            Some((InFlight::Send(send, done), _)) => {
+                // the kernel is done with the datagram
+                drop(send);
+                let _ = done.send(result(res));
+            }
+            Some((InFlight::Receive(receive, done), _)) => {
+                let received = result(res).and_then(|_| {
+                    (receive.received(res)).ok_or_else(|| io::Error::other("no source address"))
+                });
+                let _ = done.send(received);
+            }
+            None => {}
+        }
+    }
+
+    fn read_wake(&mut self) {
+        let entry = Entry {
+            opcode: IORING_OP_READ,
+            fd: self.wake.as_raw_fd(),
+            addr: &mut *self.woken as *mut u64 as u64,
+            len: 8,
+            user_data: WAKE,
+            ..Entry::default()
+        };
+        self.ring.push(entry);
+    }
+}
+
+/// A socket querying an upstream through the [`UpstreamRing`]
+pub struct RingSocket {
+    socket: Arc<std::net::UdpSocket>,
+    ring: &'static UpstreamRing,
+}
+
+impl RingSocket {
+    /// Binds a new socket for the queries of `opts`, see [`bind_query_socket`]
+    pub fn bind(ring: &'static UpstreamRing, opts: &ResolveOptions) -> io::Result<Self> {
+        let socket = Arc::new(bind_query_socket(opts)?);
+        Ok(Self { socket, ring })
+    }
+}
+
+impl QuerySocket for RingSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let (done, sent) = oneshot::channel();
+        self.ring.submit(Operation::Send {
+            id: self.ring.id(),
+            socket: Arc::clone(&self.socket),
+            datagram: (datagram.to_vec(), target),
+            done,
+        })?;
+        sent.await
+            .map_err(|_| io::Error::other("io_uring stopped"))?
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (done, received) = oneshot::channel();
+        let id = self.ring.id();
+        self.ring.submit(Operation::Receive {
+            id,
+            socket: Arc::clone(&self.socket),
+            len: buffer.len(),
+            done,
+        })?;
+        // cancels the receive if this future is dropped before it completed, eg. as the attempt
+        // timed out
+        let mut pending = PendingReceive {
+            ring: self.ring,
+            id: Some(id),
+        };
+        let received = received.await;
+        pending.id = None;
+        let (datagram, source) = received.map_err(|_| io::Error::other("io_uring stopped"))??;
+        buffer[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), source))
+    }
+}
+
+struct PendingReceive {
+    ring: &'static UpstreamRing,
+    id: Option<u64>,
+}
+
+impl Drop for PendingReceive {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let _ = self.ring.submit(Operation::Cancel(id));
+        }
+    }
+}
+
+/// A submission queue entry, `struct io_uring_sqe`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Entry {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry, `struct io_uring_cqe`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Completion {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// `struct io_sqring_offsets`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SubmissionOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_cqring_offsets`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CompletionOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_uring_params`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SubmissionOffsets,
+    cq_off: CompletionOffsets,
+}
+
+/// Memory shared with the kernel, unmapped when dropped
+struct Mapping {
+    pointer: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: maps fresh memory, which nothing else refers to
+        let pointer = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { pointer, len })
+    }
+
+    /// The value at `offset` bytes into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: the kernel told the offsets within the mapping
+        unsafe { self.pointer.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmaps memory that was mapped by `Mapping::new`
+        unsafe { libc::munmap(self.pointer, self.len) };
+    }
+}
+
+/// An io_uring instance, with the queues mapped into the process
+struct Ring {
+    fd: OwnedFd,
+    params: Params,
+    submissions: Mapping,
+    /// Separate from `submissions` on kernels before 5.4
+    completions: Option<Mapping>,
+    entries: Mapping,
+    /// Entries pushed but not submitted yet
+    unsubmitted: u32,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // SAFETY: the kernel fills in `params`
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and is owned by nobody else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        let submissions_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let completions_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Completion>();
+        let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let (submissions, completions) = match single {
+            true => {
+                let len = submissions_len.max(completions_len);
+                (Mapping::new(fd.as_raw_fd(), len, IORING_OFF_SQ_RING)?, None)
+            }
+            false => (
+                Mapping::new(fd.as_raw_fd(), submissions_len, IORING_OFF_SQ_RING)?,
+                Some(Mapping::new(
+                    fd.as_raw_fd(),
+                    completions_len,
+                    IORING_OFF_CQ_RING,
+                )?),
+            ),
+        };
+        let entries_len = params.sq_entries as usize * size_of::<Entry>();
+        let entries = Mapping::new(fd.as_raw_fd(), entries_len, IORING_OFF_SQES)?;
+        Ok(Self {
+            fd,
+            params,
+            submissions,
+            completions,
+            entries,
+            unsubmitted: 0,
+        })
+    }
+
+    fn completion_mapping(&self) -> &Mapping {
+        self.completions.as_ref().unwrap_or(&self.submissions)
+    }
+
+    fn atomic(mapping: &Mapping, offset: u32) -> &AtomicU32 {
+        // SAFETY: the kernel aligns the head and tail of the queues
+        unsafe { &*mapping.at::<AtomicU32>(offset) }
+    }
+
+    /// Queues `entry` for the next submission. The acceptor never has more operations in flight
+    /// than the ring has entries, so there always is room.
+    fn push(&mut self, entry: Entry) {
+        let offsets = &self.params.sq_off;
+        let head = Self::atomic(&self.submissions, offsets.head).load(Ordering::Acquire);
+        let tail = Self::atomic(&self.submissions, offsets.tail).load(Ordering::Relaxed);
+        debug_assert!(tail.wrapping_sub(head) < self.params.sq_entries);
+        // SAFETY: the mask keeps the index within the queues
+        unsafe {
+            let mask = *self.submissions.at::<u32>(offsets.ring_mask);
+            let index = tail & mask;
+            *self.entries.at::<Entry>(index * size_of::<Entry>() as u32) = entry;
+            *self.submissions.at::<u32>(offsets.array + index * 4) = index;
+        }
+        Self::atomic(&self.submissions, offsets.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+    }
+
+    /// Whether an entry and the read of the eventfd can be pushed before the next submission
+    fn has_room(&self) -> bool {
+        self.unsubmitted + 1 < self.params.sq_entries
+    }
+
+    /// Submits the pushed entries and waits until at least `min` completions are ready
+    fn submit_and_wait(&mut self, min: u32) -> io::Result<()> {
+        loop {
+            // SAFETY: the ring's descriptor and no arguments beyond the counts
+            let submitted = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.unsubmitted,
+                    min,
+                    IORING_ENTER_GETEVENTS,
+                    null_mut::<libc::c_void>(),
+                    0,
+                )
+            };
+            if submitted >= 0 {
+                self.unsubmitted -= submitted as u32;
+                return Ok(());
+            }
+            match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                // the completion queue is full, which the caller empties first
+                e if e.raw_os_error() == Some(libc::EBUSY) => return Ok(()),
+                e => return Err(e),
+            }
+        }
+    }
+
+    /// Takes the completions that are ready
+    fn completions(&mut self) -> Vec<Completion> {
+        let mapping = self.completion_mapping();
+        let offsets = &self.params.cq_off;
+        let head = Self::atomic(mapping, offsets.head).load(Ordering::Relaxed);
+        let tail = Self::atomic(mapping, offsets.tail).load(Ordering::Acquire);
+        // SAFETY: the mask keeps the index within the queue
+        let mask = unsafe { *mapping.at::<u32>(offsets.ring_mask) };
+        let completions = (0..tail.wrapping_sub(head))
+            .map(|i| {
+                let index = head.wrapping_add(i) & mask;
+                let offset = offsets.cqes + index * size_of::<Completion>() as u32;
+                // SAFETY: the kernel wrote the completions between head and tail
+                unsafe { *mapping.at::<Completion>(offset) }
+            })
+            .collect();
+        Self::atomic(mapping, offsets.head).store(tail, Ordering::Release);
+        completions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use dns::{
+        parse::parser::DnsParser, protocol::query::QueryBuilder, resolver::resolve_query_async,
+    };
+
+    use crate::{cli::ServerArgs, state::State};
+
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(size_of::<Entry>(), 64);
+        assert_eq!(size_of::<Completion>(), 16);
+        assert_eq!(size_of::<Params>(), 120);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_ring() {
+        let upstream = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = upstream.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            while let Ok((len, client)) = upstream.recv_from(&mut buffer) {
+                upstream.send_to(&buffer[..len], client).unwrap();
+            }
+        });
+        let Some(ring) = upstream_ring() else {
+            // io_uring is not available here, and the upstreams are queried without it
+            return;
+        };
+        let opts = ResolveOptions::default();
+        let socket = RingSocket::bind(ring, &opts).unwrap();
+
+        // receives given up on are cancelled, and the socket keeps working after
+        let mut buffer = [0; 512];
+        let unanswered = socket.recv_from(&mut buffer);
+        assert!(tokio::time::timeout(Duration::from_millis(50), unanswered)
+            .await
+            .is_err());
+        assert_eq!(socket.send_to(&[1, 2, 3], address).await.unwrap(), 3);
+        let (len, source) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!((&buffer[..len], source), (&[1, 2, 3][..], address));
+
+        // the whole exchange of the resolver, with the retries and the check of the response
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        let address = address.to_string();
+        let response = resolve_query_async(&query, &address, &socket, &opts);
+        assert_eq!(response.await.unwrap(), query);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_accept_udp() {
+        let server_args = ServerArgs::parse_from([
+            "dns-block-tokio",
+            "--benchmark",
+            "--resolution-delay-ms=0",
+            "--quiet",
+        ]);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let server = Arc::new(Server::new(State::new(server_args)));
+        accept_udp(Arc::new(socket), server);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for id in 0..100 {
+            let query = QueryBuilder::new("example.com".parse().unwrap())
+                .id(id)
+                .build();
+            client.send_to(&query, address).await.unwrap();
+        }
+        let mut ids = vec![];
+        let mut buffer = [0; 4096];
+        while ids.len() < 100 {
+            let received = client.recv_from(&mut buffer);
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), received)
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(
+                DnsParser::new(&buffer[..len])
+                    .parse_header()
+                    .unwrap()
+                    .request_id,
+            );
+        }
+        ids.sort();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
+    }
+}
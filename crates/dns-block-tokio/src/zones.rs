@@ -0,0 +1,169 @@
+use std::{path::Path, sync::Arc, time::Duration, time::SystemTime};
+
+use dns::{
+    authority::ZoneSet,
+    dnssec::{save_keys, KeyRole, ZoneSigner},
+    notify::send_notify_async,
+    protocol::name::DnsName,
+    resolver::ResolveOptions,
+    zonefile::Zone,
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{cli::ServerArgs, state::State};
+
+/// How often zone files are checked for changes
+const ZONE_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often signed zones are checked for key rollovers and signatures due for renewal
+const ZONE_SIGNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts keeping the local zones up to date: secondary zones are transferred from their
+/// primaries as their SOA timers say, zone files are read again whenever they change, and signed
+/// zones are signed again as their keys and signatures require, telling the secondaries given by
+/// `--notify` about it. Returns the tasks doing so.
+pub fn spawn_zone_tasks(state: &State) -> Vec<JoinHandle<()>> {
+    let Some(zones) = state.zones.clone() else {
+        return vec![];
+    };
+    let mut tasks = vec![];
+    let server_args = &state.args;
+    // NOTIFY messages and zone transfers are plain DNS, whatever the upstreams are queried with
+    let opts = ResolveOptions {
+        timeout: Duration::from_millis(server_args.relay_timeout_ms),
+        retries: server_args.relay_retries,
+        ..ResolveOptions::default()
+    };
+
+    let secondaries = Arc::clone(&state.secondaries);
+    for i in 0..secondaries.len() {
+        let (secondaries, zones, opts) =
+            (Arc::clone(&secondaries), Arc::clone(&zones), opts.clone());
+        tasks.push(tokio::spawn(async move {
+            secondaries[i].run(&zones, &opts).await
+        }));
+    }
+
+    if !zones.signers().is_empty() {
+        let (zones, opts, server_args) = (Arc::clone(&zones), opts.clone(), server_args.clone());
+        tasks.push(tokio::spawn(async move {
+            maintain_signatures(zones, server_args, opts).await
+        }));
+    }
+
+    let files = server_args.zone_files();
+    if !files.is_empty() {
+        let (notify, quiet) = (server_args.notify.clone(), server_args.quiet);
+        tasks.push(tokio::spawn(async move {
+            watch_zone_files(files, zones, notify, opts, quiet).await
+        }));
+    }
+    tasks
+}
+
+/// Rolls the keys of `signer` as its policy says and saves them to `path` if they changed,
+/// printing the DS records of the key signing keys for the parent zone
+pub fn roll_zone_keys(signer: &ZoneSigner, path: &Path, now: SystemTime) {
+    let changed = match signer.roll_keys(now) {
+        Ok(changed) => changed,
+        Err(e) => {
+            warn!("Could not roll DNSSEC keys of {}: {e}", signer.origin);
+            return;
+        }
+    };
+    if !changed {
+        return;
+    }
+    if let Err(e) = save_keys(path, &signer.keys()) {
+        warn!("Could not save DNSSEC keys to {}: {e}", path.display());
+    }
+    for key in signer.keys() {
+        if key.role == KeyRole::Ksk && key.timing.delete.is_none() {
+            info!(
+                "DS record of {}: {}",
+                signer.origin,
+                key.ds(&signer.origin, 3600)
+            );
+        }
+    }
+}
+
+async fn maintain_signatures(zones: Arc<ZoneSet>, server_args: ServerArgs, opts: ResolveOptions) {
+    loop {
+        tokio::time::sleep(ZONE_SIGNING_INTERVAL).await;
+        let now = SystemTime::now();
+        for signer in zones.signers() {
+            if let Some(path) = server_args.key_file(&signer.origin) {
+                roll_zone_keys(signer, &path, now);
+            }
+        }
+        for origin in zones.resign(now) {
+            if !server_args.quiet {
+                info!("Signed zone {origin}");
+            }
+            if let Some(zone) = zones.get(&origin) {
+                notify_secondaries(&zone, &server_args.notify, &opts).await;
+            }
+        }
+    }
+}
+
+async fn watch_zone_files(
+    files: Vec<(DnsName, String)>,
+    zones: Arc<ZoneSet>,
+    notify: Vec<String>,
+    opts: ResolveOptions,
+    quiet: bool,
+) {
+    // the secondaries may have missed changes while this server was down, or before the zones
+    // were read again
+    for (origin, _) in &files {
+        if let Some(zone) = zones.get(origin) {
+            notify_secondaries(&zone, &notify, &opts).await;
+        }
+    }
+
+    let mut modified: Vec<_> = files.iter().map(|(_, path)| modified(path)).collect();
+    loop {
+        tokio::time::sleep(ZONE_RELOAD_INTERVAL).await;
+        for ((origin, path), modified) in files.iter().zip(&mut modified) {
+            let now_modified = self::modified(path);
+            if now_modified == *modified {
+                continue;
+            }
+            *modified = now_modified;
+            let zone = match Zone::load(path, origin) {
+                Ok(zone) => zone,
+                Err(e) => {
+                    warn!("Could not reload zone {origin} from {path}: {e}");
+                    continue;
+                }
+            };
+            let changed = zones.get(origin).map(|current| current.serial()) != Some(zone.serial());
+            zones.insert(zone);
+            if !quiet {
+                info!("Reloaded zone {origin} from {path}");
+            }
+            if changed {
+                if let Some(zone) = zones.get(origin) {
+                    notify_secondaries(&zone, &notify, &opts).await;
+                }
+            }
+        }
+    }
+}
+
+async fn notify_secondaries(zone: &Zone, secondaries: &[String], opts: &ResolveOptions) {
+    for secondary in secondaries {
+        if let Err(e) = send_notify_async(zone, secondary, opts).await {
+            warn!("Could not notify {secondary} of zone {}: {e}", zone.origin);
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -1,20 +1,267 @@
-use dns::protocol::answer::Answer;
+use std::{
+    io::{BufRead, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dns::{
+    config::ResolverConfig,
+    protocol::{
+        answer::Answer,
+        name::DnsName,
+        query::{random_id, EdnsOptions, QueryBuilder},
+        record_type::RecordType,
+    },
+    recursive::{RecursiveResolver, TraceStep},
+    resolver::{bind_query_socket, resolve_query, ResolveOptions},
+};
+
+/// How many names are resolved at the same time in batch and benchmark mode by default
+const DEFAULT_CONCURRENCY: usize = 16;
+const DEFAULT_BENCH_QUERIES: usize = 1000;
 
 fn main() {
-    let mut args = std::env::args();
-    args.next();
-    let domain = args.next().expect("Please specify a domain name");
-    let dns_server = args.next().unwrap_or_else(|| "1.1.1.1".into());
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(position) = args.iter().position(|arg| arg == "+trace") {
+        args.remove(position);
+        return trace(&args);
+    }
+    match args.first().map(String::as_str) {
+        Some("bench") => bench(&args[1..]),
+        Some("--stdin") => batch(dns_server(args.get(1).cloned())),
+        Some(domain) => resolve(domain, &dns_server(args.get(1).cloned())),
+        None => panic!("Please specify a domain name, --stdin or bench"),
+    }
+}
+
+/// The given server, or the first name server the system is configured with, if any
+fn dns_server(server: Option<String>) -> String {
+    server.unwrap_or_else(|| {
+        ResolverConfig::from_system()
+            .ok()
+            .and_then(|config| config.upstreams().into_iter().next())
+            .unwrap_or_else(|| "1.1.1.1".into())
+    })
+}
 
+fn resolve(domain: &str, dns_server: &str) {
     println!("Resolving {domain} via DNS {dns_server}\n\n");
 
-    let (answers, _) = dns::resolver::resolve_domain(&domain, &dns_server, None, None)
+    let (answers, _) = dns::resolver::resolve_domain(domain, dns_server, None, None)
         .expect("Error resolving DNS records");
 
     for answer in answers {
         match answer {
-            Answer::A { meta, ipv4 } => println!("A\t{meta:?} - {ipv4}"),
-            Answer::CNAME { meta, cname } => println!("CNAME\t{meta:?} - {cname}"),
+            Answer::A { meta, ipv4 } => {
+                println!("A\t{} {meta:?} - {ipv4}", meta.name.to_unicode())
+            }
+            Answer::CNAME { meta, cname } => println!(
+                "CNAME\t{} {meta:?} - {}",
+                meta.name.to_unicode(),
+                cname.to_unicode()
+            ),
+            other => {
+                let meta = other.meta();
+                println!("{:?}\t{} {meta:?}", meta.r#type, meta.name.to_unicode())
+            }
+        }
+    }
+}
+
+/// Resolves the names on stdin, one per line, and prints the records of each as soon as they
+/// arrive, so the output is not in the order of the input
+fn batch(dns_server: String) {
+    println!(";; Resolving names from stdin via DNS {dns_server}");
+    let stdin = std::io::stdin();
+    std::thread::scope(|scope| {
+        for _ in 0..DEFAULT_CONCURRENCY {
+            scope.spawn(|| loop {
+                let mut line = String::new();
+                if !matches!(stdin.lock().read_line(&mut line), Ok(read) if read > 0) {
+                    break;
+                }
+                let domain = line.trim();
+                if domain.is_empty() || domain.starts_with('#') {
+                    continue;
+                }
+                match dns::resolver::resolve_domain(domain, &dns_server, None, None) {
+                    Ok((answers, _)) if answers.is_empty() => println!(";; {domain}: no records"),
+                    Ok((answers, _)) => {
+                        let mut stdout = std::io::stdout().lock();
+                        for answer in answers {
+                            writeln!(stdout, "{answer}").unwrap();
+                        }
+                    }
+                    Err(e) => eprintln!(";; {domain}: {e}"),
+                }
+            });
         }
+    });
+}
+
+/// Resolves a name from the root servers down, printing the response of every name server on
+/// the way like `dig +trace` does.
+///
+/// `<domain> [type] +trace`
+fn trace(args: &[String]) {
+    let domain = args.first().expect("Please specify a domain name");
+    let record_type: RecordType = args
+        .get(1)
+        .map(|record_type| record_type.parse().expect("Invalid record type"))
+        .unwrap_or(RecordType::A);
+
+    let resolver = RecursiveResolver::new()
+        .options(ResolveOptions {
+            timeout: Duration::from_millis(800),
+            retries: 1,
+            // so referrals show whether the delegation is signed
+            edns: Some(EdnsOptions {
+                dnssec_ok: true,
+                ..EdnsOptions::default()
+            }),
+            ..ResolveOptions::default()
+        })
+        .trace(print_step);
+    match resolver.resolve(domain, record_type) {
+        Ok(answers) => println!(";; {domain} resolved to {} records", answers.len()),
+        Err(e) => println!(";; Could not resolve {domain}: {e}"),
     }
 }
+
+fn print_step(step: &TraceStep) {
+    let mut stdout = std::io::stdout().lock();
+    for record in step
+        .response
+        .iter()
+        .flat_map(|response| response.answers.iter().chain(&response.authorities))
+    {
+        writeln!(stdout, "{record}").unwrap();
+    }
+    let asked = format!(
+        "{} {} from {} for {}",
+        step.name.to_fqdn(),
+        step.record_type,
+        step.server,
+        step.zone.to_fqdn()
+    );
+    match &step.error {
+        Some(e) => writeln!(
+            stdout,
+            ";; {asked} failed after {:.0} ms: {e}",
+            millis(step.rtt)
+        ),
+        None => writeln!(
+            stdout,
+            ";; {asked} in {:.0} ms, DNSSEC: {}",
+            millis(step.rtt),
+            if step.is_signed() {
+                "signed"
+            } else {
+                "unsigned"
+            }
+        ),
+    }
+    .unwrap();
+    writeln!(stdout).unwrap();
+}
+
+/// Sends the given number of A queries for the names, `example.com` if none are given, to the
+/// server and reports the throughput and latencies, like dnsperf does.
+///
+/// `bench <server> [--queries N] [--concurrency N] [name ...]`
+fn bench(args: &[String]) {
+    let mut args = args.iter();
+    let dns_server = args.next().expect("Please specify the server to benchmark");
+    let (mut queries, mut concurrency, mut names) =
+        (DEFAULT_BENCH_QUERIES, DEFAULT_CONCURRENCY, vec![]);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--queries" | "-n" => {
+                queries = args
+                    .next()
+                    .and_then(|queries| queries.parse().ok())
+                    .expect("--queries takes a number");
+            }
+            "--concurrency" | "-c" => {
+                concurrency = args
+                    .next()
+                    .and_then(|concurrency| concurrency.parse().ok())
+                    .filter(|&concurrency| concurrency > 0)
+                    .expect("--concurrency takes a positive number");
+            }
+            name => names.push(DnsName::from_utf8(name).expect("Invalid domain name")),
+        }
+    }
+    if names.is_empty() {
+        names.push(DnsName::new("example.com").unwrap());
+    }
+
+    // lost queries are counted instead of sent again
+    let opts = ResolveOptions {
+        retries: 0,
+        ..ResolveOptions::default()
+    };
+    let sent = AtomicUsize::new(0);
+    let latencies = Mutex::new(Vec::with_capacity(queries));
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..concurrency.min(queries) {
+            scope.spawn(|| {
+                let socket = bind_query_socket(&opts).expect("Could not bind a socket");
+                let mut own = vec![];
+                loop {
+                    let i = sent.fetch_add(1, Ordering::Relaxed);
+                    if i >= queries {
+                        break;
+                    }
+                    let query = QueryBuilder::new(names[i % names.len()].clone())
+                        .id(random_id())
+                        .build();
+                    let sent_at = Instant::now();
+                    if resolve_query(&query, dns_server, &socket, &opts).is_ok() {
+                        own.push(sent_at.elapsed());
+                    }
+                }
+                latencies.lock().unwrap().extend(own);
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    let mut latencies = latencies.into_inner().unwrap();
+    latencies.sort();
+    let completed = latencies.len();
+    println!("Queries sent:         {queries}");
+    println!(
+        "Queries completed:    {completed} ({:.2}%)",
+        completed as f64 * 100.0 / queries.max(1) as f64
+    );
+    println!("Queries lost:         {}", queries - completed);
+    println!("Run time (s):         {:.3}", elapsed.as_secs_f64());
+    println!(
+        "Queries per second:   {:.1}",
+        completed as f64 / elapsed.as_secs_f64()
+    );
+    if completed > 0 {
+        println!(
+            "Latency (ms):         min {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}, max {:.2}",
+            millis(latencies[0]),
+            millis(percentile(&latencies, 0.5)),
+            millis(percentile(&latencies, 0.9)),
+            millis(percentile(&latencies, 0.99)),
+            millis(latencies[completed - 1])
+        );
+    }
+}
+
+/// The latency below which the share `p` of the `sorted` latencies falls
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -1,23 +1,82 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use dns::parse::parser::DnsParser;
+//! Run with `cargo bench -p dns`. Each of these should keep a throughput of more than 1M
+//! messages per second, the relay parses every query at least once.
 
-fn dns_parser(c: &mut Criterion) {
-    let dns_queries: Vec<_> = (include_bytes!("./inputs/1000.bin").chunks(512))
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use dns::{parse::parser::DnsParser, protocol::query::QueryBuilder};
+
+/// Recorded queries, each padded to 512 bytes
+fn recorded() -> Vec<[u8; 512]> {
+    (include_bytes!("./inputs/1000.bin").chunks(512))
         .chain(include_bytes!("./inputs/youtube-spotify.bin").chunks(512))
         .map(|chunk| {
             let mut packet = [0u8; 512];
             packet.copy_from_slice(chunk);
             packet
         })
-        .collect();
+        .collect()
+}
+
+/// The recorded queries at their actual length and with an OPT record, like current resolvers
+/// send them
+fn queries(recorded: &[[u8; 512]]) -> Vec<Vec<u8>> {
+    (recorded.iter())
+        .map(|query| {
+            let packet = DnsParser::new(query).parse_packet().unwrap();
+            let question = &packet.questions[0];
+            QueryBuilder::new(question.domain_name.clone())
+                .id(packet.header.request_id)
+                .edns_payload_size(1232)
+                .build()
+        })
+        .collect()
+}
 
-    c.bench_function("parse full packet", |b| {
+fn dns_parser(c: &mut Criterion) {
+    let dns_recorded = recorded();
+    let dns_queries = queries(&dns_recorded);
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(dns_recorded.len() as u64));
+    group.bench_function("full packet", |b| {
         b.iter(|| {
-            for p in dns_queries.iter() {
+            for p in dns_recorded.iter() {
                 DnsParser::new(black_box(p)).parse_answers().unwrap();
             }
         });
     });
+    group.bench_function("relay information", |b| {
+        b.iter(|| {
+            for q in dns_queries.iter() {
+                DnsParser::new(black_box(q))
+                    .get_relay_information()
+                    .unwrap();
+            }
+        });
+    });
+    group.bench_function("scan query", |b| {
+        b.iter(|| {
+            for q in dns_queries.iter() {
+                DnsParser::new(black_box(q)).scan_query().unwrap();
+            }
+        });
+    });
+    group.bench_function("question", |b| {
+        b.iter(|| {
+            for q in dns_queries.iter() {
+                let mut parser = DnsParser::new(black_box(q));
+                parser.parse_header().unwrap();
+                parser.parse_question().unwrap();
+            }
+        });
+    });
+    group.bench_function("payload size", |b| {
+        b.iter(|| {
+            for q in dns_queries.iter() {
+                DnsParser::new(black_box(q)).parse_payload_size().unwrap();
+            }
+        });
+    });
+    group.finish();
 }
 
 criterion_group!(benches, dns_parser);
@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use dns::{
+    parse::parser::{encode_domain_name, DnsParser},
+    protocol::packet::Packet,
+};
+
+/// Recorded queries, parsed in full
+fn packets() -> Vec<Packet> {
+    (include_bytes!("./inputs/1000.bin").chunks(512))
+        .chain(include_bytes!("./inputs/youtube-spotify.bin").chunks(512))
+        .map(|chunk| DnsParser::new(chunk).parse_packet().unwrap())
+        .collect()
+}
+
+fn serialize(c: &mut Criterion) {
+    let packets = packets();
+    let names: Vec<_> = (packets.iter())
+        .flat_map(|packet| {
+            packet
+                .questions
+                .iter()
+                .map(|question| question.domain_name.clone())
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("serialize");
+    group.throughput(Throughput::Elements(packets.len() as u64));
+    group.bench_function("packet", |b| {
+        b.iter(|| {
+            for packet in packets.iter() {
+                black_box(packet).to_bytes();
+            }
+        });
+    });
+    group.throughput(Throughput::Elements(names.len() as u64));
+    group.bench_function("domain name", |b| {
+        b.iter(|| {
+            for name in names.iter() {
+                encode_domain_name(black_box(name));
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, serialize);
+criterion_main!(benches);
@@ -0,0 +1,1222 @@
+//! Answering authoritatively for local zones, before the cache or any upstream is asked.
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use crate::{
+    dnssec::{
+        covered_type, is_nsec3_record, nsec3_for, nsec_for, Nsec3Params, ZoneSigner, DNSKEY, DS,
+        NSEC, NSEC3,
+    },
+    parse::parser::DnsParser,
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        header::{Flags, Header},
+        name::DnsName,
+        opcode::Opcode,
+        packet::Packet,
+        query::EdnsOptions,
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    resolver::DEFAULT_MAX_CNAME_HOPS,
+    serialize::writer::PacketWriter,
+    tsig::{verify_request, TsigKey, TsigSigner},
+    update::apply_update,
+    zonefile::Zone,
+};
+
+/// Size the records of a single zone transfer message add up to at most, well below the 64K
+/// DNS over TCP allows, so a message never has to be split and compression pointers reach all of it
+const MAX_TRANSFER_CHUNK_SIZE: usize = 16 * 1024;
+
+/// What a zone says about a name, as the sections of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneAnswer {
+    /// NXDOMAIN if the name does not exist, NOERROR otherwise
+    pub response_code: ResponseCode,
+    /// Whether the zone holds the data, which referrals to a delegated child zone do not
+    pub authoritative: bool,
+    /// The records of the asked type, after the CNAME records leading to them
+    pub answers: Vec<Answer>,
+    /// The SOA record for negative answers, or the NS records of a referral
+    pub authorities: Vec<Answer>,
+    /// Addresses of the name servers of a referral
+    pub additionals: Vec<Answer>,
+}
+
+/// Who may transfer or update the zones of a [`ZoneSet`], servers either connecting from one of
+/// the addresses or signing their request with one of the keys. Nobody may by default.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    pub addresses: Vec<IpAddr>,
+    pub keys: Vec<TsigKey>,
+}
+
+impl Acl {
+    /// Whether the raw `request` from `peer` is allowed, with the signer of the responses if it
+    /// was signed, or else the response code to reject it with
+    pub(crate) fn check(
+        &self,
+        request: &[u8],
+        peer: IpAddr,
+    ) -> Result<Option<TsigSigner>, ResponseCode> {
+        match verify_request(request, &self.keys) {
+            Ok(Some(request)) => Ok(Some(TsigSigner::response(request))),
+            Ok(None) if self.addresses.contains(&peer) => Ok(None),
+            Ok(None) => Err(ResponseCode::Refused),
+            Err(e) => Err(e.response_code()),
+        }
+    }
+}
+
+impl Zone {
+    /// Answers `name` and `record_type` from the records of this zone, like an authoritative
+    /// server does. CNAME records are followed as long as they stay within the zone, and names
+    /// below a delegation are referred to the name servers of the child zone. Names that do not
+    /// exist are answered by a wildcard like `*.example.lan` at their closest encloser, if any.
+    /// https://datatracker.ietf.org/doc/html/rfc1034#section-4.3.2
+    pub fn lookup(&self, name: &DnsName, record_type: RecordType) -> ZoneAnswer {
+        let mut answers = vec![];
+        let mut name = name.clone();
+        while name.is_subdomain_of(&self.origin) && answers.len() <= DEFAULT_MAX_CNAME_HOPS {
+            if let Some(cut) = self.zone_cut(&name) {
+                if answers.is_empty() {
+                    return self.referral(&cut);
+                }
+                break;
+            }
+
+            let records = if self.exists(&name) {
+                self.records_at(&name).cloned().collect()
+            } else {
+                match self.wildcard(&name) {
+                    Some(records) => records,
+                    None => return self.negative(ResponseCode::NXDomain, answers),
+                }
+            };
+            let matching: Vec<_> = records
+                .iter()
+                .filter(|record| {
+                    record_type == RecordType::ANY || record.meta().r#type == record_type
+                })
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                break;
+            }
+            match records.iter().find_map(|record| match record {
+                Answer::CNAME { cname, .. } => Some((record, cname)),
+                _ => None,
+            }) {
+                Some((record, target)) if record_type != RecordType::CNAME => {
+                    name = target.clone();
+                    answers.push(record.clone());
+                }
+                // the name exists without records of the type
+                _ => return self.negative(ResponseCode::NoError, answers),
+            }
+        }
+        ZoneAnswer {
+            response_code: ResponseCode::NoError,
+            authoritative: true,
+            answers,
+            authorities: vec![],
+            additionals: vec![],
+        }
+    }
+
+    /// Answers like [`Zone::lookup`], along with the records validating resolvers need: the
+    /// RRSIG records of the RRsets, the NSEC or NSEC3 records proving that names or records do
+    /// not exist or that a wildcard answered, and the DS records of signed delegations. Zones
+    /// without DNSKEY records are answered like by [`Zone::lookup`].
+    /// https://datatracker.ietf.org/doc/html/rfc4035#section-3.1
+    pub fn lookup_dnssec(&self, name: &DnsName, record_type: RecordType) -> ZoneAnswer {
+        let mut answer = self.lookup(name, record_type);
+        if !self.is_signed() {
+            return answer;
+        }
+        let mut denials = vec![];
+
+        let mut signatures = vec![];
+        let mut rrsets: Vec<(&DnsName, RecordType)> = vec![];
+        for record in &answer.answers {
+            let meta = record.meta();
+            if !rrsets.contains(&(&meta.name, meta.r#type)) {
+                rrsets.push((&meta.name, meta.r#type));
+            }
+        }
+        for (owner, record_type) in rrsets {
+            if self.exists(owner) {
+                signatures.extend(self.signatures(owner, record_type));
+                continue;
+            }
+            // the signatures of the wildcard, and the proof that `owner` itself does not exist
+            let Some(wildcard) = self.closest_encloser(owner).and_then(|ce| wildcard_at(&ce))
+            else {
+                continue;
+            };
+            signatures.extend(
+                self.signatures(&wildcard, record_type)
+                    .map(|mut signature| {
+                        signature.meta_mut().name = owner.clone();
+                        signature
+                    }),
+            );
+            denials.push(Denied::WildcardAnswer(owner.clone()));
+        }
+        extend_unique(&mut answer.answers, signatures);
+
+        let negative =
+            answer.authoritative && matches!(&answer.authorities[..], [Answer::SOA { .. }]);
+        if negative {
+            // the name the CNAME records lead to
+            let last = match answer.answers.last() {
+                Some(Answer::CNAME { cname, .. }) if record_type != RecordType::CNAME => cname,
+                _ => name,
+            };
+            let soa = self.signatures(&self.origin, RecordType::SOA).collect();
+            extend_unique(&mut answer.authorities, soa);
+            denials.push(match answer.response_code {
+                ResponseCode::NXDomain => Denied::Name(last.clone()),
+                _ => Denied::Type(last.clone()),
+            });
+        } else if let (false, Some(Answer::NS { meta, .. })) =
+            (answer.authoritative, answer.authorities.first())
+        {
+            let cut = meta.name.clone();
+            let ds: Vec<_> = self
+                .records_at(&cut)
+                .filter(|record| record.meta().r#type == RecordType::OTHER(DS))
+                .cloned()
+                .collect();
+            if ds.is_empty() {
+                denials.push(Denied::Type(cut));
+            } else {
+                let signatures = self.signatures(&cut, RecordType::OTHER(DS)).collect();
+                extend_unique(&mut answer.authorities, ds);
+                extend_unique(&mut answer.authorities, signatures);
+            }
+        }
+
+        for denied in denials {
+            let proof = self.denial(&denied);
+            extend_unique(&mut answer.authorities, proof);
+        }
+        answer
+    }
+
+    /// Whether the zone is signed with DNSSEC, so it has DNSKEY records
+    pub fn is_signed(&self) -> bool {
+        self.records_at(&self.origin)
+            .any(|record| record.meta().r#type == RecordType::OTHER(DNSKEY))
+    }
+
+    /// The RRSIG records of `owner` that cover `record_type`
+    fn signatures<'a>(
+        &'a self,
+        owner: &'a DnsName,
+        record_type: RecordType,
+    ) -> impl Iterator<Item = Answer> + 'a {
+        self.records_at(owner)
+            .filter(move |record| covered_type(record) == Some(record_type))
+            .cloned()
+    }
+
+    /// The NSEC or NSEC3 records proving `denied`, with their signatures
+    /// https://datatracker.ietf.org/doc/html/rfc4035#section-3.1.3
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-7.2
+    fn denial(&self, denied: &Denied) -> Vec<Answer> {
+        let name = match denied {
+            Denied::Name(name) | Denied::Type(name) | Denied::WildcardAnswer(name) => name,
+        };
+        let closest_encloser = self
+            .closest_encloser(name)
+            .unwrap_or_else(|| self.origin.clone());
+        let next_closer = name.ancestor(closest_encloser.label_count() + 1);
+        let wildcard = wildcard_at(&closest_encloser);
+        let missing = match denied {
+            Denied::Type(name) if self.exists(name) => false,
+            Denied::Name(_) | Denied::Type(_) => true,
+            Denied::WildcardAnswer(_) => false,
+        };
+
+        let records: Vec<&Answer> = match Nsec3Params::of(self) {
+            None => {
+                let mut records = vec![nsec_for(self, name)];
+                if missing {
+                    records.push(
+                        wildcard
+                            .as_ref()
+                            .and_then(|wildcard| nsec_for(self, wildcard)),
+                    );
+                }
+                records.into_iter().flatten().collect()
+            }
+            Some(params) => {
+                let mut records = vec![];
+                match denied {
+                    Denied::WildcardAnswer(_) => {
+                        records.push(nsec3_for(self, &params, &next_closer));
+                    }
+                    _ if !missing => records.push(nsec3_for(self, &params, name)),
+                    _ => {
+                        records.push(nsec3_for(self, &params, &closest_encloser));
+                        records.push(nsec3_for(self, &params, &next_closer));
+                        records.push(
+                            wildcard
+                                .as_ref()
+                                .and_then(|wildcard| nsec3_for(self, &params, wildcard)),
+                        );
+                    }
+                }
+                records.into_iter().flatten().collect()
+            }
+        };
+
+        let mut proof = vec![];
+        for record in records {
+            let meta = record.meta();
+            let record_type = match Nsec3Params::of(self) {
+                Some(_) => RecordType::OTHER(NSEC3),
+                None => RecordType::OTHER(NSEC),
+            };
+            let signatures = self.signatures(&meta.name, record_type).collect();
+            extend_unique(&mut proof, vec![record.clone()]);
+            extend_unique(&mut proof, signatures);
+        }
+        proof
+    }
+
+    /// The records of a full zone transfer, all records of the zone starting and ending with its
+    /// SOA record. `None` if the zone has no SOA record.
+    /// https://datatracker.ietf.org/doc/html/rfc5936#section-2.2
+    pub fn axfr(&self) -> Option<Vec<Answer>> {
+        let soa = self.soa()?;
+        let mut records = vec![soa.clone()];
+        records.extend(
+            self.records
+                .iter()
+                .filter(|record| !std::ptr::eq(*record, soa))
+                .cloned(),
+        );
+        records.push(soa.clone());
+        Some(records)
+    }
+
+    fn records_at<'a>(&'a self, name: &'a DnsName) -> impl Iterator<Item = &'a Answer> {
+        self.records
+            .iter()
+            .filter(move |record| record.meta().name == *name)
+    }
+
+    /// Whether `name` has records, or records below it. The hashed names of NSEC3 records do
+    /// not count, they only prove that other names do not exist.
+    fn exists(&self, name: &DnsName) -> bool {
+        self.records_at(name).any(|record| !is_nsec3_record(record)) || self.has_descendants(name)
+    }
+
+    /// The longest existing ancestor of `name`
+    /// https://datatracker.ietf.org/doc/html/rfc4592#section-3.3.1
+    fn closest_encloser(&self, name: &DnsName) -> Option<DnsName> {
+        (self.origin.label_count()..name.label_count())
+            .rev()
+            .map(|labels| name.ancestor(labels))
+            .find(|ancestor| self.exists(ancestor))
+    }
+
+    /// The records of the wildcard at the closest encloser of `name`, with `name` as their owner.
+    /// `None` if there is no such wildcard, so `name` does not exist.
+    fn wildcard(&self, name: &DnsName) -> Option<Vec<Answer>> {
+        let wildcard = wildcard_at(&self.closest_encloser(name)?)?;
+        let records: Vec<_> = self
+            .records_at(&wildcard)
+            .map(|record| {
+                let mut record = record.clone();
+                record.meta_mut().name = name.clone();
+                record
+            })
+            .collect();
+        (!records.is_empty()).then_some(records)
+    }
+
+    /// Whether records exist below `name`, which makes it an empty non-terminal if it has none of
+    /// its own
+    /// https://datatracker.ietf.org/doc/html/rfc8020
+    fn has_descendants(&self, name: &DnsName) -> bool {
+        self.records.iter().any(|record| {
+            let owner = &record.meta().name;
+            owner.label_count() > name.label_count()
+                && owner.is_subdomain_of(name)
+                && !is_nsec3_record(record)
+        })
+    }
+
+    /// The closest name between the origin and `name` that is delegated with NS records
+    fn zone_cut(&self, name: &DnsName) -> Option<DnsName> {
+        (self.origin.label_count() + 1..=name.label_count())
+            .map(|labels| name.ancestor(labels))
+            .find(|ancestor| {
+                self.records_at(ancestor)
+                    .any(|record| matches!(record, Answer::NS { .. }))
+            })
+    }
+
+    fn referral(&self, cut: &DnsName) -> ZoneAnswer {
+        let authorities: Vec<_> = self
+            .records_at(cut)
+            .filter(|record| matches!(record, Answer::NS { .. }))
+            .cloned()
+            .collect();
+        let additionals = authorities
+            .iter()
+            .filter_map(|record| match record {
+                Answer::NS { ns, .. } => Some(ns),
+                _ => None,
+            })
+            .flat_map(|ns| {
+                self.records_at(ns)
+                    .filter(|record| matches!(record, Answer::A { .. } | Answer::AAAA { .. }))
+            })
+            .cloned()
+            .collect();
+        ZoneAnswer {
+            response_code: ResponseCode::NoError,
+            authoritative: false,
+            answers: vec![],
+            authorities,
+            additionals,
+        }
+    }
+
+    /// A negative answer with the SOA record, whose TTL is capped by its minimum field for
+    /// caching the answer
+    /// https://datatracker.ietf.org/doc/html/rfc2308#section-3
+    fn negative(&self, response_code: ResponseCode, answers: Vec<Answer>) -> ZoneAnswer {
+        let authorities = self
+            .soa()
+            .cloned()
+            .map(|mut soa| {
+                if let Answer::SOA { meta, minimum, .. } = &mut soa {
+                    meta.ttl = meta.ttl.min(*minimum as usize);
+                }
+                soa
+            })
+            .into_iter()
+            .collect();
+        ZoneAnswer {
+            response_code,
+            authoritative: true,
+            answers,
+            authorities,
+            additionals: vec![],
+        }
+    }
+}
+
+/// What the NSEC or NSEC3 records of a DNSSEC answer have to prove
+enum Denied {
+    /// The name does not exist
+    Name(DnsName),
+    /// The name has no records of the asked type
+    Type(DnsName),
+    /// The name does not exist, and was answered by a wildcard
+    WildcardAnswer(DnsName),
+}
+
+/// The wildcard name at `closest_encloser`, eg. `*.example.lan`
+fn wildcard_at(closest_encloser: &DnsName) -> Option<DnsName> {
+    if closest_encloser.is_root() {
+        DnsName::new("*")
+    } else {
+        DnsName::new(&format!("*.{closest_encloser}"))
+    }
+    .ok()
+}
+
+/// The OPT record of a response, with the DO bit of the query
+/// https://datatracker.ietf.org/doc/html/rfc3225#section-3
+pub(crate) fn opt_record(dnssec_ok: bool) -> Answer {
+    let edns = EdnsOptions::default();
+    Answer::Unknown {
+        meta: AnswerMeta {
+            name: DnsName::root(),
+            r#type: RecordType::OPT,
+            class: Class::from(edns.payload_size),
+            ttl: usize::from(dnssec_ok) << 15,
+            len: 0,
+        },
+        rdata: vec![],
+    }
+}
+
+/// Appends the `records` that `section` does not have yet
+fn extend_unique(section: &mut Vec<Answer>, records: Vec<Answer>) {
+    for record in records {
+        if !section.contains(&record) {
+            section.push(record);
+        }
+    }
+}
+
+/// Zones a server is authoritative for, each question is answered from the zone closest to its
+/// name. Zones can be replaced while queries are answered, eg. after a zone transfer.
+///
+/// ```
+/// use dns::{authority::ZoneSet, protocol::record_type::RecordType, zonefile::Zone};
+///
+/// let zone = Zone::parse(
+///     "@ 300 SOA ns hostmaster 1 7200 3600 1209600 300\nnas 300 A 192.0.2.7",
+///     &"home.arpa".parse().unwrap(),
+/// )
+/// .unwrap();
+/// let zones = ZoneSet::new([zone]);
+/// let answer = zones.lookup(&"nas.home.arpa".parse().unwrap(), RecordType::A).unwrap();
+/// assert_eq!(answer.answers[0].to_string(), "nas.home.arpa. 300 IN A 192.0.2.7");
+/// assert!(zones.lookup(&"example.com".parse().unwrap(), RecordType::A).is_none());
+/// ```
+#[derive(Debug, Default)]
+pub struct ZoneSet {
+    zones: RwLock<Vec<Arc<Zone>>>,
+    transfer_acl: Acl,
+    update_acl: Acl,
+    /// Held while an update is applied, so concurrent updates of a zone do not undo each other
+    updating: Mutex<()>,
+    signers: Vec<Arc<ZoneSigner>>,
+}
+
+impl FromIterator<Zone> for ZoneSet {
+    fn from_iter<T: IntoIterator<Item = Zone>>(zones: T) -> Self {
+        Self::new(zones)
+    }
+}
+
+impl ZoneSet {
+    pub fn new(zones: impl IntoIterator<Item = Zone>) -> Self {
+        Self {
+            zones: RwLock::new(zones.into_iter().map(Arc::new).collect()),
+            transfer_acl: Acl::default(),
+            update_acl: Acl::default(),
+            updating: Mutex::new(()),
+            signers: vec![],
+        }
+    }
+
+    /// Allows the secondaries of `acl` to transfer the zones, see [`ZoneSet::respond_transfer`]
+    pub fn transfer_acl(mut self, acl: Acl) -> Self {
+        self.transfer_acl = acl;
+        self
+    }
+
+    /// Allows the clients of `acl` to update the zones, see [`ZoneSet::respond_update`]
+    pub fn update_acl(mut self, acl: Acl) -> Self {
+        self.update_acl = acl;
+        self
+    }
+
+    /// Signs the zone of `signer` with DNSSEC, now and whenever it changes, see
+    /// [`ZoneSet::resign`]
+    pub fn signer(mut self, signer: ZoneSigner) -> Self {
+        let signer = Arc::new(signer);
+        self.signers.retain(|other| other.origin != signer.origin);
+        self.signers.push(Arc::clone(&signer));
+        if let Some(zone) = self.get(&signer.origin) {
+            self.replace(signer.sign(&zone, SystemTime::now()));
+        }
+        self
+    }
+
+    pub fn signers(&self) -> &[Arc<ZoneSigner>] {
+        &self.signers
+    }
+
+    /// Adds `zone`, replacing any zone with the same origin. Zones with a signer are signed
+    /// first.
+    pub fn insert(&self, zone: Zone) {
+        let zone = match self
+            .signers
+            .iter()
+            .find(|signer| signer.origin == zone.origin)
+        {
+            Some(signer) => signer.sign(&zone, SystemTime::now()),
+            None => zone,
+        };
+        self.replace(zone);
+    }
+
+    fn replace(&self, zone: Zone) {
+        let mut zones = self.zones.write().unwrap();
+        zones.retain(|other| other.origin != zone.origin);
+        zones.push(Arc::new(zone));
+    }
+
+    /// Signs the zones again whose signatures are due for renewal or whose keys changed, see
+    /// [`ZoneSigner::needs_signing`]. Their serials are increased, so secondaries transfer the
+    /// new signatures. Returns the origins of the zones signed.
+    pub fn resign(&self, now: SystemTime) -> Vec<DnsName> {
+        let _updating = self.updating.lock().unwrap();
+        let mut signed = vec![];
+        for signer in &self.signers {
+            if !signer.needs_signing(now) {
+                continue;
+            }
+            let Some(zone) = self.get(&signer.origin) else {
+                continue;
+            };
+            let mut zone = Zone::clone(&zone);
+            zone.increment_serial();
+            self.replace(signer.sign(&zone, now));
+            signed.push(zone.origin);
+        }
+        signed
+    }
+
+    /// Removes the zone whose origin is `origin`, if any
+    pub fn remove(&self, origin: &DnsName) -> Option<Arc<Zone>> {
+        let mut zones = self.zones.write().unwrap();
+        let position = zones.iter().position(|zone| zone.origin == *origin)?;
+        Some(zones.remove(position))
+    }
+
+    /// The zone with the longest origin that `name` is within
+    pub fn find(&self, name: &DnsName) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|zone| name.is_subdomain_of(&zone.origin))
+            .max_by_key(|zone| zone.origin.label_count())
+            .cloned()
+    }
+
+    /// The zone whose origin is `origin`
+    pub fn get(&self, origin: &DnsName) -> Option<Arc<Zone>> {
+        self.zones
+            .read()
+            .unwrap()
+            .iter()
+            .find(|zone| zone.origin == *origin)
+            .cloned()
+    }
+
+    /// Answers for `name` and `record_type`, or `None` if no zone is responsible for the name
+    pub fn lookup(&self, name: &DnsName, record_type: RecordType) -> Option<ZoneAnswer> {
+        Some(self.find(name)?.lookup(name, record_type))
+    }
+
+    /// Builds an authoritative response to the raw `query` if one of the zones is responsible for
+    /// the name in its question. Queries with the DO bit set get the DNSSEC records of signed
+    /// zones along, see [`Zone::lookup_dnssec`].
+    pub fn respond(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let opt = DnsParser::new(query).parse_opt().ok().flatten();
+        let query = DnsParser::new(query).parse_packet().ok()?;
+        let [question] = &query.questions[..] else {
+            return None;
+        };
+        if question.class != Class::IN {
+            return None;
+        }
+        let zone = self.find(&question.domain_name)?;
+        let dnssec_ok = opt.as_ref().is_some_and(|opt| opt.dnssec_ok);
+        let mut answer = if dnssec_ok {
+            zone.lookup_dnssec(&question.domain_name, question.r#type)
+        } else {
+            zone.lookup(&question.domain_name, question.r#type)
+        };
+        // https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.1
+        if opt.is_some() {
+            answer.additionals.push(opt_record(dnssec_ok));
+        }
+
+        let response = Packet {
+            header: Header {
+                request_id: query.header.request_id,
+                flags: Flags {
+                    query: false,
+                    opcode: query.header.flags.opcode,
+                    authoritative_answer: answer.authoritative,
+                    recursion_desired: query.header.flags.recursion_desired,
+                    recursion_available: true,
+                    checking_disabled: query.header.flags.checking_disabled,
+                    response_code: answer.response_code,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: query.questions,
+            answers: answer.answers,
+            authorities: answer.authorities,
+            additionals: answer.additionals,
+        };
+        Some(response.to_bytes())
+    }
+
+    /// Builds the responses to the raw AXFR `query` from `peer`, to be sent over TCP in order.
+    /// `None` if the query is not a zone transfer of one of the zones. Transfers not allowed by
+    /// the transfer ACL are refused, and responses to signed requests are signed as well.
+    pub fn respond_transfer(&self, query: &[u8], peer: IpAddr) -> Option<Vec<Vec<u8>>> {
+        let packet = DnsParser::new(query).parse_packet().ok()?;
+        let [question] = &packet.questions[..] else {
+            return None;
+        };
+        if question.r#type != RecordType::AXFR {
+            return None;
+        }
+        let zone = self.get(&question.domain_name)?;
+
+        let mut signer = match self.transfer_acl.check(query, peer) {
+            Ok(signer) => signer,
+            Err(response_code) => {
+                return Some(vec![
+                    transfer_response(&packet, response_code, vec![]).to_bytes()
+                ])
+            }
+        };
+        let Some(records) = zone.axfr() else {
+            return Some(vec![transfer_response(
+                &packet,
+                ResponseCode::ServFail,
+                vec![],
+            )
+            .to_bytes()]);
+        };
+
+        let mut messages = vec![];
+        let (mut chunk, mut size) = (vec![], 0);
+        for record in records {
+            let mut buf = vec![];
+            record.write(&mut PacketWriter::uncompressed(&mut buf));
+            if size + buf.len() > MAX_TRANSFER_CHUNK_SIZE && !chunk.is_empty() {
+                messages.push(std::mem::take(&mut chunk));
+                size = 0;
+            }
+            size += buf.len();
+            chunk.push(record);
+        }
+        messages.push(chunk);
+
+        Some(
+            messages
+                .into_iter()
+                .enumerate()
+                .map(|(i, answers)| {
+                    let mut response = transfer_response(&packet, ResponseCode::NoError, answers);
+                    // only the first message repeats the question
+                    // https://datatracker.ietf.org/doc/html/rfc5936#section-2.2.1
+                    if i > 0 {
+                        response.questions.clear();
+                    }
+                    let response = response.to_bytes();
+                    match &mut signer {
+                        Some(signer) => signer.sign(&response),
+                        None => response,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Applies the raw UPDATE `message` from `peer` to the zone named in its zone section, and
+    /// builds the response. Updates not allowed by the update ACL are refused, and responses to
+    /// signed updates are signed as well. The update only changes the zone in memory, not its
+    /// zone file. `None` if the message is no UPDATE.
+    pub fn respond_update(&self, message: &[u8], peer: IpAddr) -> Option<Vec<u8>> {
+        let update = DnsParser::new(message).parse_packet().ok()?;
+        if update.header.opcode() != Opcode::Update || !update.header.flags.query {
+            return None;
+        }
+        let mut signer = None;
+        let response_code = match self.apply(&update, message, peer, &mut signer) {
+            Ok(()) => ResponseCode::NoError,
+            Err(response_code) => response_code,
+        };
+
+        let response = Packet {
+            header: Header {
+                request_id: update.header.request_id,
+                flags: Flags {
+                    query: false,
+                    opcode: Opcode::Update,
+                    response_code,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: update.questions,
+            ..Packet::default()
+        }
+        .to_bytes();
+        Some(match &mut signer {
+            Some(signer) => signer.sign(&response),
+            None => response,
+        })
+    }
+
+    fn apply(
+        &self,
+        update: &Packet,
+        message: &[u8],
+        peer: IpAddr,
+        signer: &mut Option<TsigSigner>,
+    ) -> Result<(), ResponseCode> {
+        let [zone] = &update.questions[..] else {
+            return Err(ResponseCode::FormErr);
+        };
+        if zone.r#type != RecordType::SOA {
+            return Err(ResponseCode::FormErr);
+        }
+        self.get(&zone.domain_name).ok_or(ResponseCode::NotAuth)?;
+        *signer = self.update_acl.check(message, peer)?;
+
+        let _updating = self.updating.lock().unwrap();
+        let current = self.get(&zone.domain_name).ok_or(ResponseCode::NotAuth)?;
+        if let Some(updated) = apply_update(&current, update)? {
+            self.insert(updated);
+        }
+        Ok(())
+    }
+
+    /// Number of zones
+    pub fn len(&self) -> usize {
+        self.zones.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn transfer_response(query: &Packet, response_code: ResponseCode, answers: Vec<Answer>) -> Packet {
+    Packet {
+        header: Header {
+            request_id: query.header.request_id,
+            flags: Flags {
+                query: false,
+                opcode: query.header.flags.opcode,
+                authoritative_answer: response_code == ResponseCode::NoError,
+                response_code,
+                ..Flags::default()
+            },
+            ..Header::default()
+        },
+        questions: query.questions.clone(),
+        answers,
+        authorities: vec![],
+        additionals: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{
+        dnssec::{Denial, SigningPolicy, ZoneSigner, DS, NSEC, NSEC3, RRSIG},
+        parse::parser::DnsParser,
+        protocol::{
+            answer::Answer, name::DnsName, query::QueryBuilder, record_type::RecordType,
+            response_code::ResponseCode,
+        },
+        tsig::{TsigKey, TsigSigner},
+        zonefile::Zone,
+    };
+
+    use super::{Acl, ZoneAnswer, ZoneSet};
+
+    fn zones() -> ZoneSet {
+        let zone = Zone::parse(
+            "$TTL 3600
+@           SOA   ns hostmaster 1 7200 3600 1209600 300
+            NS    ns
+ns          A     192.0.2.53
+www         A     192.0.2.80
+alias       CNAME www
+external    CNAME www.example.com.
+a.b         TXT   \"below an empty non-terminal\"
+child       NS    ns.child
+ns.child    A     192.0.2.54
+",
+            &"example.lan".parse().unwrap(),
+        )
+        .unwrap();
+        ZoneSet::new([zone])
+    }
+
+    fn lookup(zones: &ZoneSet, name: &str, record_type: RecordType) -> ZoneAnswer {
+        zones.lookup(&name.parse().unwrap(), record_type).unwrap()
+    }
+
+    fn lines(records: &[Answer]) -> Vec<String> {
+        records.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_zone_lookup() {
+        let zones = zones();
+
+        let answer = lookup(&zones, "alias.example.lan", RecordType::A);
+        assert!(answer.authoritative);
+        assert_eq!(
+            lines(&answer.answers),
+            [
+                "alias.example.lan. 3600 IN CNAME www.example.lan.",
+                "www.example.lan. 3600 IN A 192.0.2.80"
+            ]
+        );
+        let answer = lookup(&zones, "external.example.lan", RecordType::A);
+        assert_eq!(answer.answers.len(), 1);
+
+        let answer = lookup(&zones, "missing.example.lan", RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NXDomain);
+        assert!(answer.answers.is_empty());
+        assert_eq!(
+            lines(&answer.authorities),
+            ["example.lan. 300 IN SOA ns.example.lan. hostmaster.example.lan. 1 7200 3600 1209600 300"]
+        );
+
+        for (name, record_type) in [
+            ("www.example.lan", RecordType::MX),
+            ("b.example.lan", RecordType::A),
+        ] {
+            let answer = lookup(&zones, name, record_type);
+            assert_eq!(answer.response_code, ResponseCode::NoError);
+            assert!(answer.answers.is_empty());
+            assert_eq!(answer.authorities.len(), 1);
+        }
+
+        let answer = lookup(&zones, "www.child.example.lan", RecordType::A);
+        assert!(!answer.authoritative);
+        assert_eq!(
+            lines(&answer.authorities),
+            ["child.example.lan. 3600 IN NS ns.child.example.lan."]
+        );
+        assert_eq!(
+            lines(&answer.additionals),
+            ["ns.child.example.lan. 3600 IN A 192.0.2.54"]
+        );
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let zone = Zone::parse(
+            "$TTL 60
+*.devices   A     192.0.2.100
+*.devices   TXT   \"any device\"
+tv.devices  A     192.0.2.101
+a.sub.devices A   192.0.2.102
+*.alias     CNAME tv.devices
+",
+            &"example.lan".parse().unwrap(),
+        )
+        .unwrap();
+        let zones = ZoneSet::new([zone]);
+
+        for (name, expected) in [
+            (
+                "phone.devices.example.lan",
+                "phone.devices.example.lan. 60 IN A 192.0.2.100",
+            ),
+            (
+                "a.b.devices.example.lan",
+                "a.b.devices.example.lan. 60 IN A 192.0.2.100",
+            ),
+            (
+                "tv.devices.example.lan",
+                "tv.devices.example.lan. 60 IN A 192.0.2.101",
+            ),
+            (
+                "*.devices.example.lan",
+                "*.devices.example.lan. 60 IN A 192.0.2.100",
+            ),
+        ] {
+            assert_eq!(
+                lines(&lookup(&zones, name, RecordType::A).answers),
+                [expected]
+            );
+        }
+
+        // the wildcard does not apply below names that exist
+        let answer = lookup(&zones, "b.sub.devices.example.lan", RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NXDomain);
+        // nor to existing names, even empty non-terminals
+        let answer = lookup(&zones, "tv.devices.example.lan", RecordType::TXT);
+        assert!(answer.answers.is_empty());
+        let answer = lookup(&zones, "sub.devices.example.lan", RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert!(answer.answers.is_empty());
+        // the wildcard name exists without records of the type
+        let answer = lookup(&zones, "phone.devices.example.lan", RecordType::MX);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert!(answer.answers.is_empty());
+
+        let answer = lookup(&zones, "x.alias.example.lan", RecordType::A);
+        assert_eq!(
+            lines(&answer.answers),
+            [
+                "x.alias.example.lan. 60 IN CNAME tv.devices.example.lan.",
+                "tv.devices.example.lan. 60 IN A 192.0.2.101"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zone_set_respond() {
+        let zones = zones();
+        let query = |name: &str| {
+            QueryBuilder::new(name.parse::<DnsName>().unwrap())
+                .id(7)
+                .build()
+        };
+        assert!(zones.respond(&query("example.com")).is_none());
+
+        let response = zones.respond(&query("missing.example.lan")).unwrap();
+        let response = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(response.header.request_id, 7);
+        assert!(response.header.flags.authoritative_answer);
+        assert_eq!(response.header.rcode(), ResponseCode::NXDomain);
+        assert_eq!(response.authorities.len(), 1);
+
+        // the closest zone answers
+        let sub = Zone::parse("sub 60 IN A 192.0.2.99", &"example.lan".parse().unwrap()).unwrap();
+        zones.insert(Zone {
+            origin: "sub.example.lan".parse().unwrap(),
+            ..sub
+        });
+        assert_eq!(zones.len(), 2);
+        let answer = lookup(&zones, "sub.example.lan", RecordType::A);
+        assert_eq!(
+            lines(&answer.answers),
+            ["sub.example.lan. 60 IN A 192.0.2.99"]
+        );
+    }
+
+    #[test]
+    fn test_zone_transfer() {
+        let mut contents = String::from("@ 60 SOA ns hostmaster 1 7200 3600 1209600 300\n");
+        for i in 0..1000 {
+            contents.push_str(&format!("host{i} 60 A 192.0.2.1\n"));
+        }
+        let zone = Zone::parse(&contents, &"example.lan".parse().unwrap()).unwrap();
+        let key: TsigKey = "transfer.key:c2VjcmV0".parse().unwrap();
+        let zones = ZoneSet::new([zone]).transfer_acl(Acl {
+            addresses: vec!["192.0.2.53".parse().unwrap()],
+            keys: vec![key.clone()],
+        });
+        let axfr = |name: &str| {
+            QueryBuilder::new(name.parse::<DnsName>().unwrap())
+                .id(9)
+                .record_type(RecordType::AXFR)
+                .build()
+        };
+        let secondary = "192.0.2.53".parse().unwrap();
+        let stranger = "198.51.100.1".parse().unwrap();
+
+        assert!(zones
+            .respond_transfer(&axfr("sub.example.lan"), secondary)
+            .is_none());
+        assert!(zones
+            .respond_transfer(
+                &QueryBuilder::new("example.lan".parse().unwrap()).build(),
+                secondary
+            )
+            .is_none());
+
+        let [refused] = &zones
+            .respond_transfer(&axfr("example.lan"), stranger)
+            .unwrap()[..]
+        else {
+            panic!("expected a single response");
+        };
+        let refused = DnsParser::new(refused).parse_packet().unwrap();
+        assert_eq!(refused.header.rcode(), ResponseCode::Refused);
+        assert!(refused.answers.is_empty());
+
+        let messages = zones
+            .respond_transfer(&axfr("example.lan"), secondary)
+            .unwrap();
+        assert!(messages.len() > 1);
+        let messages: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                assert!(message.len() <= u16::MAX as usize);
+                DnsParser::new(message).parse_packet().unwrap()
+            })
+            .collect();
+        assert!(messages
+            .iter()
+            .all(|message| message.header.request_id == 9
+                && message.header.flags.authoritative_answer));
+        assert_eq!(messages[0].questions.len(), 1);
+        assert!(messages[1..]
+            .iter()
+            .all(|message| message.questions.is_empty()));
+        let records: Vec<_> = messages
+            .iter()
+            .flat_map(|message| &message.answers)
+            .collect();
+        assert_eq!(records.len(), 1002);
+        assert_eq!(records[0], records[1001]);
+        assert!(matches!(records[0], Answer::SOA { .. }));
+
+        // signed requests may transfer from anywhere, and every response is signed
+        let signed = TsigSigner::query(key).sign(&axfr("example.lan"));
+        let messages = zones.respond_transfer(&signed, stranger).unwrap();
+        assert!(messages.len() > 1);
+        for message in &messages {
+            let message = DnsParser::new(message).parse_packet().unwrap();
+            assert_eq!(message.header.rcode(), ResponseCode::NoError);
+            assert_eq!(message.additionals.len(), 1);
+        }
+        let unknown_key =
+            TsigSigner::query("other.key:c2VjcmV0".parse().unwrap()).sign(&axfr("example.lan"));
+        let rejected = &zones.respond_transfer(&unknown_key, secondary).unwrap()[0];
+        assert_eq!(
+            DnsParser::new(rejected)
+                .parse_packet()
+                .unwrap()
+                .header
+                .rcode(),
+            ResponseCode::NotAuth
+        );
+    }
+
+    fn signed(denial: Denial, now: SystemTime) -> ZoneSet {
+        let mut zone = zones()
+            .get(&"example.lan".parse().unwrap())
+            .unwrap()
+            .as_ref()
+            .clone();
+        let extra = Zone::parse(
+            "*.apps 60 A 192.0.2.81\nsecure 60 NS ns.child\nsecure 60 TYPE43 \\# 4 00010d02",
+            &zone.origin,
+        )
+        .unwrap();
+        zone.records.extend(extra.records);
+        let policy = SigningPolicy {
+            denial,
+            ..SigningPolicy::default()
+        };
+        let signer = ZoneSigner::new(zone.origin.clone(), policy, vec![]);
+        signer.roll_keys(now).unwrap();
+        ZoneSet::new([zone]).signer(signer)
+    }
+
+    fn types(records: &[Answer]) -> Vec<u16> {
+        records
+            .iter()
+            .map(|record| record.meta().r#type.into())
+            .collect()
+    }
+
+    fn owner(records: &[Answer], record_type: u16) -> Vec<&str> {
+        records
+            .iter()
+            .filter(|record| u16::from(record.meta().r#type) == record_type)
+            .map(|record| record.meta().name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_dnssec_answers() {
+        let now = SystemTime::now();
+        let zones = signed(Denial::Nsec, now);
+        let zone = zones.find(&"example.lan".parse().unwrap()).unwrap();
+        let lookup =
+            |name: &str, record_type| zone.lookup_dnssec(&name.parse().unwrap(), record_type);
+
+        let answer = lookup("www.example.lan", RecordType::A);
+        assert_eq!(types(&answer.answers), [1, RRSIG]);
+        assert_eq!(
+            zone.lookup(&"www.example.lan".parse().unwrap(), RecordType::A)
+                .answers
+                .len(),
+            1
+        );
+
+        // the name is covered by the NSEC record of external, and the wildcard by the apex
+        let answer = lookup("missing.example.lan", RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NXDomain);
+        assert_eq!(
+            types(&answer.authorities),
+            [6, RRSIG, NSEC, RRSIG, NSEC, RRSIG]
+        );
+        assert_eq!(
+            owner(&answer.authorities, NSEC),
+            ["external.example.lan", "example.lan"]
+        );
+        let answer = lookup("www.example.lan", RecordType::TXT);
+        assert_eq!(owner(&answer.authorities, NSEC), ["www.example.lan"]);
+        let answer = lookup("b.example.lan", RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert_eq!(owner(&answer.authorities, NSEC), ["*.apps.example.lan"]);
+
+        // wildcard answers come with their signature and the proof that the name does not exist
+        let answer = lookup("x.apps.example.lan", RecordType::A);
+        assert_eq!(owner(&answer.answers, RRSIG), ["x.apps.example.lan"]);
+        assert_eq!(owner(&answer.authorities, NSEC), ["*.apps.example.lan"]);
+
+        // delegations are proven to be insecure, or come with their DS records
+        let answer = lookup("host.child.example.lan", RecordType::A);
+        assert_eq!(types(&answer.authorities), [2, NSEC, RRSIG]);
+        let answer = lookup("host.secure.example.lan", RecordType::A);
+        assert_eq!(types(&answer.authorities), [2, DS, RRSIG]);
+
+        let query = |dnssec_ok| {
+            let query = QueryBuilder::new("www.example.lan".parse().unwrap());
+            match dnssec_ok {
+                Some(dnssec_ok) => query.dnssec_ok(dnssec_ok).build(),
+                None => query.build(),
+            }
+        };
+        let respond = |query: Vec<u8>| {
+            let response = zones.respond(&query).unwrap();
+            let opt = DnsParser::new(&response).parse_opt().unwrap();
+            let response = DnsParser::new(&response).parse_packet().unwrap();
+            (response.answers.len(), opt.map(|opt| opt.dnssec_ok))
+        };
+        assert_eq!(respond(query(Some(true))), (2, Some(true)));
+        assert_eq!(respond(query(Some(false))), (1, Some(false)));
+        assert_eq!(respond(query(None)), (1, None));
+
+        // signatures are renewed with a new serial
+        assert!(zones.resign(now + Duration::from_secs(60)).is_empty());
+        assert_eq!(
+            zones.resign(now + Duration::from_secs(4 * 24 * 60 * 60)),
+            ["example.lan"]
+        );
+        assert_eq!(zones.get(&zone.origin).unwrap().serial(), Some(2));
+    }
+
+    #[test]
+    fn test_dnssec_answers_nsec3() {
+        let zones = signed(
+            Denial::Nsec3 {
+                iterations: 0,
+                salt: vec![],
+            },
+            SystemTime::now(),
+        );
+        let zone = zones.find(&"example.lan".parse().unwrap()).unwrap();
+        let lookup = |name: &DnsName, record_type| zone.lookup_dnssec(name, record_type);
+
+        let answer = lookup(&"missing.example.lan".parse().unwrap(), RecordType::A);
+        let chain = owner(&answer.authorities, NSEC3);
+        // the closest encloser, and the names covering the next closer name and the wildcard,
+        // unless the same record covers both
+        assert!((2..=3).contains(&chain.len()));
+        assert_eq!(owner(&answer.authorities, RRSIG).len(), chain.len() + 1);
+
+        // the hashed names of the chain do not exist
+        let hashed: DnsName = chain[0].parse().unwrap();
+        let answer = lookup(&hashed, RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NXDomain);
+        let answer = lookup(&"b.example.lan".parse().unwrap(), RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NoError);
+        assert_eq!(owner(&answer.authorities, NSEC3).len(), 1);
+    }
+}
@@ -0,0 +1,919 @@
+//! In-memory cache of answers, which are served with their remaining TTL until they expire.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{BuildHasher, RandomState},
+    io::{self, ErrorKind},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    authority::opt_record,
+    parse::parser::DnsParser,
+    protocol::{
+        answer::Answer,
+        class::Class,
+        header::{Flags, Header},
+        name::DnsName,
+        packet::Packet,
+        question::Question,
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    tcp::{read_tcp_message, write_tcp_message},
+};
+
+/// Identifies the answers to a question, names are compared case-insensitively
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: DnsName,
+    pub r#type: RecordType,
+    pub class: Class,
+}
+
+impl From<&Question> for CacheKey {
+    fn from(question: &Question) -> Self {
+        Self {
+            name: question.domain_name.clone(),
+            r#type: question.r#type,
+            class: question.class,
+        }
+    }
+}
+
+/// Default of [`DnsCache::max_entries`]
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Default of [`DnsCache::max_bytes`]
+pub const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Most shards of a cache, see [`DnsCache`]
+pub const MAX_SHARDS: usize = 16;
+
+/// Fewest entries and bytes a shard is given, so small caches are not split into shards that
+/// evict far earlier than the cache as a whole would
+const MIN_SHARD_ENTRIES: usize = 64;
+const MIN_SHARD_BYTES: usize = 64 * 1024;
+
+/// TTL of stale answers, so clients ask again soon after the upstream servers are back
+/// https://datatracker.ietf.org/doc/html/rfc8767#section-4
+pub const STALE_ANSWER_TTL: usize = 30;
+
+/// When popular entries are refreshed, see [`DnsCache::prefetch`]
+#[derive(Debug, Clone, Copy)]
+struct Prefetch {
+    min_hits: u32,
+    percent: u8,
+}
+
+/// Start of files written by [`DnsCache::save`], followed by the format version
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DNSCACHE";
+const SNAPSHOT_VERSION: u8 = 1;
+
+#[derive(Debug)]
+struct Entry {
+    answers: Vec<Answer>,
+    stored: Instant,
+    /// When the answer with the lowest TTL expires
+    expires: Instant,
+    /// Position in [`Entries::lru`], from the clock shared by all shards
+    last_used: u64,
+    /// Estimated memory used by this entry
+    size: usize,
+    /// How often the entry was used since it was stored
+    hits: u32,
+    /// Whether [`DnsCache::take_prefetch`] already returned this entry
+    prefetching: bool,
+}
+
+impl Entry {
+    /// Returns the answers with their TTLs reduced by the time they spent in the cache
+    fn answers_at(&self, now: Instant) -> Vec<Answer> {
+        let elapsed = now.duration_since(self.stored).as_secs() as usize;
+        self.answers
+            .iter()
+            .cloned()
+            .map(|mut answer| {
+                let meta = answer.meta_mut();
+                meta.ttl = meta.ttl.saturating_sub(elapsed);
+                answer
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<CacheKey, Entry>,
+    /// Keys of all entries, from the least to the most recently used
+    lru: BTreeMap<u64, CacheKey>,
+    /// Sum of the sizes of all entries
+    bytes: usize,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// Marks the entry for `key` as used at `clock`, the most recently used one, and returns it
+    fn touch(&mut self, key: &CacheKey, clock: u64) -> Option<&Entry> {
+        let entry = self.entries.get_mut(key)?;
+        entry.hits = entry.hits.saturating_add(1);
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(clock, key.clone());
+        entry.last_used = clock;
+        Some(entry)
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some((_, key)) = self.lru.pop_first() {
+            let entry = self.entries.remove(&key).expect("LRU keys have entries");
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// A part of the cache with a lock of its own, holding the entries whose keys hash to it
+#[derive(Debug, Default)]
+struct Shard {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Shard {
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap()
+    }
+
+    /// Counts a lookup that returned `answers`
+    fn count<T>(&self, answers: Option<T>) -> Option<T> {
+        let counter = match answers {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        answers
+    }
+}
+
+/// Usage of a shard of the cache, see [`DnsCache::shard_stats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShardStats {
+    pub entries: usize,
+    pub bytes: usize,
+    /// Lookups that returned answers, stale ones included
+    pub hits: u64,
+    /// Lookups that returned none
+    pub misses: u64,
+}
+
+/// Answers of previous queries, shared by all resolvers it is passed to through
+/// [`crate::resolver::ResolveOptions::cache`].
+///
+/// The cache is bounded by the number of entries as well as the memory they use. Once any limit
+/// is reached, the least recently used entries are evicted, so a flood of queries for random
+/// names can only displace entries but not grow the cache.
+///
+/// Entries are spread over up to [`MAX_SHARDS`] shards by the hash of their key, so concurrent
+/// lookups rarely wait for the same lock. Each shard gets an even part of the limits and evicts
+/// on its own, small caches are not sharded at all.
+///
+/// Expired entries can be kept for a while to answer queries with stale records while no upstream
+/// server is reachable, see [`DnsCache::serve_stale`].
+#[derive(Debug)]
+pub struct DnsCache {
+    shards: Vec<Shard>,
+    hasher: RandomState,
+    /// Incremented whenever an entry is used, shared by the shards so their LRU positions can be
+    /// compared
+    clock: AtomicU64,
+    max_entries: usize,
+    max_bytes: usize,
+    max_stale: Duration,
+    prefetch: Option<Prefetch>,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self {
+            shards: vec![],
+            hasher: RandomState::new(),
+            clock: AtomicU64::new(0),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_stale: Duration::ZERO,
+            prefetch: None,
+        }
+        .with_shards()
+    }
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of cached questions
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self.with_shards()
+    }
+
+    /// Sets the maximum memory the cached answers may use, as estimated from their size on the
+    /// wire
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self.with_shards()
+    }
+
+    /// Splits the cache into as many shards as its limits allow
+    fn with_shards(mut self) -> Self {
+        let shards = (self.max_entries / MIN_SHARD_ENTRIES)
+            .min(self.max_bytes / MIN_SHARD_BYTES)
+            .clamp(1, MAX_SHARDS);
+        self.shards = (0..shards).map(|_| Shard::default()).collect();
+        self
+    }
+
+    fn shard(&self, key: &CacheKey) -> &Shard {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Limits of each shard
+    fn shard_max_entries(&self) -> usize {
+        self.max_entries / self.shards.len()
+    }
+
+    fn shard_max_bytes(&self) -> usize {
+        self.max_bytes / self.shards.len()
+    }
+
+    /// Keeps entries for up to `max_stale` after they expired, during which
+    /// [`DnsCache::get_stale`] still returns them. Disabled by default.
+    /// https://datatracker.ietf.org/doc/html/rfc8767
+    pub fn serve_stale(mut self, max_stale: Duration) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    /// Marks entries that were used at least `min_hits` times for refreshing once they are within
+    /// `percent` of their TTL from expiring, see [`DnsCache::take_prefetch`]. Disabled by default.
+    pub fn prefetch(mut self, min_hits: u32, percent: u8) -> Self {
+        self.prefetch = Some(Prefetch {
+            min_hits,
+            percent: percent.min(100),
+        });
+        self
+    }
+
+    /// Returns the cached answers for `key`, with their TTLs reduced by the time they spent in
+    /// the cache
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<Answer>> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &CacheKey, now: Instant) -> Option<Vec<Answer>> {
+        let shard = self.shard(key);
+        shard.count(self.lookup(shard, key, now))
+    }
+
+    fn lookup(&self, shard: &Shard, key: &CacheKey, now: Instant) -> Option<Vec<Answer>> {
+        let mut entries = shard.lock();
+        let expires = entries.entries.get(key)?.expires;
+        if expires <= now {
+            if expires + self.max_stale <= now {
+                entries.remove(key);
+            }
+            return None;
+        }
+
+        entries
+            .touch(key, self.tick())
+            .map(|entry| entry.answers_at(now))
+    }
+
+    /// Returns the cached answers for `key` even if they expired, as long as they are not older
+    /// than allowed by [`DnsCache::serve_stale`]. Their TTLs are clamped to [`STALE_ANSWER_TTL`].
+    /// Meant to be used once resolving `key` failed, as stale answers beat no answers.
+    pub fn get_stale(&self, key: &CacheKey) -> Option<Vec<Answer>> {
+        self.get_stale_at(key, Instant::now())
+    }
+
+    fn get_stale_at(&self, key: &CacheKey, now: Instant) -> Option<Vec<Answer>> {
+        let shard = self.shard(key);
+        shard.count(self.lookup_stale(shard, key, now))
+    }
+
+    fn lookup_stale(&self, shard: &Shard, key: &CacheKey, now: Instant) -> Option<Vec<Answer>> {
+        let mut entries = shard.lock();
+        if entries.entries.get(key)?.expires + self.max_stale <= now {
+            entries.remove(key);
+            return None;
+        }
+
+        let answers = entries.touch(key, self.tick())?.answers_at(now);
+        let answers = answers
+            .into_iter()
+            .map(|mut answer| {
+                let meta = answer.meta_mut();
+                meta.ttl = match meta.ttl {
+                    0 => STALE_ANSWER_TTL,
+                    ttl => ttl.min(STALE_ANSWER_TTL),
+                };
+                answer
+            })
+            .collect();
+        Some(answers)
+    }
+
+    /// Like [`DnsCache::get_at`], but without marking the entry as used
+    fn peek_at(&self, key: &CacheKey, now: Instant) -> Option<Vec<Answer>> {
+        let entries = self.shard(key).lock();
+        let entry = entries.entries.get(key)?;
+        (entry.expires > now).then(|| entry.answers_at(now))
+    }
+
+    /// Caches `answers` for `key` until the first of them expires. Nothing is cached if there
+    /// are no answers or one of them must not be cached, ie. has a TTL of 0.
+    pub fn insert(&self, key: CacheKey, answers: Vec<Answer>) {
+        self.insert_at(key, answers, Instant::now());
+    }
+
+    fn insert_at(&self, key: CacheKey, answers: Vec<Answer>, now: Instant) {
+        let Some(ttl) = answers.iter().map(|answer| answer.meta().ttl).min() else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let (max_entries, max_bytes) = (self.shard_max_entries(), self.shard_max_bytes());
+        let size = entry_size(&key, &answers);
+        if size > max_bytes || max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.shard(&key).lock();
+        entries.remove(&key);
+        while entries.entries.len() >= max_entries || entries.bytes + size > max_bytes {
+            entries.evict_least_recently_used();
+        }
+
+        let last_used = self.tick();
+        entries.lru.insert(last_used, key.clone());
+        entries.bytes += size;
+        let entry = Entry {
+            answers,
+            stored: now,
+            expires: now + Duration::from_secs(ttl as u64),
+            last_used,
+            size,
+            hits: 0,
+            prefetching: false,
+        };
+        entries.entries.insert(key, entry);
+    }
+
+    /// Whether the entry answering the raw `query` is popular and about to expire, as configured
+    /// by [`DnsCache::prefetch`], so it should be resolved again before it does. Returns `true`
+    /// only once per entry, until it is replaced.
+    pub fn take_prefetch(&self, query: &[u8]) -> bool {
+        let Ok(query) = DnsParser::new(query).parse_packet() else {
+            return false;
+        };
+        let [question] = &query.questions[..] else {
+            return false;
+        };
+        self.take_prefetch_at(&question.into(), Instant::now())
+    }
+
+    fn take_prefetch_at(&self, key: &CacheKey, now: Instant) -> bool {
+        let Some(prefetch) = self.prefetch else {
+            return false;
+        };
+        let mut entries = self.shard(key).lock();
+        let Some(entry) = entries.entries.get_mut(key) else {
+            return false;
+        };
+        if entry.prefetching || entry.hits < prefetch.min_hits || entry.expires <= now {
+            return false;
+        }
+
+        let ttl = entry.expires.duration_since(entry.stored);
+        let remaining = entry.expires.duration_since(now);
+        entry.prefetching = remaining <= ttl.mul_f64(f64::from(prefetch.percent) / 100.0);
+        entry.prefetching
+    }
+
+    /// Builds a response to the raw `query` from the cache, if the answers to its question are
+    /// cached
+    pub fn respond(&self, query: &[u8]) -> Option<Vec<u8>> {
+        respond_with(query, |key| self.get(key))
+    }
+
+    /// Like [`DnsCache::respond`], but with stale answers, see [`DnsCache::get_stale`]
+    pub fn respond_stale(&self, query: &[u8]) -> Option<Vec<u8>> {
+        respond_with(query, |key| self.get_stale(key))
+    }
+
+    /// Caches the answers of the raw `response`, if it is a complete and successful one
+    pub fn store_response(&self, response: &[u8]) {
+        let Ok(response) = DnsParser::new(response).parse_packet() else {
+            return;
+        };
+        let flags = &response.header.flags;
+        if flags.query || flags.truncation || response.header.rcode() != ResponseCode::NoError {
+            return;
+        }
+        if let [question] = &response.questions[..] {
+            self.insert(question.into(), response.answers);
+        }
+    }
+
+    /// Writes all entries to `path`, so they can be restored with [`DnsCache::load`], eg. after a
+    /// restart.
+    ///
+    /// The snapshot starts with a magic number, the format version and the wall clock time it
+    /// was taken at, as seconds since the UNIX epoch. Each entry follows as a length-prefixed DNS
+    /// response holding its question and its answers with their remaining TTLs, from the least to
+    /// the most recently used entry.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.push(SNAPSHOT_VERSION);
+        snapshot.extend_from_slice(&unix_time().to_be_bytes());
+
+        let now = Instant::now();
+        let mut keys: Vec<_> = (self.shards.iter())
+            .flat_map(|shard| {
+                let entries = shard.lock();
+                let keys = entries.lru.iter().map(|(&used, key)| (used, key.clone()));
+                keys.collect::<Vec<_>>()
+            })
+            .collect();
+        keys.sort_unstable_by_key(|&(used, _)| used);
+        for (_, key) in keys {
+            let Some(answers) = self.peek_at(&key, now) else {
+                continue;
+            };
+            let response = Packet {
+                header: Header {
+                    flags: Flags {
+                        query: false,
+                        ..Flags::default()
+                    },
+                    ..Header::default()
+                },
+                questions: vec![Question {
+                    domain_name: key.name,
+                    r#type: key.r#type,
+                    class: key.class,
+                }],
+                answers,
+                ..Packet::default()
+            };
+            write_tcp_message(&mut snapshot, &response.to_bytes())?;
+        }
+
+        // replace the previous snapshot only once the new one is complete
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        std::fs::write(&partial, snapshot)?;
+        std::fs::rename(&partial, path)
+    }
+
+    /// Adds the entries saved to `path` by [`DnsCache::save`], with their TTLs reduced by the
+    /// wall clock time passed since. Returns how many entries have not expired yet.
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let snapshot = std::fs::read(path)?;
+        let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+
+        let Some(rest) = snapshot.strip_prefix(SNAPSHOT_MAGIC) else {
+            return Err(invalid("not a DNS cache snapshot"));
+        };
+        let Some((&version, rest)) = rest.split_first() else {
+            return Err(invalid("not a DNS cache snapshot"));
+        };
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(&format!(
+                "unsupported DNS cache snapshot version {version}"
+            )));
+        }
+        let (saved, mut entries) = rest
+            .split_first_chunk::<8>()
+            .ok_or_else(|| invalid("truncated DNS cache snapshot"))?;
+        let elapsed = unix_time().saturating_sub(u64::from_be_bytes(*saved)) as usize;
+
+        let mut loaded = 0;
+        while !entries.is_empty() {
+            let response = read_tcp_message(&mut entries)?;
+            let response = DnsParser::new(&response)
+                .parse_packet()
+                .map_err(|e| invalid(&format!("invalid entry in DNS cache snapshot: {e}")))?;
+            let [question] = &response.questions[..] else {
+                return Err(invalid("invalid entry in DNS cache snapshot"));
+            };
+
+            let answers: Vec<_> = response
+                .answers
+                .into_iter()
+                .map(|mut answer| {
+                    let meta = answer.meta_mut();
+                    meta.ttl = meta.ttl.saturating_sub(elapsed);
+                    answer
+                })
+                .collect();
+            if answers.iter().all(|answer| answer.meta().ttl > 0) {
+                self.insert(question.into(), answers);
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Removes all entries that expired, and are too old to be served stale, returning how many
+    /// there were
+    pub fn evict_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut evicted = 0;
+        for shard in &self.shards {
+            let mut entries = shard.lock();
+            let expired: Vec<_> = entries
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.expires + self.max_stale <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &expired {
+                entries.remove(key);
+            }
+            evicted += expired.len();
+        }
+        evicted
+    }
+
+    /// Removes all entries, returning how many there were. The lookups counted so far are kept.
+    pub fn clear(&self) -> usize {
+        let mut cleared = 0;
+        for shard in &self.shards {
+            let mut entries = shard.lock();
+            cleared += entries.entries.len();
+            *entries = Entries::default();
+        }
+        cleared
+    }
+
+    pub fn len(&self) -> usize {
+        self.shard_stats().iter().map(|stats| stats.entries).sum()
+    }
+
+    /// Estimated memory used by all entries, see [`DnsCache::max_bytes`]
+    pub fn bytes(&self) -> usize {
+        self.shard_stats().iter().map(|stats| stats.bytes).sum()
+    }
+
+    /// Usage of each shard, with the lookups counted since the cache was created
+    pub fn shard_stats(&self) -> Vec<ShardStats> {
+        (self.shards.iter())
+            .map(|shard| {
+                let entries = shard.lock();
+                ShardStats {
+                    entries: entries.entries.len(),
+                    bytes: entries.bytes,
+                    hits: shard.hits.load(Ordering::Relaxed),
+                    misses: shard.misses.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Builds a successful response to the raw `query` with the answers `get` returns for its
+/// question, if any. Queries with an OPT record get one back.
+pub(crate) fn respond_with(
+    query: &[u8],
+    get: impl FnOnce(&CacheKey) -> Option<Vec<Answer>>,
+) -> Option<Vec<u8>> {
+    let opt = DnsParser::new(query).parse_opt().ok().flatten();
+    let query = DnsParser::new(query).parse_packet().ok()?;
+    let [question] = &query.questions[..] else {
+        return None;
+    };
+    let answers = get(&question.into())?;
+    // https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.1
+    let additionals = (opt.iter()).map(|opt| opt_record(opt.dnssec_ok)).collect();
+
+    let response = Packet {
+        header: Header {
+            request_id: query.header.request_id,
+            flags: Flags {
+                query: false,
+                opcode: query.header.flags.opcode,
+                recursion_desired: query.header.flags.recursion_desired,
+                recursion_available: true,
+                checking_disabled: query.header.flags.checking_disabled,
+                response_code: ResponseCode::NoError,
+                ..Flags::default()
+            },
+            ..Header::default()
+        },
+        questions: query.questions,
+        answers,
+        additionals,
+        ..Packet::default()
+    };
+    Some(response.to_bytes())
+}
+
+/// Seconds since the UNIX epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Estimates the memory used by an entry from the size of its answers on the wire, plus some
+/// overhead for the bookkeeping
+fn entry_size(key: &CacheKey, answers: &[Answer]) -> usize {
+    let mut wire = Vec::new();
+    for answer in answers {
+        answer.to_bytes(&mut wire);
+    }
+    std::mem::size_of::<Entry>() + 2 * key.name.as_str().len() + wire.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            query::QueryBuilder,
+            record_type::RecordType,
+        },
+    };
+
+    use super::{CacheKey, DnsCache, MAX_SHARDS};
+
+    fn a(name: &str, ttl: usize) -> Answer {
+        Answer::A {
+            meta: AnswerMeta {
+                name: name.parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+                ttl,
+                len: 4,
+            },
+            ipv4: Ipv4Addr::new(192, 0, 2, 1),
+        }
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            name: name.parse().unwrap(),
+            r#type: RecordType::A,
+            class: Class::IN,
+        }
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let cache = DnsCache::new();
+        let now = Instant::now();
+        cache.insert_at(
+            key("example.com"),
+            vec![a("example.com", 300), a("example.com", 60)],
+            now,
+        );
+        cache.insert_at(key("uncacheable.com"), vec![a("uncacheable.com", 0)], now);
+        assert_eq!(cache.len(), 1);
+
+        let answers = cache
+            .get_at(&key("EXAMPLE.com"), now + Duration::from_secs(10))
+            .unwrap();
+        let ttls: Vec<_> = answers.iter().map(|answer| answer.meta().ttl).collect();
+        assert_eq!(ttls, [290, 50]);
+
+        assert!(cache
+            .get_at(&key("example.com"), now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_serve_stale() {
+        let cache = DnsCache::new().serve_stale(Duration::from_secs(3600));
+        let now = Instant::now();
+        cache.insert_at(
+            key("example.com"),
+            vec![a("example.com", 300), a("example.com", 60)],
+            now,
+        );
+
+        let stale = now + Duration::from_secs(120);
+        assert!(cache.get_at(&key("example.com"), stale).is_none());
+        let answers = cache.get_stale_at(&key("example.com"), stale).unwrap();
+        let ttls: Vec<_> = answers.iter().map(|answer| answer.meta().ttl).collect();
+        assert_eq!(ttls, [30, 30]);
+
+        let too_stale = now + Duration::from_secs(60 + 3600);
+        assert!(cache.get_stale_at(&key("example.com"), too_stale).is_none());
+        assert!(cache.is_empty());
+
+        // without serve-stale expired entries are gone right away
+        let cache = DnsCache::new();
+        cache.insert_at(key("example.com"), vec![a("example.com", 60)], now);
+        assert!(cache.get_stale_at(&key("example.com"), stale).is_none());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let cache = DnsCache::new().prefetch(2, 10);
+        let now = Instant::now();
+        cache.insert_at(key("example.com"), vec![a("example.com", 100)], now);
+
+        let almost_expired = now + Duration::from_secs(95);
+        cache.get_at(&key("example.com"), now);
+        assert!(!cache.take_prefetch_at(&key("example.com"), almost_expired));
+
+        cache.get_at(&key("example.com"), now);
+        let fresh = now + Duration::from_secs(50);
+        assert!(!cache.take_prefetch_at(&key("example.com"), fresh));
+        assert!(cache.take_prefetch_at(&key("example.com"), almost_expired));
+        // only one refresh is started
+        assert!(!cache.take_prefetch_at(&key("example.com"), almost_expired));
+
+        cache.insert_at(
+            key("example.com"),
+            vec![a("example.com", 100)],
+            almost_expired,
+        );
+        assert!(!cache.take_prefetch_at(&key("example.com"), almost_expired));
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = DnsCache::new().max_entries(2);
+        let now = Instant::now();
+        cache.insert_at(key("a.com"), vec![a("a.com", 60)], now);
+        cache.insert_at(key("b.com"), vec![a("b.com", 60)], now);
+        assert!(cache.get_at(&key("a.com"), now).is_some());
+
+        cache.insert_at(key("c.com"), vec![a("c.com", 60)], now);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_at(&key("b.com"), now).is_none());
+        assert!(cache.get_at(&key("a.com"), now).is_some());
+        assert!(cache.get_at(&key("c.com"), now).is_some());
+
+        // replacing an entry does not evict others
+        cache.insert_at(key("a.com"), vec![a("a.com", 30)], now);
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(cache.clear(), 2);
+        assert!(cache.is_empty());
+        assert_eq!(cache.bytes(), 0);
+        assert!(cache.get_at(&key("a.com"), now).is_none());
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let size = super::entry_size(&key("10.example.com"), &[a("10.example.com", 60)]);
+        let cache = DnsCache::new().max_bytes(3 * size);
+        let now = Instant::now();
+        for i in 10..100 {
+            let name = format!("{i}.example.com");
+            cache.insert_at(key(&name), vec![a(&name, 60)], now);
+            assert!(cache.bytes() <= 3 * size);
+        }
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get_at(&key("99.example.com"), now).is_some());
+        assert!(cache.get_at(&key("96.example.com"), now).is_none());
+
+        // answers larger than the whole budget are not cached
+        let cache = DnsCache::new().max_bytes(size - 1);
+        cache.insert_at(key("10.example.com"), vec![a("10.example.com", 60)], now);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_shards() {
+        // small caches keep a single shard, so evictions above follow the global LRU order
+        assert_eq!(DnsCache::new().max_entries(2).shard_stats().len(), 1);
+
+        // large enough for no thread to evict what another just inserted
+        let cache = DnsCache::new().max_entries(4096);
+        assert_eq!(cache.shard_stats().len(), MAX_SHARDS);
+        let now = Instant::now();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        let name = format!("{thread}-{i}.example.com");
+                        cache.insert_at(key(&name), vec![a(&name, 60)], now);
+                        assert!(cache.get_at(&key(&name), now).is_some());
+                    }
+                });
+            }
+        });
+        assert!(cache.get_at(&key("missing.example.com"), now).is_none());
+
+        let stats = cache.shard_stats();
+        assert_eq!(cache.len(), 2000);
+        assert!(stats.iter().all(|shard| shard.entries <= 4096 / MAX_SHARDS));
+        assert_eq!(stats.iter().map(|shard| shard.hits).sum::<u64>(), 2000);
+        assert_eq!(stats.iter().map(|shard| shard.misses).sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("dns-cache-{}", std::process::id()));
+        let cache = DnsCache::new();
+        cache.insert(key("a.com"), vec![a("a.com", 300), a("a.com", 60)]);
+        cache.insert(key("b.com"), vec![a("b.com", 60)]);
+        cache.save(&path).unwrap();
+
+        let restored = DnsCache::new().max_entries(1);
+        assert_eq!(restored.load(&path).unwrap(), 2);
+        // entries are restored in the order they were used, so the most recent one is kept
+        assert_eq!(restored.len(), 1);
+        assert!(restored.get(&key("b.com")).is_some());
+
+        let restored = DnsCache::new();
+        restored.load(&path).unwrap();
+        let ttls: Vec<_> = restored
+            .get(&key("a.com"))
+            .unwrap()
+            .iter()
+            .map(|answer| answer.meta().ttl)
+            .collect();
+        assert!(ttls[0] > 290 && ttls[1] > 50 && ttls[1] <= 60);
+
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(DnsCache::new().load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_respond_from_cache() {
+        let cache = DnsCache::new();
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(7)
+            .edns_payload_size(1232)
+            .build();
+        assert!(cache.respond(&query).is_none());
+
+        let mut response = DnsParser::new(&query).parse_packet().unwrap();
+        response.header.flags.query = false;
+        response.header.additional_count = 0;
+        response.answers = vec![a("example.com", 60)];
+        cache.store_response(&response.to_bytes());
+
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(8)
+            .build();
+        let cached = cache.respond(&query).unwrap();
+        let cached = DnsParser::new(&cached).parse_packet().unwrap();
+        assert_eq!(cached.header.request_id, 8);
+        assert!(!cached.header.flags.query);
+        assert_eq!(cached.header.additional_count, 0);
+        assert_eq!(cached.questions, response.questions);
+        assert!(matches!(&cached.answers[..], [Answer::A { .. }]));
+
+        // the OPT record is echoed, with the DO bit of the query
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .dnssec_ok(true)
+            .build();
+        let cached = cache.respond(&query).unwrap();
+        let opt = DnsParser::new(&cached).parse_opt().unwrap().unwrap();
+        assert!(opt.dnssec_ok);
+        let cached = DnsParser::new(&cached).parse_packet().unwrap();
+        assert!(matches!(&cached.answers[..], [Answer::A { .. }]));
+    }
+}
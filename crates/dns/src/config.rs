@@ -0,0 +1,334 @@
+//! Resolver settings of the operating system, so applications can use the upstream servers and
+//! search domains it is configured with.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    protocol::name::DnsName,
+    resolver::ResolveOptions,
+    upstream::{Strategy, UpstreamPool},
+};
+
+/// Where Unix systems keep their resolver configuration
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// At most this many name servers are used, as with glibc's `MAXNS`
+pub const MAX_NAME_SERVERS: usize = 3;
+
+/// Upper bounds glibc applies to the `ndots`, `timeout` and `attempts` options
+const MAX_NDOTS: usize = 15;
+const MAX_TIMEOUT_SECS: u64 = 30;
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Upstream servers and how to query them, as configured in `resolv.conf`
+/// https://man7.org/linux/man-pages/man5/resolv.conf.5.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// Servers to query in order of preference, the local host if none is configured
+    pub name_servers: Vec<SocketAddr>,
+    /// Suffixes appended to relative names, from the `search` or `domain` line
+    pub search: Vec<DnsName>,
+    /// Names with at least this many dots are tried as is before the search domains are appended
+    pub ndots: usize,
+    /// How long to wait for a response from a single server
+    pub timeout: Duration,
+    /// How often the query is sent before giving up
+    pub attempts: u32,
+    /// Whether to spread queries across the servers instead of always starting with the first
+    pub rotate: bool,
+}
+
+impl Default for ResolverConfig {
+    /// The defaults of glibc for settings missing from `resolv.conf`
+    fn default() -> Self {
+        Self {
+            name_servers: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 53))],
+            search: vec![],
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+        }
+    }
+}
+
+impl ResolverConfig {
+    /// Reads the configuration of the operating system: [`RESOLV_CONF`] on Unix, and the DNS
+    /// servers and suffixes of all network adapters that are up on Windows.
+    pub fn from_system() -> io::Result<Self> {
+        #[cfg(unix)]
+        return Self::load(RESOLV_CONF);
+        #[cfg(windows)]
+        return windows::from_adapters();
+        #[cfg(not(any(unix, windows)))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no system resolver configuration on this platform",
+        ));
+    }
+
+    /// Reads a file in `resolv.conf` format
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Parses `resolv.conf` as leniently as glibc does, ignoring unknown keywords and invalid
+    /// values
+    ///
+    /// ```
+    /// use dns::config::ResolverConfig;
+    ///
+    /// let config = ResolverConfig::parse("nameserver 192.0.2.53\nsearch example.com\noptions ndots:2");
+    /// assert_eq!(config.upstreams(), ["192.0.2.53:53"]);
+    /// assert_eq!(config.search[0].as_str(), "example.com");
+    /// assert_eq!(config.ndots, 2);
+    /// ```
+    pub fn parse(resolv_conf: &str) -> Self {
+        let mut config = Self {
+            name_servers: vec![],
+            ..Self::default()
+        };
+        for line in resolv_conf.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => {
+                    let address = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+                    if let Some(address) = address {
+                        if config.name_servers.len() < MAX_NAME_SERVERS {
+                            config.name_servers.push(SocketAddr::new(address, 53));
+                        }
+                    }
+                }
+                // whichever of domain and search comes last wins
+                Some("domain") => {
+                    config.search = fields
+                        .next()
+                        .and_then(|name| name.parse().ok())
+                        .into_iter()
+                        .collect();
+                }
+                Some("search") => {
+                    config.search = fields.filter_map(|name| name.parse().ok()).collect();
+                }
+                Some("options") => {
+                    for option in fields {
+                        config.set_option(option);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if config.name_servers.is_empty() {
+            config.name_servers = Self::default().name_servers;
+        }
+        config
+    }
+
+    fn set_option(&mut self, option: &str) {
+        let (name, value) = option.split_once(':').unwrap_or((option, ""));
+        match (name, value.parse::<u64>()) {
+            ("ndots", Ok(ndots)) => self.ndots = (ndots as usize).min(MAX_NDOTS),
+            ("timeout", Ok(secs)) => {
+                self.timeout = Duration::from_secs(secs.clamp(1, MAX_TIMEOUT_SECS))
+            }
+            ("attempts", Ok(attempts)) => self.attempts = (attempts as u32).clamp(1, MAX_ATTEMPTS),
+            ("rotate", _) => self.rotate = true,
+            _ => {}
+        }
+    }
+
+    /// The name servers in `host:port` form, as taken by [`UpstreamPool::new`]
+    pub fn upstreams(&self) -> Vec<String> {
+        self.name_servers.iter().map(ToString::to_string).collect()
+    }
+
+    /// A pool of the name servers that tries them in order, or picks the fastest one if `rotate`
+    /// is set to spread the load
+    pub fn upstream_pool(&self) -> UpstreamPool {
+        let strategy = match self.rotate {
+            true => Strategy::Fastest,
+            false => Strategy::StrictOrder,
+        };
+        UpstreamPool::new(self.upstreams()).strategy(strategy)
+    }
+
+    /// Options with the configured timeout, number of attempts and search domains
+    pub fn resolve_options(&self) -> ResolveOptions {
+        ResolveOptions {
+            timeout: self.timeout,
+            retries: self.attempts.saturating_sub(1),
+            // like glibc, every attempt waits as long
+            backoff: 1,
+            search: self.search.clone(),
+            ndots: self.ndots,
+            ..ResolveOptions::default()
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::{
+        io,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        ptr,
+    };
+
+    use windows_sys::Win32::{
+        Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_NO_DATA, NO_ERROR},
+        NetworkManagement::{
+            IpHelper::{
+                GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_MULTICAST,
+                GAA_FLAG_SKIP_UNICAST, IP_ADAPTER_ADDRESSES_LH,
+            },
+            Ndis::IfOperStatusUp,
+        },
+        Networking::WinSock::{AF_INET, AF_INET6, AF_UNSPEC, SOCKADDR, SOCKADDR_IN, SOCKADDR_IN6},
+    };
+
+    use super::{ResolverConfig, MAX_NAME_SERVERS};
+
+    /// Collects the DNS servers and suffixes of all adapters that are up
+    /// https://learn.microsoft.com/en-us/windows/win32/api/iphlpapi/nf-iphlpapi-getadaptersaddresses
+    pub(super) fn from_adapters() -> io::Result<ResolverConfig> {
+        let flags = GAA_FLAG_SKIP_UNICAST | GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST;
+        // recommended initial size, u64 for the alignment of the adapter structs
+        let mut size: u32 = 15 * 1024;
+        let mut buffer: Vec<u64>;
+        loop {
+            buffer = vec![0; (size as usize).div_ceil(8)];
+            let result = unsafe {
+                GetAdaptersAddresses(
+                    AF_UNSPEC as u32,
+                    flags,
+                    ptr::null(),
+                    buffer.as_mut_ptr().cast(),
+                    &mut size,
+                )
+            };
+            match result {
+                NO_ERROR => break,
+                ERROR_NO_DATA => return Ok(ResolverConfig::default()),
+                ERROR_BUFFER_OVERFLOW => continue,
+                error => return Err(io::Error::from_raw_os_error(error as i32)),
+            }
+        }
+
+        let mut config = ResolverConfig {
+            name_servers: vec![],
+            ..ResolverConfig::default()
+        };
+        let mut adapter = buffer.as_ptr().cast::<IP_ADAPTER_ADDRESSES_LH>();
+        while let Some(current) = unsafe { adapter.as_ref() } {
+            adapter = current.Next;
+            if current.OperStatus != IfOperStatusUp {
+                continue;
+            }
+
+            let mut server = current.FirstDnsServerAddress;
+            while let Some(current) = unsafe { server.as_ref() } {
+                server = current.Next;
+                let address = unsafe { socket_addr(current.Address.lpSockaddr) };
+                match address {
+                    Some(address) if !config.name_servers.contains(&address) => {
+                        config.name_servers.push(address)
+                    }
+                    _ => {}
+                }
+            }
+
+            let suffix = unsafe { wide_string(current.DnsSuffix) };
+            if let Some(suffix) = suffix.and_then(|suffix| suffix.parse().ok()) {
+                if !config.search.contains(&suffix) {
+                    config.search.push(suffix);
+                }
+            }
+        }
+
+        config.name_servers.truncate(MAX_NAME_SERVERS);
+        if config.name_servers.is_empty() {
+            config.name_servers = ResolverConfig::default().name_servers;
+        }
+        Ok(config)
+    }
+
+    /// # Safety
+    /// `address` has to be null or point to a valid socket address
+    unsafe fn socket_addr(address: *const SOCKADDR) -> Option<SocketAddr> {
+        match address.as_ref()?.sa_family {
+            AF_INET => {
+                let address = &*address.cast::<SOCKADDR_IN>();
+                let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.S_un.S_addr));
+                Some(SocketAddr::from((ip, 53)))
+            }
+            AF_INET6 => {
+                let address = &*address.cast::<SOCKADDR_IN6>();
+                let ip = Ipv6Addr::from(address.sin6_addr.u.Byte);
+                // site local addresses Windows falls back to when nothing is configured
+                let unconfigured = ip.segments()[..3] == [0xfec0, 0, 0xffff];
+                (!unconfigured).then_some(SocketAddr::from((ip, 53)))
+            }
+            _ => None,
+        }
+    }
+
+    /// # Safety
+    /// `string` has to be null or point to a null terminated UTF-16 string
+    unsafe fn wide_string(string: *const u16) -> Option<String> {
+        if string.is_null() {
+            return None;
+        }
+        let len = (0..).take_while(|&i| *string.add(i) != 0).count();
+        let string = String::from_utf16(std::slice::from_raw_parts(string, len)).ok()?;
+        (!string.is_empty()).then_some(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ResolverConfig;
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let config = ResolverConfig::parse(
+            "# Generated by NetworkManager
+            domain corp.example
+            search example.com example.net ; last one wins
+            nameserver 192.0.2.1
+            nameserver 2001:db8::53
+            nameserver not-an-address
+            nameserver 192.0.2.2
+            nameserver 192.0.2.3
+            options ndots:3 timeout:1 attempts:9 rotate edns0 ndots:x",
+        );
+        let name_servers: Vec<_> = config.upstreams();
+        assert_eq!(
+            name_servers,
+            ["192.0.2.1:53", "[2001:db8::53]:53", "192.0.2.2:53"]
+        );
+        let search: Vec<_> = config.search.iter().map(|name| name.as_str()).collect();
+        assert_eq!(search, ["example.com", "example.net"]);
+        assert_eq!(config.ndots, 3);
+        assert_eq!(config.timeout, Duration::from_secs(1));
+        assert_eq!(config.attempts, 5);
+        assert!(config.rotate);
+        assert_eq!(config.resolve_options().retries, 4);
+
+        let config = ResolverConfig::parse("search example.com\ndomain corp.example");
+        assert_eq!(
+            config,
+            ResolverConfig {
+                search: vec!["corp.example".parse().unwrap()],
+                ..ResolverConfig::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,1338 @@
+//! Signing authoritative zones with DNSSEC, so validating resolvers can tell that their records
+//! are authentic, and that the names and records they lack really do not exist.
+//! https://datatracker.ietf.org/doc/html/rfc4033
+//! https://datatracker.ietf.org/doc/html/rfc4034
+//! https://datatracker.ietf.org/doc/html/rfc5155
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair},
+};
+
+use crate::{
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        name::DnsName,
+        record_type::RecordType,
+    },
+    serialize::writer::PacketWriter,
+    zonefile::Zone,
+};
+
+/// Record types of DNSSEC, which have no dedicated [`RecordType`] variants
+pub const DS: u16 = 43;
+pub const RRSIG: u16 = 46;
+pub const NSEC: u16 = 47;
+pub const DNSKEY: u16 = 48;
+pub const NSEC3: u16 = 50;
+pub const NSEC3PARAM: u16 = 51;
+
+/// DNSKEY flags of all zone keys, and of the keys signing the DNSKEY RRset
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-2.1.1
+const ZONE_KEY_FLAG: u16 = 0x0100;
+const SECURE_ENTRY_POINT_FLAG: u16 = 0x0001;
+
+/// The protocol field of DNSKEY records, which must be 3
+const DNSKEY_PROTOCOL: u8 = 3;
+
+/// Hash algorithm of NSEC3, SHA-1 being the only one defined
+const NSEC3_SHA1: u8 = 1;
+
+/// Digest type of the DS records built for the parent zone
+const DS_SHA256: u8 = 2;
+
+/// How long before they were made signatures are valid, for validators whose clocks are behind
+const INCEPTION_OFFSET: Duration = Duration::from_secs(3600);
+
+/// Signing algorithms supported for zone keys
+/// https://www.iana.org/assignments/dns-sec-alg-numbers/dns-sec-alg-numbers.xhtml
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// https://datatracker.ietf.org/doc/html/rfc6605
+    EcdsaP256Sha256,
+    EcdsaP384Sha384,
+    /// https://datatracker.ietf.org/doc/html/rfc8080
+    Ed25519,
+}
+
+impl Algorithm {
+    const ALL: [Algorithm; 3] = [
+        Algorithm::EcdsaP256Sha256,
+        Algorithm::EcdsaP384Sha384,
+        Algorithm::Ed25519,
+    ];
+
+    /// The number identifying the algorithm in DNSKEY, RRSIG and DS records
+    pub fn number(&self) -> u8 {
+        match self {
+            Algorithm::EcdsaP256Sha256 => 13,
+            Algorithm::EcdsaP384Sha384 => 14,
+            Algorithm::Ed25519 => 15,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::EcdsaP256Sha256 => "ECDSAP256SHA256",
+            Algorithm::EcdsaP384Sha384 => "ECDSAP384SHA384",
+            Algorithm::Ed25519 => "ED25519",
+        }
+    }
+
+    fn ecdsa(&self) -> Option<&'static signature::EcdsaSigningAlgorithm> {
+        match self {
+            Algorithm::EcdsaP256Sha256 => Some(&signature::ECDSA_P256_SHA256_FIXED_SIGNING),
+            Algorithm::EcdsaP384Sha384 => Some(&signature::ECDSA_P384_SHA384_FIXED_SIGNING),
+            Algorithm::Ed25519 => None,
+        }
+    }
+}
+
+/// Parses the mnemonic of an algorithm regardless of case, or its number
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| {
+                algorithm.name().eq_ignore_ascii_case(s) || algorithm.number().to_string() == s
+            })
+            .ok_or_else(|| format!("unsupported DNSSEC algorithm {s}"))
+    }
+}
+
+/// What a zone key signs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    /// Key signing key, which signs the DNSKEY RRset and is referred to by the DS record in the
+    /// parent zone
+    Ksk,
+    /// Zone signing key, which signs all other RRsets
+    Zsk,
+}
+
+impl Display for KeyRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyRole::Ksk => f.write_str("ksk"),
+            KeyRole::Zsk => f.write_str("zsk"),
+        }
+    }
+}
+
+impl FromStr for KeyRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ksk" => Ok(KeyRole::Ksk),
+            "zsk" => Ok(KeyRole::Zsk),
+            _ => Err(format!("unknown key role {s}, expected ksk or zsk")),
+        }
+    }
+}
+
+/// When a key is part of the DNSKEY RRset of its zone, and when it signs the zone. A successor
+/// is published for a while before it takes over, and its predecessor stays published a while
+/// longer, so resolvers always have the key of the signatures they hold in their caches.
+/// https://datatracker.ietf.org/doc/html/rfc6781#section-4.1.1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyTiming {
+    pub publish: SystemTime,
+    pub activate: SystemTime,
+    /// When the key stops signing, if it has a successor
+    pub retire: Option<SystemTime>,
+    /// When the key is removed from the zone
+    pub delete: Option<SystemTime>,
+}
+
+impl KeyTiming {
+    /// Publishes and activates a key right away, for the first keys of a zone
+    pub fn at(now: SystemTime) -> Self {
+        Self {
+            publish: now,
+            activate: now,
+            retire: None,
+            delete: None,
+        }
+    }
+
+    pub fn is_published(&self, now: SystemTime) -> bool {
+        self.publish <= now && self.delete.is_none_or(|delete| now < delete)
+    }
+
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.activate <= now && self.retire.is_none_or(|retire| now < retire)
+    }
+
+    fn events(&self) -> impl Iterator<Item = SystemTime> {
+        [
+            Some(self.publish),
+            Some(self.activate),
+            self.retire,
+            self.delete,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// A private zone key along with its role and timing
+#[derive(Clone)]
+pub struct SigningKey {
+    pub role: KeyRole,
+    pub algorithm: Algorithm,
+    pub timing: KeyTiming,
+    pkcs8: Vec<u8>,
+    key_pair: Arc<KeyPairKind>,
+}
+
+enum KeyPairKind {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// Leaves out the private key
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("role", &self.role)
+            .field("algorithm", &self.algorithm)
+            .field("key_tag", &self.key_tag())
+            .field("timing", &self.timing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    /// Generates a new random key
+    pub fn generate(
+        role: KeyRole,
+        algorithm: Algorithm,
+        timing: KeyTiming,
+    ) -> Result<Self, String> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match algorithm.ecdsa() {
+            Some(ecdsa) => EcdsaKeyPair::generate_pkcs8(ecdsa, &rng),
+            None => Ed25519KeyPair::generate_pkcs8(&rng),
+        }
+        .map_err(|_| format!("could not generate {} key", algorithm.name()))?;
+        Self::from_pkcs8(role, algorithm, timing, pkcs8.as_ref().to_vec())
+    }
+
+    /// Reads a private key in PKCS#8 format
+    pub fn from_pkcs8(
+        role: KeyRole,
+        algorithm: Algorithm,
+        timing: KeyTiming,
+        pkcs8: Vec<u8>,
+    ) -> Result<Self, String> {
+        let key_pair = match algorithm.ecdsa() {
+            Some(ecdsa) => EcdsaKeyPair::from_pkcs8(ecdsa, &pkcs8, &SystemRandom::new())
+                .map(KeyPairKind::Ecdsa),
+            None => Ed25519KeyPair::from_pkcs8(&pkcs8).map(KeyPairKind::Ed25519),
+        }
+        .map_err(|e| format!("invalid {} key: {e}", algorithm.name()))?;
+        Ok(Self {
+            role,
+            algorithm,
+            timing,
+            pkcs8,
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    /// The public key as the DNSKEY record carries it: the coordinates of the point for ECDSA,
+    /// without the leading byte of the uncompressed form
+    /// https://datatracker.ietf.org/doc/html/rfc6605#section-4
+    fn public_key(&self) -> &[u8] {
+        match &*self.key_pair {
+            KeyPairKind::Ecdsa(key_pair) => &key_pair.public_key().as_ref()[1..],
+            KeyPairKind::Ed25519(key_pair) => key_pair.public_key().as_ref(),
+        }
+    }
+
+    /// The signature of `data`, in the format of RRSIG records
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match &*self.key_pair {
+            KeyPairKind::Ecdsa(key_pair) => key_pair
+                .sign(&SystemRandom::new(), data)
+                // signing only fails if the system has no randomness
+                .expect("no randomness to sign with")
+                .as_ref()
+                .to_vec(),
+            KeyPairKind::Ed25519(key_pair) => key_pair.sign(data).as_ref().to_vec(),
+        }
+    }
+
+    fn dnskey_rdata(&self) -> Vec<u8> {
+        let flags = match self.role {
+            KeyRole::Ksk => ZONE_KEY_FLAG | SECURE_ENTRY_POINT_FLAG,
+            KeyRole::Zsk => ZONE_KEY_FLAG,
+        };
+        let mut rdata = flags.to_be_bytes().to_vec();
+        rdata.push(DNSKEY_PROTOCOL);
+        rdata.push(self.algorithm.number());
+        rdata.extend_from_slice(self.public_key());
+        rdata
+    }
+
+    /// The tag RRSIG and DS records refer to the key with
+    /// https://datatracker.ietf.org/doc/html/rfc4034#appendix-B
+    pub fn key_tag(&self) -> u16 {
+        let sum = self
+            .dnskey_rdata()
+            .iter()
+            .enumerate()
+            .fold(0u32, |sum, (i, &byte)| {
+                sum + if i % 2 == 0 {
+                    u32::from(byte) << 8
+                } else {
+                    u32::from(byte)
+                }
+            });
+        (sum + (sum >> 16)) as u16
+    }
+
+    /// The DNSKEY record of this key in the zone `origin`
+    pub fn dnskey(&self, origin: &DnsName, ttl: u32) -> Answer {
+        dnssec_record(origin, DNSKEY, ttl, self.dnskey_rdata())
+    }
+
+    /// The DS record the parent zone of `origin` refers to this key with, with a SHA-256 digest
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-5.1.4
+    pub fn ds(&self, origin: &DnsName, ttl: u32) -> Answer {
+        let mut digested = canonical_name(origin);
+        digested.extend(self.dnskey_rdata());
+        let mut rdata = self.key_tag().to_be_bytes().to_vec();
+        rdata.push(self.algorithm.number());
+        rdata.push(DS_SHA256);
+        rdata.extend_from_slice(digest::digest(&digest::SHA256, &digested).as_ref());
+        dnssec_record(origin, DS, ttl, rdata)
+    }
+}
+
+/// A key as a line of a key file:
+/// `role algorithm publish activate retire delete pkcs8`, with the times in seconds since the
+/// Unix epoch or `-` if not scheduled, and the private key in base64
+impl Display for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |time: Option<SystemTime>| match time {
+            Some(time) => unix_time(time).to_string(),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{} {} {} {} {} {} {}",
+            self.role,
+            self.algorithm.number(),
+            time(Some(self.timing.publish)),
+            time(Some(self.timing.activate)),
+            time(self.timing.retire),
+            time(self.timing.delete),
+            STANDARD.encode(&self.pkcs8)
+        )
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_ascii_whitespace().collect();
+        let [role, algorithm, publish, activate, retire, delete, pkcs8] = fields[..] else {
+            return Err(format!(
+                "expected role algorithm publish activate retire delete key, got {s}"
+            ));
+        };
+        let time = |time: &str| -> Result<Option<SystemTime>, String> {
+            match time {
+                "-" => Ok(None),
+                seconds => seconds
+                    .parse()
+                    .map(|seconds| Some(UNIX_EPOCH + Duration::from_secs(seconds)))
+                    .map_err(|_| format!("invalid key time {time}")),
+            }
+        };
+        let required =
+            |field: &str| time(field)?.ok_or_else(|| format!("invalid key time {field}"));
+        let timing = KeyTiming {
+            publish: required(publish)?,
+            activate: required(activate)?,
+            retire: time(retire)?,
+            delete: time(delete)?,
+        };
+        let pkcs8 = STANDARD
+            .decode(pkcs8)
+            .map_err(|e| format!("invalid private key: {e}"))?;
+        Self::from_pkcs8(role.parse()?, algorithm.parse()?, timing, pkcs8)
+    }
+}
+
+/// Reads the keys of a zone from a key file with one key per line, see [`SigningKey`]. Empty
+/// lines and lines starting with `;` are skipped.
+pub fn load_keys(path: impl AsRef<Path>) -> io::Result<Vec<SigningKey>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .map(|line| {
+            line.parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+/// Writes `keys` to a key file only the owner may read, see [`load_keys`]
+pub fn save_keys(path: impl AsRef<Path>, keys: &[SigningKey]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let contents: String = keys.iter().map(|key| format!("{key}\n")).collect();
+    io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+/// How the nonexistence of names and records is proven
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denial {
+    /// NSEC records, which link the names of the zone in canonical order
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-4
+    Nsec,
+    /// NSEC3 records, which link hashes of the names so the zone can not be walked as easily
+    /// https://datatracker.ietf.org/doc/html/rfc5155
+    Nsec3 { iterations: u16, salt: Vec<u8> },
+}
+
+/// Parses `nsec`, or `nsec3` with no extra iterations and no salt as RFC 9276 recommends
+impl FromStr for Denial {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nsec" => Ok(Denial::Nsec),
+            "nsec3" => Ok(Denial::Nsec3 {
+                iterations: 0,
+                salt: vec![],
+            }),
+            _ => Err(format!(
+                "unknown denial of existence {s}, expected nsec or nsec3"
+            )),
+        }
+    }
+}
+
+/// How a zone is signed, and how often its keys are replaced
+#[derive(Debug, Clone)]
+pub struct SigningPolicy {
+    /// Algorithm of new keys. Existing keys keep theirs.
+    pub algorithm: Algorithm,
+    pub denial: Denial,
+    /// How long signatures are valid for. They are renewed once a quarter of that has passed.
+    pub signature_validity: Duration,
+    /// How long a key signs before its successor takes over, `None` to never replace it
+    pub ksk_lifetime: Option<Duration>,
+    pub zsk_lifetime: Option<Duration>,
+    /// How long a successor is published before it signs, and its predecessor after that,
+    /// which should exceed the TTL of the DNSKEY RRset and of the signatures
+    pub rollover_margin: Duration,
+    pub dnskey_ttl: u32,
+}
+
+impl Default for SigningPolicy {
+    fn default() -> Self {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        Self {
+            algorithm: Algorithm::EcdsaP256Sha256,
+            denial: Denial::Nsec,
+            signature_validity: 14 * DAY,
+            // replacing the KSK means updating the DS record at the parent zone
+            ksk_lifetime: None,
+            zsk_lifetime: Some(90 * DAY),
+            rollover_margin: 2 * DAY,
+            dnskey_ttl: 3600,
+        }
+    }
+}
+
+/// Keeps a zone signed with its keys, replacing the keys as the policy says
+///
+/// ```
+/// use std::time::SystemTime;
+///
+/// use dns::{
+///     dnssec::{SigningPolicy, ZoneSigner},
+///     zonefile::Zone,
+/// };
+///
+/// let origin = "home.arpa".parse().unwrap();
+/// let zone = Zone::parse("@ 300 SOA ns hostmaster 1 7200 3600 1209600 300", &origin).unwrap();
+/// let signer = ZoneSigner::new(origin, SigningPolicy::default(), vec![]);
+/// let now = SystemTime::now();
+/// assert!(signer.roll_keys(now).unwrap());
+/// let signed = signer.sign(&zone, now);
+/// assert!(signed.records.len() > zone.records.len());
+/// ```
+#[derive(Debug)]
+pub struct ZoneSigner {
+    pub origin: DnsName,
+    pub policy: SigningPolicy,
+    keys: Mutex<Vec<SigningKey>>,
+    /// When the zone was signed last
+    signed_at: Mutex<Option<SystemTime>>,
+}
+
+impl ZoneSigner {
+    pub fn new(origin: DnsName, policy: SigningPolicy, keys: Vec<SigningKey>) -> Self {
+        Self {
+            origin,
+            policy,
+            keys: Mutex::new(keys),
+            signed_at: Mutex::new(None),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<SigningKey> {
+        self.keys.lock().unwrap().clone()
+    }
+
+    /// Generates the first keys of the zone, schedules the successors of keys that reach the end
+    /// of their lifetime, and forgets deleted keys. Returns whether the keys changed, in which
+    /// case they should be saved, and the DS record of a new KSK be published at the parent zone
+    /// before its predecessor retires.
+    pub fn roll_keys(&self, now: SystemTime) -> Result<bool, String> {
+        let policy = &self.policy;
+        let mut keys = self.keys.lock().unwrap();
+        let count = keys.len();
+        keys.retain(|key| key.timing.delete.is_none_or(|delete| now < delete));
+        let mut changed = keys.len() != count;
+
+        for (role, lifetime) in [
+            (KeyRole::Ksk, policy.ksk_lifetime),
+            (KeyRole::Zsk, policy.zsk_lifetime),
+        ] {
+            // the key signing until further notice, either now or once its predecessor retires
+            let current = keys
+                .iter_mut()
+                .filter(|key| key.role == role && key.timing.retire.is_none())
+                .max_by_key(|key| key.timing.activate);
+            let Some(current) = current else {
+                keys.push(SigningKey::generate(
+                    role,
+                    policy.algorithm,
+                    KeyTiming::at(now),
+                )?);
+                changed = true;
+                continue;
+            };
+            let Some(lifetime) = lifetime else {
+                continue;
+            };
+            let end_of_life = current.timing.activate + lifetime;
+            if now + policy.rollover_margin < end_of_life {
+                continue;
+            }
+            let handover = end_of_life.max(now + policy.rollover_margin);
+            let successor = SigningKey::generate(
+                role,
+                policy.algorithm,
+                KeyTiming {
+                    publish: now,
+                    activate: handover,
+                    retire: None,
+                    delete: None,
+                },
+            )?;
+            current.timing.retire = Some(handover);
+            current.timing.delete = Some(handover + policy.rollover_margin);
+            keys.push(successor);
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Whether the signatures are due for renewal, or a key was published, activated, retired or
+    /// deleted since the zone was signed last
+    pub fn needs_signing(&self, now: SystemTime) -> bool {
+        let Some(signed_at) = *self.signed_at.lock().unwrap() else {
+            return true;
+        };
+        let keys = self.keys.lock().unwrap();
+        let key_event = keys
+            .iter()
+            .flat_map(|key| key.timing.events())
+            .any(|event| signed_at < event && event <= now);
+        key_event || signed_at + self.policy.signature_validity / 4 <= now
+    }
+
+    /// Signs `zone` from scratch: the DNSSEC records it has are replaced with the DNSKEY records
+    /// of the published keys, an NSEC or NSEC3 chain and signatures of all authoritative RRsets.
+    /// The DNSKEY RRset is signed by the active KSKs and everything else by the active ZSKs, or
+    /// by the KSKs if there are none. The zone is returned unchanged without active keys.
+    pub fn sign(&self, zone: &Zone, now: SystemTime) -> Zone {
+        let keys = self.keys.lock().unwrap();
+        let published: Vec<_> = keys
+            .iter()
+            .filter(|key| key.timing.is_published(now))
+            .collect();
+        let active = |role: KeyRole| -> Vec<_> {
+            keys.iter()
+                .filter(|key| key.role == role && key.timing.is_active(now))
+                .collect()
+        };
+        let (mut ksks, mut zsks) = (active(KeyRole::Ksk), active(KeyRole::Zsk));
+        if zsks.is_empty() {
+            zsks.clone_from(&ksks);
+        } else if ksks.is_empty() {
+            ksks.clone_from(&zsks);
+        }
+        if zsks.is_empty() {
+            return zone.clone();
+        }
+        *self.signed_at.lock().unwrap() = Some(now);
+
+        let validity = Validity {
+            inception: unix_time(now - INCEPTION_OFFSET) as u32,
+            expiration: unix_time(now + self.policy.signature_validity) as u32,
+        };
+        let origin = &zone.origin;
+        let mut records: Vec<_> = zone
+            .records
+            .iter()
+            .filter(|record| !is_dnssec_record(record))
+            .cloned()
+            .collect();
+        records.extend(
+            published
+                .iter()
+                .map(|key| key.dnskey(origin, self.policy.dnskey_ttl)),
+        );
+        // https://datatracker.ietf.org/doc/html/rfc9077
+        let negative_ttl = zone.soa().map_or(0, |soa| match soa {
+            Answer::SOA { meta, minimum, .. } => meta.ttl.min(*minimum as usize) as u32,
+            _ => 0,
+        });
+        let chain = match &self.policy.denial {
+            Denial::Nsec => nsec_chain(origin, &records, negative_ttl),
+            Denial::Nsec3 { iterations, salt } => {
+                let params = Nsec3Params {
+                    iterations: *iterations,
+                    salt: salt.clone(),
+                };
+                records.push(dnssec_record(origin, NSEC3PARAM, 0, params.rdata()));
+                nsec3_chain(origin, &records, negative_ttl, &params)
+            }
+        };
+        records.extend(chain);
+
+        let signatures: Vec<_> = rrsets(origin, &records)
+            .iter()
+            .flat_map(|rrset| {
+                let signers = match rrset[0].meta().r#type {
+                    RecordType::OTHER(DNSKEY) => &ksks,
+                    _ => &zsks,
+                };
+                signers
+                    .iter()
+                    .map(|key| rrsig(key, origin, rrset, &validity))
+            })
+            .collect();
+        records.extend(signatures);
+        Zone {
+            origin: origin.clone(),
+            records,
+        }
+    }
+}
+
+/// NSEC3 parameters of a zone, which are the same for all of its NSEC3 records
+/// https://datatracker.ietf.org/doc/html/rfc5155#section-4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Nsec3Params {
+    iterations: u16,
+    salt: Vec<u8>,
+}
+
+impl Nsec3Params {
+    /// The parameters of the NSEC3PARAM record of `zone`, if it has one
+    pub(crate) fn of(zone: &Zone) -> Option<Self> {
+        zone.records.iter().find_map(|record| match record {
+            Answer::Unknown { meta, rdata }
+                if meta.r#type == RecordType::OTHER(NSEC3PARAM) && meta.name == zone.origin =>
+            {
+                let salt_len = usize::from(*rdata.get(4)?);
+                Some(Self {
+                    iterations: u16::from_be_bytes([*rdata.get(2)?, *rdata.get(3)?]),
+                    salt: rdata.get(5..5 + salt_len)?.to_vec(),
+                })
+            }
+            _ => None,
+        })
+    }
+
+    fn rdata(&self) -> Vec<u8> {
+        let mut rdata = vec![NSEC3_SHA1, 0];
+        rdata.extend_from_slice(&self.iterations.to_be_bytes());
+        rdata.push(self.salt.len() as u8);
+        rdata.extend_from_slice(&self.salt);
+        rdata
+    }
+
+    /// The hash of `name`, iterated as often as the parameters say
+    /// https://datatracker.ietf.org/doc/html/rfc5155#section-5
+    fn hash(&self, name: &DnsName) -> Vec<u8> {
+        let mut hash = canonical_name(name);
+        for _ in 0..=self.iterations {
+            hash.extend_from_slice(&self.salt);
+            hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+                .as_ref()
+                .to_vec();
+        }
+        hash
+    }
+}
+
+/// The owner of the NSEC3 record of the name with `hash` in the zone `origin`
+fn hashed_owner(hash: &[u8], origin: &DnsName) -> DnsName {
+    DnsName::new(&format!("{}.{origin}", base32hex(hash))).unwrap_or_else(|_| origin.clone())
+}
+
+/// Inception and expiration of signatures, in seconds since the Unix epoch modulo 2^32
+struct Validity {
+    inception: u32,
+    expiration: u32,
+}
+
+/// The records of the DNSSEC types that are derived from the rest of a zone when it is signed.
+/// DS records are not, as they are only copied from child zones.
+fn is_dnssec_record(record: &Answer) -> bool {
+    matches!(
+        record.meta().r#type,
+        RecordType::OTHER(RRSIG | NSEC | DNSKEY | NSEC3 | NSEC3PARAM)
+    )
+}
+
+/// Whether `record` is part of an NSEC3 chain, which is owned by hashed names that do not exist
+pub(crate) fn is_nsec3_record(record: &Answer) -> bool {
+    match record {
+        Answer::Unknown { meta, rdata } => match meta.r#type {
+            RecordType::OTHER(NSEC3) => true,
+            RecordType::OTHER(RRSIG) => rdata.get(0..2) == Some(&NSEC3.to_be_bytes()[..]),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The type of records an RRSIG record covers
+pub(crate) fn covered_type(record: &Answer) -> Option<RecordType> {
+    match record {
+        Answer::Unknown { meta, rdata } if meta.r#type == RecordType::OTHER(RRSIG) => {
+            let covered = u16::from_be_bytes([*rdata.first()?, *rdata.get(1)?]);
+            Some(RecordType::from(covered as usize))
+        }
+        _ => None,
+    }
+}
+
+fn dnssec_record(owner: &DnsName, record_type: u16, ttl: u32, rdata: Vec<u8>) -> Answer {
+    Answer::Unknown {
+        meta: AnswerMeta {
+            name: owner.clone(),
+            r#type: RecordType::OTHER(record_type),
+            class: Class::IN,
+            ttl: ttl as usize,
+            len: 0,
+        },
+        rdata,
+    }
+}
+
+/// The delegations to child zones
+fn zone_cuts(origin: &DnsName, records: &[Answer]) -> Vec<DnsName> {
+    let mut cuts: Vec<DnsName> = vec![];
+    for record in records {
+        if let Answer::NS { meta, .. } = record {
+            if meta.name != *origin && !cuts.contains(&meta.name) {
+                cuts.push(meta.name.clone());
+            }
+        }
+    }
+    cuts
+}
+
+/// Whether `name` is below a delegation, and so not authoritative
+fn is_below_zone_cut(cuts: &[DnsName], name: &DnsName) -> bool {
+    cuts.iter()
+        .any(|cut| name != cut && name.is_subdomain_of(cut))
+}
+
+/// The authoritative RRsets of a zone, which are signed. At a delegation only the DS and NSEC
+/// records are authoritative, the NS records belong to the child zone.
+/// https://datatracker.ietf.org/doc/html/rfc4035#section-2.2
+fn rrsets<'a>(origin: &DnsName, records: &'a [Answer]) -> Vec<Vec<&'a Answer>> {
+    let cuts = zone_cuts(origin, records);
+    let mut rrsets: HashMap<(DnsName, RecordType), Vec<&Answer>> = HashMap::new();
+    for record in records {
+        let meta = record.meta();
+        let authoritative = match meta.r#type {
+            RecordType::OTHER(RRSIG) => false,
+            RecordType::OTHER(DS | NSEC) => true,
+            _ => !cuts.contains(&meta.name),
+        };
+        if authoritative && !is_below_zone_cut(&cuts, &meta.name) {
+            rrsets
+                .entry((meta.name.clone(), meta.r#type))
+                .or_default()
+                .push(record);
+        }
+    }
+    let mut rrsets: Vec<_> = rrsets.into_values().collect();
+    rrsets.sort_by(|a, b| {
+        let (a, b) = (a[0].meta(), b[0].meta());
+        a.name
+            .canonical_cmp(&b.name)
+            .then(u16::from(a.r#type).cmp(&u16::from(b.r#type)))
+    });
+    rrsets
+}
+
+/// The names of the NSEC chain of a zone in canonical order, which are all authoritative owner
+/// names and the delegations, with the types of their records as NSEC and NSEC3 records list
+/// them
+fn chain_names(origin: &DnsName, records: &[Answer]) -> Vec<(DnsName, Vec<u16>)> {
+    let cuts = zone_cuts(origin, records);
+    let mut types: HashMap<&DnsName, Vec<u16>> = HashMap::new();
+    for record in records {
+        let meta = record.meta();
+        if !is_below_zone_cut(&cuts, &meta.name) {
+            types
+                .entry(&meta.name)
+                .or_default()
+                .push(meta.r#type.into());
+        }
+    }
+    let mut names: Vec<_> = types
+        .into_iter()
+        .map(|(name, mut types)| {
+            // the RRSIG records of a delegation only cover its DS records
+            if !cuts.contains(name) || types.contains(&DS) {
+                types.push(RRSIG);
+            }
+            (name.clone(), types)
+        })
+        .collect();
+    names.sort_by(|a, b| a.0.canonical_cmp(&b.0));
+    names
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-4
+fn nsec_chain(origin: &DnsName, records: &[Answer], ttl: u32) -> Vec<Answer> {
+    let names = chain_names(origin, records);
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, (name, types))| {
+            let next = &names[(i + 1) % names.len()].0;
+            let mut rdata = canonical_name(next);
+            rdata.extend(type_bitmap([&types[..], &[RRSIG, NSEC]].concat()));
+            dnssec_record(name, NSEC, ttl, rdata)
+        })
+        .collect()
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc5155#section-7.1
+fn nsec3_chain(
+    origin: &DnsName,
+    records: &[Answer],
+    ttl: u32,
+    params: &Nsec3Params,
+) -> Vec<Answer> {
+    let mut names = chain_names(origin, records);
+    // empty non-terminals get NSEC3 records too, so their existence can be proven
+    let mut empty_non_terminals = vec![];
+    for (name, _) in &names {
+        for labels in origin.label_count() + 1..name.label_count() {
+            let ancestor = name.ancestor(labels);
+            if !empty_non_terminals.contains(&ancestor)
+                && !names.iter().any(|(name, _)| *name == ancestor)
+            {
+                empty_non_terminals.push(ancestor);
+            }
+        }
+    }
+    names.extend(empty_non_terminals.into_iter().map(|name| (name, vec![])));
+
+    let mut hashed: Vec<_> = names
+        .into_iter()
+        .map(|(name, types)| (params.hash(&name), types))
+        .collect();
+    hashed.sort_by(|a, b| a.0.cmp(&b.0));
+    hashed.dedup_by(|a, b| a.0 == b.0);
+    hashed
+        .iter()
+        .enumerate()
+        .map(|(i, (hash, types))| {
+            let next = &hashed[(i + 1) % hashed.len()].0;
+            let mut rdata = params.rdata();
+            rdata.push(next.len() as u8);
+            rdata.extend_from_slice(next);
+            rdata.extend(type_bitmap(types.clone()));
+            dnssec_record(&hashed_owner(hash, origin), NSEC3, ttl, rdata)
+        })
+        .collect()
+}
+
+/// The type bit maps field of NSEC and NSEC3 records, a bitmap of the types for each block of
+/// 256 types that has any
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-4.1.2
+fn type_bitmap(mut types: Vec<u16>) -> Vec<u8> {
+    types.sort_unstable();
+    types.dedup();
+    let mut bitmap = vec![];
+    for window in 0..=u8::MAX {
+        let in_window: Vec<_> = types
+            .iter()
+            .filter(|&&record_type| (record_type >> 8) as u8 == window)
+            .map(|&record_type| record_type as u8)
+            .collect();
+        let Some(&last) = in_window.last() else {
+            continue;
+        };
+        let mut bits = vec![0; usize::from(last / 8) + 1];
+        for record_type in in_window {
+            bits[usize::from(record_type / 8)] |= 0x80 >> (record_type % 8);
+        }
+        bitmap.push(window);
+        bitmap.push(bits.len() as u8);
+        bitmap.extend(bits);
+    }
+    bitmap
+}
+
+/// Signs `rrset` with `key`
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-3.1.8.1
+fn rrsig(key: &SigningKey, origin: &DnsName, rrset: &[&Answer], validity: &Validity) -> Answer {
+    let meta = rrset[0].meta();
+    let ttl = rrset
+        .iter()
+        .map(|record| record.meta().ttl)
+        .min()
+        .unwrap_or_default() as u32;
+    // a wildcard signs the names it expands to, which have the labels of the wildcard
+    let wildcard = meta.name.labels().next() == Some("*");
+    let labels = meta.name.label_count() - usize::from(wildcard);
+
+    let mut rdata = vec![];
+    let mut writer = PacketWriter::uncompressed(&mut rdata);
+    writer.write_u16(meta.r#type.into());
+    writer.write_u8(key.algorithm.number());
+    writer.write_u8(labels as u8);
+    writer.write_u32(ttl);
+    writer.write_u32(validity.expiration);
+    writer.write_u32(validity.inception);
+    writer.write_u16(key.key_tag());
+    writer.write_name_uncompressed(&origin.to_lowercase());
+
+    let mut signed = rdata.clone();
+    let mut canonical: Vec<_> = rrset.iter().map(|record| canonical_rdata(record)).collect();
+    canonical.sort();
+    canonical.dedup();
+    for record_rdata in canonical {
+        signed.extend(canonical_name(&meta.name));
+        signed.extend_from_slice(&u16::from(meta.r#type).to_be_bytes());
+        signed.extend_from_slice(&u16::from(meta.class).to_be_bytes());
+        signed.extend_from_slice(&ttl.to_be_bytes());
+        signed.extend_from_slice(&(record_rdata.len() as u16).to_be_bytes());
+        signed.extend(record_rdata);
+    }
+    rdata.extend(key.sign(&signed));
+    dnssec_record(&meta.name, RRSIG, ttl, rdata)
+}
+
+/// `name` in canonical wire format, uncompressed and in lower case
+fn canonical_name(name: &DnsName) -> Vec<u8> {
+    let mut wire = vec![];
+    PacketWriter::uncompressed(&mut wire).write_name_uncompressed(&name.to_lowercase());
+    wire
+}
+
+/// The RDATA of `record` in canonical form, with the domain names in it in lower case
+/// https://datatracker.ietf.org/doc/html/rfc4034#section-6.2
+fn canonical_rdata(record: &Answer) -> Vec<u8> {
+    let mut record = record.clone();
+    match &mut record {
+        Answer::NS { ns: name, .. }
+        | Answer::CNAME { cname: name, .. }
+        | Answer::PTR { ptr: name, .. }
+        | Answer::MX { exchange: name, .. }
+        | Answer::SRV { target: name, .. } => *name = name.to_lowercase(),
+        Answer::SOA { mname, rname, .. } => {
+            *mname = mname.to_lowercase();
+            *rname = rname.to_lowercase();
+        }
+        _ => {}
+    }
+    let mut wire = vec![];
+    record.to_bytes(&mut wire);
+    wire.split_off(record.meta().name.wire_len() + 10)
+}
+
+/// Base 32 with the extended hex alphabet in lower case and without padding, which keeps the
+/// order of the hashes NSEC3 owner names are made of
+/// https://datatracker.ietf.org/doc/html/rfc4648#section-7
+fn base32hex(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |bits, &byte| bits << 8 | u64::from(byte));
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - 5 * i)) & 0x1f;
+            encoded.push(char::from(ALPHABET[index as usize]));
+        }
+    }
+    encoded
+}
+
+/// The record of the NSEC chain of `zone` that proves what `name` lacks: the record of `name`
+/// itself if it exists, or the record of the closest name before it in canonical order
+pub(crate) fn nsec_for<'a>(zone: &'a Zone, name: &DnsName) -> Option<&'a Answer> {
+    zone.records
+        .iter()
+        .filter(|record| record.meta().r#type == RecordType::OTHER(NSEC))
+        .filter(|record| record.meta().name.canonical_cmp(name) != Ordering::Greater)
+        .max_by(|a, b| a.meta().name.canonical_cmp(&b.meta().name))
+}
+
+/// The record of the NSEC3 chain of `zone` whose hash matches the hash of `name`, or covers it,
+/// which proves that `name` does not exist
+pub(crate) fn nsec3_for<'a>(
+    zone: &'a Zone,
+    params: &Nsec3Params,
+    name: &DnsName,
+) -> Option<&'a Answer> {
+    let hash = base32hex(&params.hash(name));
+    let chain = || {
+        zone.records
+            .iter()
+            .filter(|record| record.meta().r#type == RecordType::OTHER(NSEC3))
+    };
+    let label = |record: &Answer| -> String {
+        record
+            .meta()
+            .name
+            .labels()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    };
+    chain()
+        .filter(|record| label(record) <= hash)
+        .max_by_key(|record| label(record))
+        // hashes before the first one are covered by the last record, which wraps around
+        .or_else(|| chain().max_by_key(|record| label(record)))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ED25519};
+
+    use crate::{
+        protocol::{answer::Answer, name::DnsName, record_type::RecordType},
+        zonefile::Zone,
+    };
+
+    use super::{
+        base32hex, canonical_name, hashed_owner, Algorithm, Denial, KeyRole, KeyTiming,
+        Nsec3Params, SigningKey, SigningPolicy, ZoneSigner, DNSKEY, NSEC, NSEC3, RRSIG,
+    };
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn zone() -> Zone {
+        Zone::parse(
+            "$TTL 300
+@        SOA   ns hostmaster 1 7200 3600 1209600 60
+         NS    ns
+ns       A     192.0.2.53
+WWW      A     192.0.2.80
+*.apps   A     192.0.2.81
+child    NS    ns.child
+ns.child A     192.0.2.54
+",
+            &"example.lan".parse().unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn of_type(zone: &Zone, record_type: u16) -> Vec<&Answer> {
+        zone.records
+            .iter()
+            .filter(|record| record.meta().r#type == RecordType::OTHER(record_type))
+            .collect()
+    }
+
+    fn rdata(record: &Answer) -> &[u8] {
+        match record {
+            Answer::Unknown { rdata, .. } => rdata,
+            _ => panic!("{record} is no DNSSEC record"),
+        }
+    }
+
+    #[test]
+    fn test_signing_keys() {
+        // https://datatracker.ietf.org/doc/html/rfc4648#section-10
+        assert_eq!(base32hex(b"foobar"), "cpnmuoj1e8");
+        assert_eq!(base32hex(b"f"), "co");
+
+        let timing = KeyTiming::at(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        for algorithm in [Algorithm::EcdsaP256Sha256, Algorithm::Ed25519] {
+            let key = SigningKey::generate(KeyRole::Ksk, algorithm, timing).unwrap();
+            let parsed: SigningKey = key.to_string().parse().unwrap();
+            assert_eq!(parsed.key_tag(), key.key_tag());
+            assert_eq!(parsed.timing, timing);
+            assert_eq!(parsed.role, KeyRole::Ksk);
+            let origin = "example.lan".parse().unwrap();
+            assert_eq!(
+                rdata(&key.dnskey(&origin, 3600))[0..4],
+                [1, 1, 3, algorithm.number()]
+            );
+            let ds = key.ds(&origin, 3600);
+            assert_eq!(rdata(&ds)[..2], key.key_tag().to_be_bytes());
+            assert_eq!(rdata(&ds).len(), 4 + 32);
+        }
+        assert_eq!("ed25519".parse(), Ok(Algorithm::Ed25519));
+        assert_eq!("13".parse(), Ok(Algorithm::EcdsaP256Sha256));
+        assert!("RSASHA256".parse::<Algorithm>().is_err());
+        assert!("ksk 13 1 1 - - bm90IGEga2V5".parse::<SigningKey>().is_err());
+    }
+
+    #[test]
+    fn test_sign_zone() {
+        let now = SystemTime::now();
+        let signer = ZoneSigner::new(zone().origin, SigningPolicy::default(), vec![]);
+        assert!(signer.roll_keys(now).unwrap());
+        let signed = signer.sign(&zone(), now);
+        let dnskeys = of_type(&signed, DNSKEY);
+        assert_eq!(dnskeys.len(), 2);
+
+        // the chain leaves out the glue below the delegation, and the names are in canonical order
+        let nsec = of_type(&signed, NSEC);
+        let owners: Vec<_> = nsec
+            .iter()
+            .map(|record| record.meta().name.as_str())
+            .collect();
+        assert_eq!(
+            owners,
+            [
+                "example.lan",
+                "*.apps.example.lan",
+                "child.example.lan",
+                "ns.example.lan",
+                "WWW.example.lan"
+            ]
+        );
+        let last = rdata(nsec[4]);
+        assert_eq!(
+            last[..canonical_name(&signed.origin).len()],
+            canonical_name(&signed.origin)
+        );
+
+        // every authoritative RRset is signed once, the delegation and its glue are not
+        let signatures = of_type(&signed, RRSIG);
+        assert_eq!(signatures.len(), 6 + 5);
+        assert!(signatures.iter().all(|record| !record
+            .meta()
+            .name
+            .is_subdomain_of(&"ns.child.example.lan".parse().unwrap())));
+        let wildcard = signatures
+            .iter()
+            .find(|record| {
+                record.meta().name == "*.apps.example.lan" && rdata(record)[..2] == [0, 1]
+            })
+            .unwrap();
+        assert_eq!(rdata(wildcard)[3], 3);
+
+        // the signature of the A record of www verifies with the public key of the ZSK
+        let zsk = signer
+            .keys()
+            .into_iter()
+            .find(|key| key.role == KeyRole::Zsk)
+            .unwrap();
+        let signature = signatures
+            .iter()
+            .find(|record| record.meta().name == "www.example.lan" && rdata(record)[..2] == [0, 1])
+            .unwrap();
+        let signer_name_end = 18 + canonical_name(&signed.origin).len();
+        let mut data = rdata(signature)[..signer_name_end].to_vec();
+        data.extend(canonical_name(&"www.example.lan".parse().unwrap()));
+        data.extend([0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 192, 0, 2, 80]);
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, [&[4], zsk.public_key()].concat())
+            .verify(&data, &rdata(signature)[signer_name_end..])
+            .unwrap();
+
+        // signing again replaces the DNSSEC records
+        let again = signer.sign(&signed, now);
+        assert_eq!(again.records.len(), signed.records.len());
+        assert!(!signer.needs_signing(now + DAY));
+        assert!(signer.needs_signing(now + 4 * DAY));
+    }
+
+    #[test]
+    fn test_sign_zone_nsec3() {
+        let now = SystemTime::now();
+        let policy = SigningPolicy {
+            algorithm: Algorithm::Ed25519,
+            denial: Denial::Nsec3 {
+                iterations: 0,
+                salt: vec![],
+            },
+            ..SigningPolicy::default()
+        };
+        let signer = ZoneSigner::new(zone().origin, policy, vec![]);
+        signer.roll_keys(now).unwrap();
+        let signed = signer.sign(&zone(), now);
+        assert!(of_type(&signed, NSEC).is_empty());
+        // the names of the zone, and the empty non-terminal apps.example.lan
+        let chain = of_type(&signed, NSEC3);
+        assert_eq!(chain.len(), 6);
+
+        // https://datatracker.ietf.org/doc/html/rfc5155#appendix-A
+        let params = Nsec3Params {
+            iterations: 12,
+            salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+        };
+        let example: DnsName = "example".parse().unwrap();
+        assert_eq!(
+            hashed_owner(&params.hash(&example), &example).as_str(),
+            "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example"
+        );
+        assert_eq!(
+            hashed_owner(&params.hash(&"a.example".parse().unwrap()), &example).as_str(),
+            "35mthgpgcu1qg68fab165klnsnk3dpvl.example"
+        );
+
+        let params = Nsec3Params::of(&signed).unwrap();
+        let apex = hashed_owner(&params.hash(&signed.origin), &signed.origin);
+        assert!(chain.iter().any(|record| record.meta().name == apex));
+        let apps = hashed_owner(
+            &params.hash(&"apps.example.lan".parse().unwrap()),
+            &signed.origin,
+        );
+        let apps = chain.iter().find(|record| record.meta().name == apps);
+        // the empty non-terminal has no types: without salt, the next hashed owner ends the rdata
+        assert_eq!(apps.map(|apps| rdata(apps).len()), Some(6 + 20));
+
+        // the chain is in the order of the hashes, and each record links to the next one, the
+        // last one back to the first
+        let hashes: Vec<_> = chain
+            .iter()
+            .map(|record| record.meta().name.labels().next().unwrap().to_string())
+            .collect();
+        assert!(hashes.windows(2).all(|pair| pair[0] < pair[1]));
+        for (i, record) in chain.iter().enumerate() {
+            let rdata = rdata(record);
+            assert_eq!(rdata[5], 20);
+            assert_eq!(base32hex(&rdata[6..26]), hashes[(i + 1) % hashes.len()]);
+        }
+
+        // the DNSKEY RRset is signed by the KSK, over the RRSIG RDATA up to the signature and
+        // the DNSKEY records in canonical order
+        let dnskey_signature = of_type(&signed, RRSIG)
+            .into_iter()
+            .find(|record| rdata(record)[..2] == DNSKEY.to_be_bytes())
+            .unwrap();
+        let signature = rdata(dnskey_signature);
+        assert_eq!(signature[2], 15);
+        assert_eq!(signature.len(), 18 + 13 + 64);
+        let ksk = signer
+            .keys()
+            .into_iter()
+            .find(|key| key.role == KeyRole::Ksk)
+            .unwrap();
+        assert_eq!(signature[16..18], ksk.key_tag().to_be_bytes());
+        let mut dnskeys: Vec<_> = of_type(&signed, DNSKEY).into_iter().map(rdata).collect();
+        dnskeys.sort();
+        let mut data = signature[..31].to_vec();
+        for dnskey in dnskeys {
+            data.extend(canonical_name(&signed.origin));
+            data.extend([0, 48, 0, 1]);
+            data.extend(&signature[4..8]);
+            data.extend((dnskey.len() as u16).to_be_bytes());
+            data.extend(dnskey);
+        }
+        let public_key = UnparsedPublicKey::new(&ED25519, ksk.public_key());
+        assert!(public_key.verify(&data, &signature[31..]).is_ok());
+        data[31] ^= 1;
+        assert!(public_key.verify(&data, &signature[31..]).is_err());
+    }
+
+    #[test]
+    fn test_key_rollover() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let policy = SigningPolicy {
+            zsk_lifetime: Some(30 * DAY),
+            rollover_margin: 2 * DAY,
+            ..SigningPolicy::default()
+        };
+        let signer = ZoneSigner::new(zone().origin, policy, vec![]);
+        assert!(signer.roll_keys(start).unwrap());
+        assert!(!signer.roll_keys(start + 20 * DAY).unwrap());
+
+        // the successor is published ahead of the end of the lifetime of the current ZSK
+        assert!(signer.roll_keys(start + 28 * DAY).unwrap());
+        let zsks: Vec<_> = signer
+            .keys()
+            .into_iter()
+            .filter(|key| key.role == KeyRole::Zsk)
+            .collect();
+        assert_eq!(zsks.len(), 2);
+        assert_eq!(zsks[0].timing.retire, Some(start + 30 * DAY));
+        assert_eq!(zsks[0].timing.delete, Some(start + 32 * DAY));
+        assert_eq!(zsks[1].timing.activate, start + 30 * DAY);
+        assert!(!signer.roll_keys(start + 29 * DAY).unwrap());
+
+        let signed = signer.sign(&zone(), start + 29 * DAY);
+        assert_eq!(of_type(&signed, DNSKEY).len(), 3);
+        assert!(signer.needs_signing(start + 30 * DAY));
+        let signed = signer.sign(&zone(), start + 31 * DAY);
+        let tags: Vec<_> = of_type(&signed, RRSIG)
+            .iter()
+            .filter(|record| rdata(record)[..2] == [0, 1])
+            .map(|record| u16::from_be_bytes([rdata(record)[16], rdata(record)[17]]))
+            .collect();
+        assert!(tags.iter().all(|&tag| tag == zsks[1].key_tag()));
+
+        // the predecessor is gone once its signatures expired from caches
+        assert!(signer.roll_keys(start + 33 * DAY).unwrap());
+        assert_eq!(signer.keys().len(), 2);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{fmt::Display, io};
+
+use crate::{
+    parse::error::DnsParseError,
+    protocol::{
+        name::{DnsName, NameError},
+        response_code::ResponseCode,
+    },
+    resolver::ResponseError,
+};
+
+/// Errors that can occur while resolving a name, so callers can tell eg. timeouts apart from
+/// refused queries. Names that do not exist are not an error, but resolve to no records, see
+/// [`crate::resolver::ResolveResponse::is_nxdomain`].
+#[derive(Debug)]
+pub enum ResolveError {
+    /// No upstream responded in time
+    Timeout(String),
+    /// Sending the query or receiving the response failed
+    Io(io::Error),
+    InvalidName(NameError),
+    /// The response is malformed
+    Parse(DnsParseError),
+    /// The response does not repeat the question of the query
+    QuestionMismatch,
+    /// The upstream responded without answering the question, eg. with SERVFAIL or REFUSED
+    Response(ResponseError),
+    NoUpstreams,
+    /// The upstream, given by name, does not resolve to an address
+    UnresolvableUpstream(String),
+    /// A `quic://` upstream was given, but the `doq` feature is disabled
+    DoqDisabled,
+    /// The CNAME chain leads back to the name
+    CnameLoop(DnsName),
+    /// The CNAME chain starting at the name has more hops than allowed
+    TooManyCnames(DnsName),
+    /// Recursive resolution found no name server with an address for the zone
+    NoNameServers(DnsName),
+    /// Recursive resolution of the name took too many referrals, or had to resolve the names of
+    /// name servers too deeply nested
+    TooManyReferrals(DnsName),
+    /// The name servers of the zone referred to a zone no closer to the name
+    LameReferral(DnsName),
+    /// The zone transfer of the zone did not start with its SOA record or was for another query
+    InvalidTransfer(DnsName),
+    /// Errors of a [`crate::transport::DnsTransport`], eg. DNS over HTTPS or a custom one
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl ResolveError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ResolveError::Timeout(_))
+    }
+
+    /// The response code of an upstream that did not answer the question
+    pub fn response_code(&self) -> Option<ResponseCode> {
+        match self {
+            ResolveError::Response(e) => Some(e.response_code),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::Timeout(reason) => write!(f, "{reason}"),
+            ResolveError::Io(e) => write!(f, "{e}"),
+            ResolveError::InvalidName(e) => write!(f, "invalid domain name: {e}"),
+            ResolveError::Parse(e) => write!(f, "{e}"),
+            ResolveError::QuestionMismatch => {
+                write!(f, "response does not match the question of the query")
+            }
+            ResolveError::Response(e) => write!(f, "{e}"),
+            ResolveError::NoUpstreams => write!(f, "no upstream DNS servers configured"),
+            ResolveError::UnresolvableUpstream(upstream) => {
+                write!(f, "{upstream} does not resolve to an address")
+            }
+            ResolveError::DoqDisabled => write!(f, "DNS over QUIC requires the doq feature"),
+            ResolveError::CnameLoop(name) => write!(f, "CNAME loop at {name}"),
+            ResolveError::TooManyCnames(name) => write!(f, "too many CNAME records for {name}"),
+            ResolveError::NoNameServers(zone) => {
+                write!(f, "no address for any name server of {zone}")
+            }
+            ResolveError::TooManyReferrals(name) => {
+                write!(f, "too many referrals while resolving {name}")
+            }
+            ResolveError::LameReferral(zone) => {
+                write!(f, "lame referral from the name servers of {zone}")
+            }
+            ResolveError::InvalidTransfer(zone) => write!(f, "invalid zone transfer of {zone}"),
+            ResolveError::Transport(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResolveError::Io(e) => Some(e),
+            ResolveError::InvalidName(e) => Some(e),
+            ResolveError::Parse(e) => Some(e),
+            ResolveError::Response(e) => Some(e),
+            ResolveError::Transport(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ResolveError {
+    fn from(e: io::Error) -> Self {
+        // depending on the platform, a read timeout is reported as either of those
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                ResolveError::Timeout(e.to_string())
+            }
+            _ => ResolveError::Io(e),
+        }
+    }
+}
+
+impl From<NameError> for ResolveError {
+    fn from(e: NameError) -> Self {
+        ResolveError::InvalidName(e)
+    }
+}
+
+impl From<DnsParseError> for ResolveError {
+    fn from(e: DnsParseError) -> Self {
+        ResolveError::Parse(e)
+    }
+}
+
+impl From<ResponseError> for ResolveError {
+    fn from(e: ResponseError) -> Self {
+        ResolveError::Response(e)
+    }
+}
+
+/// Errors of transports are taken apart again where they are IO errors
+impl From<Box<dyn std::error::Error + Send + Sync>> for ResolveError {
+    fn from(e: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        let e = match e.downcast::<ResolveError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => (*e).into(),
+            Err(e) => ResolveError::Transport(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::ResolveError;
+
+    #[test]
+    fn test_resolve_error_from_transport() {
+        let timeout: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(io::Error::new(io::ErrorKind::TimedOut, "no response"));
+        assert!(ResolveError::from(timeout).is_timeout());
+
+        let nested: Box<dyn std::error::Error + Send + Sync> = Box::new(ResolveError::NoUpstreams);
+        assert!(matches!(
+            ResolveError::from(nested),
+            ResolveError::NoUpstreams
+        ));
+
+        let other: Box<dyn std::error::Error + Send + Sync> = "handshake failed".into();
+        let error = ResolveError::from(other);
+        assert!(matches!(error, ResolveError::Transport(_)));
+        assert_eq!(error.to_string(), "handshake failed");
+    }
+}
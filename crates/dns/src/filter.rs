@@ -1,5 +1,1499 @@
-/// This module houses all code related to creating and handling filter rules.
+//! This module houses all code related to creating and handling filter rules.
 
-pub fn is_domain_blacklisted(domain: &str) -> bool {
-    domain.eq("google.de")
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use regex::{Regex, RegexSet, RegexSetBuilder};
+
+use crate::{
+    hosts::version,
+    parse::parser::DnsParser,
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        header::{Flags, Header},
+        name::DnsName,
+        packet::Packet,
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    transport::https::get_async,
+};
+
+/// TTL of the responses to blocked names by default, see [`Blocklist::ttl`]
+pub const BLOCKED_TTL: usize = 60;
+
+/// Primary name server of the SOA records negative responses to blocked names carry, under the
+/// special-use `.invalid` so it never resolves
+/// https://datatracker.ietf.org/doc/html/rfc6761#section-6.4
+const BLOCKED_SOA_NAME: &str = "blocked.invalid";
+
+/// Names hosts-format blocklists commonly list for the local machine, which are never blocked
+const LOCAL_NAMES: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-allnodes",
+    "ip6-allrouters",
+];
+
+/// How queries for blocked names are answered. Responses without any records carry an SOA
+/// record in the authority section, which lets clients cache them for the TTL of the blocklist
+/// https://datatracker.ietf.org/doc/html/rfc2308#section-3
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockResponse {
+    /// The name does not exist
+    #[default]
+    NxDomain,
+    /// The name exists, but without records of any type
+    NoData,
+    /// A and AAAA questions are answered with `0.0.0.0` and `::`, other questions without any
+    /// records
+    NullAddress,
+    /// The query is refused, without any records
+    Refused,
+    /// A and AAAA questions are answered with these addresses, eg. of a landing page explaining
+    /// the block, other questions and questions for a missing family without any records
+    Redirect {
+        ipv4: Option<Ipv4Addr>,
+        ipv6: Option<Ipv6Addr>,
+    },
+}
+
+impl FromStr for BlockResponse {
+    type Err = String;
+
+    /// Parses `nxdomain`, `nodata`, `null`, `refused`, or up to one IPv4 and one IPv6 address
+    /// to redirect to, separated by a comma like `192.0.2.80,2001:db8::80`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nxdomain" => return Ok(Self::NxDomain),
+            "nodata" => return Ok(Self::NoData),
+            "null" | "0.0.0.0" => return Ok(Self::NullAddress),
+            "refused" => return Ok(Self::Refused),
+            _ => {}
+        }
+        let (mut ipv4, mut ipv6) = (None, None);
+        for address in s.split(',') {
+            match address.parse::<IpAddr>() {
+                Ok(IpAddr::V4(address)) if ipv4.is_none() => ipv4 = Some(address),
+                Ok(IpAddr::V6(address)) if ipv6.is_none() => ipv6 = Some(address),
+                _ => {
+                    return Err(format!(
+                        "unknown block response {s}, expected nxdomain, nodata, null, refused or \
+                         addresses"
+                    ))
+                }
+            }
+        }
+        Ok(Self::Redirect { ipv4, ipv6 })
+    }
+}
+
+/// A blocklist file, with the response to its names if it differs from the one of the
+/// [`Blocklist`]. Parsed from `path`, or `response=path` like `refused=/etc/lists/malware.txt`.
+///
+/// Lists given as `http://` or `https://` URL are downloaded with
+/// [`BlocklistFile::download_async`] and kept at `path`, which is named after the URL. Until the
+/// first download they are empty, afterwards the kept copy is used while the URL cannot be
+/// reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlocklistFile {
+    pub path: PathBuf,
+    pub response: Option<BlockResponse>,
+    /// Where the list is downloaded from, if it is not a local file
+    pub url: Option<String>,
+}
+
+impl BlocklistFile {
+    /// Keeps downloaded lists in `directory` instead of the working directory, local files are
+    /// left as they are
+    pub fn in_directory(mut self, directory: &Path) -> Self {
+        if self.url.is_some() {
+            self.path = directory.join(&self.path);
+        }
+        self
+    }
+
+    /// Downloads the list from its URL to its path, waiting at most `timeout`, and returns
+    /// whether it changed. Requests are conditional on the `ETag` and `Last-Modified` of the
+    /// previous download, which are kept in `<path>.headers`. The file is replaced at once, so
+    /// it is never read half written.
+    pub async fn download_async(&self, timeout: Duration) -> io::Result<bool> {
+        let Some(url) = &self.url else {
+            return Ok(false);
+        };
+        let headers_path = self.path.with_extension(match self.path.extension() {
+            Some(extension) => format!("{}.headers", extension.to_string_lossy()),
+            None => "headers".to_string(),
+        });
+        // without the file, the previous headers would keep it from being downloaded again
+        let previous = if self.path.exists() {
+            std::fs::read_to_string(&headers_path).unwrap_or_default()
+        } else {
+            String::new()
+        };
+        let previous: Vec<_> = previous
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .filter_map(|(name, value)| match name {
+                "etag" => Some(("If-None-Match", value)),
+                "last-modified" => Some(("If-Modified-Since", value)),
+                _ => None,
+            })
+            .collect();
+
+        let response = get_async(url, &previous, timeout).await?;
+        match response.status {
+            200 => {}
+            304 => return Ok(false),
+            status => {
+                return Err(io::Error::other(format!(
+                    "{url} responded with HTTP {status}"
+                )))
+            }
+        }
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let partial = self.path.with_extension("partial");
+        std::fs::write(&partial, &response.body)?;
+        std::fs::rename(&partial, &self.path)?;
+
+        let mut headers = String::new();
+        for name in ["etag", "last-modified"] {
+            if let Some(value) = response.headers.get(name) {
+                headers.push_str(&format!("{name}: {value}\n"));
+            }
+        }
+        std::fs::write(&headers_path, headers)?;
+        Ok(true)
+    }
+
+    /// Modification time and length of the file, `None` if it is a list that was not
+    /// downloaded yet
+    fn version(&self) -> io::Result<Option<(SystemTime, u64)>> {
+        match version(&self.path) {
+            Ok(version) => Ok(Some(version)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.url.is_some() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl FromStr for BlocklistFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (response, path) = match s.split_once('=') {
+            Some((response, path)) if response.parse::<BlockResponse>().is_ok() => {
+                (response.parse().ok(), path)
+            }
+            _ => (None, s),
+        };
+        let Some(rest) = path
+            .strip_prefix("https://")
+            .or_else(|| path.strip_prefix("http://"))
+        else {
+            return Ok(Self {
+                path: path.into(),
+                response,
+                url: None,
+            });
+        };
+        let name: String = rest
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        Ok(Self {
+            path: name.into(),
+            response,
+            url: Some(path.to_string()),
+        })
+    }
+}
+
+/// A network like `192.0.2.0/24` or `2001:db8::/32`, or a single address without a prefix
+/// length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Whether `address` is in this network, IPv4 addresses mapped to IPv6 count as IPv4
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(address: IpAddr) -> Self {
+        let prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        Self {
+            address,
+            prefix_len,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid address {address}"))?;
+        let net = Self::from(address);
+        if prefix_len.is_empty() {
+            return Ok(net);
+        }
+        match prefix_len.parse() {
+            Ok(prefix_len) if prefix_len <= net.prefix_len => Ok(Self {
+                address,
+                prefix_len,
+            }),
+            _ => Err(format!("invalid prefix length {prefix_len} of {address}")),
+        }
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// A name that is never blocked, for all clients or only the ones in some networks. Parsed
+/// from `name [network...]`, where the name is allowed exactly, or only its subdomains as
+/// `*.example.com`, and the networks are like `192.0.2.0/24` or a single address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowRule {
+    name: DnsName,
+    scope: Scope,
+    /// Networks of the clients the rule applies to, all clients if empty
+    clients: Vec<IpNet>,
+}
+
+impl FromStr for AllowRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let name = fields.next().ok_or("expected a name")?;
+        let (name, scope) = match name.strip_prefix("*.") {
+            Some(domain) => (domain, Scope::Subdomains),
+            None => (name, Scope::Name),
+        };
+        Ok(Self {
+            name: name.parse().map_err(|e| format!("{e}"))?,
+            scope,
+            clients: fields.map(str::parse).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Which names a rule blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    /// Only the name itself
+    Name,
+    /// Only the names below it, as given by `*.example.com`
+    Subdomains,
+    /// The name and all names below it
+    Domain,
+}
+
+/// Blocked names, stored by their labels from the root down so a name and all its subdomains
+/// share one path
+#[derive(Debug, Default)]
+struct DomainTrie {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    blocks_name: bool,
+    blocks_subdomains: bool,
+    /// Response to the names blocked by this node, if it differs from the default one. The
+    /// first list to block a name decides it.
+    response: Option<BlockResponse>,
+}
+
+impl DomainTrie {
+    fn insert(&mut self, name: &DnsName, scope: Scope, response: Option<BlockResponse>) {
+        let mut node = &mut self.root;
+        for label in reversed_labels(name) {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if !node.blocks_name && !node.blocks_subdomains {
+            self.len += 1;
+        }
+        node.blocks_name |= scope != Scope::Subdomains;
+        node.blocks_subdomains |= scope != Scope::Name;
+        node.response = node.response.or(response);
+    }
+
+    /// The most specific node blocking `name`, if any
+    fn get(&self, name: &DnsName) -> Option<&Node> {
+        let mut node = &self.root;
+        let mut found = None;
+        for label in reversed_labels(name) {
+            if node.blocks_subdomains {
+                found = Some(node);
+            }
+            match node.children.get(label.as_str()) {
+                Some(child) => node = child,
+                None => return found,
+            }
+        }
+        if node.blocks_name {
+            return Some(node);
+        }
+        found
+    }
+
+    fn contains(&self, name: &DnsName) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Removes `name`, returning whether it was inserted. Its subdomains inserted on their own
+    /// are kept.
+    fn remove(&mut self, name: &DnsName) -> bool {
+        let mut node = &mut self.root;
+        for label in reversed_labels(name) {
+            node = match node.children.get_mut(label.as_str()) {
+                Some(child) => child,
+                None => return false,
+            };
+        }
+        let inserted = node.blocks_name || node.blocks_subdomains;
+        (node.blocks_name, node.blocks_subdomains, node.response) = (false, false, None);
+        self.len -= usize::from(inserted);
+        inserted
+    }
+
+    /// The names inserted, in no particular order
+    fn names(&self) -> Vec<DnsName> {
+        let mut names = vec![];
+        let mut nodes = vec![(&self.root, vec![])];
+        while let Some((node, labels)) = nodes.pop() {
+            if node.blocks_name || node.blocks_subdomains {
+                let labels: Vec<&str> = labels.iter().rev().copied().collect();
+                names.extend(labels.join(".").parse().ok());
+            }
+            for (label, child) in &node.children {
+                let mut labels = labels.clone();
+                labels.push(&**label);
+                nodes.push((child, labels));
+            }
+        }
+        names
+    }
+}
+
+fn reversed_labels(name: &DnsName) -> impl Iterator<Item = String> + '_ {
+    let labels: Vec<_> = name.labels().collect();
+    labels.into_iter().rev().map(str::to_ascii_lowercase)
+}
+
+/// A single line of a list, see [`Blocklist`]
+#[derive(Debug, PartialEq, Eq)]
+enum Rule<'a> {
+    Name(&'a str, Scope),
+    Regex(String),
+}
+
+impl<'a> Rule<'a> {
+    /// Parses an adblock-style rule like `||ads.example.com^`. Rules are turned into regexes if
+    /// they are not anchored at both ends of a name or contain wildcards.
+    /// https://adguard-dns.io/kb/general/dns-filtering-syntax/#basic-rules
+    fn adblock(rule: &'a str) -> Self {
+        let (body, start, subdomains) = match rule.strip_prefix("||") {
+            Some(body) => (body, r"^(.*\.)?", true),
+            None => match rule.strip_prefix('|') {
+                Some(body) => (body, "^", false),
+                None => (rule, "", false),
+            },
+        };
+        let (body, end) = match body.strip_suffix('^') {
+            Some(body) => (body, "$"),
+            None => (body, ""),
+        };
+        if !body.contains('*') && !start.is_empty() && !end.is_empty() {
+            return Self::Name(
+                body,
+                if subdomains {
+                    Scope::Domain
+                } else {
+                    Scope::Name
+                },
+            );
+        }
+        let body: Vec<_> = body.split('*').map(regex::escape).collect();
+        Self::Regex(format!("{start}{}{end}", body.join(".*")))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    trie: DomainTrie,
+    /// Regexes of the lists, followed by the ones given with [`Blocklist::add_regex`]
+    regexes: Vec<String>,
+    /// Response to the names matched by each regex, if it differs from the default one
+    regex_responses: Vec<Option<BlockResponse>>,
+    matcher: RegexSet,
+    /// Names never blocked for any client
+    allowed: DomainTrie,
+    /// Names never blocked for the clients in a network
+    client_allowed: Vec<(IpNet, DomainTrie)>,
+    /// Modification time and length of each file when it was read, `None` for lists that were
+    /// not downloaded yet
+    versions: Vec<Option<(SystemTime, u64)>>,
+    /// Names given with [`Blocklist::add_blocked`], which are kept when the files are read again
+    added: DomainTrie,
+    /// Names given with [`Blocklist::add_allowed`], as above
+    added_allowed: DomainTrie,
+}
+
+impl Rules {
+    /// Parses a blocklist whose names are answered with `response`, unless a line of a domain
+    /// list names another one after the domain like `ads.example.com refused`
+    fn parse(&mut self, contents: &str, response: Option<BlockResponse>) {
+        for line in contents.lines() {
+            let line = line.trim();
+            if let Some(regex) = line
+                .strip_prefix('/')
+                .and_then(|line| line.strip_suffix('/'))
+            {
+                // invalid regexes are skipped like invalid names
+                if Regex::new(regex).is_ok() {
+                    self.regexes.push(regex.to_string());
+                    self.regex_responses.push(response);
+                }
+                continue;
+            }
+            // comments and headers of adblock lists, rules with modifiers meant for browsers and
+            // cosmetic rules hiding page elements
+            if line.starts_with(['!', '['])
+                || line.contains('$')
+                || ["##", "#@#", "#?#"]
+                    .iter()
+                    .any(|cosmetic| line.contains(cosmetic))
+            {
+                continue;
+            }
+
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(first) = fields.next() else {
+                continue;
+            };
+            if let Some(exception) = first.strip_prefix("@@") {
+                // exceptions with wildcards are not supported
+                if let Rule::Name(name, scope) = Rule::adblock(exception) {
+                    if let Ok(name) = name.parse() {
+                        self.allowed.insert(&name, scope, None);
+                    }
+                }
+                continue;
+            }
+            let rule = if first.parse::<IpAddr>().is_ok() {
+                for name in fields {
+                    self.insert(Rule::Name(name, Scope::Name), response);
+                }
+                continue;
+            } else if let Some(domain) = first
+                .strip_prefix("*.")
+                .filter(|domain| !domain.contains(['*', '|', '^']))
+            {
+                Rule::Name(domain, Scope::Subdomains)
+            } else if first.contains(['*', '|', '^']) {
+                Rule::adblock(first)
+            } else {
+                Rule::Name(first, Scope::Domain)
+            };
+            let response = match fields.next().map(str::parse) {
+                Some(Ok(response)) => Some(response),
+                Some(Err(_)) => continue,
+                None => response,
+            };
+            self.insert(rule, response);
+        }
+    }
+
+    /// Parses an allowlist with an [`AllowRule`] per line, skipping the invalid ones
+    fn parse_allowlist(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            if let Ok(rule) = line.parse() {
+                self.allow(&rule);
+            }
+        }
+    }
+
+    fn allow(&mut self, rule: &AllowRule) {
+        if rule.clients.is_empty() {
+            return self.allowed.insert(&rule.name, rule.scope, None);
+        }
+        for &client in &rule.clients {
+            match self
+                .client_allowed
+                .iter_mut()
+                .find(|(net, _)| *net == client)
+            {
+                Some((_, allowed)) => allowed.insert(&rule.name, rule.scope, None),
+                None => {
+                    let mut allowed = DomainTrie::default();
+                    allowed.insert(&rule.name, rule.scope, None);
+                    self.client_allowed.push((client, allowed));
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, rule: Rule, response: Option<BlockResponse>) {
+        let (name, scope) = match rule {
+            Rule::Name(name, scope) => (name, scope),
+            Rule::Regex(regex) => {
+                self.regexes.push(regex);
+                return self.regex_responses.push(response);
+            }
+        };
+        if LOCAL_NAMES.contains(&name) || name.parse::<IpAddr>().is_ok() {
+            return;
+        }
+        if let Ok(name) = name.parse() {
+            self.trie.insert(&name, scope, response);
+        }
+    }
+
+    /// Compiles the regexes of the lists together with `extra`, into one matcher run once per
+    /// query
+    fn compile(&mut self, extra: &[String]) -> Result<(), regex::Error> {
+        self.regexes.extend_from_slice(extra);
+        self.regex_responses.resize(self.regexes.len(), None);
+        self.matcher = RegexSetBuilder::new(&self.regexes)
+            .case_insensitive(true)
+            .build()?;
+        Ok(())
+    }
+
+    /// The response to `name` if it is blocked for `client`, or else `None`. Allowed names take
+    /// precedence over blocked ones, and names fall back to the `default` response.
+    fn response(
+        &self,
+        name: &DnsName,
+        client: IpAddr,
+        default: BlockResponse,
+    ) -> Option<BlockResponse> {
+        if self.allowed.contains(name)
+            || self.added_allowed.contains(name)
+            || self
+                .client_allowed
+                .iter()
+                .any(|(net, allowed)| net.contains(client) && allowed.contains(name))
+        {
+            return None;
+        }
+        let response = match self.trie.get(name).or_else(|| self.added.get(name)) {
+            Some(node) => node.response,
+            None => {
+                let regex = self.matcher.matches(name.as_str()).into_iter().next()?;
+                self.regex_responses[regex]
+            }
+        };
+        Some(response.unwrap_or(default))
+    }
+
+    fn read(blocklist: &Blocklist) -> io::Result<Self> {
+        let mut rules = Self::default();
+        for file in &blocklist.files {
+            let version = file.version()?;
+            rules.versions.push(version);
+            if version.is_some() {
+                rules.parse(&std::fs::read_to_string(&file.path)?, file.response);
+            }
+        }
+        for path in &blocklist.allowlists {
+            rules.versions.push(Some(version(path)?));
+            rules.parse_allowlist(&std::fs::read_to_string(path)?);
+        }
+        for rule in &blocklist.allowed {
+            rules.allow(rule);
+        }
+        rules
+            .compile(&blocklist.regexes)
+            .map_err(io::Error::other)?;
+        Ok(rules)
+    }
+}
+
+/// Names to block, read from hosts files like `0.0.0.0 ads.example.com` as Pi-hole and most
+/// published lists use them, from domain lists with one name per line, or from adblock-style
+/// lists. Comments start with `#`, or `!` in adblock lists.
+///
+/// Names of hosts files are blocked exactly, names of domain lists with all their subdomains.
+/// Domain list entries like `*.example.com` block only the subdomains. Adblock rules like
+/// `||example.com^` block the name with its subdomains, `|example.com^` only the name, and
+/// rules with `*` wildcards or without anchors like `ads*.example.` any name they match. Lines
+/// like `/^ad[0-9]+\./` are regexes matched against the whole name, ignoring case. Rules with `$`
+/// modifiers and cosmetic rules of adblock lists are skipped.
+///
+/// Names allowed by an allowlist, an [`AllowRule`] or an adblock exception like
+/// `@@||example.com^` are never blocked, whatever the blocklists say. Allow rules may apply to
+/// some clients only.
+///
+/// Blocked names are answered with the [`BlockResponse`] of their domain list line, like
+/// `ads.example.com refused`, or else the one of their [`BlocklistFile`], or else the one of the
+/// blocklist.
+///
+/// Names can also be blocked and allowed while the blocklist is in use, and blocking can be
+/// paused altogether, see [`Blocklist::add_blocked`] and [`Blocklist::pause`].
+#[derive(Debug)]
+pub struct Blocklist {
+    /// Files the names were read from, if any
+    files: Vec<BlocklistFile>,
+    /// Files the allowed names were read from
+    allowlists: Vec<PathBuf>,
+    /// Rules given with [`Blocklist::allow`], which are kept when the files are read again
+    allowed: Vec<AllowRule>,
+    /// Regexes given with [`Blocklist::add_regex`], which are kept when the files are read again
+    regexes: Vec<String>,
+    rules: RwLock<Rules>,
+    response: BlockResponse,
+    ttl: usize,
+    /// Milliseconds since 1970 until which no names are blocked, 0 if blocking is not paused
+    /// and [`u64::MAX`] until it is resumed
+    paused_until: AtomicU64,
+}
+
+/// Whether a [`Blocklist`] blocks names, see [`Blocklist::pause`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockingState {
+    Enabled,
+    /// Until the time given, or until resumed if `None`
+    Paused(Option<SystemTime>),
+}
+
+impl Default for Blocklist {
+    fn default() -> Self {
+        Self {
+            files: vec![],
+            allowlists: vec![],
+            allowed: vec![],
+            regexes: vec![],
+            rules: RwLock::default(),
+            response: BlockResponse::default(),
+            ttl: BLOCKED_TTL,
+            paused_until: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Blocklist {
+    /// Names from `contents` in any of the formats, which are not backed by any file
+    pub fn parse(contents: &str) -> Self {
+        let mut rules = Rules::default();
+        rules.parse(contents, None);
+        // the regexes were validated one by one
+        rules.compile(&[]).expect("valid regexes");
+        Self {
+            rules: RwLock::new(rules),
+            ..Self::default()
+        }
+    }
+
+    /// Reads the lists at `paths`, blocking the names of all of them
+    pub fn load(paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> io::Result<Self> {
+        Self::load_files(paths.into_iter().map(|path| BlocklistFile {
+            path: path.into(),
+            response: None,
+            url: None,
+        }))
+    }
+
+    /// Reads the lists of `files`, blocking the names of all of them
+    pub fn load_files(files: impl IntoIterator<Item = BlocklistFile>) -> io::Result<Self> {
+        let blocklist = Self {
+            files: files.into_iter().collect(),
+            ..Self::default()
+        };
+        blocklist.reload()?;
+        Ok(blocklist)
+    }
+
+    /// Reads the allowlists at `paths` with an [`AllowRule`] per line, whose names are never
+    /// blocked. Comments start with `#`.
+    pub fn allowlists(
+        mut self,
+        paths: impl IntoIterator<Item = impl Into<PathBuf>>,
+    ) -> io::Result<Self> {
+        self.allowlists = paths.into_iter().map(Into::into).collect();
+        self.reload()?;
+        Ok(self)
+    }
+
+    /// The lists the names are read from
+    pub fn files(&self) -> &[BlocklistFile] {
+        &self.files
+    }
+
+    /// Never blocks the names of `rule`
+    pub fn allow(&mut self, rule: AllowRule) {
+        self.rules.get_mut().unwrap().allow(&rule);
+        self.allowed.push(rule);
+    }
+
+    /// Also blocks the names matching `regex`, ignoring case. The blocklist is unchanged if the
+    /// regex is invalid.
+    pub fn add_regex(&mut self, regex: &str) -> Result<(), String> {
+        Regex::new(regex).map_err(|e| e.to_string())?;
+        let rules = self.rules.get_mut().unwrap();
+        rules.regexes.push(regex.to_string());
+        if let Err(e) = rules.compile(&[]) {
+            rules.regexes.pop();
+            return Err(e.to_string());
+        }
+        self.regexes.push(regex.to_string());
+        Ok(())
+    }
+
+    /// How queries for blocked names are answered, unless their line or file says otherwise.
+    /// NXDOMAIN by default.
+    pub fn response(mut self, response: BlockResponse) -> Self {
+        self.response = response;
+        self
+    }
+
+    /// TTL of the responses to blocked names, [`BLOCKED_TTL`] by default
+    pub fn ttl(mut self, ttl: usize) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Reads the files again. The previous names are kept if any of them cannot be read.
+    pub fn reload(&self) -> io::Result<()> {
+        let mut rules = Rules::read(self)?;
+        let mut current = self.rules.write().unwrap();
+        rules.added = std::mem::take(&mut current.added);
+        rules.added_allowed = std::mem::take(&mut current.added_allowed);
+        *current = rules;
+        Ok(())
+    }
+
+    /// Also blocks `name` with its subdomains, like a line of a domain list, until it is
+    /// removed with [`Blocklist::remove_blocked`]
+    pub fn add_blocked(&self, name: &DnsName) {
+        let mut rules = self.rules.write().unwrap();
+        rules.added.insert(name, Scope::Domain, None);
+    }
+
+    /// No longer blocks `name` if it was given to [`Blocklist::add_blocked`], returning whether
+    /// it was. Names of the files stay blocked.
+    pub fn remove_blocked(&self, name: &DnsName) -> bool {
+        self.rules.write().unwrap().added.remove(name)
+    }
+
+    /// Never blocks `name`, like a line of an allowlist, until it is removed with
+    /// [`Blocklist::remove_allowed`]
+    pub fn add_allowed(&self, name: &DnsName) {
+        let mut rules = self.rules.write().unwrap();
+        rules.added_allowed.insert(name, Scope::Name, None);
+    }
+
+    /// No longer allows `name` if it was given to [`Blocklist::add_allowed`], returning whether
+    /// it was
+    pub fn remove_allowed(&self, name: &DnsName) -> bool {
+        self.rules.write().unwrap().added_allowed.remove(name)
+    }
+
+    /// The names given to [`Blocklist::add_blocked`] and [`Blocklist::add_allowed`], sorted
+    pub fn added(&self) -> (Vec<DnsName>, Vec<DnsName>) {
+        let rules = self.rules.read().unwrap();
+        let sorted = |mut names: Vec<DnsName>| {
+            names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            names
+        };
+        (
+            sorted(rules.added.names()),
+            sorted(rules.added_allowed.names()),
+        )
+    }
+
+    /// Stops blocking any name for `duration`, or until [`Blocklist::resume`] without one
+    pub fn pause(&self, duration: Option<Duration>) {
+        let until = match duration {
+            Some(duration) => (SystemTime::now() + duration)
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |until| until.as_millis() as u64)
+                .max(1),
+            None => u64::MAX,
+        };
+        self.paused_until.store(until, Ordering::Relaxed);
+    }
+
+    /// Blocks names again after [`Blocklist::pause`]
+    pub fn resume(&self) {
+        self.paused_until.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether names are blocked right now
+    pub fn state(&self) -> BlockingState {
+        match self.paused_until.load(Ordering::Relaxed) {
+            0 => BlockingState::Enabled,
+            u64::MAX => BlockingState::Paused(None),
+            millis => {
+                let until = UNIX_EPOCH + Duration::from_millis(millis);
+                match SystemTime::now() < until {
+                    true => BlockingState::Paused(Some(until)),
+                    false => BlockingState::Enabled,
+                }
+            }
+        }
+    }
+
+    /// Reads the files again if any of them was modified since they were last read, and returns
+    /// whether one was. Meant to be called periodically, eg. every few seconds.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let versions = self
+            .files
+            .iter()
+            .map(BlocklistFile::version)
+            .chain(self.allowlists.iter().map(|path| version(path).map(Some)))
+            .collect::<io::Result<Vec<_>>>()?;
+        if self.rules.read().unwrap().versions == versions {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Whether queries of `client` for `name` are blocked
+    pub fn is_blocked(&self, name: &DnsName, client: IpAddr) -> bool {
+        self.blocked_response(name, client).is_some()
+    }
+
+    /// How queries of `client` for `name` are answered, `None` if they are not blocked
+    pub fn blocked_response(&self, name: &DnsName, client: IpAddr) -> Option<BlockResponse> {
+        if self.state() != BlockingState::Enabled {
+            return None;
+        }
+        let rules = self.rules.read().unwrap();
+        rules.response(name, client, self.response)
+    }
+
+    /// Builds the response to the raw `query` of `client` if its question asks for a name
+    /// blocked for it
+    pub fn respond(&self, query: &[u8], client: IpAddr) -> Option<Vec<u8>> {
+        let query = DnsParser::new(query).parse_packet().ok()?;
+        let [question] = &query.questions[..] else {
+            return None;
+        };
+        let response = self.blocked_response(&question.domain_name, client)?;
+
+        let meta = AnswerMeta {
+            name: question.domain_name.clone(),
+            r#type: question.r#type,
+            class: question.class,
+            ttl: self.ttl,
+            len: 0,
+        };
+        let (ipv4, ipv6) = match response {
+            BlockResponse::NxDomain | BlockResponse::NoData | BlockResponse::Refused => {
+                (None, None)
+            }
+            BlockResponse::NullAddress => {
+                (Some(Ipv4Addr::UNSPECIFIED), Some(Ipv6Addr::UNSPECIFIED))
+            }
+            BlockResponse::Redirect { ipv4, ipv6 } => (ipv4, ipv6),
+        };
+        let answers: Vec<_> = match question.r#type {
+            RecordType::A => ipv4
+                .map(|ipv4| Answer::A { meta, ipv4 })
+                .into_iter()
+                .collect(),
+            RecordType::AAAA => ipv6
+                .map(|ipv6| Answer::AAAA { meta, ipv6 })
+                .into_iter()
+                .collect(),
+            _ => vec![],
+        };
+        let response_code = match response {
+            BlockResponse::NxDomain => ResponseCode::NXDomain,
+            BlockResponse::Refused => ResponseCode::Refused,
+            _ => ResponseCode::NoError,
+        };
+        let authorities = if answers.is_empty() && response_code != ResponseCode::Refused {
+            vec![self.soa(&question.domain_name)]
+        } else {
+            vec![]
+        };
+
+        let response = Packet {
+            header: Header {
+                request_id: query.header.request_id,
+                flags: Flags {
+                    query: false,
+                    opcode: query.header.flags.opcode,
+                    recursion_desired: query.header.flags.recursion_desired,
+                    recursion_available: true,
+                    checking_disabled: query.header.flags.checking_disabled,
+                    response_code,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: query.questions,
+            answers,
+            authorities,
+            ..Packet::default()
+        };
+        Some(response.to_bytes())
+    }
+
+    /// The SOA record of negative responses to the blocked `name`, which is made the apex of
+    /// a zone of its own so clients cache the response for the TTL of the blocklist
+    fn soa(&self, name: &DnsName) -> Answer {
+        let mname = DnsName::new(BLOCKED_SOA_NAME).expect("valid name");
+        Answer::SOA {
+            meta: AnswerMeta {
+                name: name.clone(),
+                r#type: RecordType::SOA,
+                class: Class::IN,
+                ttl: self.ttl,
+                len: 0,
+            },
+            rname: DnsName::new(&format!("hostmaster.{BLOCKED_SOA_NAME}")).expect("valid name"),
+            mname,
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: self.ttl as u32,
+        }
+    }
+
+    /// Number of rules, counting a name blocked with its subdomains once
+    pub fn len(&self) -> usize {
+        let rules = self.rules.read().unwrap();
+        rules.trie.len + rules.added.len + rules.regexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{IpAddr, Ipv4Addr, TcpListener},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{
+            answer::Answer, name::DnsName, query::QueryBuilder, record_type::RecordType,
+            response_code::ResponseCode,
+        },
+    };
+
+    use super::{
+        AllowRule, BlockResponse, BlockingState, Blocklist, BlocklistFile, IpNet, Rule, Scope,
+        BLOCKED_TTL,
+    };
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    const LIST: &str = "
+        # hosts format
+        127.0.0.1 localhost
+        0.0.0.0   0.0.0.0
+        0.0.0.0   ads.example.com tracker.example.com # inline comment
+        # domain list
+        doubleclick.net
+        *.telemetry.example.org
+    ";
+
+    #[test]
+    fn test_blocklist_matching() {
+        let blocklist = Blocklist::parse(LIST);
+        let blocked = |name: &str| blocklist.is_blocked(&name.parse().unwrap(), CLIENT);
+
+        assert!(blocked("ads.example.com"));
+        assert!(blocked("Tracker.Example.com"));
+        assert!(!blocked("cdn.ads.example.com"));
+        assert!(!blocked("example.com"));
+
+        assert!(blocked("doubleclick.net"));
+        assert!(blocked("stats.g.doubleclick.net"));
+        assert!(!blocked("notdoubleclick.net"));
+
+        assert!(blocked("eu.telemetry.example.org"));
+        assert!(!blocked("telemetry.example.org"));
+
+        assert!(!blocked("localhost"));
+        assert_eq!(blocklist.len(), 4);
+    }
+
+    #[test]
+    fn test_blocklist_adblock_rules() {
+        assert_eq!(
+            Rule::adblock("||ads.example.com^"),
+            Rule::Name("ads.example.com", Scope::Domain)
+        );
+        assert_eq!(
+            Rule::adblock("|ads.example.com^"),
+            Rule::Name("ads.example.com", Scope::Name)
+        );
+        assert_eq!(
+            Rule::adblock("||ad*.example.com^"),
+            Rule::Regex(r"^(.*\.)?ad.*\.example\.com$".to_string())
+        );
+
+        let mut blocklist = Blocklist::parse(
+            "
+            [Adblock Plus 2.0]
+            ! Title: test list
+            ||ads.example.com^
+            |exact.example.net^
+            ||metrics*.example.org^
+            ||cdn.example.com^$third-party
+            @@||good.example.com^
+            example.com##.banner
+            /^track[0-9]+\\./
+            ",
+        );
+        let blocked = |blocklist: &Blocklist, name: &str| {
+            blocklist.is_blocked(&name.parse().unwrap(), CLIENT)
+        };
+
+        assert!(blocked(&blocklist, "ads.example.com"));
+        assert!(blocked(&blocklist, "eu.ads.example.com"));
+        assert!(blocked(&blocklist, "exact.example.net"));
+        assert!(!blocked(&blocklist, "www.exact.example.net"));
+        assert!(blocked(&blocklist, "metrics-eu.example.org"));
+        assert!(blocked(&blocklist, "a.metrics.b.example.org"));
+        assert!(!blocked(&blocklist, "example.org"));
+        assert!(blocked(&blocklist, "Track42.example.com"));
+        assert!(!blocked(&blocklist, "tracker.example.com"));
+        assert!(!blocked(&blocklist, "cdn.example.com"));
+        assert!(!blocked(&blocklist, "good.example.com"));
+        assert!(!blocked(&blocklist, "example.com"));
+        assert_eq!(blocklist.len(), 4);
+
+        assert!(blocklist.add_regex("(unclosed").is_err());
+        blocklist.add_regex(r"^telemetry\.").unwrap();
+        assert!(blocked(&blocklist, "telemetry.example.com"));
+        assert!(blocked(&blocklist, "ads.example.com"));
+        assert_eq!(blocklist.len(), 5);
+    }
+
+    #[test]
+    fn test_ip_net_contains() {
+        let net: IpNet = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains("192.0.2.200".parse().unwrap()));
+        assert!(net.contains("::ffff:192.0.2.7".parse().unwrap()));
+        assert!(!net.contains("192.0.3.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        let host: IpNet = "192.0.2.1".parse().unwrap();
+        assert_eq!(host.to_string(), "192.0.2.1/32");
+        assert!(host.contains(CLIENT));
+        assert!(!host.contains("192.0.2.2".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(CLIENT));
+        assert!("192.0.2.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_blocklist_allowlist() {
+        let mut blocklist = Blocklist::parse(
+            "
+            doubleclick.net
+            ||example.com^
+            @@||cdn.example.com^
+            ",
+        );
+        let blocked = |blocklist: &Blocklist, name: &str, client: &str| {
+            blocklist.is_blocked(&name.parse().unwrap(), client.parse().unwrap())
+        };
+        assert!(blocked(&blocklist, "ads.example.com", "192.0.2.1"));
+        assert!(!blocked(&blocklist, "cdn.example.com", "192.0.2.1"));
+        assert!(!blocked(&blocklist, "eu.cdn.example.com", "192.0.2.1"));
+
+        blocklist.allow("ads.example.com".parse().unwrap());
+        assert!(!blocked(&blocklist, "ads.example.com", "192.0.2.1"));
+        assert!(blocked(&blocklist, "www.ads.example.com", "192.0.2.1"));
+
+        let rule: AllowRule = "*.doubleclick.net 192.0.2.0/28 2001:db8::7"
+            .parse()
+            .unwrap();
+        blocklist.allow(rule);
+        assert!(!blocked(&blocklist, "ad.doubleclick.net", "192.0.2.9"));
+        assert!(!blocked(&blocklist, "ad.doubleclick.net", "2001:db8::7"));
+        assert!(blocked(&blocklist, "ad.doubleclick.net", "192.0.2.99"));
+        assert!(blocked(&blocklist, "doubleclick.net", "192.0.2.9"));
+
+        assert!("".parse::<AllowRule>().is_err());
+        assert!("example.com not-a-network".parse::<AllowRule>().is_err());
+    }
+
+    #[test]
+    fn test_blocklist_added() {
+        let blocklist = Blocklist::parse(LIST);
+        let blocked = |name: &str| blocklist.is_blocked(&name.parse().unwrap(), CLIENT);
+        let name = |name: &str| name.parse::<DnsName>().unwrap();
+
+        blocklist.add_blocked(&name("tracker.example.net"));
+        blocklist.add_blocked(&name("example.org"));
+        assert!(blocked("tracker.example.net"));
+        assert!(blocked("eu.tracker.example.net"));
+        assert_eq!(blocklist.len(), 6);
+        blocklist.add_allowed(&name("ads.example.com"));
+        blocklist.add_allowed(&name("www.example.org"));
+        assert!(!blocked("ads.example.com"));
+        assert!(!blocked("www.example.org"));
+        assert!(blocked("cdn.example.org"));
+        assert_eq!(
+            blocklist.added(),
+            (
+                vec![name("example.org"), name("tracker.example.net")],
+                vec![name("ads.example.com"), name("www.example.org")]
+            )
+        );
+
+        // kept when the files are read again
+        blocklist.reload().unwrap();
+        assert!(blocked("tracker.example.net"));
+        assert!(blocklist.remove_blocked(&name("tracker.example.net")));
+        assert!(!blocklist.remove_blocked(&name("tracker.example.net")));
+        assert!(!blocked("tracker.example.net"));
+        assert!(blocklist.remove_allowed(&name("www.example.org")));
+        assert!(blocked("www.example.org"));
+        assert!(!blocklist.remove_blocked(&name("www.example.org")));
+        assert_eq!(blocklist.added().0, [name("example.org")]);
+    }
+
+    #[test]
+    fn test_blocklist_pause() {
+        let blocklist = Blocklist::parse(LIST);
+        let blocked = || blocklist.is_blocked(&"ads.example.com".parse().unwrap(), CLIENT);
+        assert_eq!(blocklist.state(), BlockingState::Enabled);
+
+        blocklist.pause(None);
+        assert_eq!(blocklist.state(), BlockingState::Paused(None));
+        assert!(!blocked());
+        blocklist.resume();
+        assert!(blocked());
+
+        blocklist.pause(Some(Duration::from_secs(300)));
+        assert!(matches!(blocklist.state(), BlockingState::Paused(Some(_))));
+        assert!(!blocked());
+        // blocking again once the time is up
+        blocklist.pause(Some(Duration::ZERO));
+        assert_eq!(blocklist.state(), BlockingState::Enabled);
+        assert!(blocked());
+    }
+
+    #[test]
+    fn test_blocklist_respond() {
+        let query = |name: &str, record_type| {
+            QueryBuilder::new(name.parse().unwrap())
+                .id(9)
+                .record_type(record_type)
+                .build()
+        };
+        let blocklist = Blocklist::parse(LIST);
+        let response = blocklist
+            .respond(&query("ads.example.com", RecordType::A), CLIENT)
+            .unwrap();
+        let parsed = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(parsed.header.request_id, 9);
+        assert_eq!(parsed.header.flags.response_code, ResponseCode::NXDomain);
+        assert!(parsed.answers.is_empty());
+        let [Answer::SOA { meta, minimum, .. }] = &parsed.authorities[..] else {
+            panic!("expected an SOA record, got {:?}", parsed.authorities);
+        };
+        assert_eq!(meta.name.as_str(), "ads.example.com");
+        assert_eq!((meta.ttl, *minimum), (BLOCKED_TTL, BLOCKED_TTL as u32));
+        assert!(blocklist
+            .respond(&query("example.com", RecordType::A), CLIENT)
+            .is_none());
+
+        let blocklist = blocklist.response(BlockResponse::NullAddress);
+        let address = |record_type| {
+            let response = blocklist
+                .respond(&query("ads.example.com", record_type), CLIENT)
+                .unwrap();
+            let parsed = DnsParser::new(&response).parse_packet().unwrap();
+            assert_eq!(parsed.header.flags.response_code, ResponseCode::NoError);
+            parsed
+                .answers
+                .iter()
+                .map(|answer| match answer {
+                    Answer::A { ipv4, .. } => ipv4.to_string(),
+                    Answer::AAAA { ipv6, .. } => ipv6.to_string(),
+                    other => panic!("unexpected {other:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(address(RecordType::A), ["0.0.0.0"]);
+        assert_eq!(address(RecordType::AAAA), ["::"]);
+        assert!(address(RecordType::MX).is_empty());
+    }
+
+    #[test]
+    fn test_blocklist_responses() {
+        assert_eq!("NODATA".parse(), Ok(BlockResponse::NoData));
+        assert_eq!(
+            "192.0.2.80".parse(),
+            Ok(BlockResponse::Redirect {
+                ipv4: Some(Ipv4Addr::new(192, 0, 2, 80)),
+                ipv6: None,
+            })
+        );
+        assert!("192.0.2.80,192.0.2.81".parse::<BlockResponse>().is_err());
+        assert!("sinkhole".parse::<BlockResponse>().is_err());
+
+        let file: BlocklistFile = "refused=/etc/lists/malware.txt".parse().unwrap();
+        assert_eq!(file.response, Some(BlockResponse::Refused));
+        assert_eq!(file.path.to_str(), Some("/etc/lists/malware.txt"));
+        let file: BlocklistFile = "/etc/lists/a=b.txt".parse().unwrap();
+        assert_eq!(
+            (file.path.to_str(), file.response),
+            (Some("/etc/lists/a=b.txt"), None)
+        );
+
+        let blocklist = Blocklist::parse(
+            "
+            nxdomain.example
+            nodata.example nodata
+            refused.example refused
+            landing.example 192.0.2.80,2001:db8::80
+            ipv4.landing.example 192.0.2.81
+            invalid.example sinkhole
+            ",
+        )
+        .ttl(300);
+        let respond = |name: &str, record_type| {
+            let query = QueryBuilder::new(name.parse().unwrap())
+                .record_type(record_type)
+                .build();
+            let response = blocklist.respond(&query, CLIENT)?;
+            let parsed = DnsParser::new(&response).parse_packet().unwrap();
+            let records: Vec<_> = parsed
+                .answers
+                .iter()
+                .chain(&parsed.authorities)
+                .map(|answer| match answer {
+                    Answer::A { ipv4, .. } => ipv4.to_string(),
+                    Answer::AAAA { ipv6, .. } => ipv6.to_string(),
+                    Answer::SOA { meta, mname, .. } => {
+                        assert_eq!(meta.ttl, 300);
+                        format!("SOA {mname}")
+                    }
+                    other => panic!("unexpected {other:?}"),
+                })
+                .collect();
+            Some((parsed.header.flags.response_code, records))
+        };
+
+        let soa = || vec!["SOA blocked.invalid".to_string()];
+        assert_eq!(
+            respond("www.nxdomain.example", RecordType::A),
+            Some((ResponseCode::NXDomain, soa()))
+        );
+        assert_eq!(
+            respond("nodata.example", RecordType::A),
+            Some((ResponseCode::NoError, soa()))
+        );
+        assert_eq!(
+            respond("refused.example", RecordType::A),
+            Some((ResponseCode::Refused, vec![]))
+        );
+        assert_eq!(
+            respond("landing.example", RecordType::AAAA),
+            Some((ResponseCode::NoError, vec!["2001:db8::80".to_string()]))
+        );
+        // the most specific name decides
+        assert_eq!(
+            respond("ipv4.landing.example", RecordType::A),
+            Some((ResponseCode::NoError, vec!["192.0.2.81".to_string()]))
+        );
+        assert_eq!(
+            respond("ipv4.landing.example", RecordType::AAAA),
+            Some((ResponseCode::NoError, soa()))
+        );
+        assert_eq!(respond("invalid.example", RecordType::A), None);
+    }
+
+    /// Serves `list` over HTTP with an ETag, answering requests that already have it with 304,
+    /// and returns the URL of the list with a counter of the requests for it
+    fn list_server(list: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0u8];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let request = String::from_utf8(request).unwrap();
+                assert!(request.starts_with("GET /lists/ads.txt HTTP/1.1\r\n"));
+                let response = if request.contains("If-None-Match: \"v1\"\r\n") {
+                    "HTTP/1.1 304 Not Modified\r\n\r\n".to_string()
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\r\n{list}",
+                        list.len()
+                    )
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (format!("http://127.0.0.1:{port}/lists/ads.txt"), requests)
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_download() {
+        let (url, requests) = list_server("0.0.0.0 ads.example.com\n");
+        let directory = std::env::temp_dir().join(format!("blocklists-{}", std::process::id()));
+        let file = format!("refused={url}")
+            .parse::<BlocklistFile>()
+            .unwrap()
+            .in_directory(&directory);
+        assert_eq!(file.url.as_deref(), Some(url.as_str()));
+        assert_eq!(file.response, Some(BlockResponse::Refused));
+        assert!(file.path.starts_with(&directory));
+
+        // lists that were not downloaded yet are empty
+        let blocklist = Blocklist::load_files([file.clone()]).unwrap();
+        let ads = "ads.example.com".parse().unwrap();
+        assert!(!blocklist.is_blocked(&ads, CLIENT));
+        assert!(!blocklist.reload_if_changed().unwrap());
+
+        let timeout = Duration::from_secs(5);
+        assert!(file.download_async(timeout).await.unwrap());
+        assert!(blocklist.reload_if_changed().unwrap());
+        assert_eq!(
+            blocklist.blocked_response(&ads, CLIENT),
+            Some(BlockResponse::Refused)
+        );
+
+        // the ETag of the first download makes the server respond with 304
+        assert!(!file.download_async(timeout).await.unwrap());
+        assert!(!blocklist.reload_if_changed().unwrap());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // the kept copy is read when the list cannot be downloaded
+        let blocklist = Blocklist::load_files([file]).unwrap();
+        assert!(blocklist.is_blocked(&ads, CLIENT));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_blocklist_reload_if_changed() {
+        let dir = std::env::temp_dir();
+        let hosts = dir.join(format!("blocklist-hosts-{}", std::process::id()));
+        let domains = dir.join(format!("blocklist-domains-{}", std::process::id()));
+        std::fs::write(&hosts, "0.0.0.0 ads.example.com\n").unwrap();
+        std::fs::write(&domains, "doubleclick.net\n").unwrap();
+        let allowed = dir.join(format!("blocklist-allowed-{}", std::process::id()));
+        std::fs::write(&allowed, "ads.example.com 192.0.2.0/24\n").unwrap();
+        let files = [
+            BlocklistFile {
+                path: hosts.clone(),
+                response: Some(BlockResponse::Refused),
+                url: None,
+            },
+            domains.to_str().unwrap().parse().unwrap(),
+        ];
+        let blocklist = Blocklist::load_files(files)
+            .unwrap()
+            .allowlists([&allowed])
+            .unwrap();
+        assert_eq!(blocklist.len(), 2);
+        assert!(!blocklist.reload_if_changed().unwrap());
+        let ads = "ads.example.com".parse().unwrap();
+        assert!(!blocklist.is_blocked(&ads, CLIENT));
+        assert!(blocklist.is_blocked(&ads, "198.51.100.1".parse().unwrap()));
+
+        std::fs::write(&allowed, "# all clients\nads.example.com\n").unwrap();
+        assert!(blocklist.reload_if_changed().unwrap());
+        assert!(!blocklist.is_blocked(&ads, "198.51.100.1".parse().unwrap()));
+        let doubleclick = "doubleclick.net".parse().unwrap();
+        assert_eq!(
+            blocklist.blocked_response(&doubleclick, CLIENT),
+            Some(BlockResponse::NxDomain)
+        );
+        std::fs::write(&allowed, "").unwrap();
+        assert!(blocklist.reload_if_changed().unwrap());
+        assert_eq!(
+            blocklist.blocked_response(&ads, CLIENT),
+            Some(BlockResponse::Refused)
+        );
+
+        std::fs::write(&domains, "doubleclick.net\nads.example.org\n").unwrap();
+        assert!(blocklist.reload_if_changed().unwrap());
+        assert!(blocklist.is_blocked(&"ads.example.org".parse().unwrap(), CLIENT));
+
+        // the previous names are kept while a file is missing
+        std::fs::remove_file(&domains).unwrap();
+        assert!(blocklist.reload_if_changed().is_err());
+        assert_eq!(blocklist.len(), 3);
+        std::fs::remove_file(&hosts).unwrap();
+        std::fs::remove_file(&allowed).unwrap();
+    }
 }
@@ -0,0 +1,306 @@
+//! Names defined in a hosts file, which are answered locally instead of asking any upstream.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use crate::{
+    cache::respond_with,
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        name::DnsName,
+        record_type::RecordType,
+    },
+};
+
+/// Where the operating system keeps its hosts file
+#[cfg(not(windows))]
+pub const HOSTS_FILE: &str = "/etc/hosts";
+#[cfg(windows)]
+pub const HOSTS_FILE: &str = r"C:\Windows\System32\drivers\etc\hosts";
+
+/// TTL of answers from the hosts file, so clients notice changes to it right away
+pub const HOSTS_TTL: usize = 0;
+
+/// Addresses of the names in a hosts file, with `ip name [aliases...]` per line as described in
+/// https://man7.org/linux/man-pages/man5/hosts.5.html
+///
+/// A and AAAA questions for names in the file are answered with their addresses of that family,
+/// which are none if it only lists the other one. PTR questions for the reverse names of the
+/// addresses are answered with the first name listed for them. All other questions are left to
+/// the upstream servers.
+#[derive(Debug, Default)]
+pub struct HostsFile {
+    /// File the hosts were read from, if any
+    path: Option<PathBuf>,
+    hosts: RwLock<Hosts>,
+}
+
+#[derive(Debug, Default)]
+struct Hosts {
+    addresses: HashMap<DnsName, Vec<IpAddr>>,
+    /// Reverse names of the addresses, with the canonical name they belong to
+    names: HashMap<DnsName, DnsName>,
+    /// Modification time and length of the file when it was read
+    version: Option<(SystemTime, u64)>,
+}
+
+impl Hosts {
+    fn parse(contents: &str) -> Self {
+        let mut hosts = Self::default();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(Ok(address)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+            let names: Vec<DnsName> = fields.filter_map(|name| name.parse().ok()).collect();
+            let Some(canonical) = names.first() else {
+                continue;
+            };
+
+            hosts
+                .names
+                .entry(reverse_name(address))
+                .or_insert_with(|| canonical.clone());
+            for name in names {
+                let addresses = hosts.addresses.entry(name).or_default();
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+        hosts
+    }
+
+    fn read(path: &Path) -> io::Result<Self> {
+        let version = version(path)?;
+        Ok(Self {
+            version: Some(version),
+            ..Self::parse(&std::fs::read_to_string(path)?)
+        })
+    }
+}
+
+impl HostsFile {
+    /// Hosts from `contents` in hosts file format, which are not backed by any file
+    pub fn parse(contents: &str) -> Self {
+        Self {
+            path: None,
+            hosts: RwLock::new(Hosts::parse(contents)),
+        }
+    }
+
+    /// Reads the hosts file at `path`, eg. [`HOSTS_FILE`]
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        Ok(Self {
+            hosts: RwLock::new(Hosts::read(&path)?),
+            path: Some(path),
+        })
+    }
+
+    /// Reads the file again. The previous hosts are kept if that fails.
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let hosts = Hosts::read(path)?;
+        *self.hosts.write().unwrap() = hosts;
+        Ok(())
+    }
+
+    /// Reads the file again if it was modified since it was last read, and returns whether it
+    /// was. Meant to be called periodically, eg. every few seconds.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        if self.hosts.read().unwrap().version == Some(version(path)?) {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Answers for `name` and `record_type`, or `None` if the hosts file does not define them
+    pub fn lookup(&self, name: &DnsName, record_type: RecordType) -> Option<Vec<Answer>> {
+        let hosts = self.hosts.read().unwrap();
+        let meta = AnswerMeta {
+            name: name.clone(),
+            r#type: record_type,
+            class: Class::IN,
+            ttl: HOSTS_TTL,
+            len: 0,
+        };
+        let answers = match record_type {
+            RecordType::A | RecordType::AAAA => hosts
+                .addresses
+                .get(name)?
+                .iter()
+                .filter_map(|&address| match address {
+                    IpAddr::V4(ipv4) if record_type == RecordType::A => Some(Answer::A {
+                        meta: meta.clone(),
+                        ipv4,
+                    }),
+                    IpAddr::V6(ipv6) if record_type == RecordType::AAAA => Some(Answer::AAAA {
+                        meta: meta.clone(),
+                        ipv6,
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            RecordType::PTR => vec![Answer::PTR {
+                meta,
+                ptr: hosts.names.get(name)?.clone(),
+            }],
+            _ => return None,
+        };
+        Some(answers)
+    }
+
+    /// Builds a response to the raw `query` if the hosts file defines the answers to its question
+    pub fn respond(&self, query: &[u8]) -> Option<Vec<u8>> {
+        respond_with(query, |key| match key.class {
+            Class::IN => self.lookup(&key.name, key.r#type),
+            _ => None,
+        })
+    }
+
+    /// Number of names with addresses
+    pub fn len(&self) -> usize {
+        self.hosts.read().unwrap().addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Modification time and length of the file at `path`, which change whenever it is written
+pub(crate) fn version(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+/// The name PTR records for `address` are found at, in `in-addr.arpa` or `ip6.arpa`
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-3.5
+/// https://datatracker.ietf.org/doc/html/rfc3596#section-2.5
+pub fn reverse_name(address: IpAddr) -> DnsName {
+    let name = match address {
+        IpAddr::V4(ipv4) => {
+            let [a, b, c, d] = ipv4.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(ipv6) => {
+            let mut name = String::new();
+            for byte in ipv6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xF, byte >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    };
+    DnsName::new(&name).expect("valid reverse name")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{answer::Answer, query::QueryBuilder, record_type::RecordType},
+    };
+
+    use super::{reverse_name, HostsFile};
+
+    const HOSTS: &str = "
+        127.0.0.1   localhost
+        ::1         localhost ip6-localhost # loopback
+        192.0.2.10  nas.home.arpa nas
+        # 192.0.2.11 printer.home.arpa
+        not-an-ip   invalid.home.arpa
+    ";
+
+    fn addresses(answers: Option<Vec<Answer>>) -> Option<Vec<String>> {
+        Some(
+            answers?
+                .iter()
+                .map(|answer| match answer {
+                    Answer::A { ipv4, .. } => ipv4.to_string(),
+                    Answer::AAAA { ipv6, .. } => ipv6.to_string(),
+                    Answer::PTR { ptr, .. } => ptr.to_string(),
+                    other => panic!("unexpected {other:?}"),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_hosts_lookup() {
+        let hosts = HostsFile::parse(HOSTS);
+        let lookup =
+            |name: &str, record_type| addresses(hosts.lookup(&name.parse().unwrap(), record_type));
+
+        assert_eq!(lookup("LocalHost", RecordType::A).unwrap(), ["127.0.0.1"]);
+        assert_eq!(lookup("localhost", RecordType::AAAA).unwrap(), ["::1"]);
+        assert_eq!(lookup("nas", RecordType::A).unwrap(), ["192.0.2.10"]);
+        assert!(lookup("ip6-localhost", RecordType::A).unwrap().is_empty());
+        assert_eq!(lookup("printer.home.arpa", RecordType::A), None);
+        assert_eq!(lookup("invalid.home.arpa", RecordType::A), None);
+        assert_eq!(lookup("nas", RecordType::MX), None);
+
+        let reverse = reverse_name("192.0.2.10".parse().unwrap());
+        assert_eq!(reverse.as_str(), "10.2.0.192.in-addr.arpa");
+        assert_eq!(
+            lookup(reverse.as_str(), RecordType::PTR).unwrap(),
+            ["nas.home.arpa"]
+        );
+        let reverse = reverse_name(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]));
+        assert_eq!(reverse.label_count(), 34);
+        assert_eq!(
+            lookup(reverse.as_str(), RecordType::PTR).unwrap(),
+            ["localhost"]
+        );
+        assert_eq!(hosts.len(), 4);
+    }
+
+    #[test]
+    fn test_hosts_respond() {
+        let hosts = HostsFile::parse(HOSTS);
+        let query = QueryBuilder::new("nas.home.arpa".parse().unwrap())
+            .id(7)
+            .build();
+        let response = hosts.respond(&query).unwrap();
+        let parsed = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(parsed.header.request_id, 7);
+        assert_eq!(addresses(Some(parsed.answers)).unwrap(), ["192.0.2.10"]);
+
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        assert!(hosts.respond(&query).is_none());
+    }
+
+    #[test]
+    fn test_hosts_reload_if_changed() {
+        let path = std::env::temp_dir().join(format!("hosts-{}", std::process::id()));
+        std::fs::write(&path, "192.0.2.1 a.home.arpa\n").unwrap();
+        let hosts = HostsFile::load(&path).unwrap();
+        assert!(!hosts.reload_if_changed().unwrap());
+
+        std::fs::write(&path, "192.0.2.1 a.home.arpa\n192.0.2.2 b.home.arpa\n").unwrap();
+        assert!(hosts.reload_if_changed().unwrap());
+        let b = hosts.lookup(&"b.home.arpa".parse().unwrap(), RecordType::A);
+        assert_eq!(addresses(b).unwrap(), ["192.0.2.2"]);
+
+        // the previous hosts are kept while the file is missing
+        std::fs::remove_file(&path).unwrap();
+        assert!(hosts.reload_if_changed().is_err());
+        assert_eq!(hosts.len(), 2);
+    }
+}
@@ -1,4 +1,26 @@
+pub mod authority;
+pub mod cache;
+pub mod config;
+pub mod dnssec;
+pub mod error;
 pub mod filter;
+pub mod hosts;
+pub mod lookup;
+pub mod mail;
+pub mod notify;
 pub mod parse;
 pub mod protocol;
+pub mod records;
+pub mod recursive;
 pub mod resolver;
+pub mod root_hints;
+pub mod secondary;
+pub mod serialize;
+pub mod service;
+pub mod tcp;
+pub mod transport;
+pub mod tsig;
+pub mod update;
+pub mod upstream;
+pub mod watch;
+pub mod zonefile;
@@ -0,0 +1,141 @@
+//! A cloneable handle turning host names into socket addresses, so HTTP clients and the like can
+//! use this crate instead of the resolver of the operating system.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::{
+    config::ResolverConfig,
+    error::ResolveError,
+    hosts::{HostsFile, HOSTS_FILE},
+    resolver::{resolve_ip, resolve_ip_async, ResolveOptions},
+    transport::BoxFuture,
+    upstream::UpstreamPool,
+};
+
+/// Resolves host names like [`std::net::ToSocketAddrs`] does, but through this crate.
+///
+/// [`DnsResolver::resolve`] has the shape custom resolvers of hyper and reqwest take, a name to
+/// a boxed `Send + 'static` future of addresses whose port the client replaces, so adapting it
+/// only takes a few lines:
+///
+/// ```ignore
+/// impl reqwest::dns::Resolve for MyResolver {
+///     fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+///         let addresses = self.0.resolve(name.as_str());
+///         Box::pin(async move { Ok(Box::new(addresses.await?.into_iter()) as reqwest::dns::Addrs) })
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    upstreams: Arc<UpstreamPool>,
+    opts: Arc<ResolveOptions>,
+}
+
+impl DnsResolver {
+    pub fn new(upstreams: UpstreamPool, opts: ResolveOptions) -> Self {
+        Self {
+            upstreams: Arc::new(upstreams),
+            opts: Arc::new(opts),
+        }
+    }
+
+    /// Uses the upstream servers and search domains the operating system is configured with,
+    /// see [`ResolverConfig::from_system`], and its hosts file if it can be read
+    pub fn from_system() -> io::Result<Self> {
+        let config = ResolverConfig::from_system()?;
+        let opts = ResolveOptions {
+            hosts: HostsFile::load(HOSTS_FILE).ok().map(Arc::new),
+            ..config.resolve_options()
+        };
+        Ok(Self::new(config.upstream_pool(), opts))
+    }
+
+    /// The addresses of `host` with `port`, in the order connections should be attempted.
+    /// IP addresses are returned as they are.
+    pub fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, ResolveError> {
+        if let Some(ip) = ip_literal(host) {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let ips = resolve_ip(host, &self.upstreams, &self.opts)?;
+        Ok(socket_addrs(ips, port))
+    }
+
+    /// Asynchronously looks up the addresses of `host`, see [`DnsResolver::lookup`]
+    pub async fn lookup_async(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, ResolveError> {
+        if let Some(ip) = ip_literal(host) {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let ips = resolve_ip_async(host, &self.upstreams, &self.opts).await?;
+        Ok(socket_addrs(ips, port))
+    }
+
+    /// The addresses of `name` with port 0, as a future that does not borrow the resolver
+    pub fn resolve(&self, name: &str) -> BoxFuture<'static, Result<Vec<SocketAddr>, ResolveError>> {
+        let (resolver, name) = (self.clone(), name.to_string());
+        Box::pin(async move { resolver.lookup_async(&name, 0).await })
+    }
+}
+
+/// `host` as an IP address, also in the brackets of URLs for IPv6
+fn ip_literal(host: &str) -> Option<IpAddr> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    host.parse().ok()
+}
+
+fn socket_addrs(ips: Vec<IpAddr>, port: u16) -> Vec<SocketAddr> {
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use crate::{hosts::HostsFile, resolver::ResolveOptions};
+
+    use super::DnsResolver;
+
+    #[tokio::test]
+    async fn test_dns_resolver() {
+        let opts = ResolveOptions {
+            hosts: Some(Arc::new(HostsFile::parse(
+                "192.0.2.1 web.example.com\n2001:db8::1 web.example.com",
+            ))),
+            ..ResolveOptions::default()
+        };
+        let resolver = DnsResolver::new("127.0.0.1:9".into(), opts);
+        let expected: Vec<SocketAddr> = ["[2001:db8::1]:443", "192.0.2.1:443"]
+            .map(|address| address.parse().unwrap())
+            .to_vec();
+        assert_eq!(resolver.lookup("web.example.com", 443).unwrap(), expected);
+        assert_eq!(
+            resolver.lookup_async("web.example.com", 443).await.unwrap(),
+            expected
+        );
+
+        let resolving = resolver.resolve("web.example.com");
+        drop(resolver);
+        let addresses = tokio::spawn(resolving).await.unwrap().unwrap();
+        assert!(addresses.iter().all(|address| address.port() == 0));
+        assert_eq!(addresses.len(), 2);
+
+        let resolver = DnsResolver::new("127.0.0.1:9".into(), ResolveOptions::default());
+        assert_eq!(
+            resolver.lookup("[::1]", 80).unwrap(),
+            ["[::1]:80".parse().unwrap()]
+        );
+    }
+}
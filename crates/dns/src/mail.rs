@@ -0,0 +1,596 @@
+//! Parsers for the TXT records email authentication publishes in DNS: SPF policies, DKIM keys and
+//! DMARC policies. The records are resolved with [`crate::resolver::resolve_txt`].
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+/// Name of the TXT record with the DKIM key of `selector` for `domain`
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.2.1
+pub fn dkim_name(selector: &str, domain: &str) -> String {
+    format!("{selector}._domainkey.{domain}")
+}
+
+/// Name of the TXT record with the DMARC policy of `domain`
+/// https://datatracker.ietf.org/doc/html/rfc7489#section-6.1
+pub fn dmarc_name(domain: &str) -> String {
+    format!("_dmarc.{domain}")
+}
+
+/// The terms of an SPF record, in the order they are evaluated
+/// https://datatracker.ietf.org/doc/html/rfc7208#section-4.6
+///
+/// ```
+/// use dns::mail::{Mechanism, Qualifier, Spf, SpfTerm};
+///
+/// let spf = Spf::parse("v=spf1 include:_spf.example.com -all").unwrap();
+/// assert_eq!(
+///     spf.terms[1],
+///     SpfTerm::Directive {
+///         qualifier: Qualifier::Fail,
+///         mechanism: Mechanism::All,
+///     }
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spf {
+    pub terms: Vec<SpfTerm>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpfTerm {
+    /// A mechanism the sender is matched against, with the result if it matches
+    Directive {
+        qualifier: Qualifier,
+        mechanism: Mechanism,
+    },
+    /// `name=value`, eg. `redirect` or `exp`. Names are lowercased.
+    Modifier { name: String, value: String },
+}
+
+/// Result of a matching mechanism, from its `+`, `-`, `~` or `?` prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+/// Domains are kept as written, so they may still contain macros
+/// https://datatracker.ietf.org/doc/html/rfc7208#section-5
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mechanism {
+    All,
+    Include(String),
+    /// Addresses of the domain, the current one if `None`, within the given prefix lengths
+    A {
+        domain: Option<String>,
+        prefix4: Option<u8>,
+        prefix6: Option<u8>,
+    },
+    /// Addresses of the mail exchangers of the domain, the current one if `None`
+    Mx {
+        domain: Option<String>,
+        prefix4: Option<u8>,
+        prefix6: Option<u8>,
+    },
+    Ptr(Option<String>),
+    Ip4 {
+        network: Ipv4Addr,
+        prefix: u8,
+    },
+    Ip6 {
+        network: Ipv6Addr,
+        prefix: u8,
+    },
+    Exists(String),
+}
+
+impl Spf {
+    /// Parses a record starting with `v=spf1`
+    pub fn parse(record: &str) -> Result<Self, String> {
+        let mut terms = record.split(' ').filter(|term| !term.is_empty());
+        if !terms
+            .next()
+            .is_some_and(|version| version.eq_ignore_ascii_case("v=spf1"))
+        {
+            return Err("not an SPF record".to_string());
+        }
+        let terms = terms.map(SpfTerm::parse).collect::<Result<_, _>>()?;
+        Ok(Self { terms })
+    }
+
+    /// The SPF record among the TXT records of a domain, `None` if there is none and an error if
+    /// there are several
+    /// https://datatracker.ietf.org/doc/html/rfc7208#section-4.5
+    pub fn from_records(records: &[String]) -> Result<Option<Self>, String> {
+        let mut spf = records.iter().filter(|record| {
+            let version = record.get(..6).unwrap_or_default();
+            version.eq_ignore_ascii_case("v=spf1")
+                && matches!(record.as_bytes().get(6), None | Some(b' '))
+        });
+        match (spf.next(), spf.next()) {
+            (None, _) => Ok(None),
+            (Some(record), None) => Self::parse(record).map(Some),
+            (Some(_), Some(_)) => Err("more than one SPF record".to_string()),
+        }
+    }
+
+    /// Domain of the `redirect` modifier, whose policy applies if no mechanism matches
+    pub fn redirect(&self) -> Option<&str> {
+        self.modifier("redirect")
+    }
+
+    /// Value of the modifier `name`, which is lowercase
+    pub fn modifier(&self, name: &str) -> Option<&str> {
+        self.terms.iter().find_map(|term| match term {
+            SpfTerm::Modifier { name: other, value } if other == name => Some(value.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl SpfTerm {
+    fn parse(term: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("{term}: {reason}");
+        let name_end = term.find([':', '/', '=']).unwrap_or(term.len());
+        if name_end > 0 && term[name_end..].starts_with('=') {
+            return Ok(SpfTerm::Modifier {
+                name: term[..name_end].to_ascii_lowercase(),
+                value: term[name_end + 1..].to_string(),
+            });
+        }
+
+        let (qualifier, name) = match term.as_bytes()[0] {
+            b'+' => (Qualifier::Pass, &term[1..name_end]),
+            b'-' => (Qualifier::Fail, &term[1..name_end]),
+            b'~' => (Qualifier::SoftFail, &term[1..name_end]),
+            b'?' => (Qualifier::Neutral, &term[1..name_end]),
+            _ => (Qualifier::Pass, &term[..name_end]),
+        };
+        let rest = &term[name_end..];
+        let domain = || rest.strip_prefix(':').map(str::to_string);
+        let required = || domain().ok_or_else(|| invalid("missing domain"));
+
+        let mechanism = match name.to_ascii_lowercase().as_str() {
+            "all" if rest.is_empty() => Mechanism::All,
+            "include" => Mechanism::Include(required()?),
+            "exists" => Mechanism::Exists(required()?),
+            "ptr" => Mechanism::Ptr(domain()),
+            "a" | "mx" => {
+                let (rest, prefix6) = split_prefix(rest, "//", 128).map_err(|e| invalid(&e))?;
+                let (rest, prefix4) = split_prefix(rest, "/", 32).map_err(|e| invalid(&e))?;
+                let domain = match rest {
+                    "" => None,
+                    rest => Some(
+                        rest.strip_prefix(':')
+                            .ok_or_else(|| invalid("missing domain"))?
+                            .to_string(),
+                    ),
+                };
+                match name.eq_ignore_ascii_case("a") {
+                    true => Mechanism::A {
+                        domain,
+                        prefix4,
+                        prefix6,
+                    },
+                    false => Mechanism::Mx {
+                        domain,
+                        prefix4,
+                        prefix6,
+                    },
+                }
+            }
+            "ip4" => {
+                let value = required()?;
+                let (network, prefix) = split_prefix(&value, "/", 32).map_err(|e| invalid(&e))?;
+                Mechanism::Ip4 {
+                    network: network
+                        .parse()
+                        .map_err(|_| invalid("invalid IPv4 network"))?,
+                    prefix: prefix.unwrap_or(32),
+                }
+            }
+            "ip6" => {
+                let value = required()?;
+                let (network, prefix) = split_prefix(&value, "/", 128).map_err(|e| invalid(&e))?;
+                Mechanism::Ip6 {
+                    network: network
+                        .parse()
+                        .map_err(|_| invalid("invalid IPv6 network"))?,
+                    prefix: prefix.unwrap_or(128),
+                }
+            }
+            _ => return Err(invalid("unknown mechanism")),
+        };
+        Ok(SpfTerm::Directive {
+            qualifier,
+            mechanism,
+        })
+    }
+}
+
+/// Splits a trailing `separator` and prefix length of at most `max` off `value`
+fn split_prefix<'a>(
+    value: &'a str,
+    separator: &str,
+    max: u8,
+) -> Result<(&'a str, Option<u8>), String> {
+    let Some((rest, prefix)) = value.rsplit_once(separator) else {
+        return Ok((value, None));
+    };
+    // `a//64` must not be taken for an IPv4 prefix
+    if prefix.is_empty() || !prefix.bytes().all(|b| b.is_ascii_digit()) || rest.ends_with('/') {
+        return Ok((value, None));
+    }
+    match prefix.parse::<u8>() {
+        Ok(prefix) if prefix <= max => Ok((rest, Some(prefix))),
+        _ => Err(format!("prefix length {prefix} out of range")),
+    }
+}
+
+/// A DKIM public key record
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.6.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimKey {
+    /// Hash algorithms signatures may use, all if empty (`h`)
+    pub hash_algorithms: Vec<String>,
+    /// `rsa` unless stated otherwise (`k`)
+    pub key_type: String,
+    /// Only meant for humans (`n`)
+    pub notes: Option<String>,
+    /// The decoded key, empty if it was revoked (`p`)
+    pub public_key: Vec<u8>,
+    /// Services the key may be used for, `*` for all (`s`)
+    pub service_types: Vec<String>,
+    /// Eg. `y` for testing or `s` for keys that do not apply to subdomains (`t`)
+    pub flags: Vec<String>,
+}
+
+impl DkimKey {
+    /// Parses a `tag=value` list, which must contain the public key
+    ///
+    /// ```
+    /// use dns::mail::DkimKey;
+    ///
+    /// let key = DkimKey::parse("v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=").unwrap();
+    /// assert_eq!(key.key_type, "ed25519");
+    /// assert_eq!(key.public_key.len(), 32);
+    /// ```
+    pub fn parse(record: &str) -> Result<Self, String> {
+        let tags = tag_list(record)?;
+        if let Some(position) = tags.iter().position(|&(name, _)| name == "v") {
+            if position != 0 || tags[0].1 != "DKIM1" {
+                return Err("v=DKIM1 has to be the first tag".to_string());
+            }
+        }
+        let tag = |name: &str| tags.iter().find(|&&(other, _)| other == name).map(|t| t.1);
+        let list = |value: &str, separator: char| -> Vec<String> {
+            value
+                .split(separator)
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        let public_key = tag("p").ok_or("missing public key")?;
+        let public_key: String = public_key.split_whitespace().collect();
+        let public_key = STANDARD
+            .decode(public_key)
+            .map_err(|e| format!("invalid public key: {e}"))?;
+        Ok(Self {
+            hash_algorithms: tag("h").map(|h| list(h, ':')).unwrap_or_default(),
+            key_type: tag("k").unwrap_or("rsa").to_string(),
+            notes: tag("n").map(str::to_string),
+            public_key,
+            service_types: list(tag("s").unwrap_or("*"), ':'),
+            flags: tag("t").map(|t| list(t, ':')).unwrap_or_default(),
+        })
+    }
+
+    /// Whether the signer revoked the key by publishing an empty one
+    pub fn is_revoked(&self) -> bool {
+        self.public_key.is_empty()
+    }
+
+    /// Whether the domain is testing DKIM, so failing signatures should not be held against it
+    pub fn is_testing(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "y")
+    }
+}
+
+/// What receivers should do with messages failing DMARC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmarcPolicy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl std::str::FromStr for DmarcPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "quarantine" => Ok(Self::Quarantine),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("unknown policy {policy}")),
+        }
+    }
+}
+
+/// How closely the authenticated domain has to match the `From` domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Alignment {
+    /// The same organizational domain suffices
+    #[default]
+    Relaxed,
+    Strict,
+}
+
+/// A DMARC policy record, with the defaults of RFC 7489 for missing tags
+/// https://datatracker.ietf.org/doc/html/rfc7489#section-6.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dmarc {
+    /// `p`
+    pub policy: DmarcPolicy,
+    /// Policy for subdomains, the same as `policy` unless stated otherwise (`sp`)
+    pub subdomain_policy: DmarcPolicy,
+    /// Share of failing messages the policy applies to (`pct`)
+    pub percent: u8,
+    /// Where aggregate reports go (`rua`)
+    pub aggregate_reports: Vec<String>,
+    /// Where failure reports go (`ruf`)
+    pub failure_reports: Vec<String>,
+    /// `adkim`
+    pub dkim_alignment: Alignment,
+    /// `aspf`
+    pub spf_alignment: Alignment,
+    /// When failure reports are sent, `0` unless stated otherwise (`fo`)
+    pub failure_options: Vec<String>,
+    /// Seconds between aggregate reports (`ri`)
+    pub report_interval: u32,
+}
+
+impl Dmarc {
+    /// Parses a `tag=value` list starting with `v=DMARC1`. Invalid values of optional tags are
+    /// replaced by their defaults, and a missing or invalid policy is taken as `none` if
+    /// aggregate reports are requested, as receivers are told to.
+    ///
+    /// ```
+    /// use dns::mail::{Dmarc, DmarcPolicy};
+    ///
+    /// let dmarc = Dmarc::parse("v=DMARC1; p=reject; sp=none; rua=mailto:dmarc@example.com").unwrap();
+    /// assert_eq!(dmarc.policy, DmarcPolicy::Reject);
+    /// assert_eq!(dmarc.subdomain_policy, DmarcPolicy::None);
+    /// assert_eq!(dmarc.percent, 100);
+    /// ```
+    pub fn parse(record: &str) -> Result<Self, String> {
+        let tags = tag_list(record)?;
+        if tags.first() != Some(&("v", "DMARC1")) {
+            return Err("v=DMARC1 has to be the first tag".to_string());
+        }
+        let tag = |name: &str| tags.iter().find(|&&(other, _)| other == name).map(|t| t.1);
+        let uris = |value: Option<&str>| -> Vec<String> {
+            value
+                .into_iter()
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|uri| !uri.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let alignment = |value: Option<&str>| match value {
+            Some(value) if value.eq_ignore_ascii_case("s") => Alignment::Strict,
+            _ => Alignment::Relaxed,
+        };
+
+        let aggregate_reports = uris(tag("rua"));
+        let policy = match tag("p").map(str::parse) {
+            Some(Ok(policy)) => policy,
+            _ if !aggregate_reports.is_empty() => DmarcPolicy::None,
+            Some(Err(e)) => return Err(e),
+            None => return Err("missing policy".to_string()),
+        };
+        Ok(Self {
+            policy,
+            subdomain_policy: tag("sp").and_then(|sp| sp.parse().ok()).unwrap_or(policy),
+            percent: tag("pct")
+                .and_then(|pct| pct.parse().ok())
+                .filter(|&pct| pct <= 100)
+                .unwrap_or(100),
+            aggregate_reports,
+            failure_reports: uris(tag("ruf")),
+            dkim_alignment: alignment(tag("adkim")),
+            spf_alignment: alignment(tag("aspf")),
+            failure_options: tag("fo")
+                .map(|fo| fo.split(':').map(|o| o.trim().to_string()).collect())
+                .unwrap_or_else(|| vec!["0".to_string()]),
+            report_interval: tag("ri").and_then(|ri| ri.parse().ok()).unwrap_or(86400),
+        })
+    }
+
+    /// The DMARC record among the TXT records at [`dmarc_name`], `None` if there is none and an
+    /// error if there are several
+    /// https://datatracker.ietf.org/doc/html/rfc7489#section-6.6.3
+    pub fn from_records(records: &[String]) -> Result<Option<Self>, String> {
+        let mut dmarc = records.iter().filter(|record| {
+            record
+                .split(';')
+                .next()
+                .is_some_and(|version| version.trim() == "v=DMARC1")
+        });
+        match (dmarc.next(), dmarc.next()) {
+            (None, _) => Ok(None),
+            (Some(record), None) => Self::parse(record).map(Some),
+            (Some(_), Some(_)) => Err("more than one DMARC record".to_string()),
+        }
+    }
+}
+
+/// Splits a `tag=value; ...` list, where each tag may only appear once
+/// https://datatracker.ietf.org/doc/html/rfc6376#section-3.2
+fn tag_list(record: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut tags: Vec<(&str, &str)> = vec![];
+    // a trailing semicolon is allowed
+    for tag in record
+        .split(';')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+    {
+        let (name, value) = tag
+            .split_once('=')
+            .ok_or_else(|| format!("{tag}: missing value"))?;
+        let name = name.trim();
+        if tags.iter().any(|&(other, _)| other == name) {
+            return Err(format!("{name}: duplicate tag"));
+        }
+        tags.push((name, value.trim()));
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alignment, DkimKey, Dmarc, DmarcPolicy, Mechanism, Qualifier, Spf, SpfTerm};
+
+    #[test]
+    fn test_parse_spf() {
+        let spf = Spf::parse(
+            "V=SPF1 +a mx:mail.example.com/24//64 ~ip4:192.0.2.0/24 ip6:2001:db8::/32 \
+             ?ptr a//96 exists:%{i}.spf.example.com redirect=_spf.example.com",
+        )
+        .unwrap();
+        let mechanisms: Vec<_> = spf
+            .terms
+            .iter()
+            .filter_map(|term| match term {
+                SpfTerm::Directive {
+                    qualifier,
+                    mechanism,
+                } => Some((*qualifier, mechanism.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            mechanisms,
+            [
+                (
+                    Qualifier::Pass,
+                    Mechanism::A {
+                        domain: None,
+                        prefix4: None,
+                        prefix6: None
+                    }
+                ),
+                (
+                    Qualifier::Pass,
+                    Mechanism::Mx {
+                        domain: Some("mail.example.com".to_string()),
+                        prefix4: Some(24),
+                        prefix6: Some(64)
+                    }
+                ),
+                (
+                    Qualifier::SoftFail,
+                    Mechanism::Ip4 {
+                        network: [192, 0, 2, 0].into(),
+                        prefix: 24
+                    }
+                ),
+                (
+                    Qualifier::Pass,
+                    Mechanism::Ip6 {
+                        network: "2001:db8::".parse().unwrap(),
+                        prefix: 32
+                    }
+                ),
+                (Qualifier::Neutral, Mechanism::Ptr(None)),
+                (
+                    Qualifier::Pass,
+                    Mechanism::A {
+                        domain: None,
+                        prefix4: None,
+                        prefix6: Some(96)
+                    }
+                ),
+                (
+                    Qualifier::Pass,
+                    Mechanism::Exists("%{i}.spf.example.com".to_string())
+                ),
+            ]
+        );
+        assert_eq!(spf.redirect(), Some("_spf.example.com"));
+
+        assert!(Spf::parse("v=spf10 -all").is_err());
+        assert!(Spf::parse("v=spf1 ip4:192.0.2.0/33").is_err());
+        assert!(Spf::parse("v=spf1 include").is_err());
+        assert!(Spf::parse("v=spf1 frobnicate -all").is_err());
+
+        let records = [
+            "google-site-verification=abc".to_string(),
+            "v=spf1 -all".to_string(),
+        ];
+        assert_eq!(Spf::from_records(&records).unwrap().unwrap().terms.len(), 1);
+        assert_eq!(Spf::from_records(&records[..1]), Ok(None));
+        let records = ["v=spf1 -all".to_string(), "v=spf1 +all".to_string()];
+        assert!(Spf::from_records(&records).is_err());
+    }
+
+    #[test]
+    fn test_parse_dkim_key() {
+        let key = DkimKey::parse(
+            "v=DKIM1; h=sha256 ; t=y:s; n=rotated yearly;\n\tp=MIIBIjANBgkqhkiG9w0BAQEFAAOC AQ8AMIIBCgKCAQEA;",
+        )
+        .unwrap();
+        assert_eq!(key.key_type, "rsa");
+        assert_eq!(key.hash_algorithms, ["sha256"]);
+        assert_eq!(key.service_types, ["*"]);
+        assert_eq!(key.notes.as_deref(), Some("rotated yearly"));
+        assert!(key.is_testing());
+        assert!(!key.is_revoked());
+
+        assert!(DkimKey::parse("p=").unwrap().is_revoked());
+        assert!(DkimKey::parse("k=rsa").is_err());
+        assert!(DkimKey::parse("k=rsa; v=DKIM1; p=").is_err());
+        assert!(DkimKey::parse("p=; p=").is_err());
+        assert!(DkimKey::parse("p=not base64!").is_err());
+    }
+
+    #[test]
+    fn test_parse_dmarc() {
+        let dmarc = Dmarc::parse(
+            "v=DMARC1;p=quarantine; pct=25; adkim=s; rua=mailto:a@example.com, mailto:b@example.net; \
+             ruf=mailto:f@example.com; fo=1:d; ri=3600",
+        )
+        .unwrap();
+        assert_eq!(dmarc.policy, DmarcPolicy::Quarantine);
+        assert_eq!(dmarc.subdomain_policy, DmarcPolicy::Quarantine);
+        assert_eq!(dmarc.percent, 25);
+        assert_eq!(dmarc.dkim_alignment, Alignment::Strict);
+        assert_eq!(dmarc.spf_alignment, Alignment::Relaxed);
+        assert_eq!(
+            dmarc.aggregate_reports,
+            ["mailto:a@example.com", "mailto:b@example.net"]
+        );
+        assert_eq!(dmarc.failure_reports, ["mailto:f@example.com"]);
+        assert_eq!(dmarc.failure_options, ["1", "d"]);
+        assert_eq!(dmarc.report_interval, 3600);
+
+        let dmarc = Dmarc::parse("v=DMARC1; p=bogus; pct=101; rua=mailto:a@example.com").unwrap();
+        assert_eq!(dmarc.policy, DmarcPolicy::None);
+        assert_eq!(dmarc.percent, 100);
+        assert!(Dmarc::parse("v=DMARC1; p=bogus").is_err());
+        assert!(Dmarc::parse("v=DMARC1").is_err());
+        assert!(Dmarc::parse("p=reject; v=DMARC1").is_err());
+
+        let records = ["v=DMARC1; p=none".to_string(), "v=spf1 -all".to_string()];
+        assert_eq!(
+            Dmarc::from_records(&records).unwrap().unwrap().policy,
+            DmarcPolicy::None
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Telling secondaries that a zone changed, so they transfer it right away instead of waiting
+//! for its refresh interval.
+//! https://datatracker.ietf.org/doc/html/rfc1996
+
+use crate::{
+    error::ResolveError,
+    parse::parser::DnsParser,
+    protocol::{
+        answer::Answer,
+        class::Class,
+        header::{Flags, Header},
+        name::DnsName,
+        opcode::Opcode,
+        packet::Packet,
+        query::random_id,
+        question::Question,
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    resolver::{
+        bind_query_socket, bind_query_socket_async, resolve_query, resolve_query_async,
+        ResolveOptions, ResponseError,
+    },
+    zonefile::Zone,
+};
+
+/// A NOTIFY message received from a primary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyRequest {
+    pub request_id: u16,
+    /// The zone that changed
+    pub zone: DnsName,
+    /// The new serial of the zone, if the primary sent its SOA record along
+    pub serial: Option<u32>,
+}
+
+impl NotifyRequest {
+    /// Parses the raw `message` if it is a NOTIFY for a zone, `None` for any other message
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let packet = DnsParser::new(message).parse_packet().ok()?;
+        if packet.header.opcode() != Opcode::Notify || !packet.header.flags.query {
+            return None;
+        }
+        let [question] = &packet.questions[..] else {
+            return None;
+        };
+        let serial = packet.answers.iter().find_map(|answer| match answer {
+            Answer::SOA { meta, serial, .. } if meta.name == question.domain_name => Some(*serial),
+            _ => None,
+        });
+        Some(Self {
+            request_id: packet.header.request_id,
+            zone: question.domain_name.clone(),
+            serial,
+        })
+    }
+
+    /// The acknowledgment the primary waits for, NOERROR if the zone is one of ours and NOTAUTH
+    /// otherwise
+    pub fn respond(&self, response_code: ResponseCode) -> Vec<u8> {
+        Packet {
+            header: Header {
+                request_id: self.request_id,
+                flags: Flags {
+                    query: false,
+                    opcode: Opcode::Notify,
+                    authoritative_answer: response_code == ResponseCode::NoError,
+                    response_code,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: vec![soa_question(&self.zone)],
+            ..Packet::default()
+        }
+        .to_bytes()
+    }
+}
+
+/// Builds a NOTIFY message for `zone`, with its SOA record so secondaries can tell whether they
+/// already have the new serial
+/// https://datatracker.ietf.org/doc/html/rfc1996#section-3.7
+pub fn notify_message(zone: &Zone) -> Vec<u8> {
+    Packet {
+        header: Header {
+            request_id: random_id(),
+            flags: Flags {
+                query: true,
+                opcode: Opcode::Notify,
+                authoritative_answer: true,
+                ..Flags::default()
+            },
+            ..Header::default()
+        },
+        questions: vec![soa_question(&zone.origin)],
+        answers: zone.soa().cloned().into_iter().collect(),
+        ..Packet::default()
+    }
+    .to_bytes()
+}
+
+/// Sends a NOTIFY for `zone` to the secondary at `address`, until it acknowledges it or the
+/// retries of `opts` run out
+pub fn send_notify(zone: &Zone, address: &str, opts: &ResolveOptions) -> Result<(), ResolveError> {
+    let socket = bind_query_socket(opts)?;
+    let response = resolve_query(&notify_message(zone), address, &socket, opts)?;
+    Ok(ResponseError::check(&response)?)
+}
+
+/// Asynchronously sends a NOTIFY for `zone` to the secondary at `address`, see [`send_notify`]
+pub async fn send_notify_async(
+    zone: &Zone,
+    address: &str,
+    opts: &ResolveOptions,
+) -> Result<(), ResolveError> {
+    let socket = bind_query_socket_async(opts).await?;
+    let response = resolve_query_async(&notify_message(zone), address, &socket, opts).await?;
+    Ok(ResponseError::check(&response)?)
+}
+
+fn soa_question(zone: &DnsName) -> Question {
+    Question {
+        domain_name: zone.clone(),
+        r#type: RecordType::SOA,
+        class: Class::IN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{opcode::Opcode, response_code::ResponseCode},
+        resolver::ResolveOptions,
+        zonefile::Zone,
+    };
+
+    use super::{notify_message, send_notify_async, NotifyRequest};
+
+    #[tokio::test]
+    async fn test_notify() {
+        let zone = Zone::parse(
+            "@ 60 SOA ns hostmaster 2024061501 7200 3600 1209600 300",
+            &"example.lan".parse().unwrap(),
+        )
+        .unwrap();
+        let message = notify_message(&zone);
+        let notify = NotifyRequest::parse(&message).unwrap();
+        assert_eq!(notify.zone, zone.origin);
+        assert_eq!(notify.serial, Some(2024061501));
+        let query = crate::protocol::query::QueryBuilder::new(zone.origin.clone()).build();
+        assert!(NotifyRequest::parse(&query).is_none());
+
+        let secondary = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = secondary.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            let (len, primary) = secondary.recv_from(&mut buffer).await.unwrap();
+            let notify = NotifyRequest::parse(&buffer[..len]).unwrap();
+            let response = notify.respond(ResponseCode::NoError);
+            secondary.send_to(&response, primary).await.unwrap();
+        });
+        let opts = ResolveOptions {
+            timeout: Duration::from_millis(500),
+            ..ResolveOptions::default()
+        };
+        send_notify_async(&zone, &address, &opts).await.unwrap();
+
+        let refused = notify.respond(ResponseCode::NotAuth);
+        let refused = DnsParser::new(&refused).parse_packet().unwrap();
+        assert_eq!(refused.header.opcode(), Opcode::Notify);
+        assert_eq!(refused.header.rcode(), ResponseCode::NotAuth);
+        assert!(!refused.header.flags.query);
+    }
+}
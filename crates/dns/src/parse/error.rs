@@ -0,0 +1,39 @@
+use std::fmt::Display;
+
+use crate::protocol::{name::NameError, record_type::RecordType};
+
+/// Errors that can occur while parsing a DNS message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsParseError {
+    /// The message ended before the field at the current position could be read
+    UnexpectedEof,
+    InvalidName(NameError),
+    /// The compression pointer at this offset points at or past itself, which could loop, or
+    /// follows too many others
+    InvalidPointer(usize),
+    /// RDATA of a record did not match its RDLENGTH
+    InvalidRecordData(RecordType),
+}
+
+impl Display for DnsParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsParseError::UnexpectedEof => write!(f, "unexpected end of DNS message"),
+            DnsParseError::InvalidName(e) => write!(f, "invalid domain name: {e}"),
+            DnsParseError::InvalidPointer(offset) => {
+                write!(f, "invalid compression pointer at offset {offset}")
+            }
+            DnsParseError::InvalidRecordData(record_type) => {
+                write!(f, "malformed RDATA of {record_type:?} record")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DnsParseError {}
+
+impl From<NameError> for DnsParseError {
+    fn from(e: NameError) -> Self {
+        DnsParseError::InvalidName(e)
+    }
+}
@@ -1,15 +1,40 @@
-use crate::protocol::{
-    answer::{Answer, AnswerMeta},
-    header::{Flags, Header},
-    question::Question,
-    record_type::RecordType,
+use crate::{
+    parse::error::DnsParseError,
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        header::{Flags, Header},
+        name::{DnsName, Name},
+        opt::{EdnsOption, Opt},
+        packet::Packet,
+        question::Question,
+        record_type::RecordType,
+    },
+    serialize::writer::PacketWriter,
 };
 
 pub type DnsPacketBuffer = [u8; 512];
 
+/// The header and first question of a query, borrowed from the message, see
+/// [`DnsParser::scan_query`]
+#[derive(Debug, Clone)]
+pub struct ScannedQuery<'a> {
+    pub header: Header,
+    /// `None` for queries without questions
+    pub question: Option<ScannedQuestion<'a>>,
+}
+
+/// A question whose name is borrowed from the message
+#[derive(Debug, Clone, Copy)]
+pub struct ScannedQuestion<'a> {
+    pub name: Name<'a>,
+    pub r#type: RecordType,
+    pub class: Class,
+}
+
 #[derive(Debug)]
 pub struct DnsParser<'a> {
-    pub buf: &'a DnsPacketBuffer,
+    pub buf: &'a [u8],
     position: usize,
 }
 
@@ -32,98 +57,78 @@ impl<const N: usize> Collate for [u8; N] {
 }
 
 impl<'a> DnsParser<'a> {
-    pub fn new(buf: &'a DnsPacketBuffer) -> Self {
+    pub fn new(buf: &'a [u8]) -> Self {
         Self { buf, position: 0 }
     }
 
-    fn peek(&self, n: usize) -> &[u8] {
-        &self.buf[self.position..self.position + n]
+    fn peek(&self, n: usize) -> Result<&'a [u8], DnsParseError> {
+        self.buf
+            .get(self.position..self.position + n)
+            .ok_or(DnsParseError::UnexpectedEof)
     }
 
-    fn peek_n<const N: usize>(&self) -> [u8; N] {
-        self.buf[self.position..self.position + N]
-            .try_into()
-            .unwrap()
+    fn peek_n<const N: usize>(&self) -> Result<[u8; N], DnsParseError> {
+        Ok(self.peek(N)?.try_into().unwrap())
     }
 
-    fn advance(&mut self, n: usize) -> &[u8] {
-        let out = &self.buf[self.position..self.position + n];
+    fn advance(&mut self, n: usize) -> Result<&'a [u8], DnsParseError> {
+        let out = self.peek(n)?;
         self.position += n;
-        out
+        Ok(out)
     }
 
-    fn advance_n<const N: usize>(&mut self) -> [u8; N] {
-        let out = self.buf[self.position..self.position + N]
-            .try_into()
-            .unwrap();
+    fn advance_n<const N: usize>(&mut self) -> Result<[u8; N], DnsParseError> {
+        let out = self.peek_n::<N>()?;
         self.position += N;
-        out
+        Ok(out)
     }
 
-    fn parse_domain_name(&mut self) -> String {
-        // parse query (again)
-        // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
-        // https://github.com/EmilHernvall/dnsguide/blob/master/chapter1.md
-        let mut name = String::new();
-        self.parse_domain_name_rec(&mut name);
-        name
+    fn parse_domain_name(&mut self) -> Result<DnsName, DnsParseError> {
+        DnsName::try_from(self.parse_name()?)
     }
 
-    fn parse_domain_name_rec(&mut self, buf: &mut String) {
-        // parse query (again)
+    /// Skips over the domain name at the current position and returns a borrowed view of it,
+    /// without copying any labels
+    pub fn parse_name(&mut self) -> Result<Name<'a>, DnsParseError> {
         // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
         // https://github.com/EmilHernvall/dnsguide/blob/master/chapter1.md
-        if self.peek(1).collate().eq(&0xC0) {
-            let offset = self.advance_n::<2>().collate() ^ 0xC000;
-            let old_position = self.position;
-            self.position = offset;
-            self.parse_domain_name_rec(buf);
-            self.position = old_position;
-        } else {
-            self.parse_domain_name_inline(buf);
-        }
-    }
-
-    fn parse_domain_name_inline(&mut self, buf: &mut String) {
-        let mut next = self.peek(1).collate();
-        if next.eq(&192) {
-            return;
-        }
-        // TODO: look to do this in one operation
-        while next > 0 && next.ne(&192) {
-            self.advance_n::<1>().collate();
-            for c in self.advance(next) {
-                buf.push(*c as char);
-            }
-            next = self.peek(1).collate();
-            if next > 0 {
-                buf.push('.');
+        let name = Name::new(self.buf, self.position);
+        loop {
+            let len = self.peek(1)?.collate();
+            if len == 0 {
+                // skip 0 byte at the end
+                self.advance_n::<1>()?;
+                break;
             }
-            if next.eq(&192) {
-                self.parse_domain_name_rec(buf);
-                return;
+            if is_pointer(len) {
+                // the rest of the name lives elsewhere, a pointer always ends the name
+                // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+                self.advance_n::<2>()?;
+                break;
             }
+            self.advance(len + 1)?;
         }
-        // skip 0 byte at the end
-        self.advance_n::<1>();
+        // the labels the pointers lead to are only read when needed, but must be there
+        name.labels().try_for_each(|label| label.map(drop))?;
+        Ok(name)
     }
 
-    pub fn parse_question(&mut self) -> Question {
-        Question {
-            domain_name: self.parse_domain_name(),
-            r#type: self.advance_n::<2>().collate(),
-            class: self.advance_n::<2>().collate(),
-        }
+    pub fn parse_question(&mut self) -> Result<Question, DnsParseError> {
+        Ok(Question {
+            domain_name: self.parse_domain_name()?,
+            r#type: self.advance_n::<2>()?.collate().into(),
+            class: Class::from(self.advance_n::<2>()?.collate() as u16),
+        })
     }
 
-    pub fn parse_answer(&mut self) -> Answer {
+    pub fn parse_answer(&mut self) -> Result<Answer, DnsParseError> {
         // parse resource record
         // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.3
-        let name = self.parse_domain_name();
-        let record_type: RecordType = self.advance_n::<2>().collate().into();
-        let class = self.advance_n::<2>().collate();
-        let ttl = self.advance_n::<4>().collate();
-        let len = self.advance_n::<2>().collate();
+        let name = self.parse_domain_name()?;
+        let record_type: RecordType = self.advance_n::<2>()?.collate().into();
+        let class = Class::from(self.advance_n::<2>()?.collate() as u16);
+        let ttl = self.advance_n::<4>()?.collate();
+        let len = self.advance_n::<2>()?.collate();
 
         let meta = AnswerMeta {
             name,
@@ -133,98 +138,323 @@ impl<'a> DnsParser<'a> {
             r#type: record_type,
         };
 
-        match record_type {
-            RecordType::A => {
-                let ipv4 = self.peek_n::<4>();
-                self.position += 4;
-                Answer::A {
-                    meta,
-                    ipv4: ipv4.into(),
+        let rdata_start = self.position;
+        let rdata_end = rdata_start + len;
+        if rdata_end > self.buf.len() {
+            return Err(DnsParseError::UnexpectedEof);
+        }
+
+        let answer = match record_type {
+            // dynamic updates match or delete records by type alone
+            // https://datatracker.ietf.org/doc/html/rfc2136#section-2.4
+            _ if len == 0 && matches!(class, Class::ANY | Class::NONE) => Answer::Unknown {
+                rdata: vec![],
+                meta,
+            },
+            RecordType::A => Answer::A {
+                ipv4: self.advance_n::<4>()?.into(),
+                meta,
+            },
+            RecordType::AAAA => Answer::AAAA {
+                ipv6: self.advance_n::<16>()?.into(),
+                meta,
+            },
+            RecordType::NS => Answer::NS {
+                ns: self.parse_domain_name()?,
+                meta,
+            },
+            RecordType::CNAME => Answer::CNAME {
+                cname: self.parse_domain_name()?,
+                meta,
+            },
+            RecordType::PTR => Answer::PTR {
+                ptr: self.parse_domain_name()?,
+                meta,
+            },
+            RecordType::MX => Answer::MX {
+                preference: self.advance_n::<2>()?.collate() as u16,
+                exchange: self.parse_domain_name()?,
+                meta,
+            },
+            RecordType::SOA => Answer::SOA {
+                mname: self.parse_domain_name()?,
+                rname: self.parse_domain_name()?,
+                serial: self.advance_n::<4>()?.collate() as u32,
+                refresh: self.advance_n::<4>()?.collate() as u32,
+                retry: self.advance_n::<4>()?.collate() as u32,
+                expire: self.advance_n::<4>()?.collate() as u32,
+                minimum: self.advance_n::<4>()?.collate() as u32,
+                meta,
+            },
+            RecordType::SRV => Answer::SRV {
+                priority: self.advance_n::<2>()?.collate() as u16,
+                weight: self.advance_n::<2>()?.collate() as u16,
+                port: self.advance_n::<2>()?.collate() as u16,
+                target: self.parse_domain_name()?,
+                meta,
+            },
+            RecordType::TXT => {
+                let mut txt = vec![];
+                while self.position < rdata_end {
+                    let string_len = self.advance_n::<1>()?[0] as usize;
+                    txt.push(self.advance(string_len)?.to_vec());
                 }
+                Answer::TXT { txt, meta }
             }
-            RecordType::CNAME => {
-                let cname = self.parse_domain_name();
-                Answer::CNAME { cname, meta }
-            }
-            RecordType::NS => todo!(),
-            RecordType::MD => todo!(),
-            RecordType::MF => todo!(),
-            // todo
-            RecordType::SOA => todo!(),
-            RecordType::MB => todo!(),
-            RecordType::MG => todo!(),
-            RecordType::MR => todo!(),
-            RecordType::NULL => todo!(),
-            RecordType::WKS => todo!(),
-            RecordType::PTR => todo!(),
-            RecordType::HINFO => todo!(),
-            RecordType::MINFO => todo!(),
-            RecordType::MX => todo!(),
-            RecordType::TXT => todo!(),
-            RecordType::AXFR => todo!(),
-            RecordType::MAILB => todo!(),
-            RecordType::MAILA => todo!(),
-            RecordType::ANY => todo!(),
-            RecordType::URI => todo!(),
-            RecordType::OTHER => todo!(),
+            _ => Answer::Unknown {
+                rdata: self.advance(len)?.to_vec(),
+                meta,
+            },
+        };
+
+        // a record must not reach into the next one, nor leave parts of its RDATA unread
+        if self.position != rdata_end {
+            return Err(DnsParseError::InvalidRecordData(record_type));
         }
+        Ok(answer)
     }
 
-    fn parse_header(&mut self) -> Header {
-        Header {
-            request_id: self.advance_n::<2>().collate() as u16,
-            flags: Flags::from(self.advance_n::<2>().collate() as u16),
-            question_count: self.advance_n::<2>().collate() as u16,
-            answer_count: self.advance_n::<2>().collate() as u16,
-            authority_count: self.advance_n::<2>().collate() as u16,
-            additional_count: self.advance_n::<2>().collate() as u16,
+    pub fn parse_header(&mut self) -> Result<Header, DnsParseError> {
+        Ok(Header {
+            request_id: self.advance_n::<2>()?.collate() as u16,
+            flags: Flags::from(self.advance_n::<2>()?.collate() as u16),
+            question_count: self.advance_n::<2>()?.collate() as u16,
+            answer_count: self.advance_n::<2>()?.collate() as u16,
+            authority_count: self.advance_n::<2>()?.collate() as u16,
+            additional_count: self.advance_n::<2>()?.collate() as u16,
+        })
+    }
+
+    /// Parses the whole message, starting at the header
+    pub fn parse_packet(mut self) -> Result<Packet, DnsParseError> {
+        self.position = 0;
+        let header = self.parse_header()?;
+
+        let questions = (0..header.question_count)
+            .map(|_| self.parse_question())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut records = |count| {
+            (0..count)
+                .map(|_| self.parse_answer())
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let answers = records(header.answer_count)?;
+        let authorities = records(header.authority_count)?;
+        let additionals = records(header.additional_count)?;
+
+        Ok(Packet {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+
+    /// Parses the header and skips over all questions, after which answers can be decoded one at
+    /// a time without parsing the rest of the message
+    pub fn answers_iter(mut self) -> Result<Answers<'a>, DnsParseError> {
+        self.position = 0;
+        let header = self.parse_header()?;
+        for _ in 0..header.question_count {
+            self.parse_name()?;
+            self.advance_n::<4>()?;
         }
+        Ok(Answers {
+            parser: self,
+            remaining: header.answer_count,
+        })
     }
 
-    pub fn parse_answers(
-        mut self,
-    ) -> Result<Vec<Answer>, Box<dyn std::error::Error + Send + Sync>> {
-        let header = self.parse_header();
+    pub fn parse_answers(self) -> Result<Vec<Answer>, DnsParseError> {
+        Ok(self.parse_packet()?.answers)
+    }
+
+    /// Skips over all sections up to the additional one and returns the OPT record in it, if the
+    /// message has one
+    pub fn parse_opt(mut self) -> Result<Option<Opt>, DnsParseError> {
+        if !self.seek_opt()? {
+            return Ok(None);
+        }
+
+        self.advance_n::<2>()?;
+        let payload_size = self.advance_n::<2>()?.collate() as u16;
+        let [extended_rcode, version, flags, _] = self.advance_n::<4>()?;
+        let len = self.advance_n::<2>()?.collate();
+        let mut rdata = DnsParser::new(self.advance(len)?);
+        let mut options = vec![];
+        while rdata.position < rdata.buf.len() {
+            let code = rdata.advance_n::<2>()?.collate() as u16;
+            let len = rdata.advance_n::<2>()?.collate();
+            let data = rdata.advance(len)?.to_vec();
+            options.push(EdnsOption { code, data });
+        }
+        Ok(Some(Opt {
+            payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok: flags & 0x80 != 0,
+            options,
+        }))
+    }
+
+    /// Position of the RDLENGTH field of the OPT record, if the message has one
+    pub(crate) fn find_opt_rdlength(mut self) -> Result<Option<usize>, DnsParseError> {
+        if !self.seek_opt()? {
+            return Ok(None);
+        }
+        self.advance_n::<8>()?;
+        let position = self.position;
+        let len = self.advance_n::<2>()?.collate();
+        self.advance(len)?;
+        Ok(Some(position))
+    }
 
+    /// Moves to the TYPE field of the OPT record, returning whether there is one
+    fn seek_opt(&mut self) -> Result<bool, DnsParseError> {
+        self.position = 0;
+        let header = self.parse_header()?;
         for _ in 0..header.question_count {
-            self.parse_question();
+            self.parse_name()?;
+            self.advance_n::<4>()?;
+        }
+        for _ in 0..header.answer_count as u32 + header.authority_count as u32 {
+            self.skip_record()?;
+        }
+
+        for _ in 0..header.additional_count {
+            self.parse_name()?;
+            let record_type: RecordType = self.peek_n::<2>()?.collate().into();
+            if record_type == RecordType::OPT {
+                return Ok(true);
+            }
+            self.advance_n::<8>()?;
+            let len = self.advance_n::<2>()?.collate();
+            self.advance(len)?;
         }
+        Ok(false)
+    }
 
-        let answers = (0..header.answer_count)
-            .map(|_| self.parse_answer())
-            .collect::<Vec<_>>();
+    /// Skips over the resource record at the current position
+    fn skip_record(&mut self) -> Result<(), DnsParseError> {
+        self.parse_name()?;
+        self.advance_n::<8>()?;
+        let len = self.advance_n::<2>()?.collate();
+        self.advance(len)?;
+        Ok(())
+    }
+
+    /// Checks the header and all questions of a query like [`DnsParser::get_relay_information`],
+    /// but without allocating: names are borrowed from the message and only the first question
+    /// is kept, which is all relaying a query needs
+    pub fn scan_query(&mut self) -> Result<ScannedQuery<'a>, DnsParseError> {
+        self.position = 0;
+        let header = self.parse_header()?;
+        let mut question = None;
+        for _ in 0..header.question_count {
+            let name = self.parse_name()?;
+            name.validate()?;
+            let scanned = ScannedQuestion {
+                name,
+                r#type: self.advance_n::<2>()?.collate().into(),
+                class: Class::from(self.advance_n::<2>()?.collate() as u16),
+            };
+            question.get_or_insert(scanned);
+        }
+        Ok(ScannedQuery { header, question })
+    }
 
-        Ok(answers)
+    /// The UDP payload size advertised in the OPT record, like [`DnsParser::parse_opt`] but
+    /// without reading the options
+    pub fn parse_payload_size(mut self) -> Result<Option<u16>, DnsParseError> {
+        if !self.seek_opt()? {
+            return Ok(None);
+        }
+        self.advance_n::<2>()?;
+        Ok(Some(self.advance_n::<2>()?.collate() as u16))
     }
 
-    /// TODO: have this on the final Packet type that we fully parse from the buffer
-    pub fn get_relay_information(
-        &mut self,
-    ) -> Result<(u16, Question), Box<dyn std::error::Error + Send + Sync>> {
+    /// Parses just the header and all questions of a query, which is all a relay needs
+    pub fn get_relay_information(&mut self) -> Result<(u16, Vec<Question>), DnsParseError> {
         self.position = 0;
-        let headers = self.parse_header();
-        let first_question = self.parse_question();
-        Ok((headers.request_id, first_question))
+        let header = self.parse_header()?;
+        let questions = (0..header.question_count)
+            .map(|_| self.parse_question())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((header.request_id, questions))
+    }
+}
+
+/// Iterator that decodes the answer section of a message on demand,
+/// see [`DnsParser::answers_iter`]
+#[derive(Debug)]
+pub struct Answers<'a> {
+    parser: DnsParser<'a>,
+    remaining: u16,
+}
+
+impl Iterator for Answers<'_> {
+    type Item = Result<Answer, DnsParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let answer = self.parser.parse_answer();
+        // a malformed record leaves the cursor at an unknown position, so stop after it
+        self.remaining = if answer.is_ok() {
+            self.remaining - 1
+        } else {
+            0
+        };
+        Some(answer)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining as usize))
     }
 }
 
-pub(crate) fn encode_domain_name(domain_name: &str) -> Vec<u8> {
-    let mut encoded = Vec::with_capacity(domain_name.len());
-    domain_name.split('.').for_each(|part| {
-        encoded.push(part.len() as u8);
-        encoded.extend(part.as_bytes());
-    });
-    encoded.push(0);
+/// Whether a label length byte is actually the start of a compression pointer
+fn is_pointer(byte: usize) -> bool {
+    byte & 0xC0 == 0xC0
+}
+
+/// Encodes `domain_name` as a sequence of labels without compression
+pub fn encode_domain_name(domain_name: &DnsName) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(domain_name.wire_len());
+    PacketWriter::uncompressed(&mut encoded).write_name(domain_name);
     encoded
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        parse::parser::{encode_domain_name, Collate, DnsParser},
-        protocol::header::{Flags, Header},
+        parse::{
+            error::DnsParseError,
+            parser::{encode_domain_name, Collate, DnsParser},
+        },
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            header::{Flags, Header},
+            name::DnsName,
+            opcode::Opcode,
+            packet::Packet,
+            question::Question,
+            record_type::RecordType,
+            response_code::ResponseCode,
+        },
+        resolver::generate_request,
     };
 
+    fn to_packet(bytes: &[u8]) -> [u8; 512] {
+        let mut packet = [0u8; 512];
+        packet[..bytes.len()].copy_from_slice(bytes);
+        packet
+    }
+
     #[test]
     fn test_parser_advance() {
         let mut input = [0u8; 512];
@@ -232,7 +462,7 @@ mod tests {
 
         let mut parser = DnsParser::new(&input);
         assert_eq!(
-            parser.advance_n::<3>().collate(),
+            parser.advance_n::<3>().unwrap().collate(),
             (0x3 << 16) | (0x2 << 8) | 0x1
         );
         assert_eq!(parser.buf.len(), 512);
@@ -244,7 +474,7 @@ mod tests {
         input[0..3].copy_from_slice(&[0x3, 0x2, 0x1]);
 
         let parser = DnsParser::new(&input);
-        assert_eq!(parser.peek_n::<3>(), [0x3, 0x2, 0x1]);
+        assert_eq!(parser.peek_n::<3>().unwrap(), [0x3, 0x2, 0x1]);
         assert_eq!(parser.buf.len(), 512);
     }
 
@@ -265,6 +495,39 @@ mod tests {
         assert_eq!(raw, encoded);
     }
 
+    #[test]
+    fn test_conversion_flags_lossless() {
+        for raw in 0..=u16::MAX {
+            assert_eq!(u16::from(Flags::from(raw)), raw);
+        }
+
+        let flags = Flags::from(0x0130_u16); // RD, AD and CD set
+        assert!(flags.query);
+        assert!(flags.recursion_desired);
+        assert!(flags.authenticated_data);
+        assert!(flags.checking_disabled);
+        assert!(!flags.z);
+
+        let header = Header {
+            flags: Flags::from(0x2805_u16), // UPDATE with REFUSED
+            ..Default::default()
+        };
+        assert_eq!(header.opcode(), Opcode::Update);
+        assert_eq!(header.rcode(), ResponseCode::Refused);
+    }
+
+    #[test]
+    fn test_extended_response_code() {
+        let rcode = ResponseCode::from_parts(0, 1);
+        assert_eq!(rcode, ResponseCode::BadVers);
+        assert_eq!(rcode.header_bits(), 0);
+        assert_eq!(rcode.extended_bits(), 1);
+
+        assert_eq!(ResponseCode::from_parts(3, 0), ResponseCode::NXDomain);
+        assert_eq!(ResponseCode::NXDomain.extended_bits(), 0);
+        assert_eq!(ResponseCode::from(4000), ResponseCode::Other(4000));
+    }
+
     #[test]
     fn test_conversion_header() {
         let header = Header {
@@ -280,13 +543,13 @@ mod tests {
         packet[0..12].copy_from_slice(&serialized_header);
 
         let mut parser = DnsParser::new(&packet);
-        let deserialized_header = parser.parse_header();
+        let deserialized_header = parser.parse_header().unwrap();
         assert_eq!(header, deserialized_header);
     }
 
     #[test]
     fn test_encode_domain_name() {
-        let res = encode_domain_name("www.example.com");
+        let res = encode_domain_name(&"www.example.com".parse().unwrap());
         assert_eq!(
             res,
             vec![
@@ -296,4 +559,481 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_encode_root_name() {
+        assert_eq!(encode_domain_name(&DnsName::root()), vec![0]);
+        assert_eq!(
+            encode_domain_name(&"example.com.".parse().unwrap()),
+            encode_domain_name(&"example.com".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_root_question() {
+        let request = generate_request(&DnsName::root(), Some(1));
+        assert_eq!(request.len(), 12 + 1 + 4);
+
+        let packet = to_packet(&request);
+        let mut parser = DnsParser::new(&packet);
+        parser.parse_header().unwrap();
+        let question = parser.parse_question().unwrap();
+        assert!(question.domain_name.is_root());
+        assert_eq!(question.domain_name.to_string(), ".");
+        assert_eq!(parser.position, request.len());
+
+        // a pointer to a root name
+        let packet = to_packet(&[0, 0xC0, 0]);
+        let mut parser = DnsParser::new(&packet);
+        parser.position = 1;
+        assert_eq!(parser.parse_name().unwrap().to_string(), ".");
+    }
+
+    #[test]
+    fn test_roundtrip_escaped_name() {
+        let name: DnsName = r"dot\.in\009label.example".parse().unwrap();
+        let packet = to_packet(&encode_domain_name(&name));
+        assert_eq!(&packet[0..13], b"\x0cdot.in\tlabel");
+
+        let mut parser = DnsParser::new(&packet);
+        let parsed = parser.parse_domain_name().unwrap();
+        assert_eq!(parsed.as_str(), r"dot\.in\009label.example");
+        assert_eq!(parsed, name);
+    }
+
+    #[test]
+    fn test_multiple_questions() {
+        let questions: Vec<_> = ["example.com", "www.example.com", "example.org"]
+            .into_iter()
+            .map(|name| Question {
+                domain_name: name.parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+            })
+            .collect();
+        let packet = Packet {
+            header: Header {
+                request_id: 42,
+                ..Default::default()
+            },
+            questions: questions.clone(),
+            ..Default::default()
+        };
+        let bytes = to_packet(&packet.to_bytes());
+
+        let (id, relayed) = DnsParser::new(&bytes).get_relay_information().unwrap();
+        assert_eq!(id, 42);
+        assert_eq!(relayed, questions);
+
+        let parsed = DnsParser::new(&bytes).parse_packet().unwrap();
+        assert_eq!(parsed.header.question_count, 3);
+        assert_eq!(parsed.questions, questions);
+
+        let scanned = DnsParser::new(&bytes).scan_query().unwrap();
+        assert_eq!(scanned.header.request_id, 42);
+        let question = scanned.question.unwrap();
+        assert_eq!(question.name.to_string(), "example.com");
+        assert_eq!(
+            (question.r#type, question.class),
+            (RecordType::A, Class::IN)
+        );
+    }
+
+    #[test]
+    fn test_scan_query() {
+        use crate::protocol::query::QueryBuilder;
+
+        let query = QueryBuilder::new("www.example.com".parse().unwrap())
+            .id(7)
+            .edns_payload_size(1232)
+            .build();
+        let scanned = DnsParser::new(&query).scan_query().unwrap();
+        assert_eq!(scanned.header.request_id, 7);
+        assert!(scanned
+            .question
+            .unwrap()
+            .name
+            .eq_ignore_ascii_case("www.example.com"));
+        assert_eq!(DnsParser::new(&query).parse_payload_size(), Ok(Some(1232)));
+
+        let mut header = query[..12].to_vec();
+        header[4..].fill(0);
+        assert!(DnsParser::new(&header)
+            .scan_query()
+            .unwrap()
+            .question
+            .is_none());
+        assert_eq!(DnsParser::new(&header).parse_payload_size(), Ok(None));
+
+        // malformed like for the full parser: cut short, or with a name pointing to itself
+        assert!(DnsParser::new(&query[..20]).scan_query().is_err());
+        let mut looped = query[..12].to_vec();
+        looped.push(63);
+        looped.extend([b'a'; 63]);
+        looped.extend([0xC0, 12, 0, 1, 0, 1]);
+        assert_eq!(
+            DnsParser::new(&looped).scan_query().unwrap_err(),
+            DnsParser::new(&looped).get_relay_information().unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_question() {
+        let question = Question {
+            domain_name: "www.example.com".parse().unwrap(),
+            r#type: RecordType::A,
+            class: Class::IN,
+        };
+
+        let mut buf = vec![];
+        question.to_bytes(&mut buf);
+        assert_eq!(buf.len(), 17 + 4);
+
+        let packet = to_packet(&buf);
+        let mut parser = DnsParser::new(&packet);
+        assert_eq!(parser.parse_question().unwrap(), question);
+    }
+
+    #[test]
+    fn test_roundtrip_answers() {
+        let answers = [
+            Answer::CNAME {
+                meta: AnswerMeta {
+                    name: "www.example.com".parse().unwrap(),
+                    r#type: RecordType::CNAME,
+                    class: Class::IN,
+                    ttl: 300,
+                    len: 13,
+                },
+                cname: "example.com".parse().unwrap(),
+            },
+            Answer::A {
+                meta: AnswerMeta {
+                    name: "example.com".parse().unwrap(),
+                    r#type: RecordType::A,
+                    class: Class::IN,
+                    ttl: 3600,
+                    len: 4,
+                },
+                ipv4: [93, 184, 216, 34].into(),
+            },
+        ];
+
+        let mut buf = vec![];
+        for answer in &answers {
+            answer.to_bytes(&mut buf);
+        }
+
+        let packet = to_packet(&buf);
+        let mut parser = DnsParser::new(&packet);
+        for answer in answers {
+            assert_eq!(parser.parse_answer().unwrap(), answer);
+        }
+    }
+
+    #[test]
+    fn test_answer_rdlength_is_derived() {
+        let answer = Answer::A {
+            meta: AnswerMeta {
+                name: "example.com".parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+                ttl: 60,
+                len: 0,
+            },
+            ipv4: [127, 0, 0, 1].into(),
+        };
+
+        let mut buf = vec![];
+        answer.to_bytes(&mut buf);
+        assert_eq!(&buf[buf.len() - 6..buf.len() - 4], &[0, 4]);
+    }
+
+    #[test]
+    fn test_parse_name_with_pointers() {
+        let mut packet = [0u8; 512];
+        let name = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, // example.com
+            3, b'W', b'W', b'W', 0xC0, 0, // WWW + pointer to example.com
+            0xC0, 13, // pointer to WWW.example.com
+        ];
+        packet[..name.len()].copy_from_slice(&name);
+
+        let mut parser = DnsParser::new(&packet);
+        assert_eq!(parser.parse_name().unwrap().to_string(), "example.com");
+        let www = parser.parse_name().unwrap();
+        assert_eq!(parser.position, 19);
+        assert!(www.eq_ignore_ascii_case("www.EXAMPLE.com"));
+        assert!(!www.eq_ignore_ascii_case("www.example"));
+        assert_eq!(
+            parser.parse_domain_name().unwrap().as_str(),
+            "WWW.example.com"
+        );
+        assert_eq!(parser.position, 21);
+    }
+
+    #[test]
+    fn test_parse_name_pointer_loop() {
+        let mut packet = [0u8; 512];
+        packet[0..2].copy_from_slice(&[0xC0, 0]);
+
+        let mut parser = DnsParser::new(&packet);
+        assert_eq!(
+            parser.parse_name().unwrap_err(),
+            DnsParseError::InvalidPointer(0)
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_pointers() {
+        // the id doubles as a 63 byte label the message is too short for
+        let query = |name: &[u8]| {
+            let mut query = vec![0x3f, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+            query.extend(name);
+            query.extend([0, 1, 0, 1]);
+            query
+        };
+        for (name, error) in [
+            (&b"\xc0\x0c"[..], DnsParseError::InvalidPointer(12)),
+            (b"\x03www\xc0\x0c", DnsParseError::InvalidPointer(16)),
+            (b"\x03www\xc0\xff", DnsParseError::InvalidPointer(16)),
+            (b"\x03www\xc0\x00", DnsParseError::UnexpectedEof),
+        ] {
+            let query = query(name);
+            assert_eq!(DnsParser::new(&query).parse_packet().unwrap_err(), error);
+            assert_eq!(
+                DnsParser::new(&query).get_relay_information().unwrap_err(),
+                error
+            );
+            assert_eq!(DnsParser::new(&query).scan_query().unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn test_answers_iter() {
+        let answers: Vec<_> = (0..3)
+            .map(|i| Answer::A {
+                meta: AnswerMeta {
+                    name: "example.com".parse().unwrap(),
+                    r#type: RecordType::A,
+                    class: Class::IN,
+                    ttl: 60,
+                    len: 4,
+                },
+                ipv4: [10, 0, 0, i].into(),
+            })
+            .collect();
+        let packet = Packet {
+            questions: vec![Question {
+                domain_name: "example.com".parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+            }],
+            answers: answers.clone(),
+            ..Default::default()
+        };
+        let bytes = packet.to_bytes();
+
+        let mut iter = Packet::answers_iter(&bytes).unwrap();
+        assert_eq!(iter.next(), Some(Ok(answers[0].clone())));
+        assert_eq!(iter.size_hint(), (0, Some(2)));
+        assert_eq!(iter.count(), 2);
+
+        // the last record is cut off
+        let mut iter = Packet::answers_iter(&bytes[..bytes.len() - 2]).unwrap();
+        assert_eq!(iter.nth(1), Some(Ok(answers[1].clone())));
+        assert_eq!(iter.next(), Some(Err(DnsParseError::UnexpectedEof)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_truncated_packets() {
+        let packet = Packet {
+            header: Header {
+                answer_count: 1,
+                ..Default::default()
+            },
+            questions: vec![Question {
+                domain_name: "example.com".parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+            }],
+            answers: vec![Answer::CNAME {
+                meta: AnswerMeta {
+                    name: "example.com".parse().unwrap(),
+                    r#type: RecordType::CNAME,
+                    class: Class::IN,
+                    ttl: 60,
+                    len: 0,
+                },
+                cname: "www.example.org".parse().unwrap(),
+            }],
+            ..Default::default()
+        };
+        let bytes = packet.to_bytes();
+        assert!(DnsParser::new(&bytes).parse_packet().is_ok());
+
+        for len in 0..bytes.len() {
+            assert_eq!(
+                DnsParser::new(&bytes[..len]).parse_packet(),
+                Err(DnsParseError::UnexpectedEof),
+                "parsing the first {len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_record_types() {
+        let meta = |r#type| AnswerMeta {
+            name: "example.com".parse().unwrap(),
+            r#type,
+            class: Class::IN,
+            ttl: 60,
+            len: 0,
+        };
+        let answers = vec![
+            Answer::AAAA {
+                meta: meta(RecordType::AAAA),
+                ipv6: "2001:db8::1".parse().unwrap(),
+            },
+            Answer::NS {
+                meta: meta(RecordType::NS),
+                ns: "ns1.example.com".parse().unwrap(),
+            },
+            Answer::SOA {
+                meta: meta(RecordType::SOA),
+                mname: "ns1.example.com".parse().unwrap(),
+                rname: "hostmaster.example.com".parse().unwrap(),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+            Answer::MX {
+                meta: meta(RecordType::MX),
+                preference: 10,
+                exchange: "mail.example.com".parse().unwrap(),
+            },
+            Answer::TXT {
+                meta: meta(RecordType::TXT),
+                txt: vec![b"v=spf1 -all".to_vec(), vec![], vec![0xFF; 3]],
+            },
+            Answer::SRV {
+                meta: meta(RecordType::SRV),
+                priority: 1,
+                weight: 5,
+                port: 5060,
+                target: "sip.example.com".parse().unwrap(),
+            },
+            Answer::PTR {
+                meta: meta(RecordType::PTR),
+                ptr: "host.example.com".parse().unwrap(),
+            },
+            Answer::Unknown {
+                meta: meta(RecordType::OTHER(65280)),
+                rdata: vec![1, 2, 3],
+            },
+        ];
+        let packet = Packet {
+            answers,
+            ..Default::default()
+        };
+        let bytes = packet.to_bytes();
+
+        let parsed = DnsParser::new(&bytes).parse_packet().unwrap();
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert_eq!(parsed.answers.len(), 8);
+        assert!(matches!(
+            &parsed.answers[2],
+            Answer::SOA { serial: 2024010101, minimum: 300, rname, .. } if rname == "hostmaster.example.com"
+        ));
+        assert!(matches!(&parsed.answers[4], Answer::TXT { txt, .. } if txt.len() == 3));
+        assert!(matches!(
+            &parsed.answers[7],
+            Answer::Unknown { meta, rdata } if meta.len == 3 && rdata == &[1, 2, 3]
+        ));
+    }
+
+    #[test]
+    fn test_rdlength_mismatch() {
+        let mut bytes = Packet {
+            answers: vec![Answer::Unknown {
+                meta: AnswerMeta {
+                    name: DnsName::root(),
+                    r#type: RecordType::A,
+                    class: Class::IN,
+                    ttl: 60,
+                    len: 0,
+                },
+                rdata: vec![10, 0, 0, 1, 0],
+            }],
+            ..Default::default()
+        }
+        .to_bytes();
+        assert_eq!(
+            DnsParser::new(&bytes).parse_packet(),
+            Err(DnsParseError::InvalidRecordData(RecordType::A))
+        );
+
+        // RDLENGTH claims more data than the message contains
+        let rdlength = bytes.len() - 7;
+        bytes[rdlength] = 0xFF;
+        assert_eq!(
+            DnsParser::new(&bytes).parse_packet(),
+            Err(DnsParseError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_parse_opt() {
+        use crate::protocol::{
+            opt::{EdnsOption, Opt},
+            query::QueryBuilder,
+        };
+
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .edns_payload_size(4096)
+            .dnssec_ok(true)
+            .build();
+        let opt = DnsParser::new(&query).parse_opt().unwrap().unwrap();
+        assert_eq!(opt.payload_size, 4096);
+        assert!(opt.dnssec_ok);
+        assert!(opt.options.is_empty());
+
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        assert_eq!(DnsParser::new(&query).parse_opt(), Ok(None));
+
+        // an answer in front of an OPT record with an option
+        let mut response = Packet {
+            answers: vec![Answer::A {
+                meta: AnswerMeta {
+                    name: "example.com".parse().unwrap(),
+                    r#type: RecordType::A,
+                    class: Class::IN,
+                    ttl: 60,
+                    len: 4,
+                },
+                ipv4: [192, 0, 2, 1].into(),
+            }],
+            ..Default::default()
+        }
+        .to_bytes();
+        response[11] = 1; // additional count
+        response.extend_from_slice(&[0, 0, 41, 0x04, 0xD0, 1, 0, 0, 0, 0, 6, 0, 15, 0, 2, 0, 18]);
+        assert_eq!(
+            DnsParser::new(&response).parse_opt(),
+            Ok(Some(Opt {
+                payload_size: 1232,
+                extended_rcode: 1,
+                version: 0,
+                dnssec_ok: false,
+                options: vec![EdnsOption {
+                    code: 15,
+                    data: vec![0, 18]
+                }],
+            }))
+        );
+    }
 }
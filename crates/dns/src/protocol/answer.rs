@@ -1,18 +1,516 @@
-use std::net::Ipv4Addr;
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-use super::record_type::RecordType;
+use crate::serialize::writer::PacketWriter;
 
-#[derive(Debug)]
+use super::{class::Class, name::DnsName, record_type::RecordType};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct AnswerMeta {
-    pub name: String,
+    pub name: DnsName,
     pub r#type: RecordType,
-    pub class: usize,
+    pub class: Class,
     pub ttl: usize,
     pub len: usize,
 }
 
-#[derive(Debug)]
+impl AnswerMeta {
+    /// Appends the wire format of the resource record header to `buf`, ie. everything up to and
+    /// including RDLENGTH.
+    /// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.3
+    pub fn to_bytes(&self, buf: &mut Vec<u8>) {
+        self.write(&mut PacketWriter::uncompressed(buf));
+    }
+
+    pub fn write(&self, writer: &mut PacketWriter) {
+        writer.write_name(&self.name);
+        writer.write_u16(self.r#type.into());
+        writer.write_u16(self.class.into());
+        writer.write_u32(self.ttl as u32);
+        writer.write_u16(self.len as u16);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Answer {
-    A { meta: AnswerMeta, ipv4: Ipv4Addr },
-    CNAME { meta: AnswerMeta, cname: String },
+    A {
+        meta: AnswerMeta,
+        ipv4: Ipv4Addr,
+    },
+    NS {
+        meta: AnswerMeta,
+        ns: DnsName,
+    },
+    CNAME {
+        meta: AnswerMeta,
+        cname: DnsName,
+    },
+    /// https://datatracker.ietf.org/doc/html/rfc1035#section-3.3.13
+    SOA {
+        meta: AnswerMeta,
+        mname: DnsName,
+        rname: DnsName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR {
+        meta: AnswerMeta,
+        ptr: DnsName,
+    },
+    MX {
+        meta: AnswerMeta,
+        preference: u16,
+        exchange: DnsName,
+    },
+    /// One or more character strings, which are not necessarily valid UTF-8
+    TXT {
+        meta: AnswerMeta,
+        txt: Vec<Vec<u8>>,
+    },
+    AAAA {
+        meta: AnswerMeta,
+        ipv6: Ipv6Addr,
+    },
+    /// https://datatracker.ietf.org/doc/html/rfc2782
+    SRV {
+        meta: AnswerMeta,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsName,
+    },
+    /// Any record type without a dedicated variant, with its RDATA kept as is
+    Unknown {
+        meta: AnswerMeta,
+        rdata: Vec<u8>,
+    },
+}
+
+impl Answer {
+    pub fn meta(&self) -> &AnswerMeta {
+        match self {
+            Answer::A { meta, .. }
+            | Answer::NS { meta, .. }
+            | Answer::CNAME { meta, .. }
+            | Answer::SOA { meta, .. }
+            | Answer::PTR { meta, .. }
+            | Answer::MX { meta, .. }
+            | Answer::TXT { meta, .. }
+            | Answer::AAAA { meta, .. }
+            | Answer::SRV { meta, .. }
+            | Answer::Unknown { meta, .. } => meta,
+        }
+    }
+
+    pub fn meta_mut(&mut self) -> &mut AnswerMeta {
+        match self {
+            Answer::A { meta, .. }
+            | Answer::NS { meta, .. }
+            | Answer::CNAME { meta, .. }
+            | Answer::SOA { meta, .. }
+            | Answer::PTR { meta, .. }
+            | Answer::MX { meta, .. }
+            | Answer::TXT { meta, .. }
+            | Answer::AAAA { meta, .. }
+            | Answer::SRV { meta, .. }
+            | Answer::Unknown { meta, .. } => meta,
+        }
+    }
+
+    /// The RDATA of this record in presentation format, eg. `10 mail.example.com.` for MX
+    pub fn rdata_to_string(&self) -> String {
+        Rdata(self).to_string()
+    }
+
+    /// Builds a record of `meta.r#type` from its RDATA in presentation format, the inverse of
+    /// [`Answer::rdata_to_string`]. Types without a dedicated variant take the generic
+    /// `\# len hex` format.
+    /// https://datatracker.ietf.org/doc/html/rfc3597#section-5
+    pub fn parse_rdata(meta: AnswerMeta, rdata: &str) -> Result<Self, String> {
+        if meta.r#type == RecordType::TXT {
+            let txt = parse_character_strings(rdata)?;
+            return Ok(Answer::TXT { meta, txt });
+        }
+        let fields: Vec<_> = rdata.split_ascii_whitespace().collect();
+        let answer = match (meta.r#type, fields.as_slice()) {
+            (RecordType::A, [ipv4]) => Answer::A {
+                meta,
+                ipv4: field(ipv4)?,
+            },
+            (RecordType::AAAA, [ipv6]) => Answer::AAAA {
+                meta,
+                ipv6: field(ipv6)?,
+            },
+            (RecordType::NS, [ns]) => Answer::NS {
+                meta,
+                ns: field(ns)?,
+            },
+            (RecordType::CNAME, [cname]) => Answer::CNAME {
+                meta,
+                cname: field(cname)?,
+            },
+            (RecordType::PTR, [ptr]) => Answer::PTR {
+                meta,
+                ptr: field(ptr)?,
+            },
+            (RecordType::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+                Answer::SOA {
+                    meta,
+                    mname: field(mname)?,
+                    rname: field(rname)?,
+                    serial: field(serial)?,
+                    refresh: field(refresh)?,
+                    retry: field(retry)?,
+                    expire: field(expire)?,
+                    minimum: field(minimum)?,
+                }
+            }
+            (RecordType::MX, [preference, exchange]) => Answer::MX {
+                meta,
+                preference: field(preference)?,
+                exchange: field(exchange)?,
+            },
+            (RecordType::SRV, [priority, weight, port, target]) => Answer::SRV {
+                meta,
+                priority: field(priority)?,
+                weight: field(weight)?,
+                port: field(port)?,
+                target: field(target)?,
+            },
+            (
+                RecordType::A
+                | RecordType::AAAA
+                | RecordType::NS
+                | RecordType::CNAME
+                | RecordType::PTR
+                | RecordType::SOA
+                | RecordType::MX
+                | RecordType::SRV,
+                _,
+            ) => return Err(format!("invalid {} RDATA {rdata}", meta.r#type)),
+            (_, ["\\#", len, hex @ ..]) => {
+                let rdata = decode_hex(&hex.concat())
+                    .filter(|bytes| Ok(bytes.len()) == len.parse())
+                    .ok_or_else(|| format!("invalid generic RDATA {rdata}"))?;
+                Answer::Unknown { meta, rdata }
+            }
+            _ => return Err(format!("unsupported {} RDATA {rdata}", meta.r#type)),
+        };
+        Ok(answer)
+    }
+
+    /// Appends the wire format of this resource record to `buf`.
+    /// RDLENGTH is derived from the written RDATA, so `meta.len` does not need to be accurate.
+    pub fn to_bytes(&self, buf: &mut Vec<u8>) {
+        self.write(&mut PacketWriter::uncompressed(buf));
+    }
+
+    /// Writes this resource record, compressing domain names in RDATA where RFC 1035 allows it
+    pub fn write(&self, writer: &mut PacketWriter) {
+        self.meta().write(writer);
+        let rdata_start = writer.len();
+        match self {
+            Answer::A { ipv4, .. } => writer.write_bytes(&ipv4.octets()),
+            Answer::NS { ns: name, .. }
+            | Answer::CNAME { cname: name, .. }
+            | Answer::PTR { ptr: name, .. } => writer.write_name(name),
+            Answer::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => {
+                writer.write_name(mname);
+                writer.write_name(rname);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    writer.write_u32(*value);
+                }
+            }
+            Answer::MX {
+                preference,
+                exchange,
+                ..
+            } => {
+                writer.write_u16(*preference);
+                writer.write_name(exchange);
+            }
+            Answer::TXT { txt, .. } => {
+                for string in txt {
+                    writer.write_u8(string.len() as u8);
+                    writer.write_bytes(string);
+                }
+            }
+            Answer::AAAA { ipv6, .. } => writer.write_bytes(&ipv6.octets()),
+            Answer::SRV {
+                priority,
+                weight,
+                port,
+                target,
+                ..
+            } => {
+                writer.write_u16(*priority);
+                writer.write_u16(*weight);
+                writer.write_u16(*port);
+                // https://datatracker.ietf.org/doc/html/rfc2782 forbids compressing the target
+                writer.write_name_uncompressed(target);
+            }
+            Answer::Unknown { rdata, .. } => writer.write_bytes(rdata),
+        }
+        let rdata_len = (writer.len() - rdata_start) as u16;
+        writer.patch_u16(rdata_start - 2, rdata_len);
+    }
+}
+
+/// The record as a line of a zone file, eg. `example.com. 300 IN A 192.0.2.1`
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-5.1
+impl Display for Answer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let meta = self.meta();
+        write!(
+            f,
+            "{} {} {} {} {}",
+            meta.name.to_fqdn(),
+            meta.ttl,
+            meta.class,
+            meta.r#type,
+            Rdata(self)
+        )
+    }
+}
+
+/// Formats only the RDATA of a record in presentation format
+struct Rdata<'a>(&'a Answer);
+
+impl Display for Rdata<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Answer::A { ipv4, .. } => write!(f, "{ipv4}"),
+            Answer::NS { ns: name, .. }
+            | Answer::CNAME { cname: name, .. }
+            | Answer::PTR { ptr: name, .. } => write!(f, "{}", name.to_fqdn()),
+            Answer::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {serial} {refresh} {retry} {expire} {minimum}",
+                mname.to_fqdn(),
+                rname.to_fqdn()
+            ),
+            Answer::MX {
+                preference,
+                exchange,
+                ..
+            } => write!(f, "{preference} {}", exchange.to_fqdn()),
+            Answer::TXT { txt, .. } => {
+                let strings: Vec<_> = txt.iter().map(|string| quote(string)).collect();
+                f.write_str(&strings.join(" "))
+            }
+            Answer::AAAA { ipv6, .. } => write!(f, "{ipv6}"),
+            Answer::SRV {
+                priority,
+                weight,
+                port,
+                target,
+                ..
+            } => write!(f, "{priority} {weight} {port} {}", target.to_fqdn()),
+            // https://datatracker.ietf.org/doc/html/rfc3597#section-5
+            Answer::Unknown { rdata, .. } => {
+                write!(f, "\\# {}", rdata.len())?;
+                if !rdata.is_empty() {
+                    f.write_str(" ")?;
+                }
+                rdata.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+        }
+    }
+}
+
+/// A character string in quotes, escaping quotes and backslashes, and other than printable
+/// ASCII as `\DDD`
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-5.1
+fn quote(string: &[u8]) -> String {
+    let mut quoted = String::from('"');
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            }
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\{byte:03}")),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn field<T: FromStr>(field: &str) -> Result<T, String> {
+    field
+        .parse()
+        .map_err(|_| format!("invalid RDATA field {field}"))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Character strings separated by whitespace, in quotes or not, with their escapes resolved
+fn parse_character_strings(rdata: &str) -> Result<Vec<Vec<u8>>, String> {
+    let invalid = || format!("invalid character strings {rdata}");
+    let mut bytes = rdata.bytes().peekable();
+    let mut strings = vec![];
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let Some(&first) = bytes.peek() else {
+            break;
+        };
+        let quoted = first == b'"';
+        if quoted {
+            bytes.next();
+        }
+        let mut string = vec![];
+        loop {
+            match bytes.next() {
+                Some(b'"') if quoted => break,
+                Some(byte) if !quoted && byte.is_ascii_whitespace() => break,
+                None if quoted => return Err(invalid()),
+                None => break,
+                Some(b'\\') => match bytes.next() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        let mut value = (digit - b'0') as u16;
+                        for _ in 0..2 {
+                            let digit = bytes
+                                .next()
+                                .filter(u8::is_ascii_digit)
+                                .ok_or_else(invalid)?;
+                            value = value * 10 + (digit - b'0') as u16;
+                        }
+                        string.push(u8::try_from(value).map_err(|_| invalid())?);
+                    }
+                    Some(escaped) => string.push(escaped),
+                    None => return Err(invalid()),
+                },
+                Some(byte) => string.push(byte),
+            }
+        }
+        if string.len() > u8::MAX as usize {
+            return Err(invalid());
+        }
+        strings.push(string);
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{class::Class, record_type::RecordType};
+
+    use super::{Answer, AnswerMeta};
+
+    fn meta(r#type: RecordType) -> AnswerMeta {
+        AnswerMeta {
+            name: "example.com".parse().unwrap(),
+            r#type,
+            class: Class::IN,
+            ttl: 300,
+            len: 0,
+        }
+    }
+
+    #[test]
+    fn test_answer_presentation() {
+        let a = Answer::A {
+            meta: meta(RecordType::A),
+            ipv4: [192, 0, 2, 1].into(),
+        };
+        assert_eq!(a.to_string(), "example.com. 300 IN A 192.0.2.1");
+
+        let mx = Answer::MX {
+            meta: meta(RecordType::MX),
+            preference: 10,
+            exchange: "mail.example.com".parse().unwrap(),
+        };
+        assert_eq!(
+            mx.to_string(),
+            "example.com. 300 IN MX 10 mail.example.com."
+        );
+
+        let txt = Answer::TXT {
+            meta: meta(RecordType::TXT),
+            txt: vec![b"v=spf1 -all".to_vec(), b"say \"hi\"\n".to_vec()],
+        };
+        assert_eq!(
+            txt.to_string(),
+            r#"example.com. 300 IN TXT "v=spf1 -all" "say \"hi\"\010""#
+        );
+
+        let srv = Answer::SRV {
+            meta: meta(RecordType::SRV),
+            priority: 0,
+            weight: 0,
+            port: 0,
+            target: ".".parse().unwrap(),
+        };
+        assert_eq!(srv.to_string(), "example.com. 300 IN SRV 0 0 0 .");
+
+        let unknown = Answer::Unknown {
+            meta: meta(RecordType::OTHER(65)),
+            rdata: vec![0, 1, 0xab],
+        };
+        assert_eq!(
+            unknown.to_string(),
+            r"example.com. 300 IN TYPE65 \# 3 0001ab"
+        );
+    }
+
+    #[test]
+    fn test_parse_rdata() {
+        for (r#type, rdata) in [
+            (RecordType::A, "192.0.2.1"),
+            (RecordType::AAAA, "2001:db8::1"),
+            (RecordType::CNAME, "www.example.com."),
+            (RecordType::MX, "10 mail.example.com."),
+            (
+                RecordType::SOA,
+                "ns.example.com. hostmaster.example.com. 1 7200 3600 1209600 300",
+            ),
+            (RecordType::SRV, "10 60 5222 xmpp.example.com."),
+            (RecordType::TXT, r#""v=spf1 -all" "say \"hi\"\010""#),
+            (RecordType::OTHER(65), r"\# 3 0001ab"),
+        ] {
+            let answer = Answer::parse_rdata(meta(r#type), rdata).unwrap();
+            assert_eq!(answer.rdata_to_string(), rdata);
+        }
+
+        let txt = Answer::parse_rdata(meta(RecordType::TXT), r"unquoted t\101xt").unwrap();
+        assert_eq!(txt.rdata_to_string(), r#""unquoted" "text""#);
+        assert!(Answer::parse_rdata(meta(RecordType::TXT), r#""open"#).is_err());
+        assert!(Answer::parse_rdata(meta(RecordType::A), "2001:db8::1").is_err());
+        assert!(Answer::parse_rdata(meta(RecordType::MX), "mail.example.com.").is_err());
+        assert!(Answer::parse_rdata(meta(RecordType::A), r"\# 4 c0000201").is_err());
+        assert!(Answer::parse_rdata(meta(RecordType::OTHER(65)), r"\# 2 0001ab").is_err());
+    }
 }
@@ -0,0 +1,76 @@
+use std::{fmt::Display, str::FromStr};
+
+/// CLASS and QCLASS values
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-3.2.4
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Class {
+    #[default]
+    IN, // 1 the Internet
+    CH, // 3 the CHAOS class
+    HS, // 4 Hesiod
+    // QCLASSes
+    NONE, // 254 used by dynamic updates, RFC 2136
+    ANY,  // 255 any class
+    Other(u16),
+}
+
+impl From<u16> for Class {
+    fn from(input: u16) -> Self {
+        match input {
+            1 => Self::IN,
+            3 => Self::CH,
+            4 => Self::HS,
+            254 => Self::NONE,
+            255 => Self::ANY,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<Class> for u16 {
+    fn from(class: Class) -> Self {
+        match class {
+            Class::IN => 1,
+            Class::CH => 3,
+            Class::HS => 4,
+            Class::NONE => 254,
+            Class::ANY => 255,
+            Class::Other(other) => other,
+        }
+    }
+}
+
+impl Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Class::IN => f.write_str("IN"),
+            Class::CH => f.write_str("CH"),
+            Class::HS => f.write_str("HS"),
+            Class::NONE => f.write_str("NONE"),
+            Class::ANY => f.write_str("ANY"),
+            // https://datatracker.ietf.org/doc/html/rfc3597#section-5
+            Class::Other(other) => write!(f, "CLASS{other}"),
+        }
+    }
+}
+
+impl FromStr for Class {
+    type Err = String;
+
+    /// Parses a mnemonic like `IN` regardless of case, or the generic `CLASS1`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let class = match s.to_ascii_uppercase().as_str() {
+            "IN" => Self::IN,
+            "CH" => Self::CH,
+            "HS" => Self::HS,
+            "NONE" => Self::NONE,
+            "ANY" | "*" => Self::ANY,
+            other => match other.strip_prefix("CLASS").map(str::parse::<u16>) {
+                Some(Ok(number)) => Self::from(number),
+                _ => return Err(format!("unknown class {s}")),
+            },
+        };
+        Ok(class)
+    }
+}
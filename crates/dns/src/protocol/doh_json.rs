@@ -0,0 +1,216 @@
+//! The JSON format of DNS over HTTPS APIs like the ones of Google and Cloudflare, served as
+//! `application/dns-json`.
+//! https://developers.google.com/speed/public-dns/docs/doh/json
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    answer::{Answer, AnswerMeta},
+    class::Class,
+    header::{Flags, Header},
+    packet::Packet,
+    question::Question,
+    record_type::RecordType,
+    response_code::ResponseCode,
+};
+
+pub const DNS_JSON: &str = "application/dns-json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DohJson {
+    #[serde(rename = "Status")]
+    status: u16,
+    #[serde(rename = "TC", default)]
+    truncation: bool,
+    #[serde(rename = "RD", default)]
+    recursion_desired: bool,
+    #[serde(rename = "RA", default)]
+    recursion_available: bool,
+    #[serde(rename = "AD", default)]
+    authenticated_data: bool,
+    #[serde(rename = "CD", default)]
+    checking_disabled: bool,
+    #[serde(rename = "Question", default)]
+    question: Vec<JsonQuestion>,
+    #[serde(rename = "Answer", default, skip_serializing_if = "Vec::is_empty")]
+    answer: Vec<JsonRecord>,
+    #[serde(rename = "Authority", default, skip_serializing_if = "Vec::is_empty")]
+    authority: Vec<JsonRecord>,
+    #[serde(rename = "Additional", default, skip_serializing_if = "Vec::is_empty")]
+    additional: Vec<JsonRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonQuestion {
+    name: String,
+    r#type: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonRecord {
+    name: String,
+    r#type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+impl JsonRecord {
+    fn new(answer: &Answer) -> Self {
+        let meta = answer.meta();
+        Self {
+            name: meta.name.to_fqdn(),
+            r#type: meta.r#type.into(),
+            ttl: meta.ttl as u32,
+            data: answer.rdata_to_string(),
+        }
+    }
+
+    fn to_answer(&self) -> Result<Answer, String> {
+        let meta = AnswerMeta {
+            name: self.name.parse().map_err(|e| format!("{e}"))?,
+            r#type: RecordType::from(self.r#type as usize),
+            class: Class::IN,
+            ttl: self.ttl as usize,
+            len: 0,
+        };
+        Answer::parse_rdata(meta, &self.data)
+    }
+}
+
+impl Packet {
+    /// This message as an `application/dns-json` response. The format has no place for the
+    /// EDNS OPT record, so it is left out, and `Status` is the response code of the header.
+    pub fn to_doh_json(&self) -> String {
+        let flags = &self.header.flags;
+        let records = |section: &[Answer]| {
+            section
+                .iter()
+                .filter(|answer| answer.meta().r#type != RecordType::OPT)
+                .map(JsonRecord::new)
+                .collect()
+        };
+        let json = DohJson {
+            status: self.header.rcode().into(),
+            truncation: flags.truncation,
+            recursion_desired: flags.recursion_desired,
+            recursion_available: flags.recursion_available,
+            authenticated_data: flags.authenticated_data,
+            checking_disabled: flags.checking_disabled,
+            question: self
+                .questions
+                .iter()
+                .map(|question| JsonQuestion {
+                    name: question.domain_name.to_fqdn(),
+                    r#type: question.r#type.into(),
+                })
+                .collect(),
+            answer: records(&self.answers),
+            authority: records(&self.authorities),
+            additional: records(&self.additionals),
+        };
+        serde_json::to_string(&json).expect("the JSON message only holds strings and numbers")
+    }
+
+    /// Parses an `application/dns-json` response into a response message with request id 0,
+    /// all records are of class IN
+    pub fn from_doh_json(json: &str) -> Result<Self, String> {
+        let json: DohJson = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let records = |section: &[JsonRecord]| {
+            section
+                .iter()
+                .map(JsonRecord::to_answer)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let packet = Packet {
+            header: Header {
+                flags: Flags {
+                    query: false,
+                    truncation: json.truncation,
+                    recursion_desired: json.recursion_desired,
+                    recursion_available: json.recursion_available,
+                    authenticated_data: json.authenticated_data,
+                    checking_disabled: json.checking_disabled,
+                    response_code: ResponseCode::from(json.status),
+                    ..Flags::default()
+                },
+                question_count: json.question.len() as u16,
+                answer_count: json.answer.len() as u16,
+                authority_count: json.authority.len() as u16,
+                additional_count: json.additional.len() as u16,
+                ..Header::default()
+            },
+            questions: json
+                .question
+                .iter()
+                .map(|question| {
+                    Ok(Question {
+                        domain_name: question.name.parse().map_err(|e| format!("{e}"))?,
+                        r#type: RecordType::from(question.r#type as usize),
+                        class: Class::IN,
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+            answers: records(&json.answer)?,
+            authorities: records(&json.authority)?,
+            additionals: records(&json.additional)?,
+        };
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        packet::Packet,
+        record_type::RecordType,
+        response_code::ResponseCode,
+    };
+
+    #[test]
+    fn test_doh_json() {
+        let json = r#"{"Status": 0, "TC": false, "RD": true, "RA": true, "AD": false, "CD": false,
+            "Question": [{"name": "www.example.com.", "type": 1}],
+            "Answer": [
+                {"name": "www.example.com.", "type": 5, "TTL": 3600, "data": "example.com."},
+                {"name": "example.com.", "type": 1, "TTL": 300, "data": "192.0.2.1"}
+            ],
+            "Comment": "Response from 192.0.2.53."}"#;
+        let packet = Packet::from_doh_json(json).unwrap();
+        assert!(!packet.header.flags.query);
+        assert!(packet.header.flags.recursion_available);
+        assert_eq!(packet.questions[0].to_string(), "www.example.com. IN A");
+        assert_eq!(
+            packet.answers[1].to_string(),
+            "example.com. 300 IN A 192.0.2.1"
+        );
+        assert_eq!(
+            Packet::from_doh_json(&packet.to_doh_json()).unwrap(),
+            packet
+        );
+        assert!(packet.to_doh_json().starts_with(
+            r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"Question":[{"name":"www.example.com.","type":1}],"Answer":[{"name":"www.example.com.","type":5,"TTL":3600,"data":"example.com."}"#
+        ));
+
+        let mut nxdomain = Packet::from_doh_json(r#"{"Status": 3}"#).unwrap();
+        assert_eq!(nxdomain.header.rcode(), ResponseCode::NXDomain);
+        nxdomain.additionals.push(Answer::Unknown {
+            meta: AnswerMeta {
+                name: ".".parse().unwrap(),
+                r#type: RecordType::OPT,
+                class: Class::from(1232),
+                ttl: 0,
+                len: 0,
+            },
+            rdata: vec![],
+        });
+        assert_eq!(
+            nxdomain.to_doh_json(),
+            r#"{"Status":3,"TC":false,"RD":false,"RA":false,"AD":false,"CD":false,"Question":[]}"#
+        );
+
+        assert!(Packet::from_doh_json(r#"{"Status": 0, "Answer": [{"name": "example.com.", "type": 1, "TTL": 1, "data": "x"}]}"#).is_err());
+    }
+}
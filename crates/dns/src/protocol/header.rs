@@ -1,3 +1,7 @@
+use std::fmt::Display;
+
+use super::{opcode::Opcode, response_code::ResponseCode};
+
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct Header {
     pub request_id: u16,
@@ -8,6 +12,18 @@ pub struct Header {
     pub additional_count: u16,
 }
 
+impl Header {
+    pub fn opcode(&self) -> Opcode {
+        self.flags.opcode
+    }
+
+    /// The response code as far as it is contained in the header, ie. without the bits an EDNS
+    /// OPT record might add
+    pub fn rcode(&self) -> ResponseCode {
+        self.flags.response_code
+    }
+}
+
 impl From<Header> for [u8; 12] {
     fn from(header: Header) -> Self {
         let raw_flags: u16 = header.flags.into();
@@ -22,29 +38,39 @@ impl From<Header> for [u8; 12] {
     }
 }
 
+/// The second 16 bits of the header
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
+/// https://datatracker.ietf.org/doc/html/rfc4035#section-3.2
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct Flags {
     pub query: bool,
-    pub opcode: u8,
+    pub opcode: Opcode,
     pub authoritative_answer: bool,
     pub truncation: bool,
     pub recursion_desired: bool,
     pub recursion_available: bool,
-    pub z: u8,
-    pub response_code: u8,
+    /// Reserved bit, must be zero in queries but is preserved as is
+    pub z: bool,
+    /// AD bit, set by DNSSEC validating resolvers if all answers were verified
+    pub authenticated_data: bool,
+    /// CD bit, asks an upstream resolver not to perform DNSSEC validation
+    pub checking_disabled: bool,
+    pub response_code: ResponseCode,
 }
 
 impl From<u16> for Flags {
     fn from(input: u16) -> Self {
         Self {
             query: (input >> 15 & 1) == 0,
-            opcode: (input >> 11 & 4) as u8,
+            opcode: Opcode::from((input >> 11 & 0xF) as u8),
             authoritative_answer: (input >> 10 & 1) > 0,
             truncation: (input >> 9 & 1) > 0,
             recursion_desired: (input >> 8 & 1) > 0,
             recursion_available: (input >> 7 & 1) > 0,
-            z: (input >> 4 & 3) as u8,
-            response_code: (input & 4) as u8,
+            z: (input >> 6 & 1) > 0,
+            authenticated_data: (input >> 5 & 1) > 0,
+            checking_disabled: (input >> 4 & 1) > 0,
+            response_code: ResponseCode::from(input & 0xF),
         }
     }
 }
@@ -53,13 +79,57 @@ impl From<Flags> for u16 {
     fn from(flags: Flags) -> Self {
         let mut value = 0u16;
         value |= if flags.query { 0 } else { 0x8000 }; // MSB needs to be set
-        value |= (flags.opcode as u16) << 11;
+        value |= (u8::from(flags.opcode) as u16 & 0xF) << 11;
         value |= u16::from(flags.authoritative_answer) << 10;
         value |= u16::from(flags.truncation) << 9;
         value |= u16::from(flags.recursion_desired) << 8;
         value |= u16::from(flags.recursion_available) << 7;
-        value |= (flags.z as u16) << 3;
-        value |= flags.response_code as u16;
+        value |= u16::from(flags.z) << 6;
+        value |= u16::from(flags.authenticated_data) << 5;
+        value |= u16::from(flags.checking_disabled) << 4;
+        value |= flags.response_code.header_bits() as u16;
         value
     }
 }
+
+/// The header as dig prints it, on two lines
+impl Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            self.opcode(),
+            self.rcode(),
+            self.request_id
+        )?;
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.flags,
+            self.question_count,
+            self.answer_count,
+            self.authority_count,
+            self.additional_count
+        )
+    }
+}
+
+/// The set flag bits by their lowercase mnemonics, eg. `qr rd ra`
+impl Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bits = [
+            (!self.query, "qr"),
+            (self.authoritative_answer, "aa"),
+            (self.truncation, "tc"),
+            (self.recursion_desired, "rd"),
+            (self.recursion_available, "ra"),
+            (self.authenticated_data, "ad"),
+            (self.checking_disabled, "cd"),
+        ];
+        let set: Vec<_> = bits
+            .into_iter()
+            .filter_map(|(set, mnemonic)| set.then_some(mnemonic))
+            .collect();
+        f.write_str(&set.join(" "))
+    }
+}
@@ -1,5 +1,12 @@
 pub mod answer;
+pub mod class;
+pub mod doh_json;
 pub mod header;
+pub mod name;
+pub mod opcode;
+pub mod opt;
+pub mod packet;
+pub mod query;
 pub mod question;
 pub mod record_type;
 pub mod response_code;
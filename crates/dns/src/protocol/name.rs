@@ -0,0 +1,716 @@
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    fmt::{Display, Write},
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use crate::parse::error::DnsParseError;
+
+/// Upper bound for compression pointers followed while reading a single name. Pointers must
+/// lead backwards so they cannot loop, this bounds the work a malicious packet can cause.
+const MAX_POINTER_HOPS: usize = 64;
+
+/// Maximum length of a domain name in wire format, including length bytes and the root label
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-2.3.4
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Presentation format of the root name
+const ROOT: &str = ".";
+
+/// Maximum length of a single label
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// An owned, validated domain name.
+///
+/// Names are kept in presentation format without the trailing dot, eg. `www.example.com`, except
+/// for the root name which is represented as `.`. Label bytes that can not appear verbatim are
+/// escaped as `\.` or `\DDD`, always using the same canonical escaping.
+/// Comparison and hashing ignore ASCII case, as required by RFC 4343.
+#[derive(Debug, Clone, Eq)]
+pub struct DnsName(String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    EmptyLabel,
+    LabelTooLong(usize),
+    NameTooLong(usize),
+    /// The name could not be converted to its ASCII form according to IDNA
+    InvalidIdn(String),
+    /// A backslash escape is incomplete or `\DDD` exceeds 255
+    InvalidEscape(String),
+}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameError::EmptyLabel => write!(f, "domain name contains an empty label"),
+            NameError::LabelTooLong(len) => {
+                write!(f, "label of {len} octets exceeds {MAX_LABEL_LEN} octets")
+            }
+            NameError::NameTooLong(len) => {
+                write!(
+                    f,
+                    "domain name of {len} octets exceeds {MAX_NAME_LEN} octets"
+                )
+            }
+            NameError::InvalidIdn(name) => write!(f, "invalid internationalized name {name:?}"),
+            NameError::InvalidEscape(label) => write!(f, "invalid escape sequence in {label:?}"),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+impl DnsName {
+    /// Validates `name` in presentation format, a single trailing dot is accepted and dropped.
+    /// Escapes like `\.` or `\032` are decoded per label.
+    /// https://datatracker.ietf.org/doc/html/rfc4343#section-2.1
+    pub fn new(name: &str) -> Result<Self, NameError> {
+        if name == ROOT {
+            return Ok(Self::root());
+        }
+
+        let mut labels: Vec<&str> = PresentationLabels { rest: Some(name) }.collect();
+        if labels.len() > 1 && labels.last() == Some(&"") {
+            labels.pop();
+        }
+        let labels = labels
+            .into_iter()
+            .map(unescape_label)
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::from_wire_labels(labels.iter().map(Vec::as_slice))
+    }
+
+    fn from_wire_labels<'a>(
+        labels: impl Iterator<Item = &'a [u8]> + Clone,
+    ) -> Result<Self, NameError> {
+        validate_labels(labels.clone().map(<[u8]>::len))?;
+
+        // the labels and the dots between them, unless some need escaping
+        let len = labels.clone().map(|label| label.len() + 1).sum::<usize>();
+        let mut name = String::with_capacity(len.saturating_sub(1));
+        for (i, label) in labels.enumerate() {
+            if i > 0 {
+                name.push('.');
+            }
+            escape_label(label, &mut name);
+        }
+        Ok(Self(name))
+    }
+
+    /// Converts a possibly internationalized name like `bücher.de` into its ASCII compatible
+    /// encoding (`xn--bcher-kva.de`) before validating it.
+    /// https://datatracker.ietf.org/doc/html/rfc5891#section-5
+    pub fn from_utf8(name: &str) -> Result<Self, NameError> {
+        if name.is_ascii() {
+            return Self::new(name);
+        }
+        let ascii =
+            idna::domain_to_ascii(name).map_err(|_| NameError::InvalidIdn(name.to_string()))?;
+        Self::new(&ascii)
+    }
+
+    pub fn root() -> Self {
+        Self(ROOT.to_string())
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0 == ROOT
+    }
+
+    /// Decodes punycode labels for display, falling back to the ASCII form for invalid labels
+    pub fn to_unicode(&self) -> String {
+        if self.is_root() {
+            return self.0.clone();
+        }
+        let (unicode, result) = idna::domain_to_unicode(&self.0);
+        match result {
+            Ok(_) => unicode,
+            Err(_) => self.0.clone(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// This name with the trailing dot of zone files, eg. `example.com.`
+    pub fn to_fqdn(&self) -> String {
+        if self.is_root() {
+            return self.0.clone();
+        }
+        format!("{}.", self.0)
+    }
+
+    /// Iterates over the labels of this name in (escaped) presentation format, which yields
+    /// nothing for the root name
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        let rest = (!self.is_root()).then_some(self.0.as_str());
+        PresentationLabels { rest }
+    }
+
+    /// Iterates over the raw label bytes as they appear on the wire
+    pub fn wire_labels(&self) -> impl Iterator<Item = Cow<'_, [u8]>> {
+        self.labels().map(|label| {
+            if label.contains('\\') {
+                // canonical escapes are always valid
+                Cow::Owned(unescape_label(label).unwrap_or_default())
+            } else {
+                Cow::Borrowed(label.as_bytes())
+            }
+        })
+    }
+
+    /// Number of labels, which is 0 for the root name
+    pub fn label_count(&self) -> usize {
+        self.labels().count()
+    }
+
+    /// Whether this name is `zone` itself or a name below it. Every name is below the root.
+    pub fn is_subdomain_of(&self, zone: &DnsName) -> bool {
+        let labels: Vec<_> = self.labels().collect();
+        let zone: Vec<_> = zone.labels().collect();
+        labels.len() >= zone.len()
+            && labels[labels.len() - zone.len()..]
+                .iter()
+                .zip(&zone)
+                .all(|(label, zone_label)| label.eq_ignore_ascii_case(zone_label))
+    }
+
+    /// The name made of the last `label_count` labels, eg. `example.com` for `www.example.com`
+    /// and 2 labels. Yields the name itself if it has no more labels than that.
+    pub fn ancestor(&self, label_count: usize) -> DnsName {
+        let labels: Vec<_> = self.labels().collect();
+        match labels.len().checked_sub(label_count) {
+            None | Some(0) => self.clone(),
+            Some(_) if label_count == 0 => Self::root(),
+            Some(skip) => Self(labels[skip..].join(".")),
+        }
+    }
+
+    /// This name with all ASCII letters in lower case, the canonical form DNSSEC signs
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-6.2
+    pub fn to_lowercase(&self) -> DnsName {
+        Self(self.0.to_ascii_lowercase())
+    }
+
+    /// Compares names in the canonical order of DNSSEC, label by label starting at the root and
+    /// ignoring ASCII case, so a name sorts right before the names below it
+    /// https://datatracker.ietf.org/doc/html/rfc4034#section-6.1
+    pub fn canonical_cmp(&self, other: &DnsName) -> Ordering {
+        let labels = |name: &DnsName| -> Vec<Vec<u8>> {
+            name.wire_labels()
+                .map(|label| label.to_ascii_lowercase())
+                .collect()
+        };
+        labels(self).iter().rev().cmp(labels(other).iter().rev())
+    }
+
+    /// Length of this name in uncompressed wire format
+    pub fn wire_len(&self) -> usize {
+        self.wire_labels()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+}
+
+/// Splits a presentation format name at all dots that are not escaped
+struct PresentationLabels<'a> {
+    rest: Option<&'a str>,
+}
+
+impl<'a> Iterator for PresentationLabels<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest?;
+        let bytes = rest.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                // the escaped byte can never be a dot, `\DDD` are all digits
+                b'\\' => i += 2,
+                b'.' => {
+                    self.rest = Some(&rest[i + 1..]);
+                    return Some(&rest[..i]);
+                }
+                _ => i += 1,
+            }
+        }
+        self.rest = None;
+        Some(rest)
+    }
+}
+
+fn unescape_label(label: &str) -> Result<Vec<u8>, NameError> {
+    let invalid = || NameError::InvalidEscape(label.to_string());
+    let bytes = label.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let escaped = bytes.get(i + 1).ok_or_else(invalid)?;
+        if escaped.is_ascii_digit() {
+            let digits = bytes.get(i + 1..i + 4).ok_or_else(invalid)?;
+            let value = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| digits.parse::<u8>().ok())
+                .ok_or_else(invalid)?;
+            out.push(value);
+            i += 4;
+        } else {
+            out.push(*escaped);
+            i += 2;
+        }
+    }
+    Ok(out)
+}
+
+/// Bytes escaped with a backslash in presentation format
+const SPECIAL: &[u8] = b".\\\"();@$";
+
+/// Appends `label` in canonical presentation format, escaping special and non-printable bytes
+fn escape_label(label: &[u8], out: &mut String) {
+    let verbatim = |byte: &u8| matches!(byte, 0x21..=0x7E) && !SPECIAL.contains(byte);
+    if label.iter().all(verbatim) {
+        // the common case, copied as a whole
+        out.push_str(std::str::from_utf8(label).expect("printable ASCII"));
+        return;
+    }
+    for byte in label {
+        match byte {
+            byte if SPECIAL.contains(byte) => {
+                out.push('\\');
+                out.push(*byte as char);
+            }
+            0x21..=0x7E => out.push(*byte as char),
+            _ => {
+                let _ = write!(out, "\\{byte:03}");
+            }
+        }
+    }
+}
+
+fn validate_labels(label_lengths: impl Iterator<Item = usize>) -> Result<(), NameError> {
+    let mut total = 1;
+    for len in label_lengths {
+        if len == 0 {
+            return Err(NameError::EmptyLabel);
+        }
+        if len > MAX_LABEL_LEN {
+            return Err(NameError::LabelTooLong(len));
+        }
+        total += len + 1;
+    }
+    if total > MAX_NAME_LEN {
+        return Err(NameError::NameTooLong(total));
+    }
+    Ok(())
+}
+
+impl Display for DnsName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for DnsName {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<&str> for DnsName {
+    type Error = NameError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl TryFrom<Name<'_>> for DnsName {
+    type Error = DnsParseError;
+
+    fn try_from(name: Name<'_>) -> Result<Self, Self::Error> {
+        // walked once for malformed pointers and labels, so the labels can be taken as they are
+        name.labels().try_for_each(|label| label.map(drop))?;
+        if name.is_root() {
+            return Ok(Self::root());
+        }
+        Ok(Self::from_wire_labels(name.labels().map_while(Result::ok))?)
+    }
+}
+
+impl AsRef<str> for DnsName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl PartialEq<str> for DnsName {
+    fn eq(&self, other: &str) -> bool {
+        if self.is_root() || other == ROOT {
+            return self.0 == other;
+        }
+        self.0
+            .eq_ignore_ascii_case(other.strip_suffix('.').unwrap_or(other))
+    }
+}
+
+impl PartialEq<&str> for DnsName {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for byte in self.0.bytes() {
+            state.write_u8(byte.to_ascii_lowercase());
+        }
+        state.write_u8(0);
+    }
+}
+
+/// A domain name borrowed from a DNS message.
+///
+/// Labels are not copied out of the message, compression pointers are only followed when the
+/// labels are actually iterated. Use `to_string()` to obtain an owned representation.
+#[derive(Debug, Clone, Copy)]
+pub struct Name<'a> {
+    message: &'a [u8],
+    /// Offset of the first label (or pointer) within `message`
+    offset: usize,
+}
+
+impl<'a> Name<'a> {
+    pub fn new(message: &'a [u8], offset: usize) -> Self {
+        Self { message, offset }
+    }
+
+    /// Iterates over the raw labels of this name, excluding the terminating root label. Ends
+    /// with an error if a label or pointer is cut off or a pointer does not lead backwards.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            message: self.message,
+            position: self.offset,
+            start: self.offset,
+            hops: 0,
+            failed: false,
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels().next().is_none()
+    }
+
+    /// Checks the labels like converting to a [`DnsName`] does, without building one
+    pub fn validate(&self) -> Result<(), DnsParseError> {
+        self.labels().try_for_each(|label| label.map(drop))?;
+        Ok(validate_labels(
+            self.labels().map_while(Result::ok).map(<[u8]>::len),
+        )?)
+    }
+
+    /// Compares this name with a dot-separated domain name, ignoring ASCII case
+    /// https://datatracker.ietf.org/doc/html/rfc4343
+    pub fn eq_ignore_ascii_case(&self, other: &str) -> bool {
+        let mut expected = PresentationLabels { rest: Some(other) }
+            .filter(|label| !label.is_empty())
+            .map(unescape_label);
+        let mut labels = self.labels();
+        loop {
+            match (labels.next(), expected.next()) {
+                (None, None) => return true,
+                (Some(Ok(label)), Some(Ok(other))) if label.eq_ignore_ascii_case(&other) => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Display for Name<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_root() {
+            return f.write_str(ROOT);
+        }
+        let mut name = String::new();
+        // as far as the labels are intact, names parsed from messages always are
+        for (i, label) in self.labels().map_while(Result::ok).enumerate() {
+            if i > 0 {
+                name.push('.');
+            }
+            escape_label(label, &mut name);
+        }
+        f.write_str(&name)
+    }
+}
+
+/// Iterator over the labels of a [`Name`], resolving compression pointers on the fly
+#[derive(Debug, Clone)]
+pub struct Labels<'a> {
+    message: &'a [u8],
+    position: usize,
+    /// Where the labels being read start, pointers must lead before it
+    start: usize,
+    hops: usize,
+    /// Whether an error was returned, after which there are no more labels
+    failed: bool,
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = Result<&'a [u8], DnsParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let label = self.walk().transpose();
+        self.failed = matches!(label, Some(Err(_)));
+        label
+    }
+}
+
+impl<'a> Labels<'a> {
+    /// Follows the pointers to the next label, `None` at the root label
+    fn walk(&mut self) -> Result<Option<&'a [u8]>, DnsParseError> {
+        loop {
+            let byte = |position: usize| {
+                (self.message.get(position).map(|&byte| byte as usize))
+                    .ok_or(DnsParseError::UnexpectedEof)
+            };
+            let len = byte(self.position)?;
+            if len == 0 {
+                return Ok(None);
+            }
+
+            if len & 0xC0 == 0xC0 {
+                // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+                let target = (len & 0x3F) << 8 | byte(self.position + 1)?;
+                self.hops += 1;
+                // names are only ever compressed to ones that came before, so each pointer
+                // leads further back than the last and none can loop
+                if target >= self.start || self.hops > MAX_POINTER_HOPS {
+                    return Err(DnsParseError::InvalidPointer(self.position));
+                }
+                (self.position, self.start) = (target, target);
+                continue;
+            }
+
+            let label = (self.message)
+                .get(self.position + 1..self.position + 1 + len)
+                .ok_or(DnsParseError::UnexpectedEof)?;
+            self.position += 1 + len;
+            return Ok(Some(label));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{DnsName, Name, NameError};
+    use crate::parse::error::DnsParseError;
+
+    #[test]
+    fn test_dns_name_validation() {
+        assert_eq!(
+            DnsName::new("example.com.").unwrap().as_str(),
+            "example.com"
+        );
+        assert_eq!(DnsName::new("a..b"), Err(NameError::EmptyLabel));
+        assert_eq!(DnsName::new(""), Err(NameError::EmptyLabel));
+        assert_eq!(
+            DnsName::new(&format!("{}.com", "a".repeat(64))),
+            Err(NameError::LabelTooLong(64))
+        );
+
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(DnsName::new(&long_name), Err(NameError::NameTooLong(257)));
+        assert!(DnsName::new(&long_name[2..]).is_ok());
+    }
+
+    #[test]
+    fn test_dns_name_root() {
+        let root = DnsName::new(".").unwrap();
+        assert!(root.is_root());
+        assert_eq!(root, DnsName::root());
+        assert_eq!(root.to_string(), ".");
+        assert_eq!(root.labels().count(), 0);
+        assert_eq!(root.wire_len(), 1);
+        assert_eq!(root, ".");
+        assert_ne!(DnsName::new("com").unwrap(), ".");
+        assert_eq!(DnsName::from_utf8(".").unwrap().to_unicode(), ".");
+    }
+
+    #[test]
+    fn test_dns_name_escapes() {
+        let name = DnsName::new(r"a\.b.example\032com.").unwrap();
+        assert_eq!(
+            name.labels().collect::<Vec<_>>(),
+            vec![r"a\.b", r"example\032com"]
+        );
+        assert_eq!(
+            name.wire_labels().collect::<Vec<_>>(),
+            vec![&b"a.b"[..], &b"example com"[..]]
+        );
+        assert_eq!(name.wire_len(), 1 + 3 + 1 + 11 + 1);
+
+        // escapes are normalized
+        let name = DnsName::new(r"\097\\\b.com").unwrap();
+        assert_eq!(name.as_str(), r"a\\b.com");
+        assert_eq!(name.wire_labels().next().unwrap().as_ref(), b"a\\b");
+
+        assert!(matches!(
+            DnsName::new(r"a\"),
+            Err(NameError::InvalidEscape(_))
+        ));
+        assert!(matches!(
+            DnsName::new(r"\256"),
+            Err(NameError::InvalidEscape(_))
+        ));
+        assert!(matches!(
+            DnsName::new(r"\12"),
+            Err(NameError::InvalidEscape(_))
+        ));
+        assert_eq!(
+            DnsName::new(&r"\000".repeat(64)),
+            Err(NameError::LabelTooLong(64))
+        );
+    }
+
+    #[test]
+    fn test_dns_name_idna() {
+        let name = DnsName::from_utf8("bücher.de").unwrap();
+        assert_eq!(name.as_str(), "xn--bcher-kva.de");
+        assert_eq!(name.to_unicode(), "bücher.de");
+
+        let name = DnsName::from_utf8("Example.COM").unwrap();
+        assert_eq!(name.as_str(), "Example.COM");
+        assert_eq!(name.to_unicode(), "example.com");
+
+        assert!(DnsName::from_utf8("xn--a.ä").is_err());
+    }
+
+    #[test]
+    fn test_dns_name_case_insensitive() {
+        let name = DnsName::new("WWW.Example.com").unwrap();
+        assert_eq!(name, DnsName::new("www.example.COM.").unwrap());
+        assert_eq!(name, "www.example.com.");
+        assert_eq!(name.to_string(), "WWW.Example.com");
+
+        let set: HashSet<_> = [name, DnsName::new("www.example.com").unwrap()].into();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_name_pointers() {
+        fn labels(message: &[u8], offset: usize) -> Result<Vec<&[u8]>, DnsParseError> {
+            Name::new(message, offset).labels().collect()
+        }
+        let message = b"\x03com\x00\x03www\xc0\x00";
+        assert_eq!(labels(message, 5).unwrap(), [&b"www"[..], b"com"]);
+        assert_eq!(Name::new(message, 5).to_string(), "www.com");
+        let name = |message, offset| DnsName::try_from(Name::new(message, offset));
+        assert_eq!(name(message, 5).unwrap(), "www.com");
+        assert!(Name::new(message, 5).validate().is_ok());
+
+        // pointing at itself, at the start of its own name, forward or past the end
+        for (message, offset, pointer) in [
+            (&b"\xc0\x00"[..], 0, 0),
+            (b"\x03www\xc0\x04", 0, 4),
+            (b"\x03www\xc0\x08\x00\x00\x03com\x00", 0, 4),
+            (b"\x03www\xc0\xff", 0, 4),
+            (b"\x00\x03www\xc0\x01", 1, 5),
+        ] {
+            let invalid = DnsParseError::InvalidPointer(pointer);
+            assert_eq!(labels(message, offset), Err(invalid.clone()));
+            assert_eq!(name(message, offset), Err(invalid.clone()));
+            assert_eq!(Name::new(message, offset).validate(), Err(invalid));
+        }
+        // too many in a row, even if each leads backwards
+        let mut message = vec![0];
+        for pointer in 0..=64u8 {
+            message.extend([0xc0, pointer.saturating_sub(1) * 2 + u8::from(pointer > 0)]);
+        }
+        let last = message.len() - 2;
+        assert_eq!(
+            labels(&message, last),
+            Err(DnsParseError::InvalidPointer(1))
+        );
+        assert!(Name::new(&message, last - 2).is_root());
+
+        // cut off labels, also where a pointer leads
+        for (message, offset) in [
+            (&b"\x03www\x05ab"[..], 0),
+            (b"\x28ab\x03www\xc0\x00", 3),
+            (b"\x03www\xc0", 0),
+            (b"\x03www", 0),
+        ] {
+            assert_eq!(labels(message, offset), Err(DnsParseError::UnexpectedEof));
+            assert_eq!(name(message, offset), Err(DnsParseError::UnexpectedEof));
+        }
+        let mut labels = Name::new(b"\x03www\xc0\xff", 0).labels();
+        assert_eq!(labels.next(), Some(Ok(&b"www"[..])));
+        assert!(labels.next().unwrap().is_err());
+        assert_eq!(labels.next(), None);
+    }
+
+    #[test]
+    fn test_dns_name_is_subdomain_of() {
+        let name = DnsName::new("www.Example.com").unwrap();
+        assert_eq!(name.label_count(), 3);
+        assert_eq!(DnsName::root().label_count(), 0);
+
+        assert!(name.is_subdomain_of(&name));
+        assert!(name.is_subdomain_of(&DnsName::new("example.COM").unwrap()));
+        assert!(name.is_subdomain_of(&DnsName::root()));
+        assert!(!name.is_subdomain_of(&DnsName::new("ample.com").unwrap()));
+        assert!(!name.is_subdomain_of(&DnsName::new("a.www.example.com").unwrap()));
+        assert!(!DnsName::root().is_subdomain_of(&name));
+
+        assert_eq!(name.ancestor(2).as_str(), "Example.com");
+        assert_eq!(name.ancestor(3), name);
+        assert_eq!(name.ancestor(5), name);
+        assert!(name.ancestor(0).is_root());
+    }
+
+    #[test]
+    fn test_dns_name_canonical_order() {
+        // https://datatracker.ietf.org/doc/html/rfc4034#section-6.1
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            r"\001.z.example",
+            "*.z.example",
+            r"\200.z.example",
+        ]
+        .map(|name| DnsName::new(name).unwrap());
+        let mut sorted = ordered.clone();
+        sorted.reverse();
+        sorted.sort_by(DnsName::canonical_cmp);
+        assert_eq!(sorted, ordered);
+        assert_eq!(ordered[3].to_lowercase().as_str(), "z.a.example");
+    }
+}
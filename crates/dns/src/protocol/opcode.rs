@@ -0,0 +1,54 @@
+use std::fmt::Display;
+
+/// Kind of query in a message, as set by its originator
+/// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-5
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    #[default]
+    Query,
+    /// Inverse query, obsoleted by RFC 3425
+    IQuery,
+    Status,
+    Notify,
+    Update,
+    Other(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(input: u8) -> Self {
+        match input {
+            0 => Self::Query,
+            1 => Self::IQuery,
+            2 => Self::Status,
+            4 => Self::Notify,
+            5 => Self::Update,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Other(other) => other,
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opcode::Query => write!(f, "QUERY"),
+            Opcode::IQuery => write!(f, "IQUERY"),
+            Opcode::Status => write!(f, "STATUS"),
+            Opcode::Notify => write!(f, "NOTIFY"),
+            Opcode::Update => write!(f, "UPDATE"),
+            Opcode::Other(other) => write!(f, "OPCODE{other}"),
+        }
+    }
+}
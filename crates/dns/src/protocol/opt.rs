@@ -0,0 +1,343 @@
+use crate::parse::parser::DnsParser;
+
+use super::{query::EdnsOptions, record_type::RecordType};
+
+/// The OPT pseudo record of a message, which carries its EDNS(0) settings in place of the class
+/// and TTL fields of regular records
+/// https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.2
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Opt {
+    /// Largest UDP payload the sender is able to receive
+    pub payload_size: u16,
+    /// Upper 8 bits of the response code, see [`super::response_code::ResponseCode::from_parts`]
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// DO bit, whether the sender understands DNSSEC records
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+/// An option in the RDATA of an OPT record
+/// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-11
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// Option code of padding
+/// https://datatracker.ietf.org/doc/html/rfc7830
+pub const PADDING_OPTION: u16 = 12;
+
+/// Option code of Extended DNS Errors
+pub const EXTENDED_ERROR_OPTION: u16 = 15;
+
+/// Block size queries sent over encrypted transports are padded to, so their length reveals
+/// little about the queried name
+/// https://datatracker.ietf.org/doc/html/rfc8467#section-4.1
+pub const QUERY_PADDING_BLOCK: usize = 128;
+
+/// Pads the raw `message` to a multiple of `block_size` bytes with a Padding option, adding an
+/// OPT record if it has none. Messages that are padded already, have no question or can not be
+/// parsed are returned as they are.
+pub fn pad_message(message: &[u8], block_size: usize) -> Vec<u8> {
+    let mut padded = message.to_vec();
+    let has_question = DnsParser::new(message)
+        .parse_header()
+        .is_ok_and(|header| header.question_count > 0);
+    let Ok(rdlength) = DnsParser::new(message).find_opt_rdlength() else {
+        return padded;
+    };
+    if !has_question {
+        return padded;
+    }
+    let padding_len = |len: usize| (block_size - len % block_size) % block_size;
+
+    match rdlength {
+        Some(position) => {
+            let opt = DnsParser::new(message).parse_opt().ok().flatten();
+            if opt.is_some_and(|opt| opt.options.iter().any(|o| o.code == PADDING_OPTION)) {
+                return padded;
+            }
+            let len = u16::from_be_bytes([message[position], message[position + 1]]) as usize;
+            let padding = padding_len(message.len() + 4);
+            let Ok(new_len) = u16::try_from(len + 4 + padding) else {
+                return padded;
+            };
+
+            let end = position + 2 + len;
+            padded[position..position + 2].copy_from_slice(&new_len.to_be_bytes());
+            padded.splice(end..end, padding_option(padding));
+        }
+        None => {
+            let padding = padding_len(message.len() + 11 + 4);
+            let additional_count = u16::from_be_bytes([message[10], message[11]]);
+            padded[10..12].copy_from_slice(&additional_count.saturating_add(1).to_be_bytes());
+
+            let payload_size = EdnsOptions::default().payload_size;
+            padded.push(0); // root name
+            padded.extend_from_slice(&u16::from(RecordType::OPT).to_be_bytes());
+            padded.extend_from_slice(&payload_size.to_be_bytes());
+            padded.extend_from_slice(&[0; 4]);
+            padded.extend_from_slice(&(4 + padding as u16).to_be_bytes());
+            padded.extend(padding_option(padding));
+        }
+    }
+    padded
+}
+
+/// A Padding option with `len` zero bytes
+fn padding_option(len: usize) -> Vec<u8> {
+    let mut option = Vec::with_capacity(4 + len);
+    option.extend_from_slice(&PADDING_OPTION.to_be_bytes());
+    option.extend_from_slice(&(len as u16).to_be_bytes());
+    option.resize(4 + len, 0);
+    option
+}
+
+impl Opt {
+    /// Decodes all Extended DNS Error options, skipping malformed ones
+    pub fn extended_errors(&self) -> Vec<ExtendedError> {
+        self.options
+            .iter()
+            .filter(|option| option.code == EXTENDED_ERROR_OPTION)
+            .filter_map(|option| ExtendedError::from_option_data(&option.data))
+            .collect()
+    }
+}
+
+/// Why a server failed to answer, or answered the way it did, beyond its response code
+/// https://datatracker.ietf.org/doc/html/rfc8914#section-2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub code: ExtendedErrorCode,
+    /// Additional explanation meant for humans, may be empty
+    pub extra_text: String,
+}
+
+impl ExtendedError {
+    fn from_option_data(data: &[u8]) -> Option<Self> {
+        let (code, extra_text) = data.split_first_chunk::<2>()?;
+        Some(Self {
+            code: u16::from_be_bytes(*code).into(),
+            extra_text: String::from_utf8_lossy(extra_text)
+                .trim_end_matches('\0')
+                .to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for ExtendedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code)?;
+        if !self.extra_text.is_empty() {
+            write!(f, " ({})", self.extra_text)?;
+        }
+        Ok(())
+    }
+}
+
+/// INFO-CODE of an Extended DNS Error
+/// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#extended-dns-error-codes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ExtendedErrorCode {
+    Other,
+    UnsupportedDnskeyAlgorithm,
+    UnsupportedDsDigestType,
+    StaleAnswer,
+    ForgedAnswer,
+    DnssecIndeterminate,
+    DnssecBogus,
+    SignatureExpired,
+    SignatureNotYetValid,
+    DnskeyMissing,
+    RrsigsMissing,
+    NoZoneKeyBitSet,
+    NsecMissing,
+    CachedError,
+    NotReady,
+    Blocked,
+    Censored,
+    Filtered,
+    Prohibited,
+    StaleNxdomainAnswer,
+    NotAuthoritative,
+    NotSupported,
+    NoReachableAuthority,
+    NetworkError,
+    InvalidData,
+    Unknown(u16),
+}
+
+impl ExtendedErrorCode {
+    /// Whether DNSSEC validation of the answer failed
+    pub fn is_dnssec_failure(self) -> bool {
+        matches!(
+            self,
+            Self::UnsupportedDnskeyAlgorithm
+                | Self::UnsupportedDsDigestType
+                | Self::DnssecIndeterminate
+                | Self::DnssecBogus
+                | Self::SignatureExpired
+                | Self::SignatureNotYetValid
+                | Self::DnskeyMissing
+                | Self::RrsigsMissing
+                | Self::NoZoneKeyBitSet
+                | Self::NsecMissing
+        )
+    }
+
+    /// Whether the server withheld the answer because of a policy, eg. a blocklist
+    pub fn is_filtered(self) -> bool {
+        matches!(
+            self,
+            Self::Blocked | Self::Censored | Self::Filtered | Self::Prohibited
+        )
+    }
+}
+
+impl From<u16> for ExtendedErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Other,
+            1 => Self::UnsupportedDnskeyAlgorithm,
+            2 => Self::UnsupportedDsDigestType,
+            3 => Self::StaleAnswer,
+            4 => Self::ForgedAnswer,
+            5 => Self::DnssecIndeterminate,
+            6 => Self::DnssecBogus,
+            7 => Self::SignatureExpired,
+            8 => Self::SignatureNotYetValid,
+            9 => Self::DnskeyMissing,
+            10 => Self::RrsigsMissing,
+            11 => Self::NoZoneKeyBitSet,
+            12 => Self::NsecMissing,
+            13 => Self::CachedError,
+            14 => Self::NotReady,
+            15 => Self::Blocked,
+            16 => Self::Censored,
+            17 => Self::Filtered,
+            18 => Self::Prohibited,
+            19 => Self::StaleNxdomainAnswer,
+            20 => Self::NotAuthoritative,
+            21 => Self::NotSupported,
+            22 => Self::NoReachableAuthority,
+            23 => Self::NetworkError,
+            24 => Self::InvalidData,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl std::fmt::Display for ExtendedErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Other => "Other Error",
+            Self::UnsupportedDnskeyAlgorithm => "Unsupported DNSKEY Algorithm",
+            Self::UnsupportedDsDigestType => "Unsupported DS Digest Type",
+            Self::StaleAnswer => "Stale Answer",
+            Self::ForgedAnswer => "Forged Answer",
+            Self::DnssecIndeterminate => "DNSSEC Indeterminate",
+            Self::DnssecBogus => "DNSSEC Bogus",
+            Self::SignatureExpired => "Signature Expired",
+            Self::SignatureNotYetValid => "Signature Not Yet Valid",
+            Self::DnskeyMissing => "DNSKEY Missing",
+            Self::RrsigsMissing => "RRSIGs Missing",
+            Self::NoZoneKeyBitSet => "No Zone Key Bit Set",
+            Self::NsecMissing => "NSEC Missing",
+            Self::CachedError => "Cached Error",
+            Self::NotReady => "Not Ready",
+            Self::Blocked => "Blocked",
+            Self::Censored => "Censored",
+            Self::Filtered => "Filtered",
+            Self::Prohibited => "Prohibited",
+            Self::StaleNxdomainAnswer => "Stale NXDOMAIN Answer",
+            Self::NotAuthoritative => "Not Authoritative",
+            Self::NotSupported => "Not Supported",
+            Self::NoReachableAuthority => "No Reachable Authority",
+            Self::NetworkError => "Network Error",
+            Self::InvalidData => "Invalid Data",
+            Self::Unknown(code) => return write!(f, "Extended DNS Error {code}"),
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse::parser::DnsParser, protocol::query::QueryBuilder};
+
+    use super::{
+        pad_message, EdnsOption, ExtendedError, ExtendedErrorCode, Opt, EXTENDED_ERROR_OPTION,
+        PADDING_OPTION,
+    };
+
+    #[test]
+    fn test_pad_message() {
+        let name = "example.com".parse().unwrap();
+        for query in [
+            QueryBuilder::new(name).build(),
+            QueryBuilder::new("a.very.long.name.example.com".parse().unwrap())
+                .dnssec_ok(true)
+                .build(),
+        ] {
+            let padded = pad_message(&query, 128);
+            assert_eq!(padded.len(), 128);
+            // all but the additional count of the header is kept
+            assert_eq!(padded[..10], query[..10]);
+
+            let packet = DnsParser::new(&padded).parse_packet().unwrap();
+            assert_eq!(packet.header.additional_count, 1);
+            let opt = DnsParser::new(&padded).parse_opt().unwrap().unwrap();
+            assert!(matches!(&opt.options[..], [option] if option.code == PADDING_OPTION));
+
+            // padding again changes nothing
+            assert_eq!(pad_message(&padded, 128), padded);
+        }
+
+        assert_eq!(pad_message(&[0, 0], 128), [0, 0]);
+    }
+
+    #[test]
+    fn test_extended_errors() {
+        let option = |data: &[u8]| EdnsOption {
+            code: EXTENDED_ERROR_OPTION,
+            data: data.to_vec(),
+        };
+        let opt = Opt {
+            options: vec![
+                option(&[0, 6]),
+                EdnsOption {
+                    code: 10,
+                    data: vec![0; 8],
+                },
+                option(&[0, 17, b'a', b'd', b's']),
+                option(&[0]),
+                option(&[1, 0]),
+            ],
+            ..Opt::default()
+        };
+
+        let errors = opt.extended_errors();
+        assert_eq!(
+            errors,
+            [
+                ExtendedError {
+                    code: ExtendedErrorCode::DnssecBogus,
+                    extra_text: String::new(),
+                },
+                ExtendedError {
+                    code: ExtendedErrorCode::Filtered,
+                    extra_text: "ads".to_string(),
+                },
+                ExtendedError {
+                    code: ExtendedErrorCode::Unknown(256),
+                    extra_text: String::new(),
+                },
+            ]
+        );
+        assert!(errors[0].code.is_dnssec_failure());
+        assert!(errors[1].code.is_filtered());
+        assert_eq!(errors[1].to_string(), "Filtered (ads)");
+    }
+}
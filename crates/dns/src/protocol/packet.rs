@@ -0,0 +1,57 @@
+use crate::{
+    parse::{
+        error::DnsParseError,
+        parser::{Answers, DnsParser},
+    },
+    serialize::writer::PacketWriter,
+};
+
+use super::{answer::Answer, header::Header, question::Question};
+
+/// A fully parsed DNS message
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub header: Header,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Answer>,
+    /// Records pointing towards the authoritative servers, eg. NS records of a referral
+    pub authorities: Vec<Answer>,
+    /// Records that may help using the other sections, eg. addresses of name servers or an OPT
+    /// record, which is kept as [`Answer::Unknown`]
+    pub additionals: Vec<Answer>,
+}
+
+impl Packet {
+    /// Lazily decodes the answers of the raw `message`, for callers that are only interested in
+    /// some of the records and do not want to parse the whole message upfront
+    pub fn answers_iter(message: &[u8]) -> Result<Answers<'_>, DnsParseError> {
+        DnsParser::new(message).answers_iter()
+    }
+
+    /// Serializes this message with compressed names, the section counts in the header are
+    /// derived from the sections themselves
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = self.header.clone();
+        header.question_count = self.questions.len() as u16;
+        header.answer_count = self.answers.len() as u16;
+        header.authority_count = self.authorities.len() as u16;
+        header.additional_count = self.additionals.len() as u16;
+
+        let mut buf = Vec::with_capacity(512);
+        let mut writer = PacketWriter::new(&mut buf);
+        writer.write_header(&header);
+        for question in &self.questions {
+            question.write(&mut writer);
+        }
+        for answer in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            answer.write(&mut writer);
+        }
+        buf
+    }
+}
@@ -0,0 +1,222 @@
+use rand::{rngs::OsRng, Rng};
+
+use crate::serialize::writer::PacketWriter;
+
+use super::{
+    class::Class,
+    header::{Flags, Header},
+    name::DnsName,
+    question::Question,
+    record_type::RecordType,
+};
+
+/// Query ID of [`QueryBuilder`] when none is specified
+pub const DEFAULT_ID: u16 = 1337;
+
+/// Picks a query ID with the OS random number generator, so off-path attackers can't guess it
+/// https://datatracker.ietf.org/doc/html/rfc5452#section-9.2
+pub fn random_id() -> u16 {
+    OsRng.gen()
+}
+
+/// Builds the wire format of a query for a single question
+///
+/// ```
+/// use dns::protocol::{query::QueryBuilder, record_type::RecordType};
+///
+/// let query = QueryBuilder::new("example.com".parse().unwrap())
+///     .id(42)
+///     .record_type(RecordType::MX)
+///     .edns_payload_size(1232)
+///     .build();
+/// assert_eq!(&query[0..2], &[0, 42]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct QueryBuilder {
+    id: u16,
+    name: DnsName,
+    record_type: RecordType,
+    class: Class,
+    recursion_desired: bool,
+    checking_disabled: bool,
+    edns: Option<EdnsOptions>,
+}
+
+/// Settings for the OPT pseudo record attached to a query
+/// https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdnsOptions {
+    /// Largest UDP payload the sender is able to receive
+    pub payload_size: u16,
+    /// DO bit, asks for DNSSEC records to be included in the response
+    pub dnssec_ok: bool,
+}
+
+impl Default for EdnsOptions {
+    fn default() -> Self {
+        Self {
+            // https://www.dnsflagday.net/2020/
+            payload_size: 1232,
+            dnssec_ok: false,
+        }
+    }
+}
+
+impl QueryBuilder {
+    /// Creates a recursive query for INternet A records of `name`
+    pub fn new(name: DnsName) -> Self {
+        Self {
+            id: DEFAULT_ID,
+            name,
+            record_type: RecordType::A,
+            class: Class::IN,
+            recursion_desired: true,
+            checking_disabled: false,
+            edns: None,
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn record_type(mut self, record_type: RecordType) -> Self {
+        self.record_type = record_type;
+        self
+    }
+
+    pub fn class(mut self, class: Class) -> Self {
+        self.class = class;
+        self
+    }
+
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> Self {
+        self.recursion_desired = recursion_desired;
+        self
+    }
+
+    pub fn checking_disabled(mut self, checking_disabled: bool) -> Self {
+        self.checking_disabled = checking_disabled;
+        self
+    }
+
+    /// Attaches an OPT record advertising `payload_size` bytes as receive buffer size
+    pub fn edns_payload_size(mut self, payload_size: u16) -> Self {
+        self.edns
+            .get_or_insert_with(EdnsOptions::default)
+            .payload_size = payload_size;
+        self
+    }
+
+    /// Sets the DO bit, which implies attaching an OPT record
+    pub fn dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.edns.get_or_insert_with(EdnsOptions::default).dnssec_ok = dnssec_ok;
+        self
+    }
+
+    pub fn edns(mut self, edns: Option<EdnsOptions>) -> Self {
+        self.edns = edns;
+        self
+    }
+
+    pub fn question(&self) -> Question {
+        Question {
+            domain_name: self.name.clone(),
+            r#type: self.record_type,
+            class: self.class,
+        }
+    }
+
+    pub fn header(&self) -> Header {
+        Header {
+            request_id: self.id,
+            flags: Flags {
+                recursion_desired: self.recursion_desired,
+                checking_disabled: self.checking_disabled,
+                ..Flags::default()
+            },
+            question_count: 1,
+            additional_count: u16::from(self.edns.is_some()),
+            ..Header::default()
+        }
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut query = Vec::with_capacity(12 + self.name.wire_len() + 4 + 11);
+        let mut writer = PacketWriter::uncompressed(&mut query);
+        writer.write_header(&self.header());
+        self.question().write(&mut writer);
+
+        if let Some(edns) = self.edns {
+            writer.write_u8(0); // root name
+            writer.write_u16(RecordType::OPT.into());
+            writer.write_u16(edns.payload_size);
+            // extended RCODE, version 0, DO bit and the remaining Z flags
+            writer.write_u32(u32::from(edns.dnssec_ok) << 15);
+            writer.write_u16(0); // no options
+        }
+        query
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{class::Class, opcode::Opcode, record_type::RecordType},
+    };
+
+    use super::{QueryBuilder, DEFAULT_ID};
+
+    fn parse(query: &[u8]) -> crate::protocol::packet::Packet {
+        DnsParser::new(query).parse_packet().unwrap()
+    }
+
+    #[test]
+    fn test_default_query() {
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        assert_eq!(query.len(), 12 + 13 + 4);
+
+        let packet = parse(&query);
+        assert_eq!(packet.header.request_id, DEFAULT_ID);
+        assert_eq!(packet.header.opcode(), Opcode::Query);
+        assert!(packet.header.flags.recursion_desired);
+        assert_eq!(packet.header.additional_count, 0);
+        assert_eq!(packet.questions[0].r#type, RecordType::A);
+        assert_eq!(packet.questions[0].class, Class::IN);
+    }
+
+    #[test]
+    fn test_query_options() {
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(7)
+            .record_type(RecordType::TXT)
+            .class(Class::CH)
+            .recursion_desired(false)
+            .checking_disabled(true)
+            .build();
+
+        let packet = parse(&query);
+        assert_eq!(packet.header.request_id, 7);
+        assert!(!packet.header.flags.recursion_desired);
+        assert!(packet.header.flags.checking_disabled);
+        assert_eq!(packet.questions[0].r#type, RecordType::TXT);
+        assert_eq!(packet.questions[0].class, Class::CH);
+    }
+
+    #[test]
+    fn test_query_edns() {
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .edns_payload_size(4096)
+            .dnssec_ok(true)
+            .build();
+
+        let header = DnsParser::new(&query).parse_header().unwrap();
+        assert_eq!(header.additional_count, 1);
+        assert_eq!(
+            &query[12 + 13 + 4..],
+            &[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 0]
+        );
+    }
+}
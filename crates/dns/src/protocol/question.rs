@@ -1,6 +1,39 @@
-#[derive(Debug)]
+use std::fmt::Display;
+
+use crate::serialize::writer::PacketWriter;
+
+use super::{class::Class, name::DnsName, record_type::RecordType};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Question {
-    pub domain_name: String,
-    pub r#type: usize,
-    pub class: usize,
+    pub domain_name: DnsName,
+    pub r#type: RecordType,
+    pub class: Class,
+}
+
+impl Question {
+    /// Appends the wire format of this question to `buf`
+    /// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.2
+    pub fn to_bytes(&self, buf: &mut Vec<u8>) {
+        self.write(&mut PacketWriter::uncompressed(buf));
+    }
+
+    pub fn write(&self, writer: &mut PacketWriter) {
+        writer.write_name(&self.domain_name);
+        writer.write_u16(self.r#type.into());
+        writer.write_u16(self.class.into());
+    }
+}
+
+/// The question in presentation format, eg. `example.com. IN A`
+impl Display for Question {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.domain_name.to_fqdn(),
+            self.class,
+            self.r#type
+        )
+    }
 }
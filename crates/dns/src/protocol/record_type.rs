@@ -1,4 +1,6 @@
-#[derive(Debug, Copy, Clone)]
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum RecordType {
     A,     // 1 a host address
@@ -17,13 +19,16 @@ pub enum RecordType {
     MINFO, // 14 mailbox or mail list information
     MX,    // 15 mail exchange
     TXT,   // 16 text strings1
+    AAAA,  // 28 a host IPv6 address, RFC 3596
+    SRV,   // 33 location of services, RFC 2782
+    OPT,   // 41 EDNS(0) pseudo record, RFC 6891
     // QTYPEs
     AXFR,  // 252 A request for a transfer of an entire zone
     MAILB, // 253 A request for mailbox-related records (MB, MG or MR)
     MAILA, // 254 A request for mail agent RRs (Obsolete - see MX)
     ANY,   // 255 A request for all records
     URI,   // 256
-    OTHER,
+    OTHER(u16),
 }
 
 impl From<usize> for RecordType {
@@ -45,12 +50,116 @@ impl From<usize> for RecordType {
             14 => Self::MINFO,
             15 => Self::MX,
             16 => Self::TXT,
+            28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
             252 => Self::AXFR,
             253 => Self::MAILB,
             254 => Self::MAILA,
             255 => Self::ANY,
             256 => Self::URI,
-            _ => Self::OTHER,
+            other => Self::OTHER(other as u16),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(record_type: RecordType) -> Self {
+        match record_type {
+            RecordType::A => 1,
+            RecordType::NS => 2,
+            RecordType::MD => 3,
+            RecordType::MF => 4,
+            RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::MB => 7,
+            RecordType::MG => 8,
+            RecordType::MR => 9,
+            RecordType::NULL => 10,
+            RecordType::WKS => 11,
+            RecordType::PTR => 12,
+            RecordType::HINFO => 13,
+            RecordType::MINFO => 14,
+            RecordType::MX => 15,
+            RecordType::TXT => 16,
+            RecordType::AAAA => 28,
+            RecordType::SRV => 33,
+            RecordType::OPT => 41,
+            RecordType::AXFR => 252,
+            RecordType::MAILB => 253,
+            RecordType::MAILA => 254,
+            RecordType::ANY => 255,
+            RecordType::URI => 256,
+            RecordType::OTHER(other) => other,
+        }
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // https://datatracker.ietf.org/doc/html/rfc3597#section-5
+            RecordType::OTHER(other) => write!(f, "TYPE{other}"),
+            mnemonic => write!(f, "{mnemonic:?}"),
+        }
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = String;
+
+    /// Parses a mnemonic like `AAAA` regardless of case, or the generic `TYPE28`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let record_type = match s.to_ascii_uppercase().as_str() {
+            "A" => Self::A,
+            "NS" => Self::NS,
+            "MD" => Self::MD,
+            "MF" => Self::MF,
+            "CNAME" => Self::CNAME,
+            "SOA" => Self::SOA,
+            "MB" => Self::MB,
+            "MG" => Self::MG,
+            "MR" => Self::MR,
+            "NULL" => Self::NULL,
+            "WKS" => Self::WKS,
+            "PTR" => Self::PTR,
+            "HINFO" => Self::HINFO,
+            "MINFO" => Self::MINFO,
+            "MX" => Self::MX,
+            "TXT" => Self::TXT,
+            "AAAA" => Self::AAAA,
+            "SRV" => Self::SRV,
+            "OPT" => Self::OPT,
+            "AXFR" => Self::AXFR,
+            "MAILB" => Self::MAILB,
+            "MAILA" => Self::MAILA,
+            "ANY" | "*" => Self::ANY,
+            "URI" => Self::URI,
+            other => match other.strip_prefix("TYPE").map(str::parse::<u16>) {
+                Some(Ok(number)) => Self::from(number as usize),
+                _ => return Err(format!("unknown record type {s}")),
+            },
+        };
+        Ok(record_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecordType;
+
+    #[test]
+    fn test_record_type_presentation() {
+        assert_eq!(RecordType::AAAA.to_string(), "AAAA");
+        assert_eq!(RecordType::OTHER(65).to_string(), "TYPE65");
+        assert_eq!("srv".parse(), Ok(RecordType::SRV));
+        assert_eq!("TYPE1".parse(), Ok(RecordType::A));
+        assert_eq!("type65".parse(), Ok(RecordType::OTHER(65)));
+        assert!("TYPE65536".parse::<RecordType>().is_err());
+        assert!("HTTPS2".parse::<RecordType>().is_err());
+        for number in 0..=300 {
+            let record_type = RecordType::from(number);
+            assert_eq!(record_type.to_string().parse(), Ok(record_type));
         }
     }
 }
@@ -1,18 +1,130 @@
-#[derive(Debug)]
+use std::fmt::Display;
+
+/// Response codes, including the extended ones that need the upper 8 bits from an EDNS OPT record
+/// https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ResponseCode {
-    NOERROR,
-    FORMERR,
-    NXDOMAIN,
-    SERVFAIL,
+    #[default]
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    /// BADVERS for EDNS, BADSIG in the context of TSIG
+    BadVers,
+    BadKey,
+    BadTime,
+    BadMode,
+    BadName,
+    BadAlg,
+    BadTrunc,
+    BadCookie,
+    Other(u16),
+}
+
+impl ResponseCode {
+    /// Combines the 4 bits from the header with the upper 8 bits from the OPT record's TTL field
+    /// https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.3
+    pub fn from_parts(header_bits: u8, extended_bits: u8) -> Self {
+        Self::from((extended_bits as u16) << 4 | (header_bits as u16 & 0xF))
+    }
+
+    /// The lower 4 bits that go into the header
+    pub fn header_bits(self) -> u8 {
+        (u16::from(self) & 0xF) as u8
+    }
+
+    /// The upper 8 bits that go into an OPT record, zero for all non-extended codes
+    pub fn extended_bits(self) -> u8 {
+        (u16::from(self) >> 4) as u8
+    }
 }
 
-impl From<ResponseCode> for u8 {
+impl From<u16> for ResponseCode {
+    fn from(input: u16) -> Self {
+        match input {
+            0 => Self::NoError,
+            1 => Self::FormErr,
+            2 => Self::ServFail,
+            3 => Self::NXDomain,
+            4 => Self::NotImp,
+            5 => Self::Refused,
+            6 => Self::YXDomain,
+            7 => Self::YXRRSet,
+            8 => Self::NXRRSet,
+            9 => Self::NotAuth,
+            10 => Self::NotZone,
+            16 => Self::BadVers,
+            17 => Self::BadKey,
+            18 => Self::BadTime,
+            19 => Self::BadMode,
+            20 => Self::BadName,
+            21 => Self::BadAlg,
+            22 => Self::BadTrunc,
+            23 => Self::BadCookie,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<ResponseCode> for u16 {
     fn from(rc: ResponseCode) -> Self {
         match rc {
-            ResponseCode::NOERROR => 0,
-            ResponseCode::FORMERR => 1,
-            ResponseCode::SERVFAIL => 2,
-            ResponseCode::NXDOMAIN => 3,
+            ResponseCode::NoError => 0,
+            ResponseCode::FormErr => 1,
+            ResponseCode::ServFail => 2,
+            ResponseCode::NXDomain => 3,
+            ResponseCode::NotImp => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::YXDomain => 6,
+            ResponseCode::YXRRSet => 7,
+            ResponseCode::NXRRSet => 8,
+            ResponseCode::NotAuth => 9,
+            ResponseCode::NotZone => 10,
+            ResponseCode::BadVers => 16,
+            ResponseCode::BadKey => 17,
+            ResponseCode::BadTime => 18,
+            ResponseCode::BadMode => 19,
+            ResponseCode::BadName => 20,
+            ResponseCode::BadAlg => 21,
+            ResponseCode::BadTrunc => 22,
+            ResponseCode::BadCookie => 23,
+            ResponseCode::Other(other) => other,
         }
     }
 }
+
+/// The mnemonic as dig shows it, eg. `NXDOMAIN`
+impl Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mnemonic = match self {
+            ResponseCode::NoError => "NOERROR",
+            ResponseCode::FormErr => "FORMERR",
+            ResponseCode::ServFail => "SERVFAIL",
+            ResponseCode::NXDomain => "NXDOMAIN",
+            ResponseCode::NotImp => "NOTIMP",
+            ResponseCode::Refused => "REFUSED",
+            ResponseCode::YXDomain => "YXDOMAIN",
+            ResponseCode::YXRRSet => "YXRRSET",
+            ResponseCode::NXRRSet => "NXRRSET",
+            ResponseCode::NotAuth => "NOTAUTH",
+            ResponseCode::NotZone => "NOTZONE",
+            ResponseCode::BadVers => "BADVERS",
+            ResponseCode::BadKey => "BADKEY",
+            ResponseCode::BadTime => "BADTIME",
+            ResponseCode::BadMode => "BADMODE",
+            ResponseCode::BadName => "BADNAME",
+            ResponseCode::BadAlg => "BADALG",
+            ResponseCode::BadTrunc => "BADTRUNC",
+            ResponseCode::BadCookie => "BADCOOKIE",
+            ResponseCode::Other(other) => return write!(f, "RCODE{other}"),
+        };
+        f.write_str(mnemonic)
+    }
+}
@@ -7,7 +7,7 @@ use super::{
 
 pub fn generate_nx_response(id: u16) -> Result<DnsPacketBuffer, Box<dyn std::error::Error>> {
     let flags = Flags {
-        response_code: ResponseCode::NXDOMAIN.into(),
+        response_code: ResponseCode::NXDomain,
         query: false,
         ..Flags::default()
     };
@@ -29,7 +29,7 @@ pub fn generate_response_with_answer(
     response_code: ResponseCode,
 ) -> Result<[u8; 512], Box<dyn std::error::Error>> {
     let flags = Flags {
-        response_code: response_code.into(),
+        response_code,
         query: false,
         ..Flags::default()
     };
@@ -0,0 +1,273 @@
+//! Records defined right in the configuration, like `router.lan=192.168.1.1`, which are answered
+//! locally before the blocklists or any upstream are consulted.
+
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+use crate::{
+    cache::respond_with,
+    hosts::reverse_name,
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        name::DnsName,
+        record_type::RecordType,
+    },
+};
+
+/// TTL of local records unless configured otherwise
+pub const LOCAL_RECORD_TTL: usize = 300;
+
+/// At most this many CNAMEs are followed through the local records, so loops end
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// A record given as `name=address` for an A or AAAA record, or as `name=TYPE rdata` with the
+/// RDATA in presentation format, eg. `www.lan=CNAME router.lan` or `lan=TXT "hello"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalRecord(pub Answer);
+
+impl FromStr for LocalRecord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or("expected name=value, eg. router.lan=192.168.1.1")?;
+        let name: DnsName = name.trim().parse().map_err(|e| format!("{e}"))?;
+        let value = value.trim();
+        let (record_type, rdata) = match value.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => (RecordType::A, value),
+            Ok(IpAddr::V6(_)) => (RecordType::AAAA, value),
+            Err(_) => {
+                let (record_type, rdata) = value
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| format!("expected an address or TYPE rdata, not {value}"))?;
+                (record_type.parse()?, rdata.trim())
+            }
+        };
+        let meta = AnswerMeta {
+            name,
+            r#type: record_type,
+            class: Class::IN,
+            ttl: LOCAL_RECORD_TTL,
+            len: 0,
+        };
+        Ok(Self(Answer::parse_rdata(meta, rdata)?))
+    }
+}
+
+/// Records answered locally, by name
+///
+/// Questions for names with local records are answered from them alone: with the records of the
+/// type asked for, or else the CNAME of the name followed through the local records, or else no
+/// records at all. PTR questions for the reverse names of local addresses are answered with the
+/// first name given for them. All other questions are left to the blocklists and upstreams.
+#[derive(Debug, Default)]
+pub struct LocalRecords {
+    records: HashMap<DnsName, Vec<Answer>>,
+    /// Reverse names of the addresses, with the name they were first given for
+    names: HashMap<DnsName, DnsName>,
+    ttl: usize,
+}
+
+impl LocalRecords {
+    pub fn new(records: impl IntoIterator<Item = LocalRecord>) -> Self {
+        let mut local = Self {
+            ttl: LOCAL_RECORD_TTL,
+            ..Self::default()
+        };
+        for LocalRecord(answer) in records {
+            let name = answer.meta().name.clone();
+            let address = match &answer {
+                Answer::A { ipv4, .. } => Some(IpAddr::V4(*ipv4)),
+                Answer::AAAA { ipv6, .. } => Some(IpAddr::V6(*ipv6)),
+                _ => None,
+            };
+            if let Some(address) = address {
+                (local.names)
+                    .entry(reverse_name(address))
+                    .or_insert_with(|| name.clone());
+            }
+            let answers = local.records.entry(name).or_default();
+            if !answers.contains(&answer) {
+                answers.push(answer);
+            }
+        }
+        local
+    }
+
+    /// Seconds clients may cache the records for, [`LOCAL_RECORD_TTL`] by default
+    pub fn ttl(mut self, ttl: usize) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Answers for `name` and `record_type`, or `None` if there are no local records for `name`
+    pub fn lookup(&self, name: &DnsName, record_type: RecordType) -> Option<Vec<Answer>> {
+        let with_ttl = |mut answer: Answer| {
+            answer.meta_mut().ttl = self.ttl;
+            answer
+        };
+        if record_type == RecordType::PTR {
+            if let Some(ptr) = self.names.get(name) {
+                let meta = AnswerMeta {
+                    name: name.clone(),
+                    r#type: RecordType::PTR,
+                    class: Class::IN,
+                    ttl: self.ttl,
+                    len: 0,
+                };
+                let ptr = ptr.clone();
+                return Some(vec![Answer::PTR { meta, ptr }]);
+            }
+        }
+
+        let mut records = self.records.get(name)?;
+        let mut answers = vec![];
+        for _ in 0..MAX_CNAME_CHAIN {
+            let before = answers.len();
+            let matching = records
+                .iter()
+                .filter(|answer| answer.meta().r#type == record_type);
+            answers.extend(matching.cloned().map(with_ttl));
+            if answers.len() > before {
+                break;
+            }
+            let Some((answer, cname)) = records.iter().find_map(|answer| match answer {
+                Answer::CNAME { cname, .. } => Some((answer, cname)),
+                _ => None,
+            }) else {
+                break;
+            };
+            answers.push(with_ttl(answer.clone()));
+            match self.records.get(cname) {
+                Some(next) => records = next,
+                None => break,
+            }
+        }
+        Some(answers)
+    }
+
+    /// Builds a response to the raw `query` if there are local records for its question
+    pub fn respond(&self, query: &[u8]) -> Option<Vec<u8>> {
+        respond_with(query, |key| match key.class {
+            Class::IN => self.lookup(&key.name, key.r#type),
+            _ => None,
+        })
+    }
+
+    /// Number of names with local records
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{query::QueryBuilder, record_type::RecordType},
+    };
+
+    use super::*;
+
+    const RECORDS: &[&str] = &[
+        "router.lan=192.168.1.1",
+        "router.lan = fd00::1",
+        "nas.lan=192.168.1.2",
+        "www.lan=CNAME router.lan",
+        "web.lan=cname www.lan",
+        "lan=TXT \"hello world\" again",
+        "loop-a.lan=CNAME loop-b.lan",
+        "loop-b.lan=CNAME loop-a.lan",
+    ];
+
+    fn records() -> LocalRecords {
+        LocalRecords::new(RECORDS.iter().map(|record| record.parse().unwrap()))
+    }
+
+    fn rdata(answers: Option<Vec<Answer>>) -> Option<Vec<String>> {
+        Some(answers?.iter().map(Answer::to_string).collect())
+    }
+
+    #[test]
+    fn test_local_record_from_str() {
+        let record = |s: &str| s.parse::<LocalRecord>().map(|record| record.0.to_string());
+        assert_eq!(
+            record("router.lan=192.168.1.1").unwrap(),
+            "router.lan. 300 IN A 192.168.1.1"
+        );
+        assert_eq!(
+            record("lan=TXT v=spf1").unwrap(),
+            r#"lan. 300 IN TXT "v=spf1""#
+        );
+        assert!(record("router.lan").is_err());
+        assert!(record("router.lan=router").is_err());
+        assert!(record("router.lan=A fd00::1").is_err());
+        assert!(record("router.lan=BOGUS 1").is_err());
+    }
+
+    #[test]
+    fn test_local_records_lookup() {
+        let records = records().ttl(60);
+        let lookup =
+            |name: &str, record_type| rdata(records.lookup(&name.parse().unwrap(), record_type));
+
+        assert_eq!(
+            lookup("Router.lan", RecordType::A).unwrap(),
+            ["router.lan. 60 IN A 192.168.1.1"]
+        );
+        assert_eq!(
+            lookup("router.lan", RecordType::AAAA).unwrap(),
+            ["router.lan. 60 IN AAAA fd00::1"]
+        );
+        // names with local records have no others
+        assert!(lookup("nas.lan", RecordType::MX).unwrap().is_empty());
+        assert!(lookup("nas.lan", RecordType::AAAA).unwrap().is_empty());
+        assert_eq!(lookup("printer.lan", RecordType::A), None);
+
+        assert_eq!(
+            lookup("web.lan", RecordType::A).unwrap(),
+            [
+                "web.lan. 60 IN CNAME www.lan.",
+                "www.lan. 60 IN CNAME router.lan.",
+                "router.lan. 60 IN A 192.168.1.1",
+            ]
+        );
+        assert_eq!(
+            lookup("web.lan", RecordType::CNAME).unwrap(),
+            ["web.lan. 60 IN CNAME www.lan."]
+        );
+        assert_eq!(lookup("loop-a.lan", RecordType::A).unwrap().len(), 8);
+        assert_eq!(
+            lookup("lan", RecordType::TXT).unwrap(),
+            [r#"lan. 60 IN TXT "hello world" "again""#]
+        );
+
+        let reverse = reverse_name("192.168.1.1".parse().unwrap());
+        assert_eq!(
+            lookup(reverse.as_str(), RecordType::PTR).unwrap(),
+            ["1.1.168.192.in-addr.arpa. 60 IN PTR router.lan."]
+        );
+        assert_eq!(records.len(), 7);
+    }
+
+    #[test]
+    fn test_local_records_respond() {
+        let records = records();
+        let query = QueryBuilder::new("nas.lan".parse().unwrap()).id(9).build();
+        let response = records.respond(&query).unwrap();
+        let parsed = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(parsed.header.request_id, 9);
+        assert_eq!(
+            rdata(Some(parsed.answers)).unwrap(),
+            ["nas.lan. 300 IN A 192.168.1.2"]
+        );
+
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        assert!(records.respond(&query).is_none());
+    }
+}
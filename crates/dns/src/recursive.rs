@@ -0,0 +1,1070 @@
+//! Iterative resolution starting at the root servers, which makes the crate usable without any
+//! upstream resolver to forward to.
+
+use std::{
+    fmt::Debug,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::ResolveError,
+    parse::parser::DnsParser,
+    protocol::{
+        answer::Answer,
+        name::DnsName,
+        packet::Packet,
+        query::{random_id, QueryBuilder},
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    resolver::{
+        bind_query_socket, bind_query_socket_async, resolve_query, resolve_query_async,
+        ResolveOptions, ResponseError, DEFAULT_MAX_CNAME_HOPS,
+    },
+    root_hints::RootHints,
+    transport::BoxFuture,
+};
+
+/// How many referrals [`RecursiveResolver`] follows for a single name by default
+pub const DEFAULT_MAX_REFERRALS: usize = 16;
+
+/// How deeply resolutions may nest, eg. to find the address of a name server without glue
+const MAX_DEPTH: usize = 4;
+
+/// Minimized queries sent for a single name, before the full name is revealed to save round trips
+/// https://datatracker.ietf.org/doc/html/rfc9156#section-2.3
+const MAX_MINIMISE_COUNT: usize = 10;
+
+/// How long to keep using the root hints after a failed priming query, before priming again
+const PRIMING_RETRY: Duration = Duration::from_secs(60);
+
+/// Resolves names by walking the delegation tree from the root servers down to the authoritative
+/// servers of the name, instead of asking an upstream resolver to do so.
+///
+/// Referrals are followed with the addresses of the name servers from the additional section,
+/// as long as that glue lies within the zone of the server that sent it, and the addresses of
+/// all other name servers are resolved the same way. Only IPv4 addresses are used, as queries are
+/// sent from IPv4 sockets.
+///
+/// By default, name servers are only told the labels of the name that are one below their own
+/// zone, see [`RecursiveResolver::qname_minimization`].
+///
+/// The addresses of the root servers are refreshed with a priming query before the first
+/// resolution and whenever its records expire, see [`RecursiveResolver::prime`]. Clones share
+/// the primed root hints.
+#[derive(Debug, Clone)]
+pub struct RecursiveResolver {
+    hints: Arc<RwLock<RootHints>>,
+    priming: bool,
+    opts: ResolveOptions,
+    max_referrals: usize,
+    qname_minimization: bool,
+    /// Port name servers of referrals are queried on
+    port: u16,
+    tracer: Option<Tracer>,
+}
+
+/// Receives a [`TraceStep`] for every query sent to a name server
+#[derive(Clone)]
+struct Tracer(Arc<dyn Fn(&TraceStep) + Send + Sync>);
+
+impl Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Tracer")
+    }
+}
+
+/// A query sent to a name server while walking the delegation tree, see
+/// [`RecursiveResolver::trace`]
+#[derive(Debug)]
+pub struct TraceStep {
+    /// Zone the server was asked as an authority of
+    pub zone: DnsName,
+    pub server: SocketAddr,
+    /// Name and record type asked for, which are minimized unless the server is authoritative
+    /// for the full name
+    pub name: DnsName,
+    pub record_type: RecordType,
+    pub rtt: Duration,
+    /// The response, if one arrived and could be parsed
+    pub response: Option<Packet>,
+    /// Why the server was not usable, in which case the next one is asked
+    pub error: Option<String>,
+}
+
+impl TraceStep {
+    /// Whether the response carries DNSSEC signatures or, for a referral, DS records for a
+    /// signed delegation. Those are only included if the queries set the DO bit, see
+    /// [`crate::protocol::query::EdnsOptions`].
+    pub fn is_signed(&self) -> bool {
+        const DS: u16 = 43;
+        const RRSIG: u16 = 46;
+        self.response.iter().any(|response| {
+            response
+                .answers
+                .iter()
+                .chain(&response.authorities)
+                .any(|answer| matches!(u16::from(answer.meta().r#type), DS | RRSIG))
+        })
+    }
+}
+
+impl Default for RecursiveResolver {
+    fn default() -> Self {
+        Self {
+            hints: Arc::default(),
+            priming: true,
+            opts: ResolveOptions {
+                timeout: Duration::from_millis(800),
+                retries: 1,
+                ..ResolveOptions::default()
+            },
+            max_referrals: DEFAULT_MAX_REFERRALS,
+            qname_minimization: true,
+            port: 53,
+            tracer: None,
+        }
+    }
+}
+
+/// What a name server said about the queried name
+#[derive(Debug)]
+enum Step {
+    /// The records of the queried type, after the CNAME records leading to them. Empty if the
+    /// type does not exist for the name, or the name an alias points to does not exist.
+    Answer(Vec<Answer>),
+    /// Neither the name nor any name below it exists
+    /// https://datatracker.ietf.org/doc/html/rfc8020
+    NoSuchName,
+    /// The name is an alias for `target`, which has to be resolved from the root again since the
+    /// server is not responsible for it
+    Cname { chain: Vec<Answer>, target: DnsName },
+    /// `zone` is delegated to other name servers, closer to the queried name
+    Referral {
+        zone: DnsName,
+        name_servers: Vec<NameServer>,
+    },
+}
+
+#[derive(Debug)]
+struct NameServer {
+    name: DnsName,
+    /// From the additional section
+    glue: Vec<Ipv4Addr>,
+}
+
+impl RecursiveResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the built-in [`crate::root_hints::ROOT_SERVERS`], eg. with a hints file read by
+    /// [`RootHints::load`]
+    pub fn root_hints(mut self, hints: RootHints) -> Self {
+        self.hints = Arc::new(RwLock::new(hints));
+        self
+    }
+
+    /// Whether to refresh the root hints with priming queries, otherwise they are used as is
+    pub fn priming(mut self, priming: bool) -> Self {
+        self.priming = priming;
+        self
+    }
+
+    /// Sets the timeouts and retries of every query sent to a name server. Its transport has to
+    /// be [`crate::transport::Transport::Udp`], as name servers only offer plain DNS.
+    pub fn options(mut self, opts: ResolveOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    pub fn max_referrals(mut self, max_referrals: usize) -> Self {
+        self.max_referrals = max_referrals;
+        self
+    }
+
+    /// Whether to send each name server only as many labels of the name as it needs to refer to
+    /// the next zone, asking for A records, instead of the full name and record type. Servers
+    /// that fail to respond to such a query are asked for the full name instead.
+    /// https://datatracker.ietf.org/doc/html/rfc9156
+    pub fn qname_minimization(mut self, qname_minimization: bool) -> Self {
+        self.qname_minimization = qname_minimization;
+        self
+    }
+
+    /// Calls `tracer` with every query sent to a name server and its response, eg. to show the
+    /// delegation path like `dig +trace` does. Priming queries are not traced.
+    pub fn trace(mut self, tracer: impl Fn(&TraceStep) + Send + Sync + 'static) -> Self {
+        self.tracer = Some(Tracer(Arc::new(tracer)));
+        self
+    }
+
+    /// The root servers resolution currently starts from
+    pub fn current_root_hints(&self) -> RootHints {
+        self.hints.read().unwrap().clone()
+    }
+
+    /// Asks the root servers for the name servers of the root zone and uses them as root hints
+    /// until their records expire. A failed priming query keeps the previous hints.
+    /// https://datatracker.ietf.org/doc/html/rfc8109
+    pub fn prime(&self) -> Result<(), ResolveError> {
+        let query = self.priming_query();
+        let socket = bind_query_socket(&self.opts)?;
+        let mut last = Err(ResolveError::NoNameServers(DnsName::root()));
+        for server in self.roots() {
+            last = resolve_query(&query, &server.to_string(), &socket, &self.opts)
+                .and_then(|response| RootHints::from_priming_response(&response, Instant::now()));
+            if last.is_ok() {
+                break;
+            }
+        }
+        self.update_hints(last)
+    }
+
+    /// Asynchronously primes the root hints, see [`RecursiveResolver::prime`]
+    pub async fn prime_async(&self) -> Result<(), ResolveError> {
+        let query = self.priming_query();
+        let socket = bind_query_socket_async(&self.opts).await?;
+        let mut last = Err(ResolveError::NoNameServers(DnsName::root()));
+        for server in self.roots() {
+            last = resolve_query_async(&query, &server.to_string(), &socket, &self.opts)
+                .await
+                .and_then(|response| RootHints::from_priming_response(&response, Instant::now()));
+            if last.is_ok() {
+                break;
+            }
+        }
+        self.update_hints(last)
+    }
+
+    fn priming_query(&self) -> Vec<u8> {
+        self.query(&DnsName::root(), RecordType::NS)
+    }
+
+    fn update_hints(&self, primed: Result<RootHints, ResolveError>) -> Result<(), ResolveError> {
+        let mut hints = self.hints.write().unwrap();
+        match primed {
+            Ok(primed) => {
+                *hints = primed;
+                Ok(())
+            }
+            Err(e) => {
+                hints.expires = Some(Instant::now() + PRIMING_RETRY);
+                Err(e)
+            }
+        }
+    }
+
+    fn needs_priming(&self) -> bool {
+        self.priming && self.hints.read().unwrap().needs_priming(Instant::now())
+    }
+
+    fn roots(&self) -> Vec<SocketAddr> {
+        self.hints.read().unwrap().socket_addrs(self.port)
+    }
+
+    /// Resolves INternet records of any type for `domain`, including any CNAME records leading
+    /// to them. A name or type that does not exist yields no records.
+    pub fn resolve(
+        &self,
+        domain: &str,
+        record_type: RecordType,
+    ) -> Result<Vec<Answer>, ResolveError> {
+        let name = DnsName::from_utf8(domain)?;
+        if self.needs_priming() {
+            // resolution still works with the previous hints
+            let _ = self.prime();
+        }
+        self.resolve_at(&name, record_type, 0)
+    }
+
+    /// Asynchronously resolves INternet records of any type for `domain`, see
+    /// [`RecursiveResolver::resolve`]
+    pub async fn resolve_async(
+        &self,
+        domain: &str,
+        record_type: RecordType,
+    ) -> Result<Vec<Answer>, ResolveError> {
+        let name = DnsName::from_utf8(domain)?;
+        if self.needs_priming() {
+            let _ = self.prime_async().await;
+        }
+        self.resolve_at_async(name, record_type, 0).await
+    }
+
+    fn resolve_at(
+        &self,
+        name: &DnsName,
+        record_type: RecordType,
+        depth: usize,
+    ) -> Result<Vec<Answer>, ResolveError> {
+        if depth > MAX_DEPTH {
+            return Err(ResolveError::TooManyReferrals(name.clone()));
+        }
+
+        let roots = self.roots();
+        let mut walk = Walk::new(name.clone(), &roots, self.qname_minimization);
+        while walk.referrals <= self.max_referrals {
+            let (qname, qtype) = walk.question(record_type);
+            let step = match self.ask(&walk.servers, &qname, qtype, &walk.zone) {
+                Err(_) if walk.is_minimized() => {
+                    walk.reveal_all();
+                    continue;
+                }
+                step => step?,
+            };
+            let name_servers = match walk.advance(step, &roots)? {
+                Some(answers) => return Ok(answers),
+                None if walk.servers.is_empty() => std::mem::take(&mut walk.name_servers),
+                None => continue,
+            };
+            walk.servers = self.name_server_addresses(&name_servers, &walk.zone, depth)?;
+        }
+        Err(ResolveError::TooManyReferrals(name.clone()))
+    }
+
+    fn resolve_at_async(
+        &self,
+        name: DnsName,
+        record_type: RecordType,
+        depth: usize,
+    ) -> BoxFuture<'_, Result<Vec<Answer>, ResolveError>> {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                return Err(ResolveError::TooManyReferrals(name.clone()));
+            }
+
+            let roots = self.roots();
+            let mut walk = Walk::new(name.clone(), &roots, self.qname_minimization);
+            while walk.referrals <= self.max_referrals {
+                let (qname, qtype) = walk.question(record_type);
+                let step = match self
+                    .ask_async(&walk.servers, &qname, qtype, &walk.zone)
+                    .await
+                {
+                    Err(_) if walk.is_minimized() => {
+                        walk.reveal_all();
+                        continue;
+                    }
+                    step => step?,
+                };
+                let name_servers = match walk.advance(step, &roots)? {
+                    Some(answers) => return Ok(answers),
+                    None if walk.servers.is_empty() => std::mem::take(&mut walk.name_servers),
+                    None => continue,
+                };
+                walk.servers = self
+                    .name_server_addresses_async(&name_servers, &walk.zone, depth)
+                    .await?;
+            }
+            Err(ResolveError::TooManyReferrals(name.clone()))
+        })
+    }
+
+    /// Asks `servers` one after the other, until one of them gives a usable response
+    fn ask(
+        &self,
+        servers: &[SocketAddr],
+        name: &DnsName,
+        record_type: RecordType,
+        zone: &DnsName,
+    ) -> Result<Step, ResolveError> {
+        let query = self.query(name, record_type);
+        let socket = bind_query_socket(&self.opts)?;
+        let mut last = Err(ResolveError::NoNameServers(zone.clone()));
+        for server in servers {
+            let start = Instant::now();
+            let response = resolve_query(&query, &server.to_string(), &socket, &self.opts);
+            last = self.examine(server, start, response, name, record_type, zone);
+            if last.is_ok() {
+                break;
+            }
+        }
+        last
+    }
+
+    async fn ask_async(
+        &self,
+        servers: &[SocketAddr],
+        name: &DnsName,
+        record_type: RecordType,
+        zone: &DnsName,
+    ) -> Result<Step, ResolveError> {
+        let query = self.query(name, record_type);
+        let socket = bind_query_socket_async(&self.opts).await?;
+        let mut last = Err(ResolveError::NoNameServers(zone.clone()));
+        for server in servers {
+            let start = Instant::now();
+            let response =
+                resolve_query_async(&query, &server.to_string(), &socket, &self.opts).await;
+            last = self.examine(server, start, response, name, record_type, zone);
+            if last.is_ok() {
+                break;
+            }
+        }
+        last
+    }
+
+    /// Classifies the response of `server` to a query sent at `start`, and traces it
+    fn examine(
+        &self,
+        server: &SocketAddr,
+        start: Instant,
+        response: Result<Vec<u8>, ResolveError>,
+        name: &DnsName,
+        record_type: RecordType,
+        zone: &DnsName,
+    ) -> Result<Step, ResolveError> {
+        let rtt = start.elapsed();
+        let Some(Tracer(tracer)) = &self.tracer else {
+            return response.and_then(|response| classify(&response, name, record_type, zone));
+        };
+        let packet = response
+            .as_ref()
+            .ok()
+            .and_then(|response| DnsParser::new(response).parse_packet().ok());
+        let step = response.and_then(|response| classify(&response, name, record_type, zone));
+        tracer(&TraceStep {
+            zone: zone.clone(),
+            server: *server,
+            name: name.clone(),
+            record_type,
+            rtt,
+            response: packet,
+            error: step.as_ref().err().map(ToString::to_string),
+        });
+        step
+    }
+
+    fn query(&self, name: &DnsName, record_type: RecordType) -> Vec<u8> {
+        QueryBuilder::new(name.clone())
+            .id(random_id())
+            .record_type(record_type)
+            .recursion_desired(false)
+            .edns(self.opts.edns)
+            .build()
+    }
+
+    /// Addresses of the name servers, from their glue or else by resolving their names
+    fn name_server_addresses(
+        &self,
+        name_servers: &[NameServer],
+        zone: &DnsName,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>, ResolveError> {
+        let glued = self.glued_addresses(name_servers);
+        if !glued.is_empty() {
+            return Ok(glued);
+        }
+
+        let mut last_error = None;
+        for name_server in name_servers {
+            match self.resolve_at(&name_server.name, RecordType::A, depth + 1) {
+                Ok(answers) => match self.addresses(&answers) {
+                    addresses if addresses.is_empty() => {}
+                    addresses => return Ok(addresses),
+                },
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ResolveError::NoNameServers(zone.clone())))
+    }
+
+    async fn name_server_addresses_async(
+        &self,
+        name_servers: &[NameServer],
+        zone: &DnsName,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>, ResolveError> {
+        let glued = self.glued_addresses(name_servers);
+        if !glued.is_empty() {
+            return Ok(glued);
+        }
+
+        let mut last_error = None;
+        for name_server in name_servers {
+            match self
+                .resolve_at_async(name_server.name.clone(), RecordType::A, depth + 1)
+                .await
+            {
+                Ok(answers) => match self.addresses(&answers) {
+                    addresses if addresses.is_empty() => {}
+                    addresses => return Ok(addresses),
+                },
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ResolveError::NoNameServers(zone.clone())))
+    }
+
+    fn glued_addresses(&self, name_servers: &[NameServer]) -> Vec<SocketAddr> {
+        name_servers
+            .iter()
+            .flat_map(|name_server| &name_server.glue)
+            .map(|&ip| SocketAddr::from((ip, self.port)))
+            .collect()
+    }
+
+    fn addresses(&self, answers: &[Answer]) -> Vec<SocketAddr> {
+        answers
+            .iter()
+            .filter_map(|answer| match answer {
+                Answer::A { ipv4, .. } => Some(SocketAddr::from((*ipv4, self.port))),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Progress of resolving a single name
+struct Walk {
+    name: DnsName,
+    /// Zone the current servers are authoritative for
+    zone: DnsName,
+    servers: Vec<SocketAddr>,
+    /// Name servers of a referral, whose addresses are not known yet
+    name_servers: Vec<NameServer>,
+    /// CNAME records followed so far
+    chain: Vec<Answer>,
+    referrals: usize,
+    qname_minimization: bool,
+    /// Labels of the name the current servers are asked for, or `None` to ask for the full name
+    revealed: Option<usize>,
+    minimized_queries: usize,
+}
+
+impl Walk {
+    fn new(name: DnsName, roots: &[SocketAddr], qname_minimization: bool) -> Self {
+        Self {
+            name,
+            zone: DnsName::root(),
+            servers: roots.to_vec(),
+            name_servers: vec![],
+            chain: vec![],
+            referrals: 0,
+            qname_minimization,
+            // the root servers only need to know the top level domain
+            revealed: qname_minimization.then_some(1),
+            minimized_queries: 0,
+        }
+    }
+
+    /// The name and record type to ask the current servers for
+    fn question(&mut self, record_type: RecordType) -> (DnsName, RecordType) {
+        if self.minimized_queries >= MAX_MINIMISE_COUNT {
+            self.reveal_all();
+        }
+        if !self.is_minimized() {
+            return (self.name.clone(), record_type);
+        }
+        self.minimized_queries += 1;
+        let labels = self.revealed.unwrap_or_default();
+        // https://datatracker.ietf.org/doc/html/rfc9156#section-3
+        (self.name.ancestor(labels), RecordType::A)
+    }
+
+    /// Whether the current servers are not asked for the full name
+    fn is_minimized(&self) -> bool {
+        self.revealed
+            .is_some_and(|labels| labels < self.name.label_count())
+    }
+
+    fn reveal_all(&mut self) {
+        self.revealed = None;
+    }
+
+    /// Takes the `step` of the last response, and returns all records once the walk is done.
+    /// If it leaves no servers to ask next, their addresses have to be found for the name servers
+    /// in [`Walk::name_servers`].
+    fn advance(
+        &mut self,
+        step: Step,
+        roots: &[SocketAddr],
+    ) -> Result<Option<Vec<Answer>>, ResolveError> {
+        match step {
+            // a minimized name without a delegation exists, so the servers need another label
+            Step::Answer(_) | Step::Cname { .. } if self.is_minimized() => {
+                self.revealed = self.revealed.map(|labels| labels + 1);
+                Ok(None)
+            }
+            Step::Answer(answers) => {
+                let mut records = std::mem::take(&mut self.chain);
+                records.extend(answers);
+                Ok(Some(records))
+            }
+            // nothing below a name that does not exist
+            Step::NoSuchName => Ok(Some(std::mem::take(&mut self.chain))),
+            Step::Cname { chain, target } => {
+                let seen = self
+                    .chain
+                    .iter()
+                    .chain(&chain)
+                    .any(|answer| answer.meta().name == target);
+                if seen {
+                    return Err(ResolveError::CnameLoop(target));
+                }
+                self.chain.extend(chain);
+                if self.chain.len() > DEFAULT_MAX_CNAME_HOPS {
+                    return Err(ResolveError::TooManyCnames(target));
+                }
+                *self = Self {
+                    chain: std::mem::take(&mut self.chain),
+                    referrals: self.referrals,
+                    ..Self::new(target, roots, self.qname_minimization)
+                };
+                Ok(None)
+            }
+            Step::Referral { zone, name_servers } => {
+                self.referrals += 1;
+                self.revealed = self.qname_minimization.then(|| zone.label_count() + 1);
+                self.zone = zone;
+                self.servers = vec![];
+                self.name_servers = name_servers;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Makes sense of the `response` of a name server that is authoritative for `zone`, only trusting
+/// records within that zone
+fn classify(
+    response: &[u8],
+    name: &DnsName,
+    record_type: RecordType,
+    zone: &DnsName,
+) -> Result<Step, ResolveError> {
+    ResponseError::check(response)?;
+    let response = DnsParser::new(response).parse_packet()?;
+    let in_zone = |answer: &&Answer| answer.meta().name.is_subdomain_of(zone);
+
+    // follow the CNAME records within the zone, unless they are what was asked for
+    let mut current = name.clone();
+    let mut chain = vec![];
+    loop {
+        let cname = response.answers.iter().filter(in_zone).find(|answer| {
+            matches!(answer, Answer::CNAME { meta, .. } if meta.name == current)
+                && record_type != RecordType::CNAME
+        });
+        let Some(Answer::CNAME { cname: target, .. }) = cname else {
+            break;
+        };
+        if chain.len() > DEFAULT_MAX_CNAME_HOPS {
+            return Err(ResolveError::TooManyCnames(name.clone()));
+        }
+        current = target.clone();
+        chain.extend(cname.cloned());
+    }
+
+    let records: Vec<_> = response
+        .answers
+        .iter()
+        .filter(in_zone)
+        .filter(|answer| answer.meta().r#type == record_type && answer.meta().name == current)
+        .cloned()
+        .collect();
+    let nxdomain = response.header.rcode() == ResponseCode::NXDomain;
+    if nxdomain && chain.is_empty() {
+        return Ok(Step::NoSuchName);
+    }
+    if !records.is_empty() {
+        chain.extend(records);
+        return Ok(Step::Answer(chain));
+    }
+    if !chain.is_empty() && !current.is_subdomain_of(zone) {
+        return Ok(Step::Cname {
+            chain,
+            target: current,
+        });
+    }
+
+    // a referral to a zone between the current one and the name
+    let delegated = response.authorities.iter().find_map(|answer| match answer {
+        Answer::NS { meta, .. }
+            if meta.name != *zone
+                && meta.name.is_subdomain_of(zone)
+                && current.is_subdomain_of(&meta.name) =>
+        {
+            Some(meta.name.clone())
+        }
+        _ => None,
+    });
+    let Some(delegated) = delegated else {
+        let referral = response
+            .authorities
+            .iter()
+            .any(|answer| matches!(answer, Answer::NS { .. }) && response.answers.is_empty());
+        if referral && !response.header.flags.authoritative_answer {
+            return Err(ResolveError::LameReferral(zone.clone()));
+        }
+        // the name exists without records of the type
+        return Ok(Step::Answer(chain));
+    };
+
+    let name_servers = response
+        .authorities
+        .iter()
+        .filter_map(|answer| match answer {
+            Answer::NS { meta, ns } if meta.name == delegated => Some(ns),
+            _ => None,
+        })
+        .map(|ns| NameServer {
+            name: ns.clone(),
+            glue: response
+                .additionals
+                .iter()
+                .filter(in_zone)
+                .filter_map(|answer| match answer {
+                    Answer::A { meta, ipv4 } if meta.name == *ns => Some(*ipv4),
+                    _ => None,
+                })
+                .collect(),
+        })
+        .collect();
+    if !chain.is_empty() {
+        // the alias points into a zone delegated further down
+        return Ok(Step::Cname {
+            chain,
+            target: current,
+        });
+    }
+    Ok(Step::Referral {
+        zone: delegated,
+        name_servers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            packet::Packet,
+            question::Question,
+            record_type::RecordType,
+            response_code::ResponseCode,
+        },
+        root_hints::{RootHints, RootServer},
+    };
+
+    use super::RecursiveResolver;
+
+    fn meta(name: &str, r#type: RecordType) -> AnswerMeta {
+        AnswerMeta {
+            name: name.parse().unwrap(),
+            r#type,
+            class: Class::IN,
+            ttl: 300,
+            len: 0,
+        }
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Answer {
+        Answer::A {
+            meta: meta(name, RecordType::A),
+            ipv4: ip.into(),
+        }
+    }
+
+    fn ns(zone: &str, ns: &str) -> Answer {
+        Answer::NS {
+            meta: meta(zone, RecordType::NS),
+            ns: ns.parse().unwrap(),
+        }
+    }
+
+    fn cname(name: &str, target: &str) -> Answer {
+        Answer::CNAME {
+            meta: meta(name, RecordType::CNAME),
+            cname: target.parse().unwrap(),
+        }
+    }
+
+    /// Sections of a response, and whether it is authoritative and says the name does not exist
+    #[derive(Default)]
+    struct Response {
+        answers: Vec<Answer>,
+        authorities: Vec<Answer>,
+        additionals: Vec<Answer>,
+        nxdomain: bool,
+    }
+
+    /// Questions a name server was asked, as name and record type
+    type Asked = Arc<Mutex<Vec<String>>>;
+
+    /// Starts a name server on `ip` and `port` that responds to all questions with `zone`
+    fn mock_name_server(ip: Ipv4Addr, port: u16, zone: fn(&Question) -> Response) -> (u16, Asked) {
+        let server = UdpSocket::bind((ip, port)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let asked = Asked::default();
+        let questions = asked.clone();
+        std::thread::spawn(move || loop {
+            let mut query = [0; 512];
+            let (len, client) = server.recv_from(&mut query).unwrap();
+            let query = DnsParser::new(&query[..len]).parse_packet().unwrap();
+            assert!(!query.header.flags.recursion_desired);
+            let question = &query.questions[0];
+            questions
+                .lock()
+                .unwrap()
+                .push(format!("{} {:?}", question.domain_name, question.r#type));
+
+            let response = zone(&query.questions[0]);
+            let mut packet = Packet {
+                header: query.header,
+                questions: query.questions,
+                answers: response.answers,
+                authorities: response.authorities,
+                additionals: response.additionals,
+            };
+            packet.header.flags.query = false;
+            packet.header.flags.authoritative_answer = packet.authorities.is_empty();
+            if response.nxdomain {
+                packet.header.flags.authoritative_answer = true;
+                packet.header.flags.response_code = ResponseCode::NXDomain;
+            }
+            server.send_to(&packet.to_bytes(), client).unwrap();
+        });
+        (port, asked)
+    }
+
+    fn root(question: &Question) -> Response {
+        if question.domain_name.is_root() {
+            return Response {
+                answers: vec![ns(".", "a.root.test")],
+                additionals: vec![a("a.root.test", [127, 0, 0, 1])],
+                ..Response::default()
+            };
+        }
+        Response {
+            authorities: vec![ns("com", "a.gtld.test")],
+            // the root is responsible for all names, so any glue is trusted
+            additionals: vec![a("a.gtld.test", [127, 0, 0, 2])],
+            ..Response::default()
+        }
+    }
+
+    fn com(question: &Question) -> Response {
+        let name = &question.domain_name;
+        if name.is_subdomain_of(&"glueless.com".parse().unwrap()) {
+            return Response {
+                authorities: vec![ns("glueless.com", "ns.example.com")],
+                ..Response::default()
+            };
+        }
+        Response {
+            authorities: vec![ns("example.com", "ns.example.com")],
+            additionals: vec![
+                a("ns.example.com", [127, 0, 0, 3]),
+                // not within com, so it must be ignored
+                a("ns.example.org", [127, 0, 0, 99]),
+            ],
+            ..Response::default()
+        }
+    }
+
+    fn example(question: &Question) -> Response {
+        match question.domain_name.as_str() {
+            "www.example.com" | "www.glueless.com" => Response {
+                answers: vec![a(question.domain_name.as_str(), [192, 0, 2, 1])],
+                ..Response::default()
+            },
+            "ns.example.com" => Response {
+                answers: vec![a("ns.example.com", [127, 0, 0, 3])],
+                ..Response::default()
+            },
+            "alias.example.com" => Response {
+                answers: vec![
+                    cname("alias.example.com", "www.example.com"),
+                    a("www.example.com", [192, 0, 2, 1]),
+                ],
+                ..Response::default()
+            },
+            "external.example.com" => Response {
+                answers: vec![
+                    cname("external.example.com", "www.glueless.com"),
+                    // not within example.com, so it must be resolved from the root
+                    a("www.glueless.com", [203, 0, 113, 66]),
+                ],
+                ..Response::default()
+            },
+            _ => Response {
+                nxdomain: true,
+                ..Response::default()
+            },
+        }
+    }
+
+    /// A resolver for the mock root, com and example.com servers, with the questions each of them
+    /// was asked
+    fn mock_hierarchy() -> (RecursiveResolver, [Asked; 3]) {
+        let (port, root) = mock_name_server(Ipv4Addr::new(127, 0, 0, 1), 0, root);
+        let (_, com) = mock_name_server(Ipv4Addr::new(127, 0, 0, 2), port, com);
+        let (_, example) = mock_name_server(Ipv4Addr::new(127, 0, 0, 3), port, example);
+        let hints = RootHints {
+            name_servers: vec![RootServer {
+                name: "root.test".parse().unwrap(),
+                addresses: vec![[127, 0, 0, 1].into()],
+            }],
+            expires: None,
+        };
+        let resolver = RecursiveResolver {
+            port,
+            ..RecursiveResolver::new().root_hints(hints).priming(false)
+        };
+        (resolver, [root, com, example])
+    }
+
+    fn addresses(answers: &[Answer]) -> Vec<String> {
+        answers
+            .iter()
+            .map(|answer| match answer {
+                Answer::A { meta, ipv4 } => format!("{} {ipv4}", meta.name),
+                Answer::CNAME { meta, cname } => format!("{} {cname}", meta.name),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_recursive_resolution() {
+        let (resolver, _) = mock_hierarchy();
+        let answers = resolver.resolve("www.example.com", RecordType::A).unwrap();
+        assert_eq!(addresses(&answers), ["www.example.com 192.0.2.1"]);
+
+        let answers = resolver
+            .resolve("alias.example.com", RecordType::A)
+            .unwrap();
+        assert_eq!(
+            addresses(&answers),
+            [
+                "alias.example.com www.example.com",
+                "www.example.com 192.0.2.1"
+            ]
+        );
+
+        let answers = resolver
+            .resolve("missing.example.com", RecordType::A)
+            .unwrap();
+        assert!(answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recursive_resolution_async() {
+        let (resolver, _) = mock_hierarchy();
+
+        // the address of the name server has to be resolved first
+        let answers = resolver
+            .resolve_async("www.glueless.com", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(addresses(&answers), ["www.glueless.com 192.0.2.1"]);
+
+        // records outside of the zone of a server are not trusted
+        let answers = resolver
+            .resolve_async("external.example.com", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            addresses(&answers),
+            [
+                "external.example.com www.glueless.com",
+                "www.glueless.com 192.0.2.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_qname_minimization() {
+        let asked = |asked: &Asked| std::mem::take(&mut *asked.lock().unwrap());
+        let (resolver, [root, com, example]) = mock_hierarchy();
+
+        let answers = resolver.resolve("www.example.com", RecordType::MX).unwrap();
+        assert!(answers.is_empty());
+        assert_eq!(asked(&root), ["com A"]);
+        assert_eq!(asked(&com), ["example.com A"]);
+        assert_eq!(asked(&example), ["www.example.com MX"]);
+
+        // there is nothing below a name that does not exist
+        let answers = resolver
+            .resolve("a.b.missing.example.com", RecordType::A)
+            .unwrap();
+        assert!(answers.is_empty());
+        assert_eq!(asked(&example), ["missing.example.com A"]);
+
+        // names below existing names are revealed label by label
+        let answers = resolver
+            .resolve("a.b.www.example.com", RecordType::A)
+            .unwrap();
+        assert!(answers.is_empty());
+        assert_eq!(
+            asked(&example),
+            ["www.example.com A", "b.www.example.com A"]
+        );
+
+        // every resolution starts at the root again
+        assert_eq!(asked(&root), ["com A", "com A"]);
+        let resolver = resolver.qname_minimization(false);
+        asked(&com);
+        resolver.resolve("www.example.com", RecordType::MX).unwrap();
+        assert_eq!(asked(&root), ["www.example.com MX"]);
+        assert_eq!(asked(&com), ["www.example.com MX"]);
+    }
+
+    #[test]
+    fn test_trace() {
+        let (resolver, _) = mock_hierarchy();
+        let steps = Arc::new(Mutex::new(vec![]));
+        let traced = steps.clone();
+        let resolver = resolver.trace(move |step| {
+            let response = step.response.as_ref().unwrap();
+            traced.lock().unwrap().push(format!(
+                "{} {} {} {}",
+                step.zone,
+                step.server.ip(),
+                step.name,
+                response.answers.len() + response.authorities.len()
+            ));
+            assert!(step.error.is_none() && !step.is_signed());
+        });
+
+        resolver.resolve("www.example.com", RecordType::A).unwrap();
+        assert_eq!(
+            *steps.lock().unwrap(),
+            [
+                ". 127.0.0.1 com 1",
+                "com 127.0.0.2 example.com 1",
+                "example.com 127.0.0.3 www.example.com 1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_priming() {
+        let asked = |asked: &Asked| std::mem::take(&mut *asked.lock().unwrap());
+        let (resolver, [root, ..]) = mock_hierarchy();
+        let resolver = resolver.priming(true);
+
+        resolver
+            .resolve_async("www.example.com", RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(asked(&root), [". NS", "com A"]);
+        let hints = resolver.current_root_hints();
+        assert_eq!(hints.name_servers[0].name.as_str(), "a.root.test");
+        assert!(hints.expires.is_some());
+
+        // the hints are used until the records of the priming response expire
+        resolver.resolve("www.example.com", RecordType::A).unwrap();
+        assert_eq!(asked(&root), ["com A"]);
+    }
+}
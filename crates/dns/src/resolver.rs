@@ -1,83 +1,1423 @@
-use std::{net::UdpSocket, time::Duration};
+use std::{
+    borrow::Cow,
+    future::Future,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::OsRng, Rng};
 
 use crate::{
-    parse::parser::{encode_domain_name, DnsParser},
-    protocol::{answer::Answer, utils::generate_nx_response},
+    authority::ZoneSet,
+    cache::DnsCache,
+    error::ResolveError,
+    hosts::HostsFile,
+    parse::{error::DnsParseError, parser::DnsParser},
+    protocol::{
+        answer::Answer,
+        header::Flags,
+        name::{DnsName, NameError},
+        opt::ExtendedError,
+        query::{random_id, EdnsOptions, QueryBuilder},
+        record_type::RecordType,
+        response_code::ResponseCode,
+        utils::generate_nx_response,
+    },
+    serialize::writer::{write_response, MAX_UDP_MESSAGE_SIZE},
+    tcp::{read_tcp_message, read_tcp_message_async, write_tcp_message, write_tcp_message_async},
+    transport::{
+        https::{DohMethod, DohTransport},
+        tls::DotTransport,
+        Transport,
+    },
+    upstream::UpstreamPool,
 };
 
-/// Synchronously resolves INternet A records for `domain` using the DNS server `dns`
+#[cfg(feature = "doq")]
+use crate::transport::quic::DoqTransport;
+
+/// Settings shared by all queries of a resolver
+#[derive(Debug, Clone)]
+pub struct ResolveOptions {
+    /// Query ID to use, a random one for every query if unset
+    pub id: Option<u16>,
+    /// OPT record attached to generated queries, which allows responses larger than 512 bytes.
+    /// Advertises a payload size of 1232 bytes by default.
+    pub edns: Option<EdnsOptions>,
+    /// How long to wait for a response to the first datagram
+    pub timeout: Duration,
+    /// How often the query is sent again after running into a timeout
+    pub retries: u32,
+    /// Factor by which the timeout grows with every retry
+    pub backoff: u32,
+    /// Upper bound for the timeout of a single attempt, before jitter
+    pub max_timeout: Duration,
+    /// Up to this much is randomly added to every timeout, so clients that lost their datagrams
+    /// at the same time do not retry in lockstep
+    pub jitter: Duration,
+    /// Whether to repeat the query over TCP when the UDP response was truncated, otherwise the
+    /// truncated response is returned with the TC bit still set
+    pub tcp_fallback: bool,
+    /// Protocol used to talk to the upstream servers
+    pub transport: Transport,
+    /// Whether sockets for UDP queries are bound to a port picked with a CSPRNG from all
+    /// unprivileged ports, instead of one from the smaller ephemeral range the OS picks
+    /// https://datatracker.ietf.org/doc/html/rfc5452#section-9.2
+    pub randomize_source_port: bool,
+    /// Cache consulted before querying upstream servers, which successful responses are added to
+    /// and which answers stale records if no upstream server responds, see
+    /// [`DnsCache::serve_stale`]
+    pub cache: Option<Arc<DnsCache>>,
+    /// Zones whose names are answered authoritatively, before consulting the hosts file, the
+    /// cache or any upstream server
+    pub zones: Option<Arc<ZoneSet>>,
+    /// Hosts file whose names are answered before consulting the cache or any upstream server
+    pub hosts: Option<Arc<HostsFile>>,
+    /// Suffixes tried for relative names, see [`ResolveOptions::search_names`]
+    pub search: Vec<DnsName>,
+    /// Names with fewer dots than this are tried with the search domains before they are tried
+    /// as is
+    pub ndots: usize,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self {
+            id: None,
+            edns: Some(EdnsOptions::default()),
+            timeout: Duration::from_secs(2),
+            retries: 2,
+            backoff: 2,
+            max_timeout: Duration::from_secs(10),
+            jitter: Duration::from_millis(100),
+            tcp_fallback: true,
+            transport: Transport::Udp,
+            randomize_source_port: true,
+            cache: None,
+            zones: None,
+            hosts: None,
+            search: vec![],
+            ndots: 1,
+        }
+    }
+}
+
+impl ResolveOptions {
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// The names to try in turn for `domain`, with the search domains appended as glibc does:
+    /// names ending with a dot are absolute and only tried as is. Names with at least
+    /// [`ResolveOptions::ndots`] dots are tried as is first, all others after the search domains.
+    ///
+    /// ```
+    /// use dns::resolver::ResolveOptions;
+    ///
+    /// let opts = ResolveOptions {
+    ///     search: vec!["home.arpa".parse().unwrap()],
+    ///     ..ResolveOptions::default()
+    /// };
+    /// let names = opts.search_names("nas").unwrap();
+    /// assert_eq!(names[0].as_str(), "nas.home.arpa");
+    /// assert_eq!(names[1].as_str(), "nas");
+    /// ```
+    pub fn search_names(&self, domain: &str) -> Result<Vec<DnsName>, NameError> {
+        let name = DnsName::from_utf8(domain)?;
+        if domain.ends_with('.') || name.is_root() {
+            return Ok(vec![name]);
+        }
+
+        // names that get too long with a suffix are skipped
+        let mut names: Vec<_> = self
+            .search
+            .iter()
+            .filter_map(|suffix| DnsName::new(&format!("{name}.{suffix}")).ok())
+            .collect();
+        if name.label_count() > self.ndots {
+            names.insert(0, name);
+        } else {
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    /// Size of the largest response to `query` that is accepted, which is the payload size the
+    /// query advertises. Raw queries passed through, eg. by a relay, carry their own OPT record.
+    fn max_response_size(&self, query: &[u8]) -> usize {
+        let advertised = DnsParser::new(query)
+            .parse_opt()
+            .ok()
+            .flatten()
+            .map(|opt| opt.payload_size as usize);
+        let configured = self.edns.map(|edns| edns.payload_size as usize);
+        advertised
+            .max(configured)
+            .unwrap_or_default()
+            .max(MAX_UDP_MESSAGE_SIZE)
+    }
+
+    /// Timeout of the `attempt`th transmission, starting at 0
+    fn attempt_timeout(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff.saturating_pow(attempt);
+        let timeout = self.timeout.saturating_mul(backoff).min(self.max_timeout);
+        timeout + self.jitter.mul_f64(rand::random::<f64>())
+    }
+
+    /// Method used for upstream servers given as `https://` URL
+    fn doh_method(&self) -> DohMethod {
+        match self.transport {
+            Transport::Https { method } => method,
+            _ => DohMethod::default(),
+        }
+    }
+}
+
+/// Returns the query to send for the `attempt`th transmission, which gets a fresh ID for every
+/// retry so a late response to an earlier attempt can not be mistaken for the current one
+fn attempt_query(query: &[u8], attempt: u32) -> Cow<'_, [u8]> {
+    if attempt == 0 || query.len() < 2 {
+        return Cow::Borrowed(query);
+    }
+    let mut query = query.to_vec();
+    query[0..2].copy_from_slice(&random_id().to_be_bytes());
+    Cow::Owned(query)
+}
+
+/// Whether `source` is `upstream`, also if one of them is an IPv4-mapped IPv6 address as
+/// reported by dual-stack sockets
+fn is_same_address(source: SocketAddr, upstream: SocketAddr) -> bool {
+    source.port() == upstream.port() && source.ip().to_canonical() == upstream.ip().to_canonical()
+}
+
+/// Whether `response` repeats the question of `query`, so it can not be a response to another
+/// query that happened to use the same ID. Names are compared case-insensitively.
+/// https://datatracker.ietf.org/doc/html/rfc5452#section-9.1
+fn answers_question(query: &[u8], response: &[u8]) -> bool {
+    let mut parser = DnsParser::new(query);
+    let question = match parser.parse_header() {
+        Ok(header) if header.question_count > 0 => parser.parse_question(),
+        // there is nothing to compare with
+        _ => return true,
+    };
+    let Ok(question) = question else {
+        return true;
+    };
+
+    let mut parser = DnsParser::new(response);
+    match parser.parse_header() {
+        Ok(header) if header.question_count > 0 => {
+            parser.parse_question().is_ok_and(|echo| echo == question)
+        }
+        // some servers leave out the question of error responses
+        Ok(header) => header.rcode() != ResponseCode::NoError,
+        Err(_) => false,
+    }
+}
+
+fn ensure_question(query: &[u8], response: &[u8]) -> Result<(), ResolveError> {
+    match answers_question(query, response) {
+        true => Ok(()),
+        false => Err(ResolveError::QuestionMismatch),
+    }
+}
+
+/// A response that does not answer the question, along with the reasons the server gave for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseError {
+    /// Including the extended bits of the OPT record
+    pub response_code: ResponseCode,
+    /// https://datatracker.ietf.org/doc/html/rfc8914
+    pub extended_errors: Vec<ExtendedError>,
+}
+
+impl ResponseError {
+    /// Fails if the raw `response` is neither a successful one nor says that the name does not
+    /// exist, or if the server withheld the answer because of a policy, which is reported with a
+    /// NOERROR or NXDOMAIN response by many filtering resolvers
+    pub fn check(response: &[u8]) -> Result<(), Self> {
+        let mut parser = DnsParser::new(response);
+        let Ok(header) = parser.parse_header() else {
+            return Ok(());
+        };
+        let opt = DnsParser::new(response).parse_opt().ok().flatten();
+        let error = Self {
+            response_code: ResponseCode::from_parts(
+                header.rcode().header_bits(),
+                opt.as_ref().map_or(0, |opt| opt.extended_rcode),
+            ),
+            extended_errors: opt.map(|opt| opt.extended_errors()).unwrap_or_default(),
+        };
+
+        let answered = matches!(
+            error.response_code,
+            ResponseCode::NoError | ResponseCode::NXDomain
+        );
+        if answered && !error.is_filtered() {
+            return Ok(());
+        }
+        Err(error)
+    }
+
+    /// Whether the server refused to answer because of a blocklist or similar policy
+    pub fn is_filtered(&self) -> bool {
+        self.extended_errors
+            .iter()
+            .any(|error| error.code.is_filtered())
+    }
+
+    /// Whether the answer failed DNSSEC validation
+    pub fn is_dnssec_failure(&self) -> bool {
+        self.extended_errors
+            .iter()
+            .any(|error| error.code.is_dnssec_failure())
+    }
+}
+
+impl std::fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream responded with {:?}", self.response_code)?;
+        for (i, error) in self.extended_errors.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{separator}{error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+/// ID of a DNS message, if it is long enough to have one
+fn message_id(message: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*message.first()?, *message.get(1)?]))
+}
+
+/// A socket asynchronous UDP queries are sent from, implemented by tokio's, and by others that are
+/// driven differently, eg. through io_uring
+pub trait QuerySocket: Sync {
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+
+    fn send_to(
+        &self,
+        datagram: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<usize>> + Send;
+
+    fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = std::io::Result<(usize, SocketAddr)>> + Send;
+}
+
+impl QuerySocket for tokio::net::UdpSocket {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    fn send_to(
+        &self,
+        datagram: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<usize>> + Send {
+        self.send_to(datagram, target)
+    }
+
+    fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = std::io::Result<(usize, SocketAddr)>> + Send {
+        self.recv_from(buffer)
+    }
+}
+
+/// Binds a new socket for UDP queries to IPv4 upstreams, see
+/// [`ResolveOptions::randomize_source_port`]. Exchanges with IPv6 upstreams bind their own socket
+/// with [`bind_query_socket_for`].
+pub fn bind_query_socket(opts: &ResolveOptions) -> std::io::Result<UdpSocket> {
+    bind_query_socket_for(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), opts)
+}
+
+/// Binds a new socket for UDP queries to the unspecified address of the family of `upstream`
+pub fn bind_query_socket_for(
+    upstream: SocketAddr,
+    opts: &ResolveOptions,
+) -> std::io::Result<UdpSocket> {
+    let unspecified = unspecified_address(upstream);
+    if opts.randomize_source_port {
+        for port in random_ports() {
+            match UdpSocket::bind((unspecified, port)) {
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                result => return result,
+            }
+        }
+    }
+    UdpSocket::bind((unspecified, 0))
+}
+
+/// Asynchronously binds a new socket for UDP queries, see [`bind_query_socket`]
+pub async fn bind_query_socket_async(
+    opts: &ResolveOptions,
+) -> std::io::Result<tokio::net::UdpSocket> {
+    bind_query_socket_for_async(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), opts).await
+}
+
+/// Asynchronously binds a new socket for UDP queries to `upstream`, see
+/// [`bind_query_socket_for`]
+pub async fn bind_query_socket_for_async(
+    upstream: SocketAddr,
+    opts: &ResolveOptions,
+) -> std::io::Result<tokio::net::UdpSocket> {
+    let unspecified = unspecified_address(upstream);
+    if opts.randomize_source_port {
+        for port in random_ports() {
+            match tokio::net::UdpSocket::bind((unspecified, port)).await {
+                Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+                result => return result,
+            }
+        }
+    }
+    tokio::net::UdpSocket::bind((unspecified, 0)).await
+}
+
+fn unspecified_address(upstream: SocketAddr) -> IpAddr {
+    match upstream {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// A few random unprivileged ports to try binding to, before leaving the choice to the OS
+fn random_ports() -> impl Iterator<Item = u16> {
+    (0..8).map(|_| OsRng.gen_range(1024..=u16::MAX))
+}
+
+/// Gives the response the ID of the original query, no matter which attempt it belongs to
+fn restore_id(query: &[u8], response: &mut [u8]) {
+    if query.len() >= 2 && response.len() >= 2 {
+        response[0..2].copy_from_slice(&query[0..2]);
+    }
+}
+
+/// A parsed response, along with where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveResponse {
+    /// Including the extended bits of the OPT record
+    pub rcode: ResponseCode,
+    pub flags: Flags,
+    pub answers: Vec<Answer>,
+    pub authorities: Vec<Answer>,
+    pub additionals: Vec<Answer>,
+    /// Time until the response arrived, zero if it was answered locally
+    pub rtt: Duration,
+    /// The server that sent the response, `None` if it came from the hosts file or the cache
+    pub upstream: Option<String>,
+}
+
+impl ResolveResponse {
+    /// Parses the raw `response` that `upstream` sent after `rtt`
+    pub fn parse(
+        response: &[u8],
+        upstream: Option<String>,
+        rtt: Duration,
+    ) -> Result<Self, DnsParseError> {
+        let opt = DnsParser::new(response).parse_opt()?;
+        let packet = DnsParser::new(response).parse_packet()?;
+        Ok(Self {
+            rcode: ResponseCode::from_parts(
+                packet.header.rcode().header_bits(),
+                opt.map_or(0, |opt| opt.extended_rcode),
+            ),
+            flags: packet.header.flags,
+            answers: packet.answers,
+            authorities: packet.authorities,
+            additionals: packet.additionals,
+            rtt,
+            upstream,
+        })
+    }
+
+    /// Whether the name does not exist, as opposed to existing without records of the type
+    pub fn is_nxdomain(&self) -> bool {
+        self.rcode == ResponseCode::NXDomain
+    }
+}
+
+/// Synchronously resolves INternet records of any type for `domain`, failing over between
+/// `upstreams` if necessary
+///
+/// A single server can be passed as `&"1.1.1.1:53".into()`. Responses that do not answer the
+/// question fail with a [`ResponseError`], which tells why if the server included Extended DNS
+/// Errors. Relative names are tried with the search domains of `opts` until one of them has
+/// records, see [`ResolveOptions::search_names`].
+pub fn resolve_record(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<Answer>, ResolveError> {
+    Ok(resolve_response(domain, record_type, upstreams, opts)?.answers)
+}
+
+/// Resolves records like [`resolve_record`], but returns the whole response. If no name has
+/// records, it is the response for the first one that resolved.
+pub fn resolve_response(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<ResolveResponse, ResolveError> {
+    // a name without records beats failing to resolve another one
+    let (mut found, mut error) = (None, None);
+    for name in opts.search_names(domain)? {
+        match resolve_name(name, record_type, upstreams, opts) {
+            Ok(response) if !response.answers.is_empty() => return Ok(response),
+            Ok(response) => found = found.or(Some(response)),
+            Err(e) => error = Some(e),
+        }
+    }
+    found_or(found, error)
+}
+
+fn found_or(
+    found: Option<ResolveResponse>,
+    error: Option<ResolveError>,
+) -> Result<ResolveResponse, ResolveError> {
+    match (found, error) {
+        (Some(response), _) => Ok(response),
+        (None, Some(e)) => Err(e),
+        (None, None) => unreachable!("there is at least one name to search"),
+    }
+}
+
+/// Resolves records for exactly `name`, without trying any search domains
+fn resolve_name(
+    name: DnsName,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<ResolveResponse, ResolveError> {
+    let request = QueryBuilder::new(name)
+        .id(opts.id.unwrap_or_else(random_id))
+        .record_type(record_type)
+        .edns(opts.edns)
+        .build();
+
+    if let Some(response) = local_response(&request, opts) {
+        return Ok(ResolveResponse::parse(&response, None, Duration::ZERO)?);
+    }
+
+    let socket = bind_query_socket(opts)?;
+    match upstreams.exchange(&request, &socket, opts) {
+        Ok(answered) => {
+            ResponseError::check(&answered.response)?;
+            cache_response(&answered.response, opts);
+            Ok(ResolveResponse::parse(
+                &answered.response,
+                Some(answered.upstream),
+                answered.rtt,
+            )?)
+        }
+        Err(e) => {
+            let response = stale_response(&request, opts).ok_or(e)?;
+            Ok(ResolveResponse::parse(&response, None, Duration::ZERO)?)
+        }
+    }
+}
+
+/// Asynchronously resolves INternet records of any type for `domain`, see [`resolve_record`]
+pub async fn resolve_record_async(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<Answer>, ResolveError> {
+    Ok(resolve_response_async(domain, record_type, upstreams, opts)
+        .await?
+        .answers)
+}
+
+/// Asynchronously resolves records and returns the whole response, see [`resolve_response`]
+pub async fn resolve_response_async(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<ResolveResponse, ResolveError> {
+    let (mut found, mut error) = (None, None);
+    for name in opts.search_names(domain)? {
+        match resolve_name_async(name, record_type, upstreams, opts).await {
+            Ok(response) if !response.answers.is_empty() => return Ok(response),
+            Ok(response) => found = found.or(Some(response)),
+            Err(e) => error = Some(e),
+        }
+    }
+    found_or(found, error)
+}
+
+async fn resolve_name_async(
+    name: DnsName,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<ResolveResponse, ResolveError> {
+    let request = QueryBuilder::new(name)
+        .id(opts.id.unwrap_or_else(random_id))
+        .record_type(record_type)
+        .edns(opts.edns)
+        .build();
+
+    if let Some(response) = local_response(&request, opts) {
+        return Ok(ResolveResponse::parse(&response, None, Duration::ZERO)?);
+    }
+
+    let socket = bind_query_socket_async(opts).await?;
+    match upstreams.exchange_async(&request, &socket, opts).await {
+        Ok(answered) => {
+            ResponseError::check(&answered.response)?;
+            cache_response(&answered.response, opts);
+            Ok(ResolveResponse::parse(
+                &answered.response,
+                Some(answered.upstream),
+                answered.rtt,
+            )?)
+        }
+        Err(e) => {
+            let response = stale_response(&request, opts).ok_or(e)?;
+            Ok(ResolveResponse::parse(&response, None, Duration::ZERO)?)
+        }
+    }
+}
+
+/// Resolves the IPv4 and IPv6 addresses of `domain` with concurrent A and AAAA queries, in the
+/// order connections to them should be attempted, see [`sort_addresses`]. Only fails if both
+/// queries fail.
+pub fn resolve_ip(
+    domain: &str,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<IpAddr>, ResolveError> {
+    let (ipv6, ipv4) = std::thread::scope(|scope| {
+        let ipv6 = scope.spawn(|| resolve_record(domain, RecordType::AAAA, upstreams, opts));
+        let ipv4 = resolve_record(domain, RecordType::A, upstreams, opts);
+        let ipv6 = ipv6
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (ipv6, ipv4)
+    });
+    merge_addresses(ipv6, ipv4)
+}
+
+/// Asynchronously resolves the addresses of `domain`, see [`resolve_ip`]
+pub async fn resolve_ip_async(
+    domain: &str,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<IpAddr>, ResolveError> {
+    let (ipv6, ipv4) = tokio::join!(
+        resolve_record_async(domain, RecordType::AAAA, upstreams, opts),
+        resolve_record_async(domain, RecordType::A, upstreams, opts),
+    );
+    merge_addresses(ipv6, ipv4)
+}
+
+fn merge_addresses(
+    ipv6: Result<Vec<Answer>, ResolveError>,
+    ipv4: Result<Vec<Answer>, ResolveError>,
+) -> Result<Vec<IpAddr>, ResolveError> {
+    let (ipv6, ipv4) = match (ipv6, ipv4) {
+        (Err(e), Err(_)) => return Err(e),
+        (ipv6, ipv4) => (ipv6.unwrap_or_default(), ipv4.unwrap_or_default()),
+    };
+    let addresses = ipv6
+        .iter()
+        .chain(&ipv4)
+        .filter_map(|answer| match answer {
+            Answer::A { ipv4, .. } => Some(IpAddr::from(*ipv4)),
+            Answer::AAAA { ipv6, .. } => Some(IpAddr::from(*ipv6)),
+            _ => None,
+        })
+        .collect();
+    Ok(sort_addresses(addresses))
+}
+
+/// Resolves the TXT records of `domain`, with the character strings of each record joined
+/// together as SPF, DKIM and DMARC expect, see [`crate::mail`]. Invalid UTF-8 is replaced.
+/// https://datatracker.ietf.org/doc/html/rfc7208#section-3.3
+pub fn resolve_txt(
+    domain: &str,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<String>, ResolveError> {
+    Ok(txt_strings(resolve_record(
+        domain,
+        RecordType::TXT,
+        upstreams,
+        opts,
+    )?))
+}
+
+/// Asynchronously resolves the TXT records of `domain`, see [`resolve_txt`]
+pub async fn resolve_txt_async(
+    domain: &str,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<String>, ResolveError> {
+    Ok(txt_strings(
+        resolve_record_async(domain, RecordType::TXT, upstreams, opts).await?,
+    ))
+}
+
+fn txt_strings(answers: Vec<Answer>) -> Vec<String> {
+    answers
+        .into_iter()
+        .filter_map(|answer| match answer {
+            Answer::TXT { txt, .. } => Some(String::from_utf8_lossy(&txt.concat()).into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Sorts `addresses` by the precedence of the default policy table of RFC 6724, keeping the
+/// order within the same precedence, and then interleaves the address families as RFC 8305
+/// recommends, starting with the family of the most preferred address. Without knowing the
+/// source addresses of the host, the other rules of RFC 6724 are not applied.
+/// https://datatracker.ietf.org/doc/html/rfc6724#section-2.1
+/// https://datatracker.ietf.org/doc/html/rfc8305#section-4
+///
+/// ```
+/// use dns::resolver::sort_addresses;
+///
+/// let sorted = sort_addresses(vec![
+///     "192.0.2.1".parse().unwrap(),
+///     "192.0.2.2".parse().unwrap(),
+///     "2001:db8::1".parse().unwrap(),
+/// ]);
+/// assert_eq!(sorted[0], "2001:db8::1".parse::<std::net::IpAddr>().unwrap());
+/// assert_eq!(sorted[1], "192.0.2.1".parse::<std::net::IpAddr>().unwrap());
+/// ```
+pub fn sort_addresses(mut addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+    addresses.sort_by_key(|&address| std::cmp::Reverse(precedence(address)));
+
+    let Some(first_is_ipv6) = addresses.first().map(IpAddr::is_ipv6) else {
+        return addresses;
+    };
+    let len = addresses.len();
+    let (preferred, other): (Vec<IpAddr>, Vec<IpAddr>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_ipv6);
+
+    let mut sorted = Vec::with_capacity(len);
+    let mut other = other.into_iter();
+    for address in preferred {
+        sorted.push(address);
+        sorted.extend(other.next());
+    }
+    sorted.extend(other);
+    sorted
+}
+
+/// Precedence of `address` in the default policy table
+/// https://datatracker.ietf.org/doc/html/rfc6724#section-2.1
+fn precedence(address: IpAddr) -> u8 {
+    let ipv6 = match address {
+        // as IPv4-mapped address
+        IpAddr::V4(_) => return 35,
+        IpAddr::V6(ipv6) => ipv6,
+    };
+    let [first, second, ..] = ipv6.segments();
+    match () {
+        _ if ipv6 == Ipv6Addr::LOCALHOST => 50,
+        _ if ipv6.to_ipv4_mapped().is_some() => 35,
+        // 6to4
+        _ if first == 0x2002 => 30,
+        // Teredo
+        _ if first == 0x2001 && second == 0 => 5,
+        // unique local
+        _ if first & 0xfe00 == 0xfc00 => 3,
+        // IPv4-compatible, site-local and 6bone
+        _ if ipv6.segments()[..6] == [0; 6] || first & 0xffc0 == 0xfec0 || first == 0x3ffe => 1,
+        _ => 40,
+    }
+}
+
+/// How many CNAME records [`resolve_with_cname_chasing`] follows by default
+pub const DEFAULT_MAX_CNAME_HOPS: usize = 8;
+
+/// Records for a name, after following all CNAME records in the way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CnameResolution {
+    /// The CNAME records that were followed, starting with the one for the queried name
+    pub chain: Vec<Answer>,
+    /// Records of the queried type for the end of the chain
+    pub answers: Vec<Answer>,
+}
+
+impl CnameResolution {
+    /// The name the records in [`CnameResolution::answers`] belong to
+    pub fn canonical_name(&self) -> Option<&DnsName> {
+        match self.chain.last() {
+            Some(Answer::CNAME { cname, .. }) => Some(cname),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves records of `record_type` for `domain` like [`resolve_record`], but follows up to
+/// `max_hops` CNAME records. Parts of the chain missing from a response are queried separately.
+/// All names are taken as absolute, so no search domains are tried.
+pub fn resolve_with_cname_chasing(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+    max_hops: usize,
+) -> Result<CnameResolution, ResolveError> {
+    let mut resolution = CnameResolution {
+        chain: Vec::new(),
+        answers: Vec::new(),
+    };
+    let mut name = DnsName::from_utf8(domain)?;
+    loop {
+        let answers = resolve_name(name.clone(), record_type, upstreams, opts)?.answers;
+        match follow_cnames(&mut resolution, &name, answers, record_type, max_hops)? {
+            Some(next) => name = next,
+            None => return Ok(resolution),
+        }
+    }
+}
+
+/// Asynchronously resolves records while following CNAME records, see
+/// [`resolve_with_cname_chasing`]
+pub async fn resolve_with_cname_chasing_async(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+    max_hops: usize,
+) -> Result<CnameResolution, ResolveError> {
+    let mut resolution = CnameResolution {
+        chain: Vec::new(),
+        answers: Vec::new(),
+    };
+    let mut name = DnsName::from_utf8(domain)?;
+    loop {
+        let answers = resolve_name_async(name.clone(), record_type, upstreams, opts)
+            .await?
+            .answers;
+        match follow_cnames(&mut resolution, &name, answers, record_type, max_hops)? {
+            Some(next) => name = next,
+            None => return Ok(resolution),
+        }
+    }
+}
+
+/// Follows the CNAME records in the `answers` to a query for `name`, adding them to the chain of
+/// `resolution`. Returns the name to query next if the chain ends without records of
+/// `record_type`, otherwise the records are added to `resolution`.
+fn follow_cnames(
+    resolution: &mut CnameResolution,
+    name: &DnsName,
+    mut answers: Vec<Answer>,
+    record_type: RecordType,
+    max_hops: usize,
+) -> Result<Option<DnsName>, ResolveError> {
+    if record_type == RecordType::CNAME {
+        resolution.answers = answers;
+        return Ok(None);
+    }
+
+    let mut current = name.clone();
+    let mut followed = false;
+    while let Some(position) = answers
+        .iter()
+        .position(|answer| matches!(answer, Answer::CNAME { meta, .. } if meta.name == current))
+    {
+        let cname = answers.remove(position);
+        let Answer::CNAME { cname: target, .. } = &cname else {
+            unreachable!("only CNAME records are matched");
+        };
+
+        let seen = resolution
+            .chain
+            .iter()
+            .any(|answer| matches!(answer, Answer::CNAME { meta, .. } if meta.name == *target));
+        if seen || *target == *name {
+            return Err(ResolveError::CnameLoop(target.clone()));
+        }
+        if resolution.chain.len() == max_hops {
+            return Err(ResolveError::TooManyCnames(name.clone()));
+        }
+        current = target.clone();
+        resolution.chain.push(cname);
+        followed = true;
+    }
+
+    let records: Vec<_> = answers
+        .into_iter()
+        .filter(|answer| answer.meta().r#type == record_type && answer.meta().name == current)
+        .collect();
+    if records.is_empty() && followed {
+        return Ok(Some(current));
+    }
+    resolution.answers = records;
+    Ok(None)
+}
+
+/// Sends the raw `query` to `dns` and waits for the raw response. Over UDP, the query is sent
+/// again for every attempt that timed out, other transports leave that to TCP. If `dns` is an
+/// `https://` URL, the query is sent with DNS over HTTPS regardless of `opts.transport`, and
+/// likewise with DNS over QUIC for `quic://` URLs. Only a [`Transport::Custom`] takes precedence.
+///
+/// Responses that don't repeat the question of `query` are rejected.
+pub fn resolve_query(
+    query: &[u8],
+    dns: &str,
+    socket: &UdpSocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let response = send_query(query, dns, socket, opts)?;
+    ensure_question(query, &response)?;
+    Ok(response)
+}
+
+fn send_query(
+    query: &[u8],
+    dns: &str,
+    socket: &UdpSocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    if let Transport::Custom(transport) = &opts.transport {
+        return Ok(transport.exchange(query)?);
+    }
+    if dns.starts_with("https://") {
+        return Ok(DohTransport::shared(dns, opts.doh_method())?.exchange(query, opts.max_timeout)?);
+    }
+    if dns.starts_with("quic://") {
+        #[cfg(feature = "doq")]
+        return Ok(DoqTransport::shared(dns)?.exchange(query, opts.max_timeout)?);
+        #[cfg(not(feature = "doq"))]
+        return Err(ResolveError::DoqDisabled);
+    }
+    if let Transport::Tls { host, spki_pin } = &opts.transport {
+        return Ok(DotTransport::shared(dns, host, spki_pin.as_deref())?
+            .exchange(query, opts.max_timeout)?);
+    }
+    exchange_udp(query, dns, socket, opts)
+}
+
+/// Sends the raw `query` to `dns` over UDP, retrying and falling back to TCP as configured
+pub(crate) fn exchange_udp(
+    query: &[u8],
+    dns: &str,
+    socket: &UdpSocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let upstream = dns
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| ResolveError::UnresolvableUpstream(dns.to_string()))?;
+    // a socket of the other address family cannot reach the upstream at all
+    let own_socket;
+    let socket = if socket.local_addr()?.is_ipv4() == upstream.is_ipv4() {
+        socket
+    } else {
+        own_socket = bind_query_socket_for(upstream, opts)?;
+        &own_socket
+    };
+    let mut buffer = vec![0; opts.max_response_size(query)];
+    let mut sent_ids = Vec::new();
+    for attempt in 0..=opts.retries {
+        let attempt_query = attempt_query(query, attempt);
+        sent_ids.push(message_id(&attempt_query));
+        if let Err(e) = socket.send_to(&attempt_query, upstream) {
+            tracing::warn!(upstream = dns, error = %e, "Failed to send request");
+            return Err(e.into());
+        }
+
+        let deadline = Instant::now() + opts.attempt_timeout(attempt);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            match socket.recv_from(&mut buffer) {
+                Ok((len, source)) => {
+                    // anyone can send datagrams to our port, so keep waiting for the real response
+                    let response = &buffer[..len];
+                    if !is_same_address(source, upstream)
+                        || !sent_ids.contains(&message_id(response))
+                        || !answers_question(query, response)
+                    {
+                        continue;
+                    }
+                    let mut response = response.to_vec();
+                    restore_id(query, &mut response);
+                    if opts.tcp_fallback && is_truncated(&response) {
+                        return resolve_query_tcp(query, dns, opts);
+                    }
+                    return Ok(response);
+                }
+                // depending on the platform, a read timeout is reported as either of those
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => {
+                    tracing::warn!(upstream = dns, error = %e, "Failed to receive response");
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    Err(timed_out(dns, opts))
+}
+
+/// Asynchronously sends the raw `query` to `dns` and waits for the raw response, see
+/// [`resolve_query`]
+pub async fn resolve_query_async(
+    query: &[u8],
+    dns: &str,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let response = send_query_async(query, dns, socket, opts).await?;
+    ensure_question(query, &response)?;
+    Ok(response)
+}
+
+async fn send_query_async(
+    query: &[u8],
+    dns: &str,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    if let Transport::Custom(transport) = &opts.transport {
+        return Ok(transport.exchange_async(query).await?);
+    }
+    if dns.starts_with("https://") {
+        return Ok(DohTransport::shared(dns, opts.doh_method())?
+            .exchange_async(query, opts.max_timeout)
+            .await?);
+    }
+    if dns.starts_with("quic://") {
+        #[cfg(feature = "doq")]
+        return Ok(DoqTransport::shared(dns)?
+            .exchange_async(query, opts.max_timeout)
+            .await?);
+        #[cfg(not(feature = "doq"))]
+        return Err(ResolveError::DoqDisabled);
+    }
+    if let Transport::Tls { host, spki_pin } = &opts.transport {
+        return Ok(DotTransport::shared(dns, host, spki_pin.as_deref())?
+            .exchange_async(query, opts.max_timeout)
+            .await?);
+    }
+    exchange_udp_async(query, dns, socket, opts).await
+}
+
+/// Asynchronously sends the raw `query` to `dns` over UDP, see [`exchange_udp`]
+pub(crate) async fn exchange_udp_async(
+    query: &[u8],
+    dns: &str,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let upstream = tokio::net::lookup_host(dns)
+        .await?
+        .next()
+        .ok_or_else(|| ResolveError::UnresolvableUpstream(dns.to_string()))?;
+    if socket.local_addr()?.is_ipv4() != upstream.is_ipv4() {
+        let socket = bind_query_socket_for_async(upstream, opts).await?;
+        return exchange_udp_on_async(query, dns, upstream, &socket, opts).await;
+    }
+    exchange_udp_on_async(query, dns, upstream, socket, opts).await
+}
+
+/// Sends the raw `query` to `upstream`, whose address family `socket` is of
+async fn exchange_udp_on_async(
+    query: &[u8],
+    dns: &str,
+    upstream: SocketAddr,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let mut buffer = vec![0; opts.max_response_size(query)];
+    let mut sent_ids = Vec::new();
+    for attempt in 0..=opts.retries {
+        let attempt_query = attempt_query(query, attempt);
+        sent_ids.push(message_id(&attempt_query));
+        if let Err(e) = socket.send_to(&attempt_query, upstream).await {
+            tracing::warn!(upstream = dns, error = %e, "Failed to send request");
+            return Err(e.into());
+        }
+
+        let deadline = tokio::time::Instant::now() + opts.attempt_timeout(attempt);
+        loop {
+            match tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+                Ok(Ok((len, source))) => {
+                    let response = &buffer[..len];
+                    if !is_same_address(source, upstream)
+                        || !sent_ids.contains(&message_id(response))
+                        || !answers_question(query, response)
+                    {
+                        continue;
+                    }
+                    let mut response = response.to_vec();
+                    restore_id(query, &mut response);
+                    if opts.tcp_fallback && is_truncated(&response) {
+                        return resolve_query_tcp_async(query, dns, opts).await;
+                    }
+                    return Ok(response);
+                }
+                Ok(Err(e)) => {
+                    tracing::warn!(upstream = dns, error = %e, "Failed to receive response");
+                    return Err(e.into());
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Err(timed_out(dns, opts))
+}
+
+/// Sends the raw `query` to `dns` over a new TCP connection and waits for the raw response,
+/// giving up after `opts.max_timeout` for connecting as well as for the response
+pub fn resolve_query_tcp(
+    query: &[u8],
+    dns: &str,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let address = dns
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| ResolveError::UnresolvableUpstream(dns.to_string()))?;
+    let mut stream = TcpStream::connect_timeout(&address, opts.max_timeout)?;
+    stream.set_read_timeout(Some(opts.max_timeout))?;
+    stream.set_write_timeout(Some(opts.max_timeout))?;
+
+    write_tcp_message(&mut stream, query)?;
+    Ok(read_tcp_message(&mut stream)?)
+}
+
+/// Asynchronously sends the raw `query` to `dns` over a new TCP connection, see
+/// [`resolve_query_tcp`]
+pub async fn resolve_query_tcp_async(
+    query: &[u8],
+    dns: &str,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let exchange = async {
+        let mut stream = tokio::net::TcpStream::connect(dns).await?;
+        write_tcp_message_async(&mut stream, query).await?;
+        read_tcp_message_async(&mut stream).await
+    };
+    match tokio::time::timeout(opts.max_timeout, exchange).await {
+        Ok(response) => Ok(response?),
+        Err(_) => Err(ResolveError::Timeout(format!(
+            "no response from {dns} over TCP"
+        ))),
+    }
+}
+
+fn is_truncated(response: &[u8]) -> bool {
+    DnsParser::new(response)
+        .parse_header()
+        .is_ok_and(|header| header.flags.truncation)
+}
+
+fn timed_out(dns: &str, opts: &ResolveOptions) -> ResolveError {
+    ResolveError::Timeout(format!(
+        "no response from {dns} after {} attempts",
+        opts.retries + 1
+    ))
+}
+
+/// Synchronously resolves INternet A records for `domain` using the DNS server `dns`. The query
+/// gets a random ID unless `id` is given, and the returned response carries the ID either way.
 pub fn resolve_domain(
     domain: &str,
     dns: &str,
     id: Option<u16>,
     socket: Option<UdpSocket>,
-) -> Result<(Vec<Answer>, [u8; 512]), Box<dyn std::error::Error + Send + Sync>> {
-    let socket = socket.unwrap_or_else(|| UdpSocket::bind(("0.0.0.0", 0)).unwrap());
+) -> Result<(Vec<Answer>, [u8; 512]), ResolveError> {
+    let opts = ResolveOptions {
+        id,
+        // the response has to fit into 512 bytes
+        edns: None,
+        ..ResolveOptions::default()
+    };
+    resolve_domain_with(domain, dns, socket, &opts)
+}
 
-    let request = generate_request(domain, id);
-    if let Err(e) = socket.send_to(&request, dns) {
-        println!("Failed to send request for {domain} to {dns:?}: {e:?}");
-        return Err(e.into());
-    }
+/// Resolves INternet A records for `domain` like [`resolve_domain`], with the settings of
+/// `opts` and answering from its cache while the TTLs last
+pub fn resolve_domain_with(
+    domain: &str,
+    dns: &str,
+    socket: Option<UdpSocket>,
+    opts: &ResolveOptions,
+) -> Result<(Vec<Answer>, [u8; 512]), ResolveError> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => bind_query_socket(opts)?,
+    };
 
-    let mut response = [0; 512];
-    let (_, _) = socket.recv_from(&mut response).map_err(|e| {
-        println!("Failed to receive response for {domain} from {dns:?}: {e:?}");
-        e
-    })?;
+    let request = generate_request(&DnsName::from_utf8(domain)?, opts.id);
+    let response = match local_response(&request, opts) {
+        Some(response) => response,
+        None => match resolve_query(&request, dns, &socket, opts) {
+            Ok(response) => {
+                cache_response(&response, opts);
+                response
+            }
+            Err(e) => stale_response(&request, opts).ok_or(e)?,
+        },
+    };
+    let response = to_packet(&response);
 
     let answers = DnsParser::new(&response).parse_answers()?;
     Ok((answers, response))
 }
 
-#[allow(unused)]
-async fn resolve_domain_async(
+/// Asynchronously resolves INternet A records for `domain` using the DNS server `dns`, see
+/// [`resolve_domain`]
+pub async fn resolve_domain_async(
     domain: &str,
     dns: &str,
     id: Option<u16>,
-    socket: &tokio::net::UdpSocket,
-) -> Result<(Vec<Answer>, [u8; 512]), Box<dyn std::error::Error + Send + Sync>> {
-    let request = generate_request(domain, id);
-    if let Err(e) = socket.send_to(&request, dns).await {
-        println!("Failed to send request for {domain} to {dns:?}: {e:?}");
-        return Err(e.into());
-    }
+    socket: Option<tokio::net::UdpSocket>,
+) -> Result<(Vec<Answer>, [u8; 512]), ResolveError> {
+    let opts = ResolveOptions {
+        id,
+        // the response has to fit into 512 bytes
+        edns: None,
+        ..ResolveOptions::default()
+    };
+    resolve_domain_with_async(domain, dns, socket, &opts).await
+}
 
-    let mut response = [0; 512];
-    let (_, _) = socket.recv_from(&mut response).await.map_err(|e| {
-        println!("Failed to receive response for {domain} from {dns:?}: {e:?}");
-        e
-    })?;
+/// Asynchronously resolves INternet A records for `domain`, see [`resolve_domain_with`]
+pub async fn resolve_domain_with_async(
+    domain: &str,
+    dns: &str,
+    socket: Option<tokio::net::UdpSocket>,
+    opts: &ResolveOptions,
+) -> Result<(Vec<Answer>, [u8; 512]), ResolveError> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => bind_query_socket_async(opts).await?,
+    };
+
+    let request = generate_request(&DnsName::from_utf8(domain)?, opts.id);
+    let response = match local_response(&request, opts) {
+        Some(response) => response,
+        None => match resolve_query_async(&request, dns, &socket, opts).await {
+            Ok(response) => {
+                cache_response(&response, opts);
+                response
+            }
+            Err(e) => stale_response(&request, opts).ok_or(e)?,
+        },
+    };
+    let response = to_packet(&response);
 
     let answers = DnsParser::new(&response).parse_answers()?;
     Ok((answers, response))
 }
 
-/// Asynchronously send the incoming raw DNS packet to the relay DNS server and
+/// Asynchronously send the incoming raw DNS packet to the relay DNS servers and
 /// pipes the response back to the originating socket.
 pub async fn relay_query_async(
     original_query: &[u8; 512],
-    upstream_dns: &str,
-    socket: &tokio::net::UdpSocket,
-) -> Result<[u8; 512], Box<dyn std::error::Error + Send + Sync>> {
-    if let Err(e) = socket.send_to(original_query, "8.8.8.8:53").await {
-        println!("Failed to send request to {upstream_dns:?}: {e:?}");
-        return Err(e.into());
+    upstreams: &UpstreamPool,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<[u8; 512], ResolveError> {
+    let response = relay_message_async(original_query, upstreams, socket, opts).await?;
+    Ok(to_packet(&response))
+}
+
+/// Relays the raw `query` like [`relay_query_async`], but returns the response at its full
+/// length instead of in a UDP sized buffer, eg. for clients connected over TCP
+pub async fn relay_message_async(
+    original_query: &[u8],
+    upstreams: &UpstreamPool,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<Vec<u8>, ResolveError> {
+    let relayed = relay_exchange_async(original_query, upstreams, socket, opts).await?;
+    Ok(relayed.response)
+}
+
+/// Where the response to a relayed query came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseSource {
+    Zone,
+    Hosts,
+    Cache,
+    Upstream,
+    /// Expired records from the cache, as no upstream answered
+    Stale,
+}
+
+/// A response relayed by [`relay_exchange_async`], and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relayed {
+    pub response: Vec<u8>,
+    pub source: ResponseSource,
+    /// The upstream that answered and its round trip time, if the response came from one
+    pub upstream: Option<(String, Duration)>,
+}
+
+/// Relays the raw `query` like [`relay_message_async`], and tells where the response came
+/// from, eg. to log cache hits
+pub async fn relay_exchange_async(
+    original_query: &[u8],
+    upstreams: &UpstreamPool,
+    socket: &impl QuerySocket,
+    opts: &ResolveOptions,
+) -> Result<Relayed, ResolveError> {
+    if let Some((response, source)) = local_response_with_source(original_query, opts) {
+        let upstream = None;
+        return Ok(Relayed {
+            response,
+            source,
+            upstream,
+        });
     }
 
-    let mut response = [0; 512];
-    let (_, _) = socket.recv_from(&mut response).await.map_err(|e| {
-        println!("Failed to receive response from {upstream_dns:?}: {e:?}");
-        e
-    })?;
+    // the ID of the client might be predictable, so upstream gets a random one
+    let query = with_random_id(original_query);
+    let exchanged = match upstreams.exchange_async(&query, socket, opts).await {
+        Ok(exchanged) => exchanged,
+        Err(e) => {
+            let response = stale_response(original_query, opts).ok_or(e)?;
+            let (source, upstream) = (ResponseSource::Stale, None);
+            return Ok(Relayed {
+                response,
+                source,
+                upstream,
+            });
+        }
+    };
+    let mut response = exchanged.response;
+    restore_id(original_query, &mut response);
+    cache_response(&response, opts);
+    Ok(Relayed {
+        response,
+        source: ResponseSource::Upstream,
+        upstream: Some((exchanged.upstream, exchanged.rtt)),
+    })
+}
 
-    Ok(response)
+/// Resolves the raw `query` again, bypassing the cache of `opts` but storing the response in it.
+/// Meant to refresh entries in the background once [`DnsCache::take_prefetch`] asks for it.
+pub fn prefetch(
+    query: &[u8],
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<(), ResolveError> {
+    let query = with_random_id(query);
+    let socket = bind_query_socket(opts)?;
+    let response = upstreams.resolve_query(&query, &socket, opts)?;
+    cache_response(&response, opts);
+    Ok(())
+}
+
+/// Asynchronously resolves the raw `query` again to refresh the cache, see [`prefetch`]
+pub async fn prefetch_async(
+    query: &[u8],
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<(), ResolveError> {
+    let query = with_random_id(query);
+    let socket = bind_query_socket_async(opts).await?;
+    let response = upstreams.resolve_query_async(&query, &socket, opts).await?;
+    cache_response(&response, opts);
+    Ok(())
+}
+
+/// Copies `query` with a random ID
+fn with_random_id(query: &[u8]) -> Vec<u8> {
+    let mut query = query.to_vec();
+    if query.len() >= 2 {
+        query[0..2].copy_from_slice(&random_id().to_be_bytes());
+    }
+    query
+}
+
+/// Answers `query` from the local zones, the hosts file or else the cache, without asking any
+/// upstream
+fn local_response(query: &[u8], opts: &ResolveOptions) -> Option<Vec<u8>> {
+    Some(local_response_with_source(query, opts)?.0)
+}
+
+fn local_response_with_source(
+    query: &[u8],
+    opts: &ResolveOptions,
+) -> Option<(Vec<u8>, ResponseSource)> {
+    if let Some(response) = opts.zones.as_ref().and_then(|zones| zones.respond(query)) {
+        return Some((response, ResponseSource::Zone));
+    }
+    if let Some(response) = opts.hosts.as_ref().and_then(|hosts| hosts.respond(query)) {
+        return Some((response, ResponseSource::Hosts));
+    }
+    let response = opts.cache.as_ref()?.respond(query)?;
+    Some((response, ResponseSource::Cache))
+}
+
+/// Answers `query` with stale records from the cache, once no upstream server answered it
+/// https://datatracker.ietf.org/doc/html/rfc8767
+fn stale_response(query: &[u8], opts: &ResolveOptions) -> Option<Vec<u8>> {
+    opts.cache.as_ref()?.respond_stale(query)
+}
+
+fn cache_response(response: &[u8], opts: &ResolveOptions) {
+    if let Some(cache) = &opts.cache {
+        cache.store_response(response);
+    }
+}
+
+/// Fits the raw `response` into the UDP payload size the client of `query` advertised, 512 bytes
+/// without EDNS. Responses too large for it are cut down to their question with the TC bit set,
+/// so the client retries over TCP.
+/// https://datatracker.ietf.org/doc/html/rfc6891#section-7
+pub fn fit_udp_response(query: &[u8], response: Vec<u8>) -> Vec<u8> {
+    let payload_size = DnsParser::new(query)
+        .parse_payload_size()
+        .ok()
+        .flatten()
+        .map_or(0, usize::from);
+    let max_size = payload_size.max(MAX_UDP_MESSAGE_SIZE);
+    if response.len() <= max_size {
+        return response;
+    }
+    let header = DnsParser::new(&response).parse_header();
+    let questions = DnsParser::new(&response).get_relay_information();
+    let (Ok(mut header), Ok((_, questions))) = (header, questions) else {
+        // only the ID is of use to the client then
+        let mut truncated = vec![0; 12];
+        truncated[..2].copy_from_slice(&response[..2.min(response.len())]);
+        truncated[2] = 0x82;
+        return truncated;
+    };
+    header.flags.truncation = true;
+    write_response(&header, &questions, &[], max_size)
+}
+
+/// Copies a response of at most 512 bytes into a fixed size packet buffer
+fn to_packet(response: &[u8]) -> [u8; 512] {
+    let mut packet = [0; 512];
+    let len = response.len().min(packet.len());
+    packet[..len].copy_from_slice(&response[..len]);
+    packet
 }
 
 pub async fn stub_response_with_delay(
     id: Option<u16>,
     delay: Duration,
-) -> Result<(Vec<Answer>, [u8; 512]), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(Vec<Answer>, [u8; 512]), ResolveError> {
     let response = generate_nx_response(id.unwrap_or(1337)).unwrap();
     tokio::time::sleep(delay).await;
     // Still parse answers, to keep the same API as the actual resolve function
@@ -85,34 +1425,48 @@ pub async fn stub_response_with_delay(
     Ok((answers, response))
 }
 
-/// Generates a recursive DNS query for INternet A records
-pub(crate) fn generate_request(domain: &str, id: Option<u16>) -> Vec<u8> {
-    const DEFAULT_ID: [u8; 2] = [(1337u16 >> 4) as u8, (1337 & 0xFF) as u8];
-    let id = id
-        .map(|n| [(n >> 8) as u8, (n & 0xFF) as u8])
-        .unwrap_or(DEFAULT_ID);
-    const QTYPE: [u8; 2] = [0x00, 0x01];
-    const QCLASS: [u8; 2] = [0x00, 0x01];
-
-    let request_header: [u8; 12] = [
-        id[0], id[1], // identification
-        0x01, 0x00, // flags
-        0x00, 0x01, // question section
-        0x00, 0x00, // answer section
-        0x00, 0x00, // authority section
-        0x00, 0x00, // additional section
-    ];
-    let mut request = Vec::with_capacity(16 + domain.len());
-    request.extend(request_header);
-    request.extend(encode_domain_name(domain));
-    request.extend(QTYPE);
-    request.extend(QCLASS);
-    request
+/// Generates a recursive DNS query for INternet A records, with a random ID unless one is given
+pub(crate) fn generate_request(domain: &DnsName, id: Option<u16>) -> Vec<u8> {
+    QueryBuilder::new(domain.clone())
+        .id(id.unwrap_or_else(random_id))
+        .build()
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{resolve_domain, Answer};
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, UdpSocket},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        cache::{CacheKey, DnsCache, STALE_ANSWER_TTL},
+        error::ResolveError,
+        hosts::HostsFile,
+        parse::parser::DnsParser,
+        protocol::{
+            answer::AnswerMeta,
+            class::Class,
+            header::{Flags, Header},
+            packet::Packet,
+            query::QueryBuilder,
+            record_type::RecordType,
+            response_code::ResponseCode,
+        },
+        transport::{DnsTransport, Transport},
+    };
+
+    use super::{
+        bind_query_socket, bind_query_socket_for, fit_udp_response, generate_request, prefetch,
+        prefetch_async, relay_exchange_async, relay_query_async, resolve_domain,
+        resolve_domain_async, resolve_domain_with, resolve_domain_with_async, resolve_ip,
+        resolve_ip_async, resolve_query, resolve_query_async, resolve_record, resolve_record_async,
+        resolve_response, resolve_response_async, resolve_txt, resolve_txt_async,
+        resolve_with_cname_chasing, resolve_with_cname_chasing_async, sort_addresses, Answer,
+        DnsName, ResolveOptions, ResponseSource, DEFAULT_MAX_CNAME_HOPS,
+    };
 
     const DNS_SERVERS: [&str; 1] = ["1.1.1.1:53"];
 
@@ -123,4 +1477,1080 @@ mod tests {
             assert!(matches!(answers.last(), Some(&Answer::A { ipv4: _, .. })));
         }
     }
+
+    /// Answers a single query with `answer`, which has to be of the queried type
+    fn mock_upstream(answer: Answer) -> (String, std::thread::JoinHandle<()>) {
+        mock_upstream_on("127.0.0.1:0", answer)
+    }
+
+    /// Answers a single query on `address` like [`mock_upstream`]
+    fn mock_upstream_on(address: &str, answer: Answer) -> (String, std::thread::JoinHandle<()>) {
+        let server = UdpSocket::bind(address).unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut query = [0; 512];
+            let (len, client) = server.recv_from(&mut query).unwrap();
+            let query = DnsParser::new(&query[..len]).parse_packet().unwrap();
+            assert_eq!(query.questions[0].r#type, answer.meta().r#type);
+
+            let response = Packet {
+                header: Header {
+                    flags: Flags {
+                        query: false,
+                        ..query.header.flags
+                    },
+                    ..query.header
+                },
+                questions: query.questions,
+                answers: vec![answer],
+                additionals: query.additionals,
+                ..Packet::default()
+            };
+            server.send_to(&response.to_bytes(), client).unwrap();
+        });
+        (address, handle)
+    }
+
+    fn mx_answer() -> Answer {
+        Answer::MX {
+            meta: AnswerMeta {
+                name: "example.com".parse().unwrap(),
+                r#type: RecordType::MX,
+                class: Class::IN,
+                ttl: 60,
+                len: 0,
+            },
+            preference: 10,
+            exchange: "mail.example.com".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_bind_query_socket_for() {
+        let opts = ResolveOptions::default();
+        let ipv4 = bind_query_socket_for("192.0.2.1:53".parse().unwrap(), &opts).unwrap();
+        assert!(ipv4.local_addr().unwrap().is_ipv4());
+        let ipv6 = bind_query_socket_for("[2001:db8::1]:53".parse().unwrap(), &opts).unwrap();
+        assert!(ipv6.local_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn test_resolve_record_ipv6_upstream() {
+        let (address, handle) = mock_upstream_on("[::1]:0", mx_answer());
+        let answers = resolve_record(
+            "example.com",
+            RecordType::MX,
+            &address.as_str().into(),
+            &ResolveOptions::default(),
+        )
+        .unwrap();
+        handle.join().unwrap();
+        assert!(matches!(
+            &answers[..],
+            [Answer::MX { exchange, .. }] if exchange == "mail.example.com"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_record_ipv6_upstream_async() {
+        let (address, handle) = mock_upstream_on("[::1]:0", mx_answer());
+        let answers = resolve_record_async(
+            "example.com",
+            RecordType::MX,
+            &address.as_str().into(),
+            &ResolveOptions::default(),
+        )
+        .await
+        .unwrap();
+        handle.join().unwrap();
+        assert!(matches!(
+            &answers[..],
+            [Answer::MX { exchange, .. }] if exchange == "mail.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_record_mx() {
+        let (address, handle) = mock_upstream(mx_answer());
+        let opts = ResolveOptions {
+            id: Some(4711),
+            ..ResolveOptions::default()
+        };
+        let answers = resolve_record(
+            "example.com",
+            RecordType::MX,
+            &address.as_str().into(),
+            &opts,
+        )
+        .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(answers.len(), 1);
+        assert!(matches!(
+            &answers[0],
+            Answer::MX { preference: 10, exchange, .. } if exchange == "mail.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_record_edns() {
+        // does not fit into a plain DNS message of 512 bytes
+        let txt = Answer::TXT {
+            meta: AnswerMeta {
+                name: "example.com".parse().unwrap(),
+                r#type: RecordType::TXT,
+                class: Class::IN,
+                ttl: 60,
+                len: 0,
+            },
+            txt: vec![vec![b'x'; 250]; 3],
+        };
+        let (address, handle) = mock_upstream(txt.clone());
+        let answers = resolve_record(
+            "example.com",
+            RecordType::TXT,
+            &address.as_str().into(),
+            &ResolveOptions::default(),
+        )
+        .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(answers.len(), 1);
+        assert!(matches!(&answers[0], Answer::TXT { txt, .. } if txt.len() == 3));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_record_async() {
+        let (address, handle) = mock_upstream(mx_answer());
+        let answers = resolve_record_async(
+            "example.com",
+            RecordType::MX,
+            &address.as_str().into(),
+            &ResolveOptions::default(),
+        )
+        .await
+        .unwrap();
+        handle.join().unwrap();
+        assert!(matches!(&answers[..], [Answer::MX { preference: 10, .. }]));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_domain_async() {
+        let a = Answer::A {
+            meta: AnswerMeta {
+                name: "example.com".parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+                ttl: 60,
+                len: 0,
+            },
+            ipv4: Ipv4Addr::new(192, 0, 2, 1),
+        };
+        let (address, handle) = mock_upstream(a);
+        let (answers, response) = resolve_domain_async("example.com", &address, Some(42), None)
+            .await
+            .unwrap();
+        handle.join().unwrap();
+
+        assert!(
+            matches!(&answers[..], [Answer::A { ipv4, .. }] if ipv4.octets() == [192, 0, 2, 1])
+        );
+        assert_eq!(response[0..2], 42u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_resolve_query_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let opts = ResolveOptions {
+            timeout: Duration::from_millis(50),
+            retries: 1,
+            ..ResolveOptions::default()
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let error = resolve_query(&[1, 2, 3], &address, &client, &opts).unwrap_err();
+        assert!(error.is_timeout(), "{error}");
+
+        // the query was sent once more after the first timeout
+        server.set_nonblocking(true).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(server.recv_from(&mut buf).unwrap().0, 3);
+        assert_eq!(server.recv_from(&mut buf).unwrap().0, 3);
+        assert!(server.recv_from(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_query_async_retries() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let mut buf = [0; 16];
+            // ignore the first attempt
+            server.recv_from(&mut buf).await.unwrap();
+            let (len, client) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&buf[..len], client).await.unwrap();
+        });
+
+        let opts = ResolveOptions {
+            timeout: Duration::from_millis(50),
+            retries: 1,
+            ..ResolveOptions::default()
+        };
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response = resolve_query_async(&[4, 5], &address, &client, &opts)
+            .await
+            .unwrap();
+        // the retry went out with a fresh ID, but the response carries the original one
+        assert_eq!(response, vec![4, 5]);
+        handle.await.unwrap();
+    }
+
+    #[test]
+    fn test_resolve_query_discards_mismatched_ids() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 16];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&[0xDE, 0xAD, 6], client).unwrap();
+            server.send_to(&buf[..len], client).unwrap();
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let opts = ResolveOptions {
+            retries: 0,
+            ..ResolveOptions::default()
+        };
+        let response = resolve_query(&[4, 5, 6], &address, &client, &opts).unwrap();
+        assert_eq!(response, vec![4, 5, 6]);
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_query_async_discards_mismatched_ids() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            let (_, client) = server.recv_from(&mut buf).await.unwrap();
+            // only ever answers with the wrong ID
+            server.send_to(&[0xDE, 0xAD], client).await.unwrap();
+        });
+
+        let opts = ResolveOptions {
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..ResolveOptions::default()
+        };
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let error = resolve_query_async(&[4, 5], &address, &client, &opts)
+            .await
+            .unwrap_err();
+        assert!(error.is_timeout(), "{error}");
+    }
+
+    #[test]
+    fn test_resolve_query_discards_foreign_sources() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 16];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            // a response with the right ID, but from the wrong address
+            spoofer
+                .send_to(&[buf[0], buf[1], 0xBA, 0xD0], client)
+                .unwrap();
+            server.send_to(&buf[..len], client).unwrap();
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let opts = ResolveOptions {
+            retries: 0,
+            ..ResolveOptions::default()
+        };
+        let response = resolve_query(&[4, 5, 6], &address, &client, &opts).unwrap();
+        assert_eq!(response, vec![4, 5, 6]);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_attempt_timeout_backoff() {
+        let opts = ResolveOptions {
+            timeout: Duration::from_millis(500),
+            backoff: 3,
+            max_timeout: Duration::from_secs(3),
+            jitter: Duration::ZERO,
+            ..ResolveOptions::default()
+        };
+        assert_eq!(opts.attempt_timeout(0), Duration::from_millis(500));
+        assert_eq!(opts.attempt_timeout(1), Duration::from_millis(1500));
+        assert_eq!(opts.attempt_timeout(2), Duration::from_secs(3));
+        assert_eq!(opts.attempt_timeout(40), Duration::from_secs(3));
+
+        let opts = ResolveOptions {
+            jitter: Duration::from_millis(100),
+            ..opts
+        };
+        for attempt in 2..10 {
+            let timeout = opts.attempt_timeout(attempt);
+            assert!(timeout >= Duration::from_secs(3));
+            assert!(timeout <= Duration::from_millis(3100));
+        }
+    }
+
+    /// Serves `response` over TCP and a truncated version of it over UDP, on the same port
+    fn mock_truncating_upstream(response: Vec<u8>) -> String {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = udp.local_addr().unwrap();
+        let tcp = std::net::TcpListener::bind(address).unwrap();
+
+        let mut truncated = response[..12].to_vec();
+        truncated[2] |= 0x02;
+        std::thread::spawn(move || {
+            let mut query = [0; 512];
+            let (_, client) = udp.recv_from(&mut query).unwrap();
+            udp.send_to(&truncated, client).unwrap();
+        });
+        std::thread::spawn(move || {
+            let (mut stream, _) = tcp.accept().unwrap();
+            crate::tcp::read_tcp_message(&mut stream).unwrap();
+            crate::tcp::write_tcp_message(&mut stream, &response).unwrap();
+        });
+        address.to_string()
+    }
+
+    #[test]
+    fn test_tcp_fallback() {
+        let response = vec![0, 1, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xAB, 0xCD];
+        let address = mock_truncating_upstream(response.clone());
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let full = resolve_query(&[0, 1], &address, &client, &ResolveOptions::default()).unwrap();
+        assert_eq!(full, response);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_fallback_async() {
+        let response = vec![0, 1, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xAB, 0xCD];
+        let address = mock_truncating_upstream(response.clone());
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let full = resolve_query_async(&[0, 1], &address, &client, &ResolveOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(full, response);
+    }
+
+    #[test]
+    fn test_random_ids_and_ports() {
+        let ids: HashSet<_> = (0..16)
+            .map(|_| generate_request(&DnsName::root(), None)[0..2].to_vec())
+            .collect();
+        assert!(ids.len() > 1);
+
+        let opts = ResolveOptions::default();
+        let sockets: Vec<_> = (0..4).map(|_| bind_query_socket(&opts).unwrap()).collect();
+        for socket in sockets {
+            assert!(socket.local_addr().unwrap().port() >= 1024);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_randomizes_id() {
+        let transport = Arc::new(RecordingTransport::default());
+        let opts = ResolveOptions::default().transport(Transport::Custom(transport.clone()));
+        let mut query = [0; 512];
+        let request = generate_request(&"example.com".parse().unwrap(), Some(0x1234));
+        query[..request.len()].copy_from_slice(&request);
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response = relay_query_async(&query, &"127.0.0.1:9".into(), &client, &opts)
+            .await
+            .unwrap();
+        assert_eq!(response[0..2], [0x12, 0x34]);
+
+        let queries = transport.queries.lock().unwrap();
+        assert_eq!(queries[0][2..], query[2..]);
+    }
+
+    #[test]
+    fn test_fit_udp_response() {
+        let query = QueryBuilder::new("example.com".parse().unwrap())
+            .id(3)
+            .build();
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        assert_eq!(fit_udp_response(&query, response.clone()), response);
+
+        response.resize(600, 0);
+        let edns = QueryBuilder::new("example.com".parse().unwrap())
+            .edns_payload_size(1232)
+            .build();
+        assert_eq!(fit_udp_response(&edns, response.clone()), response);
+
+        let fitted = fit_udp_response(&query, response);
+        let parsed = DnsParser::new(&fitted).parse_packet().unwrap();
+        assert!(parsed.header.flags.truncation);
+        assert_eq!(parsed.header.request_id, 3);
+        assert_eq!(parsed.questions.len(), 1);
+        assert!(parsed.answers.is_empty());
+
+        assert_eq!(fit_udp_response(&query, vec![0; 513]).len(), 12);
+    }
+
+    #[test]
+    fn test_tcp_fallback_disabled() {
+        let response = vec![0, 1, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xAB, 0xCD];
+        let address = mock_truncating_upstream(response);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let opts = ResolveOptions {
+            tcp_fallback: false,
+            ..ResolveOptions::default()
+        };
+        let truncated = resolve_query(&[0, 1], &address, &client, &opts).unwrap();
+        assert_eq!(truncated.len(), 12);
+        assert_eq!(truncated[2] & 0x02, 0x02);
+    }
+
+    /// Answers every query with an empty response, recording the queries it got
+    #[derive(Debug, Default)]
+    struct RecordingTransport {
+        queries: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl DnsTransport for RecordingTransport {
+        fn exchange(
+            &self,
+            query: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            self.queries.lock().unwrap().push(query.to_vec());
+            let mut response = query.to_vec();
+            response[2] |= 0x80;
+            Ok(response)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_transport() {
+        let transport = Arc::new(RecordingTransport::default());
+        let opts = ResolveOptions::default().transport(Transport::Custom(transport.clone()));
+
+        // nothing listens at the upstream address, the custom transport is used instead
+        let answers = resolve_record("example.com", RecordType::A, &"127.0.0.1:9".into(), &opts);
+        assert!(answers.unwrap().is_empty());
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response = resolve_query_async(&[0, 1, 0, 0], "127.0.0.1:9", &client, &opts)
+            .await
+            .unwrap();
+        assert_eq!(response, [0, 1, 0x80, 0]);
+
+        let queries = transport.queries.lock().unwrap();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[1], [0, 1, 0, 0]);
+    }
+
+    /// Responds to queries by calling a function
+    #[derive(Debug)]
+    struct FnTransport(fn(&[u8]) -> Vec<u8>);
+
+    impl DnsTransport for FnTransport {
+        fn exchange(
+            &self,
+            query: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok((self.0)(query))
+        }
+    }
+
+    /// Responds to `query` without answers, but with the question asking for `name` instead
+    fn respond_for(query: &[u8], name: &str) -> Vec<u8> {
+        let mut packet = DnsParser::new(query).parse_packet().unwrap();
+        packet.header.flags.query = false;
+        packet.questions[0].domain_name = name.parse().unwrap();
+        packet.to_bytes()
+    }
+
+    #[test]
+    fn test_question_echo() {
+        let resolve = |respond| {
+            let opts = ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(respond))));
+            resolve_record("example.com", RecordType::A, &"127.0.0.1:9".into(), &opts)
+        };
+
+        assert!(resolve(|query| respond_for(query, "exAMPle.COM")).is_ok());
+        let error = resolve(|query| respond_for(query, "example.org")).unwrap_err();
+        assert!(matches!(error, ResolveError::QuestionMismatch), "{error}");
+    }
+
+    #[test]
+    fn test_resolve_query_discards_other_questions() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            let spoofed = respond_for(&buf[..len], "example.org");
+            server.send_to(&spoofed, client).unwrap();
+            let response = respond_for(&buf[..len], "example.com");
+            server.send_to(&response, client).unwrap();
+        });
+
+        let opts = ResolveOptions {
+            retries: 0,
+            ..ResolveOptions::default()
+        };
+        let answers = resolve_record(
+            "example.com",
+            RecordType::A,
+            &address.as_str().into(),
+            &opts,
+        );
+        assert!(answers.unwrap().is_empty());
+        handle.join().unwrap();
+    }
+
+    fn meta(name: &str, r#type: RecordType) -> AnswerMeta {
+        AnswerMeta {
+            name: name.parse().unwrap(),
+            r#type,
+            class: Class::IN,
+            ttl: 60,
+            len: 0,
+        }
+    }
+
+    fn cname(name: &str, target: &str) -> Answer {
+        Answer::CNAME {
+            meta: meta(name, RecordType::CNAME),
+            cname: target.parse().unwrap(),
+        }
+    }
+
+    /// Responds to `query` with `answers`
+    fn respond_with(query: &[u8], answers: Vec<Answer>) -> Vec<u8> {
+        let mut packet = DnsParser::new(query).parse_packet().unwrap();
+        packet.header.flags.query = false;
+        packet.answers = answers;
+        packet.to_bytes()
+    }
+
+    /// Serves a chain of CNAME records, which is only partially included in the first response
+    fn cname_chain(query: &[u8]) -> Vec<u8> {
+        let name = DnsParser::new(query).parse_packet().unwrap().questions[0]
+            .domain_name
+            .clone();
+        let answers = match name.as_str() {
+            "www.example.com" => vec![
+                cname("www.example.com", "cdn.example.net"),
+                cname("cdn.example.net", "edge.example.org"),
+            ],
+            "edge.example.org" => vec![Answer::A {
+                meta: meta("edge.example.org", RecordType::A),
+                ipv4: Ipv4Addr::new(192, 0, 2, 7),
+            }],
+            "loop.example.com" => vec![
+                cname("loop.example.com", "pool.example.com"),
+                cname("pool.example.com", "loop.example.com"),
+            ],
+            _ => vec![],
+        };
+        respond_with(query, answers)
+    }
+
+    #[tokio::test]
+    async fn test_cname_chasing() {
+        let opts = ResolveOptions::default()
+            .transport(Transport::Custom(Arc::new(FnTransport(cname_chain))));
+        let upstreams = "127.0.0.1:9".into();
+
+        let resolution = resolve_with_cname_chasing(
+            "www.example.com",
+            RecordType::A,
+            &upstreams,
+            &opts,
+            DEFAULT_MAX_CNAME_HOPS,
+        )
+        .unwrap();
+        assert_eq!(resolution.chain.len(), 2);
+        assert_eq!(resolution.canonical_name().unwrap(), "edge.example.org");
+        assert!(matches!(
+            &resolution.answers[..],
+            [Answer::A { ipv4, .. }] if ipv4.octets() == [192, 0, 2, 7]
+        ));
+
+        let resolution = resolve_with_cname_chasing_async(
+            "www.example.com",
+            RecordType::A,
+            &upstreams,
+            &opts,
+            DEFAULT_MAX_CNAME_HOPS,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resolution.answers.len(), 1);
+
+        // too many hops
+        let error =
+            resolve_with_cname_chasing("www.example.com", RecordType::A, &upstreams, &opts, 1);
+        assert!(error.is_err());
+        let error = resolve_with_cname_chasing(
+            "loop.example.com",
+            RecordType::A,
+            &upstreams,
+            &opts,
+            DEFAULT_MAX_CNAME_HOPS,
+        );
+        assert!(error.unwrap_err().to_string().contains("loop"));
+    }
+
+    fn cached_a(query: &[u8]) -> Vec<u8> {
+        let answer = Answer::A {
+            meta: meta("cached.example.com", RecordType::A),
+            ipv4: Ipv4Addr::new(192, 0, 2, 9),
+        };
+        respond_with(query, vec![answer])
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache = Arc::new(DnsCache::new());
+        let opts = ResolveOptions {
+            cache: Some(Arc::clone(&cache)),
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(cached_a))))
+        };
+        let upstreams = "127.0.0.1:9".into();
+        resolve_record("cached.example.com", RecordType::A, &upstreams, &opts).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // the upstream is not asked again
+        let opts = ResolveOptions {
+            cache: Some(cache),
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(|_| vec![]))))
+        };
+        let answers = resolve_record_async("cached.example.com", RecordType::A, &upstreams, &opts)
+            .await
+            .unwrap();
+        assert!(
+            matches!(&answers[..], [Answer::A { ipv4, .. }] if ipv4.octets() == [192, 0, 2, 9])
+        );
+
+        let mut query = [0; 512];
+        let request = generate_request(&"cached.example.com".parse().unwrap(), Some(0x4321));
+        query[..request.len()].copy_from_slice(&request);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let response = relay_query_async(&query, &upstreams, &client, &opts)
+            .await
+            .unwrap();
+        assert_eq!(response[0..2], [0x43, 0x21]);
+        assert_eq!(DnsParser::new(&response).parse_answers().unwrap(), answers);
+        let relayed = relay_exchange_async(&request, &upstreams, &client, &opts)
+            .await
+            .unwrap();
+        assert_eq!(relayed.source, ResponseSource::Cache);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_domain_cache() {
+        let cache = Arc::new(DnsCache::new());
+        let opts = ResolveOptions {
+            cache: Some(Arc::clone(&cache)),
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(cached_a))))
+        };
+        let (answers, _) =
+            resolve_domain_with("cached.example.com", "127.0.0.1:9", None, &opts).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // the second call is answered from the cache, the upstream is not asked again
+        let opts = ResolveOptions {
+            id: Some(0x4321),
+            cache: Some(cache),
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(|_| vec![]))))
+        };
+        let (cached, response) =
+            resolve_domain_with_async("cached.example.com", "127.0.0.1:9", None, &opts)
+                .await
+                .unwrap();
+        assert_eq!(response[0..2], [0x43, 0x21]);
+        assert_eq!(cached, answers);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let cache = Arc::new(DnsCache::new());
+        let opts = ResolveOptions {
+            cache: Some(Arc::clone(&cache)),
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(cached_a))))
+        };
+        let upstreams = "127.0.0.1:9".into();
+        let query = generate_request(&"cached.example.com".parse().unwrap(), None);
+        prefetch_async(&query, &upstreams, &opts).await.unwrap();
+        assert!(cache.respond(&query).is_some());
+
+        // the cache is refreshed even if it has answers
+        let upstreams = "127.0.0.1:9".into();
+        let opts = ResolveOptions {
+            cache: Some(cache),
+            retries: 0,
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(|_| vec![]))))
+        };
+        assert!(prefetch(&query, &upstreams, &opts).is_err());
+    }
+
+    /// Responds to `query` with `response_code` and an Extended DNS Error with `info_code`
+    fn respond_with_extended_error(query: &[u8], response_code: u16, info_code: u8) -> Vec<u8> {
+        let mut response = DnsParser::new(query).parse_packet().unwrap();
+        response.header.flags.query = false;
+        response.header.flags.response_code = response_code.into();
+        response.additionals = vec![Answer::Unknown {
+            meta: AnswerMeta {
+                name: DnsName::root(),
+                r#type: RecordType::OPT,
+                class: Class::Other(1232),
+                ttl: 0,
+                len: 6,
+            },
+            rdata: vec![0, 15, 0, 2, 0, info_code],
+        }];
+        response.to_bytes()
+    }
+
+    /// Fails with SERVFAIL because validating the answer failed
+    fn dnssec_bogus(query: &[u8]) -> Vec<u8> {
+        respond_with_extended_error(query, 2, 6)
+    }
+
+    /// Answers with NXDOMAIN because the name is on a blocklist
+    fn blocked(query: &[u8]) -> Vec<u8> {
+        respond_with_extended_error(query, 3, 15)
+    }
+
+    #[tokio::test]
+    async fn test_extended_dns_errors() {
+        let upstreams = "127.0.0.1:9".into();
+        let opts = ResolveOptions::default()
+            .transport(Transport::Custom(Arc::new(FnTransport(dnssec_bogus))));
+        let error = resolve_record("example.com", RecordType::A, &upstreams, &opts).unwrap_err();
+        let ResolveError::Response(error) = error else {
+            panic!("unexpected {error}");
+        };
+        assert_eq!(error.response_code, ResponseCode::ServFail);
+        assert!(error.is_dnssec_failure());
+        assert!(!error.is_filtered());
+        assert_eq!(
+            error.to_string(),
+            "upstream responded with ServFail: DNSSEC Bogus"
+        );
+
+        let opts =
+            ResolveOptions::default().transport(Transport::Custom(Arc::new(FnTransport(blocked))));
+        let error = resolve_record_async("example.com", RecordType::A, &upstreams, &opts)
+            .await
+            .unwrap_err();
+        let ResolveError::Response(error) = error else {
+            panic!("unexpected {error}");
+        };
+        assert_eq!(error.response_code, ResponseCode::NXDomain);
+        assert!(error.is_filtered());
+
+        // a plain NXDOMAIN is an answer
+        let nxdomain = |query: &[u8]| {
+            let mut response = respond_with(query, vec![]);
+            response[3] = (response[3] & 0xF0) | 3;
+            response
+        };
+        let opts =
+            ResolveOptions::default().transport(Transport::Custom(Arc::new(FnTransport(nxdomain))));
+        let answers = resolve_record("example.com", RecordType::A, &upstreams, &opts).unwrap();
+        assert!(answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale() {
+        let cache = Arc::new(DnsCache::new().serve_stale(Duration::from_secs(60)));
+        let answer = Answer::A {
+            meta: AnswerMeta {
+                ttl: 1,
+                ..meta("stale.example.com", RecordType::A)
+            },
+            ipv4: Ipv4Addr::new(192, 0, 2, 9),
+        };
+        cache.insert(
+            CacheKey {
+                name: "stale.example.com".parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+            },
+            vec![answer],
+        );
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        // the upstream is broken, so the expired answer is served
+        let opts = ResolveOptions {
+            cache: Some(Arc::clone(&cache)),
+            retries: 0,
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(|_| vec![]))))
+        };
+        let upstreams = "127.0.0.1:9".into();
+        let answers = resolve_record_async("stale.example.com", RecordType::A, &upstreams, &opts)
+            .await
+            .unwrap();
+        assert!(matches!(&answers[..], [answer] if answer.meta().ttl == STALE_ANSWER_TTL));
+
+        // but only while the upstream is down
+        let opts = ResolveOptions {
+            cache: Some(cache),
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(cached_a))))
+        };
+        let answers =
+            resolve_record("stale.example.com", RecordType::A, &upstreams, &opts).unwrap();
+        assert!(matches!(&answers[..], [answer] if answer.meta().ttl == 60));
+    }
+
+    #[test]
+    fn test_resolve_record_from_hosts() {
+        // the upstream fails every query, so only names from the hosts file resolve
+        let opts = ResolveOptions {
+            hosts: Some(Arc::new(HostsFile::parse("192.0.2.7 nas.home.arpa"))),
+            retries: 0,
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(|_| vec![]))))
+        };
+        let upstreams = "127.0.0.1:9".into();
+        let answers = resolve_record("nas.home.arpa", RecordType::A, &upstreams, &opts).unwrap();
+        assert!(
+            matches!(&answers[..], [Answer::A { ipv4, .. }] if *ipv4 == Ipv4Addr::new(192, 0, 2, 7))
+        );
+        assert!(
+            resolve_record("nas.home.arpa", RecordType::AAAA, &upstreams, &opts)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(resolve_record("example.com", RecordType::A, &upstreams, &opts).is_err());
+    }
+
+    #[test]
+    fn test_search_names() {
+        let opts = ResolveOptions {
+            search: vec!["home.arpa".parse().unwrap(), "example.com".parse().unwrap()],
+            ndots: 2,
+            ..ResolveOptions::default()
+        };
+        let names = |domain| -> Vec<String> {
+            let names = opts.search_names(domain).unwrap();
+            names.iter().map(ToString::to_string).collect()
+        };
+        assert_eq!(names("nas"), ["nas.home.arpa", "nas.example.com", "nas"]);
+        assert_eq!(
+            names("www.example"),
+            [
+                "www.example.home.arpa",
+                "www.example.example.com",
+                "www.example"
+            ]
+        );
+        assert_eq!(
+            names("a.b.example"),
+            [
+                "a.b.example",
+                "a.b.example.home.arpa",
+                "a.b.example.example.com"
+            ]
+        );
+        assert_eq!(names("nas."), ["nas"]);
+        assert_eq!(
+            ResolveOptions::default().search_names("nas").unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_record_with_search_domains() {
+        fn home_arpa(query: &[u8]) -> Vec<u8> {
+            let mut response = DnsParser::new(query).parse_packet().unwrap();
+            response.header.flags.query = false;
+            match response.questions[0].domain_name.as_str() {
+                "nas.home.arpa" => {
+                    response.answers = vec![Answer::A {
+                        meta: meta("nas.home.arpa", RecordType::A),
+                        ipv4: Ipv4Addr::new(192, 0, 2, 7),
+                    }]
+                }
+                _ => response.header.flags.response_code = ResponseCode::NXDomain,
+            }
+            response.to_bytes()
+        }
+
+        let opts = ResolveOptions {
+            search: vec!["example.com".parse().unwrap(), "home.arpa".parse().unwrap()],
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(home_arpa))))
+        };
+        let upstreams = "127.0.0.1:9".into();
+        let answers = resolve_record("nas", RecordType::A, &upstreams, &opts).unwrap();
+        assert_eq!(answers[0].meta().name.as_str(), "nas.home.arpa");
+        let answers = resolve_record_async("nas", RecordType::A, &upstreams, &opts)
+            .await
+            .unwrap();
+        assert_eq!(answers.len(), 1);
+
+        // absolute names are not searched
+        let answers = resolve_record("nas.", RecordType::A, &upstreams, &opts).unwrap();
+        assert!(answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_response() {
+        fn nxdomain_below_example(query: &[u8]) -> Vec<u8> {
+            let mut response = DnsParser::new(query).parse_packet().unwrap();
+            response.header.flags.query = false;
+            response.header.flags.recursion_available = true;
+            if response.questions[0].domain_name.as_str() != "example.com" {
+                response.header.flags.response_code = ResponseCode::NXDomain;
+            }
+            response.to_bytes()
+        }
+
+        let opts = ResolveOptions {
+            hosts: Some(Arc::new(HostsFile::parse("192.0.2.1 nas.home.arpa"))),
+            ..ResolveOptions::default().transport(Transport::Custom(Arc::new(FnTransport(
+                nxdomain_below_example,
+            ))))
+        };
+        let upstreams = "127.0.0.1:9".into();
+
+        let response = resolve_response("example.com", RecordType::A, &upstreams, &opts).unwrap();
+        assert!(response.answers.is_empty());
+        assert!(!response.is_nxdomain());
+        assert!(response.flags.recursion_available);
+        assert_eq!(response.upstream.as_deref(), Some("127.0.0.1:9"));
+
+        let response = resolve_response_async("www.example.com", RecordType::A, &upstreams, &opts)
+            .await
+            .unwrap();
+        assert!(response.is_nxdomain());
+
+        let response = resolve_response("nas.home.arpa", RecordType::A, &upstreams, &opts).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.upstream, None);
+        assert_eq!(response.rtt, Duration::ZERO);
+    }
+
+    /// Answers A and AAAA questions for dual.example.com with two addresses each
+    fn dual_stack(query: &[u8]) -> Vec<u8> {
+        let packet = DnsParser::new(query).parse_packet().unwrap();
+        let answers = match packet.questions[0].r#type {
+            RecordType::A => ["192.0.2.1", "192.0.2.2"]
+                .map(|ip| Answer::A {
+                    meta: meta("dual.example.com", RecordType::A),
+                    ipv4: ip.parse().unwrap(),
+                })
+                .to_vec(),
+            _ => ["2001:db8::1", "2001:db8::2"]
+                .map(|ip| Answer::AAAA {
+                    meta: meta("dual.example.com", RecordType::AAAA),
+                    ipv6: ip.parse().unwrap(),
+                })
+                .to_vec(),
+        };
+        respond_with(query, answers)
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip() {
+        let opts = ResolveOptions::default()
+            .transport(Transport::Custom(Arc::new(FnTransport(dual_stack))));
+        let upstreams = "127.0.0.1:9".into();
+        let expected: Vec<IpAddr> = ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]
+            .map(|ip| ip.parse().unwrap())
+            .to_vec();
+        assert_eq!(
+            resolve_ip("dual.example.com", &upstreams, &opts).unwrap(),
+            expected
+        );
+        assert_eq!(
+            resolve_ip_async("dual.example.com", &upstreams, &opts)
+                .await
+                .unwrap(),
+            expected
+        );
+
+        let opts = ResolveOptions {
+            retries: 0,
+            ..ResolveOptions::default()
+                .transport(Transport::Custom(Arc::new(FnTransport(|_| vec![]))))
+        };
+        assert!(resolve_ip("dual.example.com", &upstreams, &opts).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_txt() {
+        let opts = ResolveOptions::default().transport(Transport::Custom(Arc::new(FnTransport(
+            |query| {
+                let txt = Answer::TXT {
+                    meta: meta("example.com", RecordType::TXT),
+                    txt: vec![b"v=spf1 ip4:192.0.2.0/24 ".to_vec(), b"-all".to_vec()],
+                };
+                respond_with(query, vec![txt])
+            },
+        ))));
+        let upstreams = "127.0.0.1:9".into();
+        let expected = ["v=spf1 ip4:192.0.2.0/24 -all"];
+        assert_eq!(
+            resolve_txt("example.com", &upstreams, &opts).unwrap(),
+            expected
+        );
+        assert_eq!(
+            resolve_txt_async("example.com", &upstreams, &opts)
+                .await
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_sort_addresses() {
+        let sort = |addresses: &[&str]| -> Vec<String> {
+            let addresses = addresses.iter().map(|ip| ip.parse().unwrap()).collect();
+            sort_addresses(addresses)
+                .iter()
+                .map(ToString::to_string)
+                .collect()
+        };
+        // IPv4 is preferred over unique local and 6to4 addresses
+        assert_eq!(
+            sort(&[
+                "fd00::1",
+                "2002:c000:201::1",
+                "192.0.2.1",
+                "2001:db8::1",
+                "192.0.2.2"
+            ]),
+            [
+                "2001:db8::1",
+                "192.0.2.1",
+                "2002:c000:201::1",
+                "192.0.2.2",
+                "fd00::1"
+            ]
+        );
+        assert_eq!(sort(&["192.0.2.1", "::1"]), ["::1", "192.0.2.1"]);
+        assert_eq!(
+            sort(&["192.0.2.1", "192.0.2.2"]),
+            ["192.0.2.1", "192.0.2.2"]
+        );
+        assert!(sort(&[]).is_empty());
+    }
 }
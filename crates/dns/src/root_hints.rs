@@ -0,0 +1,357 @@
+//! Addresses of the root servers, which iterative resolution starts from
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::ResolveError,
+    parse::parser::DnsParser,
+    protocol::{answer::Answer, name::DnsName},
+    resolver::ResponseError,
+};
+
+/// Names and addresses of the root servers
+/// https://www.internic.net/domain/named.root
+pub const ROOT_SERVERS: [(&str, Ipv4Addr, Ipv6Addr); 13] = [
+    (
+        "a.root-servers.net",
+        Ipv4Addr::new(198, 41, 0, 4),
+        Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30),
+    ),
+    (
+        "b.root-servers.net",
+        Ipv4Addr::new(170, 247, 170, 2),
+        Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb),
+    ),
+    (
+        "c.root-servers.net",
+        Ipv4Addr::new(192, 33, 4, 12),
+        Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc),
+    ),
+    (
+        "d.root-servers.net",
+        Ipv4Addr::new(199, 7, 91, 13),
+        Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd),
+    ),
+    (
+        "e.root-servers.net",
+        Ipv4Addr::new(192, 203, 230, 10),
+        Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe),
+    ),
+    (
+        "f.root-servers.net",
+        Ipv4Addr::new(192, 5, 5, 241),
+        Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf),
+    ),
+    (
+        "g.root-servers.net",
+        Ipv4Addr::new(192, 112, 36, 4),
+        Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d),
+    ),
+    (
+        "h.root-servers.net",
+        Ipv4Addr::new(198, 97, 190, 53),
+        Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "i.root-servers.net",
+        Ipv4Addr::new(192, 36, 148, 17),
+        Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "j.root-servers.net",
+        Ipv4Addr::new(192, 58, 128, 30),
+        Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30),
+    ),
+    (
+        "k.root-servers.net",
+        Ipv4Addr::new(193, 0, 14, 129),
+        Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1),
+    ),
+    (
+        "l.root-servers.net",
+        Ipv4Addr::new(199, 7, 83, 42),
+        Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42),
+    ),
+    (
+        "m.root-servers.net",
+        Ipv4Addr::new(202, 12, 27, 33),
+        Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35),
+    ),
+];
+
+/// Name servers of the root zone with their addresses, either from a hints file or from the
+/// response to a priming query.
+/// https://datatracker.ietf.org/doc/html/rfc8109
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootHints {
+    pub name_servers: Vec<RootServer>,
+    /// When the records of a priming response expire, `None` for hints that were not primed
+    pub expires: Option<Instant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootServer {
+    pub name: DnsName,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Default for RootHints {
+    /// The built-in [`ROOT_SERVERS`]
+    fn default() -> Self {
+        Self {
+            name_servers: ROOT_SERVERS
+                .iter()
+                .map(|&(name, ipv4, ipv6)| RootServer {
+                    name: DnsName::new(name).expect("valid root server name"),
+                    addresses: vec![ipv4.into(), ipv6.into()],
+                })
+                .collect(),
+            expires: None,
+        }
+    }
+}
+
+impl RootHints {
+    /// Reads a root hints file in zone file format, like `named.root`
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Parses the NS, A and AAAA records of root hints in zone file format, one record per line
+    /// with an optional TTL and class and `;` starting comments. Records of other types are
+    /// ignored.
+    ///
+    /// ```
+    /// use dns::root_hints::RootHints;
+    ///
+    /// let hints = RootHints::parse(
+    ///     ".                   3600000  NS    A.ROOT-SERVERS.NET.
+    ///      A.ROOT-SERVERS.NET. 3600000  A     198.41.0.4 ; VeriSign, Inc.",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(hints.name_servers[0].addresses, ["198.41.0.4".parse::<std::net::IpAddr>().unwrap()]);
+    /// ```
+    pub fn parse(hints: &str) -> Result<Self, String> {
+        let mut name_servers: Vec<RootServer> = vec![];
+        let mut addresses: Vec<(DnsName, IpAddr)> = vec![];
+        for (number, line) in hints.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let mut fields = line.split_whitespace().peekable();
+            let Some(owner) = fields.next() else {
+                continue;
+            };
+            let invalid = |reason: &str| format!("line {}: {reason}", number + 1);
+            let owner = DnsName::new(owner).map_err(|e| invalid(&e.to_string()))?;
+
+            fields.next_if(|ttl| ttl.bytes().all(|b| b.is_ascii_digit()));
+            fields.next_if(|class| class.eq_ignore_ascii_case("IN"));
+            let (Some(r#type), Some(data)) = (fields.next(), fields.next()) else {
+                return Err(invalid("expected a record type and data"));
+            };
+            match r#type.to_ascii_uppercase().as_str() {
+                "NS" if owner.is_root() => name_servers.push(RootServer {
+                    name: DnsName::new(data).map_err(|e| invalid(&e.to_string()))?,
+                    addresses: vec![],
+                }),
+                "NS" => return Err(invalid("NS record for a zone other than the root")),
+                "A" => addresses.push((
+                    owner,
+                    data.parse::<Ipv4Addr>()
+                        .map_err(|e| invalid(&e.to_string()))?
+                        .into(),
+                )),
+                "AAAA" => addresses.push((
+                    owner,
+                    data.parse::<Ipv6Addr>()
+                        .map_err(|e| invalid(&e.to_string()))?
+                        .into(),
+                )),
+                _ => {}
+            }
+        }
+
+        for (owner, address) in addresses {
+            if let Some(name_server) = name_servers.iter_mut().find(|ns| ns.name == owner) {
+                name_server.addresses.push(address);
+            }
+        }
+        let hints = Self {
+            name_servers,
+            expires: None,
+        };
+        if hints.socket_addrs(53).is_empty() {
+            return Err("no root server has an IPv4 address".to_string());
+        }
+        Ok(hints)
+    }
+
+    /// Takes the name servers of the root zone from the `response` to a priming query, which
+    /// expire with the shortest TTL of their records
+    pub fn from_priming_response(response: &[u8], now: Instant) -> Result<Self, ResolveError> {
+        ResponseError::check(response)?;
+        let response = DnsParser::new(response).parse_packet()?;
+
+        let mut ttl = usize::MAX;
+        let mut name_servers: Vec<_> = response
+            .answers
+            .iter()
+            .filter_map(|answer| match answer {
+                Answer::NS { meta, ns } if meta.name.is_root() => {
+                    ttl = ttl.min(meta.ttl);
+                    Some(RootServer {
+                        name: ns.clone(),
+                        addresses: vec![],
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        for answer in &response.additionals {
+            let (meta, address) = match answer {
+                Answer::A { meta, ipv4 } => (meta, IpAddr::from(*ipv4)),
+                Answer::AAAA { meta, ipv6 } => (meta, IpAddr::from(*ipv6)),
+                _ => continue,
+            };
+            if let Some(name_server) = name_servers.iter_mut().find(|ns| ns.name == meta.name) {
+                ttl = ttl.min(meta.ttl);
+                name_server.addresses.push(address);
+            }
+        }
+        name_servers.retain(|name_server| !name_server.addresses.is_empty());
+        if name_servers.is_empty() {
+            return Err(ResolveError::NoNameServers(DnsName::root()));
+        }
+
+        Ok(Self {
+            name_servers,
+            expires: Some(now + Duration::from_secs(ttl as u64)),
+        })
+    }
+
+    /// Whether the hints should be refreshed with a priming query
+    pub fn needs_priming(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires <= now)
+    }
+
+    /// IPv4 addresses of all root servers with `port`
+    pub fn socket_addrs(&self, port: u16) -> Vec<SocketAddr> {
+        self.name_servers
+            .iter()
+            .flat_map(|name_server| &name_server.addresses)
+            .filter(|address| address.is_ipv4())
+            .map(|&address| SocketAddr::new(address, port))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use crate::protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        packet::Packet,
+        record_type::RecordType,
+    };
+
+    use super::{RootHints, ROOT_SERVERS};
+
+    fn meta(name: &str, r#type: RecordType, ttl: usize) -> AnswerMeta {
+        AnswerMeta {
+            name: name.parse().unwrap(),
+            r#type,
+            class: Class::IN,
+            ttl,
+            len: 0,
+        }
+    }
+
+    #[test]
+    fn test_parse_root_hints() {
+        let hints = RootHints::parse(
+            ";       This file holds the information on root name servers
+            .                        3600000      NS    A.ROOT-SERVERS.NET.
+            A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+            A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+            ;
+            .                        3600000  IN  NS    B.ROOT-SERVERS.NET.
+            B.ROOT-SERVERS.NET.      IN           A     170.247.170.2
+            OTHER.EXAMPLE.           3600000      A     192.0.2.1",
+        )
+        .unwrap();
+        let addresses: Vec<_> = hints
+            .name_servers
+            .iter()
+            .map(|ns| (ns.name.as_str(), ns.addresses.clone()))
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                (
+                    "A.ROOT-SERVERS.NET",
+                    vec![
+                        "198.41.0.4".parse::<IpAddr>().unwrap(),
+                        "2001:503:ba3e::2:30".parse().unwrap()
+                    ]
+                ),
+                ("B.ROOT-SERVERS.NET", vec!["170.247.170.2".parse().unwrap()]),
+            ]
+        );
+        assert_eq!(hints.socket_addrs(53).len(), 2);
+        assert!(hints.needs_priming(Instant::now()));
+
+        assert!(RootHints::parse("example. NS a.example.").is_err());
+        assert!(RootHints::parse(". NS a.example.\na.example. A 300.0.0.1").is_err());
+        assert!(RootHints::parse(". NS a.example.").is_err());
+        assert_eq!(
+            RootHints::default().socket_addrs(53).len(),
+            ROOT_SERVERS.len()
+        );
+    }
+
+    #[test]
+    fn test_priming_response() {
+        let response = Packet {
+            answers: vec![
+                Answer::NS {
+                    meta: meta(".", RecordType::NS, 518400),
+                    ns: "a.root-servers.net".parse().unwrap(),
+                },
+                Answer::NS {
+                    meta: meta(".", RecordType::NS, 518400),
+                    ns: "b.root-servers.net".parse().unwrap(),
+                },
+            ],
+            additionals: vec![
+                Answer::A {
+                    meta: meta("a.root-servers.net", RecordType::A, 3600),
+                    ipv4: [198, 41, 0, 4].into(),
+                },
+                Answer::A {
+                    meta: meta("other.example", RecordType::A, 60),
+                    ipv4: [192, 0, 2, 1].into(),
+                },
+            ],
+            ..Packet::default()
+        };
+
+        let now = Instant::now();
+        let hints = RootHints::from_priming_response(&response.to_bytes(), now).unwrap();
+        // b.root-servers.net has no address
+        assert_eq!(hints.name_servers.len(), 1);
+        assert_eq!(hints.socket_addrs(53), ["198.41.0.4:53".parse().unwrap()]);
+        assert_eq!(hints.expires, Some(now + Duration::from_secs(3600)));
+        assert!(!hints.needs_priming(now));
+        assert!(hints.needs_priming(now + Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,430 @@
+//! Zones transferred from a primary server, which are answered like local zones once they
+//! arrived.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    authority::ZoneSet,
+    error::ResolveError,
+    notify::NotifyRequest,
+    parse::parser::DnsParser,
+    protocol::{
+        answer::Answer,
+        name::DnsName,
+        query::{random_id, QueryBuilder},
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    resolver::{bind_query_socket_async, resolve_query_async, ResolveOptions, ResponseError},
+    tcp::{read_tcp_message_async, write_tcp_message_async},
+    tsig::{TsigKey, TsigSigner},
+    zonefile::Zone,
+};
+
+/// How long to wait before trying again when the zone was never transferred, so no SOA record
+/// says how long
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shortest time between two refreshes, however short the timers of the SOA record are
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A zone this server is a secondary for
+#[derive(Debug)]
+pub struct SecondaryZone {
+    pub origin: DnsName,
+    /// The server the zone is transferred from, and the only one NOTIFY messages are accepted from
+    pub primary: SocketAddr,
+    /// Key the transfer requests are signed with
+    pub key: Option<TsigKey>,
+    /// Serial of the zone transferred last
+    serial: Mutex<Option<u32>>,
+    refresh: Notify,
+}
+
+impl SecondaryZone {
+    pub fn new(origin: DnsName, primary: SocketAddr) -> Self {
+        Self {
+            origin,
+            primary,
+            key: None,
+            serial: Mutex::new(None),
+            refresh: Notify::new(),
+        }
+    }
+
+    pub fn key(mut self, key: TsigKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Serial of the zone transferred last, `None` before the first transfer
+    pub fn serial(&self) -> Option<u32> {
+        *self.serial.lock().unwrap()
+    }
+
+    /// Makes [`SecondaryZone::run`] check the serial of the primary right away, instead of
+    /// when the refresh interval is up
+    pub fn request_refresh(&self) {
+        self.refresh.notify_one();
+    }
+
+    /// Transfers the zone once, see [`transfer_zone_async`]
+    pub async fn transfer(&self, opts: &ResolveOptions) -> Result<Zone, ResolveError> {
+        transfer_zone_async(&self.origin, self.primary, self.key.as_ref(), opts).await
+    }
+
+    /// Keeps the zone in `zones` up to date, honoring the timers of its SOA record: the zone is
+    /// transferred on start, and the serial of the primary is checked every refresh interval or
+    /// whenever a refresh is requested, eg. by a NOTIFY from the primary. The zone is transferred
+    /// again once the serial increased. While the primary does not respond, it is asked again
+    /// every retry interval, and the zone is removed from `zones` when it expires. Never
+    /// finishes.
+    /// https://datatracker.ietf.org/doc/html/rfc1034#section-4.3.5
+    pub async fn run(&self, zones: &ZoneSet, opts: &ResolveOptions) {
+        // when the primary last confirmed the data of the zone
+        let mut refreshed: Option<Instant> = None;
+        loop {
+            let timers = zones
+                .get(&self.origin)
+                .and_then(|zone| SoaTimers::of(&zone));
+            let wait = match self.refresh(zones, opts).await {
+                Ok(()) => {
+                    refreshed = Some(Instant::now());
+                    zones
+                        .get(&self.origin)
+                        .and_then(|zone| SoaTimers::of(&zone))
+                        .map_or(DEFAULT_RETRY_INTERVAL, |timers| timers.refresh)
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        zone = %self.origin,
+                        primary = %self.primary,
+                        error = %e,
+                        "Could not refresh zone"
+                    );
+                    let expired = timers
+                        .as_ref()
+                        .zip(refreshed)
+                        .is_some_and(|(timers, refreshed)| refreshed.elapsed() >= timers.expire);
+                    if expired && zones.remove(&self.origin).is_some() {
+                        *self.serial.lock().unwrap() = None;
+                        tracing::warn!(zone = %self.origin, "Zone expired");
+                    }
+                    timers.map_or(DEFAULT_RETRY_INTERVAL, |timers| timers.retry)
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait.max(MIN_REFRESH_INTERVAL)) => {}
+                _ = self.refresh.notified() => {}
+            }
+        }
+    }
+
+    /// Transfers the zone into `zones` unless the serial of the primary is not newer than the one
+    /// transferred last
+    async fn refresh(&self, zones: &ZoneSet, opts: &ResolveOptions) -> Result<(), ResolveError> {
+        if let Some(serial) = self.serial() {
+            if !is_newer_serial(self.primary_serial(opts).await?, serial) {
+                return Ok(());
+            }
+        }
+        let zone = self.transfer(opts).await?;
+        *self.serial.lock().unwrap() = zone.serial();
+        zones.insert(zone);
+        Ok(())
+    }
+
+    /// Asks the primary for the serial of its SOA record
+    async fn primary_serial(&self, opts: &ResolveOptions) -> Result<u32, ResolveError> {
+        let query = QueryBuilder::new(self.origin.clone())
+            .id(random_id())
+            .record_type(RecordType::SOA)
+            .recursion_desired(false)
+            .build();
+        let socket = bind_query_socket_async(opts).await?;
+        let response =
+            resolve_query_async(&query, &self.primary.to_string(), &socket, opts).await?;
+        ResponseError::check(&response)?;
+        DnsParser::new(&response)
+            .parse_packet()?
+            .answers
+            .into_iter()
+            .find_map(|answer| match answer {
+                Answer::SOA { meta, serial, .. } if meta.name == self.origin => Some(serial),
+                _ => None,
+            })
+            .ok_or_else(|| ResolveError::InvalidTransfer(self.origin.clone()))
+    }
+}
+
+/// The intervals of an SOA record that tell secondaries how to keep the zone up to date
+struct SoaTimers {
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+impl SoaTimers {
+    fn of(zone: &Zone) -> Option<Self> {
+        match zone.soa()? {
+            Answer::SOA {
+                refresh,
+                retry,
+                expire,
+                ..
+            } => Some(Self {
+                refresh: Duration::from_secs(*refresh as u64),
+                retry: Duration::from_secs(*retry as u64),
+                expire: Duration::from_secs(*expire as u64),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `serial` comes after `previous`, in the serial number arithmetic that lets serials
+/// wrap around
+/// https://datatracker.ietf.org/doc/html/rfc1982#section-3.2
+pub fn is_newer_serial(serial: u32, previous: u32) -> bool {
+    (serial.wrapping_sub(previous) as i32) > 0
+}
+
+/// Answers the raw NOTIFY `message` from `peer`, and requests a refresh of the secondary zone it
+/// is about unless its serial is the one transferred last. NOTIFY messages for other zones or
+/// from servers other than the primary of the zone are refused. `None` if the message is no
+/// NOTIFY.
+/// https://datatracker.ietf.org/doc/html/rfc1996#section-3.10
+pub fn handle_notify(
+    secondaries: &[SecondaryZone],
+    message: &[u8],
+    peer: IpAddr,
+) -> Option<Vec<u8>> {
+    let notify = NotifyRequest::parse(message)?;
+    let Some(secondary) = secondaries
+        .iter()
+        .find(|secondary| secondary.origin == notify.zone)
+    else {
+        return Some(notify.respond(ResponseCode::NotAuth));
+    };
+    if secondary.primary.ip() != peer {
+        return Some(notify.respond(ResponseCode::Refused));
+    }
+    if notify.serial.is_none() || notify.serial != secondary.serial() {
+        secondary.request_refresh();
+    }
+    Some(notify.respond(ResponseCode::NoError))
+}
+
+/// Transfers the zone at `origin` from `primary` with AXFR over TCP, signing the request with
+/// `key` if given. Reading each message times out after `opts.max_timeout`.
+/// https://datatracker.ietf.org/doc/html/rfc5936
+pub async fn transfer_zone_async(
+    origin: &DnsName,
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+    opts: &ResolveOptions,
+) -> Result<Zone, ResolveError> {
+    let id = random_id();
+    let mut query = QueryBuilder::new(origin.clone())
+        .id(id)
+        .record_type(RecordType::AXFR)
+        .recursion_desired(false)
+        .build();
+    if let Some(key) = key {
+        query = TsigSigner::query(key.clone()).sign(&query);
+    }
+    let timed_out = || ResolveError::Timeout(format!("no response from {primary} over TCP"));
+
+    let mut stream =
+        tokio::time::timeout(opts.max_timeout, tokio::net::TcpStream::connect(primary))
+            .await
+            .map_err(|_| timed_out())??;
+    write_tcp_message_async(&mut stream, &query).await?;
+
+    let mut records: Vec<Answer> = vec![];
+    loop {
+        let message = tokio::time::timeout(opts.max_timeout, read_tcp_message_async(&mut stream))
+            .await
+            .map_err(|_| timed_out())??;
+        ResponseError::check(&message)?;
+        let packet = DnsParser::new(&message).parse_packet()?;
+        if packet.header.request_id != id {
+            return Err(ResolveError::InvalidTransfer(origin.clone()));
+        }
+        let starts_with_soa = matches!(
+            records.first().or(packet.answers.first()),
+            Some(Answer::SOA { meta, .. }) if meta.name == *origin
+        );
+        if !starts_with_soa {
+            return Err(ResolveError::InvalidTransfer(origin.clone()));
+        }
+        records.extend(packet.answers);
+        // the transfer ends with the SOA record it started with
+        if records.len() > 1 && records.last() == records.first() {
+            records.pop();
+            return Ok(Zone {
+                origin: origin.clone(),
+                records,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use tokio::task::JoinHandle;
+
+    use crate::{
+        authority::{Acl, ZoneSet},
+        notify::notify_message,
+        parse::parser::DnsParser,
+        protocol::{record_type::RecordType, response_code::ResponseCode},
+        resolver::ResolveOptions,
+        tcp::{read_tcp_message_async, write_tcp_message_async},
+        zonefile::Zone,
+    };
+
+    use super::{handle_notify, is_newer_serial, SecondaryZone};
+
+    fn zone(serial: u32, timers: &str) -> Zone {
+        let mut contents = format!("@ 60 SOA ns hostmaster {serial} {timers} 300\n");
+        for i in 0..500 {
+            contents.push_str(&format!("host{i} 60 A 192.0.2.1\n"));
+        }
+        Zone::parse(&contents, &"example.lan".parse().unwrap()).unwrap()
+    }
+
+    /// Serves `zones` like a primary, transfers over TCP and SOA queries over UDP
+    async fn primary(zones: Arc<ZoneSet>) -> (SocketAddr, [JoinHandle<()>; 2]) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let socket = tokio::net::UdpSocket::bind(address).await.unwrap();
+        let served = Arc::clone(&zones);
+        let tcp = tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = listener.accept().await.unwrap();
+                let query = read_tcp_message_async(&mut stream).await.unwrap();
+                for message in served.respond_transfer(&query, peer.ip()).unwrap() {
+                    write_tcp_message_async(&mut stream, &message)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        let udp = tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let response = zones.respond(&buffer[..len]).unwrap();
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        (address, [tcp, udp])
+    }
+
+    fn primary_zones(zone: Zone) -> Arc<ZoneSet> {
+        Arc::new(ZoneSet::new([zone]).transfer_acl(Acl {
+            addresses: vec!["127.0.0.1".parse().unwrap()],
+            keys: vec![],
+        }))
+    }
+
+    /// Runs the secondary for `example.lan` at `primary` in the background
+    fn secondary(primary: SocketAddr) -> (Arc<[SecondaryZone; 1]>, Arc<ZoneSet>) {
+        let secondaries = Arc::new([SecondaryZone::new("example.lan".parse().unwrap(), primary)]);
+        let zones = Arc::new(ZoneSet::default());
+        let opts = ResolveOptions {
+            timeout: Duration::from_millis(200),
+            retries: 0,
+            max_timeout: Duration::from_secs(1),
+            ..ResolveOptions::default()
+        };
+        let (running, secondary_zones) = (Arc::clone(&secondaries), Arc::clone(&zones));
+        tokio::spawn(async move { running[0].run(&secondary_zones, &opts).await });
+        (secondaries, zones)
+    }
+
+    async fn wait_for(secondary: &SecondaryZone, serial: Option<u32>) {
+        for _ in 0..250 {
+            if secondary.serial() == serial {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("serial {serial:?} was not reached");
+    }
+
+    fn rcode(message: &[u8]) -> ResponseCode {
+        DnsParser::new(message)
+            .parse_packet()
+            .unwrap()
+            .header
+            .rcode()
+    }
+
+    #[tokio::test]
+    async fn test_secondary_zone() {
+        let primary_zones = primary_zones(zone(1, "7200 3600 1209600"));
+        let (primary, _tasks) = primary(Arc::clone(&primary_zones)).await;
+        let (secondaries, zones) = secondary(primary);
+
+        wait_for(&secondaries[0], Some(1)).await;
+        let name = "host499.example.lan".parse().unwrap();
+        assert_eq!(zones.lookup(&name, RecordType::A).unwrap().answers.len(), 1);
+        let origin = "example.lan".parse().unwrap();
+        assert_eq!(zones.get(&origin).unwrap().records.len(), 501);
+
+        // a NOTIFY from anyone but the primary is refused
+        primary_zones.insert(zone(2, "7200 3600 1209600"));
+        let notify = notify_message(&zone(2, "7200 3600 1209600"));
+        let stranger = "192.0.2.1".parse().unwrap();
+        let refused = handle_notify(&secondaries[..], &notify, stranger).unwrap();
+        assert_eq!(rcode(&refused), ResponseCode::Refused);
+        let acknowledged = handle_notify(&secondaries[..], &notify, primary.ip()).unwrap();
+        assert_eq!(rcode(&acknowledged), ResponseCode::NoError);
+        wait_for(&secondaries[0], Some(2)).await;
+
+        let other = Zone {
+            origin: "example.com".parse().unwrap(),
+            ..zone(1, "7200 3600 1209600")
+        };
+        let not_ours =
+            handle_notify(&secondaries[..], &notify_message(&other), primary.ip()).unwrap();
+        assert_eq!(rcode(&not_ours), ResponseCode::NotAuth);
+    }
+
+    #[tokio::test]
+    async fn test_secondary_refresh() {
+        // refresh every second, and expire after 2 seconds without the primary
+        let primary_zones = primary_zones(zone(1, "1 1 2"));
+        let (primary, tasks) = primary(Arc::clone(&primary_zones)).await;
+        let (secondaries, zones) = secondary(primary);
+        wait_for(&secondaries[0], Some(1)).await;
+
+        primary_zones.insert(zone(2, "1 1 2"));
+        wait_for(&secondaries[0], Some(2)).await;
+
+        for task in tasks {
+            task.abort();
+        }
+        wait_for(&secondaries[0], None).await;
+        assert!(zones.is_empty());
+    }
+
+    #[test]
+    fn test_serial_arithmetic() {
+        assert!(is_newer_serial(2, 1));
+        assert!(!is_newer_serial(1, 1));
+        assert!(!is_newer_serial(1, 2));
+        assert!(is_newer_serial(0, u32::MAX));
+        assert!(!is_newer_serial(u32::MAX, 0));
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use crate::protocol::{answer::Answer, header::Header, name::DnsName, question::Question};
+
+/// Maximum size of a DNS message sent over UDP without EDNS
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
+pub const MAX_UDP_MESSAGE_SIZE: usize = 512;
+
+/// Compression pointers can only address the first 16K of a message
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Writes DNS messages in wire format into a buffer.
+///
+/// Domain names are compressed by default, ie. whenever a name (or one of its suffixes) was already
+/// written to the message, a pointer to the previous occurrence is emitted instead.
+/// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+#[derive(Debug)]
+pub struct PacketWriter<'a> {
+    buf: &'a mut Vec<u8>,
+    /// Position in `buf` at which the message starts, since pointers are relative to the message
+    start: usize,
+    /// Maps already written (lowercased) name suffixes to their offset in the message
+    names: Option<HashMap<String, u16>>,
+}
+
+impl<'a> PacketWriter<'a> {
+    /// Creates a writer that compresses domain names, assuming the message starts at the current
+    /// end of `buf`
+    pub fn new(buf: &'a mut Vec<u8>) -> Self {
+        Self {
+            start: buf.len(),
+            buf,
+            names: Some(HashMap::new()),
+        }
+    }
+
+    /// Creates a writer that writes all domain names in full
+    pub fn uncompressed(buf: &'a mut Vec<u8>) -> Self {
+        Self {
+            start: buf.len(),
+            buf,
+            names: None,
+        }
+    }
+
+    /// Number of bytes written to the message so far
+    pub fn len(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Overwrites two already written bytes at message offset `at`, eg. to fill in a length field
+    pub fn patch_u16(&mut self, at: usize, value: u16) {
+        let at = self.start + at;
+        self.buf[at..at + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Drops everything written after message offset `len`
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(self.start + len);
+        if let Some(names) = self.names.as_mut() {
+            names.retain(|_, offset| (*offset as usize) < len);
+        }
+    }
+
+    pub fn write_header(&mut self, header: &Header) {
+        let raw: [u8; 12] = header.clone().into();
+        self.write_bytes(&raw);
+    }
+
+    /// Writes `name` as a sequence of labels, using a compression pointer for the longest suffix
+    /// that was already written to this message.
+    pub fn write_name(&mut self, name: &DnsName) {
+        let labels: Vec<&str> = name.labels().collect();
+
+        for (i, wire_label) in name.wire_labels().enumerate() {
+            let suffix = labels[i..].join(".").to_ascii_lowercase();
+            let offset = self.len();
+
+            if let Some(names) = self.names.as_mut() {
+                if let Some(pointer) = names.get(&suffix) {
+                    let pointer = 0xC000 | *pointer;
+                    self.write_u16(pointer);
+                    return;
+                }
+
+                if offset <= MAX_POINTER_OFFSET {
+                    names.insert(suffix, offset as u16);
+                }
+            }
+
+            self.write_u8(wire_label.len() as u8);
+            self.write_bytes(&wire_label);
+        }
+        self.write_u8(0);
+    }
+
+    /// Writes `name` in full, without pointing to or being pointed at by other names
+    pub fn write_name_uncompressed(&mut self, name: &DnsName) {
+        for wire_label in name.wire_labels() {
+            self.write_u8(wire_label.len() as u8);
+            self.write_bytes(&wire_label);
+        }
+        self.write_u8(0);
+    }
+}
+
+/// Serializes a response message with compressed names.
+///
+/// Answers that do not fit into `max_size` bytes are left out and the TC bit is set instead,
+/// so the client knows to retry over TCP.
+/// https://datatracker.ietf.org/doc/html/rfc2181#section-9
+pub fn write_response(
+    header: &Header,
+    questions: &[Question],
+    answers: &[Answer],
+    max_size: usize,
+) -> Vec<u8> {
+    let mut header = header.clone();
+    header.question_count = questions.len() as u16;
+    header.answer_count = answers.len() as u16;
+    header.authority_count = 0;
+    header.additional_count = 0;
+
+    let mut buf = Vec::with_capacity(max_size);
+    let mut writer = PacketWriter::new(&mut buf);
+    writer.write_header(&header);
+    for question in questions {
+        question.write(&mut writer);
+    }
+
+    for (written, answer) in answers.iter().enumerate() {
+        let checkpoint = writer.len();
+        answer.write(&mut writer);
+        if writer.len() > max_size {
+            writer.truncate(checkpoint);
+            header.answer_count = written as u16;
+            header.flags.truncation = true;
+            let raw: [u8; 12] = header.into();
+            buf[0..12].copy_from_slice(&raw);
+            break;
+        }
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            header::Header,
+            question::Question,
+            record_type::RecordType,
+        },
+    };
+
+    use super::{write_response, PacketWriter, MAX_UDP_MESSAGE_SIZE};
+
+    fn a_record(name: &str, last_octet: u8) -> Answer {
+        Answer::A {
+            meta: AnswerMeta {
+                name: name.parse().unwrap(),
+                r#type: RecordType::A,
+                class: Class::IN,
+                ttl: 300,
+                len: 4,
+            },
+            ipv4: [10, 0, 0, last_octet].into(),
+        }
+    }
+
+    #[test]
+    fn test_write_name_compression() {
+        let mut buf = vec![];
+        let mut writer = PacketWriter::new(&mut buf);
+        writer.write_name(&"www.example.com".parse().unwrap());
+        writer.write_name(&"mail.EXAMPLE.com".parse().unwrap());
+        writer.write_name(&"www.example.com.".parse().unwrap());
+
+        assert_eq!(
+            buf,
+            vec![
+                3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o',
+                b'm', 0, // www.example.com
+                4, b'm', b'a', b'i', b'l', 0xC0, 4, // mail + pointer to example.com
+                0xC0, 0 // pointer to www.example.com
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_name_uncompressed() {
+        let mut buf = vec![];
+        let mut writer = PacketWriter::uncompressed(&mut buf);
+        let name = "example.com".parse().unwrap();
+        writer.write_name(&name);
+        writer.write_name(&name);
+        assert_eq!(buf.len(), 2 * 13);
+    }
+
+    #[test]
+    fn test_write_response_roundtrip() {
+        let question = Question {
+            domain_name: "example.com".parse().unwrap(),
+            r#type: RecordType::A,
+            class: Class::IN,
+        };
+        let answers: Vec<_> = (0..3).map(|i| a_record("example.com", i)).collect();
+
+        let response = write_response(
+            &Header::default(),
+            &[question],
+            &answers,
+            MAX_UDP_MESSAGE_SIZE,
+        );
+        // 12 header + 17 question + 3 * (2 pointer + 10 meta + 4 ipv4)
+        assert_eq!(response.len(), 12 + 17 + 3 * 16);
+
+        let mut packet = [0u8; 512];
+        packet[..response.len()].copy_from_slice(&response);
+        let parsed = DnsParser::new(&packet).parse_answers().unwrap();
+        assert_eq!(parsed, answers);
+    }
+
+    #[test]
+    fn test_write_response_truncates() {
+        let answers: Vec<_> = (0..100).map(|i| a_record("example.com", i)).collect();
+        let response = write_response(&Header::default(), &[], &answers, MAX_UDP_MESSAGE_SIZE);
+        assert!(response.len() <= MAX_UDP_MESSAGE_SIZE);
+
+        let mut packet = [0u8; 512];
+        packet[..response.len()].copy_from_slice(&response);
+        let header = DnsParser::new(&packet).parse_header().unwrap();
+        assert!(header.flags.truncation);
+        assert!(header.answer_count < 100);
+
+        let parsed = DnsParser::new(&packet).parse_answers().unwrap();
+        assert_eq!(parsed.len(), header.answer_count as usize);
+    }
+}
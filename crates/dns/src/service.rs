@@ -0,0 +1,262 @@
+//! Discovering the servers of a service through its SRV records.
+//! https://datatracker.ietf.org/doc/html/rfc2782
+
+use std::net::SocketAddr;
+
+use rand::Rng;
+
+use crate::{
+    error::ResolveError,
+    protocol::{answer::Answer, record_type::RecordType},
+    resolver::{
+        resolve_ip, resolve_ip_async, resolve_record, resolve_record_async, ResolveOptions,
+    },
+    upstream::UpstreamPool,
+};
+
+/// Resolves the SRV records of `service` for `domain`, eg. `_xmpp-client._tcp` and
+/// `example.com`, and the addresses of their targets, in the order connections should be
+/// attempted, see [`sort_srv`]. Targets that fail to resolve are skipped, and an empty list
+/// means the service is unavailable.
+///
+/// ```no_run
+/// use dns::{resolver::ResolveOptions, service::discover_service};
+///
+/// let upstreams = "1.1.1.1:53".into();
+/// let servers = discover_service("_xmpp-client._tcp", "example.com", &upstreams, &ResolveOptions::default());
+/// ```
+pub fn discover_service(
+    service: &str,
+    domain: &str,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    let records = resolve_record(
+        &format!("{service}.{domain}"),
+        RecordType::SRV,
+        upstreams,
+        opts,
+    )?;
+    let (mut addresses, mut error) = (vec![], None);
+    for (target, port) in targets(records) {
+        match resolve_ip(&target, upstreams, opts) {
+            Ok(ips) => addresses.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port))),
+            Err(e) => error = Some(e),
+        }
+    }
+    collect(addresses, error)
+}
+
+/// Asynchronously discovers the servers of `service` for `domain`, see [`discover_service`]
+pub async fn discover_service_async(
+    service: &str,
+    domain: &str,
+    upstreams: &UpstreamPool,
+    opts: &ResolveOptions,
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    let name = format!("{service}.{domain}");
+    let records = resolve_record_async(&name, RecordType::SRV, upstreams, opts).await?;
+    let (mut addresses, mut error) = (vec![], None);
+    for (target, port) in targets(records) {
+        match resolve_ip_async(&target, upstreams, opts).await {
+            Ok(ips) => addresses.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port))),
+            Err(e) => error = Some(e),
+        }
+    }
+    collect(addresses, error)
+}
+
+/// Targets and ports of the sorted SRV records, none if the service is decidedly not available
+fn targets(records: Vec<Answer>) -> Vec<(String, u16)> {
+    sort_srv(records)
+        .into_iter()
+        .filter_map(|record| match record {
+            Answer::SRV { target, port, .. } if !target.is_root() => {
+                Some((target.as_str().to_string(), port))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Only fails if no target could be resolved
+fn collect(
+    addresses: Vec<SocketAddr>,
+    error: Option<ResolveError>,
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    match error {
+        Some(e) if addresses.is_empty() => Err(e),
+        _ => Ok(addresses),
+    }
+}
+
+/// Orders SRV records by priority, and randomly within the same priority so that each record is
+/// picked first with a probability proportional to its weight. Other records are dropped.
+/// https://datatracker.ietf.org/doc/html/rfc2782#page-3
+pub fn sort_srv(records: Vec<Answer>) -> Vec<Answer> {
+    sort_srv_with(records, &mut rand::thread_rng())
+}
+
+fn sort_srv_with(records: Vec<Answer>, rng: &mut impl Rng) -> Vec<Answer> {
+    let mut records: Vec<(u16, u16, Answer)> = records
+        .into_iter()
+        .filter_map(|record| match record {
+            Answer::SRV {
+                priority, weight, ..
+            } => Some((priority, weight, record)),
+            _ => None,
+        })
+        .collect();
+    // records without weight go first, so they have a chance of being picked at all
+    records.sort_by_key(|&(priority, weight, _)| (priority, weight != 0));
+
+    let mut sorted = Vec::with_capacity(records.len());
+    while let Some(&(priority, ..)) = records.first() {
+        let len = records
+            .iter()
+            .take_while(|&&(other, ..)| other == priority)
+            .count();
+        let mut group: Vec<_> = records.drain(..len).collect();
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|&(_, weight, _)| weight as u32).sum();
+            let pick = rng.gen_range(0..=total);
+            let mut running = 0;
+            let position = group
+                .iter()
+                .position(|&(_, weight, _)| {
+                    running += weight as u32;
+                    running >= pick
+                })
+                .expect("pick is at most the total weight");
+            sorted.push(group.remove(position).2);
+        }
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            record_type::RecordType,
+        },
+        resolver::ResolveOptions,
+        transport::{DnsTransport, Transport},
+    };
+
+    use super::{discover_service, discover_service_async, sort_srv_with};
+
+    fn srv(priority: u16, weight: u16, target: &str) -> Answer {
+        Answer::SRV {
+            meta: AnswerMeta {
+                name: "_xmpp-client._tcp.example.com".parse().unwrap(),
+                r#type: RecordType::SRV,
+                class: Class::IN,
+                ttl: 60,
+                len: 0,
+            },
+            priority,
+            weight,
+            port: 5222,
+            target: target.parse().unwrap(),
+        }
+    }
+
+    fn target(record: &Answer) -> &str {
+        match record {
+            Answer::SRV { target, .. } => target.as_str(),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_sort_srv() {
+        let records = vec![
+            srv(20, 0, "backup.example.com"),
+            srv(10, 0, "never.example.com"),
+            srv(10, 90, "big.example.com"),
+            srv(10, 10, "small.example.com"),
+        ];
+        let mut rng = StdRng::seed_from_u64(2782);
+        let mut first_big = 0;
+        for _ in 0..1000 {
+            let sorted = sort_srv_with(records.clone(), &mut rng);
+            let targets: Vec<_> = sorted.iter().map(target).collect();
+            assert_eq!(targets.len(), 4);
+            assert_eq!(targets[3], "backup.example.com");
+            first_big += (targets[0] == "big.example.com") as usize;
+        }
+        // picked first with a probability of 90 in 101
+        assert!((830..950).contains(&first_big), "{first_big}");
+    }
+
+    #[derive(Debug)]
+    struct Service;
+
+    impl DnsTransport for Service {
+        fn exchange(
+            &self,
+            query: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            let mut response = DnsParser::new(query).parse_packet()?;
+            response.header.flags.query = false;
+            let question = &response.questions[0];
+            let meta = AnswerMeta {
+                name: question.domain_name.clone(),
+                r#type: question.r#type,
+                class: Class::IN,
+                ttl: 60,
+                len: 0,
+            };
+            response.answers = match (question.domain_name.as_str(), question.r#type) {
+                ("_xmpp-client._tcp.example.com", RecordType::SRV) => vec![
+                    srv(10, 0, "xmpp.example.com"),
+                    srv(20, 0, "missing.example.com"),
+                    srv(30, 0, "xmpp6.example.com"),
+                ],
+                ("xmpp.example.com", RecordType::A) => vec![Answer::A {
+                    meta,
+                    ipv4: [192, 0, 2, 1].into(),
+                }],
+                ("xmpp6.example.com", RecordType::AAAA) => vec![Answer::AAAA {
+                    meta,
+                    ipv6: "2001:db8::1".parse()?,
+                }],
+                ("_imap._tcp.example.com", RecordType::SRV) => vec![srv(0, 0, ".")],
+                _ => vec![],
+            };
+            Ok(response.to_bytes())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discover_service() {
+        let opts = ResolveOptions::default().transport(Transport::Custom(Arc::new(Service)));
+        let upstreams = "127.0.0.1:9".into();
+        let expected: Vec<SocketAddr> = ["192.0.2.1:5222", "[2001:db8::1]:5222"]
+            .map(|address| address.parse().unwrap())
+            .to_vec();
+        assert_eq!(
+            discover_service("_xmpp-client._tcp", "example.com", &upstreams, &opts).unwrap(),
+            expected
+        );
+        assert_eq!(
+            discover_service_async("_xmpp-client._tcp", "example.com", &upstreams, &opts)
+                .await
+                .unwrap(),
+            expected
+        );
+        assert!(
+            discover_service("_imap._tcp", "example.com", &upstreams, &opts)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Message framing for DNS over TCP, where every message is prefixed with its length as a
+//! two byte integer.
+//! https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2
+//! https://datatracker.ietf.org/doc/html/rfc7766#section-8
+
+use std::io::{Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Reads the next length-prefixed DNS message from `reader`, blocking until it was fully received
+pub fn read_tcp_message(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len)?;
+
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Writes `message` to `writer` with its length prefix
+pub fn write_tcp_message(writer: &mut impl Write, message: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&frame(message)?)?;
+    writer.flush()
+}
+
+/// Asynchronously reads the next length-prefixed DNS message from `reader`
+pub async fn read_tcp_message_async(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Asynchronously writes `message` to `writer` with its length prefix
+pub async fn write_tcp_message_async(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &[u8],
+) -> std::io::Result<()> {
+    writer.write_all(&frame(message)?).await?;
+    writer.flush().await
+}
+
+/// Size of the chunks read from the underlying stream
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Yields complete DNS messages from a stream of pipelined, length-prefixed TCP messages.
+///
+/// Unlike [`read_tcp_message`], reads are done in larger chunks and buffered, so several messages
+/// arriving in a single segment as well as messages split across segments are handled.
+/// Iteration ends when the stream is closed between two messages.
+#[derive(Debug)]
+pub struct MessageStream<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R> MessageStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::with_capacity(READ_CHUNK_SIZE),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Number of buffered bytes that do not yet form a complete message
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl<R: Read> Iterator for MessageStream<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            if let Some(message) = take_message(&mut self.buffer) {
+                return Some(Ok(message));
+            }
+
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return end_of_stream(&self.buffer),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> MessageStream<R> {
+    /// Asynchronously waits for the next complete message, returning `None` once the stream was
+    /// closed between two messages
+    pub async fn next_message(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            if let Some(message) = take_message(&mut self.buffer) {
+                return Some(Ok(message));
+            }
+
+            match self.reader.read(&mut chunk).await {
+                Ok(0) => return end_of_stream(&self.buffer),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Removes the first complete message from `buffer`, if there is one
+fn take_message(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buffer.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if buffer.len() < len + 2 {
+        return None;
+    }
+
+    let message = buffer[2..len + 2].to_vec();
+    buffer.drain(..len + 2);
+    Some(message)
+}
+
+fn end_of_stream(buffer: &[u8]) -> Option<std::io::Result<Vec<u8>>> {
+    if buffer.is_empty() {
+        None
+    } else {
+        Some(Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "stream closed with {} bytes of an incomplete message",
+                buffer.len()
+            ),
+        )))
+    }
+}
+
+/// Prepends the length to `message`, so both go out in a single segment if possible
+fn frame(message: &[u8]) -> std::io::Result<Vec<u8>> {
+    let len: u16 = message.len().try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "DNS message of {} bytes is too large for TCP",
+                message.len()
+            ),
+        )
+    })?;
+
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+    Ok(framed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{read_tcp_message, read_tcp_message_async, write_tcp_message, MessageStream};
+
+    /// Yields at most one byte per `read` call to simulate fragmented TCP segments
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_tcp_message_roundtrip() {
+        let mut wire = vec![];
+        write_tcp_message(&mut wire, &[1, 2, 3]).unwrap();
+        write_tcp_message(&mut wire, &[4; 300]).unwrap();
+        assert_eq!(&wire[0..5], &[0, 3, 1, 2, 3]);
+
+        let mut reader = Trickle(&wire);
+        assert_eq!(read_tcp_message(&mut reader).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_tcp_message(&mut reader).unwrap(), vec![4; 300]);
+        assert!(read_tcp_message(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_tcp_message_too_large() {
+        let mut wire = vec![];
+        assert!(write_tcp_message(&mut wire, &[0; 70_000]).is_err());
+        assert!(wire.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_message_async() {
+        let wire = [0u8, 2, 0xAB, 0xCD, 0, 1];
+        let mut reader = &wire[..];
+        assert_eq!(
+            read_tcp_message_async(&mut reader).await.unwrap(),
+            vec![0xAB, 0xCD]
+        );
+        // the second message is announced as one byte long, but was cut off
+        assert!(read_tcp_message_async(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn test_message_stream_pipelined() {
+        let mut wire = vec![];
+        for i in 0..10u8 {
+            write_tcp_message(&mut wire, &vec![i; i as usize * 100]).unwrap();
+        }
+
+        // everything arrives at once
+        let messages: Vec<_> = MessageStream::new(&wire[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(messages.len(), 10);
+        assert_eq!(messages[7], vec![7; 700]);
+
+        // byte by byte
+        let messages: Vec<_> = MessageStream::new(Trickle(&wire))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(messages.len(), 10);
+        assert_eq!(messages[0], Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_message_stream_incomplete() {
+        let wire = [0u8, 2, 1, 2, 0, 5, 1];
+        let mut stream = MessageStream::new(&wire[..]);
+        assert_eq!(stream.next().unwrap().unwrap(), vec![1, 2]);
+        assert!(stream.next().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_message_stream_async() {
+        let (mut client, server) = tokio::io::duplex(8);
+        let writer = tokio::spawn(async move {
+            for i in 0..3u8 {
+                super::write_tcp_message_async(&mut client, &[i; 20])
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut stream = MessageStream::new(server);
+        for i in 0..3u8 {
+            assert_eq!(stream.next_message().await.unwrap().unwrap(), vec![i; 20]);
+        }
+        writer.await.unwrap();
+        assert!(stream.next_message().await.is_none());
+    }
+}
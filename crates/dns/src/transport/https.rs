@@ -0,0 +1,763 @@
+//! DNS over HTTPS client, speaking just enough HTTP/1.1 to exchange DNS messages, and to
+//! download files like blocklists.
+//! https://datatracker.ietf.org/doc/html/rfc8484
+
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::opt::{pad_message, QUERY_PADDING_BLOCK};
+
+use super::{
+    split_authority,
+    tls::{connect, connect_async, web_pki_config, with_timeout, AsyncTlsStream, TlsStream},
+    BoxFuture, DnsTransport, IdleConnections, DEFAULT_TIMEOUT,
+};
+
+pub const DOH_PORT: u16 = 443;
+
+/// Media type of DNS messages in wire format
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Largest HTTP header section that is accepted
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Largest body of a DoH response, the size limit of DNS messages
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Largest body of a download with [`get_async`]
+const MAX_DOWNLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// How a query is encoded into the HTTP request
+/// https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DohMethod {
+    /// The query is the request body
+    #[default]
+    Post,
+    /// The query is base64url encoded into the `dns` URL parameter, which makes responses
+    /// cacheable by HTTP caches
+    Get,
+}
+
+/// Sends queries to a single DNS over HTTPS endpoint, eg. `https://cloudflare-dns.com/dns-query`.
+///
+/// Like [`super::tls::DotTransport`], connections are kept alive and reused for subsequent
+/// queries if the server allows it, concurrent queries each use their own one, and queries are
+/// padded the same way.
+#[derive(Debug)]
+pub struct DohTransport {
+    /// `host:port` to connect to
+    address: String,
+    /// Value of the Host header
+    authority: String,
+    /// Path and query of the endpoint
+    path: String,
+    method: DohMethod,
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    /// Timeout of queries sent through [`DnsTransport`]
+    timeout: Duration,
+    connections: IdleConnections<TlsStream>,
+    async_connections: IdleConnections<AsyncTlsStream>,
+}
+
+impl DohTransport {
+    /// Creates a transport for the endpoint at `url`, verifying its certificate against the
+    /// bundled Mozilla root certificates
+    pub fn new(
+        url: &str,
+        method: DohMethod,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Self::with_tls_config(url, method, doh_config()?)
+    }
+
+    /// Creates a transport for the endpoint at `url` with a custom TLS configuration, eg. one
+    /// trusting a private CA
+    pub fn with_tls_config(
+        url: &str,
+        method: DohMethod,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| format!("{url} is not an https URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, address) = split_authority(authority, DOH_PORT);
+
+        Ok(Self {
+            address,
+            authority: authority.to_string(),
+            path: path.to_string(),
+            method,
+            server_name: ServerName::try_from(host.to_string())?,
+            config,
+            timeout: DEFAULT_TIMEOUT,
+            connections: IdleConnections::default(),
+            async_connections: IdleConnections::default(),
+        })
+    }
+
+    /// Sets how long queries sent through [`DnsTransport`] wait for connecting as well as for
+    /// the response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the process wide transport for this endpoint, so its connections are shared by
+    /// all queries to it
+    pub fn shared(
+        url: &str,
+        method: DohMethod,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        type Key = (String, DohMethod);
+        static TRANSPORTS: OnceLock<Mutex<HashMap<Key, Arc<DohTransport>>>> = OnceLock::new();
+
+        let key = (url.to_string(), method);
+        let mut transports = TRANSPORTS.get_or_init(Default::default).lock().unwrap();
+        if let Some(transport) = transports.get(&key) {
+            return Ok(Arc::clone(transport));
+        }
+        let transport = Arc::new(Self::new(url, method)?);
+        transports.insert(key, Arc::clone(&transport));
+        Ok(transport)
+    }
+
+    /// Sends the raw `query` and waits at most `timeout` for connecting as well as for the
+    /// response
+    pub fn exchange(
+        &self,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = self.request(query);
+        if let Some(mut stream) = self.connections.take() {
+            // otherwise most likely the server closed the idle connection, so start over
+            if let Ok(response) = send_on(&mut stream, &request, timeout) {
+                if response.keep_alive {
+                    self.connections.put(stream);
+                }
+                return response.into_dns_message(query);
+            }
+        }
+
+        let mut stream = connect(&self.address, &self.server_name, &self.config, timeout)?;
+        let response = send_on(&mut stream, &request, timeout)?;
+        if response.keep_alive {
+            self.connections.put(stream);
+        }
+        response.into_dns_message(query)
+    }
+
+    /// Asynchronously sends the raw `query`, see [`DohTransport::exchange`]
+    pub async fn exchange_async(
+        &self,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = self.request(query);
+        if let Some(mut stream) = self.async_connections.take() {
+            if let Ok(response) = with_timeout(timeout, send_on_async(&mut stream, &request)).await
+            {
+                if response.keep_alive {
+                    self.async_connections.put(stream);
+                }
+                return response.into_dns_message(query);
+            }
+        }
+
+        let connecting = connect_async(&self.address, &self.server_name, &self.config);
+        let mut stream = with_timeout(timeout, connecting).await?;
+        let response = with_timeout(timeout, send_on_async(&mut stream, &request)).await?;
+        if response.keep_alive {
+            self.async_connections.put(stream);
+        }
+        response.into_dns_message(query)
+    }
+
+    /// Builds the HTTP request carrying `query`, padded and with its ID set to 0 as recommended
+    /// by https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
+    fn request(&self, query: &[u8]) -> Vec<u8> {
+        let mut query = pad_message(query, QUERY_PADDING_BLOCK);
+        if query.len() >= 2 {
+            query[0..2].copy_from_slice(&[0, 0]);
+        }
+
+        let mut request = match self.method {
+            DohMethod::Post => format!(
+                "POST {} HTTP/1.1\r\nContent-Type: {DNS_MESSAGE}\r\nContent-Length: {}\r\n",
+                self.path,
+                query.len()
+            ),
+            DohMethod::Get => {
+                let separator = if self.path.contains('?') { '&' } else { '?' };
+                format!(
+                    "GET {}{separator}dns={} HTTP/1.1\r\n",
+                    self.path,
+                    URL_SAFE_NO_PAD.encode(&query)
+                )
+            }
+        };
+        request.push_str(&format!(
+            "Host: {}\r\nAccept: {DNS_MESSAGE}\r\n\r\n",
+            self.authority
+        ));
+
+        let mut request = request.into_bytes();
+        if self.method == DohMethod::Post {
+            request.extend_from_slice(&query);
+        }
+        request
+    }
+}
+
+impl DnsTransport for DohTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        self.exchange(query, self.timeout)
+    }
+
+    fn exchange_async<'a>(
+        &'a self,
+        query: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(self.exchange_async(query, self.timeout))
+    }
+}
+
+/// TLS config with ALPN set up for HTTP/1.1, shared by all transports
+fn doh_config() -> Result<Arc<ClientConfig>, rustls::Error> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(Arc::clone(config));
+    }
+
+    let mut config = (*web_pki_config()?).clone();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::clone(CONFIG.get_or_init(|| Arc::new(config))))
+}
+
+fn send_on(
+    stream: &mut TlsStream,
+    request: &[u8],
+    timeout: Duration,
+) -> std::io::Result<HttpResponse> {
+    stream.sock.set_read_timeout(Some(timeout))?;
+    stream.sock.set_write_timeout(Some(timeout))?;
+    stream.write_all(request)?;
+    stream.flush()?;
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk)?;
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(response) = parse_response(&buffer, n == 0, MAX_MESSAGE_SIZE)? {
+            return Ok(response);
+        }
+    }
+}
+
+async fn send_on_async(
+    stream: &mut AsyncTlsStream,
+    request: &[u8],
+) -> std::io::Result<HttpResponse> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(response) = parse_response(&buffer, n == 0, MAX_MESSAGE_SIZE)? {
+            return Ok(response);
+        }
+    }
+}
+
+/// Downloads `url`, which is either `https://` or `http://`, with a GET request carrying the
+/// extra `headers`, and waits at most `timeout` for all of it. Meant for files like blocklists,
+/// the response is returned whatever its status.
+pub(crate) async fn get_async(
+    url: &str,
+    headers: &[(&str, &str)],
+    timeout: Duration,
+) -> std::io::Result<HttpResponse> {
+    let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+        (Some(rest), _) => (true, rest),
+        (None, Some(rest)) => (false, rest),
+        _ => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{url} is not an http or https URL"),
+            ))
+        }
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, address) = split_authority(authority, if tls { DOH_PORT } else { 80 });
+
+    let mut request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n\
+         Accept-Encoding: identity\r\n"
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    with_timeout(timeout, async {
+        let response = if tls {
+            let server_name = ServerName::try_from(host.to_string())
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+            let config = web_pki_config().map_err(std::io::Error::other)?;
+            let stream = connect_async(&address, &server_name, &config).await?;
+            read_until_closed(stream, request.as_bytes()).await?
+        } else {
+            let stream = tokio::net::TcpStream::connect(&address).await?;
+            read_until_closed(stream, request.as_bytes()).await?
+        };
+        parse_response(&response, true, MAX_DOWNLOAD_SIZE)?
+            .ok_or_else(|| invalid("incomplete response"))
+    })
+    .await
+}
+
+/// Sends `request` and reads everything the server sends until it closes the connection
+async fn read_until_closed(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        let n = match stream.read(&mut chunk).await {
+            Ok(n) => n,
+            // many servers close TLS connections without a close_notify alert
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            return Ok(buffer);
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if buffer.len() > MAX_HEADER_SIZE + MAX_DOWNLOAD_SIZE {
+            return Err(invalid("body too large"));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    content_type: Option<String>,
+    keep_alive: bool,
+    /// Values of all header fields, by their lowercase names
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Checks that this is a successful DoH response and returns the contained DNS message with
+    /// the ID of `query`
+    fn into_dns_message(
+        self,
+        query: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        if self.status != 200 {
+            return Err(
+                format!("DNS over HTTPS server responded with HTTP {}", self.status).into(),
+            );
+        }
+        if self.content_type.as_deref() != Some(DNS_MESSAGE) {
+            return Err(format!(
+                "DNS over HTTPS server responded with content type {:?}",
+                self.content_type
+            )
+            .into());
+        }
+
+        let mut message = self.body;
+        if query.len() >= 2 && message.len() >= 2 {
+            message[0..2].copy_from_slice(&query[0..2]);
+        }
+        Ok(message)
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid HTTP response: {message}"),
+    )
+}
+
+/// Parses a complete HTTP/1.x response from `buffer`, returning `None` if more data is needed.
+/// `eof` tells whether the connection was closed after the data in `buffer`. Bodies larger than
+/// `max_body` are rejected.
+fn parse_response(
+    buffer: &[u8],
+    eof: bool,
+    max_body: usize,
+) -> std::io::Result<Option<HttpResponse>> {
+    let Some(header_end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if eof {
+            return Err(invalid("connection closed within the header"));
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(invalid("header too large"));
+        }
+        return Ok(None);
+    };
+
+    let header = String::from_utf8_lossy(&buffer[..header_end]);
+    let mut lines = header.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let mut status_parts = status_line.split_whitespace();
+    let version = status_parts.next().unwrap_or_default();
+    let status: u16 = status_parts
+        .next()
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("not HTTP/1.x"));
+    }
+
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let connection = headers
+        .get("connection")
+        .map(|value| value.to_ascii_lowercase());
+    let keep_alive = match version {
+        "HTTP/1.0" => connection.as_deref() == Some("keep-alive"),
+        _ => connection.as_deref() != Some("close"),
+    };
+
+    let content = &buffer[header_end + 4..];
+    let chunked = headers
+        .get("transfer-encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    let body = if chunked {
+        match decode_chunked(content, max_body)? {
+            Some(body) => body,
+            None if eof => return Err(invalid("connection closed within the body")),
+            None => return Ok(None),
+        }
+    } else if let Some(len) = headers.get("content-length") {
+        let len: usize = len
+            .parse()
+            .map_err(|_| invalid("malformed Content-Length"))?;
+        if len > max_body {
+            return Err(invalid("body too large"));
+        }
+        match content.get(..len) {
+            Some(body) => body.to_vec(),
+            None if eof => return Err(invalid("connection closed within the body")),
+            None => return Ok(None),
+        }
+    } else {
+        // the body is delimited by the end of the connection
+        if !eof {
+            return Ok(None);
+        }
+        if content.len() > max_body {
+            return Err(invalid("body too large"));
+        }
+        content.to_vec()
+    };
+
+    Ok(Some(HttpResponse {
+        status,
+        content_type: headers.get("content-type").map(|value| {
+            // ignore parameters like "; charset=..."
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        }),
+        keep_alive: keep_alive && (chunked || headers.contains_key("content-length")),
+        headers,
+        body,
+    }))
+}
+
+/// Decodes a body with chunked transfer encoding, returning `None` if it is not complete yet
+fn decode_chunked(mut content: &[u8], max_body: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    loop {
+        let Some(line_end) = content.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        let size_line = String::from_utf8_lossy(&content[..line_end]);
+        // chunk extensions after ';' are ignored
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("malformed chunk size"))?;
+        if body.len() + size > max_body {
+            return Err(invalid("body too large"));
+        }
+        content = &content[line_end + 2..];
+
+        if size == 0 {
+            // skip trailers until the final empty line
+            return Ok(content.windows(2).position(|w| w == b"\r\n").map(|_| body));
+        }
+        let Some(chunk) = content.get(..size + 2) else {
+            return Ok(None);
+        };
+        body.extend_from_slice(&chunk[..size]);
+        content = &content[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use rustls::{
+        pki_types::PrivateKeyDer, ClientConfig, RootCertStore, ServerConfig, ServerConnection,
+        StreamOwned,
+    };
+
+    use super::{parse_response, DohMethod, DohTransport, MAX_MESSAGE_SIZE};
+
+    /// Starts an HTTPS server with a self-signed certificate for `localhost` that responds to DoH
+    /// requests by echoing the query, chunked if `chunked` is set, but never to queries whose
+    /// third byte is 0xfe. Returns the endpoint URL, a
+    /// client config trusting the server and a counter of accepted connections.
+    fn mock_server(chunked: bool) -> (String, Arc<ClientConfig>, Arc<AtomicUsize>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = Arc::new(
+            ClientConfig::builder_with_provider(super::super::tls::provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+
+        let key = PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+        let server_config = Arc::new(
+            ServerConfig::builder_with_provider(super::super::tls::provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![certified.cert.der().clone()], key)
+                .unwrap(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            for tcp in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let tls = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+                let mut stream = StreamOwned::new(tls, tcp.unwrap());
+                std::thread::spawn(move || {
+                    while let Some(query) = read_request(&mut stream) {
+                        if query.get(2) == Some(&0xfe) {
+                            continue;
+                        }
+                        let response = if chunked {
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\
+                                 Transfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                                query.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(&query);
+                            response.extend_from_slice(b"\r\n0\r\n\r\n");
+                            response
+                        } else {
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n\
+                                 Content-Length: {}\r\n\r\n",
+                                query.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(&query);
+                            response
+                        };
+                        stream.write_all(&response).unwrap();
+                        stream.flush().unwrap();
+                    }
+                });
+            }
+        });
+        (
+            format!("https://localhost:{port}/dns-query"),
+            client_config,
+            connections,
+        )
+    }
+
+    /// Reads a DoH request and returns the DNS query it carries
+    fn read_request(stream: &mut impl Read) -> Option<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut byte = [0u8];
+        while !buffer.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte).ok()? == 0 {
+                return None;
+            }
+            buffer.push(byte[0]);
+        }
+        let header = String::from_utf8(buffer).unwrap();
+        assert!(header.contains("Accept: application/dns-message"));
+
+        if let Some(rest) = header.strip_prefix("GET /dns-query?dns=") {
+            let encoded = rest.split_whitespace().next().unwrap();
+            return Some(URL_SAFE_NO_PAD.decode(encoded).unwrap());
+        }
+        assert!(header.starts_with("POST /dns-query HTTP/1.1"));
+        let len: usize = header
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut query = vec![0; len];
+        stream.read_exact(&mut query).ok()?;
+        Some(query)
+    }
+
+    #[test]
+    fn test_doh_post() {
+        let (url, config, connections) = mock_server(false);
+        let transport = DohTransport::with_tls_config(&url, DohMethod::Post, config).unwrap();
+
+        for i in 1..4u8 {
+            let query = [0, i, 1, 2, 3];
+            let response = transport.exchange(&query, Duration::from_secs(5)).unwrap();
+            assert_eq!(response, query);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_doh_get_async() {
+        let (url, config, connections) = mock_server(true);
+        let transport = DohTransport::with_tls_config(&url, DohMethod::Get, config).unwrap();
+
+        for i in 1..4u8 {
+            let query = [0, i, 0xFB, 0xFF];
+            let response = transport
+                .exchange_async(&query, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(response, query);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_doh_concurrent_queries() {
+        let (url, config, connections) = mock_server(false);
+        let transport = DohTransport::with_tls_config(&url, DohMethod::Post, config).unwrap();
+
+        // queries do not wait for the response to another one
+        let unanswered = transport.exchange_async(&[0, 1, 0xfe], Duration::from_secs(1));
+        let answered = tokio::time::timeout(
+            Duration::from_millis(500),
+            transport.exchange_async(&[0, 2, 1], Duration::from_secs(5)),
+        );
+        let (unanswered, answered) = tokio::join!(unanswered, answered);
+        assert!(unanswered.is_err());
+        assert_eq!(answered.unwrap().unwrap(), [0, 2, 1]);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // the connection of the timed out query is not reused
+        let response = transport.exchange_async(&[0, 3, 1], Duration::from_secs(5));
+        assert_eq!(response.await.unwrap(), [0, 3, 1]);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_doh_url() {
+        let transport = DohTransport::new("https://dns.google/dns-query", DohMethod::Get).unwrap();
+        assert_eq!(transport.address, "dns.google:443");
+        assert_eq!(transport.authority, "dns.google");
+        assert_eq!(transport.path, "/dns-query");
+
+        let transport = DohTransport::new("https://[::1]:8443/q?ct", DohMethod::Get).unwrap();
+        assert_eq!(transport.address, "[::1]:8443");
+        let request = String::from_utf8(transport.request(&[0xAB, 0xCD, 1])).unwrap();
+        assert!(request.starts_with("GET /q?ct&dns=AAAB HTTP/1.1\r\nHost: [::1]:8443\r\n"));
+
+        assert!(DohTransport::new("http://dns.google/dns-query", DohMethod::Post).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = b"HTTP/1.1 200 OK\r\ncontent-type: application/dns-message; charset=x\r\nContent-Length: 3\r\n\r\nabc";
+        for len in 0..response.len() {
+            assert_eq!(
+                parse_response(&response[..len], false, MAX_MESSAGE_SIZE).unwrap(),
+                None
+            );
+            assert!(parse_response(&response[..len], true, MAX_MESSAGE_SIZE).is_err());
+        }
+        let parsed = parse_response(response, false, MAX_MESSAGE_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.status, 200);
+        assert_eq!(
+            parsed.content_type.as_deref(),
+            Some("application/dns-message")
+        );
+        assert!(parsed.keep_alive);
+        assert_eq!(parsed.body, b"abc");
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n2\r\nab\r\n1;ext\r\nc\r\n0\r\n\r\n";
+        let parsed = parse_response(chunked, false, MAX_MESSAGE_SIZE)
+            .unwrap()
+            .unwrap();
+        assert!(!parsed.keep_alive);
+        assert_eq!(parsed.body, b"abc");
+        assert_eq!(
+            parse_response(&chunked[..chunked.len() - 2], false, MAX_MESSAGE_SIZE).unwrap(),
+            None
+        );
+
+        let until_close = b"HTTP/1.0 404 Not Found\r\n\r\nnope";
+        assert_eq!(
+            parse_response(until_close, false, MAX_MESSAGE_SIZE).unwrap(),
+            None
+        );
+        let parsed = parse_response(until_close, true, MAX_MESSAGE_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.status, 404);
+        assert!(parsed.into_dns_message(&[]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! The protocols queries can be sent to upstream servers with, behind the [`DnsTransport`] trait.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub mod https;
+pub mod plain;
+#[cfg(feature = "doq")]
+pub mod quic;
+pub mod tls;
+
+/// Timeout of queries sent through [`DnsTransport`], unless configured otherwise
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most idle connections kept open to a server, for the queries sent to it at the same time
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Future returned by [`DnsTransport::exchange_async`]
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Sends raw queries to an upstream server and returns its raw responses.
+///
+/// All transports of this crate implement it, and custom ones, eg. test doubles or transports
+/// tunneling through a proxy, can be plugged into the resolver with [`Transport::Custom`].
+pub trait DnsTransport: std::fmt::Debug + Send + Sync {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Asynchronously sends the raw `query`. Defaults to [`DnsTransport::exchange`], which blocks
+    /// the current thread, so transports doing IO should override it.
+    fn exchange_async<'a>(
+        &'a self,
+        query: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move { self.exchange(query) })
+    }
+}
+
+/// How queries are sent to upstream servers
+#[derive(Debug, Default, Clone)]
+pub enum Transport {
+    /// Plain DNS over UDP, repeated over TCP if the response was truncated
+    #[default]
+    Udp,
+    /// DNS over TLS, see [`tls::DotTransport`]
+    Tls {
+        /// Name the certificate of the server is verified against, also sent as SNI
+        host: String,
+        /// Base64 encoded SHA-256 hash of the server's SubjectPublicKeyInfo. If set, the server is
+        /// authenticated by this pin alone instead of the web PKI.
+        /// https://datatracker.ietf.org/doc/html/rfc7858#section-4.2
+        spki_pin: Option<String>,
+    },
+    /// DNS over HTTPS, see [`https::DohTransport`]. The upstream server has to be given as
+    /// `https://` URL of the endpoint. Such URLs are always queried over HTTPS, with
+    /// [`https::DohMethod::Post`] unless this transport selects otherwise.
+    Https { method: https::DohMethod },
+    /// Sends all queries through the given transport, ignoring the upstream server they are
+    /// meant for
+    Custom(Arc<dyn DnsTransport>),
+}
+
+impl PartialEq for Transport {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Udp, Self::Udp) => true,
+            (
+                Self::Tls { host, spki_pin },
+                Self::Tls {
+                    host: other_host,
+                    spki_pin: other_pin,
+                },
+            ) => host == other_host && spki_pin == other_pin,
+            (Self::Https { method }, Self::Https { method: other }) => method == other,
+            (Self::Custom(transport), Self::Custom(other)) => Arc::ptr_eq(transport, other),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Transport {}
+
+/// Open connections to a server that no query is using. Each query takes one of them, or opens a
+/// new one if there is none, and puts it back once answered, so concurrent queries neither wait
+/// for each other nor share a connection.
+#[derive(Debug)]
+pub(crate) struct IdleConnections<T>(Mutex<Vec<T>>);
+
+impl<T> Default for IdleConnections<T> {
+    fn default() -> Self {
+        Self(Mutex::new(Vec::new()))
+    }
+}
+
+impl<T> IdleConnections<T> {
+    /// The connection used last, as it is the least likely to have been closed by the server
+    pub(crate) fn take(&self) -> Option<T> {
+        self.0.lock().unwrap().pop()
+    }
+
+    /// Keeps `connection` for later queries, unless enough are kept already
+    pub(crate) fn put(&self, connection: T) {
+        let mut idle = self.0.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+    }
+}
+
+/// Appends `port` to `address` unless it already contains one
+pub(crate) fn with_default_port(address: &str, port: u16) -> String {
+    if address.parse::<std::net::SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+    match address.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+        Ok(std::net::IpAddr::V4(ip)) => format!("{ip}:{port}"),
+        Err(_) if !address.contains(':') => format!("{address}:{port}"),
+        Err(_) => address.to_string(),
+    }
+}
+
+/// Splits the authority of a URL, ie. `host[:port]`, into the host and the address to connect
+/// to, which gets `port` if it has none
+pub(crate) fn split_authority(authority: &str, port: u16) -> (&str, String) {
+    let (host, address) = match authority.rsplit_once(':') {
+        // an IPv6 address without a port, ie. "[::1]"
+        Some((_, rest)) if rest.ends_with(']') => (authority, format!("{authority}:{port}")),
+        Some((host, _)) => (host, authority.to_string()),
+        None => (authority, with_default_port(authority, port)),
+    };
+    (host.trim_start_matches('[').trim_end_matches(']'), address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::with_default_port;
+
+    #[test]
+    fn test_with_default_port() {
+        assert_eq!(with_default_port("1.1.1.1", 853), "1.1.1.1:853");
+        assert_eq!(with_default_port("1.1.1.1:8853", 853), "1.1.1.1:8853");
+        assert_eq!(
+            with_default_port("2606:4700::1111", 853),
+            "[2606:4700::1111]:853"
+        );
+        assert_eq!(with_default_port("[::1]:53", 853), "[::1]:53");
+        assert_eq!(with_default_port("dns.quad9.net", 853), "dns.quad9.net:853");
+        assert_eq!(
+            with_default_port("dns.quad9.net:53", 853),
+            "dns.quad9.net:53"
+        );
+    }
+}
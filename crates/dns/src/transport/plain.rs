@@ -0,0 +1,115 @@
+//! Plain DNS over UDP and TCP as [`DnsTransport`]s, eg. to wrap them in custom transports.
+
+use crate::resolver::{
+    bind_query_socket, bind_query_socket_async, exchange_udp, exchange_udp_async,
+    resolve_query_tcp, resolve_query_tcp_async, ResolveOptions,
+};
+
+use super::{BoxFuture, DnsTransport};
+
+/// Sends queries to a single server over UDP, from a new socket for every query. Retries, timeouts
+/// and the fallback to TCP for truncated responses follow its [`ResolveOptions`].
+#[derive(Debug, Clone)]
+pub struct UdpTransport {
+    address: String,
+    opts: ResolveOptions,
+}
+
+impl UdpTransport {
+    pub fn new(address: &str, opts: ResolveOptions) -> Self {
+        Self {
+            address: address.to_string(),
+            opts,
+        }
+    }
+}
+
+impl DnsTransport for UdpTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let socket = bind_query_socket(&self.opts)?;
+        Ok(exchange_udp(query, &self.address, &socket, &self.opts)?)
+    }
+
+    fn exchange_async<'a>(
+        &'a self,
+        query: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let socket = bind_query_socket_async(&self.opts).await?;
+            Ok(exchange_udp_async(query, &self.address, &socket, &self.opts).await?)
+        })
+    }
+}
+
+/// Sends queries to a single server over a new TCP connection each, waiting at most
+/// `max_timeout` of its [`ResolveOptions`]
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    address: String,
+    opts: ResolveOptions,
+}
+
+impl TcpTransport {
+    pub fn new(address: &str, opts: ResolveOptions) -> Self {
+        Self {
+            address: address.to_string(),
+            opts,
+        }
+    }
+}
+
+impl DnsTransport for TcpTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(resolve_query_tcp(query, &self.address, &self.opts)?)
+    }
+
+    fn exchange_async<'a>(
+        &'a self,
+        query: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(
+            async move { Ok(resolve_query_tcp_async(query, &self.address, &self.opts).await?) },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, UdpSocket};
+
+    use crate::{
+        resolver::ResolveOptions,
+        tcp::{read_tcp_message, write_tcp_message},
+    };
+
+    use super::{DnsTransport, TcpTransport, UdpTransport};
+
+    #[tokio::test]
+    async fn test_plain_transports() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpListener::bind(udp.local_addr().unwrap()).unwrap();
+        let address = udp.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, client)) = udp.recv_from(&mut buf) {
+                udp.send_to(&buf[..len], client).unwrap();
+            }
+        });
+        std::thread::spawn(move || {
+            for stream in tcp.incoming() {
+                let mut stream = stream.unwrap();
+                let query = read_tcp_message(&mut stream).unwrap();
+                write_tcp_message(&mut stream, &query).unwrap();
+            }
+        });
+
+        let transports: [Box<dyn DnsTransport>; 2] = [
+            Box::new(UdpTransport::new(&address, ResolveOptions::default())),
+            Box::new(TcpTransport::new(&address, ResolveOptions::default())),
+        ];
+        for transport in transports {
+            assert_eq!(transport.exchange(&[1, 2, 3]).unwrap(), [1, 2, 3]);
+            assert_eq!(transport.exchange_async(&[4, 5]).await.unwrap(), [4, 5]);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! DNS over QUIC client, available with the `doq` feature.
+//! https://datatracker.ietf.org/doc/html/rfc9250
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint};
+
+use crate::protocol::opt::{pad_message, QUERY_PADDING_BLOCK};
+
+use super::{
+    split_authority,
+    tls::{web_pki_config, with_timeout},
+    BoxFuture, DnsTransport, DEFAULT_TIMEOUT,
+};
+
+pub const DOQ_PORT: u16 = 853;
+
+/// Application protocol negotiated with the server
+/// https://datatracker.ietf.org/doc/html/rfc9250#section-4.1.1
+pub const DOQ_ALPN: &[u8] = b"doq";
+
+/// Sends queries to a single DNS over QUIC server, given as `quic://host[:port]`.
+///
+/// Each query is sent on a stream of its own, so queries don't block each other, and the
+/// connection is reused by all of them until it is closed. Connections are driven by a runtime
+/// owned by this module, which lets them outlive the runtime of the caller and makes
+/// [`DoqTransport::exchange`] usable without one. Queries are padded with [`pad_message`].
+#[derive(Debug)]
+pub struct DoqTransport {
+    /// `host:port` to connect to
+    address: String,
+    host: String,
+    config: ClientConfig,
+    /// Timeout of queries sent through [`DnsTransport`]
+    timeout: Duration,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl DoqTransport {
+    /// Creates a transport for the server at `url`, verifying its certificate against the bundled
+    /// Mozilla root certificates
+    pub fn new(url: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Self::with_tls_config(url, web_pki_config()?)
+    }
+
+    /// Creates a transport for the server at `url` with a custom TLS configuration, eg. one
+    /// trusting a private CA. Its ALPN protocols are replaced with the one of DoQ.
+    pub fn with_tls_config(
+        url: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let authority = url
+            .strip_prefix("quic://")
+            .ok_or_else(|| format!("{url} is not a quic URL"))?
+            .trim_end_matches('/');
+        let (host, address) = split_authority(authority, DOQ_PORT);
+
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(config)?));
+
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            config,
+            timeout: DEFAULT_TIMEOUT,
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Sets how long queries sent through [`DnsTransport`] wait for connecting as well as for
+    /// the response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the process wide transport for this server, so its connection is shared by all
+    /// queries to it
+    pub fn shared(url: &str) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        static TRANSPORTS: OnceLock<Mutex<HashMap<String, Arc<DoqTransport>>>> = OnceLock::new();
+
+        let mut transports = TRANSPORTS.get_or_init(Default::default).lock().unwrap();
+        if let Some(transport) = transports.get(url) {
+            return Ok(Arc::clone(transport));
+        }
+        let transport = Arc::new(Self::new(url)?);
+        transports.insert(url.to_string(), Arc::clone(&transport));
+        Ok(transport)
+    }
+
+    /// Sends the raw `query` and waits at most `timeout` for connecting as well as for the
+    /// response. Blocks the current thread, so it must not be called from async code.
+    pub fn exchange(
+        &self,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        runtime().block_on(self.exchange_async(query, timeout))
+    }
+
+    /// Asynchronously sends the raw `query`, see [`DoqTransport::exchange`]
+    pub async fn exchange_async(
+        &self,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let query = &pad_message(query, QUERY_PADDING_BLOCK);
+        let (connection, reused) = self.connection(timeout).await?;
+        match with_timeout(timeout, exchange_on(&connection, query)).await {
+            Ok(response) => Ok(response),
+            // the server closed the idle connection, so start over
+            Err(_) if reused && connection.close_reason().is_some() => {
+                let (connection, _) = self.connection(timeout).await?;
+                Ok(with_timeout(timeout, exchange_on(&connection, query)).await?)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the open connection to the server, or establishes a new one. Also tells whether
+    /// the connection was reused.
+    async fn connection(&self, timeout: Duration) -> std::io::Result<(Connection, bool)> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection.as_ref().filter(|c| c.close_reason().is_none()) {
+            return Ok((open.clone(), true));
+        }
+
+        let connecting = runtime().spawn(connect(
+            self.address.clone(),
+            self.host.clone(),
+            self.config.clone(),
+        ));
+        let open = with_timeout(timeout, async {
+            connecting.await.map_err(std::io::Error::other)?
+        })
+        .await?;
+        *connection = Some(open.clone());
+        Ok((open, false))
+    }
+}
+
+impl DnsTransport for DoqTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        self.exchange(query, self.timeout)
+    }
+
+    fn exchange_async<'a>(
+        &'a self,
+        query: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(self.exchange_async(query, self.timeout))
+    }
+}
+
+/// Runtime driving the endpoints and connections of all transports
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("doq")
+            .enable_all()
+            .build()
+            .expect("failed to start the DNS over QUIC runtime")
+    })
+}
+
+async fn connect(
+    address: String,
+    host: String,
+    config: ClientConfig,
+) -> std::io::Result<Connection> {
+    let socket_address = tokio::net::lookup_host(&address)
+        .await?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::other(format!("{address} does not resolve to an address"))
+        })?;
+    let local_address: SocketAddr = if socket_address.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+
+    // the endpoint is kept alive by its connection
+    let endpoint = Endpoint::client(local_address)?;
+    endpoint
+        .connect_with(config, socket_address, &host)
+        .map_err(std::io::Error::other)?
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Sends `query` on a new stream, with its ID set to 0 as the server ignores it anyway, and
+/// returns the response with the ID of `query`
+/// https://datatracker.ietf.org/doc/html/rfc9250#section-4.2
+async fn exchange_on(connection: &Connection, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let len = u16::try_from(query.len())
+        .map_err(|_| std::io::Error::other("query too large for a DNS message"))?;
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(query);
+    if query.len() >= 2 {
+        message[2..4].copy_from_slice(&[0, 0]);
+    }
+
+    let (mut send, mut receive) = connection.open_bi().await?;
+    send.write_all(&message).await?;
+    send.finish().map_err(std::io::Error::other)?;
+
+    let mut response = receive
+        .read_to_end(2 + u16::MAX as usize)
+        .await
+        .map_err(std::io::Error::other)?;
+    if response.len() < 2
+        || u16::from_be_bytes([response[0], response[1]]) as usize != response.len() - 2
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "DNS over QUIC response is not a single length prefixed message",
+        ));
+    }
+    response.drain(..2);
+    if query.len() >= 2 && response.len() >= 2 {
+        response[0..2].copy_from_slice(&query[0..2]);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use quinn::{crypto::rustls::QuicServerConfig, Endpoint, ServerConfig};
+    use rustls::{pki_types::PrivateKeyDer, RootCertStore};
+
+    use super::{runtime, DoqTransport, DOQ_ALPN};
+
+    /// Starts a DoQ server with a self-signed certificate for `127.0.0.1` that responds with the
+    /// query it received, but with the ID 0 replaced by 0xFFFF. Returns the server's URL, a client
+    /// config trusting it and a counter of accepted connections.
+    fn mock_server() -> (String, Arc<rustls::ClientConfig>, Arc<AtomicUsize>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".into()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = Arc::new(
+            rustls::ClientConfig::builder_with_provider(super::super::tls::provider())
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+
+        let key = PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+        let mut tls = rustls::ServerConfig::builder_with_provider(super::super::tls::provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)
+            .unwrap();
+        tls.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        let server_config =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+
+        let _runtime = runtime().enter();
+        let endpoint = Endpoint::server(server_config, (Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        runtime().spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let connection = incoming.await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut receive)) = connection.accept_bi().await {
+                        let mut message = receive.read_to_end(u16::MAX as usize).await.unwrap();
+                        assert_eq!(message[2..4], [0, 0]);
+                        message[2..4].copy_from_slice(&[0xFF, 0xFF]);
+                        send.write_all(&message).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+        (
+            format!("quic://127.0.0.1:{port}"),
+            client_config,
+            connections,
+        )
+    }
+
+    #[test]
+    fn test_doq_reuses_connection() {
+        let (url, config, connections) = mock_server();
+        let transport = DoqTransport::with_tls_config(&url, config).unwrap();
+
+        for i in 1..4u8 {
+            let query = [0, i, 1, 2, 3];
+            let response = transport.exchange(&query, Duration::from_secs(5)).unwrap();
+            assert_eq!(response, query);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_doq_concurrent_async() {
+        let (url, config, connections) = mock_server();
+        let transport = Arc::new(DoqTransport::with_tls_config(&url, config).unwrap());
+        // establish the connection before the concurrent queries
+        transport
+            .exchange_async(&[0, 0], Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let queries = (1..10u8).map(|i| {
+            let transport = Arc::clone(&transport);
+            tokio::spawn(async move {
+                let query = [i, i, 0xAB];
+                let response = transport
+                    .exchange_async(&query, Duration::from_secs(5))
+                    .await
+                    .unwrap();
+                assert_eq!(response, query);
+            })
+        });
+        for query in queries.collect::<Vec<_>>() {
+            query.await.unwrap();
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_doq_rejects_untrusted_certificate() {
+        let (url, _, _) = mock_server();
+        let transport = DoqTransport::new(&url).unwrap();
+        assert!(transport.exchange(&[0, 1], Duration::from_secs(5)).is_err());
+    }
+}
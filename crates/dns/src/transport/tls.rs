@@ -0,0 +1,513 @@
+//! DNS over TLS client.
+//! https://datatracker.ietf.org/doc/html/rfc7858
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+
+use crate::{
+    protocol::opt::{pad_message, QUERY_PADDING_BLOCK},
+    tcp::{read_tcp_message, read_tcp_message_async, write_tcp_message, write_tcp_message_async},
+};
+
+use super::{with_default_port, BoxFuture, DnsTransport, IdleConnections, DEFAULT_TIMEOUT};
+
+/// Port DNS over TLS servers listen on by default
+pub const DOT_PORT: u16 = 853;
+
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+pub(crate) type AsyncTlsStream = tokio_rustls::client::TlsStream<tokio::net::TcpStream>;
+
+/// Sends queries to a single DNS over TLS server.
+///
+/// Connections are kept open and reused for subsequent queries, with queries sent at the same
+/// time each getting a connection of their own, and reestablished once the server closed them.
+/// TLS sessions are resumed where possible when reconnecting. Queries are
+/// padded with [`pad_message`], so their length does not reveal the queried name.
+#[derive(Debug)]
+pub struct DotTransport {
+    address: String,
+    server_name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    /// Timeout of queries sent through [`DnsTransport`]
+    timeout: Duration,
+    connections: IdleConnections<TlsStream>,
+    async_connections: IdleConnections<AsyncTlsStream>,
+}
+
+impl DotTransport {
+    /// Creates a transport for the server at `address`, which defaults to port 853, that has to
+    /// present a certificate for `host`, or one with the given SPKI pin
+    pub fn new(
+        address: &str,
+        host: &str,
+        spki_pin: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let config = match spki_pin {
+            Some(pin) => pinned_config(pin)?,
+            None => web_pki_config()?,
+        };
+        Ok(Self {
+            address: with_default_port(address, DOT_PORT),
+            server_name: ServerName::try_from(host.to_string())?,
+            config,
+            timeout: DEFAULT_TIMEOUT,
+            connections: IdleConnections::default(),
+            async_connections: IdleConnections::default(),
+        })
+    }
+
+    /// Sets how long queries sent through [`DnsTransport`] wait for connecting as well as for
+    /// the response
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the process wide transport for this server, so its connections are shared by all
+    /// queries to it
+    pub fn shared(
+        address: &str,
+        host: &str,
+        spki_pin: Option<&str>,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        type Key = (String, String, Option<String>);
+        static TRANSPORTS: OnceLock<Mutex<HashMap<Key, Arc<DotTransport>>>> = OnceLock::new();
+
+        let key = (
+            address.to_string(),
+            host.to_string(),
+            spki_pin.map(str::to_string),
+        );
+        let mut transports = TRANSPORTS.get_or_init(Default::default).lock().unwrap();
+        if let Some(transport) = transports.get(&key) {
+            return Ok(Arc::clone(transport));
+        }
+        let transport = Arc::new(Self::new(address, host, spki_pin)?);
+        transports.insert(key, Arc::clone(&transport));
+        Ok(transport)
+    }
+
+    /// Sends the raw `query` and waits at most `timeout` for connecting as well as for the
+    /// response
+    pub fn exchange(
+        &self,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let query = &pad_message(query, QUERY_PADDING_BLOCK);
+        if let Some(mut stream) = self.connections.take() {
+            // otherwise most likely the server closed the idle connection, so start over
+            if let Ok(response) = exchange_on(&mut stream, query, timeout) {
+                self.connections.put(stream);
+                return Ok(response);
+            }
+        }
+
+        let mut stream = self.connect(timeout)?;
+        let response = exchange_on(&mut stream, query, timeout)?;
+        self.connections.put(stream);
+        Ok(response)
+    }
+
+    /// Asynchronously sends the raw `query`, see [`DotTransport::exchange`]
+    pub async fn exchange_async(
+        &self,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let query = &pad_message(query, QUERY_PADDING_BLOCK);
+        if let Some(mut stream) = self.async_connections.take() {
+            if let Ok(response) = with_timeout(timeout, exchange_on_async(&mut stream, query)).await
+            {
+                self.async_connections.put(stream);
+                return Ok(response);
+            }
+        }
+
+        let mut stream = with_timeout(timeout, self.connect_async()).await?;
+        let response = with_timeout(timeout, exchange_on_async(&mut stream, query)).await?;
+        self.async_connections.put(stream);
+        Ok(response)
+    }
+
+    fn connect(
+        &self,
+        timeout: Duration,
+    ) -> Result<TlsStream, Box<dyn std::error::Error + Send + Sync>> {
+        connect(&self.address, &self.server_name, &self.config, timeout)
+    }
+
+    async fn connect_async(&self) -> std::io::Result<AsyncTlsStream> {
+        connect_async(&self.address, &self.server_name, &self.config).await
+    }
+}
+
+impl DnsTransport for DotTransport {
+    fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        self.exchange(query, self.timeout)
+    }
+
+    fn exchange_async<'a>(
+        &'a self,
+        query: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(self.exchange_async(query, self.timeout))
+    }
+}
+
+/// Opens a TLS connection to `address`, verifying the server as `server_name`
+pub(crate) fn connect(
+    address: &str,
+    server_name: &ServerName<'static>,
+    config: &Arc<ClientConfig>,
+    timeout: Duration,
+) -> Result<TlsStream, Box<dyn std::error::Error + Send + Sync>> {
+    let socket_address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{address} does not resolve to an address"))?;
+    let tcp = TcpStream::connect_timeout(&socket_address, timeout)?;
+    tcp.set_nodelay(true)?;
+    let tls = ClientConnection::new(Arc::clone(config), server_name.clone())?;
+    Ok(StreamOwned::new(tls, tcp))
+}
+
+pub(crate) async fn connect_async(
+    address: &str,
+    server_name: &ServerName<'static>,
+    config: &Arc<ClientConfig>,
+) -> std::io::Result<AsyncTlsStream> {
+    let tcp = tokio::net::TcpStream::connect(address).await?;
+    tcp.set_nodelay(true)?;
+    tokio_rustls::TlsConnector::from(Arc::clone(config))
+        .connect(server_name.clone(), tcp)
+        .await
+}
+
+fn exchange_on(
+    stream: &mut TlsStream,
+    query: &[u8],
+    timeout: Duration,
+) -> std::io::Result<Vec<u8>> {
+    stream.sock.set_read_timeout(Some(timeout))?;
+    stream.sock.set_write_timeout(Some(timeout))?;
+    write_tcp_message(stream, query)?;
+    read_tcp_message(stream)
+}
+
+async fn exchange_on_async(stream: &mut AsyncTlsStream, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    write_tcp_message_async(stream, query).await?;
+    read_tcp_message_async(stream).await
+}
+
+pub(crate) async fn with_timeout<T>(
+    timeout: Duration,
+    future: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "request to the DNS server timed out",
+            ))
+        })
+}
+
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Config verifying servers against the bundled Mozilla root certificates, shared by all
+/// transports so they share the TLS session cache as well
+pub(crate) fn web_pki_config() -> Result<Arc<ClientConfig>, rustls::Error> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(Arc::clone(config));
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::clone(CONFIG.get_or_init(|| Arc::new(config))))
+}
+
+fn pinned_config(pin: &str) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let pin: [u8; 32] = STANDARD
+        .decode(pin)?
+        .try_into()
+        .map_err(|_| "SPKI pin is not a SHA-256 hash")?;
+    let verifier = PinnedVerifier {
+        pin,
+        provider: provider(),
+    };
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Base64 encoded SHA-256 hash of the SubjectPublicKeyInfo of a DER encoded certificate, as used
+/// in [`super::Transport::Tls`]
+pub fn spki_pin(certificate: &[u8]) -> Option<String> {
+    let spki = subject_public_key_info(certificate)?;
+    Some(STANDARD.encode(ring::digest::digest(&ring::digest::SHA256, spki)))
+}
+
+/// Accepts exactly the certificates whose public key matches the pin, the out-of-band key-pinned
+/// privacy profile of https://datatracker.ietf.org/doc/html/rfc7858#section-4.2
+#[derive(Debug)]
+struct PinnedVerifier {
+    pin: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let spki = subject_public_key_info(end_entity).ok_or(rustls::Error::InvalidCertificate(
+            CertificateError::BadEncoding,
+        ))?;
+        let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+        if hash.as_ref() != self.pin {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Finds the encoded SubjectPublicKeyInfo in a DER encoded X.509 certificate
+/// https://datatracker.ietf.org/doc/html/rfc5280#section-4.1
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, mut tbs_certificate, _) = der_element(certificate)?;
+
+    // explicitly tagged version, defaults to v1 if absent
+    if tbs_certificate.first() == Some(&0xA0) {
+        tbs_certificate = der_element(tbs_certificate)?.2;
+    }
+    // serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        tbs_certificate = der_element(tbs_certificate)?.2;
+    }
+
+    let rest = der_element(tbs_certificate)?.2;
+    Some(&tbs_certificate[..tbs_certificate.len() - rest.len()])
+}
+
+/// Splits off the first DER element of `input`, returning its tag, contents and what follows it
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first_len = *input.get(1)? as usize;
+    let (len, header_len) = if first_len < 0x80 {
+        (first_len, 2)
+    } else {
+        let len_bytes = first_len & 0x7F;
+        if len_bytes == 0 || len_bytes > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + len_bytes)?
+            .iter()
+            .fold(0usize, |acc, byte| acc << 8 | *byte as usize);
+        (len, 2 + len_bytes)
+    };
+    let contents = input.get(header_len..header_len + len)?;
+    Some((tag, contents, &input[header_len + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use rustls::{pki_types::PrivateKeyDer, ServerConfig, ServerConnection, StreamOwned};
+
+    use crate::{
+        protocol::{opt::QUERY_PADDING_BLOCK, query::QueryBuilder},
+        tcp::{read_tcp_message, write_tcp_message},
+    };
+
+    use super::{spki_pin, DotTransport};
+
+    /// Starts a DNS over TLS server with a self-signed certificate for `dns.test` that echoes
+    /// every query but those starting with 0xff, returning its address, SPKI pin and a counter of
+    /// accepted connections
+    fn mock_server() -> (String, String, Arc<AtomicUsize>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["dns.test".into()]).unwrap();
+        let pin = spki_pin(certified.cert.der()).unwrap();
+        let key = PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+        let config = Arc::new(
+            ServerConfig::builder_with_provider(super::provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![certified.cert.der().clone()], key)
+                .unwrap(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            for tcp in listener.incoming() {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let tls = ServerConnection::new(Arc::clone(&config)).unwrap();
+                let mut stream = StreamOwned::new(tls, tcp.unwrap());
+                std::thread::spawn(move || {
+                    while let Ok(query) = read_tcp_message(&mut stream) {
+                        if query.first() != Some(&0xff) {
+                            write_tcp_message(&mut stream, &query).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        (address, pin, connections)
+    }
+
+    #[test]
+    fn test_dot_exchange_reuses_connection() {
+        let (address, pin, connections) = mock_server();
+        let transport = DotTransport::new(&address, "dns.test", Some(&pin)).unwrap();
+
+        for i in 0..3u8 {
+            let response = transport
+                .exchange(&[i; 20], Duration::from_secs(5))
+                .unwrap();
+            assert_eq!(response, vec![i; 20]);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dot_exchange_async() {
+        let (address, pin, connections) = mock_server();
+        let transport = DotTransport::new(&address, "dns.test", Some(&pin)).unwrap();
+
+        for i in 0..3u8 {
+            let response = transport
+                .exchange_async(&[i; 20], Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(response, vec![i; 20]);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dot_concurrent_queries() {
+        let (address, pin, connections) = mock_server();
+        let transport = DotTransport::new(&address, "dns.test", Some(&pin)).unwrap();
+
+        // queries do not wait for the response to another one
+        let unanswered = transport.exchange_async(&[0xff; 20], Duration::from_secs(1));
+        let answered = tokio::time::timeout(
+            Duration::from_millis(500),
+            transport.exchange_async(&[1; 20], Duration::from_secs(5)),
+        );
+        let (unanswered, answered) = tokio::join!(unanswered, answered);
+        assert!(unanswered.is_err());
+        assert_eq!(answered.unwrap().unwrap(), vec![1; 20]);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // the connection of the timed out query is not reused
+        let response = transport.exchange_async(&[2; 20], Duration::from_secs(5));
+        assert_eq!(response.await.unwrap(), vec![2; 20]);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_dot_pads_queries() {
+        let (address, pin, _) = mock_server();
+        let transport = DotTransport::new(&address, "dns.test", Some(&pin)).unwrap();
+
+        let query = QueryBuilder::new("example.com".parse().unwrap()).build();
+        let echoed = transport.exchange(&query, Duration::from_secs(5)).unwrap();
+        assert_eq!(echoed.len(), QUERY_PADDING_BLOCK);
+    }
+
+    #[test]
+    fn test_dot_rejects_untrusted_certificates() {
+        let (address, _, _) = mock_server();
+        let timeout = Duration::from_secs(5);
+
+        // self-signed certificates are not part of the web PKI
+        let transport = DotTransport::new(&address, "dns.test", None).unwrap();
+        assert!(transport.exchange(&[1, 2], timeout).is_err());
+
+        let wrong_pin = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        let transport = DotTransport::new(&address, "dns.test", Some(wrong_pin)).unwrap();
+        assert!(transport.exchange(&[1, 2], timeout).is_err());
+
+        assert!(DotTransport::new(&address, "dns.test", Some("not a pin")).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+//! Transaction signatures, which authenticate messages between two servers sharing a secret key,
+//! eg. a primary and the secondaries allowed to transfer its zones.
+//! https://datatracker.ietf.org/doc/html/rfc8945
+
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::hmac;
+
+use crate::{
+    parse::parser::DnsParser,
+    protocol::{
+        answer::Answer, class::Class, name::DnsName, record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    serialize::writer::PacketWriter,
+};
+
+/// Record type of the TSIG record, which is always the last record of a signed message
+pub const TSIG: u16 = 250;
+
+/// Seconds the clocks of the signer and the verifier may differ by
+/// https://datatracker.ietf.org/doc/html/rfc8945#section-10
+const DEFAULT_FUDGE: u16 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    /// The name identifying the algorithm in TSIG records
+    pub fn name(&self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha384 => "hmac-sha384",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn hmac(&self) -> hmac::Algorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+            TsigAlgorithm::HmacSha384 => hmac::HMAC_SHA384,
+            TsigAlgorithm::HmacSha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            TsigAlgorithm::HmacSha256,
+            TsigAlgorithm::HmacSha384,
+            TsigAlgorithm::HmacSha512,
+        ]
+        .into_iter()
+        .find(|algorithm| {
+            algorithm
+                .name()
+                .eq_ignore_ascii_case(s.trim_end_matches('.'))
+        })
+        .ok_or_else(|| format!("unsupported TSIG algorithm {s}"))
+    }
+}
+
+/// A named secret shared with another server
+#[derive(Clone, PartialEq, Eq)]
+pub struct TsigKey {
+    pub name: DnsName,
+    pub algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+/// Leaves out the secret
+impl std::fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TsigKey {
+    pub fn new(name: DnsName, algorithm: TsigAlgorithm, secret: Vec<u8>) -> Self {
+        Self {
+            name,
+            algorithm,
+            secret,
+        }
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let key = hmac::Key::new(self.algorithm.hmac(), &self.secret);
+        hmac::sign(&key, data).as_ref().to_vec()
+    }
+
+    fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        let key = hmac::Key::new(self.algorithm.hmac(), &self.secret);
+        hmac::verify(&key, data, mac).is_ok()
+    }
+}
+
+/// Parses keys in the `[algorithm:]name:secret` format of `dig -y`, with the secret in base64
+/// and HMAC-SHA256 if no algorithm is given
+impl FromStr for TsigKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split(':').collect();
+        let (algorithm, name, secret) = match parts[..] {
+            [name, secret] => (TsigAlgorithm::HmacSha256, name, secret),
+            [algorithm, name, secret] => (algorithm.parse()?, name, secret),
+            _ => return Err(format!("expected [algorithm:]name:secret, got {s}")),
+        };
+        let name = DnsName::new(name).map_err(|e| format!("invalid key name {name}: {e}"))?;
+        let secret = STANDARD
+            .decode(secret)
+            .map_err(|e| format!("invalid key secret: {e}"))?;
+        Ok(Self::new(name, algorithm, secret))
+    }
+}
+
+/// Why a signed message was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TsigError {
+    /// The TSIG record is not the last record or cannot be parsed
+    Malformed,
+    /// The message is signed with a key that is not known
+    BadKey,
+    /// The MAC does not match the message
+    BadSig,
+    /// The message was signed too long ago, or the clocks differ
+    BadTime,
+}
+
+impl TsigError {
+    /// The response code telling the signer about the error
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            TsigError::Malformed => ResponseCode::FormErr,
+            _ => ResponseCode::NotAuth,
+        }
+    }
+}
+
+impl Display for TsigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TsigError::Malformed => write!(f, "malformed TSIG record"),
+            TsigError::BadKey => write!(f, "unknown TSIG key"),
+            TsigError::BadSig => write!(f, "TSIG signature does not match"),
+            TsigError::BadTime => write!(f, "TSIG signature expired"),
+        }
+    }
+}
+
+impl std::error::Error for TsigError {}
+
+/// Signs the messages of one side of a transaction, each after the first covering the MAC of the
+/// previous one, so a zone transfer cannot be reordered or cut short.
+/// https://datatracker.ietf.org/doc/html/rfc8945#section-5.3.1
+#[derive(Debug, Clone)]
+pub struct TsigSigner {
+    key: TsigKey,
+    /// The MAC of the request for the first response, then the MAC of the previous message
+    prior_mac: Option<Vec<u8>>,
+    first: bool,
+}
+
+impl TsigSigner {
+    /// Signs a request
+    pub fn query(key: TsigKey) -> Self {
+        Self {
+            key,
+            prior_mac: None,
+            first: true,
+        }
+    }
+
+    /// Signs the responses to a request verified with [`verify_request`]
+    pub fn response(request: VerifiedRequest) -> Self {
+        Self {
+            key: request.key,
+            prior_mac: Some(request.mac),
+            first: true,
+        }
+    }
+
+    /// `message` with a TSIG record appended
+    pub fn sign(&mut self, message: &[u8]) -> Vec<u8> {
+        let time_signed = unix_time();
+        let original_id = u16::from_be_bytes([message[0], message[1]]);
+
+        let mut data = vec![];
+        if let Some(prior_mac) = &self.prior_mac {
+            data.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+            data.extend_from_slice(prior_mac);
+        }
+        data.extend_from_slice(message);
+        if self.first {
+            write_variables(&mut data, &self.key, time_signed, DEFAULT_FUDGE);
+        } else {
+            write_timers(&mut data, time_signed, DEFAULT_FUDGE);
+        }
+        let mac = self.key.mac(&data);
+
+        let mut signed = message.to_vec();
+        write_record(
+            &mut signed,
+            &self.key,
+            time_signed,
+            DEFAULT_FUDGE,
+            &mac,
+            original_id,
+        );
+        self.prior_mac = Some(mac);
+        self.first = false;
+        signed
+    }
+}
+
+/// A request signed with one of the known keys
+#[derive(Debug, Clone)]
+pub struct VerifiedRequest {
+    pub key: TsigKey,
+    mac: Vec<u8>,
+}
+
+/// Checks the TSIG record of `message` against `keys`. `Ok(None)` if the message is not signed.
+pub fn verify_request(
+    message: &[u8],
+    keys: &[TsigKey],
+) -> Result<Option<VerifiedRequest>, TsigError> {
+    let packet = DnsParser::new(message)
+        .parse_packet()
+        .map_err(|_| TsigError::Malformed)?;
+    let is_tsig = |record: &Answer| record.meta().r#type == RecordType::from(TSIG as usize);
+    let Some(record) = packet.additionals.last().filter(|record| is_tsig(record)) else {
+        let elsewhere = packet
+            .answers
+            .iter()
+            .chain(&packet.authorities)
+            .chain(&packet.additionals)
+            .any(is_tsig);
+        return if elsewhere {
+            Err(TsigError::Malformed)
+        } else {
+            Ok(None)
+        };
+    };
+    let Answer::Unknown { meta, rdata } = record else {
+        return Err(TsigError::Malformed);
+    };
+    let fields = TsigRdata::parse(rdata).ok_or(TsigError::Malformed)?;
+    let key = keys
+        .iter()
+        .find(|key| key.name == meta.name && fields.algorithm.parse() == Ok(key.algorithm))
+        .ok_or(TsigError::BadKey)?;
+
+    // the MAC covers the message as it was before the TSIG record was added
+    let record_len = meta.name.wire_len() + 10 + rdata.len();
+    let mut data = message
+        .get(..message.len().saturating_sub(record_len))
+        .filter(|unsigned| unsigned.len() >= 12)
+        .ok_or(TsigError::Malformed)?
+        .to_vec();
+    data[..2].copy_from_slice(&fields.original_id.to_be_bytes());
+    data[10..12].copy_from_slice(&(packet.header.additional_count - 1).to_be_bytes());
+    write_variables(&mut data, key, fields.time_signed, fields.fudge);
+    if !key.verify(&data, &fields.mac) {
+        return Err(TsigError::BadSig);
+    }
+    if unix_time().abs_diff(fields.time_signed) > fields.fudge as u64 {
+        return Err(TsigError::BadTime);
+    }
+    Ok(Some(VerifiedRequest {
+        key: key.clone(),
+        mac: fields.mac,
+    }))
+}
+
+/// The fields of a TSIG record that matter for verifying it
+/// https://datatracker.ietf.org/doc/html/rfc8945#section-4.2
+struct TsigRdata {
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+}
+
+impl TsigRdata {
+    fn parse(rdata: &[u8]) -> Option<Self> {
+        // the algorithm name is never compressed
+        let mut labels = vec![];
+        let mut position = 0;
+        loop {
+            let len = *rdata.get(position)? as usize;
+            position += 1;
+            if len == 0 {
+                break;
+            }
+            labels.push(String::from_utf8_lossy(
+                rdata.get(position..position + len)?,
+            ));
+            position += len;
+        }
+        let rest = &rdata[position..];
+        let time_signed = rest
+            .get(..6)?
+            .iter()
+            .fold(0u64, |time, &byte| time << 8 | byte as u64);
+        let u16_at = |at: usize| Some(u16::from_be_bytes(rest.get(at..at + 2)?.try_into().ok()?));
+        let fudge = u16_at(6)?;
+        let mac_len = u16_at(8)? as usize;
+        let mac = rest.get(10..10 + mac_len)?.to_vec();
+        let original_id = u16_at(10 + mac_len)?;
+        Some(Self {
+            algorithm: labels.join("."),
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+        })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn algorithm_name(key: &TsigKey) -> DnsName {
+    DnsName::new(key.algorithm.name()).expect("algorithm names are valid domain names")
+}
+
+/// The TSIG variables that are covered by the MAC along with the message, with the names in
+/// canonical, lowercase form
+/// https://datatracker.ietf.org/doc/html/rfc8945#section-4.3.3
+fn write_variables(buf: &mut Vec<u8>, key: &TsigKey, time_signed: u64, fudge: u16) {
+    let mut writer = PacketWriter::uncompressed(buf);
+    let name =
+        DnsName::new(&key.name.as_str().to_ascii_lowercase()).unwrap_or_else(|_| key.name.clone());
+    writer.write_name_uncompressed(&name);
+    writer.write_u16(Class::ANY.into());
+    writer.write_u32(0);
+    writer.write_name_uncompressed(&algorithm_name(key));
+    write_timers(buf, time_signed, fudge);
+    // no error and no other data
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+}
+
+fn write_timers(buf: &mut Vec<u8>, time_signed: u64, fudge: u16) {
+    buf.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+    buf.extend_from_slice(&fudge.to_be_bytes());
+}
+
+/// Appends the TSIG record to the signed `message` and counts it in the header
+fn write_record(
+    message: &mut Vec<u8>,
+    key: &TsigKey,
+    time_signed: u64,
+    fudge: u16,
+    mac: &[u8],
+    original_id: u16,
+) {
+    let mut rdata = vec![];
+    PacketWriter::uncompressed(&mut rdata).write_name_uncompressed(&algorithm_name(key));
+    write_timers(&mut rdata, time_signed, fudge);
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(mac);
+    rdata.extend_from_slice(&original_id.to_be_bytes());
+    rdata.extend_from_slice(&[0, 0, 0, 0]);
+
+    let additional_count = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional_count.to_be_bytes());
+    let mut writer = PacketWriter::uncompressed(message);
+    writer.write_name_uncompressed(&key.name);
+    writer.write_u16(TSIG);
+    writer.write_u16(Class::ANY.into());
+    writer.write_u32(0);
+    writer.write_u16(rdata.len() as u16);
+    writer.write_bytes(&rdata);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::query::QueryBuilder;
+
+    use super::{verify_request, TsigError, TsigKey, TsigSigner};
+
+    #[test]
+    fn test_tsig() {
+        let key: TsigKey = "hmac-sha256:transfer.key:c2VjcmV0IHNoYXJlZCB3aXRoIHNlY29uZGFyaWVz"
+            .parse()
+            .unwrap();
+        let other: TsigKey = "other.key:c2VjcmV0".parse().unwrap();
+        assert!("hmac-md5:old.key:c2VjcmV0".parse::<TsigKey>().is_err());
+
+        let query = QueryBuilder::new("example.lan".parse().unwrap())
+            .id(42)
+            .build();
+        assert!(verify_request(&query, std::slice::from_ref(&key))
+            .unwrap()
+            .is_none());
+
+        let signed = TsigSigner::query(key.clone()).sign(&query);
+        assert_eq!(&signed[..query.len()][12..], &query[12..]);
+        let verified = verify_request(&signed, &[other.clone(), key.clone()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(verified.key, key);
+        assert_eq!(
+            verify_request(&signed, &[other]).unwrap_err(),
+            TsigError::BadKey
+        );
+
+        let mut tampered = signed.clone();
+        // recursion desired
+        tampered[2] ^= 1;
+        assert_eq!(
+            verify_request(&tampered, std::slice::from_ref(&key)).unwrap_err(),
+            TsigError::BadSig
+        );
+
+        // responses chain to the request, so they differ from a signature of the request alone
+        let mut signer = TsigSigner::response(verified);
+        let first = signer.sign(&query);
+        let second = signer.sign(&query);
+        assert_ne!(first[query.len()..], signed[query.len()..]);
+        assert_ne!(first, second);
+    }
+}
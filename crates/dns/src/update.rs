@@ -0,0 +1,886 @@
+//! Dynamic updates, which add and delete records of authoritative zones without editing their
+//! zone files.
+//! https://datatracker.ietf.org/doc/html/rfc2136
+
+use crate::{
+    error::ResolveError,
+    parse::parser::DnsParser,
+    protocol::{
+        answer::{Answer, AnswerMeta},
+        class::Class,
+        header::{Flags, Header},
+        name::DnsName,
+        opcode::Opcode,
+        packet::Packet,
+        query::random_id,
+        question::Question,
+        record_type::RecordType,
+        response_code::ResponseCode,
+    },
+    resolver::{
+        bind_query_socket, bind_query_socket_async, resolve_query, resolve_query_async,
+        ResolveOptions, ResponseError,
+    },
+    secondary::is_newer_serial,
+    tsig::{TsigKey, TsigSigner},
+    zonefile::Zone,
+};
+
+/// Builds the wire format of an UPDATE message for a zone, with the prerequisites the server
+/// checks before it applies all of the changes, or none of them
+/// https://datatracker.ietf.org/doc/html/rfc2136#section-2
+///
+/// ```
+/// use std::net::Ipv4Addr;
+///
+/// use dns::{
+///     protocol::{
+///         answer::{Answer, AnswerMeta},
+///         class::Class,
+///         record_type::RecordType,
+///     },
+///     update::UpdateBuilder,
+/// };
+///
+/// let host = "host.example.lan".parse().unwrap();
+/// let update = UpdateBuilder::new("example.lan".parse().unwrap())
+///     .id(42)
+///     .require_name_not_in_use(&host)
+///     .add_record(Answer::A {
+///         meta: AnswerMeta {
+///             name: host,
+///             r#type: RecordType::A,
+///             class: Class::IN,
+///             ttl: 300,
+///             len: 0,
+///         },
+///         ipv4: Ipv4Addr::new(192, 0, 2, 9),
+///     })
+///     .build();
+/// assert_eq!(&update[0..4], &[0, 42, 0x28, 0]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct UpdateBuilder {
+    id: u16,
+    zone: DnsName,
+    prerequisites: Vec<Answer>,
+    updates: Vec<Answer>,
+    key: Option<TsigKey>,
+}
+
+impl UpdateBuilder {
+    /// Creates an update of the INternet zone `zone` without prerequisites or changes
+    pub fn new(zone: DnsName) -> Self {
+        Self {
+            id: random_id(),
+            zone,
+            prerequisites: vec![],
+            updates: vec![],
+            key: None,
+        }
+    }
+
+    pub fn id(mut self, id: u16) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Signs the update with `key`, which servers usually require
+    pub fn key(mut self, key: TsigKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Requires `name` to own at least one record
+    pub fn require_name_in_use(mut self, name: &DnsName) -> Self {
+        let prerequisite = without_rdata(name, RecordType::ANY, Class::ANY);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Requires `name` to own no records at all
+    pub fn require_name_not_in_use(mut self, name: &DnsName) -> Self {
+        let prerequisite = without_rdata(name, RecordType::ANY, Class::NONE);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Requires `name` to own records of `record_type`, whatever their data
+    pub fn require_rrset_exists(mut self, name: &DnsName, record_type: RecordType) -> Self {
+        let prerequisite = without_rdata(name, record_type, Class::ANY);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Requires `name` to own no records of `record_type`
+    pub fn require_rrset_not_exists(mut self, name: &DnsName, record_type: RecordType) -> Self {
+        let prerequisite = without_rdata(name, record_type, Class::NONE);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Requires `record` to exist. All records required for a name and type together must be
+    /// exactly the records the zone has for them.
+    pub fn require_record(mut self, record: Answer) -> Self {
+        self.prerequisites.push(with_class(record, Class::IN));
+        self
+    }
+
+    /// Adds `record` to the zone, or updates its TTL if it exists already
+    pub fn add_record(mut self, record: Answer) -> Self {
+        self.updates.push(record);
+        self
+    }
+
+    /// Deletes all records of `record_type` owned by `name`
+    pub fn delete_rrset(mut self, name: &DnsName, record_type: RecordType) -> Self {
+        let update = without_rdata(name, record_type, Class::ANY);
+        self.updates.push(update);
+        self
+    }
+
+    /// Deletes all records owned by `name`. At the zone apex, the SOA and NS records are kept.
+    pub fn delete_name(mut self, name: &DnsName) -> Self {
+        let update = without_rdata(name, RecordType::ANY, Class::ANY);
+        self.updates.push(update);
+        self
+    }
+
+    /// Deletes the one record with the owner, type and data of `record`
+    pub fn delete_record(mut self, record: Answer) -> Self {
+        self.updates.push(with_class(record, Class::NONE));
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let update = Packet {
+            header: Header {
+                request_id: self.id,
+                flags: Flags {
+                    query: true,
+                    opcode: Opcode::Update,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: vec![Question {
+                domain_name: self.zone.clone(),
+                r#type: RecordType::SOA,
+                class: Class::IN,
+            }],
+            answers: self.prerequisites.clone(),
+            authorities: self.updates.clone(),
+            additionals: vec![],
+        }
+        .to_bytes();
+        match &self.key {
+            Some(key) => TsigSigner::query(key.clone()).sign(&update),
+            None => update,
+        }
+    }
+
+    /// Sends the update to the primary server of the zone at `address`, until it responds or the
+    /// retries of `opts` run out. Fails with the response code of the server if it rejected the
+    /// update.
+    pub fn send(&self, address: &str, opts: &ResolveOptions) -> Result<(), ResolveError> {
+        let socket = bind_query_socket(opts)?;
+        let response = resolve_query(&self.build(), address, &socket, opts)?;
+        check_response(&response)
+    }
+
+    /// Asynchronously sends the update to the server at `address`, see [`UpdateBuilder::send`]
+    pub async fn send_async(
+        &self,
+        address: &str,
+        opts: &ResolveOptions,
+    ) -> Result<(), ResolveError> {
+        let socket = bind_query_socket_async(opts).await?;
+        let response = resolve_query_async(&self.build(), address, &socket, opts).await?;
+        check_response(&response)
+    }
+}
+
+/// A record matching `name` and `record_type` by themselves, see
+/// https://datatracker.ietf.org/doc/html/rfc2136#section-2.4
+fn without_rdata(name: &DnsName, record_type: RecordType, class: Class) -> Answer {
+    Answer::Unknown {
+        meta: AnswerMeta {
+            name: name.clone(),
+            r#type: record_type,
+            class,
+            ttl: 0,
+            len: 0,
+        },
+        rdata: vec![],
+    }
+}
+
+fn with_class(mut record: Answer, class: Class) -> Answer {
+    let meta = record.meta_mut();
+    meta.class = class;
+    meta.ttl = 0;
+    record
+}
+
+/// Unlike for queries, NXDOMAIN means that a prerequisite failed
+fn check_response(response: &[u8]) -> Result<(), ResolveError> {
+    let response_code = DnsParser::new(response).parse_header()?.rcode();
+    match response_code {
+        ResponseCode::NoError => Ok(()),
+        _ => Err(ResponseError {
+            response_code,
+            extended_errors: vec![],
+        }
+        .into()),
+    }
+}
+
+/// Applies the prerequisites and updates of the parsed UPDATE message to `zone`, all of them or
+/// none. The zone with its serial increased if any record changed, `None` if none did, or the
+/// response code telling why the update was rejected.
+pub(crate) fn apply_update(zone: &Zone, update: &Packet) -> Result<Option<Zone>, ResponseCode> {
+    check_prerequisites(zone, &update.answers)?;
+    for record in &update.authorities {
+        check_update(zone, record)?;
+    }
+
+    let mut records = zone.records.clone();
+    for record in &update.authorities {
+        apply(&zone.origin, &mut records, record);
+    }
+    if records == zone.records {
+        return Ok(None);
+    }
+    let mut updated = Zone {
+        origin: zone.origin.clone(),
+        records,
+    };
+    // the serial tells secondaries that the zone changed, unless the update set one itself
+    if updated.serial() == zone.serial() {
+        updated.increment_serial();
+    }
+    Ok(Some(updated))
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc2136#section-3.2
+fn check_prerequisites(zone: &Zone, prerequisites: &[Answer]) -> Result<(), ResponseCode> {
+    let mut required = vec![];
+    for record in prerequisites {
+        let meta = record.meta();
+        if meta.ttl != 0 {
+            return Err(ResponseCode::FormErr);
+        }
+        if !meta.name.is_subdomain_of(&zone.origin) {
+            return Err(ResponseCode::NotZone);
+        }
+        let (name, record_type) = (&meta.name, meta.r#type);
+        match meta.class {
+            Class::ANY | Class::NONE if !has_no_rdata(record) => return Err(ResponseCode::FormErr),
+            Class::ANY if record_type == RecordType::ANY && !in_use(zone, name) => {
+                return Err(ResponseCode::NXDomain)
+            }
+            Class::ANY
+                if record_type != RecordType::ANY && rrset(zone, name, record_type).is_empty() =>
+            {
+                return Err(ResponseCode::NXRRSet)
+            }
+            Class::NONE if record_type == RecordType::ANY && in_use(zone, name) => {
+                return Err(ResponseCode::YXDomain)
+            }
+            Class::NONE
+                if record_type != RecordType::ANY && !rrset(zone, name, record_type).is_empty() =>
+            {
+                return Err(ResponseCode::YXRRSet)
+            }
+            Class::ANY | Class::NONE => {}
+            Class::IN => required.push(record),
+            _ => return Err(ResponseCode::FormErr),
+        }
+    }
+
+    // the RRsets of records given with their data must be exactly those records
+    for record in &required {
+        let meta = record.meta();
+        let given: Vec<_> = required
+            .iter()
+            .filter(|other| other.meta().name == meta.name && other.meta().r#type == meta.r#type)
+            .copied()
+            .collect();
+        let existing = rrset(zone, &meta.name, meta.r#type);
+        let all_given = existing
+            .iter()
+            .all(|record| given.iter().any(|other| same_record(record, other)));
+        let all_existing = given
+            .iter()
+            .all(|record| existing.iter().any(|other| same_record(record, other)));
+        if !all_given || !all_existing {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc2136#section-3.4.1
+fn check_update(zone: &Zone, record: &Answer) -> Result<(), ResponseCode> {
+    let meta = record.meta();
+    if !meta.name.is_subdomain_of(&zone.origin) {
+        return Err(ResponseCode::NotZone);
+    }
+    let valid = match meta.class {
+        Class::IN => !is_meta_type(meta.r#type),
+        Class::ANY => {
+            meta.ttl == 0
+                && has_no_rdata(record)
+                && (meta.r#type == RecordType::ANY || !is_meta_type(meta.r#type))
+        }
+        Class::NONE => meta.ttl == 0 && !is_meta_type(meta.r#type),
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ResponseCode::FormErr)
+    }
+}
+
+/// https://datatracker.ietf.org/doc/html/rfc2136#section-3.4.2
+fn apply(origin: &DnsName, records: &mut Vec<Answer>, update: &Answer) {
+    let meta = update.meta();
+    let (name, record_type) = (&meta.name, meta.r#type);
+    let at_apex = name == origin;
+    match meta.class {
+        Class::IN if record_type == RecordType::SOA => {
+            let Some(current) = records
+                .iter_mut()
+                .find(|record| matches!(record, Answer::SOA { meta, .. } if meta.name == *origin))
+            else {
+                return;
+            };
+            if let (Answer::SOA { serial: new, .. }, Answer::SOA { serial: old, .. }) =
+                (update, &*current)
+            {
+                if at_apex && is_newer_serial(*new, *old) {
+                    *current = stored(update);
+                }
+            }
+        }
+        Class::IN => {
+            let at_name = || records.iter().filter(|record| record.meta().name == *name);
+            let has_cname = at_name().any(|record| record.meta().r#type == RecordType::CNAME);
+            let has_other = at_name().any(|record| record.meta().r#type != RecordType::CNAME);
+            // a name is either an alias or has other records
+            if (record_type == RecordType::CNAME && has_other)
+                || (record_type != RecordType::CNAME && has_cname)
+            {
+                return;
+            }
+            let existing = records.iter_mut().find(|record| {
+                if record_type == RecordType::CNAME {
+                    record.meta().name == *name && record.meta().r#type == RecordType::CNAME
+                } else {
+                    same_record(record, update)
+                }
+            });
+            match existing {
+                Some(existing) => *existing = stored(update),
+                None => records.push(stored(update)),
+            }
+        }
+        Class::ANY => records.retain(|record| {
+            let record_meta = record.meta();
+            let kept_at_apex =
+                at_apex && matches!(record_meta.r#type, RecordType::SOA | RecordType::NS);
+            record_meta.name != *name
+                || (record_type != RecordType::ANY && record_meta.r#type != record_type)
+                || kept_at_apex
+        }),
+        Class::NONE => {
+            let apex_ns = || {
+                records.iter().filter(|record| {
+                    record.meta().name == *origin && record.meta().r#type == RecordType::NS
+                })
+            };
+            let last_apex_ns = at_apex && record_type == RecordType::NS && apex_ns().count() <= 1;
+            if record_type == RecordType::SOA || last_apex_ns {
+                return;
+            }
+            records.retain(|record| !same_record(record, update));
+        }
+        _ => {}
+    }
+}
+
+/// Whether `name` owns any records
+fn in_use(zone: &Zone, name: &DnsName) -> bool {
+    zone.records
+        .iter()
+        .any(|record| record.meta().name == *name)
+}
+
+fn rrset<'a>(zone: &'a Zone, name: &DnsName, record_type: RecordType) -> Vec<&'a Answer> {
+    zone.records
+        .iter()
+        .filter(|record| record.meta().name == *name && record.meta().r#type == record_type)
+        .collect()
+}
+
+fn has_no_rdata(record: &Answer) -> bool {
+    matches!(record, Answer::Unknown { rdata, .. } if rdata.is_empty())
+}
+
+/// Types that only exist in questions, or pseudo records like OPT and TSIG
+fn is_meta_type(record_type: RecordType) -> bool {
+    match record_type {
+        RecordType::OPT
+        | RecordType::AXFR
+        | RecordType::MAILA
+        | RecordType::MAILB
+        | RecordType::ANY => true,
+        // TKEY, TSIG and IXFR
+        RecordType::OTHER(record_type) => (249..=251).contains(&record_type),
+        _ => false,
+    }
+}
+
+/// The record as it is kept in the zone, with the class of the zone and no wire length
+fn stored(record: &Answer) -> Answer {
+    let mut record = record.clone();
+    let meta = record.meta_mut();
+    meta.class = Class::IN;
+    meta.len = 0;
+    record
+}
+
+/// Whether the records have the same owner, type and data, whatever their class and TTL
+fn same_record(a: &Answer, b: &Answer) -> bool {
+    let normalized = |record: &Answer| {
+        let mut record = stored(record);
+        record.meta_mut().ttl = 0;
+        record
+    };
+    normalized(a) == normalized(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::SystemTime};
+
+    use crate::{
+        authority::{Acl, ZoneSet},
+        dnssec::{Denial, SigningPolicy, ZoneSigner, NSEC, RRSIG},
+        parse::parser::DnsParser,
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            header::{Flags, Header},
+            opcode::Opcode,
+            packet::Packet,
+            query::QueryBuilder,
+            question::Question,
+            record_type::RecordType,
+            response_code::ResponseCode,
+        },
+        resolver::ResolveOptions,
+        tsig::TsigKey,
+        zonefile::Zone,
+    };
+
+    use super::UpdateBuilder;
+
+    fn zones() -> ZoneSet {
+        let zone = Zone::parse(
+            "$TTL 60
+@       SOA   ns hostmaster 10 7200 3600 1209600 300
+        NS    ns
+ns      A     192.0.2.53
+www     A     192.0.2.80
+alias   CNAME www
+",
+            &"example.lan".parse().unwrap(),
+        )
+        .unwrap();
+        ZoneSet::new([zone]).update_acl(Acl {
+            addresses: vec!["192.0.2.1".parse().unwrap()],
+            keys: vec![],
+        })
+    }
+
+    fn record(name: &str, class: Class, ttl: usize, ipv4: Option<[u8; 4]>) -> Answer {
+        let meta = AnswerMeta {
+            name: name.parse().unwrap(),
+            r#type: RecordType::A,
+            class,
+            ttl,
+            len: 0,
+        };
+        match ipv4 {
+            Some(ipv4) => Answer::A {
+                meta,
+                ipv4: Ipv4Addr::from(ipv4),
+            },
+            None => Answer::Unknown {
+                meta,
+                rdata: vec![],
+            },
+        }
+    }
+
+    fn update(prerequisites: Vec<Answer>, updates: Vec<Answer>) -> Vec<u8> {
+        update_of("example.lan", prerequisites, updates)
+    }
+
+    fn update_of(zone: &str, prerequisites: Vec<Answer>, updates: Vec<Answer>) -> Vec<u8> {
+        Packet {
+            header: Header {
+                request_id: 3,
+                flags: Flags {
+                    query: true,
+                    opcode: Opcode::Update,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            },
+            questions: vec![Question {
+                domain_name: zone.parse().unwrap(),
+                r#type: RecordType::SOA,
+                class: Class::IN,
+            }],
+            answers: prerequisites,
+            authorities: updates,
+            additionals: vec![],
+        }
+        .to_bytes()
+    }
+
+    fn respond(zones: &ZoneSet, message: &[u8]) -> ResponseCode {
+        let response = zones
+            .respond_update(message, "192.0.2.1".parse().unwrap())
+            .unwrap();
+        let response = DnsParser::new(&response).parse_packet().unwrap();
+        assert_eq!(response.header.opcode(), Opcode::Update);
+        assert_eq!(response.header.request_id, 3);
+        response.header.rcode()
+    }
+
+    fn records(zones: &ZoneSet, name: &str, record_type: RecordType) -> Vec<String> {
+        zones
+            .lookup(&name.parse().unwrap(), record_type)
+            .unwrap()
+            .answers
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    fn serial(zones: &ZoneSet) -> Option<u32> {
+        zones.get(&"example.lan".parse().unwrap()).unwrap().serial()
+    }
+
+    #[test]
+    fn test_update() {
+        let zones = zones();
+        let add = update(
+            vec![],
+            vec![record(
+                "host.example.lan",
+                Class::IN,
+                300,
+                Some([192, 0, 2, 9]),
+            )],
+        );
+        assert_eq!(respond(&zones, &add), ResponseCode::NoError);
+        assert_eq!(
+            records(&zones, "host.example.lan", RecordType::A),
+            ["host.example.lan. 300 IN A 192.0.2.9"]
+        );
+        assert_eq!(serial(&zones), Some(11));
+        // adding the same record again changes nothing
+        assert_eq!(respond(&zones, &add), ResponseCode::NoError);
+        assert_eq!(serial(&zones), Some(11));
+
+        // only from allowed clients
+        let refused = zones
+            .respond_update(&add, "198.51.100.1".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            DnsParser::new(&refused)
+                .parse_packet()
+                .unwrap()
+                .header
+                .rcode(),
+            ResponseCode::Refused
+        );
+
+        // the name must not be in use yet, and nothing is applied if a prerequisite fails
+        let create = update(
+            vec![Answer::Unknown {
+                meta: AnswerMeta {
+                    r#type: RecordType::ANY,
+                    ..record("host.example.lan", Class::NONE, 0, None)
+                        .meta()
+                        .clone()
+                },
+                rdata: vec![],
+            }],
+            vec![record(
+                "host.example.lan",
+                Class::IN,
+                300,
+                Some([192, 0, 2, 10]),
+            )],
+        );
+        assert_eq!(respond(&zones, &create), ResponseCode::YXDomain);
+        assert_eq!(records(&zones, "host.example.lan", RecordType::A).len(), 1);
+
+        // the RRset must be exactly the given records
+        let replace = |expected: [u8; 4]| {
+            update(
+                vec![record("www.example.lan", Class::IN, 0, Some(expected))],
+                vec![
+                    record("www.example.lan", Class::ANY, 0, None),
+                    record("www.example.lan", Class::IN, 60, Some([192, 0, 2, 81])),
+                ],
+            )
+        };
+        assert_eq!(
+            respond(&zones, &replace([192, 0, 2, 1])),
+            ResponseCode::NXRRSet
+        );
+        assert_eq!(
+            respond(&zones, &replace([192, 0, 2, 80])),
+            ResponseCode::NoError
+        );
+        assert_eq!(
+            records(&zones, "www.example.lan", RecordType::A),
+            ["www.example.lan. 60 IN A 192.0.2.81"]
+        );
+
+        // records outside of the zone, or with a TTL in a prerequisite
+        let outside = update(
+            vec![],
+            vec![record(
+                "www.example.com",
+                Class::IN,
+                60,
+                Some([192, 0, 2, 1]),
+            )],
+        );
+        assert_eq!(respond(&zones, &outside), ResponseCode::NotZone);
+        let ttl = update(
+            vec![record("www.example.lan", Class::ANY, 60, None)],
+            vec![],
+        );
+        assert_eq!(respond(&zones, &ttl), ResponseCode::FormErr);
+
+        // aliases cannot get other records
+        let alias = update(
+            vec![],
+            vec![record(
+                "alias.example.lan",
+                Class::IN,
+                60,
+                Some([192, 0, 2, 1]),
+            )],
+        );
+        assert_eq!(respond(&zones, &alias), ResponseCode::NoError);
+        assert_eq!(
+            records(&zones, "alias.example.lan", RecordType::CNAME),
+            ["alias.example.lan. 60 IN CNAME www.example.lan."]
+        );
+
+        // deleting a single record, and everything at a name
+        let delete = update(
+            vec![],
+            vec![record(
+                "host.example.lan",
+                Class::NONE,
+                0,
+                Some([192, 0, 2, 9]),
+            )],
+        );
+        assert_eq!(respond(&zones, &delete), ResponseCode::NoError);
+        let answer = zones
+            .lookup(&"host.example.lan".parse().unwrap(), RecordType::A)
+            .unwrap();
+        assert_eq!(answer.response_code, ResponseCode::NXDomain);
+        let apex = update(
+            vec![],
+            vec![Answer::Unknown {
+                meta: AnswerMeta {
+                    r#type: RecordType::ANY,
+                    ..record("example.lan", Class::ANY, 0, None).meta().clone()
+                },
+                rdata: vec![],
+            }],
+        );
+        assert_eq!(respond(&zones, &apex), ResponseCode::NoError);
+        assert_eq!(records(&zones, "example.lan", RecordType::NS).len(), 1);
+        assert!(serial(&zones).is_some());
+
+        assert_eq!(
+            respond(&zones, &update_of("example.com", vec![], vec![])),
+            ResponseCode::NotAuth
+        );
+        let query = QueryBuilder::new("example.lan".parse().unwrap()).build();
+        assert!(zones
+            .respond_update(&query, "192.0.2.1".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn test_update_signed() {
+        let origin = "example.lan".parse().unwrap();
+        let policy = SigningPolicy {
+            denial: Denial::Nsec,
+            ..SigningPolicy::default()
+        };
+        let signer = ZoneSigner::new(origin, policy, vec![]);
+        signer.roll_keys(SystemTime::now()).unwrap();
+        let zones = zones().signer(signer);
+        let lookup = |name: &str, record_type| {
+            let zone = zones.find(&"example.lan".parse().unwrap()).unwrap();
+            zone.lookup_dnssec(&name.parse().unwrap(), record_type)
+        };
+        let types = |records: &[Answer]| -> Vec<u16> {
+            records
+                .iter()
+                .map(|record| record.meta().r#type.into())
+                .collect()
+        };
+        // the name, and the wildcard covered by the apex
+        let nsec_owners = |name: &str| -> Vec<String> {
+            lookup(name, RecordType::A)
+                .authorities
+                .iter()
+                .filter(|record| u16::from(record.meta().r#type) == NSEC)
+                .map(|record| record.meta().name.to_string())
+                .collect()
+        };
+        assert_eq!(
+            nsec_owners("host.example.lan"),
+            ["alias.example.lan", "example.lan"]
+        );
+
+        // added records are signed, and the NSEC chain covers their name
+        let add = update(
+            vec![],
+            vec![record(
+                "host.example.lan",
+                Class::IN,
+                300,
+                Some([192, 0, 2, 9]),
+            )],
+        );
+        assert_eq!(respond(&zones, &add), ResponseCode::NoError);
+        assert_eq!(serial(&zones), Some(11));
+        assert_eq!(
+            types(&lookup("host.example.lan", RecordType::A).answers),
+            [1, RRSIG]
+        );
+        assert_eq!(
+            types(&lookup("www.example.lan", RecordType::A).answers),
+            [1, RRSIG]
+        );
+        assert_eq!(
+            nsec_owners("hosts.example.lan"),
+            ["host.example.lan", "example.lan"]
+        );
+        let soa = lookup("example.lan", RecordType::SOA);
+        assert_eq!(types(&soa.answers), [6, RRSIG]);
+
+        // and deleted ones leave the chain again
+        let delete = update(
+            vec![],
+            vec![record("host.example.lan", Class::ANY, 0, None)],
+        );
+        assert_eq!(respond(&zones, &delete), ResponseCode::NoError);
+        assert_eq!(serial(&zones), Some(12));
+        let answer = lookup("host.example.lan", RecordType::A);
+        assert_eq!(answer.response_code, ResponseCode::NXDomain);
+        assert_eq!(
+            nsec_owners("hosts.example.lan"),
+            ["alias.example.lan", "example.lan"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_builder() {
+        let key: TsigKey = "update.key:c2VjcmV0".parse().unwrap();
+        let zones = zones().update_acl(Acl {
+            addresses: vec![],
+            keys: vec![key.clone()],
+        });
+        let host = "host.example.lan".parse().unwrap();
+        let www = "www.example.lan".parse().unwrap();
+        let update = UpdateBuilder::new("example.lan".parse().unwrap())
+            .id(3)
+            .key(key)
+            .require_name_not_in_use(&host)
+            .require_rrset_exists(&www, RecordType::A)
+            .require_record(record(
+                "www.example.lan",
+                Class::IN,
+                60,
+                Some([192, 0, 2, 80]),
+            ))
+            .add_record(record(
+                "host.example.lan",
+                Class::IN,
+                300,
+                Some([192, 0, 2, 9]),
+            ))
+            .delete_record(record(
+                "www.example.lan",
+                Class::IN,
+                60,
+                Some([192, 0, 2, 80]),
+            ))
+            .add_record(record(
+                "www.example.lan",
+                Class::IN,
+                60,
+                Some([192, 0, 2, 81]),
+            ));
+        assert_eq!(respond(&zones, &update.build()), ResponseCode::NoError);
+        assert_eq!(
+            records(&zones, "host.example.lan", RecordType::A),
+            ["host.example.lan. 300 IN A 192.0.2.9"]
+        );
+        assert_eq!(
+            records(&zones, "www.example.lan", RecordType::A),
+            ["www.example.lan. 60 IN A 192.0.2.81"]
+        );
+        // unsigned updates, and ones whose prerequisites no longer hold
+        let unsigned = UpdateBuilder::new("example.lan".parse().unwrap())
+            .id(3)
+            .delete_rrset(&www, RecordType::A);
+        assert_eq!(respond(&zones, &unsigned.build()), ResponseCode::Refused);
+        assert_eq!(respond(&zones, &update.build()), ResponseCode::YXDomain);
+
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let zones = std::sync::Arc::new(zones);
+        let served = std::sync::Arc::clone(&zones);
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            for _ in 0..2 {
+                let (len, client) = server.recv_from(&mut buffer).await.unwrap();
+                let response = served.respond_update(&buffer[..len], client.ip()).unwrap();
+                server.send_to(&response, client).await.unwrap();
+            }
+        });
+        let opts = ResolveOptions {
+            timeout: std::time::Duration::from_millis(500),
+            ..ResolveOptions::default()
+        };
+        let delete = update.clone().delete_name(&host);
+        assert!(delete.send_async(&address, &opts).await.is_err());
+        let delete = UpdateBuilder::new("example.lan".parse().unwrap())
+            .key("update.key:c2VjcmV0".parse().unwrap())
+            .require_name_in_use(&host)
+            .require_rrset_not_exists(&host, RecordType::CNAME)
+            .delete_name(&host);
+        delete.send_async(&address, &opts).await.unwrap();
+        assert!(records(&zones, "host.example.lan", RecordType::A).is_empty());
+    }
+}
@@ -0,0 +1,461 @@
+//! Selection of and failover between several upstream DNS servers.
+
+use std::{
+    net::UdpSocket,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::ResolveError,
+    parse::parser::DnsParser,
+    protocol::response_code::ResponseCode,
+    resolver::{resolve_query, resolve_query_async, QuerySocket, ResolveOptions},
+};
+
+/// After this many failed queries in a row an upstream is considered unhealthy and only tried
+/// once all healthy ones failed as well
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Weight of a new RTT sample in the smoothed RTT, as in
+/// https://datatracker.ietf.org/doc/html/rfc6298#section-2
+const RTT_SAMPLE_WEIGHT: f64 = 1.0 / 8.0;
+
+/// A raw response, along with the upstream that sent it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamResponse {
+    pub response: Vec<u8>,
+    pub upstream: String,
+    /// Time from sending the query until the response arrived, including retries
+    pub rtt: Duration,
+}
+
+/// In which order the upstreams of an [`UpstreamPool`] are tried
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Prefer the healthy upstream with the lowest smoothed RTT, probing the others every now and
+    /// then so their RTT stays up to date
+    #[default]
+    Fastest,
+    /// Stick to the upstream that answered last and only move on once it fails
+    Failover,
+    /// Always start with the first upstream, like dnsmasq's `strict-order`
+    StrictOrder,
+}
+
+/// What is known about a single upstream
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UpstreamStats {
+    /// Smoothed round trip time of successful queries, `None` until the first one
+    pub srtt: Option<Duration>,
+    pub queries: u64,
+    pub errors: u64,
+    pub consecutive_failures: u32,
+    /// When a query was last sent to this upstream
+    pub last_used: Option<Instant>,
+}
+
+impl UpstreamStats {
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures < MAX_CONSECUTIVE_FAILURES
+    }
+
+    /// Share of queries that failed
+    pub fn error_rate(&self) -> f64 {
+        if self.queries == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.queries as f64
+    }
+
+    fn record_success(&mut self, rtt: Duration) {
+        self.queries += 1;
+        self.consecutive_failures = 0;
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => srtt.mul_f64(1.0 - RTT_SAMPLE_WEIGHT) + rtt.mul_f64(RTT_SAMPLE_WEIGHT),
+            None => rtt,
+        });
+    }
+
+    fn record_failure(&mut self) {
+        self.queries += 1;
+        self.errors += 1;
+        self.consecutive_failures += 1;
+    }
+}
+
+#[derive(Debug)]
+struct PoolState {
+    stats: Vec<UpstreamStats>,
+    /// Index of the upstream that answered last
+    current: usize,
+}
+
+/// A set of upstream DNS servers along with their latency and error statistics.
+///
+/// Every query is sent to one upstream at a time, in the order given by the [`Strategy`]. An
+/// upstream that times out or responds with SERVFAIL is skipped in favour of the next one.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    servers: Vec<String>,
+    strategy: Strategy,
+    probe_interval: Duration,
+    state: Mutex<PoolState>,
+}
+
+impl UpstreamPool {
+    /// Creates a pool from server addresses in `host:port` form, in order of preference
+    pub fn new(servers: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let servers: Vec<String> = servers.into_iter().map(Into::into).collect();
+        Self {
+            state: Mutex::new(PoolState {
+                stats: vec![UpstreamStats::default(); servers.len()],
+                current: 0,
+            }),
+            servers,
+            strategy: Strategy::default(),
+            probe_interval: Duration::from_secs(30),
+        }
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// How long an upstream may go unused before it is probed again: with [`Strategy::Fastest`] the
+    /// next query is sent to it first, and an unhealthy upstream is considered healthy again
+    pub fn probe_interval(mut self, probe_interval: Duration) -> Self {
+        self.probe_interval = probe_interval;
+        self
+    }
+
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// Statistics of all upstreams, in the same order as [`UpstreamPool::servers`]
+    pub fn stats(&self) -> Vec<UpstreamStats> {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// The server the next query would be sent to first, not taking probing into account
+    pub fn current(&self) -> Option<&str> {
+        let first = *self.order(false).first()?;
+        Some(&self.servers[first])
+    }
+
+    /// Sends the raw `query` to the upstreams one by one, see [`resolve_query`]
+    pub fn resolve_query(
+        &self,
+        query: &[u8],
+        socket: &UdpSocket,
+        opts: &ResolveOptions,
+    ) -> Result<Vec<u8>, ResolveError> {
+        Ok(self.exchange(query, socket, opts)?.response)
+    }
+
+    /// Sends the raw `query` to the upstreams one by one like [`UpstreamPool::resolve_query`],
+    /// and tells which one responded how quickly
+    pub fn exchange(
+        &self,
+        query: &[u8],
+        socket: &UdpSocket,
+        opts: &ResolveOptions,
+    ) -> Result<UpstreamResponse, ResolveError> {
+        let mut last = Err(ResolveError::NoUpstreams);
+        for index in self.order(true) {
+            let server = &self.servers[index];
+            let start = self.begin(index);
+            let result = resolve_query(query, server, socket, opts);
+            match self.finish(index, start, result) {
+                Ok(response) if !is_server_failure(&response.response) => return Ok(response),
+                result => last = result,
+            }
+            tracing::warn!(upstream = server, "Upstream failed, trying the next one");
+        }
+        last
+    }
+
+    /// Asynchronously sends the raw `query` to the upstreams one by one, see
+    /// [`resolve_query_async`]
+    pub async fn resolve_query_async(
+        &self,
+        query: &[u8],
+        socket: &impl QuerySocket,
+        opts: &ResolveOptions,
+    ) -> Result<Vec<u8>, ResolveError> {
+        Ok(self.exchange_async(query, socket, opts).await?.response)
+    }
+
+    /// Asynchronously sends the raw `query` to the upstreams one by one, see
+    /// [`UpstreamPool::exchange`]
+    pub async fn exchange_async(
+        &self,
+        query: &[u8],
+        socket: &impl QuerySocket,
+        opts: &ResolveOptions,
+    ) -> Result<UpstreamResponse, ResolveError> {
+        let mut last = Err(ResolveError::NoUpstreams);
+        for index in self.order(true) {
+            let server = &self.servers[index];
+            let start = self.begin(index);
+            let result = resolve_query_async(query, server, socket, opts).await;
+            match self.finish(index, start, result) {
+                Ok(response) if !is_server_failure(&response.response) => return Ok(response),
+                result => last = result,
+            }
+            tracing::warn!(upstream = server, "Upstream failed, trying the next one");
+        }
+        last
+    }
+
+    /// Records how the query to upstream `index` went
+    fn finish(
+        &self,
+        index: usize,
+        start: Instant,
+        result: Result<Vec<u8>, ResolveError>,
+    ) -> Result<UpstreamResponse, ResolveError> {
+        let rtt = start.elapsed();
+        match &result {
+            Ok(response) if !is_server_failure(response) => self.succeeded(index, rtt),
+            _ => self.failed(index),
+        }
+        // the fields are only evaluated if the event is logged
+        tracing::debug!(
+            upstream = self.servers[index],
+            rtt_ms = rtt.as_millis() as u64,
+            outcome = match &result {
+                Ok(response) if is_server_failure(response) => "server failure".to_string(),
+                Ok(_) => "answered".to_string(),
+                Err(e) => e.to_string(),
+            },
+            "Upstream exchanged query"
+        );
+        Ok(UpstreamResponse {
+            response: result?,
+            upstream: self.servers[index].clone(),
+            rtt,
+        })
+    }
+
+    /// Indices of all upstreams in the order they should be tried in
+    fn order(&self, probe: bool) -> Vec<usize> {
+        let state = self.state.lock().unwrap();
+        let len = self.servers.len();
+        let now = Instant::now();
+        let stale = |i: usize| {
+            state.stats[i]
+                .last_used
+                .is_some_and(|used| now.duration_since(used) >= self.probe_interval)
+        };
+        // an unhealthy upstream gets another chance once it was left alone for a while
+        let healthy = |i: usize| state.stats[i].is_healthy() || stale(i);
+
+        let mut order: Vec<usize> = match self.strategy {
+            Strategy::StrictOrder => (0..len).collect(),
+            Strategy::Failover | Strategy::Fastest => {
+                (0..len).map(|i| (state.current + i) % len).collect()
+            }
+        };
+
+        // unhealthy upstreams go last, but keep their relative order
+        order.sort_by_key(|&i| !healthy(i));
+
+        if self.strategy == Strategy::Fastest {
+            // upstreams without any RTT sample yet are tried first, so every one gets measured
+            order.sort_by_key(|&i| (!healthy(i), state.stats[i].srtt.unwrap_or(Duration::ZERO)));
+
+            let probed = order.iter().skip(1).position(|&i| stale(i));
+            if let (true, Some(probed)) = (probe, probed) {
+                let probed = order.remove(probed + 1);
+                order.insert(0, probed);
+            }
+        }
+        order
+    }
+
+    fn begin(&self, index: usize) -> Instant {
+        let now = Instant::now();
+        self.state.lock().unwrap().stats[index].last_used = Some(now);
+        now
+    }
+
+    fn succeeded(&self, index: usize, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.stats[index].record_success(rtt);
+        state.current = index;
+    }
+
+    fn failed(&self, index: usize) {
+        self.state.lock().unwrap().stats[index].record_failure();
+    }
+}
+
+impl From<&str> for UpstreamPool {
+    fn from(server: &str) -> Self {
+        Self::new([server])
+    }
+}
+
+fn is_server_failure(response: &[u8]) -> bool {
+    DnsParser::new(response)
+        .parse_header()
+        .is_ok_and(|header| header.rcode() == ResponseCode::ServFail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use crate::{
+        protocol::{
+            header::{Flags, Header},
+            response_code::ResponseCode,
+        },
+        resolver::ResolveOptions,
+    };
+
+    use super::{Strategy, UpstreamPool};
+
+    /// Answers every query on a background thread after `delay` with an empty response with
+    /// `rcode`
+    fn mock_upstream(rcode: ResponseCode, delay: Duration) -> String {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        std::thread::spawn(move || loop {
+            let mut query = [0; 512];
+            let Ok((_, client)) = server.recv_from(&mut query) else {
+                return;
+            };
+            std::thread::sleep(delay);
+            let header: [u8; 12] = Header {
+                request_id: u16::from_be_bytes([query[0], query[1]]),
+                flags: Flags {
+                    query: false,
+                    response_code: rcode,
+                    ..Flags::default()
+                },
+                ..Header::default()
+            }
+            .into();
+            server.send_to(&header, client).unwrap();
+        });
+        address
+    }
+
+    fn opts() -> ResolveOptions {
+        ResolveOptions {
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            jitter: Duration::ZERO,
+            ..ResolveOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_failover() {
+        // nothing listens on this one, so it times out
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_address = silent.local_addr().unwrap().to_string();
+        let failing = mock_upstream(ResponseCode::ServFail, Duration::ZERO);
+        let working = mock_upstream(ResponseCode::NoError, Duration::ZERO);
+
+        let upstreams =
+            UpstreamPool::new([&silent_address, &failing, &working]).strategy(Strategy::Failover);
+        assert_eq!(upstreams.current(), Some(silent_address.as_str()));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let query = [0u8, 42, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let response = upstreams.resolve_query(&query, &socket, &opts()).unwrap();
+        assert_eq!(&response[0..2], &[0, 42]);
+        assert_eq!(upstreams.current(), Some(working.as_str()));
+
+        let stats = upstreams.stats();
+        assert_eq!(stats[0].errors, 1);
+        assert_eq!(stats[1].errors, 1);
+        assert_eq!(stats[2].errors, 0);
+        assert!(stats[2].srtt.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failover_async() {
+        let failing = mock_upstream(ResponseCode::ServFail, Duration::ZERO);
+        let working = mock_upstream(ResponseCode::NoError, Duration::ZERO);
+        let upstreams = UpstreamPool::new([&failing, &working]).strategy(Strategy::Failover);
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = [0u8; 12];
+        upstreams
+            .resolve_query_async(&query, &socket, &opts())
+            .await
+            .unwrap();
+        assert_eq!(upstreams.current(), Some(working.as_str()));
+    }
+
+    #[test]
+    fn test_strict_order() {
+        let failing = mock_upstream(ResponseCode::ServFail, Duration::ZERO);
+        let working = mock_upstream(ResponseCode::NoError, Duration::ZERO);
+        let upstreams = UpstreamPool::new([&failing, &working]).strategy(Strategy::StrictOrder);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        upstreams
+            .resolve_query(&[0u8; 12], &socket, &opts())
+            .unwrap();
+        assert_eq!(upstreams.current(), Some(failing.as_str()));
+
+        // until it is considered unhealthy
+        for _ in 0..2 {
+            upstreams
+                .resolve_query(&[0u8; 12], &socket, &opts())
+                .unwrap();
+        }
+        assert_eq!(upstreams.current(), Some(working.as_str()));
+        assert_eq!(upstreams.stats()[0].error_rate(), 1.0);
+    }
+
+    #[test]
+    fn test_fastest() {
+        let slow = mock_upstream(ResponseCode::NoError, Duration::from_millis(20));
+        let fast = mock_upstream(ResponseCode::NoError, Duration::ZERO);
+        let upstreams = UpstreamPool::new([&slow, &fast]).probe_interval(Duration::from_secs(60));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // both get measured once, then the faster one is preferred
+        for _ in 0..4 {
+            upstreams
+                .resolve_query(&[0u8; 12], &socket, &opts())
+                .unwrap();
+        }
+        let stats = upstreams.stats();
+        assert_eq!(stats[0].queries, 1);
+        assert_eq!(stats[1].queries, 3);
+        assert_eq!(upstreams.current(), Some(fast.as_str()));
+
+        // once the probe interval passed, the slow one is tried again
+        let upstreams = upstreams.probe_interval(Duration::ZERO);
+        upstreams
+            .resolve_query(&[0u8; 12], &socket, &opts())
+            .unwrap();
+        assert_eq!(upstreams.stats()[0].queries, 2);
+    }
+
+    #[test]
+    fn test_all_upstreams_fail() {
+        let failing = mock_upstream(ResponseCode::ServFail, Duration::ZERO);
+        let upstreams = UpstreamPool::new([&failing]);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // SERVFAIL of the last upstream is still passed on
+        let response = upstreams
+            .resolve_query(&[0u8; 12], &socket, &opts())
+            .unwrap();
+        assert_eq!(response[3] & 0x0F, 2);
+
+        assert!(UpstreamPool::new(Vec::<String>::new())
+            .resolve_query(&[0u8; 12], &socket, &opts())
+            .is_err());
+    }
+}
@@ -0,0 +1,240 @@
+//! Tracking the records of a name over time, eg. for service discovery.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+    error::ResolveError,
+    protocol::{answer::Answer, record_type::RecordType},
+    resolver::{resolve_record_async, ResolveOptions},
+    upstream::UpstreamPool,
+};
+
+/// Default of [`Watch::min_interval`]
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default of [`Watch::max_interval`]
+pub const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Default of [`Watch::retry_interval`]
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Starts watching the records of `record_type` for `domain`, see [`Watch`]
+pub fn watch(
+    domain: &str,
+    record_type: RecordType,
+    upstreams: Arc<UpstreamPool>,
+    opts: ResolveOptions,
+) -> Watch {
+    Watch {
+        domain: domain.to_string(),
+        record_type,
+        upstreams,
+        opts,
+        min_interval: DEFAULT_MIN_INTERVAL,
+        max_interval: DEFAULT_MAX_INTERVAL,
+        retry_interval: DEFAULT_RETRY_INTERVAL,
+        current: None,
+        next_query: None,
+    }
+}
+
+/// Resolves a name again whenever the TTL of its records runs out, and yields the records only
+/// when they changed. TTLs and the order of the records are not considered a change.
+///
+/// [`Watch::next`] never finishes, so a `futures::Stream` is simply
+/// `futures::stream::unfold(watch, |mut watch| async { Some((watch.next().await, watch)) })`.
+///
+/// ```no_run
+/// # async fn run() {
+/// use std::sync::Arc;
+///
+/// use dns::{protocol::record_type::RecordType, resolver::ResolveOptions, watch::watch};
+///
+/// let upstreams = Arc::new("1.1.1.1:53".into());
+/// let mut watch = watch("example.com", RecordType::A, upstreams, ResolveOptions::default());
+/// loop {
+///     match watch.next().await {
+///         Ok(answers) => println!("now {answers:?}"),
+///         Err(e) => println!("could not resolve: {e}"),
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Watch {
+    domain: String,
+    record_type: RecordType,
+    upstreams: Arc<UpstreamPool>,
+    opts: ResolveOptions,
+    min_interval: Duration,
+    max_interval: Duration,
+    retry_interval: Duration,
+    /// Records yielded last
+    current: Option<Vec<Answer>>,
+    next_query: Option<Instant>,
+}
+
+impl Watch {
+    /// Resolves at most this often, even if the TTL is shorter
+    pub fn min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Resolves at least this often, even if the TTL is longer. Wins over
+    /// [`Watch::min_interval`].
+    pub fn max_interval(mut self, max_interval: Duration) -> Self {
+        self.max_interval = max_interval;
+        self
+    }
+
+    /// How long to wait before resolving again after an error, or if there are no records
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    /// The records yielded last
+    pub fn current(&self) -> Option<&[Answer]> {
+        self.current.as_deref()
+    }
+
+    /// Resolves the name right away the first time, and afterwards waits until the records
+    /// differ from those yielded last. Errors are yielded as well, the name is resolved again
+    /// after [`Watch::retry_interval`]. Dropping the future before it completes loses nothing.
+    pub async fn next(&mut self) -> Result<Vec<Answer>, ResolveError> {
+        loop {
+            if let Some(next_query) = self.next_query {
+                tokio::time::sleep_until(next_query).await;
+            }
+
+            let resolved =
+                resolve_record_async(&self.domain, self.record_type, &self.upstreams, &self.opts)
+                    .await;
+            let answers = match resolved {
+                Ok(answers) => answers,
+                Err(e) => {
+                    self.next_query = Some(Instant::now() + self.retry_interval);
+                    return Err(e);
+                }
+            };
+
+            self.next_query = Some(Instant::now() + self.refresh_interval(&answers));
+            if matches!(&self.current, Some(current) if same_records(current, &answers)) {
+                continue;
+            }
+            self.current = Some(answers.clone());
+            return Ok(answers);
+        }
+    }
+
+    fn refresh_interval(&self, answers: &[Answer]) -> Duration {
+        let Some(ttl) = answers.iter().map(|answer| answer.meta().ttl).min() else {
+            return self.retry_interval;
+        };
+        Duration::from_secs(ttl as u64)
+            .max(self.min_interval)
+            .min(self.max_interval)
+    }
+}
+
+/// Whether both contain the same records, regardless of their order and TTLs
+fn same_records(a: &[Answer], b: &[Answer]) -> bool {
+    let without_ttl = |answers: &[Answer]| -> Vec<Answer> {
+        answers
+            .iter()
+            .map(|answer| {
+                let mut answer = answer.clone();
+                answer.meta_mut().ttl = 0;
+                answer.meta_mut().len = 0;
+                answer
+            })
+            .collect()
+    };
+    let (a, mut b) = (without_ttl(a), without_ttl(b));
+    a.len() == b.len()
+        && a.iter()
+            .all(|answer| match b.iter().position(|other| other == answer) {
+                Some(position) => {
+                    b.swap_remove(position);
+                    true
+                }
+                None => false,
+            })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        parse::parser::DnsParser,
+        protocol::{
+            answer::{Answer, AnswerMeta},
+            class::Class,
+            record_type::RecordType,
+        },
+        resolver::ResolveOptions,
+        transport::{DnsTransport, Transport},
+    };
+
+    use super::watch;
+
+    /// Answers with 192.0.2.1 for the first three queries and with 192.0.2.2 afterwards, with
+    /// TTLs that change every time
+    #[derive(Debug, Default)]
+    struct Changing(AtomicUsize);
+
+    impl DnsTransport for Changing {
+        fn exchange(
+            &self,
+            query: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            let queries = self.0.fetch_add(1, Ordering::SeqCst);
+            let mut response = DnsParser::new(query).parse_packet()?;
+            response.header.flags.query = false;
+            response.answers = vec![Answer::A {
+                meta: AnswerMeta {
+                    name: "svc.example.com".parse()?,
+                    r#type: RecordType::A,
+                    class: Class::IN,
+                    ttl: 10 - queries,
+                    len: 4,
+                },
+                ipv4: [192, 0, 2, if queries < 3 { 1 } else { 2 }].into(),
+            }];
+            Ok(response.to_bytes())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let transport = Arc::new(Changing::default());
+        let opts = ResolveOptions::default().transport(Transport::Custom(transport.clone()));
+        let mut watch = watch(
+            "svc.example.com",
+            RecordType::A,
+            Arc::new("127.0.0.1:9".into()),
+            opts,
+        )
+        .min_interval(Duration::ZERO)
+        .max_interval(Duration::from_millis(10));
+
+        let answers = watch.next().await.unwrap();
+        assert!(matches!(&answers[..], [Answer::A { ipv4, .. }] if ipv4.octets()[3] == 1));
+        assert!(watch.current().is_some());
+
+        // the records only change with the fourth query
+        let answers = watch.next().await.unwrap();
+        assert!(matches!(&answers[..], [Answer::A { ipv4, .. }] if ipv4.octets()[3] == 2));
+        assert_eq!(transport.0.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,503 @@
+//! Zone files, the master file format authoritative data is usually kept in.
+//! https://datatracker.ietf.org/doc/html/rfc1035#section-5
+
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use crate::protocol::{
+    answer::{Answer, AnswerMeta},
+    class::Class,
+    name::DnsName,
+    record_type::RecordType,
+};
+
+/// How deeply `$INCLUDE` directives may nest, which stops files from including each other forever
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// The records of a zone file, in the order they appear in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    /// Origin the file was read with
+    pub origin: DnsName,
+    pub records: Vec<Answer>,
+}
+
+impl Zone {
+    /// Reads a zone file, see [`Zone::parse`]. Files named by `$INCLUDE` are looked up relative to
+    /// the directory of `path`.
+    pub fn load(path: impl AsRef<Path>, origin: &DnsName) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Self::parse_in(&contents, origin, dir)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Parses a zone file, with relative names and `@` below `origin` until a `$ORIGIN` directive
+    /// changes it. Entries may span lines within parentheses, and the owner, TTL and class can be
+    /// left out to take those of the previous record, or the TTL of a `$TTL` directive. Files
+    /// named by `$INCLUDE` are looked up relative to the working directory.
+    ///
+    /// ```
+    /// use dns::zonefile::Zone;
+    ///
+    /// let zone = Zone::parse(
+    ///     "$TTL 1h
+    /// @   IN SOA ns hostmaster ( 1 7200 3600 1209600 300 )
+    ///     IN NS  ns
+    /// ns  A      192.0.2.53 ; the name server",
+    ///     &"example.com".parse().unwrap(),
+    /// )
+    /// .unwrap();
+    /// assert_eq!(zone.records[2].to_string(), "ns.example.com. 3600 IN A 192.0.2.53");
+    /// ```
+    pub fn parse(contents: &str, origin: &DnsName) -> Result<Self, String> {
+        Self::parse_in(contents, origin, Path::new("."))
+    }
+
+    fn parse_in(contents: &str, origin: &DnsName, dir: &Path) -> Result<Self, String> {
+        let mut state = State {
+            origin: origin.clone(),
+            owner: None,
+            last_ttl: None,
+            default_ttl: None,
+            class: Class::IN,
+            records: vec![],
+        };
+        state.read(contents, dir, 0)?;
+        Ok(Self {
+            origin: origin.clone(),
+            records: state.records,
+        })
+    }
+
+    /// This zone as a zone file with absolute names, padded into columns. The SOA record of the
+    /// origin goes first, the other records stay in their order.
+    ///
+    /// ```
+    /// use dns::zonefile::Zone;
+    ///
+    /// let zone = Zone::parse("$TTL 300\nwww A 192.0.2.1\nmail.example.com. 60 MX 10 @", &"example.com".parse().unwrap()).unwrap();
+    /// assert_eq!(
+    ///     zone.to_zonefile(),
+    ///     "$ORIGIN example.com.\n\
+    ///      www.example.com.  300 IN A  192.0.2.1\n\
+    ///      mail.example.com. 60  IN MX 10 example.com.\n"
+    /// );
+    /// ```
+    pub fn to_zonefile(&self) -> String {
+        let soa = self.soa();
+        let records: Vec<_> = soa
+            .into_iter()
+            .chain(
+                self.records
+                    .iter()
+                    .filter(|&record| soa.is_none_or(|soa| !std::ptr::eq(soa, record))),
+            )
+            .map(|record| {
+                let meta = record.meta();
+                [
+                    meta.name.to_fqdn(),
+                    meta.ttl.to_string(),
+                    meta.class.to_string(),
+                    meta.r#type.to_string(),
+                    record.rdata_to_string(),
+                ]
+            })
+            .collect();
+        let mut widths = [0; 4];
+        for fields in &records {
+            for (width, field) in widths.iter_mut().zip(fields) {
+                *width = (*width).max(field.len());
+            }
+        }
+
+        let mut zonefile = format!("$ORIGIN {}\n", self.origin.to_fqdn());
+        let [owner_width, ttl_width, class_width, type_width] = widths;
+        for [owner, ttl, class, record_type, rdata] in records {
+            zonefile.push_str(&format!(
+                "{owner:owner_width$} {ttl:ttl_width$} {class:class_width$} \
+                 {record_type:type_width$} {rdata}\n"
+            ));
+        }
+        zonefile
+    }
+
+    /// The SOA record of the origin, which a zone file should start with
+    pub fn soa(&self) -> Option<&Answer> {
+        self.records
+            .iter()
+            .find(|record| matches!(record, Answer::SOA { meta, .. } if meta.name == self.origin))
+    }
+
+    /// Serial of the SOA record, which changes whenever the zone does
+    pub fn serial(&self) -> Option<u32> {
+        match self.soa()? {
+            Answer::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        }
+    }
+
+    /// Increases the serial of the SOA record by one, wrapping around as RFC 1982 allows
+    pub fn increment_serial(&mut self) {
+        let origin = &self.origin;
+        if let Some(Answer::SOA { serial, .. }) = self
+            .records
+            .iter_mut()
+            .find(|record| matches!(record, Answer::SOA { meta, .. } if meta.name == *origin))
+        {
+            *serial = serial.wrapping_add(1);
+        }
+    }
+}
+
+/// What entries take from the ones before them
+struct State {
+    origin: DnsName,
+    /// Owner of the previous record, for entries starting with a blank
+    owner: Option<DnsName>,
+    /// TTL of the previous record
+    last_ttl: Option<usize>,
+    /// TTL of records without one, from the `$TTL` directive
+    /// https://datatracker.ietf.org/doc/html/rfc2308#section-4
+    default_ttl: Option<usize>,
+    class: Class,
+    records: Vec<Answer>,
+}
+
+impl State {
+    fn read(&mut self, contents: &str, dir: &Path, depth: usize) -> Result<(), String> {
+        for entry in entries(contents)? {
+            self.entry(&entry, dir, depth)
+                .map_err(|reason| format!("line {}: {reason}", entry.line))?;
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self, entry: &Entry, dir: &Path, depth: usize) -> Result<(), String> {
+        let mut tokens = entry.tokens.iter().map(String::as_str).peekable();
+        let owner = match tokens.peek() {
+            _ if entry.blank_owner => self
+                .owner
+                .clone()
+                .ok_or("no previous record to take the owner from")?,
+            Some(directive) if directive.starts_with('$') => {
+                let directive = directive.to_ascii_uppercase();
+                tokens.next();
+                return self.directive(&directive, &mut tokens, dir, depth);
+            }
+            Some(owner) => absolute(owner, &self.origin)?,
+            None => unreachable!("entries have tokens"),
+        };
+        if !entry.blank_owner {
+            tokens.next();
+        }
+
+        // the TTL and class come in either order
+        let (mut ttl, mut class) = (None, None);
+        for _ in 0..2 {
+            match tokens.peek() {
+                Some(field) if ttl.is_none() && field.starts_with(|c: char| c.is_ascii_digit()) => {
+                    ttl = Some(parse_ttl(field)?);
+                }
+                Some(field) if class.is_none() && field.parse::<Class>().is_ok() => {
+                    class = field.parse().ok();
+                }
+                _ => break,
+            }
+            tokens.next();
+        }
+        let r#type: RecordType = tokens.next().ok_or("expected a record type")?.parse()?;
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("no TTL, and no $TTL directive before")?;
+        let class = class.unwrap_or(self.class);
+
+        // fields with names that may be relative, and SOA timers that may have units
+        let (names, timers): (&[usize], &[usize]) = match r#type {
+            RecordType::NS | RecordType::CNAME | RecordType::PTR => (&[0], &[]),
+            RecordType::MX => (&[1], &[]),
+            RecordType::SOA => (&[0, 1], &[3, 4, 5, 6]),
+            RecordType::SRV => (&[3], &[]),
+            _ => (&[], &[]),
+        };
+        let rdata = tokens
+            .enumerate()
+            .map(|(i, field)| match i {
+                _ if names.contains(&i) => Ok(absolute(field, &self.origin)?.to_fqdn()),
+                _ if timers.contains(&i) => Ok(parse_ttl(field)?.to_string()),
+                _ => Ok(field.to_string()),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let meta = AnswerMeta {
+            name: owner.clone(),
+            r#type,
+            class,
+            ttl,
+            len: 0,
+        };
+        self.records
+            .push(Answer::parse_rdata(meta, &rdata.join(" "))?);
+        self.owner = Some(owner);
+        self.last_ttl = Some(ttl);
+        self.class = class;
+        Ok(())
+    }
+
+    fn directive<'a>(
+        &mut self,
+        directive: &str,
+        arguments: &mut impl Iterator<Item = &'a str>,
+        dir: &Path,
+        depth: usize,
+    ) -> Result<(), String> {
+        let mut argument = || {
+            arguments
+                .next()
+                .ok_or_else(|| format!("{directive} takes an argument"))
+        };
+        match directive {
+            "$ORIGIN" => self.origin = absolute(argument()?, &self.origin)?,
+            "$TTL" => self.default_ttl = Some(parse_ttl(argument()?)?),
+            "$INCLUDE" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err("too deeply nested $INCLUDE".to_string());
+                }
+                let path = dir.join(argument()?);
+                let origin = match arguments.next() {
+                    Some(origin) => absolute(origin, &self.origin)?,
+                    None => self.origin.clone(),
+                };
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+                // the origin of the including file is unaffected by the included one
+                let outer = std::mem::replace(&mut self.origin, origin);
+                let dir = path.parent().unwrap_or(dir);
+                let included = self
+                    .read(&contents, dir, depth + 1)
+                    .map_err(|e| format!("{}: {e}", path.display()));
+                self.origin = outer;
+                included?;
+            }
+            _ => return Err(format!("unknown directive {directive}")),
+        }
+        Ok(())
+    }
+}
+
+/// `name` as an absolute name, relative names and `@` are below `origin`
+fn absolute(name: &str, origin: &DnsName) -> Result<DnsName, String> {
+    let name = match name {
+        "@" => return Ok(origin.clone()),
+        _ if name.ends_with('.') && !name.ends_with("\\.") => DnsName::new(name),
+        _ if origin.is_root() => DnsName::new(name),
+        _ => DnsName::new(&format!("{name}.{origin}")),
+    };
+    name.map_err(|e| e.to_string())
+}
+
+/// A TTL in seconds, or with the units BIND allows, eg. `1h30m`
+fn parse_ttl(ttl: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid TTL {ttl}");
+    if let Ok(seconds) = ttl.parse::<u32>() {
+        return Ok(seconds as usize);
+    }
+    let (mut seconds, mut number) = (0u64, None::<u64>);
+    for c in ttl.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = Some(number.unwrap_or(0).saturating_mul(10) + digit as u64);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let number = number.take().ok_or_else(invalid)?;
+        seconds = seconds.saturating_add(number.saturating_mul(unit));
+    }
+    match number {
+        None => u32::try_from(seconds).map(|seconds| seconds as usize),
+        Some(_) => return Err(invalid()),
+    }
+    .map_err(|_| invalid())
+}
+
+/// A record or directive, which spans multiple lines within parentheses
+struct Entry {
+    /// Line the entry starts on, counting from 1
+    line: usize,
+    /// Whether the entry starts with a blank, so it has the owner of the previous record
+    blank_owner: bool,
+    /// Fields as they are written, quoted strings with their quotes and escapes
+    tokens: Vec<String>,
+}
+
+/// Splits a zone file into entries, leaving out comments
+fn entries(contents: &str) -> Result<Vec<Entry>, String> {
+    let mut chars = contents.chars().peekable();
+    let (mut entries, mut line) = (vec![], 1);
+    while chars.peek().is_some() {
+        let mut entry = Entry {
+            line,
+            blank_owner: chars.peek().is_some_and(|&c| c == ' ' || c == '\t'),
+            tokens: vec![],
+        };
+        let (mut token, mut parentheses, mut quoted) = (None::<String>, 0usize, false);
+        while let Some(c) = chars.next() {
+            if c == '\n' {
+                line += 1;
+            }
+            match c {
+                '\\' => {
+                    let token = token.get_or_insert_with(String::new);
+                    token.push(c);
+                    if let Some(escaped) = chars.next() {
+                        line += (escaped == '\n') as usize;
+                        token.push(escaped);
+                    }
+                }
+                '"' => {
+                    quoted = !quoted;
+                    token.get_or_insert_with(String::new).push(c);
+                }
+                _ if quoted => token.get_or_insert_with(String::new).push(c),
+                ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+                '(' | ')' | ' ' | '\t' | '\r' | '\n' => {
+                    entry.tokens.extend(token.take());
+                    match c {
+                        '(' => parentheses += 1,
+                        ')' => {
+                            parentheses = parentheses
+                                .checked_sub(1)
+                                .ok_or_else(|| format!("line {line}: unbalanced parentheses"))?;
+                        }
+                        '\n' if parentheses == 0 => break,
+                        _ => {}
+                    }
+                }
+                _ => token.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if quoted || parentheses > 0 {
+            return Err(format!("line {}: unterminated entry", entry.line));
+        }
+        entry.tokens.extend(token);
+        if !entry.tokens.is_empty() {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::name::DnsName;
+
+    use super::{parse_ttl, Zone};
+
+    fn lines(zone: &Zone) -> Vec<String> {
+        zone.records.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_zone() {
+        let origin: DnsName = "example.com".parse().unwrap();
+        let zone = Zone::parse(
+            r#"$TTL 1d
+@       IN  SOA ns.example.com. hostmaster (
+                2024010101 ; serial
+                2h 1h 2w 5m )
+        IN  NS  ns
+        IN  MX  10 mail.example.net.
+ns   300    A   192.0.2.53
+        TXT "v=spf1 ip4:192.0.2.0/24 -all" "a;b (c)"
+$ORIGIN sub
+www     CH  CNAME @
+_sip._udp SRV 0 5 5060 sip.example.com.
+other 60 IN TYPE65 \# 2 abcd
+"#,
+            &origin,
+        )
+        .unwrap();
+        assert_eq!(
+            lines(&zone),
+            [
+                "example.com. 86400 IN SOA ns.example.com. hostmaster.example.com. 2024010101 7200 3600 1209600 300",
+                "example.com. 86400 IN NS ns.example.com.",
+                "example.com. 86400 IN MX 10 mail.example.net.",
+                "ns.example.com. 300 IN A 192.0.2.53",
+                r#"ns.example.com. 86400 IN TXT "v=spf1 ip4:192.0.2.0/24 -all" "a;b (c)""#,
+                "www.sub.example.com. 86400 CH CNAME sub.example.com.",
+                "_sip._udp.sub.example.com. 86400 CH SRV 0 5 5060 sip.example.com.",
+                r"other.sub.example.com. 60 IN TYPE65 \# 2 abcd",
+            ]
+        );
+        assert!(zone.soa().is_some());
+
+        let error = Zone::parse("www A 192.0.2.1", &origin).unwrap_err();
+        assert_eq!(error, "line 1: no TTL, and no $TTL directive before");
+        assert!(Zone::parse("$TTL 1\n@ SOA ns hostmaster ( 1 2 3 4 5", &origin).is_err());
+        assert!(Zone::parse("$TTL 1\n@ MX mail", &origin).is_err());
+        assert!(Zone::parse("$GENERATE 1-2 a A 192.0.2.1", &origin).is_err());
+        assert_eq!(parse_ttl("1h30m"), Ok(5400));
+        assert!(parse_ttl("1x").is_err());
+        assert!(parse_ttl("1h30").is_err());
+    }
+
+    #[test]
+    fn test_zone_to_zonefile() {
+        let zone = Zone::parse(
+            "$TTL 3600\nwww A 192.0.2.1\n@ SOA ns hostmaster 1 2 3 4 5\nt TXT \"a \\\"quoted\\\" \\\\ text\" \\009\n",
+            &"example.com".parse().unwrap(),
+        )
+        .unwrap();
+        let zonefile = zone.to_zonefile();
+        assert_eq!(
+            zonefile,
+            r#"$ORIGIN example.com.
+example.com.     3600 IN SOA ns.example.com. hostmaster.example.com. 1 2 3 4 5
+www.example.com. 3600 IN A   192.0.2.1
+t.example.com.   3600 IN TXT "a \"quoted\" \\ text" "\009"
+"#
+        );
+        assert_eq!(
+            Zone::parse(&zonefile, &DnsName::root())
+                .unwrap()
+                .records
+                .len(),
+            3
+        );
+        let reparsed = Zone::parse(&zonefile, &zone.origin).unwrap();
+        assert_eq!(reparsed.to_zonefile(), zonefile);
+    }
+
+    #[test]
+    fn test_zone_include() {
+        let dir = std::env::temp_dir().join(format!("zonefile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hosts.zone"), "www A 192.0.2.80\n").unwrap();
+        std::fs::write(
+            dir.join("example.zone"),
+            "$TTL 60\n$INCLUDE hosts.zone lan\nmail A 192.0.2.25\n",
+        )
+        .unwrap();
+
+        let zone = Zone::load(dir.join("example.zone"), &"example.com".parse().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            lines(&zone),
+            [
+                "www.lan.example.com. 60 IN A 192.0.2.80",
+                "mail.example.com. 60 IN A 192.0.2.25",
+            ]
+        );
+    }
+}